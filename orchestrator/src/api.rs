@@ -2,23 +2,37 @@
 //!
 //! # Authentication
 //!
-//! All endpoints except `/health`, `/auth/login`, `/auth/register`, and `/auth/status`
-//! require JWT authentication via the `Authorization: Bearer <token>` header.
+//! All endpoints except `/health`, `/auth/login`, `/auth/register`, `/auth/status`,
+//! `/auth/totp/verify`, `/auth/device/*` and `/api/auth/refresh` require JWT authentication via the
+//! `Authorization: Bearer <token>` header, or a session cookie from
+//! `/auth/login?cookie=true` plus `X-CSRF-Token` for writes. `/metrics` is
+//! guarded by network or scrape token instead.
 //!
-//! WebSocket endpoints accept the JWT token via the `?token=<jwt>` query parameter.
+//! WebSocket endpoints take a one-time ticket from `POST /auth/ws-ticket` via the
+//! `?ticket=` query parameter (or, deprecated, the JWT via `?token=<jwt>`); it is
+//! checked before the upgrade, and the connection task gets the caller's claims.
 //!
 //! ## Getting a Token
 //!
 //! 1. First, set an admin password using the CLI: `claw-pen-orchestrator --set-password`
-//!    OR enable registration with `ENABLE_REGISTRATION=true` and call POST /auth/register
+//!    OR call POST /auth/register with the bootstrap token logged at startup
 //!
-//! 2. Authenticate: `POST /auth/login` with `{"password": "your-password"}`
+//! 2. Authenticate: `POST /auth/login` with `{"username": "admin", "password": "your-password"}`
 //!
 //! 3. Use the returned `access_token` in subsequent requests:
 //!    `Authorization: Bearer <access_token>`
 //!
-//! 4. Refresh tokens with `POST /auth/refresh` when the access token expires
+//! 4. Refresh tokens with `POST /api/auth/refresh` when the access token expires
+//!
+//! ## Agent API
+//!
+//! Agents call `/api/agent/self/*` with the service token they are given at
+//! launch in the `CLAW_PEN_AGENT_TOKEN` secret; see [`crate::service_tokens`].
 
+use crate::auth::{Claims, OptionalClaims};
+use crate::scopes;
+use crate::service_tokens::{self, AgentIdentity, AGENT_TOKEN_SECRET};
+use crate::users::Role;
 use crate::validation;
 use axum::extract::ws::{WebSocket, WebSocketUpgrade};
 use axum::{
@@ -30,9 +44,16 @@ use axum::{
 };
 use serde::Serialize;
 
-// Helper to sanitize error messages before returning to clients
-fn sanitize_error(e: &str) -> String {
-    validation::sanitize_error_message(e)
+/// Reject callers whose token role is below `role`; requests that went
+/// through no auth middleware carry no claims and are let through
+fn require_role(claims: Option<&Claims>, role: Role) -> Result<(), (StatusCode, String)> {
+    match claims {
+        Some(claims) if claims.require_role(role).is_err() => Err((
+            StatusCode::FORBIDDEN,
+            "Insufficient permissions".to_string(),
+        )),
+        _ => Ok(()),
+    }
 }
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -44,12 +65,70 @@ use crate::AppState;
 
 // === Health ===
 
-pub async fn health() -> &'static str {
-    "OK"
+/// Header carrying the orchestrator version on `/health`
+pub const VERSION_HEADER: &str = "x-claw-pen-version";
+
+pub async fn health() -> ([(&'static str, &'static str); 1], &'static str) {
+    ([(VERSION_HEADER, env!("CARGO_PKG_VERSION"))], "OK")
 }
 
 // === Agents ===
 
+/// Give agent `agent_id` a new service token in its secrets, ending any
+/// earlier one; the agent starts without one if this fails
+async fn issue_agent_token(state: &AppState, agent_id: &str) {
+    let scopes: Vec<&str> = scopes::SERVICE_SCOPES
+        .iter()
+        .map(|(name, _)| *name)
+        .collect();
+    let minted = {
+        let mut auth = state.auth.write().await;
+        auth.revoke_service_tokens(agent_id).and_then(|()| {
+            auth.mint_service_token(agent_id, &scopes, service_tokens::ttl_from_env())
+        })
+    };
+    let result = match minted {
+        Ok(token) => state
+            .secrets
+            .set_secret(agent_id, AGENT_TOKEN_SECRET, &token)
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        tracing::warn!(
+            "Failed to issue a service token to agent {}: {}",
+            agent_id,
+            e
+        );
+    }
+}
+
+/// End agent `agent_id`'s service tokens and remove the current one from
+/// its secrets
+async fn revoke_agent_token(state: &AppState, agent_id: &str) {
+    if let Err(e) = state.auth.write().await.revoke_service_tokens(agent_id) {
+        tracing::warn!(
+            "Failed to revoke agent {}'s service tokens: {}",
+            agent_id,
+            e
+        );
+    }
+    if let Err(e) = state
+        .secrets
+        .delete_secret(agent_id, AGENT_TOKEN_SECRET)
+        .await
+    {
+        tracing::warn!("Failed to delete agent {}'s service token: {}", agent_id, e);
+    }
+}
+
+/// Whether `agent` is in `project`, ignoring case and Unicode form
+fn in_project(agent: &AgentContainer, project: &str) -> bool {
+    agent.project.as_deref().map(validation::project_name_key)
+        == Some(validation::project_name_key(project))
+}
+
 pub async fn list_agents(
     State(state): State<Arc<AppState>>,
     Query(params): Query<HashMap<String, String>>,
@@ -61,7 +140,7 @@ pub async fn list_agents(
         .filter(|c| {
             // Filter by project
             if let Some(project) = params.get("project") {
-                if c.project.as_deref() != Some(project.as_str()) {
+                if !in_project(c, project) {
                     return false;
                 }
             }
@@ -93,120 +172,57 @@ pub async fn list_agents(
 
 pub async fn create_agent(
     State(state): State<Arc<AppState>>,
+    OptionalClaims(claims): OptionalClaims,
     Json(req): Json<CreateAgentRequest>,
-) -> Result<Json<AgentContainer>, (StatusCode, String)> {
-    // === Input Validation ===
-
-    // Validate agent name (container name)
-    if let Err(e) = validation::validate_container_name(&req.name) {
-        return Err((StatusCode::BAD_REQUEST, sanitize_error(&e.to_string())));
-    }
-
-    // Validate project name if provided
-    if let Some(ref project) = req.project {
-        if let Err(e) = validation::validate_project_name(project) {
-            return Err((StatusCode::BAD_REQUEST, sanitize_error(&e.to_string())));
+) -> axum::response::Result<Json<AgentContainer>> {
+    require_role(claims.as_ref(), Role::Operator)?;
+
+    // Every invalid field at once, so the form can be fixed in one go;
+    // warnings only refuse the agent in strict mode
+    if let Err(mut errors) = state.validation.validate_agent_spec(&req) {
+        if !state.validation.strict_exec_args {
+            errors.retain(|e| {
+                if e.kind.is_warning() {
+                    tracing::warn!("Agent '{}': {}: {}", req.name, e.field, e.message);
+                }
+                !e.kind.is_warning()
+            });
         }
-    }
-
-    // Validate tags if provided
-    for tag in &req.tags {
-        if let Err(e) = validation::validate_tag(tag) {
-            return Err((StatusCode::BAD_REQUEST, sanitize_error(&e.to_string())));
+        if !errors.is_empty() {
+            return Err(validation::ValidationErrors(errors).into());
         }
     }
-
-    // Validate runtime if provided
+    let project = req
+        .project
+        .as_deref()
+        .map(|p| state.validation.normalize_project_name(p))
+        .transpose()?;
     let runtime = req.runtime.as_ref().map(|r| r.to_lowercase());
-    if let Some(ref rt) = runtime {
-        if rt != "docker" && rt != "exo" {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                format!("Invalid runtime '{}'. Must be 'docker' or 'exo'.", rt),
-            ));
-        }
-    }
-    if let Some(ref cfg) = req.config {
-        // Validate env vars count
-        if let Some(ref env) = cfg.env_vars {
-            if env.len() > validation::MAX_ENV_VARS_COUNT {
-                return Err((
-                    StatusCode::BAD_REQUEST,
+
+    // Build config from template + overrides
+    let mut config = if let Some(ref template_name) = req.template {
+        let template = state.templates.get(template_name);
+        if let (Some(template), Some(version_req)) = (template, &req.template_version) {
+            // Checked by the spec validation
+            let version_req: semver::VersionReq = version_req.parse().unwrap_or_default();
+            if !template
+                .semver()
+                .is_some_and(|version| version_req.matches(&version))
+            {
+                return Err(validation::ValidationError::new(
+                    "template_version",
+                    validation::ValidationErrorKind::NotFound,
                     format!(
-                        "Too many environment variables (max {})",
-                        validation::MAX_ENV_VARS_COUNT
+                        "Template '{}' is at version {}, which doesn't match {}",
+                        template_name,
+                        template.version.as_deref().unwrap_or("none"),
+                        version_req
                     ),
-                ));
-            }
-            // Validate each env var key/value
-            for (key, value) in env {
-                if let Err(e) = validation::validate_env_key(key) {
-                    return Err((StatusCode::BAD_REQUEST, sanitize_error(&e.to_string())));
-                }
-                if let Err(e) = validation::validate_env_value(value) {
-                    return Err((StatusCode::BAD_REQUEST, sanitize_error(&e.to_string())));
-                }
-            }
-        }
-
-        // Validate secrets count
-        if let Some(ref secrets) = cfg.secrets {
-            if secrets.len() > validation::MAX_SECRETS_COUNT {
-                return Err((
-                    StatusCode::BAD_REQUEST,
-                    format!("Too many secrets (max {})", validation::MAX_SECRETS_COUNT),
-                ));
-            }
-            for secret in secrets {
-                if let Err(e) = validation::validate_secret_name(secret) {
-                    return Err((StatusCode::BAD_REQUEST, sanitize_error(&e.to_string())));
-                }
-            }
-        }
-
-        // Validate volumes count and paths
-        if let Some(ref volumes) = cfg.volumes {
-            if volumes.len() > validation::MAX_VOLUMES_COUNT {
-                return Err((
-                    StatusCode::BAD_REQUEST,
-                    format!("Too many volumes (max {})", validation::MAX_VOLUMES_COUNT),
-                ));
-            }
-            for vol in volumes {
-                // Note: Full path validation requires filesystem access, done at container creation
-                if let Err(e) = validation::validate_container_target(&vol.target) {
-                    return Err((StatusCode::BAD_REQUEST, sanitize_error(&e.to_string())));
-                }
-            }
-        }
-
-        // Validate LLM model name if provided
-        if let Some(ref model) = cfg.llm_model {
-            if let Err(e) = validation::validate_llm_model(model) {
-                return Err((StatusCode::BAD_REQUEST, sanitize_error(&e.to_string())));
-            }
-        }
-
-        // Validate memory and CPU if provided
-        if let Some(mem) = cfg.memory_mb {
-            if let Err(e) = validation::validate_memory_mb(mem) {
-                return Err((StatusCode::BAD_REQUEST, sanitize_error(&e.to_string())));
-            }
-        }
-        if let Some(cpu) = cfg.cpu_cores {
-            if let Err(e) = validation::validate_cpu_cores(cpu) {
-                return Err((StatusCode::BAD_REQUEST, sanitize_error(&e.to_string())));
+                )
+                .into());
             }
         }
-    }
-
-    // === End Input Validation ===
-
-    // Build config from template + overrides
-    let mut config = if let Some(ref template_name) = req.template {
-        state
-            .templates
-            .get(template_name)
+        template
             .map(|t| {
                 let mut cfg = AgentConfig::default();
                 if let Some(ref provider) = t.config.llm_provider {
@@ -235,6 +251,43 @@ pub async fn create_agent(
         config.apply(partial);
     }
 
+    {
+        let containers = state.containers.read().await;
+        check_host_capacity(&state, &containers, &config, None).await?;
+    }
+
+    // Store canonical mount targets, then create host volume directories
+    // that don't exist yet, checking again
+    // in case the tree changed since validation, and pin them so a swap
+    // before the mount is caught
+    let mut pinned_volumes = Vec::new();
+    for (i, volume) in config.volumes.iter_mut().enumerate() {
+        volume.target = state
+            .validation
+            .validate_container_target(&volume.target)
+            .map_err(|e| e.for_field(format!("volumes[{}].target", i)))?;
+        if !validation::is_host_path(&volume.source) {
+            continue;
+        }
+        let field = format!("volumes[{}].source", i);
+        let new = state
+            .validation
+            .validate_volume_path_for_create(&volume.source)
+            .map_err(|e| e.for_field(&field))?;
+        std::fs::create_dir_all(&new.path).map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to create volume directory: {}", e),
+            )
+        })?;
+        let pinned = state
+            .validation
+            .resolve_and_pin_volume_path(&new.path.to_string_lossy())
+            .map_err(|e| e.for_field(&field))?;
+        volume.source = pinned.path.to_string_lossy().to_string();
+        pinned_volumes.push(pinned);
+    }
+
     // Create container
 
     // Inject API key from agent config
@@ -260,6 +313,18 @@ pub async fn create_agent(
         crate::config::ContainerRuntimeType::Exo => Some("exo".to_string()),
     });
 
+    for pinned in &pinned_volumes {
+        pinned.reassert()?;
+    }
+
+    // Runtime errors can carry paths and addresses
+    let runtime_error = |e: anyhow::Error| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            validation::sanitize_error_message(&e.to_string()),
+        )
+    };
+
     // Get the appropriate runtime client based on agent's runtime preference
     let id = if let Some(ref rt) = agent_runtime {
         if rt == "exo" {
@@ -268,21 +333,21 @@ pub async fn create_agent(
                 .exo_runtime
                 .create_container(&req.name, &config)
                 .await
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+                .map_err(runtime_error)?
         } else {
             // Use default runtime (docker or containment)
             state
                 .runtime
                 .create_container(&req.name, &config)
                 .await
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+                .map_err(runtime_error)?
         }
     } else {
         state
             .runtime
             .create_container(&req.name, &config)
             .await
-            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .map_err(runtime_error)?
     };
 
     let agent = AgentContainer {
@@ -292,7 +357,7 @@ pub async fn create_agent(
         config,
         tailscale_ip: None,
         resource_usage: None,
-        project: req.project,
+        project,
         tags: req.tags,
         restart_policy: AgentConfig::default().restart_policy,
         health_status: None,
@@ -336,21 +401,37 @@ pub async fn create_agent(
 pub async fn get_agent(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
-) -> Result<Json<AgentContainer>, (StatusCode, String)> {
+) -> axum::response::Result<Json<AgentContainer>> {
+    // Runtimes name containers their own way, so only the charset is checked
+    validation::validate_agent_id_compat(&id)?;
     let containers = state.containers.read().await;
-    containers
+    let agent = containers
         .iter()
         .find(|c| c.id == id)
         .cloned()
-        .map(Json)
-        .ok_or_else(|| (StatusCode::NOT_FOUND, "Agent not found".to_string()))
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Agent not found".to_string()))?;
+    Ok(Json(agent))
 }
 
 pub async fn update_agent(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
     Json(req): Json<UpdateAgentRequest>,
-) -> Result<Json<AgentContainer>, (StatusCode, String)> {
+) -> axum::response::Result<Json<AgentContainer>> {
+    validation::validate_agent_id_compat(&id)?;
+    if let Some(ref name) = req.name {
+        state.validation.validate_container_name(name, false)?;
+    }
+    if let Some(LlmProvider::Custom { ref endpoint }) =
+        req.config.as_ref().and_then(|c| c.llm_provider.as_ref())
+    {
+        validation::validate_llm_endpoint(endpoint)?;
+    }
+    let project = req
+        .project
+        .as_deref()
+        .map(|p| state.validation.normalize_project_name(p))
+        .transpose()?;
     let mut containers = state.containers.write().await;
     let agent = containers
         .iter_mut()
@@ -360,8 +441,8 @@ pub async fn update_agent(
     if let Some(name) = req.name {
         agent.name = name;
     }
-    if let Some(project) = req.project {
-        agent.project = Some(project);
+    if project.is_some() {
+        agent.project = project;
     }
     if let Some(tags) = req.tags {
         agent.tags = tags;
@@ -380,8 +461,12 @@ pub async fn update_agent(
 
 pub async fn delete_agent(
     State(state): State<Arc<AppState>>,
+    OptionalClaims(claims): OptionalClaims,
     Path(id): Path<String>,
-) -> Result<StatusCode, (StatusCode, String)> {
+) -> axum::response::Result<StatusCode> {
+    require_role(claims.as_ref(), Role::Operator)?;
+    validation::validate_agent_id_compat(&id)?;
+
     // First check if agent exists in our list and get its runtime
     let (agent_exists, agent_runtime) = {
         let containers = state.containers.read().await;
@@ -393,7 +478,7 @@ pub async fn delete_agent(
     };
 
     if !agent_exists {
-        return Err((StatusCode::NOT_FOUND, "Agent not found".to_string()));
+        return Err((StatusCode::NOT_FOUND, "Agent not found".to_string()).into());
     }
 
     // Choose the right runtime based on agent's runtime setting
@@ -405,6 +490,7 @@ pub async fn delete_agent(
 
     // Stop if running (ignore errors if container doesn't exist)
     let _ = runtime.stop_container(&id).await;
+    revoke_agent_token(&state, &id).await;
 
     // Delete container (ignore errors if container doesn't exist)
     let _ = runtime.delete_container(&id).await;
@@ -428,12 +514,78 @@ pub async fn delete_agent(
     Ok(StatusCode::NO_CONTENT)
 }
 
+/// Refuse `config` if the host can't fit it next to the running agents,
+/// other than `except`
+async fn check_host_capacity(
+    state: &AppState,
+    agents: &[AgentContainer],
+    config: &AgentConfig,
+    except: Option<&str>,
+) -> Result<(), validation::ValidationErrors> {
+    let running = agents
+        .iter()
+        .filter(|a| a.status == AgentStatus::Running && Some(a.id.as_str()) != except);
+    let (reserved_mb, reserved_cores) = running.fold((0u64, 0f64), |(mb, cores), a| {
+        (
+            mb + a.config.memory_mb as u64,
+            cores + a.config.cpu_cores as f64,
+        )
+    });
+    let host = *state.host.read().await;
+    let mut errors: Vec<_> = [
+        state
+            .validation
+            .validate_memory_against_host(config.memory_mb, &host, reserved_mb),
+        state
+            .validation
+            .validate_cpu_against_host(config.cpu_cores, &host, reserved_cores),
+    ]
+    .into_iter()
+    .filter_map(Result::err)
+    .collect();
+    // Free space is what's left now, whatever other agents may still write
+    let disk = match config.disk_mb {
+        Some(disk_mb) => Some(("disk_mb", disk_mb)),
+        None => config
+            .storage_opts
+            .get("size")
+            .and_then(|size| validation::parse_storage_size_mb(size))
+            .map(|size_mb| ("storage_opts[size]", size_mb)),
+    };
+    if let Some((field, disk_mb)) = disk {
+        if let Err(e) = state.validation.validate_disk_mb(disk_mb, &state.disk) {
+            errors.push(e.for_field(field));
+        }
+    }
+    if let Some(ref gpus) = config.gpus {
+        // GPUs can be shared, so only the host's count matters
+        errors.extend(
+            validation::validate_gpu_request(gpus, host.gpu_count)
+                .err()
+                .unwrap_or_default(),
+        );
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(validation::ValidationErrors(errors))
+    }
+}
+
 pub async fn start_agent(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
-) -> Result<Json<AgentContainer>, (StatusCode, String)> {
+) -> axum::response::Result<Json<AgentContainer>> {
     let mut containers = state.containers.write().await;
 
+    let agent = containers
+        .iter()
+        .find(|a| a.id == id)
+        .ok_or((StatusCode::NOT_FOUND, "Agent not found".to_string()))?;
+    if agent.status != AgentStatus::Running {
+        check_host_capacity(&state, &containers, &agent.config, Some(&id)).await?;
+    }
+
     let agent = containers
         .iter_mut()
         .find(|a| a.id == id)
@@ -481,11 +633,13 @@ pub async fn start_agent(
             return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Container ID mismatch".to_string(),
-            ));
+            )
+                .into());
         }
     }
 
     // Start the container
+    issue_agent_token(&state, &id).await;
     runtime
         .start_container(&id)
         .await
@@ -525,6 +679,7 @@ pub async fn stop_agent(
         .stop_container(&id)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    revoke_agent_token(&state, &id).await;
 
     let mut containers = state.containers.write().await;
 
@@ -555,7 +710,7 @@ pub async fn start_all(
     for agent in containers.iter() {
         // Filter by project if specified
         if let Some(project) = params.get("project") {
-            if agent.project.as_deref() != Some(project.as_str()) {
+            if !in_project(agent, project) {
                 continue;
             }
         }
@@ -568,6 +723,7 @@ pub async fn start_all(
                 &state.runtime
             };
 
+            issue_agent_token(&state, &agent.id).await;
             if runtime.start_container(&agent.id).await.is_ok() {
                 started.push(agent.id.clone());
             }
@@ -586,7 +742,7 @@ pub async fn stop_all(
 
     for agent in containers.iter() {
         if let Some(project) = params.get("project") {
-            if agent.project.as_deref() != Some(project.as_str()) {
+            if !in_project(agent, project) {
                 continue;
             }
         }
@@ -600,6 +756,7 @@ pub async fn stop_all(
             };
 
             if runtime.stop_container(&agent.id).await.is_ok() {
+                revoke_agent_token(&state, &agent.id).await;
                 stopped.push(agent.id.clone());
             }
         }
@@ -636,31 +793,23 @@ pub async fn get_logs(
         .and_then(|s| s.parse().ok())
         .unwrap_or(100);
 
-    let logs = runtime
+    let mut logs = runtime
         .get_logs(&id, tail)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    for log in &mut logs {
+        log.message = state.validation.sanitize_log_line(&log.message);
+    }
 
     Ok(Json(logs))
 }
 
 pub async fn logs_websocket(
     State(state): State<Arc<AppState>>,
+    claims: Claims,
     Path(id): Path<String>,
-    Query(params): Query<HashMap<String, String>>,
     ws: WebSocketUpgrade,
 ) -> Result<Response, (StatusCode, String)> {
-    // Validate JWT token from query parameter
-    let token = params.get("token").ok_or((
-        StatusCode::UNAUTHORIZED,
-        "Missing authentication token".to_string(),
-    ))?;
-
-    let auth = state.auth.read().await;
-    auth.validate_token(token)
-        .map_err(|e| (StatusCode::UNAUTHORIZED, format!("Invalid token: {}", e)))?;
-    drop(auth);
-
     // Check if agent exists
     let containers = state.containers.read().await;
     let _agent = containers
@@ -669,16 +818,24 @@ pub async fn logs_websocket(
         .ok_or_else(|| (StatusCode::NOT_FOUND, "Agent not found".to_string()))?;
     drop(containers);
 
-    Ok(ws.on_upgrade(move |socket| handle_logs_stream(socket, state, id)))
+    Ok(ws.on_upgrade(move |socket| handle_logs_stream(socket, state, id, claims)))
 }
 
-async fn handle_logs_stream(mut socket: WebSocket, state: Arc<AppState>, id: String) {
+async fn handle_logs_stream(
+    mut socket: WebSocket,
+    state: Arc<AppState>,
+    id: String,
+    claims: Claims,
+) {
+    tracing::debug!("{} streaming logs of {}", claims.sub, id);
+
     use axum::extract::ws::Message;
     use tokio_stream::StreamExt;
 
     let mut stream = state.runtime.stream_logs(&id).await;
 
-    while let Some(log) = stream.next().await {
+    while let Some(mut log) = stream.next().await {
+        log.message = state.validation.sanitize_log_line(&log.message);
         let msg = serde_json::to_string(&log).unwrap_or_default();
         if socket.send(Message::Text(msg)).await.is_err() {
             break;
@@ -876,6 +1033,16 @@ fn get_system_memory() -> (u64, u64) {
     }
 }
 
+// === Validation ===
+
+/// GET /validation/rules - Name rules, limits and policies, so forms can
+/// check input before sending it
+pub async fn validation_rules(
+    State(state): State<Arc<AppState>>,
+) -> Json<validation::ValidationRules> {
+    Json(state.validation.rules())
+}
+
 // === Templates ===
 
 pub async fn list_templates(
@@ -891,6 +1058,8 @@ pub async fn list_templates(
                 TemplateInfo {
                     name: t.name.clone(),
                     description: t.description.clone(),
+                    version: t.version.clone(),
+                    compatibility: t.compatibility.clone(),
                     provider: t.config.llm_provider.clone(),
                     model: t.config.llm_model.clone(),
                 },
@@ -905,6 +1074,10 @@ pub async fn list_templates(
 pub struct TemplateInfo {
     pub name: String,
     pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compatibility: Option<String>,
     pub provider: Option<String>,
     pub model: Option<String>,
 }
@@ -915,15 +1088,17 @@ pub async fn list_projects(State(state): State<Arc<AppState>>) -> Json<Vec<Proje
     let containers = state.containers.read().await;
     let mut projects: HashMap<String, Project> = HashMap::new();
 
+    // Names differing only in case or form are the same project
     for agent in containers.iter() {
         if let Some(ref project_name) = agent.project {
             let project = projects
-                .entry(project_name.clone())
+                .entry(validation::project_name_key(project_name))
                 .or_insert_with(|| Project {
-                    id: project_name.to_lowercase().replace(' ', "-"),
+                    id: validation::project_name_key(project_name).replace(' ', "-"),
                     name: project_name.clone(),
                     description: None,
                     agents: Vec::new(),
+                    labels: HashMap::new(),
                     created_at: chrono::Utc::now().to_rfc3339(),
                 });
             project.agents.push(agent.id.clone());
@@ -934,18 +1109,25 @@ pub async fn list_projects(State(state): State<Arc<AppState>>) -> Json<Vec<Proje
 }
 
 pub async fn create_project(
-    State(_state): State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
     Json(req): Json<CreateProjectRequest>,
-) -> Json<Project> {
+) -> axum::response::Result<Json<Project>> {
+    state
+        .validation
+        .validate_project_spec(&req)
+        .map_err(validation::ValidationErrors)?;
+    let name = state.validation.normalize_project_name(&req.name)?;
+
     let project = Project {
-        id: req.name.to_lowercase().replace(' ', "-"),
-        name: req.name,
+        id: validation::project_name_key(&name).replace(' ', "-"),
+        name,
         description: req.description,
         agents: Vec::new(),
+        labels: req.labels,
         created_at: chrono::Utc::now().to_rfc3339(),
     };
 
-    Json(project)
+    Ok(Json(project))
 }
 
 // === Secrets ===
@@ -963,7 +1145,8 @@ pub async fn set_secret(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
     Json(req): Json<SetSecretRequest>,
-) -> Result<StatusCode, (StatusCode, String)> {
+) -> axum::response::Result<StatusCode> {
+    validation::validate_secret_name(&req.name)?;
     state
         .secrets
         .set_secret(&id, &req.name, &req.value)
@@ -976,7 +1159,8 @@ pub async fn set_secret(
 pub async fn delete_secret(
     State(state): State<Arc<AppState>>,
     Path((id, name)): Path<(String, String)>,
-) -> Result<StatusCode, (StatusCode, String)> {
+) -> axum::response::Result<StatusCode> {
+    validation::validate_secret_name(&name)?;
     state
         .secrets
         .delete_secret(&id, &name)
@@ -986,6 +1170,62 @@ pub async fn delete_secret(
     Ok(StatusCode::NO_CONTENT)
 }
 
+// === Agent API ===
+
+/// The calling agent's own record
+pub async fn agent_self(
+    State(state): State<Arc<AppState>>,
+    AgentIdentity { agent_id }: AgentIdentity,
+) -> Result<Json<AgentContainer>, (StatusCode, String)> {
+    let containers = state.containers.read().await;
+    containers
+        .iter()
+        .find(|a| a.id == agent_id)
+        .cloned()
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "Agent not found".to_string()))
+}
+
+/// The calling agent's secrets, less its service token
+pub async fn agent_self_secrets(
+    State(state): State<Arc<AppState>>,
+    AgentIdentity { agent_id }: AgentIdentity,
+) -> Result<Json<HashMap<String, String>>, (StatusCode, String)> {
+    let mut secrets = state
+        .secrets
+        .get_all_secrets(&agent_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    secrets.remove(AGENT_TOKEN_SECRET);
+    Ok(Json(secrets))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ReportHealthRequest {
+    pub healthy: bool,
+    pub message: Option<String>,
+}
+
+/// The calling agent reporting its own health
+pub async fn agent_self_health(
+    State(state): State<Arc<AppState>>,
+    AgentIdentity { agent_id }: AgentIdentity,
+    Json(req): Json<ReportHealthRequest>,
+) -> Result<Json<HealthStatus>, (StatusCode, String)> {
+    let status = HealthStatus {
+        healthy: req.healthy,
+        last_check: chrono::Utc::now().to_rfc3339(),
+        message: req.message,
+    };
+    let mut containers = state.containers.write().await;
+    let agent = containers
+        .iter_mut()
+        .find(|a| a.id == agent_id)
+        .ok_or((StatusCode::NOT_FOUND, "Agent not found".to_string()))?;
+    agent.health_status = Some(status.clone());
+    Ok(Json(status))
+}
+
 // === API Keys ===
 
 #[derive(Debug, serde::Deserialize)]
@@ -1024,12 +1264,21 @@ pub async fn list_api_keys(State(state): State<Arc<AppState>>) -> Json<Vec<ApiKe
     )
 }
 
+/// Keep provider keys out of error messages and logs, named by provider
+pub fn redact_api_keys(keys: &HashMap<String, String>) {
+    crate::redaction::install(
+        "api_keys",
+        crate::redaction::Redactor::new(keys.iter().map(|(p, k)| (p.as_str(), k.as_str()))),
+    );
+}
+
 pub async fn set_api_key(
     State(state): State<Arc<AppState>>,
     Json(req): Json<SetApiKeyRequest>,
 ) -> Result<StatusCode, (StatusCode, String)> {
     let mut keys = state.api_keys.write().await;
     keys.insert(req.provider.clone(), req.key);
+    redact_api_keys(&keys);
 
     // Persist to disk
     let keys_path = state.data_dir.join("api_keys.json");
@@ -1046,6 +1295,7 @@ pub async fn delete_api_key(
 ) -> Result<StatusCode, (StatusCode, String)> {
     let mut keys = state.api_keys.write().await;
     keys.remove(&provider);
+    redact_api_keys(&keys);
 
     // Persist to disk
     let keys_path = state.data_dir.join("api_keys.json");
@@ -1136,7 +1386,11 @@ pub async fn export_agent(
 pub async fn import_agent(
     State(state): State<Arc<AppState>>,
     Json(agent): Json<AgentContainer>,
-) -> Result<Json<AgentContainer>, (StatusCode, String)> {
+) -> axum::response::Result<Json<AgentContainer>> {
+    state
+        .validation
+        .validate_container_name(&agent.name, false)?;
+
     // Choose runtime based on imported agent's runtime setting
     let runtime: &dyn ContainerRuntime = if agent.runtime.as_deref() == Some("exo") {
         &state.exo_runtime
@@ -1205,22 +1459,10 @@ fn parse_provider(s: &str) -> LlmProvider {
 /// Example: `ws://localhost:3000/api/agents/{id}/chat?token=eyJhbGciOiJIUzI1NiIs...`
 pub async fn chat_websocket(
     State(state): State<Arc<AppState>>,
+    claims: Claims,
     Path(id): Path<String>,
-    Query(params): Query<HashMap<String, String>>,
     ws: WebSocketUpgrade,
 ) -> Result<Response, (StatusCode, String)> {
-    // Validate JWT token from query parameter
-    let token = params.get("token").ok_or((
-        StatusCode::UNAUTHORIZED,
-        "Missing authentication token".to_string(),
-    ))?;
-
-    let auth = state.auth.read().await;
-    let _claims = auth
-        .validate_token(token)
-        .map_err(|e| (StatusCode::UNAUTHORIZED, format!("Invalid token: {}", e)))?;
-    drop(auth);
-
     // Check if agent exists and is running
     let containers = state.containers.read().await;
     let agent = containers
@@ -1235,10 +1477,31 @@ pub async fn chat_websocket(
     let agent_id = agent.id.clone();
     drop(containers);
 
-    Ok(ws.on_upgrade(move |socket| handle_chat_stream(socket, state, agent_id)))
+    Ok(ws.on_upgrade(move |socket| handle_chat_stream(socket, state, agent_id, claims)))
+}
+
+/// Whether the caller may send chat messages: an operator whose token has
+/// the write `scope`
+fn can_send(claims: &Claims, scope: &str) -> bool {
+    claims.require_role(Role::Operator).is_ok() && claims.has_scope(scope)
+}
+
+/// Reply sent instead of handling a chat message from a read-only account
+fn read_only_reply() -> String {
+    serde_json::json!({
+        "role": "system",
+        "content": "Your account is read-only and can't send messages.",
+        "timestamp": chrono::Utc::now().timestamp()
+    })
+    .to_string()
 }
 
-async fn handle_chat_stream(socket: WebSocket, _state: Arc<AppState>, _agent_id: String) {
+async fn handle_chat_stream(
+    socket: WebSocket,
+    _state: Arc<AppState>,
+    _agent_id: String,
+    claims: Claims,
+) {
     use axum::extract::ws::Message;
     use futures_util::{SinkExt, StreamExt};
 
@@ -1259,6 +1522,13 @@ async fn handle_chat_stream(socket: WebSocket, _state: Arc<AppState>, _agent_id:
     while let Some(msg_result) = rx.next().await {
         match msg_result {
             Ok(Message::Text(text)) => {
+                if !can_send(&claims, scopes::AGENTS_WRITE) {
+                    if tx.send(Message::Text(read_only_reply())).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+
                 // Parse the incoming message
                 if let Ok(msg_data) = serde_json::from_str::<serde_json::Value>(&text) {
                     let user_content = msg_data
@@ -1336,21 +1606,10 @@ pub struct ClassifyRequest {
 /// Authentication: Pass JWT token via `?token=<jwt>` query parameter
 pub async fn team_chat_websocket(
     State(state): State<Arc<AppState>>,
+    claims: Claims,
     Path(id): Path<String>,
-    Query(params): Query<HashMap<String, String>>,
     ws: WebSocketUpgrade,
 ) -> Result<Response, (StatusCode, String)> {
-    // Validate JWT token from query parameter
-    let token = params.get("token").ok_or((
-        StatusCode::UNAUTHORIZED,
-        "Missing authentication token".to_string(),
-    ))?;
-
-    let auth = state.auth.read().await;
-    auth.validate_token(token)
-        .map_err(|e| (StatusCode::UNAUTHORIZED, format!("Invalid token: {}", e)))?;
-    drop(auth);
-
     // Check if team exists
     let team = state
         .teams
@@ -1361,7 +1620,9 @@ pub async fn team_chat_websocket(
     let team_id = team.id.clone();
     let team_name = team.name.clone();
 
-    Ok(ws.on_upgrade(move |socket| handle_team_chat_stream(socket, state, team_id, team_name)))
+    Ok(ws.on_upgrade(move |socket| {
+        handle_team_chat_stream(socket, state, team_id, team_name, claims)
+    }))
 }
 
 async fn handle_team_chat_stream(
@@ -1369,6 +1630,7 @@ async fn handle_team_chat_stream(
     state: Arc<AppState>,
     team_id: String,
     team_name: String,
+    claims: Claims,
 ) {
     use axum::extract::ws::Message;
     use futures_util::{SinkExt, StreamExt};
@@ -1390,6 +1652,13 @@ async fn handle_team_chat_stream(
     while let Some(msg_result) = rx.next().await {
         match msg_result {
             Ok(Message::Text(text)) => {
+                if !can_send(&claims, scopes::TEAMS_WRITE) {
+                    if tx.send(Message::Text(read_only_reply())).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+
                 if let Ok(msg_data) = serde_json::from_str::<serde_json::Value>(&text) {
                     let user_content = msg_data
                         .get("content")
@@ -0,0 +1,301 @@
+//! API keys
+//!
+//! Long-lived credentials for scripts and CI. The `cp_live_…` secret is shown
+//! once when the key is created; the [`AuthStore`] only holds an HMAC-SHA256 of
+//! it, so checking a key costs one keyed hash instead of an Argon2 run per
+//! request. `last_used_at` is written at most once a minute. (`api_keys.json`
+//! is the provider key store in `api.rs`.)
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::AuthError;
+use crate::auth_store::AuthStore;
+use crate::scopes;
+use crate::users::Role;
+
+/// Prefix of every API key secret, so the middleware can tell them from JWTs
+pub const API_KEY_PREFIX: &str = "cp_live_";
+
+/// Random bytes in a secret, after the prefix
+const SECRET_LENGTH: usize = 32;
+
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Seconds between persisted `last_used_at` updates for one key
+const LAST_USED_RESOLUTION: i64 = 60;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// `token_type` of the claims an API key resolves to
+pub const API_KEY_TOKEN_TYPE: &str = "api_key";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// HMAC-SHA256 of the secret, base64
+    pub key_hash: String,
+    pub role: Role,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+}
+
+impl ApiKey {
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|exp| exp <= now)
+    }
+
+    /// Scopes the key grants: those it was limited to, or else its role's
+    pub fn effective_scopes(&self) -> Vec<String> {
+        let allowed = scopes::for_role(self.role);
+        if self.scopes.is_empty() {
+            return allowed.into_iter().map(String::from).collect();
+        }
+        // A role's scopes can't grow past it, but keep to them anyway
+        self.scopes
+            .iter()
+            .filter(|s| allowed.contains(&s.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether `last_used_at` is stale enough to be worth writing
+    pub fn needs_touch(&self, now: i64) -> bool {
+        !matches!(self.last_used_at, Some(last) if now - last < LAST_USED_RESOLUTION)
+    }
+}
+
+/// A key as listed by `GET /auth/api-keys`, without the hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    pub role: Role,
+    pub scopes: Vec<String>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+}
+
+impl From<&ApiKey> for ApiKeyInfo {
+    fn from(key: &ApiKey) -> Self {
+        Self {
+            id: key.id.clone(),
+            name: key.name.clone(),
+            role: key.role,
+            scopes: key.scopes.clone(),
+            created_at: key.created_at,
+            expires_at: key.expires_at,
+            last_used_at: key.last_used_at,
+        }
+    }
+}
+
+pub struct ApiKeyStore {
+    store: Arc<dyn AuthStore>,
+    /// Key for hashing secrets, derived from the JWT secret
+    hash_key: Vec<u8>,
+    keys: Vec<ApiKey>,
+}
+
+impl ApiKeyStore {
+    pub fn load(store: Arc<dyn AuthStore>, jwt_secret: &[u8]) -> Result<Self, AuthError> {
+        let keys = store.api_keys()?;
+        // A separate key, so stored hashes are never JWT signatures
+        let mut mac =
+            HmacSha256::new_from_slice(jwt_secret).expect("HMAC accepts keys of any length");
+        mac.update(b"claw-pen api keys");
+        Ok(Self {
+            store,
+            hash_key: mac.finalize().into_bytes().to_vec(),
+            keys,
+        })
+    }
+
+    fn hash(&self, secret: &str) -> String {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.hash_key).expect("HMAC accepts keys of any length");
+        mac.update(secret.as_bytes());
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    }
+
+    /// Create a key, returning its metadata and the secret, which isn't stored
+    pub fn create(
+        &mut self,
+        name: &str,
+        role: Role,
+        scopes: Vec<String>,
+        expires_at: Option<i64>,
+        now: i64,
+    ) -> Result<(ApiKeyInfo, String), AuthError> {
+        let name = name.trim();
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+            return Err(AuthError::InvalidApiKeyRequest(format!(
+                "Name must be 1-{} characters",
+                MAX_NAME_LENGTH
+            )));
+        }
+        let allowed = scopes::for_role(role);
+        if let Some(scope) = scopes.iter().find(|s| !allowed.contains(&s.as_str())) {
+            return Err(AuthError::InvalidApiKeyRequest(
+                if scopes::is_known(scope) {
+                    format!("The {} scope is beyond the key's role", scope)
+                } else {
+                    format!("Unknown scope {:?}", scope)
+                },
+            ));
+        }
+        if expires_at.is_some_and(|exp| exp <= now) {
+            return Err(AuthError::InvalidApiKeyRequest(
+                "expires_at must be in the future".to_string(),
+            ));
+        }
+
+        let mut bytes = [0u8; SECRET_LENGTH];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let secret = format!("{}{}", API_KEY_PREFIX, URL_SAFE_NO_PAD.encode(bytes));
+
+        let key = ApiKey {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            key_hash: self.hash(&secret),
+            role,
+            scopes,
+            created_at: now,
+            expires_at,
+            last_used_at: None,
+        };
+        let info = ApiKeyInfo::from(&key);
+        self.store.put_api_key(&key)?;
+        self.keys.push(key);
+        Ok((info, secret))
+    }
+
+    pub fn list(&self) -> Vec<ApiKeyInfo> {
+        self.keys.iter().map(ApiKeyInfo::from).collect()
+    }
+
+    /// The unexpired key a secret belongs to
+    pub fn find(&self, secret: &str, now: i64) -> Option<&ApiKey> {
+        if !secret.starts_with(API_KEY_PREFIX) {
+            return None;
+        }
+        let hash = self.hash(secret);
+        self.keys
+            .iter()
+            .find(|k| k.key_hash == hash && !k.is_expired(now))
+    }
+
+    /// Record a use of key `id`, if the stored time is stale
+    pub fn touch(&mut self, id: &str, now: i64) -> Result<(), AuthError> {
+        let Some(key) = self.keys.iter_mut().find(|k| k.id == id) else {
+            return Ok(());
+        };
+        if !key.needs_touch(now) {
+            return Ok(());
+        }
+        key.last_used_at = Some(now);
+        self.store.put_api_key(key)
+    }
+
+    pub fn revoke(&mut self, id: &str) -> Result<(), AuthError> {
+        self.store.remove_api_key(id)?;
+        self.keys.retain(|k| k.id != id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth_store::MemoryAuthStore;
+
+    fn backing() -> Arc<dyn AuthStore> {
+        Arc::new(MemoryAuthStore::default())
+    }
+
+    fn store(backing: &Arc<dyn AuthStore>) -> ApiKeyStore {
+        ApiKeyStore::load(backing.clone(), b"test secret").unwrap()
+    }
+
+    #[test]
+    fn test_secret_is_only_stored_hashed() {
+        let backing = backing();
+        let mut keys = store(&backing);
+        let (info, secret) = keys
+            .create(
+                "ci",
+                Role::Operator,
+                vec!["agents.write".into()],
+                None,
+                1_000,
+            )
+            .unwrap();
+
+        assert!(secret.starts_with(API_KEY_PREFIX));
+        assert_eq!(keys.find(&secret, 1_000).unwrap().id, info.id);
+        assert!(keys.find("cp_live_wrong", 1_000).is_none());
+        assert!(keys.find("not-a-key", 1_000).is_none());
+
+        let stored = serde_json::to_string(&backing.export().unwrap()).unwrap();
+        assert!(!stored.contains(&secret));
+
+        // A reload with the same hash key still resolves the secret
+        let reloaded = store(&backing);
+        assert_eq!(
+            reloaded.find(&secret, 1_000).unwrap().scopes,
+            ["agents.write"]
+        );
+    }
+
+    #[test]
+    fn test_expired_and_revoked_keys_are_rejected() {
+        let backing = backing();
+        let mut keys = store(&backing);
+        let (info, secret) = keys
+            .create("nightly", Role::Viewer, vec![], Some(2_000), 1_000)
+            .unwrap();
+        assert!(keys.find(&secret, 1_999).is_some());
+        assert!(keys.find(&secret, 2_000).is_none());
+
+        keys.revoke(&info.id).unwrap();
+        assert!(keys.find(&secret, 1_500).is_none());
+        assert!(matches!(
+            keys.revoke(&info.id),
+            Err(AuthError::ApiKeyNotFound)
+        ));
+
+        assert!(keys
+            .create("  ", Role::Viewer, vec![], None, 1_000)
+            .is_err());
+        assert!(keys
+            .create("late", Role::Viewer, vec![], Some(900), 1_000)
+            .is_err());
+    }
+
+    #[test]
+    fn test_last_used_is_written_at_most_once_a_minute() {
+        let backing = backing();
+        let mut keys = store(&backing);
+        let (info, secret) = keys
+            .create("ci", Role::Operator, vec![], None, 1_000)
+            .unwrap();
+
+        keys.touch(&info.id, 1_000).unwrap();
+        keys.touch(&info.id, 1_030).unwrap();
+        assert_eq!(keys.find(&secret, 1_030).unwrap().last_used_at, Some(1_000));
+        assert!(!keys.find(&secret, 1_059).unwrap().needs_touch(1_059));
+
+        keys.touch(&info.id, 1_060).unwrap();
+        assert_eq!(store(&backing).list()[0].last_used_at, Some(1_060));
+    }
+}
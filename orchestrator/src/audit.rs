@@ -0,0 +1,558 @@
+//! Audit log of authentication and authorization events
+//!
+//! Logins, refreshes, logouts, registrations, password changes, lockouts and
+//! 403s are appended as JSON lines to `audit/audit.jsonl` in the data
+//! directory. Requests only queue an entry; a background thread numbers and
+//! writes them, so a slow disk never holds up a login. Source IPs are stored
+//! as keyed hashes: the same IP always hashes the same, so repeated failures
+//! can be correlated, but the log alone doesn't give the address away.
+//! Entries never carry passwords or tokens.
+//!
+//! Once the file reaches `AUDIT_LOG_MAX_BYTES` (default 10 MiB) it is renamed
+//! to `audit-<last seq>.jsonl`, and only the newest `AUDIT_LOG_RETAIN_FILES`
+//! (default 10) of those are kept. `GET /auth/audit` reads them back.
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::convert::Infallible;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+/// Header a caller or proxy can set to tie audit entries to its own logs
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Bytes of the keyed hash kept for a source IP
+const IP_HASH_LENGTH: usize = 16;
+
+/// Entries returned by `GET /auth/audit` when no limit is given
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+
+pub const MAX_PAGE_SIZE: usize = 1_000;
+
+const CURRENT_FILE: &str = "audit.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    LoginSuccess,
+    LoginFailure,
+    /// Refused by the login limiter before the password was checked
+    Lockout,
+    TokenRefresh,
+    Logout,
+    LogoutAll,
+    /// A login session ended with `DELETE /auth/sessions/:id`
+    SessionRevoked,
+    Registration,
+    PasswordChange,
+    /// A 403 from an authenticated route, or from `/auth/register` for a
+    /// client outside `REGISTRATION_ALLOWED_NETWORKS`
+    Forbidden,
+}
+
+/// One line of the audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Position in the log, counting up from 1; the paging cursor
+    pub seq: u64,
+    pub timestamp: i64,
+    pub event: AuditEvent,
+    /// Account the event is about, or the username a login named
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    /// Keyed hash of the source IP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_ip: Option<String>,
+    pub request_id: String,
+    /// What failed or was refused, never a credential
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// An event to record, built up by the caller
+#[derive(Debug, Clone)]
+pub struct Audit {
+    event: AuditEvent,
+    request_id: String,
+    subject: Option<String>,
+    ip: Option<IpAddr>,
+    detail: Option<String>,
+}
+
+impl Audit {
+    pub fn new(event: AuditEvent, request_id: &RequestId) -> Self {
+        Self {
+            event,
+            request_id: request_id.0.clone(),
+            subject: None,
+            ip: None,
+            detail: None,
+        }
+    }
+
+    pub fn subject(mut self, subject: &str) -> Self {
+        self.subject = Some(subject.to_string());
+        self
+    }
+
+    /// Source IP; the unspecified address, used when it isn't known, is left out
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.ip = Some(ip).filter(|ip| !ip.is_unspecified());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// Id of the current request: its `X-Request-Id` if it sent a sensible one,
+/// otherwise a fresh UUID. Extracting it more than once in a request gives
+/// the same id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn from_parts(parts: &mut Parts) -> Self {
+        if let Some(id) = parts.extensions.get::<RequestId>() {
+            return id.clone();
+        }
+        let id = parts
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| {
+                !v.is_empty()
+                    && v.len() <= MAX_REQUEST_ID_LENGTH
+                    && v.chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+            })
+            .map_or_else(|| Uuid::new_v4().to_string(), str::to_string);
+        let id = RequestId(id);
+        parts.extensions.insert(id.clone());
+        id
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestId {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_parts(parts))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    /// Size at which the current file is rotated
+    pub max_file_bytes: u64,
+    /// Rotated files kept
+    pub retain_files: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            max_file_bytes: 10 * 1024 * 1024,
+            retain_files: 10,
+        }
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+impl AuditConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_file_bytes: env_or("AUDIT_LOG_MAX_BYTES", default.max_file_bytes).max(1),
+            retain_files: env_or("AUDIT_LOG_RETAIN_FILES", default.retain_files),
+        }
+    }
+}
+
+/// Query of `GET /auth/audit`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditQuery {
+    /// Only entries at or after this Unix time
+    pub since: Option<i64>,
+    /// Only entries after this `seq`; pass the previous page's `next_after`
+    pub after: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+/// Response of `GET /auth/audit`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditPage {
+    /// Oldest first
+    pub entries: Vec<AuditEntry>,
+    /// Cursor for the next page, if there are more entries
+    pub next_after: Option<u64>,
+}
+
+enum Message {
+    Entry(AuditEntry),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Handle for queueing and reading audit entries; clones share one writer
+#[derive(Clone)]
+pub struct AuditLog {
+    dir: PathBuf,
+    ip_key: Arc<Vec<u8>>,
+    sender: mpsc::UnboundedSender<Message>,
+}
+
+impl AuditLog {
+    /// Start the writer for the log in `dir`, hashing IPs with `ip_key`
+    pub fn start(dir: PathBuf, config: AuditConfig, ip_key: Vec<u8>) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut writer = Writer {
+            seq: last_seq(&dir)?,
+            dir: dir.clone(),
+            config,
+            file: None,
+        };
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        std::thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || {
+                while let Some(message) = receiver.blocking_recv() {
+                    match message {
+                        Message::Entry(entry) => {
+                            if let Err(e) = writer.append(entry) {
+                                tracing::error!("Failed to write audit entry: {}", e);
+                            }
+                        }
+                        Message::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })?;
+        Ok(Self {
+            dir,
+            ip_key: Arc::new(ip_key),
+            sender,
+        })
+    }
+
+    /// Queue an entry; returns at once
+    pub fn record(&self, audit: Audit) {
+        let entry = AuditEntry {
+            seq: 0,
+            timestamp: Utc::now().timestamp(),
+            event: audit.event,
+            subject: audit.subject,
+            source_ip: audit.ip.map(|ip| self.hash_ip(ip)),
+            request_id: audit.request_id,
+            detail: audit.detail,
+        };
+        if self.sender.send(Message::Entry(entry)).is_err() {
+            tracing::error!("Audit writer has stopped; entry dropped");
+        }
+    }
+
+    /// Wait until everything queued so far is written
+    pub async fn flush(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = wait.await;
+        }
+    }
+
+    /// Keyed hash of `ip`, as entries store it
+    pub fn hash_ip(&self, ip: IpAddr) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.ip_key).expect("HMAC accepts keys of any length");
+        mac.update(ip.to_string().as_bytes());
+        URL_SAFE_NO_PAD.encode(&mac.finalize().into_bytes()[..IP_HASH_LENGTH])
+    }
+
+    /// A page of entries matching `query`, oldest first
+    pub fn read(&self, query: &AuditQuery) -> io::Result<AuditPage> {
+        let since = query.since.unwrap_or(i64::MIN);
+        let after = query.after.unwrap_or(0);
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+
+        let mut files: Vec<PathBuf> = rotated_files(&self.dir)?
+            .into_iter()
+            // A rotated file's name is its last seq
+            .filter(|(last, _)| *last > after)
+            .map(|(_, path)| path)
+            .collect();
+        files.push(self.dir.join(CURRENT_FILE));
+
+        let mut entries = Vec::new();
+        for path in files {
+            let file = match fs::File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for line in io::BufReader::new(file).lines() {
+                let line = line?;
+                let Ok(entry) = serde_json::from_str::<AuditEntry>(&line) else {
+                    continue;
+                };
+                if entry.seq > after && entry.timestamp >= since {
+                    entries.push(entry);
+                    // One extra tells whether there is another page
+                    if entries.len() > limit {
+                        entries.truncate(limit);
+                        let next_after = entries.last().map(|e| e.seq);
+                        return Ok(AuditPage {
+                            entries,
+                            next_after,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(AuditPage {
+            entries,
+            next_after: None,
+        })
+    }
+}
+
+/// Rotated files in `dir` with the last seq each holds, oldest first
+fn rotated_files(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    for dir_entry in fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        let last = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix("audit-")?.strip_suffix(".jsonl"))
+            .and_then(|n| n.parse().ok());
+        if let Some(last) = last {
+            files.push((last, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The seq of the last entry written to the log in `dir`, so numbering
+/// carries on across restarts
+fn last_seq(dir: &Path) -> io::Result<u64> {
+    let current = dir.join(CURRENT_FILE);
+    if current.exists() {
+        let last = io::BufReader::new(fs::File::open(current)?)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str::<AuditEntry>(&line).ok())
+            .last();
+        if let Some(entry) = last {
+            return Ok(entry.seq);
+        }
+    }
+    Ok(rotated_files(dir)?.last().map_or(0, |(last, _)| *last))
+}
+
+/// State of the background writer
+struct Writer {
+    dir: PathBuf,
+    config: AuditConfig,
+    seq: u64,
+    /// The current file and its size, once opened
+    file: Option<(fs::File, u64)>,
+}
+
+impl Writer {
+    fn append(&mut self, mut entry: AuditEntry) -> io::Result<()> {
+        self.seq += 1;
+        entry.seq = self.seq;
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        if self.file.is_none() {
+            let mut options = fs::OpenOptions::new();
+            options.append(true).create(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            let file = options.open(self.dir.join(CURRENT_FILE))?;
+            let size = file.metadata()?.len();
+            self.file = Some((file, size));
+        }
+        let (file, size) = self.file.as_mut().expect("opened above");
+        file.write_all(line.as_bytes())?;
+        *size += line.len() as u64;
+
+        if *size >= self.config.max_file_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Start a new current file, dropping the oldest rotated ones
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        fs::rename(
+            self.dir.join(CURRENT_FILE),
+            self.dir.join(format!("audit-{:020}.jsonl", self.seq)),
+        )?;
+        let files = rotated_files(&self.dir)?;
+        let excess = files.len().saturating_sub(self.config.retain_files);
+        for (_, path) in &files[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+    use tempfile::tempdir;
+
+    fn request_id() -> RequestId {
+        RequestId("req-1".to_string())
+    }
+
+    async fn log_events(log: &AuditLog, count: usize) {
+        for i in 0..count {
+            log.record(
+                Audit::new(AuditEvent::LoginFailure, &request_id()).subject(&format!("u{}", i)),
+            );
+        }
+        log.flush().await;
+    }
+
+    #[tokio::test]
+    async fn test_entries_page_by_seq() {
+        let dir = tempdir().unwrap();
+        let log =
+            AuditLog::start(dir.path().join("audit"), AuditConfig::default(), vec![1]).unwrap();
+        log_events(&log, 5).await;
+
+        let first = log
+            .read(&AuditQuery {
+                limit: Some(2),
+                ..Default::default()
+            })
+            .unwrap();
+        let seqs: Vec<u64> = first.entries.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, [1, 2]);
+        assert_eq!(first.next_after, Some(2));
+
+        let rest = log
+            .read(&AuditQuery {
+                after: first.next_after,
+                limit: Some(10),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(rest.entries.len(), 3);
+        assert_eq!(rest.entries[0].subject.as_deref(), Some("u2"));
+        assert_eq!(rest.next_after, None);
+
+        let future = log
+            .read(&AuditQuery {
+                since: Some(Utc::now().timestamp() + 60),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(future.entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rotation_keeps_newest_files_and_numbering() {
+        let dir = tempdir().unwrap();
+        let audit_dir = dir.path().join("audit");
+        let config = AuditConfig {
+            max_file_bytes: 1,
+            retain_files: 2,
+        };
+        let log = AuditLog::start(audit_dir.clone(), config.clone(), vec![1]).unwrap();
+        // Every entry fills a file
+        log_events(&log, 4).await;
+        let kept: Vec<u64> = rotated_files(&audit_dir)
+            .unwrap()
+            .into_iter()
+            .map(|(last, _)| last)
+            .collect();
+        assert_eq!(kept, [3, 4]);
+
+        // A restarted writer carries on from the last seq
+        let log = AuditLog::start(audit_dir, AuditConfig::default(), vec![1]).unwrap();
+        log_events(&log, 1).await;
+        let page = log.read(&AuditQuery::default()).unwrap();
+        let seqs: Vec<u64> = page.entries.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, [3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_source_ips_are_hashed() {
+        let dir = tempdir().unwrap();
+        let log = AuditLog::start(
+            dir.path().join("audit"),
+            AuditConfig::default(),
+            vec![7; 32],
+        )
+        .unwrap();
+        let ip: IpAddr = "203.0.113.9".parse().unwrap();
+        for _ in 0..2 {
+            log.record(Audit::new(AuditEvent::LoginFailure, &request_id()).ip(ip));
+        }
+        log.record(
+            Audit::new(AuditEvent::LoginFailure, &request_id())
+                .ip(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED)),
+        );
+        log.flush().await;
+
+        let entries = log.read(&AuditQuery::default()).unwrap().entries;
+        let hash = entries[0].source_ip.clone().unwrap();
+        assert_eq!(entries[1].source_ip.as_ref(), Some(&hash));
+        assert!(!hash.contains("203.0.113.9"));
+        assert_eq!(entries[2].source_ip, None);
+        let raw = fs::read_to_string(dir.path().join("audit").join(CURRENT_FILE)).unwrap();
+        assert!(!raw.contains("203.0.113"));
+    }
+
+    #[tokio::test]
+    async fn test_request_id_from_header_or_generated() {
+        let (mut parts, _) = Request::builder()
+            .header(REQUEST_ID_HEADER, "abc-123")
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert_eq!(RequestId::from_parts(&mut parts).0, "abc-123");
+
+        let (mut parts, _) = Request::builder()
+            .header(REQUEST_ID_HEADER, "bad id")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let generated = RequestId::from_parts(&mut parts);
+        assert_ne!(generated.0, "bad id");
+        // The same request keeps its id
+        assert_eq!(RequestId::from_parts(&mut parts), generated);
+    }
+}
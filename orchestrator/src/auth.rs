@@ -6,53 +6,329 @@
 //!
 //! 1. On first run, a random JWT secret is generated and stored securely
 //! 2. An admin password hash is stored (initially must be set via CLI or registration endpoint)
-//! 3. Clients call `/auth/login` with password to get a JWT token
-//! 4. All subsequent requests include `Authorization: Bearer <token>` header
-//! 5. WebSocket connections pass token via `?token=<jwt>` query param
+//! 3. Clients call `/auth/login` with username and password to get a JWT token
+//! 4. All subsequent requests include `Authorization: Bearer <token>` header;
+//!    scripts can send an API key (`Bearer cp_live_…`) instead, and browsers
+//!    can use a session cookie from `/auth/login?cookie=true` (see `session_cookies`)
+//! 5. WebSocket connections pass a one-time ticket from `/auth/ws-ticket` via
+//!    the `?ticket=` query param (or, deprecated, the token via `?token=`)
+//!
+//! Handlers get the caller by taking [`Claims`], [`AdminClaims`] or
+//! [`OptionalClaims`] as an argument. Route groups check the token's scopes
+//! with the [`require_scope`] layer; see `scopes`.
 //!
 //! # Endpoints
 //!
 //! - `POST /auth/login` - Authenticate and get JWT token (public)
 //! - `POST /auth/register` - Register admin user (disabled by default, enable via ENABLE_REGISTRATION=true)
-//! - `POST /auth/refresh` - Refresh an existing JWT token (requires auth)
+//! - `POST /api/auth/refresh` - Exchange a refresh token for new tokens (public; the refresh token is the credential)
+//! - `POST /auth/change-password` - Change the admin password (requires an access token)
+//! - `POST /auth/logout` - Revoke the presented token and its refresh token (requires auth)
+//! - `POST /auth/logout-all` - Revoke every token issued to the caller so far (requires auth)
+//! - `GET /auth/sessions`, `DELETE /auth/sessions/:id` - List and end login sessions (own, or
+//!   anyone's for admins)
+//! - `GET/POST /auth/users`, `DELETE /auth/users/:name`, `POST /auth/users/:name/password` -
+//!   Manage accounts (admin only)
+//! - `GET/POST /auth/api-keys`, `DELETE /auth/api-keys/:id` - Manage API keys (admin only)
+//! - `POST /auth/totp/setup`, `POST /auth/totp/confirm` - Turn on two-factor login (requires auth)
+//! - `POST /auth/totp/verify` - Exchange an `mfa_token` and a code for tokens (public)
+//! - `POST /auth/ws-ticket` - Get a one-time ticket for a WebSocket connection (requires auth)
+//! - `POST /auth/rotate-secret` - Start signing tokens with a new key (admin only)
+//! - `POST /auth/reload` - Re-read auth state changed on disk, e.g. by the CLI (admin only)
+//! - `GET /auth/jwks` - Public keys for verifying EdDSA-signed tokens (public)
+//! - `GET /auth/audit` - Read the audit log of auth events (admin only)
 //! - `GET /auth/status` - Check auth configuration status (public)
 
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
+    Algorithm, Argon2, Params, Version,
 };
 use axum::{
-    extract::{Request, State},
-    http::{header, StatusCode},
+    async_trait,
+    extract::{FromRequestParts, Path as UrlPath, Query, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue, Method, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
     Json,
 };
 use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
 use chrono::Utc;
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{decode, decode_header, encode, errors::ErrorKind, Header, Validation};
+use once_cell::sync::Lazy;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Display,
     fs,
+    net::{IpAddr, Ipv4Addr},
+    ops::RangeInclusive,
     path::{Path, PathBuf},
-    sync::Arc,
+    str::FromStr,
+    sync::{Arc, Once},
+    time::Instant,
 };
 use thiserror::Error;
+use uuid::Uuid;
 
+use crate::api_keys::{ApiKeyInfo, ApiKeyStore, API_KEY_PREFIX, API_KEY_TOKEN_TYPE};
+use crate::audit::{Audit, AuditEvent, AuditPage, AuditQuery, RequestId};
+use crate::auth_body::AuthJson;
+use crate::auth_files::{self, write_private_atomic, Source, Status};
+use crate::auth_metrics::AuthMetrics;
+use crate::auth_reload;
+use crate::auth_store::{self, AuthSnapshot, AuthStore, SqliteAuthStore, AUDIT_IP_KEY, JWT_SECRET};
+use crate::bootstrap::{self, BootstrapToken};
+use crate::client_ip::ClientIp;
+use crate::denylist::TokenDenylist;
+use crate::devices::{
+    self, ApproveDeviceRequest, Device, DeviceChallenge, DeviceLoginRequest, DeviceStore,
+};
+use crate::introspection::{IntrospectRequest, Introspection};
+use crate::keyring::{JwkSet, JwtKey, JwtKeyInfo, JwtKeyring, KeyAlgorithm};
+use crate::master_key::{self, MasterKey, SealedStore};
+use crate::password_policy::{PasswordPolicy, PasswordViolation};
+use crate::scopes::{self, ScopeInfo};
+use crate::service_tokens::{self, SERVICE_TOKEN_TYPE};
+use crate::session_cookies::{self, CookieOption, SESSION_COOKIE};
+use crate::sessions::{self, Session, SessionClient, SessionPage, SessionQuery};
+use crate::totp::{self, TotpCipher, TotpSetup, TotpState};
+use crate::users::{self, Role, User, UserInfo, UserStore, DEFAULT_USERNAME};
+use crate::ws_tickets::WsTicket;
 use crate::AppState;
 
 // === Configuration ===
 
-/// JWT token expiration time in hours
-const JWT_EXPIRATION_HOURS: i64 = 24;
+/// Time to enter a two-factor code after the password, in minutes
+const MFA_TOKEN_EXPIRATION_MINUTES: i64 = 5;
 
-/// Refresh token expiration time in days
-const REFRESH_TOKEN_EXPIRATION_DAYS: i64 = 7;
+/// `token_type` of the intermediate token returned when a code is needed
+const MFA_TOKEN_TYPE: &str = "mfa";
 
 /// JWT secret length in bytes (256 bits)
 const JWT_SECRET_LENGTH: usize = 32;
 
+/// Issuer and audience a token is minted with and checked against
+///
+/// Overridable through the environment so orchestrators sharing a secret in a
+/// federated setup can keep their tokens apart:
+/// - `JWT_ISSUER` - `iss` of issued tokens (default `claw-pen-orchestrator`)
+/// - `JWT_AUDIENCE` - `aud` of issued tokens (default `claw-pen-api`)
+/// - `JWT_LEEWAY_SECS` - clock skew allowed on `exp` and `nbf` (default 30)
+/// - `JWT_ACCEPT_LEGACY_TOKENS` - still accept tokens without `iss` and `aud`,
+///   issued before they were added (default `true`; turn off once those have
+///   expired)
+/// - `WS_ACCEPT_QUERY_TOKENS` - still accept access tokens and API keys in
+///   `?token=` on WebSocket upgrades instead of a ticket (default `true`;
+///   deprecated, turn off once clients use `/auth/ws-ticket`)
+#[derive(Debug, Clone)]
+pub struct TokenConfig {
+    pub issuer: String,
+    pub audience: String,
+    pub leeway_secs: u64,
+    pub accept_legacy: bool,
+    pub accept_ws_query_token: bool,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        Self {
+            issuer: "claw-pen-orchestrator".to_string(),
+            audience: "claw-pen-api".to_string(),
+            leeway_secs: 30,
+            accept_legacy: true,
+            accept_ws_query_token: true,
+        }
+    }
+}
+
+impl TokenConfig {
+    fn from_lookup(lookup: &impl Fn(&str) -> Option<String>) -> Self {
+        let default = Self::default();
+        let var = |name: &str| {
+            lookup(name)
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        Self {
+            issuer: var("JWT_ISSUER").unwrap_or(default.issuer),
+            audience: var("JWT_AUDIENCE").unwrap_or(default.audience),
+            leeway_secs: var("JWT_LEEWAY_SECS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default.leeway_secs),
+            accept_legacy: var("JWT_ACCEPT_LEGACY_TOKENS")
+                .map(|v| v.to_lowercase() == "true")
+                .unwrap_or(default.accept_legacy),
+            accept_ws_query_token: var("WS_ACCEPT_QUERY_TOKENS")
+                .map(|v| v.to_lowercase() == "true")
+                .unwrap_or(default.accept_ws_query_token),
+        }
+    }
+}
+
+/// Token lifetimes, password hashing cost and password policy
+///
+/// Read from the environment; values outside the ranges below fail startup:
+/// - `JWT_ACCESS_TTL_SECS` - access token lifetime (default 86400, 60 to 604800)
+/// - `JWT_REFRESH_TTL_SECS` - refresh token lifetime (default 604800, up to
+///   7776000, and no shorter than the access token's)
+/// - `ARGON2_MEMORY_KIB` - Argon2 memory cost (default 19456, 8192 to 4194304)
+/// - `ARGON2_ITERATIONS` - Argon2 time cost (default 2, 1 to 100)
+/// - `ARGON2_PARALLELISM` - Argon2 lanes (default 1, 1 to 64)
+/// - `JWT_ALGORITHM` - `HS256` (default) or `EdDSA`, to sign with an Ed25519
+///   key whose public half is published at `/auth/jwks`
+/// - `PASSWORD_MIN_LENGTH` - shortest new password (default 12, 8 to 128)
+/// - `PASSWORD_MIN_ENTROPY_BITS` - least estimated entropy of a new password
+///   (default 50, 0 to 128)
+///
+/// Passwords hashed with other Argon2 parameters are re-hashed on the next
+/// successful login; a change of algorithm rotates the signing key.
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    pub access_ttl_secs: i64,
+    pub refresh_ttl_secs: i64,
+    pub argon2: Params,
+    pub algorithm: KeyAlgorithm,
+    pub token: TokenConfig,
+    pub password: PasswordPolicy,
+    /// Refuse to start when auth files are readable by others, instead of
+    /// tightening them
+    pub strict_permissions: bool,
+}
+
+impl AuthConfig {
+    /// When keys replaced at `now` stop verifying: once every token they
+    /// could have signed has expired
+    fn key_retirement(&self, now: i64) -> i64 {
+        now + self.refresh_ttl_secs + self.token.leeway_secs as i64
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            access_ttl_secs: 24 * 3600,
+            refresh_ttl_secs: 7 * 24 * 3600,
+            argon2: Params::DEFAULT,
+            algorithm: KeyAlgorithm::default(),
+            token: TokenConfig::default(),
+            password: PasswordPolicy::default(),
+            strict_permissions: false,
+        }
+    }
+}
+
+/// Parse variable `name` if set, failing unless it is within `range`
+fn config_value<T>(
+    lookup: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    default: T,
+    range: RangeInclusive<T>,
+) -> Result<T, AuthError>
+where
+    T: FromStr + PartialOrd + Display,
+{
+    let Some(raw) = lookup(name).filter(|v| !v.trim().is_empty()) else {
+        return Ok(default);
+    };
+    raw.trim()
+        .parse()
+        .ok()
+        .filter(|v| range.contains(v))
+        .ok_or_else(|| {
+            AuthError::InvalidConfig(format!(
+                "{} must be a whole number from {} to {}, got {:?}",
+                name,
+                range.start(),
+                range.end(),
+                raw
+            ))
+        })
+}
+
+impl AuthConfig {
+    pub fn from_env() -> Result<Self, AuthError> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// Build the settings from variables looked up by name
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, AuthError> {
+        let default = Self::default();
+        let access_ttl_secs = config_value(
+            &lookup,
+            "JWT_ACCESS_TTL_SECS",
+            default.access_ttl_secs,
+            60..=7 * 24 * 3600,
+        )?;
+        let refresh_ttl_secs = config_value(
+            &lookup,
+            "JWT_REFRESH_TTL_SECS",
+            default.refresh_ttl_secs,
+            60..=90 * 24 * 3600,
+        )?;
+        if refresh_ttl_secs < access_ttl_secs {
+            return Err(AuthError::InvalidConfig(format!(
+                "JWT_REFRESH_TTL_SECS ({}) must not be shorter than JWT_ACCESS_TTL_SECS ({})",
+                refresh_ttl_secs, access_ttl_secs
+            )));
+        }
+
+        let memory = config_value(
+            &lookup,
+            "ARGON2_MEMORY_KIB",
+            default.argon2.m_cost(),
+            8192..=4 * 1024 * 1024,
+        )?;
+        let iterations = config_value(
+            &lookup,
+            "ARGON2_ITERATIONS",
+            default.argon2.t_cost(),
+            1..=100,
+        )?;
+        let parallelism = config_value(
+            &lookup,
+            "ARGON2_PARALLELISM",
+            default.argon2.p_cost(),
+            1..=64,
+        )?;
+        let argon2 = Params::new(memory, iterations, parallelism, None)
+            .map_err(|e| AuthError::InvalidConfig(format!("Invalid Argon2 parameters: {}", e)))?;
+
+        let algorithm = match lookup("JWT_ALGORITHM").filter(|v| !v.trim().is_empty()) {
+            Some(name) => name.trim().parse()?,
+            None => default.algorithm,
+        };
+
+        let password = PasswordPolicy {
+            min_length: config_value(
+                &lookup,
+                "PASSWORD_MIN_LENGTH",
+                default.password.min_length,
+                8..=128,
+            )?,
+            min_entropy_bits: config_value(
+                &lookup,
+                "PASSWORD_MIN_ENTROPY_BITS",
+                default.password.min_entropy_bits,
+                0..=128,
+            )?,
+        };
+
+        Ok(Self {
+            access_ttl_secs,
+            refresh_ttl_secs,
+            argon2,
+            algorithm,
+            token: TokenConfig::from_lookup(&lookup),
+            password,
+            strict_permissions: lookup("STRICT_AUTH_PERMISSIONS")
+                .map(|v| v.trim().to_lowercase() == "true")
+                .unwrap_or(default.strict_permissions),
+        })
+    }
+}
+
 // === Error Types ===
 
 #[derive(Debug, Error)]
@@ -66,12 +342,108 @@ pub enum AuthError {
     #[error("Token expired")]
     TokenExpired,
 
+    #[error("Token was issued before the password changed")]
+    TokenSuperseded,
+
     #[error("Registration is disabled")]
     RegistrationDisabled,
 
+    #[error("Registration is not allowed from this network")]
+    RegistrationNetworkDenied,
+
+    #[error("Missing, wrong or expired bootstrap token")]
+    InvalidBootstrapToken,
+
     #[error("User already exists")]
     UserAlreadyExists,
 
+    #[error("Current password is incorrect")]
+    WrongPassword,
+
+    #[error("Password is too weak: {}", describe_violations(.0))]
+    WeakPassword(Vec<PasswordViolation>),
+
+    #[error("Insufficient permissions")]
+    Forbidden,
+
+    #[error("Token lacks the {0} scope")]
+    MissingScope(String),
+
+    #[error("User not found")]
+    UserNotFound,
+
+    #[error("Cannot remove the last admin")]
+    LastAdmin,
+
+    #[error("Invalid username")]
+    InvalidUsername,
+
+    #[error("API key not found")]
+    ApiKeyNotFound,
+
+    #[error("Invalid API key request: {0}")]
+    InvalidApiKeyRequest(String),
+
+    #[error("Too many failed logins, retry in {retry_after}s")]
+    TooManyAttempts { retry_after: u64 },
+
+    #[error("Account temporarily locked, retry in {retry_after}s")]
+    AccountLocked { retry_after: u64 },
+
+    #[error("Too many requests, retry in {retry_after}s")]
+    TooManyRequests { retry_after: u64 },
+
+    #[error("Invalid two-factor code")]
+    InvalidTotpCode,
+
+    #[error("Device signature doesn't verify")]
+    InvalidDeviceSignature,
+
+    #[error("Device signature is too old or too far in the future")]
+    StaleDeviceSignature,
+
+    #[error("Unknown, used or expired device challenge")]
+    InvalidDeviceChallenge,
+
+    #[error("Device is not approved")]
+    DeviceNotApproved,
+
+    #[error("Device not found")]
+    DeviceNotFound,
+
+    #[error("Session not found")]
+    SessionNotFound,
+
+    #[error("Invalid device request: {0}")]
+    InvalidDeviceRequest(String),
+
+    #[error("Missing or wrong CSRF token")]
+    InvalidCsrfToken,
+
+    #[error("Service tokens only work on the agent API")]
+    ServiceTokenNotAllowed,
+
+    #[error("Request body is too large")]
+    PayloadTooLarge,
+
+    #[error("Invalid request body: {0}")]
+    InvalidBody(String),
+
+    #[error("Two-factor authentication is already enabled")]
+    TotpAlreadyEnabled,
+
+    #[error("Two-factor setup has not been started")]
+    TotpNotSetUp,
+
+    #[error("Encryption error: {0}")]
+    EncryptionError(String),
+
+    #[error("Invalid auth configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("Database error: {0}")]
+    DatabaseError(String),
+
     #[error("Password hash error: {0}")]
     HashError(String),
 
@@ -90,10 +462,21 @@ pub enum AuthError {
     #[error("Missing authorization header")]
     MissingAuthHeader,
 
+    #[error("Missing authentication token")]
+    MissingToken,
+
     #[error("Invalid authorization header format")]
     InvalidAuthHeaderFormat,
 }
 
+fn describe_violations(violations: &[PasswordViolation]) -> String {
+    violations
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
 impl From<argon2::password_hash::Error> for AuthError {
     fn from(err: argon2::password_hash::Error) -> Self {
         AuthError::HashError(err.to_string())
@@ -106,25 +489,291 @@ impl From<base64::DecodeError> for AuthError {
     }
 }
 
-impl IntoResponse for AuthError {
-    fn into_response(self) -> Response {
-        let (status, message) = match &self {
-            AuthError::InvalidCredentials => (StatusCode::UNAUTHORIZED, "Invalid credentials"),
-            AuthError::InvalidToken => (StatusCode::UNAUTHORIZED, "Invalid or expired token"),
-            AuthError::TokenExpired => (StatusCode::UNAUTHORIZED, "Token expired"),
-            AuthError::MissingAuthHeader => {
-                (StatusCode::UNAUTHORIZED, "Missing authorization header")
-            }
+/// Whether error responses use the old `{"error": "<message>"}` shape
+///
+/// Set `AUTH_LEGACY_ERROR_FORMAT=true` to keep it for clients that still
+/// match on the message; it will be removed in the next release.
+static LEGACY_ERROR_FORMAT: Lazy<bool> = Lazy::new(|| {
+    std::env::var("AUTH_LEGACY_ERROR_FORMAT")
+        .map(|v| v.to_lowercase() == "true")
+        .unwrap_or(false)
+});
+
+impl AuthError {
+    /// HTTP status, stable machine-readable code and client-facing message
+    ///
+    /// Internal errors share one code and message so their details stay in
+    /// the logs.
+    fn parts(&self) -> (StatusCode, &'static str, &str) {
+        match self {
+            AuthError::InvalidCredentials => (
+                StatusCode::UNAUTHORIZED,
+                "INVALID_CREDENTIALS",
+                "Invalid credentials",
+            ),
+            AuthError::InvalidToken => (
+                StatusCode::UNAUTHORIZED,
+                "TOKEN_INVALID",
+                "Invalid or expired token",
+            ),
+            AuthError::TokenExpired => (StatusCode::UNAUTHORIZED, "TOKEN_EXPIRED", "Token expired"),
+            AuthError::TokenSuperseded => (
+                StatusCode::UNAUTHORIZED,
+                "TOKEN_SUPERSEDED",
+                "Password changed since the token was issued; log in again",
+            ),
+            AuthError::JwtError(e) => match e.kind() {
+                ErrorKind::ExpiredSignature => {
+                    (StatusCode::UNAUTHORIZED, "TOKEN_EXPIRED", "Token expired")
+                }
+                ErrorKind::InvalidKeyFormat
+                | ErrorKind::InvalidRsaKey(_)
+                | ErrorKind::InvalidEcdsaKey
+                | ErrorKind::RsaFailedSigning
+                | ErrorKind::Crypto(_) => (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "INTERNAL_ERROR",
+                    "Internal server error",
+                ),
+                _ => (
+                    StatusCode::UNAUTHORIZED,
+                    "TOKEN_INVALID",
+                    "Invalid or expired token",
+                ),
+            },
+            AuthError::MissingAuthHeader => (
+                StatusCode::UNAUTHORIZED,
+                "MISSING_AUTH_HEADER",
+                "Missing authorization header",
+            ),
+            AuthError::MissingToken => (
+                StatusCode::UNAUTHORIZED,
+                "MISSING_TOKEN",
+                "Missing authentication token",
+            ),
             AuthError::InvalidAuthHeaderFormat => (
                 StatusCode::UNAUTHORIZED,
+                "INVALID_AUTH_HEADER",
                 "Invalid authorization header format",
             ),
-            AuthError::RegistrationDisabled => (StatusCode::FORBIDDEN, "Registration is disabled"),
-            AuthError::UserAlreadyExists => (StatusCode::CONFLICT, "User already exists"),
-            _ => (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error"),
+            AuthError::InvalidBootstrapToken => (
+                StatusCode::FORBIDDEN,
+                "BOOTSTRAP_TOKEN_INVALID",
+                "Missing, wrong or expired bootstrap token",
+            ),
+            AuthError::RegistrationDisabled => (
+                StatusCode::FORBIDDEN,
+                "REGISTRATION_DISABLED",
+                "Registration is disabled",
+            ),
+            AuthError::RegistrationNetworkDenied => (
+                StatusCode::FORBIDDEN,
+                "REGISTRATION_NETWORK_DENIED",
+                "Registration is not allowed from this network",
+            ),
+            AuthError::UserAlreadyExists => {
+                (StatusCode::CONFLICT, "USER_EXISTS", "User already exists")
+            }
+            AuthError::WrongPassword => (
+                StatusCode::FORBIDDEN,
+                "WRONG_PASSWORD",
+                "Current password is incorrect",
+            ),
+            AuthError::WeakPassword(_) => (
+                StatusCode::BAD_REQUEST,
+                "WEAK_PASSWORD",
+                "Password does not meet the password policy",
+            ),
+            AuthError::MissingScope(_) => (
+                StatusCode::FORBIDDEN,
+                "INSUFFICIENT_SCOPE",
+                "Token lacks a required scope",
+            ),
+            AuthError::Forbidden => (
+                StatusCode::FORBIDDEN,
+                "FORBIDDEN",
+                "Insufficient permissions",
+            ),
+            AuthError::UserNotFound => (StatusCode::NOT_FOUND, "USER_NOT_FOUND", "User not found"),
+            AuthError::LastAdmin => (
+                StatusCode::CONFLICT,
+                "LAST_ADMIN",
+                "Cannot remove the last admin",
+            ),
+            AuthError::InvalidUsername => (
+                StatusCode::BAD_REQUEST,
+                "INVALID_USERNAME",
+                "Username must be 1-32 lowercase letters, digits, '.', '_' or '-'",
+            ),
+            AuthError::ApiKeyNotFound => (
+                StatusCode::NOT_FOUND,
+                "API_KEY_NOT_FOUND",
+                "API key not found",
+            ),
+            AuthError::InvalidApiKeyRequest(message) => (
+                StatusCode::BAD_REQUEST,
+                "INVALID_API_KEY_REQUEST",
+                message.as_str(),
+            ),
+            AuthError::TooManyAttempts { .. } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "RATE_LIMITED",
+                "Too many failed logins",
+            ),
+            AuthError::AccountLocked { .. } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "ACCOUNT_LOCKED",
+                "Account temporarily locked",
+            ),
+            AuthError::TooManyRequests { .. } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "TOO_MANY_REQUESTS",
+                "Too many requests",
+            ),
+            AuthError::InvalidTotpCode => (
+                StatusCode::UNAUTHORIZED,
+                "INVALID_TOTP_CODE",
+                "Invalid two-factor code",
+            ),
+            AuthError::InvalidDeviceSignature => (
+                StatusCode::UNAUTHORIZED,
+                "DEVICE_SIGNATURE_INVALID",
+                "Device signature doesn't verify",
+            ),
+            AuthError::StaleDeviceSignature => (
+                StatusCode::UNAUTHORIZED,
+                "DEVICE_SIGNATURE_STALE",
+                "signedAt is too far from the server's clock",
+            ),
+            AuthError::InvalidDeviceChallenge => (
+                StatusCode::UNAUTHORIZED,
+                "DEVICE_CHALLENGE_INVALID",
+                "Unknown, used or expired device challenge",
+            ),
+            AuthError::DeviceNotApproved => (
+                StatusCode::FORBIDDEN,
+                "DEVICE_NOT_APPROVED",
+                "Device is unknown or was revoked",
+            ),
+            AuthError::DeviceNotFound => (
+                StatusCode::NOT_FOUND,
+                "DEVICE_NOT_FOUND",
+                "Device not found",
+            ),
+            AuthError::SessionNotFound => (
+                StatusCode::NOT_FOUND,
+                "SESSION_NOT_FOUND",
+                "Session not found",
+            ),
+            AuthError::InvalidDeviceRequest(message) => (
+                StatusCode::BAD_REQUEST,
+                "INVALID_DEVICE_REQUEST",
+                message.as_str(),
+            ),
+            AuthError::InvalidCsrfToken => (
+                StatusCode::FORBIDDEN,
+                "CSRF_TOKEN_INVALID",
+                "Missing or wrong CSRF token",
+            ),
+            AuthError::ServiceTokenNotAllowed => (
+                StatusCode::FORBIDDEN,
+                "SERVICE_TOKEN_NOT_ALLOWED",
+                "Service tokens only work on the agent API",
+            ),
+            AuthError::PayloadTooLarge => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "PAYLOAD_TOO_LARGE",
+                "Request body is too large",
+            ),
+            AuthError::InvalidBody(message) => {
+                (StatusCode::BAD_REQUEST, "INVALID_BODY", message.as_str())
+            }
+            AuthError::TotpAlreadyEnabled => (
+                StatusCode::CONFLICT,
+                "TOTP_ALREADY_ENABLED",
+                "Two-factor authentication is already enabled",
+            ),
+            AuthError::TotpNotSetUp => (
+                StatusCode::CONFLICT,
+                "TOTP_NOT_SET_UP",
+                "Two-factor setup has not been started",
+            ),
+            AuthError::EncryptionError(_)
+            | AuthError::InvalidConfig(_)
+            | AuthError::DatabaseError(_)
+            | AuthError::HashError(_)
+            | AuthError::IoError(_)
+            | AuthError::JsonError(_)
+            | AuthError::Base64Error(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "INTERNAL_ERROR",
+                "Internal server error",
+            ),
+        }
+    }
+
+    /// Stable code clients can match on instead of the message
+    pub fn code(&self) -> &'static str {
+        self.parts().1
+    }
+
+    /// Whether the same request may succeed later without changes
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            AuthError::TooManyAttempts { .. }
+                | AuthError::AccountLocked { .. }
+                | AuthError::TooManyRequests { .. }
+        )
+    }
+
+    /// Response body, `{"error": {"code", "message", "retryable"}}`, or the
+    /// old `{"error": "<message>"}` in the legacy format
+    fn body(&self, legacy: bool) -> serde_json::Value {
+        let (_, code, message) = self.parts();
+        let mut body = if legacy {
+            serde_json::json!({ "error": message })
+        } else {
+            serde_json::json!({
+                "error": {
+                    "code": code,
+                    "message": message,
+                    "retryable": self.retryable(),
+                }
+            })
+        };
+        let detail = match self {
+            AuthError::WeakPassword(violations) => {
+                Some(("violations", serde_json::json!(violations)))
+            }
+            AuthError::MissingScope(scope) => Some(("scope", serde_json::json!(scope))),
+            _ => None,
         };
+        if let Some((key, value)) = detail {
+            let target = if legacy {
+                &mut body
+            } else {
+                &mut body["error"]
+            };
+            target[key] = value;
+        }
+        body
+    }
+}
 
-        (status, Json(serde_json::json!({ "error": message }))).into_response()
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let status = self.parts().0;
+        let mut response = (status, Json(self.body(*LEGACY_ERROR_FORMAT))).into_response();
+        if let AuthError::TooManyAttempts { retry_after }
+        | AuthError::AccountLocked { retry_after }
+        | AuthError::TooManyRequests { retry_after } = self
+        {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
     }
 }
 
@@ -132,25 +781,162 @@ impl IntoResponse for AuthError {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
-    /// Subject (user identifier) - "admin" for single-user mode
+    /// Subject: the username
     pub sub: String,
+    /// Role of the account when the token was issued
+    #[serde(default = "legacy_role")]
+    pub role: Role,
     /// Issued at timestamp
     pub iat: i64,
     /// Expiration timestamp
     pub exp: i64,
-    /// Token type: "access" or "refresh"
+    /// Token type: "access", "refresh", "mfa" while a two-factor code is
+    /// pending, or "api_key" for API keys
     #[serde(rename = "type")]
     pub token_type: String,
+    /// Password version the token was issued under; refresh tokens from
+    /// before a password change are rejected
+    #[serde(rename = "pwv", default)]
+    pub password_version: u64,
+    /// Unique token id, recorded in the denylist on logout
+    #[serde(default)]
+    pub jti: String,
+    /// On access tokens, the jti of the refresh token issued alongside
+    #[serde(rename = "rjti", default, skip_serializing_if = "Option::is_none")]
+    pub refresh_jti: Option<String>,
+    /// Subject's token generation; `logout-all` bumps it
+    #[serde(rename = "gen", default)]
+    pub generation: u64,
+    /// What the token may do: its role's scopes, or the subset an API key
+    /// was limited to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    /// Issuer; set when the token is signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    /// Audience; set when the token is signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// Not before; set to `iat` when the token is signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>,
+    /// Device the token was issued to by a device login; it stops working
+    /// when the device is revoked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// Session the token belongs to; refreshing keeps it, and ending the
+    /// session revokes every token with it
+    #[serde(rename = "sid", default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    /// On service tokens, the agent they were minted for
+    #[serde(rename = "agent", default, skip_serializing_if = "Option::is_none")]
+    pub agent_id: Option<String>,
+}
+
+/// Tokens from before accounts had roles all belonged to the admin
+fn legacy_role() -> Role {
+    Role::Admin
+}
+
+impl Claims {
+    /// Fail with 403 unless the token's role is at least `role`
+    pub fn require_role(&self, role: Role) -> Result<(), AuthError> {
+        if self.role >= role {
+            Ok(())
+        } else {
+            Err(AuthError::Forbidden)
+        }
+    }
+
+    /// Whether the token grants `scope`; tokens from before scopes get
+    /// their role's
+    pub fn has_scope(&self, scope: &str) -> bool {
+        if self.scopes.is_empty() && self.token_type != API_KEY_TOKEN_TYPE {
+            return scopes::for_role(self.role).contains(&scope);
+        }
+        self.scopes.iter().any(|s| s == scope)
+    }
+
+    /// Fail with 403 naming `scope` unless the token grants it
+    pub fn require_scope(&self, scope: &str) -> Result<(), AuthError> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(AuthError::MissingScope(scope.to_string()))
+        }
+    }
+
+    /// Whether the token would pass for [`AdminClaims`]
+    fn is_admin(&self) -> bool {
+        self.require_role(Role::Admin).is_ok() && self.has_scope(scopes::AUTH_ADMIN)
+    }
+}
+
+fn default_username() -> String {
+    DEFAULT_USERNAME.to_string()
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginRequest {
+    /// Defaults to "admin" for clients from before multiple accounts
+    #[serde(default = "default_username")]
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateUserRequest {
+    pub username: String,
+    pub password: String,
+    pub role: Role,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetPasswordRequest {
     pub password: String,
 }
 
+fn default_api_key_role() -> Role {
+    Role::Operator
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    #[serde(default = "default_api_key_role")]
+    pub role: Role,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Unix timestamp; the key never expires if unset
+    pub expires_at: Option<i64>,
+}
+
+/// Response of `POST /auth/api-keys`; the secret is never shown again
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub key: ApiKeyInfo,
+    pub secret: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegisterRequest {
+    /// Account to create; the first admin is always `admin`
+    #[serde(default)]
+    pub username: Option<String>,
     pub password: String,
+    /// The token from `bootstrap_token` in the data directory; needed for the
+    /// first admin
+    #[serde(default)]
+    pub bootstrap_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -161,214 +947,1289 @@ pub struct TokenResponse {
     pub expires_in: i64,
 }
 
+/// Response of `/auth/login`: tokens, or a challenge for accounts with two-factor login
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Tokens(TokenResponse),
+    MfaRequired(MfaChallenge),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaChallenge {
+    /// Exchanged with a code at `/auth/totp/verify`
+    pub mfa_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpVerifyRequest {
+    pub mfa_token: String,
+    /// A 6-digit code or an unused recovery code
+    pub code: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthStatus {
     pub auth_enabled: bool,
     pub has_admin: bool,
     pub registration_enabled: bool,
+    /// Whether registering the first admin needs the bootstrap token
+    #[serde(default)]
+    pub bootstrap_required: bool,
+    /// Whether the caller's account has two-factor login on; only present
+    /// when the request carries a valid access token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_enabled: Option<bool>,
+    /// Every scope a token or API key can have
+    #[serde(default)]
+    pub scopes: Vec<ScopeInfo>,
 }
 
 // === Auth Manager ===
 
 /// Manages authentication state and credentials
 pub struct AuthManager {
-    /// Path to the auth data directory
-    data_dir: PathBuf,
-    /// JWT secret for encoding/decoding
-    jwt_secret: Vec<u8>,
-    /// Hashed admin password
-    admin_password_hash: Option<String>,
+    /// Where everything below is persisted
+    store: Arc<dyn AuthStore>,
+    /// Keys for signing and verifying tokens
+    keyring: JwtKeyring,
+    /// Accounts and their password hashes
+    users: UserStore,
     /// Whether registration is enabled
     registration_enabled: bool,
+    /// Logged-out token ids
+    denylist: TokenDenylist,
+    /// Token generation per subject
+    token_generations: HashMap<String, u64>,
+    /// Token lifetimes and checks, and password hashing cost
+    config: AuthConfig,
+    /// Hashed API keys
+    api_keys: ApiKeyStore,
+    /// Devices approved for device logins
+    devices: DeviceStore,
+    /// Encrypts TOTP secrets at rest
+    totp: TotpCipher,
+    /// Counters for logins, validations and refreshes
+    metrics: Arc<AuthMetrics>,
+    /// Hash with the configured parameters that logins for unknown accounts
+    /// are checked against
+    dummy_hash: String,
+    /// Token the first admin registers with, while there is none
+    bootstrap: Option<BootstrapToken>,
 }
 
-impl AuthManager {
-    /// Create a new AuthManager, initializing JWT secret if needed
-    pub fn new(data_dir: &PathBuf) -> Result<Self, AuthError> {
-        // Ensure data directory exists
-        fs::create_dir_all(data_dir)?;
+#[cfg(test)]
+thread_local! {
+    /// Argon2 verifications run on this thread, for tests
+    static VERIFICATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
 
-        let secret_path = data_dir.join("jwt_secret");
-        let password_path = data_dir.join("admin_password");
+/// Whether `ENABLE_REGISTRATION` turns the registration endpoint on
+fn registration_enabled_from_env() -> bool {
+    std::env::var("ENABLE_REGISTRATION")
+        .map(|v| v.to_lowercase() == "true")
+        .unwrap_or(false)
+}
 
-        // Load or generate JWT secret
-        let jwt_secret = if secret_path.exists() {
-            let secret_b64 = fs::read_to_string(&secret_path)?;
-            BASE64_STANDARD.decode(secret_b64.trim())?
-        } else {
-            let mut secret = vec![0u8; JWT_SECRET_LENGTH];
-            OsRng.fill_bytes(&mut secret);
+/// What `AuthManager::reload` found changed
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReloadSummary {
+    pub users_added: Vec<String>,
+    pub users_removed: Vec<String>,
+    /// Accounts whose password, role, disabled flag or second factor changed
+    pub users_changed: Vec<String>,
+    pub keys_added: Vec<String>,
+    pub keys_removed: Vec<String>,
+    /// The new registration flag, if it changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registration_enabled: Option<bool>,
+}
 
-            // Store with restrictive permissions (0600)
-            let secret_b64 = BASE64_STANDARD.encode(&secret);
-            fs::write(&secret_path, &secret_b64)?;
+impl ReloadSummary {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
 
-            // Set restrictive permissions on Unix
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::PermissionsExt;
-                fs::set_permissions(&secret_path, fs::Permissions::from_mode(0o600))?;
+impl Display for ReloadSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no changes");
+        }
+        let mut parts = Vec::new();
+        for (label, names) in [
+            ("users added", &self.users_added),
+            ("users removed", &self.users_removed),
+            ("users changed", &self.users_changed),
+            ("signing keys added", &self.keys_added),
+            ("signing keys removed", &self.keys_removed),
+        ] {
+            if !names.is_empty() {
+                parts.push(format!("{}: {}", label, names.join(", ")));
             }
+        }
+        if let Some(enabled) = self.registration_enabled {
+            parts.push(format!(
+                "registration {}",
+                if enabled { "enabled" } else { "disabled" }
+            ));
+        }
+        write!(f, "{}", parts.join("; "))
+    }
+}
 
-            tracing::info!("Generated new JWT secret and stored at {:?}", secret_path);
-            secret
-        };
+/// Names in `new` but not `old`, and in `old` but not `new`
+fn added_and_removed(
+    old: &std::collections::BTreeSet<String>,
+    new: &std::collections::BTreeSet<String>,
+) -> (Vec<String>, Vec<String>) {
+    (
+        new.difference(old).cloned().collect(),
+        old.difference(new).cloned().collect(),
+    )
+}
 
-        // Load admin password hash if exists
-        let admin_password_hash = if password_path.exists() {
-            let hash = fs::read_to_string(&password_path)?;
-            Some(hash.trim().to_string())
-        } else {
-            None
+fn hash_password(password: &str, params: &Params) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string(),
+    )
+}
+
+impl AuthManager {
+    /// Create a new AuthManager, initializing JWT secret if needed
+    pub fn new(data_dir: &Path) -> Result<Self, AuthError> {
+        Self::with_config(data_dir, AuthConfig::from_env()?)
+    }
+
+    /// Like `new`, with settings given instead of read from the environment
+    pub fn with_config(data_dir: &Path, config: AuthConfig) -> Result<Self, AuthError> {
+        auth_files::enforce_permissions(data_dir, config.strict_permissions)?;
+        Self::with_store(auth_store::open(data_dir)?, config)
+    }
+
+    /// Like `with_config`, keeping state in `store` instead of the data directory
+    ///
+    /// Everything is read from the store once here and written through on
+    /// every change.
+    pub fn with_store(store: Arc<dyn AuthStore>, config: AuthConfig) -> Result<Self, AuthError> {
+        // Load or generate JWT secret
+        let jwt_secret = match store.secret(JWT_SECRET)? {
+            Some(secret_b64) => BASE64_STANDARD.decode(secret_b64.trim())?,
+            None => {
+                let mut secret = vec![0u8; JWT_SECRET_LENGTH];
+                OsRng.fill_bytes(&mut secret);
+                store.set_secret(JWT_SECRET, &BASE64_STANDARD.encode(&secret))?;
+                tracing::info!("Generated new JWT secret");
+                secret
+            }
         };
 
-        // Check if registration is enabled via environment variable
-        let registration_enabled = std::env::var("ENABLE_REGISTRATION")
-            .map(|v| v.to_lowercase() == "true")
-            .unwrap_or(false);
+        let users = UserStore::load(store.clone())?;
+
+        let now = Utc::now().timestamp();
+        let denylist = TokenDenylist::load(store.clone(), now)?;
+        // Signing keys rotate; API key hashes and TOTP encryption stay keyed by
+        // the original secret
+        let mut keyring = JwtKeyring::load(store.clone(), &jwt_secret, now)?;
+        if keyring.signing_key().algorithm != config.algorithm {
+            let key = keyring.rotate(config.algorithm, config.key_retirement(now), now)?;
+            tracing::info!(
+                "Signing algorithm changed to {:?}, now signing with {}",
+                key.algorithm,
+                key.kid
+            );
+        }
+        let api_keys = ApiKeyStore::load(store.clone(), &jwt_secret)?;
+        let devices = DeviceStore::load(store.clone())?;
+        let token_generations = store.token_generations()?;
 
+        // Logins for unknown accounts check this, with the configured cost
+        let dummy_hash = hash_password(&Uuid::new_v4().to_string(), &config.argon2)?;
+
+        let registration_enabled = registration_enabled_from_env();
         if registration_enabled {
             tracing::warn!("⚠️  Registration endpoint is ENABLED. Disable in production!");
         }
 
         Ok(Self {
-            data_dir: data_dir.clone(),
-            jwt_secret,
-            admin_password_hash,
+            store,
+            users,
             registration_enabled,
+            denylist,
+            token_generations,
+            config,
+            api_keys,
+            devices,
+            totp: TotpCipher::new(&jwt_secret),
+            keyring,
+            metrics: Arc::new(AuthMetrics::new()),
+            dummy_hash,
+            bootstrap: None,
         })
     }
 
+    /// Re-read accounts, signing keys, revocations, API keys, devices and the
+    /// registration flag, e.g. after `--set-password` changed the store under
+    /// a running server. Nothing changes unless everything reads and checks
+    /// out.
+    pub fn reload(&mut self) -> Result<ReloadSummary, AuthError> {
+        let now = Utc::now().timestamp();
+        let jwt_secret = match self.store.secret(JWT_SECRET)? {
+            Some(secret_b64) => BASE64_STANDARD.decode(secret_b64.trim())?,
+            None => {
+                return Err(AuthError::InvalidConfig(
+                    "The auth store has no JWT secret".to_string(),
+                ))
+            }
+        };
+        let users = UserStore::load(self.store.clone())?;
+        for user in users.iter() {
+            users::validate_username(&user.username)?;
+            PasswordHash::new(&user.password_hash)?;
+        }
+        let keyring = JwtKeyring::load(self.store.clone(), &jwt_secret, now)?;
+        keyring.check()?;
+        let denylist = TokenDenylist::load(self.store.clone(), now)?;
+        let token_generations = self.store.token_generations()?;
+        let api_keys = ApiKeyStore::load(self.store.clone(), &jwt_secret)?;
+        let devices = DeviceStore::load(self.store.clone())?;
+        let registration_enabled = registration_enabled_from_env();
+
+        let names = |store: &UserStore| store.iter().map(|u| u.username.clone()).collect();
+        let (users_added, users_removed) = added_and_removed(&names(&self.users), &names(&users));
+        let users_changed = users
+            .iter()
+            .filter(|new| {
+                self.users.get(&new.username).is_some_and(|old| {
+                    serde_json::to_value(old).ok() != serde_json::to_value(new).ok()
+                })
+            })
+            .map(|u| u.username.clone())
+            .collect();
+        let kids = |keyring: &JwtKeyring| keyring.list().into_iter().map(|k| k.kid).collect();
+        let (keys_added, keys_removed) = added_and_removed(&kids(&self.keyring), &kids(&keyring));
+        let summary = ReloadSummary {
+            users_added,
+            users_removed,
+            users_changed,
+            keys_added,
+            keys_removed,
+            registration_enabled: (registration_enabled != self.registration_enabled)
+                .then_some(registration_enabled),
+        };
+
+        self.users = users;
+        self.keyring = keyring;
+        self.denylist = denylist;
+        self.token_generations = token_generations;
+        self.api_keys = api_keys;
+        self.devices = devices;
+        self.registration_enabled = registration_enabled;
+        self.expire_bootstrap(now);
+        Ok(summary)
+    }
+
+    /// The metrics this manager records into, for `GET /metrics`
+    pub fn metrics(&self) -> Arc<AuthMetrics> {
+        self.metrics.clone()
+    }
+
+    /// How many logged-out tokens haven't expired yet
+    pub fn revoked_count(&self) -> usize {
+        self.denylist.active(Utc::now().timestamp())
+    }
+
     /// Check if an admin user exists
     pub fn has_admin(&self) -> bool {
-        self.admin_password_hash.is_some()
+        self.users.has_admin()
     }
 
-    /// Register admin user (only if registration is enabled or no admin exists)
-    pub fn register(&mut self, password: &str) -> Result<(), AuthError> {
-        // Allow registration if:
-        // 1. Registration is explicitly enabled, OR
-        // 2. No admin exists yet (first-time setup)
-        if !self.registration_enabled && self.has_admin() {
-            return Err(AuthError::RegistrationDisabled);
+    /// Register an account: the first admin, with the bootstrap token, and
+    /// after that viewers, if registration is enabled
+    pub fn register(&mut self, req: &RegisterRequest) -> Result<UserInfo, AuthError> {
+        // The first admin needs the bootstrap token; after that,
+        // ENABLE_REGISTRATION lets people create viewer accounts
+        if self.has_admin() {
+            if !self.registration_enabled {
+                return Err(AuthError::RegistrationDisabled);
+            }
+            let username = req.username.as_deref().ok_or(AuthError::InvalidUsername)?;
+            return self.create_user(&CreateUserRequest {
+                username: username.to_string(),
+                password: req.password.clone(),
+                role: Role::Viewer,
+                disabled: false,
+            });
         }
 
-        if self.has_admin() {
-            return Err(AuthError::UserAlreadyExists);
+        let now = Utc::now().timestamp();
+        self.expire_bootstrap(now);
+        let valid = match (&self.bootstrap, &req.bootstrap_token) {
+            (Some(token), Some(presented)) => token.matches(presented, now),
+            _ => false,
+        };
+        if !valid {
+            return Err(AuthError::InvalidBootstrapToken);
         }
+        self.create_first_admin(&req.password)?;
+        if let Some(token) = self.bootstrap.take() {
+            token.discard();
+        }
+        Ok(UserInfo::from(
+            self.users.get(DEFAULT_USERNAME).expect("just created"),
+        ))
+    }
 
-        // Hash the password with Argon2
-        let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
-        let password_hash = argon2
-            .hash_password(password.as_bytes(), &salt)?
-            .to_string();
+    /// Start first-run setup: make the bootstrap token registering the first
+    /// admin needs, and log it. Does nothing once an admin exists.
+    pub fn start_bootstrap(&mut self, data_dir: &Path) -> Result<(), AuthError> {
+        if self.has_admin() {
+            bootstrap::remove_file(data_dir)?;
+            return Ok(());
+        }
+        let (token, secret) = BootstrapToken::create(data_dir, Utc::now().timestamp())?;
+        tracing::warn!("================================================================");
+        tracing::warn!("No admin account exists. Register one within 24 hours with");
+        tracing::warn!("POST /auth/register and this bootstrap token, also saved to");
+        tracing::warn!(
+            "{}:",
+            data_dir.join(bootstrap::BOOTSTRAP_TOKEN_FILE).display()
+        );
+        tracing::warn!("    {}", secret);
+        tracing::warn!("================================================================");
+        self.bootstrap = Some(token);
+        Ok(())
+    }
 
-        // Store the hash
-        let password_path = self.data_dir.join("admin_password");
-        fs::write(&password_path, &password_hash)?;
+    /// When the bootstrap token expires, if there is one
+    pub fn bootstrap_expires_at(&self) -> Option<i64> {
+        self.bootstrap.as_ref().map(BootstrapToken::expires_at)
+    }
 
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            fs::set_permissions(&password_path, fs::Permissions::from_mode(0o600))?;
+    /// Drop the bootstrap token once it has expired or an admin exists
+    pub fn expire_bootstrap(&mut self, now: i64) {
+        let done = self.has_admin() || self.bootstrap_expires_at().is_some_and(|exp| exp <= now);
+        if done {
+            if let Some(token) = self.bootstrap.take() {
+                if !self.has_admin() {
+                    tracing::warn!("The bootstrap token expired; restart to get a new one");
+                }
+                token.discard();
+            }
+        }
+    }
+
+    /// Create the `admin` account, without the checks `register` makes; for
+    /// first-run setup
+    pub fn create_first_admin(&mut self, password: &str) -> Result<(), AuthError> {
+        if self.has_admin() {
+            return Err(AuthError::UserAlreadyExists);
         }
+        self.config
+            .password
+            .check(password)
+            .map_err(AuthError::WeakPassword)?;
 
-        self.admin_password_hash = Some(password_hash);
+        // Hash the password with Argon2 and store the account. Another
+        // request or process may have registered since the check above; the
+        // store refuses a second first admin.
+        self.users.insert_first_admin(User {
+            username: DEFAULT_USERNAME.to_string(),
+            password_hash: hash_password(password, &self.config.argon2)?,
+            role: Role::Admin,
+            disabled: false,
+            password_version: 0,
+            password_changed_at: None,
+            totp: None,
+        })?;
         tracing::info!("Admin user registered successfully");
 
         Ok(())
     }
 
-    /// Verify password and generate tokens
-    pub fn login(&self, password: &str) -> Result<TokenResponse, AuthError> {
-        let stored_hash = self
-            .admin_password_hash
-            .as_ref()
-            .ok_or(AuthError::InvalidCredentials)?;
-
-        // Verify password
-        let parsed_hash = PasswordHash::new(stored_hash)?;
-        let argon2 = Argon2::default();
+    /// Check a username and password, returning the enabled account they belong to
+    fn verify_password(&self, username: &str, password: &str) -> Result<&User, AuthError> {
+        let user = self.users.get(username).filter(|u| !u.disabled);
+        let matches = self.verify_or_dummy(user.map(|u| u.password_hash.as_str()), password)?;
+        match user {
+            Some(user) if matches => Ok(user),
+            _ => Err(AuthError::InvalidCredentials),
+        }
+    }
 
-        argon2
+    /// Check `password` against `hash`, or against the dummy hash when there
+    /// is no account, so a missing account, or no admin at all, takes as long
+    /// as a wrong password and can't be told apart by timing
+    fn verify_or_dummy(&self, hash: Option<&str>, password: &str) -> Result<bool, AuthError> {
+        let parsed_hash = PasswordHash::new(hash.unwrap_or(&self.dummy_hash))?;
+        #[cfg(test)]
+        VERIFICATIONS.with(|v| v.set(v.get() + 1));
+        let verified = Argon2::default()
             .verify_password(password.as_bytes(), &parsed_hash)
-            .map_err(|_| AuthError::InvalidCredentials)?;
-
-        // Generate tokens
-        let access_token = self.generate_token("admin", "access", JWT_EXPIRATION_HOURS * 3600)?;
-        let refresh_token = self.generate_token(
-            "admin",
-            "refresh",
-            REFRESH_TOKEN_EXPIRATION_DAYS * 24 * 3600,
-        )?;
+            .is_ok();
+        Ok(hash.is_some() && verified)
+    }
 
-        Ok(TokenResponse {
-            access_token,
-            refresh_token,
-            token_type: "Bearer".to_string(),
-            expires_in: JWT_EXPIRATION_HOURS * 3600,
+    /// Whether an account's password hash was made with other Argon2
+    /// parameters than the configured ones
+    pub fn needs_rehash(&self, username: &str) -> bool {
+        let Some(hash) = self
+            .users
+            .get(username)
+            .and_then(|u| PasswordHash::new(&u.password_hash).ok())
+        else {
+            return false;
+        };
+        let want = &self.config.argon2;
+        hash.algorithm != Algorithm::Argon2id.ident()
+            || !Params::try_from(&hash).is_ok_and(|p| {
+                p.m_cost() == want.m_cost()
+                    && p.t_cost() == want.t_cost()
+                    && p.p_cost() == want.p_cost()
+            })
+    }
+
+    /// Re-hash a just-verified password with the configured parameters. The
+    /// password version stays, so no tokens are invalidated.
+    pub fn rehash_password(&mut self, username: &str, password: &str) -> Result<(), AuthError> {
+        let user = self.verify_password(username, password)?;
+        if !self.needs_rehash(&user.username) {
+            return Ok(());
+        }
+        let password_hash = hash_password(password, &self.config.argon2)?;
+        self.users
+            .update(username, |u| u.password_hash = password_hash)?;
+        tracing::info!(
+            "Re-hashed password of {} with new Argon2 parameters",
+            username
+        );
+        Ok(())
+    }
+
+    /// Verify credentials and generate tokens, or an `mfa_token` if the
+    /// account also needs a two-factor code
+    pub fn login(&self, username: &str, password: &str) -> Result<LoginResponse, AuthError> {
+        let result = self.check_login(username, password);
+        self.metrics.login(match &result {
+            Ok(LoginResponse::Tokens(_)) => "success",
+            Ok(LoginResponse::MfaRequired(_)) => "mfa_required",
+            Err(e) => e.code(),
+        });
+        result
+    }
+
+    fn check_login(&self, username: &str, password: &str) -> Result<LoginResponse, AuthError> {
+        let user = self.verify_password(username, password)?;
+        if !user.totp.as_ref().is_some_and(|t| t.enabled) {
+            return self
+                .issue_tokens(user, None, None)
+                .map(LoginResponse::Tokens);
+        }
+
+        let now = Utc::now().timestamp();
+        let expires_in = MFA_TOKEN_EXPIRATION_MINUTES * 60;
+        let claims = Claims {
+            sub: user.username.clone(),
+            role: user.role,
+            iat: now,
+            exp: now + expires_in,
+            token_type: MFA_TOKEN_TYPE.to_string(),
+            password_version: user.password_version,
+            jti: Uuid::new_v4().to_string(),
+            refresh_jti: None,
+            generation: self.generation(&user.username),
+            scopes: Vec::new(),
+            iss: None,
+            aud: None,
+            nbf: None,
+            device_id: None,
+            session_id: None,
+            agent_id: None,
+        };
+        Ok(LoginResponse::MfaRequired(MfaChallenge {
+            mfa_token: self.generate_token(&claims)?,
+            token_type: MFA_TOKEN_TYPE.to_string(),
+            expires_in,
+        }))
+    }
+
+    /// Start two-factor setup: a new secret and recovery codes, pending
+    /// until `confirm_totp`
+    pub fn setup_totp(&mut self, username: &str) -> Result<TotpSetup, AuthError> {
+        let user = self.users.get(username).ok_or(AuthError::UserNotFound)?;
+        if user.totp.as_ref().is_some_and(|t| t.enabled) {
+            return Err(AuthError::TotpAlreadyEnabled);
+        }
+
+        let secret = totp::generate_secret();
+        let recovery_codes = totp::generate_recovery_codes();
+        let state = TotpState {
+            secret: self.totp.encrypt(&secret)?,
+            enabled: false,
+            recovery_codes: recovery_codes
+                .iter()
+                .map(|c| self.totp.hash_recovery_code(c))
+                .collect(),
+            last_step: None,
+        };
+        self.users
+            .update(username, |user| user.totp = Some(state))?;
+
+        Ok(TotpSetup {
+            secret: totp::base32_encode(&secret),
+            provisioning_uri: totp::provisioning_uri(username, &secret),
+            recovery_codes,
         })
     }
 
+    /// Turn on two-factor login once the first code from the app checks out
+    pub fn confirm_totp(&mut self, username: &str, code: &str) -> Result<(), AuthError> {
+        let user = self.users.get(username).ok_or(AuthError::UserNotFound)?;
+        let state = match &user.totp {
+            Some(state) if state.enabled => return Err(AuthError::TotpAlreadyEnabled),
+            Some(state) => state,
+            None => return Err(AuthError::TotpNotSetUp),
+        };
+        let secret = self.totp.decrypt(&state.secret)?;
+        let step = totp::verify_code(&secret, code, Utc::now().timestamp(), state.last_step)
+            .ok_or(AuthError::InvalidTotpCode)?;
+
+        self.users.update(username, |user| {
+            if let Some(state) = user.totp.as_mut() {
+                state.enabled = true;
+                state.last_step = Some(step);
+            }
+        })?;
+        tracing::info!("Two-factor login enabled for {}", username);
+        Ok(())
+    }
+
+    /// Exchange an `mfa_token` and a TOTP or recovery code for tokens
+    pub fn verify_totp(&mut self, mfa_token: &str, code: &str) -> Result<TokenResponse, AuthError> {
+        let claims = self.validate_claims(mfa_token)?;
+        if claims.token_type != MFA_TOKEN_TYPE {
+            return Err(AuthError::InvalidToken);
+        }
+        let user = self.users.get(&claims.sub).ok_or(AuthError::InvalidToken)?;
+        let state = match &user.totp {
+            Some(state) if state.enabled => state,
+            _ => return Err(AuthError::InvalidToken),
+        };
+        if claims.password_version != user.password_version {
+            return Err(AuthError::TokenSuperseded);
+        }
+
+        let secret = self.totp.decrypt(&state.secret)?;
+        let step = totp::verify_code(&secret, code, Utc::now().timestamp(), state.last_step);
+        let recovery_hash = self.totp.hash_recovery_code(code);
+        let recovery_used = step.is_none() && state.recovery_codes.contains(&recovery_hash);
+        if step.is_none() && !recovery_used {
+            return Err(AuthError::InvalidTotpCode);
+        }
+
+        self.users.update(&claims.sub, |user| {
+            if let Some(state) = user.totp.as_mut() {
+                if step.is_some() {
+                    state.last_step = step;
+                } else {
+                    state.recovery_codes.retain(|c| *c != recovery_hash);
+                }
+            }
+        })?;
+        if recovery_used {
+            tracing::warn!("Recovery code used for {}", claims.sub);
+        }
+
+        let user = self.users.get(&claims.sub).ok_or(AuthError::InvalidToken)?;
+        self.issue_tokens(user, None, None)
+    }
+
+    /// Whether the account has two-factor login on
+    pub fn totp_enabled(&self, username: &str) -> bool {
+        self.users
+            .get(username)
+            .and_then(|u| u.totp.as_ref())
+            .is_some_and(|t| t.enabled)
+    }
+
+    /// Change an account's password, invalidating its refresh tokens issued so far
+    pub fn change_password(
+        &mut self,
+        username: &str,
+        current_password: &str,
+        new_password: &str,
+    ) -> Result<(), AuthError> {
+        self.verify_password(username, current_password)
+            .map_err(|e| match e {
+                AuthError::InvalidCredentials => AuthError::WrongPassword,
+                other => other,
+            })?;
+        self.set_password(username, new_password)?;
+        tracing::info!(
+            "Password for {} changed; existing refresh tokens revoked",
+            username
+        );
+        Ok(())
+    }
+
+    /// Set an account's password without knowing the old one, for admins
+    pub fn set_password(&mut self, username: &str, new_password: &str) -> Result<(), AuthError> {
+        self.config
+            .password
+            .check(new_password)
+            .map_err(AuthError::WeakPassword)?;
+        self.users
+            .set_password(username, hash_password(new_password, &self.config.argon2)?)
+    }
+
+    pub fn list_users(&self) -> Vec<UserInfo> {
+        self.users.list()
+    }
+
+    pub fn create_user(&mut self, req: &CreateUserRequest) -> Result<UserInfo, AuthError> {
+        users::validate_username(&req.username)?;
+        self.config
+            .password
+            .check(&req.password)
+            .map_err(AuthError::WeakPassword)?;
+        let user = User {
+            username: req.username.clone(),
+            password_hash: hash_password(&req.password, &self.config.argon2)?,
+            role: req.role,
+            disabled: req.disabled,
+            password_version: 0,
+            password_changed_at: None,
+            totp: None,
+        };
+        let info = UserInfo::from(&user);
+        self.users.insert(user)?;
+        tracing::info!("Created {:?} account {}", req.role, req.username);
+        Ok(info)
+    }
+
+    /// Delete an account and revoke its tokens, so a later account with the
+    /// same name doesn't inherit them
+    pub fn delete_user(&mut self, username: &str) -> Result<(), AuthError> {
+        self.users.remove(username)?;
+        self.logout_all(username)?;
+        tracing::info!("Deleted account {}", username);
+        Ok(())
+    }
+
+    /// Seconds a refresh token lasts
+    pub fn refresh_ttl_secs(&self) -> i64 {
+        self.config.refresh_ttl_secs
+    }
+
     /// Refresh an access token using a refresh token
     pub fn refresh(&self, refresh_token: &str) -> Result<TokenResponse, AuthError> {
         let claims = self.validate_token(refresh_token)?;
+        let user = self.users.get(&claims.sub).ok_or(AuthError::InvalidToken)?;
 
         if claims.token_type != "refresh" {
             return Err(AuthError::InvalidToken);
         }
+        if claims.password_version != user.password_version {
+            return Err(AuthError::TokenSuperseded);
+        }
 
-        // Generate new tokens
-        let access_token =
-            self.generate_token(&claims.sub, "access", JWT_EXPIRATION_HOURS * 3600)?;
-        let new_refresh_token = self.generate_token(
-            &claims.sub,
-            "refresh",
-            REFRESH_TOKEN_EXPIRATION_DAYS * 24 * 3600,
+        let tokens = self.issue_tokens(
+            user,
+            claims.device_id.as_deref(),
+            claims.session_id.as_deref(),
         )?;
+        self.metrics.refresh_rotation();
+        Ok(tokens)
+    }
 
-        Ok(TokenResponse {
-            access_token,
-            refresh_token: new_refresh_token,
-            token_type: "Bearer".to_string(),
-            expires_in: JWT_EXPIRATION_HOURS * 3600,
-        })
+    /// Revoke a token and, for an access token, the refresh token issued
+    /// with it, and end the session they belong to
+    pub fn logout(&mut self, claims: &Claims) -> Result<(), AuthError> {
+        let now = Utc::now().timestamp();
+        self.denylist.revoke(&claims.jti, claims.exp, now)?;
+        let refresh_exp = claims.iat + self.config.refresh_ttl_secs;
+        if let Some(ref refresh_jti) = claims.refresh_jti {
+            self.denylist.revoke(refresh_jti, refresh_exp, now)?;
+        }
+        if let Some(ref session_id) = claims.session_id {
+            self.end_session(session_id, refresh_exp, now)?;
+        }
+        tracing::info!("Token for {} revoked", claims.sub);
+        Ok(())
+    }
+
+    /// Note the session `tokens` belong to as started or refreshed by `client`
+    pub fn record_session(
+        &self,
+        tokens: &TokenResponse,
+        client: &SessionClient,
+    ) -> Result<(), AuthError> {
+        let claims = self.decode(&tokens.refresh_token)?;
+        let Some(id) = claims.session_id else {
+            return Ok(());
+        };
+        let session = match self.store.session(&id)? {
+            Some(session) => Session {
+                last_refresh: claims.iat,
+                expires_at: claims.exp,
+                user_agent: client.user_agent.clone(),
+                ip_hash: client.ip_hash.clone(),
+                ..session
+            },
+            None => Session {
+                id,
+                username: claims.sub,
+                created_at: claims.iat,
+                last_refresh: claims.iat,
+                expires_at: claims.exp,
+                user_agent: client.user_agent.clone(),
+                ip_hash: client.ip_hash.clone(),
+                device_id: claims.device_id,
+                generation: claims.generation,
+                revoked_at: None,
+            },
+        };
+        self.store.put_session(&session, claims.iat)
+    }
+
+    /// Whether tokens of `session` still work: it wasn't ended, and nothing
+    /// since revoked every token of its account or device
+    fn session_is_live(&self, session: &Session, now: i64) -> bool {
+        session.revoked_at.is_none()
+            && session.expires_at > now
+            && !self.denylist.contains(&session.id)
+            && session.generation == self.generation(&session.username)
+            && self.users.get(&session.username).is_some_and(|user| {
+                !user.disabled
+                    && user
+                        .password_changed_at
+                        .is_none_or(|changed_at| session.last_refresh >= changed_at)
+            })
+            && session
+                .device_id
+                .as_deref()
+                .is_none_or(|id| self.devices.active(id).is_some())
+    }
+
+    /// A page of live sessions: `caller`'s own, or for admins anyone's
+    pub fn list_sessions(
+        &self,
+        caller: &Claims,
+        query: &SessionQuery,
+    ) -> Result<SessionPage, AuthError> {
+        let username = match query.username.as_deref() {
+            Some(username) => Some(username),
+            None if caller.is_admin() => None,
+            None => Some(caller.sub.as_str()),
+        };
+        if username != Some(caller.sub.as_str()) && !caller.is_admin() {
+            return Err(AuthError::Forbidden);
+        }
+        let now = Utc::now().timestamp();
+        let sessions = self
+            .store
+            .sessions()?
+            .into_iter()
+            .filter(|s| username.is_none_or(|name| s.username == name))
+            .filter(|s| self.session_is_live(s, now))
+            .collect();
+        Ok(sessions::page(
+            sessions,
+            query,
+            caller.session_id.as_deref(),
+        ))
+    }
+
+    /// End a live session of `caller`'s, or for admins anyone's; returns it
+    pub fn revoke_session(&mut self, caller: &Claims, id: &str) -> Result<Session, AuthError> {
+        let now = Utc::now().timestamp();
+        let session = self
+            .store
+            .session(id)?
+            .filter(|s| self.session_is_live(s, now))
+            .filter(|s| s.username == caller.sub || caller.is_admin())
+            .ok_or(AuthError::SessionNotFound)?;
+        self.end_session(id, session.expires_at, now)?;
+        tracing::info!("Session {} of {} revoked", id, session.username);
+        Ok(session)
+    }
+
+    /// Revoke every token of a session, which may have been refreshed up to
+    /// a refresh token expiring at `expires_at`
+    fn end_session(&mut self, id: &str, expires_at: i64, now: i64) -> Result<(), AuthError> {
+        let session = self.store.session(id)?;
+        let expires_at = session
+            .as_ref()
+            .map_or(expires_at, |s| s.expires_at.max(expires_at));
+        let leeway = self.config.token.leeway_secs as i64;
+        self.denylist.revoke(id, expires_at + leeway, now)?;
+        if let Some(mut session) = session.filter(|s| s.revoked_at.is_none()) {
+            session.revoked_at = Some(now);
+            self.store.put_session(&session, now)?;
+        }
+        Ok(())
+    }
+
+    /// Invalidate every token issued to `subject` so far
+    pub fn logout_all(&mut self, subject: &str) -> Result<(), AuthError> {
+        let generation = self.store.bump_generation(subject)?;
+        self.token_generations
+            .insert(subject.to_string(), generation);
+        tracing::info!("All tokens for {} revoked", subject);
+        Ok(())
     }
 
-    /// Generate a JWT token
-    fn generate_token(
+    /// Sign a service token for agent `agent_id` with `scopes`, all from
+    /// [`scopes::SERVICE_SCOPES`], good for `ttl_secs`
+    pub fn mint_service_token(
         &self,
-        subject: &str,
-        token_type: &str,
-        expires_in_seconds: i64,
+        agent_id: &str,
+        scopes: &[&str],
+        ttl_secs: i64,
     ) -> Result<String, AuthError> {
+        if agent_id.is_empty() || scopes.is_empty() {
+            // Without scopes a token would get its role's, see `has_scope`
+            return Err(AuthError::InvalidConfig(
+                "service tokens need an agent id and scopes".to_string(),
+            ));
+        }
+        if let Some(scope) = scopes.iter().find(|s| !scopes::is_service_scope(s)) {
+            return Err(AuthError::InvalidConfig(format!(
+                "{} isn't a service token scope",
+                scope
+            )));
+        }
+        if !(1..=service_tokens::MAX_TTL_SECS).contains(&ttl_secs) {
+            return Err(AuthError::InvalidConfig(format!(
+                "service token lifetime must be 1 to {} seconds",
+                service_tokens::MAX_TTL_SECS
+            )));
+        }
         let now = Utc::now().timestamp();
+        let subject = service_tokens::subject(agent_id);
         let claims = Claims {
-            sub: subject.to_string(),
+            generation: self.generation(&subject),
+            sub: subject,
+            role: Role::Viewer,
             iat: now,
-            exp: now + expires_in_seconds,
-            token_type: token_type.to_string(),
+            exp: now + ttl_secs,
+            token_type: SERVICE_TOKEN_TYPE.to_string(),
+            password_version: 0,
+            jti: Uuid::new_v4().to_string(),
+            refresh_jti: None,
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+            iss: None,
+            aud: None,
+            nbf: None,
+            device_id: None,
+            session_id: None,
+            agent_id: Some(agent_id.to_string()),
         };
+        self.generate_token(&claims)
+    }
 
-        let token = encode(
-            &Header::default(),
-            &claims,
-            &EncodingKey::from_secret(&self.jwt_secret),
-        )?;
+    /// End every service token minted for `agent_id` so far, when the agent
+    /// stops or is deleted
+    pub fn revoke_service_tokens(&mut self, agent_id: &str) -> Result<(), AuthError> {
+        self.logout_all(&service_tokens::subject(agent_id))
+    }
+
+    fn generation(&self, subject: &str) -> u64 {
+        self.token_generations.get(subject).copied().unwrap_or(0)
+    }
+
+    /// Issue an access token and the refresh token paired with it, for
+    /// `device_id` after a device login, in session `session_id` or a new one
+    fn issue_tokens(
+        &self,
+        user: &User,
+        device_id: Option<&str>,
+        session_id: Option<&str>,
+    ) -> Result<TokenResponse, AuthError> {
+        let now = Utc::now().timestamp();
+        let refresh = Claims {
+            sub: user.username.clone(),
+            role: user.role,
+            iat: now,
+            exp: now + self.config.refresh_ttl_secs,
+            token_type: "refresh".to_string(),
+            password_version: user.password_version,
+            jti: Uuid::new_v4().to_string(),
+            refresh_jti: None,
+            generation: self.generation(&user.username),
+            scopes: scopes::for_role(user.role)
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            iss: None,
+            aud: None,
+            nbf: None,
+            device_id: device_id.map(str::to_string),
+            session_id: Some(session_id.map_or_else(|| Uuid::new_v4().to_string(), str::to_string)),
+            agent_id: None,
+        };
+        let access = Claims {
+            exp: now + self.config.access_ttl_secs,
+            token_type: "access".to_string(),
+            jti: Uuid::new_v4().to_string(),
+            refresh_jti: Some(refresh.jti.clone()),
+            ..refresh.clone()
+        };
+
+        Ok(TokenResponse {
+            access_token: self.generate_token(&access)?,
+            refresh_token: self.generate_token(&refresh)?,
+            token_type: "Bearer".to_string(),
+            expires_in: self.config.access_ttl_secs,
+        })
+    }
+
+    /// Sign a JWT token with the newest key, stamped with our issuer and audience
+    fn generate_token(&self, claims: &Claims) -> Result<String, AuthError> {
+        let claims = Claims {
+            iss: Some(self.config.token.issuer.clone()),
+            aud: Some(self.config.token.audience.clone()),
+            nbf: Some(claims.iat),
+            ..claims.clone()
+        };
+        let key = self.keyring.signing_key();
+        let header = Header {
+            kid: Some(key.kid.clone()),
+            ..Header::new(key.algorithm.jwt())
+        };
+        let token = encode(&header, &claims, &key.encoding_key())?;
 
         Ok(token)
     }
 
+    /// Sign new tokens with a fresh key. Earlier keys keep verifying until
+    /// every token they signed has expired.
+    pub fn rotate_secret(&mut self) -> Result<Vec<JwtKeyInfo>, AuthError> {
+        let now = Utc::now().timestamp();
+        let retire_at = self.config.key_retirement(now);
+        let kid = self
+            .keyring
+            .rotate(self.config.algorithm, retire_at, now)?
+            .kid
+            .clone();
+        tracing::info!("Rotated JWT signing key, now signing with {}", kid);
+        Ok(self.keyring.list())
+    }
+
+    /// Public keys of the EdDSA signing keys still in use
+    pub fn jwks(&self) -> JwkSet {
+        self.keyring.jwks(Utc::now().timestamp())
+    }
+
+    pub fn list_api_keys(&self) -> Vec<ApiKeyInfo> {
+        self.api_keys.list()
+    }
+
+    pub fn create_api_key(
+        &mut self,
+        req: &CreateApiKeyRequest,
+    ) -> Result<CreatedApiKey, AuthError> {
+        let (key, secret) = self.api_keys.create(
+            &req.name,
+            req.role,
+            req.scopes.clone(),
+            req.expires_at,
+            Utc::now().timestamp(),
+        )?;
+        tracing::info!("Created API key {} ({})", key.name, key.id);
+        Ok(CreatedApiKey { key, secret })
+    }
+
+    pub fn revoke_api_key(&mut self, id: &str) -> Result<(), AuthError> {
+        self.api_keys.revoke(id)?;
+        tracing::info!("Revoked API key {}", id);
+        Ok(())
+    }
+
+    pub fn list_devices(&self) -> Vec<Device> {
+        self.devices.list()
+    }
+
+    /// Approve a device to log in as `req.username`, or as `admin` if unset
+    pub fn approve_device(
+        &mut self,
+        admin: &str,
+        req: &ApproveDeviceRequest,
+    ) -> Result<Device, AuthError> {
+        let key = devices::parse_public_key(&req.public_key).ok_or_else(|| {
+            AuthError::InvalidDeviceRequest("public_key is not a base64 Ed25519 key".to_string())
+        })?;
+        if devices::device_id(&key) != req.device_id {
+            return Err(AuthError::InvalidDeviceRequest(
+                "device_id is not the SHA-256 of public_key".to_string(),
+            ));
+        }
+        let username = req.username.as_deref().unwrap_or(admin);
+        self.users.get(username).ok_or(AuthError::UserNotFound)?;
+        let device = Device {
+            id: req.device_id.clone(),
+            public_key: req.public_key.trim().to_string(),
+            username: username.to_string(),
+            name: req.name.clone(),
+            approved_by: admin.to_string(),
+            approved_at: Utc::now().timestamp(),
+            revoked_at: None,
+        };
+        self.devices.approve(device.clone())?;
+        tracing::info!("Approved device {} for {}", device.id, username);
+        Ok(device)
+    }
+
+    /// Revoke a device; tokens it got stop working
+    pub fn revoke_device(&mut self, id: &str) -> Result<(), AuthError> {
+        self.devices.revoke(id, Utc::now().timestamp())?;
+        tracing::info!("Revoked device {}", id);
+        Ok(())
+    }
+
+    /// Issue tokens for a signed device login whose nonce was already
+    /// redeemed; `now_ms` is in Unix millis like `signedAt`
+    pub fn device_login(
+        &self,
+        req: &DeviceLoginRequest,
+        now_ms: i64,
+    ) -> Result<(String, TokenResponse), AuthError> {
+        let result = self.check_device_login(req, now_ms);
+        self.metrics.login(match &result {
+            Ok(_) => "success",
+            Err(e) => e.code(),
+        });
+        result
+    }
+
+    fn check_device_login(
+        &self,
+        req: &DeviceLoginRequest,
+        now_ms: i64,
+    ) -> Result<(String, TokenResponse), AuthError> {
+        req.verify(now_ms)?;
+        let device = self
+            .devices
+            .active(&req.device_id)
+            .filter(|d| d.public_key == req.public_key.trim())
+            .ok_or(AuthError::DeviceNotApproved)?;
+        let user = self
+            .users
+            .get(&device.username)
+            .filter(|u| !u.disabled)
+            .ok_or(AuthError::DeviceNotApproved)?;
+        let tokens = self.issue_tokens(user, Some(&device.id), None)?;
+        Ok((user.username.clone(), tokens))
+    }
+
+    /// Resolve an API key secret to claims carrying the key's role and scopes.
+    /// The flag says whether the key's last-used time is due a write.
+    pub fn validate_api_key(&self, secret: &str) -> Result<(Claims, bool), AuthError> {
+        let now = Utc::now().timestamp();
+        let key = self
+            .api_keys
+            .find(secret, now)
+            .ok_or(AuthError::InvalidToken)?;
+        let claims = Claims {
+            sub: format!("api-key:{}", key.id),
+            role: key.role,
+            iat: key.created_at,
+            exp: key.expires_at.unwrap_or(i64::MAX),
+            token_type: API_KEY_TOKEN_TYPE.to_string(),
+            password_version: 0,
+            jti: String::new(),
+            refresh_jti: None,
+            generation: 0,
+            scopes: key.effective_scopes(),
+            iss: None,
+            aud: None,
+            nbf: None,
+            device_id: None,
+            session_id: None,
+            agent_id: None,
+        };
+        Ok((claims, key.needs_touch(now)))
+    }
+
+    /// Record that an API key was just used
+    pub fn touch_api_key(&mut self, secret: &str) -> Result<(), AuthError> {
+        let now = Utc::now().timestamp();
+        let Some(id) = self.api_keys.find(secret, now).map(|k| k.id.clone()) else {
+            return Ok(());
+        };
+        self.api_keys.touch(&id, now)
+    }
+
     /// Validate a JWT token and return claims
+    ///
+    /// Logged-out tokens, those from before a `logout-all`, those of
+    /// deleted or disabled accounts, and `mfa` tokens are rejected.
     pub fn validate_token(&self, token: &str) -> Result<Claims, AuthError> {
-        let token_data = decode::<Claims>(
-            token,
-            &DecodingKey::from_secret(&self.jwt_secret),
-            &Validation::default(),
-        )?;
+        let result =
+            self.validate_claims(token)
+                .and_then(|claims| match claims.token_type.as_str() {
+                    MFA_TOKEN_TYPE => Err(AuthError::InvalidToken),
+                    _ => Ok(claims),
+                });
+        self.metrics.token_validation(match &result {
+            Ok(_) => "valid",
+            Err(e) => e.code(),
+        });
+        result
+    }
+
+    /// What `POST /auth/introspect` says of `token`: its claims if it is a
+    /// live access token, otherwise only that it is inactive
+    pub fn introspect(&self, token: &str) -> Introspection {
+        let Some(claims) = self
+            .validate_token(token)
+            .ok()
+            .filter(|claims| claims.token_type == "access")
+        else {
+            return Introspection::inactive();
+        };
+        let scopes = if claims.scopes.is_empty() {
+            scopes::for_role(claims.role)
+                .into_iter()
+                .map(str::to_string)
+                .collect()
+        } else {
+            claims.scopes
+        };
+        Introspection {
+            active: true,
+            sub: Some(claims.sub),
+            exp: Some(claims.exp),
+            iat: Some(claims.iat),
+            scope: Some(scopes.join(" ")),
+            token_type: Some(claims.token_type),
+        }
+    }
+
+    /// Checks for `decode`: the key's algorithm, our issuer and audience,
+    /// `exp` and `nbf` with leeway. Legacy checks don't require the issuer
+    /// and audience.
+    fn validation(&self, legacy: bool, algorithm: KeyAlgorithm) -> Validation {
+        // Only the key's own algorithm, so an HS256 token can't be checked
+        // against a public key used as an HMAC secret
+        let mut validation = Validation::new(algorithm.jwt());
+        validation.leeway = self.config.token.leeway_secs;
+        validation.validate_nbf = true;
+        if legacy {
+            validation.validate_aud = false;
+            validation.set_required_spec_claims(&["exp"]);
+        } else {
+            validation.set_issuer(&[&self.config.token.issuer]);
+            validation.set_audience(&[&self.config.token.audience]);
+            validation.set_required_spec_claims(&["exp", "nbf", "iss", "aud"]);
+        }
+        validation
+    }
+
+    /// Decode a JWT with the key its header names, or with each key in turn
+    /// for tokens from before keys had ids
+    fn decode(&self, token: &str) -> Result<Claims, AuthError> {
+        let header = decode_header(token)?;
+        let mut result = Err(AuthError::InvalidToken);
+        for key in self
+            .keyring
+            .verification_keys(header.kid.as_deref(), Utc::now().timestamp())
+        {
+            result = self.decode_with(token, key);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Decode a JWT with `key`, falling back to the legacy checks for a token
+    /// with neither issuer nor audience while those are accepted
+    fn decode_with(&self, token: &str, key: &JwtKey) -> Result<Claims, AuthError> {
+        let decoding_key = key.decoding_key()?;
+        match decode::<Claims>(token, &decoding_key, &self.validation(false, key.algorithm)) {
+            Ok(data) => Ok(data.claims),
+            Err(e)
+                if self.config.token.accept_legacy
+                    && matches!(e.kind(), ErrorKind::MissingRequiredClaim(_)) =>
+            {
+                let claims =
+                    decode::<Claims>(token, &decoding_key, &self.validation(true, key.algorithm))?
+                        .claims;
+                if claims.iss.is_some() || claims.aud.is_some() {
+                    return Err(e.into());
+                }
+                tracing::debug!("Accepted legacy token for {}", claims.sub);
+                Ok(claims)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Decode a JWT and check it hasn't been revoked, whatever its type
+    fn validate_claims(&self, token: &str) -> Result<Claims, AuthError> {
+        let claims = self.decode(token)?;
+        self.check_not_revoked(&claims)?;
+        Ok(claims)
+    }
+
+    /// Fail if the token behind `claims` was logged out, or its account
+    /// deleted or disabled, since they were read, or if it was issued before
+    /// the account's password last changed. A token issued in the second of
+    /// the change still counts, so logging in right after changing works.
+    /// Tokens from a device login also need the device still approved.
+    fn check_not_revoked(&self, claims: &Claims) -> Result<(), AuthError> {
+        if self.denylist.contains(&claims.jti)
+            || claims
+                .session_id
+                .as_deref()
+                .is_some_and(|id| self.denylist.contains(id))
+            || claims.generation != self.generation(&claims.sub)
+        {
+            return Err(AuthError::InvalidToken);
+        }
+        // An agent's lease ends by bumping its generation; there's no account
+        if claims.token_type == SERVICE_TOKEN_TYPE {
+            return Ok(());
+        }
+        let Some(user) = self.users.get(&claims.sub).filter(|u| !u.disabled) else {
+            return Err(AuthError::InvalidToken);
+        };
+        if user
+            .password_changed_at
+            .is_some_and(|changed_at| claims.iat < changed_at)
+        {
+            return Err(AuthError::TokenSuperseded);
+        }
+        if let Some(ref device_id) = claims.device_id {
+            self.devices
+                .active(device_id)
+                .filter(|d| d.username == claims.sub)
+                .ok_or(AuthError::DeviceNotApproved)?;
+        }
+        Ok(())
+    }
 
-        Ok(token_data.claims)
+    /// Key the audit log hashes source IPs with, generated on first use
+    pub fn audit_ip_key(&self) -> Result<Vec<u8>, AuthError> {
+        if let Some(key) = self.store.secret(AUDIT_IP_KEY)? {
+            return Ok(BASE64_STANDARD.decode(key.trim())?);
+        }
+        let mut key = vec![0u8; JWT_SECRET_LENGTH];
+        OsRng.fill_bytes(&mut key);
+        self.store
+            .set_secret(AUDIT_IP_KEY, &BASE64_STANDARD.encode(&key))?;
+        Ok(key)
     }
 
     /// Get the current auth status
@@ -377,6 +2238,9 @@ impl AuthManager {
             auth_enabled: true,
             has_admin: self.has_admin(),
             registration_enabled: self.registration_enabled || !self.has_admin(),
+            bootstrap_required: !self.has_admin(),
+            totp_enabled: None,
+            scopes: scopes::list(),
         }
     }
 }
@@ -384,57 +2248,671 @@ impl AuthManager {
 // === API Handlers ===
 
 /// POST /auth/login - Authenticate and get JWT tokens
+///
+/// With `?cookie=true` the tokens go into session cookies instead of the body.
 pub async fn login(
     State(state): State<Arc<AppState>>,
-    Json(req): Json<LoginRequest>,
-) -> Result<Json<TokenResponse>, AuthError> {
-    let auth = state.auth.read().await;
-    auth.login(&req.password).map(Json)
+    client_ip: Option<ClientIp>,
+    request_id: RequestId,
+    client: SessionClient,
+    Query(options): Query<CookieOption>,
+    AuthJson(req): AuthJson<LoginRequest>,
+) -> Result<Response, AuthError> {
+    let ip = client_ip.map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |ClientIp(ip)| ip);
+    let attempt = state
+        .login_limiter
+        .lock()
+        .await
+        .begin_attempt(ip, &req.username, Instant::now());
+    if let Err(e) = attempt {
+        state.metrics.login(e.code());
+        state.audit.record(
+            Audit::new(AuditEvent::Lockout, &request_id)
+                .subject(&req.username)
+                .ip(ip)
+                .detail(e.code()),
+        );
+        return Err(e);
+    }
+
+    let (result, needs_rehash) = {
+        let auth = state.auth.read().await;
+        let result = auth.login(&req.username, &req.password);
+        let needs_rehash = result.is_ok() && auth.needs_rehash(&req.username);
+        (result, needs_rehash)
+    };
+    if needs_rehash {
+        if let Err(e) = state
+            .auth
+            .write()
+            .await
+            .rehash_password(&req.username, &req.password)
+        {
+            tracing::warn!("Failed to re-hash password of {}: {}", req.username, e);
+        }
+    }
+    // With two-factor login the attempt only succeeds at /auth/totp/verify
+    match &result {
+        Ok(LoginResponse::Tokens(_)) => {
+            state
+                .login_limiter
+                .lock()
+                .await
+                .record_success(ip, &req.username);
+            state.audit.record(
+                Audit::new(AuditEvent::LoginSuccess, &request_id)
+                    .subject(&req.username)
+                    .ip(ip),
+            );
+        }
+        Ok(LoginResponse::MfaRequired(_)) => {}
+        Err(e) => state.audit.record(
+            Audit::new(AuditEvent::LoginFailure, &request_id)
+                .subject(&req.username)
+                .ip(ip)
+                .detail(e.code()),
+        ),
+    }
+    let response = result?;
+    if let LoginResponse::Tokens(ref tokens) = response {
+        state.auth.read().await.record_session(tokens, &client)?;
+    }
+    match response {
+        LoginResponse::Tokens(tokens) if options.cookie => {
+            Ok(cookie_session(&state, &tokens).await)
+        }
+        response => Ok(Json(response).into_response()),
+    }
+}
+
+/// Response putting `tokens` in session cookies
+async fn cookie_session(state: &AppState, tokens: &TokenResponse) -> Response {
+    let refresh_ttl_secs = state.auth.read().await.refresh_ttl_secs();
+    let (cookies, session) = session_cookies::start(tokens, refresh_ttl_secs);
+    (cookies, Json(session)).into_response()
 }
 
 /// POST /auth/register - Register admin user
 ///
 /// This endpoint is disabled by default for security. Enable by setting
 /// ENABLE_REGISTRATION=true environment variable, or use the CLI to set
-/// the initial password.
+/// the initial password. Either way it only answers clients in
+/// `REGISTRATION_ALLOWED_NETWORKS`, loopback by default.
 pub async fn register(
     State(state): State<Arc<AppState>>,
-    Json(req): Json<RegisterRequest>,
+    client_ip: Option<ClientIp>,
+    request_id: RequestId,
+    AuthJson(req): AuthJson<RegisterRequest>,
 ) -> Result<StatusCode, AuthError> {
+    let allowed = client_ip.is_some_and(|ClientIp(ip)| {
+        state
+            .registration_networks
+            .iter()
+            .any(|network| network.contains(ip))
+    });
+    if !allowed {
+        let mut audit =
+            Audit::new(AuditEvent::Forbidden, &request_id).detail("POST /auth/register");
+        if let Some(ClientIp(ip)) = client_ip {
+            audit = audit.ip(ip);
+        }
+        state.audit.record(audit);
+        return Err(AuthError::RegistrationNetworkDenied);
+    }
     let mut auth = state.auth.write().await;
-    auth.register(&req.password)?;
+    let user = auth.register(&req)?;
+    let mut audit = Audit::new(AuditEvent::Registration, &request_id).subject(&user.username);
+    if let Some(ClientIp(ip)) = client_ip {
+        audit = audit.ip(ip);
+    }
+    state.audit.record(audit);
     Ok(StatusCode::CREATED)
 }
 
 /// POST /auth/refresh - Refresh access token
+///
+/// Without `refresh_token` in the body, uses the refresh cookie of a cookie
+/// session (which needs the CSRF token) and answers with new cookies.
 pub async fn refresh(
     State(state): State<Arc<AppState>>,
-    Json(req): Json<RefreshRequest>,
-) -> Result<Json<TokenResponse>, AuthError> {
+    request_id: RequestId,
+    client: SessionClient,
+    method: Method,
+    headers: HeaderMap,
+    AuthJson(req): AuthJson<RefreshRequest>,
+) -> Result<Response, AuthError> {
+    let (refresh_token, from_cookie) = match req.refresh_token.as_deref() {
+        Some(token) => (token, false),
+        None => {
+            let token = session_cookies::cookie(&headers, session_cookies::REFRESH_COOKIE)
+                .ok_or(AuthError::MissingToken)?;
+            session_cookies::check_csrf(&method, &headers)?;
+            (token, true)
+        }
+    };
     let auth = state.auth.read().await;
-    auth.refresh(&req.refresh_token).map(Json)
+    let tokens = auth.refresh(refresh_token)?;
+    auth.record_session(&tokens, &client)?;
+    let mut audit = Audit::new(AuditEvent::TokenRefresh, &request_id);
+    if let Ok(claims) = auth.validate_claims(refresh_token) {
+        audit = audit.subject(&claims.sub);
+    }
+    state.audit.record(audit);
+    if from_cookie {
+        let (cookies, session) = session_cookies::start(&tokens, auth.refresh_ttl_secs());
+        return Ok((cookies, Json(session)).into_response());
+    }
+    Ok(Json(tokens).into_response())
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RefreshRequest {
-    pub refresh_token: String,
+    /// Unset for cookie sessions, which send `{}`
+    #[serde(default)]
+    pub refresh_token: Option<String>,
 }
 
-/// GET /auth/status - Check auth configuration
-pub async fn auth_status(State(state): State<Arc<AppState>>) -> Json<AuthStatus> {
-    let auth = state.auth.read().await;
-    Json(auth.status())
+/// POST /auth/change-password - Change the caller's password
+///
+/// Requires an access token. Returns 204 on success and 403 when the current
+/// password is wrong.
+pub async fn change_password(
+    State(state): State<Arc<AppState>>,
+    claims: Claims,
+    request_id: RequestId,
+    AuthJson(req): AuthJson<ChangePasswordRequest>,
+) -> Result<StatusCode, AuthError> {
+    if claims.token_type != "access" {
+        return Err(AuthError::InvalidToken);
+    }
+    let mut auth = state.auth.write().await;
+    auth.change_password(&claims.sub, &req.current_password, &req.new_password)?;
+    state
+        .audit
+        .record(Audit::new(AuditEvent::PasswordChange, &request_id).subject(&claims.sub));
+    Ok(StatusCode::NO_CONTENT)
 }
 
-// === Middleware ===
+/// GET /auth/jwks - Public keys for verifying EdDSA-signed tokens
+pub async fn jwks(State(state): State<Arc<AppState>>) -> Json<JwkSet> {
+    Json(state.auth.read().await.jwks())
+}
 
-/// JWT authentication middleware for HTTP requests
-#[allow(dead_code)]
-pub async fn auth_middleware(
+/// POST /auth/reload - Re-read auth state from the data directory (admin only)
+pub async fn reload(
     State(state): State<Arc<AppState>>,
-    mut request: Request,
-    next: Next,
-) -> Result<Response, AuthError> {
+    _admin: AdminClaims,
+) -> Result<Json<ReloadSummary>, AuthError> {
+    auth_reload::reload(&state, "POST /auth/reload")
+        .await
+        .map(Json)
+}
+
+/// POST /auth/rotate-secret - Start signing tokens with a new key (admin only)
+pub async fn rotate_secret(
+    State(state): State<Arc<AppState>>,
+    _admin: AdminClaims,
+) -> Result<Json<Vec<JwtKeyInfo>>, AuthError> {
+    let mut auth = state.auth.write().await;
+    auth.rotate_secret().map(Json)
+}
+
+/// GET /auth/users - List accounts (admin only)
+pub async fn list_users(
+    State(state): State<Arc<AppState>>,
+    _admin: AdminClaims,
+) -> Result<Json<Vec<UserInfo>>, AuthError> {
+    let auth = state.auth.read().await;
+    Ok(Json(auth.list_users()))
+}
+
+/// POST /auth/users - Create an account (admin only)
+pub async fn create_user(
+    State(state): State<Arc<AppState>>,
+    _admin: AdminClaims,
+    AuthJson(req): AuthJson<CreateUserRequest>,
+) -> Result<(StatusCode, Json<UserInfo>), AuthError> {
+    let mut auth = state.auth.write().await;
+    let user = auth.create_user(&req)?;
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+/// DELETE /auth/users/:name - Delete an account and revoke its tokens (admin only)
+pub async fn delete_user(
+    State(state): State<Arc<AppState>>,
+    _admin: AdminClaims,
+    UrlPath(name): UrlPath<String>,
+) -> Result<StatusCode, AuthError> {
+    let mut auth = state.auth.write().await;
+    auth.delete_user(&name)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /auth/users/:name/password - Reset an account's password (admin only)
+pub async fn set_user_password(
+    State(state): State<Arc<AppState>>,
+    AdminClaims(admin): AdminClaims,
+    request_id: RequestId,
+    UrlPath(name): UrlPath<String>,
+    AuthJson(req): AuthJson<SetPasswordRequest>,
+) -> Result<StatusCode, AuthError> {
+    let mut auth = state.auth.write().await;
+    auth.set_password(&name, &req.password)?;
+    state.audit.record(
+        Audit::new(AuditEvent::PasswordChange, &request_id)
+            .subject(&name)
+            .detail(format!("reset by {}", admin.sub)),
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /auth/api-keys - List API keys without their secrets (admin only)
+pub async fn list_api_keys(
+    State(state): State<Arc<AppState>>,
+    _admin: AdminClaims,
+) -> Result<Json<Vec<ApiKeyInfo>>, AuthError> {
+    let auth = state.auth.read().await;
+    Ok(Json(auth.list_api_keys()))
+}
+
+/// POST /auth/api-keys - Create an API key; the secret is only in this response (admin only)
+pub async fn create_api_key(
+    State(state): State<Arc<AppState>>,
+    _admin: AdminClaims,
+    AuthJson(req): AuthJson<CreateApiKeyRequest>,
+) -> Result<(StatusCode, Json<CreatedApiKey>), AuthError> {
+    let mut auth = state.auth.write().await;
+    let created = auth.create_api_key(&req)?;
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+/// DELETE /auth/api-keys/:id - Revoke an API key (admin only)
+pub async fn revoke_api_key(
+    State(state): State<Arc<AppState>>,
+    _admin: AdminClaims,
+    UrlPath(id): UrlPath<String>,
+) -> Result<StatusCode, AuthError> {
+    let mut auth = state.auth.write().await;
+    auth.revoke_api_key(&id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /auth/status - Check auth configuration
+pub async fn auth_status(
+    State(state): State<Arc<AppState>>,
+    OptionalClaims(caller): OptionalClaims,
+) -> Json<AuthStatus> {
+    let auth = state.auth.read().await;
+    let mut status = auth.status();
+    let caller = caller.filter(|claims| claims.token_type == "access");
+    if let Some(claims) = caller {
+        status.totp_enabled = Some(auth.totp_enabled(&claims.sub));
+    }
+    Json(status)
+}
+
+/// POST /auth/totp/setup - Start two-factor setup for the caller
+///
+/// Returns the secret, an `otpauth://` URI and recovery codes. Nothing
+/// changes at login until `/auth/totp/confirm` succeeds.
+pub async fn totp_setup(
+    State(state): State<Arc<AppState>>,
+    claims: Claims,
+) -> Result<Json<TotpSetup>, AuthError> {
+    if claims.token_type != "access" {
+        return Err(AuthError::InvalidToken);
+    }
+    let mut auth = state.auth.write().await;
+    auth.setup_totp(&claims.sub).map(Json)
+}
+
+/// POST /auth/totp/confirm - Turn on two-factor login with a first code
+pub async fn totp_confirm(
+    State(state): State<Arc<AppState>>,
+    claims: Claims,
+    AuthJson(req): AuthJson<TotpCodeRequest>,
+) -> Result<StatusCode, AuthError> {
+    if claims.token_type != "access" {
+        return Err(AuthError::InvalidToken);
+    }
+    let mut auth = state.auth.write().await;
+    auth.confirm_totp(&claims.sub, &req.code)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /auth/totp/verify - Finish a two-factor login
+///
+/// Failed codes count towards the same limits as failed passwords. Takes
+/// `?cookie=true` like `/auth/login`.
+pub async fn totp_verify(
+    State(state): State<Arc<AppState>>,
+    client_ip: Option<ClientIp>,
+    request_id: RequestId,
+    client: SessionClient,
+    Query(options): Query<CookieOption>,
+    AuthJson(req): AuthJson<TotpVerifyRequest>,
+) -> Result<Response, AuthError> {
+    let ip = client_ip.map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |ClientIp(ip)| ip);
+    let username = state.auth.read().await.validate_claims(&req.mfa_token)?.sub;
+    let attempt = state
+        .login_limiter
+        .lock()
+        .await
+        .begin_attempt(ip, &username, Instant::now());
+    if let Err(e) = attempt {
+        state.audit.record(
+            Audit::new(AuditEvent::Lockout, &request_id)
+                .subject(&username)
+                .ip(ip)
+                .detail(e.code()),
+        );
+        return Err(e);
+    }
+
+    let result = state
+        .auth
+        .write()
+        .await
+        .verify_totp(&req.mfa_token, &req.code);
+    match &result {
+        Ok(_) => {
+            state
+                .login_limiter
+                .lock()
+                .await
+                .record_success(ip, &username);
+            state.audit.record(
+                Audit::new(AuditEvent::LoginSuccess, &request_id)
+                    .subject(&username)
+                    .ip(ip),
+            );
+        }
+        Err(e) => state.audit.record(
+            Audit::new(AuditEvent::LoginFailure, &request_id)
+                .subject(&username)
+                .ip(ip)
+                .detail(e.code()),
+        ),
+    }
+    let tokens = result?;
+    state.auth.read().await.record_session(&tokens, &client)?;
+    if options.cookie {
+        return Ok(cookie_session(&state, &tokens).await);
+    }
+    Ok(Json(tokens).into_response())
+}
+
+/// POST /auth/logout - Revoke the presented token and its paired refresh
+/// token, and clear session cookies
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    claims: Claims,
+    request_id: RequestId,
+) -> Result<(StatusCode, session_cookies::SetCookies), AuthError> {
+    let mut auth = state.auth.write().await;
+    auth.logout(&claims)?;
+    state
+        .audit
+        .record(Audit::new(AuditEvent::Logout, &request_id).subject(&claims.sub));
+    Ok((StatusCode::NO_CONTENT, session_cookies::clear()))
+}
+
+/// POST /auth/device/challenge - Get a nonce for a device login
+pub async fn device_challenge(State(state): State<Arc<AppState>>) -> Json<DeviceChallenge> {
+    let mut challenges = state.device_challenges.lock().await;
+    Json(challenges.issue(Utc::now().timestamp()))
+}
+
+/// POST /auth/device/login - Exchange a device signature over a challenge
+/// nonce for tokens
+pub async fn device_login(
+    State(state): State<Arc<AppState>>,
+    client_ip: Option<ClientIp>,
+    request_id: RequestId,
+    client: SessionClient,
+    AuthJson(req): AuthJson<DeviceLoginRequest>,
+) -> Result<Json<TokenResponse>, AuthError> {
+    let now = Utc::now();
+    // Used up whether or not the rest checks out
+    let redeemed = state
+        .device_challenges
+        .lock()
+        .await
+        .redeem(&req.nonce, now.timestamp());
+    let result = match redeemed {
+        Ok(()) => {
+            let auth = state.auth.read().await;
+            auth.device_login(&req, now.timestamp_millis())
+                .and_then(|(username, tokens)| {
+                    auth.record_session(&tokens, &client)?;
+                    Ok((username, tokens))
+                })
+        }
+        Err(e) => Err(e),
+    };
+    let mut audit = match &result {
+        Ok((username, _)) => Audit::new(AuditEvent::LoginSuccess, &request_id)
+            .subject(username)
+            .detail(format!("device {}", req.device_id)),
+        Err(e) => Audit::new(AuditEvent::LoginFailure, &request_id)
+            .subject(&format!("device:{}", req.device_id))
+            .detail(e.code()),
+    };
+    if let Some(ClientIp(ip)) = client_ip {
+        audit = audit.ip(ip);
+    }
+    state.audit.record(audit);
+    result.map(|(_, tokens)| Json(tokens))
+}
+
+/// GET /auth/devices - List approved and revoked devices (admin only)
+pub async fn list_devices(
+    State(state): State<Arc<AppState>>,
+    _admin: AdminClaims,
+) -> Json<Vec<Device>> {
+    Json(state.auth.read().await.list_devices())
+}
+
+/// POST /auth/devices - Approve a device for device logins (admin only)
+pub async fn approve_device(
+    State(state): State<Arc<AppState>>,
+    AdminClaims(admin): AdminClaims,
+    AuthJson(req): AuthJson<ApproveDeviceRequest>,
+) -> Result<(StatusCode, Json<Device>), AuthError> {
+    let mut auth = state.auth.write().await;
+    let device = auth.approve_device(&admin.sub, &req)?;
+    Ok((StatusCode::CREATED, Json(device)))
+}
+
+/// DELETE /auth/devices/:id - Revoke a device and the tokens it got (admin only)
+pub async fn revoke_device(
+    State(state): State<Arc<AppState>>,
+    _admin: AdminClaims,
+    UrlPath(id): UrlPath<String>,
+) -> Result<StatusCode, AuthError> {
+    let mut auth = state.auth.write().await;
+    auth.revoke_device(&id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /auth/sessions?username=&after=&limit= - List live login sessions
+///
+/// Accounts see their own; admins see everyone's, or one account's with
+/// `username`. The caller's own session has `current` set.
+pub async fn list_sessions(
+    State(state): State<Arc<AppState>>,
+    claims: Claims,
+    Query(query): Query<SessionQuery>,
+) -> Result<Json<SessionPage>, AuthError> {
+    let auth = state.auth.read().await;
+    auth.list_sessions(&claims, &query).map(Json)
+}
+
+/// DELETE /auth/sessions/:id - End a session, revoking every token in it
+///
+/// Accounts can end their own sessions, admins anyone's.
+pub async fn revoke_session(
+    State(state): State<Arc<AppState>>,
+    claims: Claims,
+    request_id: RequestId,
+    UrlPath(id): UrlPath<String>,
+) -> Result<StatusCode, AuthError> {
+    let mut auth = state.auth.write().await;
+    let session = auth.revoke_session(&claims, &id)?;
+    state.audit.record(
+        Audit::new(AuditEvent::SessionRevoked, &request_id)
+            .subject(&session.username)
+            .detail(format!("session {} by {}", session.id, claims.sub)),
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /auth/ws-ticket - Get a one-time ticket for a WebSocket upgrade
+///
+/// Connect with `?ticket=<ticket>` within 30 seconds instead of putting the
+/// access token in the URL.
+pub async fn ws_ticket(
+    State(state): State<Arc<AppState>>,
+    claims: Claims,
+) -> Result<Json<WsTicket>, AuthError> {
+    let mut tickets = state.ws_tickets.lock().await;
+    tickets.issue(&claims, Utc::now().timestamp()).map(Json)
+}
+
+/// POST /auth/introspect - Whether a token from another client is live, for
+/// services holding the `auth.introspect` scope
+///
+/// Inactive tokens get `{"active": false}` and nothing else.
+pub async fn introspect(
+    State(state): State<Arc<AppState>>,
+    claims: Claims,
+    AuthJson(req): AuthJson<IntrospectRequest>,
+) -> Result<Json<Introspection>, AuthError> {
+    state
+        .introspection_limiter
+        .lock()
+        .await
+        .check(&claims.sub, Instant::now())?;
+    let auth = state.auth.read().await;
+    Ok(Json(auth.introspect(&req.token)))
+}
+
+/// POST /auth/logout-all - Revoke every token issued to the caller so far,
+/// and clear session cookies
+pub async fn logout_all(
+    State(state): State<Arc<AppState>>,
+    claims: Claims,
+    request_id: RequestId,
+) -> Result<(StatusCode, session_cookies::SetCookies), AuthError> {
+    let mut auth = state.auth.write().await;
+    auth.logout_all(&claims.sub)?;
+    state
+        .audit
+        .record(Audit::new(AuditEvent::LogoutAll, &request_id).subject(&claims.sub));
+    Ok((StatusCode::NO_CONTENT, session_cookies::clear()))
+}
+
+/// GET /auth/audit?since=&after=&limit= - Read the audit log (admin only)
+///
+/// Entries come oldest first; pass `next_after` as `after` for the next page.
+pub async fn audit_log(
+    State(state): State<Arc<AppState>>,
+    _admin: AdminClaims,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditPage>, AuthError> {
+    // Include what this process has queued but not yet written
+    state.audit.flush().await;
+    Ok(Json(state.audit.read(&query)?))
+}
+
+// === Extractors ===
+
+/// The caller's claims, as a handler argument
+///
+/// Uses the claims left by `auth_middleware` or `ws_auth_middleware`, and
+/// otherwise checks the `Authorization` header itself, so a handler taking
+/// `Claims` can't be reached unauthenticated even on a route without the
+/// middleware. Rejects with 401 like the middleware.
+///
+/// ```ignore
+/// async fn whoami(claims: Claims) -> String {
+///     claims.sub
+/// }
+/// ```
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Claims {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(claims) = parts.extensions.get::<Claims>() {
+            return Ok(claims.clone());
+        }
+        authenticate(state, &parts.method, &parts.headers).await
+    }
+}
+
+/// Claims of an admin caller; 401 without credentials, 403 for lower roles
+/// and for API keys without the `auth.admin` scope
+///
+/// ```ignore
+/// async fn rotate(_admin: AdminClaims) -> StatusCode {
+///     StatusCode::NO_CONTENT
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct AdminClaims(pub Claims);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for AdminClaims {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let claims = Claims::from_request_parts(parts, state).await?;
+        claims.require_role(Role::Admin)?;
+        claims.require_scope(scopes::AUTH_ADMIN)?;
+        Ok(AdminClaims(claims))
+    }
+}
+
+/// The caller's claims if the request carries valid credentials, for
+/// endpoints that answer anyone but say more to a known caller
+///
+/// ```ignore
+/// async fn greet(OptionalClaims(claims): OptionalClaims) -> String {
+///     claims.map_or("hello".to_string(), |c| format!("hello {}", c.sub))
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct OptionalClaims(pub Option<Claims>);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for OptionalClaims {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(OptionalClaims(
+            Claims::from_request_parts(parts, state).await.ok(),
+        ))
+    }
+}
+
+// === Middleware ===
+
+/// JWT authentication middleware for HTTP requests
+pub async fn auth_middleware(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
     // Skip auth for health check
     if request.uri().path() == "/health" {
         return Ok(next.run(request).await);
@@ -449,102 +2927,2317 @@ pub async fn auth_middleware(
         return Ok(next.run(request).await);
     }
 
+    let started = Instant::now();
+    let claims = authenticate(&state, request.method(), request.headers()).await;
+    state.metrics.middleware_latency(started.elapsed());
+    let claims = claims?;
+    let subject = claims.sub.clone();
+
+    // Store claims in request extensions for handlers to use
+    request.extensions_mut().insert(claims);
+
+    let (mut parts, body) = request.into_parts();
+    let request_id = RequestId::from_parts(&mut parts);
+    let client_ip = parts.extensions.get::<ClientIp>().copied();
+    let target = format!("{} {}", parts.method, parts.uri.path());
+    let response = next.run(Request::from_parts(parts, body)).await;
+    if response.status() == StatusCode::FORBIDDEN {
+        let mut audit = Audit::new(AuditEvent::Forbidden, &request_id)
+            .subject(&subject)
+            .detail(target);
+        if let Some(ClientIp(ip)) = client_ip {
+            audit = audit.ip(ip);
+        }
+        state.audit.record(audit);
+    }
+    Ok(response)
+}
+
+/// Route layer answering 403 unless the caller's token has the scope given
+/// as its state; goes inside `auth_middleware`
+///
+/// ```ignore
+/// router.route_layer(middleware::from_fn_with_state("agents.write", auth::require_scope))
+/// ```
+pub async fn require_scope(
+    State(scope): State<&'static str>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let claims = request
+        .extensions()
+        .get::<Claims>()
+        .ok_or(AuthError::MissingToken)?;
+    claims.require_scope(scope)?;
+    Ok(next.run(request).await)
+}
+
+/// Check the `Authorization` header of a request: a bearer API key or a
+/// JWT other than a refresh token
+///
+/// Without the header, takes an access token from the session cookie, and
+/// then the CSRF token for anything but safe methods.
+async fn authenticate(
+    state: &AppState,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Claims, AuthError> {
     // Extract token from Authorization header
-    let auth_header = request
-        .headers()
+    let Some(auth_header) = headers
         .get(header::AUTHORIZATION)
         .and_then(|h| h.to_str().ok())
-        .ok_or(AuthError::MissingAuthHeader)?;
+    else {
+        return authenticate_cookie(state, method, headers).await;
+    };
 
     // Parse "Bearer <token>"
     let token = auth_header
         .strip_prefix("Bearer ")
         .ok_or(AuthError::InvalidAuthHeaderFormat)?;
 
-    // Validate token
+    // Validate the API key or JWT
     let auth = state.auth.read().await;
-    let claims = auth.validate_token(token)?;
+    let claims = if token.starts_with(API_KEY_PREFIX) {
+        let (claims, stale) = auth.validate_api_key(token)?;
+        drop(auth);
+        if stale {
+            if let Err(e) = state.auth.write().await.touch_api_key(token) {
+                tracing::warn!("Failed to record API key use: {}", e);
+            }
+        }
+        claims
+    } else {
+        auth.validate_token(token)?
+    };
+    // Refresh tokens are only good for /api/auth/refresh
+    if claims.token_type == "refresh" {
+        return Err(AuthError::InvalidToken);
+    }
+    if claims.token_type == SERVICE_TOKEN_TYPE {
+        return Err(AuthError::ServiceTokenNotAllowed);
+    }
+    Ok(claims)
+}
 
-    // Store claims in request extensions for handlers to use
-    request.extensions_mut().insert(claims);
+/// Check the session cookie of a request, and its CSRF token
+async fn authenticate_cookie(
+    state: &AppState,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Claims, AuthError> {
+    let token =
+        session_cookies::cookie(headers, SESSION_COOKIE).ok_or(AuthError::MissingAuthHeader)?;
+    // Only JWTs are put in cookies
+    let claims = state.auth.read().await.validate_token(token)?;
+    if claims.token_type != "access" {
+        return Err(AuthError::InvalidToken);
+    }
+    session_cookies::check_csrf(method, headers)?;
+    Ok(claims)
+}
+
+/// Value of `name` in a query string, if set and not empty
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+        .filter(|value| !value.is_empty())
+}
+
+/// Validate the `token` query parameter of a WebSocket upgrade request
+///
+/// Accepts an access token or an API key while `WS_ACCEPT_QUERY_TOKENS` is
+/// on. Deprecated in favour of tickets from `/auth/ws-ticket`.
+pub fn validate_ws_token(auth: &AuthManager, query: &str) -> Result<Claims, AuthError> {
+    let token = query_param(query, "token").ok_or(AuthError::MissingToken)?;
+    if !auth.config.token.accept_ws_query_token {
+        return Err(AuthError::InvalidToken);
+    }
+    static DEPRECATION: Once = Once::new();
+    DEPRECATION.call_once(|| {
+        tracing::warn!(
+            "WebSocket clients are sending tokens in ?token=, which ends up in logs; \
+             switch them to POST /auth/ws-ticket and set WS_ACCEPT_QUERY_TOKENS=false"
+        )
+    });
+    let claims = if token.starts_with(API_KEY_PREFIX) {
+        auth.validate_api_key(token)?.0
+    } else {
+        auth.validate_token(token)?
+    };
+    if claims.token_type == "refresh" {
+        return Err(AuthError::InvalidToken);
+    }
+    if claims.token_type == SERVICE_TOKEN_TYPE {
+        return Err(AuthError::ServiceTokenNotAllowed);
+    }
+    Ok(claims)
+}
 
+/// Auth middleware for WebSocket routes
+///
+/// Checks `?token=` before the upgrade handler runs, so a missing or invalid
+/// token gets a 401 instead of an upgraded connection. The claims go into
+/// request extensions like `auth_middleware`'s, for the handler to hand to
+/// the connection task.
+pub async fn ws_auth_middleware(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let query = request.uri().query().unwrap_or("");
+    let claims = match query_param(query, "ticket") {
+        Some(ticket) => {
+            let claims = state
+                .ws_tickets
+                .lock()
+                .await
+                .redeem(ticket, Utc::now().timestamp())?;
+            let auth = state.auth.read().await;
+            if claims.token_type != API_KEY_TOKEN_TYPE {
+                auth.check_not_revoked(&claims)?;
+            }
+            claims
+        }
+        None => validate_ws_token(&*state.auth.read().await, query)?,
+    };
+    request.extensions_mut().insert(claims);
     Ok(next.run(request).await)
 }
 
-/// Extract and validate JWT from WebSocket query parameter or first message
-/// Returns the claims if valid, None if no token provided (for optional auth)
-#[allow(dead_code)]
-pub fn validate_ws_token(auth: &AuthManager, query_params: &str) -> Option<Claims> {
-    // Parse query string
-    for pair in query_params.split('&') {
-        if let Some(token) = pair.strip_prefix("token=") {
-            return auth.validate_token(token).ok();
+// === CLI Utilities ===
+
+/// CLI mode: add a new JWT signing key, as `POST /auth/rotate-secret` does
+pub fn cli_rotate_jwt_secret(data_dir: &Path) -> Result<(), AuthError> {
+    let mut auth = AuthManager::new(data_dir)?;
+    for key in auth.rotate_secret()? {
+        match key.retire_at {
+            Some(at) => println!("  {} retires at {}", key.kid, at),
+            None => println!("✓ New signing key {}", key.kid),
         }
     }
-    None
+    println!("Restart the orchestrator to start signing with it.");
+    Ok(())
 }
 
-// === CLI Utilities ===
+/// CLI mode: seal the auth secrets stored before the master key was set
+pub fn cli_rewrap_auth_secrets(data_dir: &Path) -> Result<(), AuthError> {
+    let key = MasterKey::from_env(data_dir)?.ok_or_else(|| {
+        AuthError::InvalidConfig(format!(
+            "set {} or {} to the master key first",
+            master_key::MASTER_KEY_ENV,
+            master_key::MASTER_KEY_FILE_ENV
+        ))
+    })?;
+    let store = SealedStore::new(
+        Arc::new(SqliteAuthStore::open(&data_dir.join("auth.db"))?),
+        Some(key),
+    );
+    let sealed = store.rewrap()?;
+    if sealed.is_empty() {
+        println!("✓ Every auth secret was already encrypted");
+    }
+    for name in sealed {
+        println!("✓ Encrypted {}", name);
+    }
+    Ok(())
+}
 
-/// Set the admin password from CLI
-/// Usage: claw-pen-orchestrator --set-password
-pub fn cli_set_password(data_dir: &Path) -> Result<(), AuthError> {
-    use std::io::{self, BufRead, Write};
+/// Environment variable `--set-password` reads the password from when set
+const ADMIN_PASSWORD_VAR: &str = "CLAW_PEN_ADMIN_PASSWORD";
 
-    println!("Set admin password for Claw Pen Orchestrator");
-    print!("Enter new password: ");
-    io::stdout().flush()?;
+/// Where `--set-password` reads the new password from
+#[derive(Debug, Clone, PartialEq)]
+pub enum PasswordSource {
+    /// Prompt twice with echo off
+    Prompt,
+    /// First line of a file, `--password-file <path>`
+    File(PathBuf),
+    /// First line of stdin, `--password-stdin`
+    Stdin,
+    /// `CLAW_PEN_ADMIN_PASSWORD`, removed from our environment once read
+    Env,
+}
 
-    let stdin = io::stdin();
-    let password = stdin
-        .lock()
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetPasswordOptions {
+    pub source: PasswordSource,
+    /// Replace the password of an existing admin account (`--force`)
+    pub force: bool,
+}
+
+impl SetPasswordOptions {
+    /// Options from the command line; without `--password-file` or
+    /// `--password-stdin` the environment variable is used if set, and
+    /// otherwise the user is prompted
+    pub fn from_args(args: &[String]) -> Result<Self, AuthError> {
+        let invalid = |message: &str| {
+            AuthError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                message.to_string(),
+            ))
+        };
+        let file = match args.iter().position(|a| a == "--password-file") {
+            Some(i) => Some(
+                args.get(i + 1)
+                    .filter(|path| !path.starts_with("--"))
+                    .map(PathBuf::from)
+                    .ok_or_else(|| invalid("--password-file needs a path"))?,
+            ),
+            None => None,
+        };
+        let stdin = args.iter().any(|a| a == "--password-stdin");
+        let source = match (file, stdin) {
+            (Some(_), true) => {
+                return Err(invalid(
+                    "--password-file and --password-stdin can't be used together",
+                ))
+            }
+            (Some(path), false) => PasswordSource::File(path),
+            (None, true) => PasswordSource::Stdin,
+            (None, false) if std::env::var_os(ADMIN_PASSWORD_VAR).is_some() => PasswordSource::Env,
+            (None, false) => PasswordSource::Prompt,
+        };
+        Ok(Self {
+            source,
+            force: args.iter().any(|a| a == "--force"),
+        })
+    }
+}
+
+/// First line of `reader`, without its line ending
+fn first_line(reader: impl std::io::BufRead) -> Result<String, AuthError> {
+    reader
         .lines()
         .next()
         .ok_or_else(|| {
-            AuthError::IoError(io::Error::new(io::ErrorKind::UnexpectedEof, "No input"))
+            AuthError::IoError(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "No input",
+            ))
         })?
-        .map_err(AuthError::IoError)?;
+        .map_err(AuthError::IoError)
+}
+
+fn read_password(source: &PasswordSource) -> Result<String, AuthError> {
+    match source {
+        PasswordSource::Prompt => {
+            let password = rpassword::prompt_password("Enter new password: ")?;
+            let confirmation = rpassword::prompt_password("Confirm new password: ")?;
+            if password != confirmation {
+                return Err(AuthError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Passwords do not match",
+                )));
+            }
+            Ok(password)
+        }
+        PasswordSource::File(path) => first_line(std::io::BufReader::new(fs::File::open(path)?)),
+        PasswordSource::Stdin => first_line(std::io::stdin().lock()),
+        PasswordSource::Env => {
+            let password = std::env::var(ADMIN_PASSWORD_VAR).map_err(|e| {
+                AuthError::IoError(std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+            })?;
+            std::env::remove_var(ADMIN_PASSWORD_VAR);
+            eprintln!(
+                "Read the password from {}; unset it where it was set so it doesn't linger",
+                ADMIN_PASSWORD_VAR
+            );
+            Ok(password)
+        }
+    }
+}
+
+/// Set the admin password from the CLI
+///
+/// Usage: `claw-pen-orchestrator --set-password [--password-file <path> |
+/// --password-stdin] [--force]`, or with `CLAW_PEN_ADMIN_PASSWORD` set.
+/// Fails if the password breaks the password policy, or if an admin account
+/// already exists and `--force` isn't given.
+pub fn cli_set_password(data_dir: &Path, options: &SetPasswordOptions) -> Result<(), AuthError> {
+    println!("Set admin password for Claw Pen Orchestrator");
+    let password = read_password(&options.source)?;
+    set_admin_password(data_dir, &password, options.force)?;
+    println!("✓ Admin password set successfully");
+    Ok(())
+}
 
-    if password.len() < 8 {
-        eprintln!("Password must be at least 8 characters");
-        return Ok(());
+/// Store `password` on the admin account, creating it and the JWT secret if
+/// needed
+fn set_admin_password(data_dir: &Path, password: &str, force: bool) -> Result<(), AuthError> {
+    let config = AuthConfig::from_env()?;
+    if let Err(violations) = config.password.check(password) {
+        eprintln!("Password rejected:");
+        for violation in &violations {
+            eprintln!("  - {}", violation);
+        }
+        return Err(AuthError::WeakPassword(violations));
     }
 
-    // Create auth manager and set password
-    let secret_path = data_dir.join("jwt_secret");
-    let password_path = data_dir.join("admin_password");
+    let store = auth_store::open(data_dir)?;
+    let mut users = UserStore::load(store.clone())?;
+    let exists = users.get(DEFAULT_USERNAME).is_some();
+    if exists && !force {
+        eprintln!("An admin password is already set; pass --force to replace it");
+        return Err(AuthError::UserAlreadyExists);
+    }
 
     // Generate JWT secret if needed
-    let _jwt_secret = if secret_path.exists() {
-        let secret_b64 = fs::read_to_string(&secret_path)?;
-        BASE64_STANDARD.decode(secret_b64.trim())?
-    } else {
+    if store.secret(JWT_SECRET)?.is_none() {
         let mut secret = vec![0u8; JWT_SECRET_LENGTH];
         OsRng.fill_bytes(&mut secret);
-        let secret_b64 = BASE64_STANDARD.encode(&secret);
-        fs::write(&secret_path, &secret_b64)?;
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            fs::set_permissions(&secret_path, fs::Permissions::from_mode(0o600))?;
-        }
-        secret
-    };
+        store.set_secret(JWT_SECRET, &BASE64_STANDARD.encode(&secret))?;
+    }
 
-    // Hash and store password
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password(password.as_bytes(), &salt)?
-        .to_string();
+    // An admin exists now, so the bootstrap token is no longer needed
+    bootstrap::remove_file(data_dir)?;
 
-    fs::write(&password_path, &password_hash)?;
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        fs::set_permissions(&password_path, fs::Permissions::from_mode(0o600))?;
+    // Hash and store password on the admin account
+    let password_hash = hash_password(password, &config.argon2)?;
+    if exists {
+        users.set_password(DEFAULT_USERNAME, password_hash)?;
+    } else {
+        users.insert(User {
+            username: DEFAULT_USERNAME.to_string(),
+            password_hash,
+            role: Role::Admin,
+            disabled: false,
+            password_version: 0,
+            password_changed_at: None,
+            totp: None,
+        })?;
     }
+    Ok(())
+}
 
-    println!("✓ Admin password set successfully");
+/// CLI mode: write everything in the auth store to `path` as JSON
+///
+/// Usage: `claw-pen-orchestrator --export-auth-state <file>`. The file holds
+/// the signing secrets, so it is written readable only by its owner.
+pub fn cli_export_auth_state(data_dir: &Path, path: &Path) -> Result<(), AuthError> {
+    let snapshot = auth_store::open(data_dir)?.export()?;
+    write_private_atomic(path, &serde_json::to_string_pretty(&snapshot)?)?;
+    println!(
+        "✓ Exported {} accounts and {} API keys to {:?}",
+        snapshot.users.len(),
+        snapshot.api_keys.len(),
+        path
+    );
+    eprintln!("The export contains the JWT signing secrets; keep it private");
+    Ok(())
+}
+
+/// CLI mode: replace everything in the auth store with an export
+///
+/// Usage: `claw-pen-orchestrator --import-auth-state <file>`. Stop the
+/// orchestrator first; a running one keeps what it loaded at startup.
+pub fn cli_import_auth_state(data_dir: &Path, path: &Path) -> Result<(), AuthError> {
+    let (snapshot, source) =
+        auth_files::read_with_backup(path, |s| serde_json::from_str::<AuthSnapshot>(s))?;
+    if source == Source::Backup {
+        eprintln!(
+            "⚠ {:?} is missing or corrupt; importing its backup {:?} instead",
+            path,
+            auth_files::backup_path(path)
+        );
+    }
+    if !snapshot.secrets.contains_key(JWT_SECRET) {
+        return Err(AuthError::InvalidConfig(format!(
+            "{:?} has no JWT secret; is it an auth state export?",
+            path
+        )));
+    }
+    auth_store::open(data_dir)?.import(&snapshot)?;
+    println!(
+        "✓ Imported {} accounts and {} API keys from {:?}",
+        snapshot.users.len(),
+        snapshot.api_keys.len(),
+        path
+    );
     Ok(())
 }
+
+/// CLI mode: check every auth file in the data directory and report
+///
+/// Usage: `claw-pen-orchestrator --check-auth-files`. Fails if any file is
+/// broken, so it can run before a start or in a health check.
+pub fn cli_check_auth_files(data_dir: &Path) -> Result<(), AuthError> {
+    let checks = auth_files::check(data_dir);
+    for check in &checks {
+        let mark = match check.status {
+            Status::Ok => "✓",
+            Status::Warning => "⚠",
+            Status::Error => "✗",
+        };
+        println!("{} {}: {}", mark, check.path.display(), check.detail);
+    }
+    let broken = checks.iter().filter(|c| c.status == Status::Error).count();
+    if broken > 0 {
+        return Err(AuthError::InvalidConfig(format!(
+            "{} auth file(s) in {:?} need attention",
+            broken, data_dir
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{DecodingKey, EncodingKey};
+    use tempfile::tempdir;
+
+    fn expect_tokens(response: LoginResponse) -> TokenResponse {
+        match response {
+            LoginResponse::Tokens(tokens) => tokens,
+            LoginResponse::MfaRequired(_) => panic!("expected tokens, got an MFA challenge"),
+        }
+    }
+
+    fn manager_with_tokens(dir: &Path, token: TokenConfig) -> AuthManager {
+        let config = AuthConfig {
+            token,
+            ..AuthConfig::default()
+        };
+        AuthManager::with_config(dir, config).unwrap()
+    }
+
+    fn manager_with_admin(dir: &Path) -> AuthManager {
+        let mut auth = AuthManager::new(dir).unwrap();
+        auth.create_first_admin("correct horse").unwrap();
+        auth
+    }
+
+    /// Argon2 verifications `f` runs
+    fn count_verifications(f: impl FnOnce()) -> usize {
+        let before = VERIFICATIONS.with(|v| v.get());
+        f();
+        VERIFICATIONS.with(|v| v.get()) - before
+    }
+
+    #[test]
+    fn test_failed_logins_always_verify_a_hash() {
+        let dir = tempdir().unwrap();
+        let mut auth = AuthManager::new(dir.path()).unwrap();
+        let attempt = |auth: &AuthManager, username: &str, password: &str| {
+            count_verifications(|| {
+                assert!(matches!(
+                    auth.login(username, password),
+                    Err(AuthError::InvalidCredentials)
+                ));
+            })
+        };
+
+        // No admin yet
+        assert_eq!(attempt(&auth, "admin", "correct horse"), 1);
+
+        auth.create_first_admin("correct horse").unwrap();
+        add_user(&mut auth, "parked", Role::Viewer, true);
+        assert_eq!(attempt(&auth, "admin", "wrong password"), 1);
+        assert_eq!(attempt(&auth, "nobody", "correct horse"), 1);
+        assert_eq!(attempt(&auth, "parked", "viewer password"), 1);
+    }
+
+    #[test]
+    fn test_change_password_checks_current_and_length() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+
+        assert!(matches!(
+            auth.change_password("admin", "wrong password", "battery staple"),
+            Err(AuthError::WrongPassword)
+        ));
+        assert!(matches!(
+            auth.change_password("admin", "correct horse", "short"),
+            Err(AuthError::WeakPassword(_))
+        ));
+        assert!(auth.login("admin", "correct horse").is_ok());
+    }
+
+    #[test]
+    fn test_change_password_revokes_refresh_tokens() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        let before = expect_tokens(auth.login("admin", "correct horse").unwrap());
+
+        auth.change_password("admin", "correct horse", "battery staple")
+            .unwrap();
+
+        assert!(matches!(
+            auth.login("admin", "correct horse"),
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            auth.refresh(&before.refresh_token),
+            Err(AuthError::TokenSuperseded)
+        ));
+        let after = expect_tokens(auth.login("admin", "battery staple").unwrap());
+        assert!(auth.refresh(&after.refresh_token).is_ok());
+
+        // The new hash and version survive a restart
+        let reloaded = AuthManager::new(dir.path()).unwrap();
+        assert!(reloaded.login("admin", "battery staple").is_ok());
+        assert!(reloaded.refresh(&before.refresh_token).is_err());
+        assert!(reloaded.refresh(&after.refresh_token).is_ok());
+    }
+
+    #[test]
+    fn test_logout_revokes_token_pair() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        let tokens = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        let other = expect_tokens(auth.login("admin", "correct horse").unwrap());
+
+        let claims = auth.validate_token(&tokens.access_token).unwrap();
+        assert!(claims.refresh_jti.is_some());
+        auth.logout(&claims).unwrap();
+
+        assert!(auth.validate_token(&tokens.access_token).is_err());
+        assert!(auth.refresh(&tokens.refresh_token).is_err());
+        assert!(auth.validate_token(&other.access_token).is_ok());
+
+        // Revocations survive a restart
+        let reloaded = AuthManager::new(dir.path()).unwrap();
+        assert!(reloaded.validate_token(&tokens.access_token).is_err());
+        assert!(reloaded.validate_token(&other.access_token).is_ok());
+    }
+
+    #[test]
+    fn test_logout_all_invalidates_earlier_tokens() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        let before = expect_tokens(auth.login("admin", "correct horse").unwrap());
+
+        auth.logout_all("admin").unwrap();
+
+        assert!(auth.validate_token(&before.access_token).is_err());
+        assert!(auth.refresh(&before.refresh_token).is_err());
+        let after = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        assert!(auth.validate_token(&after.access_token).is_ok());
+
+        let reloaded = AuthManager::new(dir.path()).unwrap();
+        assert!(reloaded.validate_token(&before.access_token).is_err());
+        assert!(reloaded.validate_token(&after.access_token).is_ok());
+    }
+
+    #[test]
+    fn test_password_change_supersedes_older_tokens() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        let tokens = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        let claims = auth.validate_token(&tokens.access_token).unwrap();
+
+        let changed_at = claims.iat - 5;
+        auth.users
+            .update("admin", |u| u.password_changed_at = Some(changed_at))
+            .unwrap();
+        let issued_at = |iat| {
+            auth.generate_token(&Claims {
+                iat,
+                ..claims.clone()
+            })
+            .unwrap()
+        };
+        assert!(matches!(
+            auth.validate_token(&issued_at(changed_at - 1)),
+            Err(AuthError::TokenSuperseded)
+        ));
+        // Issued in the same second as the change
+        assert!(auth.validate_token(&issued_at(changed_at)).is_ok());
+        assert!(auth.validate_token(&tokens.access_token).is_ok());
+
+        auth.change_password("admin", "correct horse", "battery staple")
+            .unwrap();
+        let changed_at = auth.users.get("admin").unwrap().password_changed_at;
+        assert!(changed_at.is_some_and(|t| t >= claims.iat));
+        let reloaded = AuthManager::new(dir.path()).unwrap();
+        assert_eq!(
+            reloaded.users.get("admin").unwrap().password_changed_at,
+            changed_at
+        );
+        let older = reloaded
+            .generate_token(&Claims {
+                iat: changed_at.unwrap() - 1,
+                ..claims
+            })
+            .unwrap();
+        assert!(matches!(
+            reloaded.validate_token(&older),
+            Err(AuthError::TokenSuperseded)
+        ));
+    }
+
+    #[test]
+    fn test_cli_password_reset_supersedes_tokens() {
+        let dir = tempdir().unwrap();
+        let auth = manager_with_admin(dir.path());
+        let tokens = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        let claims = auth.validate_token(&tokens.access_token).unwrap();
+
+        set_admin_password(dir.path(), "battery staple", true).unwrap();
+        let reloaded = AuthManager::new(dir.path()).unwrap();
+        let changed_at = reloaded
+            .users
+            .get("admin")
+            .unwrap()
+            .password_changed_at
+            .unwrap();
+        assert!(changed_at >= claims.iat);
+        let older = reloaded
+            .generate_token(&Claims {
+                iat: changed_at - 1,
+                ..claims
+            })
+            .unwrap();
+        assert!(matches!(
+            reloaded.validate_token(&older),
+            Err(AuthError::TokenSuperseded)
+        ));
+    }
+
+    #[test]
+    fn test_reload_picks_up_changes_from_other_processes() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        assert!(auth.reload().unwrap().is_empty());
+
+        set_admin_password(dir.path(), "battery staple", true).unwrap();
+        let mut other = AuthManager::new(dir.path()).unwrap();
+        add_user(&mut other, "viewer", Role::Viewer, false);
+        other.rotate_secret().unwrap();
+
+        let summary = auth.reload().unwrap();
+        assert_eq!(summary.users_added, ["viewer"]);
+        assert_eq!(summary.users_changed, ["admin"]);
+        assert_eq!(summary.keys_added.len(), 1);
+        assert!(summary.users_removed.is_empty() && summary.keys_removed.is_empty());
+        assert!(summary.to_string().contains("users added: viewer"));
+        assert!(auth.login("admin", "battery staple").is_ok());
+        // Tokens signed with the new key verify
+        let tokens = expect_tokens(other.login("viewer", "viewer password").unwrap());
+        assert!(auth.validate_token(&tokens.access_token).is_ok());
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_previous_state() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        let mut broken = auth.users.get("admin").unwrap().clone();
+        broken.password_hash = "not a hash".to_string();
+        broken.role = Role::Viewer;
+        auth.store.update_user(&broken).unwrap();
+
+        assert!(auth.reload().is_err());
+        assert!(auth.login("admin", "correct horse").is_ok());
+        assert_eq!(auth.users.get("admin").unwrap().role, Role::Admin);
+    }
+
+    fn device_request(key: &ed25519_dalek::SigningKey, signed_at: i64) -> DeviceLoginRequest {
+        use ed25519_dalek::Signer;
+        let device_id = devices::device_id(&key.verifying_key());
+        let nonce = "n0nce".to_string();
+        let message = devices::signable_string(&device_id, signed_at, &nonce);
+        DeviceLoginRequest {
+            device_id,
+            public_key: BASE64_STANDARD.encode(key.verifying_key().as_bytes()),
+            signed_at,
+            signature: BASE64_STANDARD.encode(key.sign(message.as_bytes()).to_bytes()),
+            nonce,
+        }
+    }
+
+    #[test]
+    fn test_device_login_needs_an_approved_device() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        add_user(&mut auth, "viewer", Role::Viewer, false);
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let now_ms = Utc::now().timestamp_millis();
+        let req = device_request(&key, now_ms);
+        assert!(matches!(
+            auth.device_login(&req, now_ms),
+            Err(AuthError::DeviceNotApproved)
+        ));
+
+        // The id has to match the key
+        let mismatched = ApproveDeviceRequest {
+            device_id: "0".repeat(64),
+            public_key: req.public_key.clone(),
+            username: None,
+            name: None,
+        };
+        assert!(matches!(
+            auth.approve_device("admin", &mismatched),
+            Err(AuthError::InvalidDeviceRequest(_))
+        ));
+        let approve = ApproveDeviceRequest {
+            device_id: req.device_id.clone(),
+            username: Some("viewer".to_string()),
+            ..mismatched
+        };
+        let device = auth.approve_device("admin", &approve).unwrap();
+        assert_eq!(device.approved_by, "admin");
+
+        let (username, tokens) = auth.device_login(&req, now_ms).unwrap();
+        assert_eq!(username, "viewer");
+        let claims = auth.validate_token(&tokens.access_token).unwrap();
+        assert_eq!(claims.sub, "viewer");
+        assert_eq!(claims.role, Role::Viewer);
+        assert_eq!(claims.device_id.as_deref(), Some(req.device_id.as_str()));
+        // Refreshed tokens still belong to the device
+        let refreshed = auth.refresh(&tokens.refresh_token).unwrap();
+        let refreshed_claims = auth.validate_token(&refreshed.access_token).unwrap();
+        assert_eq!(refreshed_claims.device_id, claims.device_id);
+
+        // Approvals survive a restart
+        let reloaded = AuthManager::new(dir.path()).unwrap();
+        assert_eq!(reloaded.list_devices(), vec![device]);
+
+        auth.revoke_device(&req.device_id).unwrap();
+        assert!(matches!(
+            auth.validate_token(&tokens.access_token),
+            Err(AuthError::DeviceNotApproved)
+        ));
+        assert!(matches!(
+            auth.device_login(&req, now_ms),
+            Err(AuthError::DeviceNotApproved)
+        ));
+        assert!(matches!(
+            auth.revoke_device(&req.device_id),
+            Err(AuthError::DeviceNotFound)
+        ));
+        assert!(auth.list_devices()[0].revoked_at.is_some());
+
+        // Approving it again works, but not for a disabled account
+        auth.approve_device("admin", &approve).unwrap();
+        auth.device_login(&req, now_ms).unwrap();
+        add_user(&mut auth, "gone", Role::Viewer, true);
+        let for_disabled = ApproveDeviceRequest {
+            username: Some("gone".to_string()),
+            ..approve
+        };
+        auth.approve_device("admin", &for_disabled).unwrap();
+        assert!(matches!(
+            auth.device_login(&req, now_ms),
+            Err(AuthError::DeviceNotApproved)
+        ));
+    }
+
+    #[test]
+    fn test_ending_a_session_revokes_its_whole_family() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        add_user(&mut auth, "viewer", Role::Viewer, false);
+        let laptop = SessionClient {
+            user_agent: Some("laptop".to_string()),
+            ip_hash: Some("aGFzaA".to_string()),
+        };
+        let first = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        auth.record_session(&first, &laptop).unwrap();
+        let refreshed = auth.refresh(&first.refresh_token).unwrap();
+        auth.record_session(&refreshed, &laptop).unwrap();
+        let desktop = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        auth.record_session(&desktop, &SessionClient::default())
+            .unwrap();
+        let viewer = expect_tokens(auth.login("viewer", "viewer password").unwrap());
+        auth.record_session(&viewer, &SessionClient::default())
+            .unwrap();
+
+        let admin = auth.validate_token(&desktop.access_token).unwrap();
+        let laptop_id = auth
+            .validate_token(&first.access_token)
+            .unwrap()
+            .session_id
+            .unwrap();
+        // Refreshing stays in the session
+        assert_eq!(
+            auth.validate_token(&refreshed.access_token)
+                .unwrap()
+                .session_id,
+            Some(laptop_id.clone())
+        );
+        let page = auth
+            .list_sessions(&admin, &SessionQuery::default())
+            .unwrap();
+        assert_eq!(page.sessions.len(), 3);
+        let current: Vec<_> = page.sessions.iter().filter(|s| s.current).collect();
+        assert_eq!(current.len(), 1);
+        assert_eq!(Some(&current[0].session.id), admin.session_id.as_ref());
+        let listed = page
+            .sessions
+            .iter()
+            .find(|s| s.session.id == laptop_id)
+            .unwrap();
+        assert_eq!(listed.session.user_agent.as_deref(), Some("laptop"));
+
+        // Viewers only see, and can only end, their own
+        let viewer_claims = auth.validate_token(&viewer.access_token).unwrap();
+        let own = auth
+            .list_sessions(&viewer_claims, &SessionQuery::default())
+            .unwrap();
+        assert_eq!(own.sessions.len(), 1);
+        let others = SessionQuery {
+            username: Some("admin".to_string()),
+            ..SessionQuery::default()
+        };
+        assert!(matches!(
+            auth.list_sessions(&viewer_claims, &others),
+            Err(AuthError::Forbidden)
+        ));
+        assert!(matches!(
+            auth.revoke_session(&viewer_claims, &laptop_id),
+            Err(AuthError::SessionNotFound)
+        ));
+
+        auth.revoke_session(&admin, &laptop_id).unwrap();
+        // Every token of the family, refreshed or not, stops working
+        for token in [
+            &first.access_token,
+            &refreshed.access_token,
+            &refreshed.refresh_token,
+        ] {
+            assert!(matches!(
+                auth.validate_token(token),
+                Err(AuthError::InvalidToken)
+            ));
+        }
+        auth.validate_token(&desktop.access_token).unwrap();
+        assert!(matches!(
+            auth.revoke_session(&admin, &laptop_id),
+            Err(AuthError::SessionNotFound)
+        ));
+        let page = auth.list_sessions(&admin, &others).unwrap();
+        assert_eq!(page.sessions.len(), 1);
+
+        // Logging out ends the session too
+        auth.logout(&admin).unwrap();
+        let viewer_only = auth
+            .list_sessions(&viewer_claims, &SessionQuery::default())
+            .unwrap();
+        assert_eq!(viewer_only.sessions.len(), 1);
+        assert!(auth
+            .store
+            .sessions()
+            .unwrap()
+            .iter()
+            .all(|s| s.username == "viewer" || s.revoked_at.is_some()));
+    }
+
+    fn register_request(username: Option<&str>, token: Option<&str>) -> RegisterRequest {
+        RegisterRequest {
+            username: username.map(str::to_string),
+            password: "correct horse".to_string(),
+            bootstrap_token: token.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_first_admin_needs_the_bootstrap_token() {
+        let dir = tempdir().unwrap();
+        let mut auth = AuthManager::new(dir.path()).unwrap();
+        assert!(auth.status().bootstrap_required);
+        // No token was made
+        assert!(matches!(
+            auth.register(&register_request(None, Some("cpb_guess"))),
+            Err(AuthError::InvalidBootstrapToken)
+        ));
+
+        auth.start_bootstrap(dir.path()).unwrap();
+        let path = dir.path().join(bootstrap::BOOTSTRAP_TOKEN_FILE);
+        let token = fs::read_to_string(&path).unwrap();
+        for presented in [None, Some("cpb_guess")] {
+            assert!(matches!(
+                auth.register(&register_request(None, presented)),
+                Err(AuthError::InvalidBootstrapToken)
+            ));
+        }
+        let admin = auth
+            .register(&register_request(Some("root"), Some(&token)))
+            .unwrap();
+        assert_eq!(admin.username, "admin");
+        assert_eq!(admin.role, Role::Admin);
+        assert!(!path.exists());
+        assert!(!auth.status().bootstrap_required);
+
+        // Without ENABLE_REGISTRATION nobody else can register
+        assert!(matches!(
+            auth.register(&register_request(Some("newbie"), Some(&token))),
+            Err(AuthError::RegistrationDisabled)
+        ));
+        auth.registration_enabled = true;
+        let viewer = auth
+            .register(&register_request(Some("newbie"), None))
+            .unwrap();
+        assert_eq!(viewer.role, Role::Viewer);
+        assert!(matches!(
+            auth.register(&register_request(None, None)),
+            Err(AuthError::InvalidUsername)
+        ));
+    }
+
+    #[test]
+    fn test_bootstrap_token_expires() {
+        let dir = tempdir().unwrap();
+        let mut auth = AuthManager::new(dir.path()).unwrap();
+        auth.start_bootstrap(dir.path()).unwrap();
+        let path = dir.path().join(bootstrap::BOOTSTRAP_TOKEN_FILE);
+        let token = fs::read_to_string(&path).unwrap();
+
+        auth.expire_bootstrap(auth.bootstrap_expires_at().unwrap() - 1);
+        assert!(path.exists());
+        auth.expire_bootstrap(auth.bootstrap_expires_at().unwrap());
+        assert!(!path.exists());
+        assert!(matches!(
+            auth.register(&register_request(None, Some(&token))),
+            Err(AuthError::InvalidBootstrapToken)
+        ));
+        assert!(!auth.has_admin());
+    }
+
+    #[test]
+    fn test_cli_admin_password_removes_the_bootstrap_token() {
+        let dir = tempdir().unwrap();
+        let mut auth = AuthManager::new(dir.path()).unwrap();
+        auth.start_bootstrap(dir.path()).unwrap();
+        set_admin_password(dir.path(), "correct horse", false).unwrap();
+        assert!(!dir.path().join(bootstrap::BOOTSTRAP_TOKEN_FILE).exists());
+
+        auth.reload().unwrap();
+        assert!(auth.bootstrap_expires_at().is_none());
+        auth.start_bootstrap(dir.path()).unwrap();
+        assert!(auth.bootstrap_expires_at().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_password_file_stays_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        auth.change_password("admin", "correct horse", "battery staple")
+            .unwrap();
+
+        let mode = fs::metadata(dir.path().join("auth.db"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    fn add_user(auth: &mut AuthManager, username: &str, role: Role, disabled: bool) {
+        auth.create_user(&CreateUserRequest {
+            username: username.to_string(),
+            password: "viewer password".to_string(),
+            role,
+            disabled,
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_tokens_carry_the_account_role() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        add_user(&mut auth, "watcher", Role::Viewer, false);
+
+        let tokens = expect_tokens(auth.login("watcher", "viewer password").unwrap());
+        let claims = auth.validate_token(&tokens.access_token).unwrap();
+        assert_eq!(claims.sub, "watcher");
+        assert_eq!(claims.role, Role::Viewer);
+        assert!(claims.require_role(Role::Viewer).is_ok());
+        assert!(matches!(
+            claims.require_role(Role::Operator),
+            Err(AuthError::Forbidden)
+        ));
+
+        let admin = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        let claims = auth.validate_token(&admin.access_token).unwrap();
+        assert!(claims.require_role(Role::Admin).is_ok());
+    }
+
+    #[test]
+    fn test_disabled_and_unknown_users_cannot_log_in() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        add_user(&mut auth, "parked", Role::Operator, true);
+
+        assert!(matches!(
+            auth.login("parked", "viewer password"),
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            auth.login("nobody", "viewer password"),
+            Err(AuthError::InvalidCredentials)
+        ));
+    }
+
+    #[test]
+    fn test_deleting_a_user_invalidates_its_tokens() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        add_user(&mut auth, "temp", Role::Operator, false);
+        let tokens = expect_tokens(auth.login("temp", "viewer password").unwrap());
+
+        auth.delete_user("temp").unwrap();
+        assert!(auth.validate_token(&tokens.access_token).is_err());
+        assert!(auth.refresh(&tokens.refresh_token).is_err());
+
+        // A new account with the same name doesn't inherit old tokens
+        add_user(&mut auth, "temp", Role::Admin, false);
+        assert!(auth.validate_token(&tokens.access_token).is_err());
+        auth.delete_user("admin").unwrap();
+        assert!(matches!(
+            auth.delete_user("temp"),
+            Err(AuthError::LastAdmin)
+        ));
+    }
+
+    #[test]
+    fn test_api_key_resolves_to_scoped_claims() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        let created = auth
+            .create_api_key(&CreateApiKeyRequest {
+                name: "ci".to_string(),
+                role: Role::Operator,
+                scopes: vec!["agents.write".to_string()],
+                expires_at: None,
+            })
+            .unwrap();
+
+        let (claims, stale) = auth.validate_api_key(&created.secret).unwrap();
+        assert_eq!(claims.token_type, API_KEY_TOKEN_TYPE);
+        assert_eq!(claims.role, Role::Operator);
+        assert_eq!(claims.scopes, ["agents.write"]);
+        assert!(stale);
+
+        auth.touch_api_key(&created.secret).unwrap();
+        assert!(!auth.validate_api_key(&created.secret).unwrap().1);
+        // API keys aren't JWTs
+        assert!(auth.validate_token(&created.secret).is_err());
+
+        auth.revoke_api_key(&created.key.id).unwrap();
+        assert!(auth.validate_api_key(&created.secret).is_err());
+    }
+
+    #[test]
+    fn test_api_key_scopes_stay_within_role() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        let request = |role, scope: &str| CreateApiKeyRequest {
+            name: "ci".to_string(),
+            role,
+            scopes: vec![scope.to_string()],
+            expires_at: None,
+        };
+        for (role, scope) in [
+            (Role::Viewer, scopes::AGENTS_WRITE),
+            (Role::Operator, scopes::AUTH_ADMIN),
+            (Role::Admin, "agents.*"),
+        ] {
+            assert!(matches!(
+                auth.create_api_key(&request(role, scope)),
+                Err(AuthError::InvalidApiKeyRequest(_))
+            ));
+        }
+
+        // Without scopes a key gets its role's
+        let created = auth
+            .create_api_key(&CreateApiKeyRequest {
+                scopes: Vec::new(),
+                ..request(Role::Operator, "")
+            })
+            .unwrap();
+        let (claims, _) = auth.validate_api_key(&created.secret).unwrap();
+        assert!(claims.has_scope(scopes::AGENTS_WRITE));
+        assert!(matches!(
+            claims.require_scope(scopes::AUTH_ADMIN),
+            Err(AuthError::MissingScope(scope)) if scope == scopes::AUTH_ADMIN
+        ));
+    }
+
+    #[test]
+    fn test_tokens_carry_role_scopes() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        add_user(&mut auth, "viewer", Role::Viewer, false);
+        let tokens = expect_tokens(auth.login("viewer", "viewer password").unwrap());
+        let claims = auth.validate_token(&tokens.access_token).unwrap();
+        assert_eq!(claims.scopes, scopes::for_role(Role::Viewer));
+        assert!(claims.has_scope(scopes::AGENTS_READ));
+        assert!(!claims.has_scope(scopes::AGENTS_WRITE));
+        let refreshed = auth.refresh(&tokens.refresh_token).unwrap();
+        let refreshed = auth.validate_token(&refreshed.access_token).unwrap();
+        assert_eq!(refreshed.scopes, claims.scopes);
+
+        // Tokens from before scopes get their role's
+        let legacy = Claims {
+            scopes: Vec::new(),
+            role: Role::Admin,
+            ..claims.clone()
+        };
+        assert!(legacy.has_scope(scopes::AUTH_ADMIN));
+        // API keys don't
+        let key = Claims {
+            token_type: API_KEY_TOKEN_TYPE.to_string(),
+            ..legacy
+        };
+        assert!(!key.has_scope(scopes::AGENTS_READ));
+    }
+
+    fn current_code(auth: &AuthManager, username: &str) -> String {
+        let state = auth.users.get(username).unwrap().totp.clone().unwrap();
+        let secret = auth.totp.decrypt(&state.secret).unwrap();
+        format!("{:06}", totp::code_at(&secret, Utc::now().timestamp() / 30))
+    }
+
+    #[test]
+    fn test_totp_login_needs_a_code() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        let setup = auth.setup_totp("admin").unwrap();
+        assert!(setup.provisioning_uri.starts_with("otpauth://totp/"));
+        assert_eq!(setup.recovery_codes.len(), totp::RECOVERY_CODE_COUNT);
+
+        // Pending setup doesn't change login
+        expect_tokens(auth.login("admin", "correct horse").unwrap());
+        assert!(matches!(
+            auth.confirm_totp("admin", "000000"),
+            Err(AuthError::InvalidTotpCode)
+        ));
+        let code = current_code(&auth, "admin");
+        auth.confirm_totp("admin", &code).unwrap();
+        assert!(auth.totp_enabled("admin"));
+
+        let LoginResponse::MfaRequired(challenge) = auth.login("admin", "correct horse").unwrap()
+        else {
+            panic!("expected an MFA challenge");
+        };
+        // The mfa token isn't an access token
+        assert!(auth.validate_token(&challenge.mfa_token).is_err());
+        // The code used to confirm can't be replayed
+        assert!(matches!(
+            auth.verify_totp(&challenge.mfa_token, &code),
+            Err(AuthError::InvalidTotpCode)
+        ));
+
+        // Each recovery code works once
+        let recovery = &setup.recovery_codes[0];
+        let tokens = auth.verify_totp(&challenge.mfa_token, recovery).unwrap();
+        assert!(auth.validate_token(&tokens.access_token).is_ok());
+        assert!(auth.verify_totp(&challenge.mfa_token, recovery).is_err());
+
+        // The secret isn't stored in the clear
+        let stored =
+            String::from_utf8_lossy(&fs::read(dir.path().join("auth.db")).unwrap()).into_owned();
+        assert!(!stored.contains(&setup.secret));
+        assert!(!stored.contains(recovery.as_str()));
+    }
+
+    #[test]
+    fn test_ws_token_from_query() {
+        let dir = tempdir().unwrap();
+        let auth = manager_with_admin(dir.path());
+        let tokens = expect_tokens(auth.login("admin", "correct horse").unwrap());
+
+        let query = format!("since=10&token={}", tokens.access_token);
+        assert_eq!(validate_ws_token(&auth, &query).unwrap().sub, "admin");
+        assert!(matches!(
+            validate_ws_token(&auth, "since=10"),
+            Err(AuthError::MissingToken)
+        ));
+        assert!(matches!(
+            validate_ws_token(&auth, "token="),
+            Err(AuthError::MissingToken)
+        ));
+        let refresh = format!("token={}", tokens.refresh_token);
+        assert!(validate_ws_token(&auth, &refresh).is_err());
+        assert!(validate_ws_token(&auth, "token=garbage").is_err());
+    }
+
+    #[test]
+    fn test_ws_query_tokens_can_be_turned_off() {
+        let dir = tempdir().unwrap();
+        let auth = manager_with_admin(dir.path());
+        let tokens = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        let query = format!("token={}", tokens.access_token);
+        // Only the exact parameter name counts
+        assert!(matches!(
+            validate_ws_token(&auth, &format!("x{}", query)),
+            Err(AuthError::MissingToken)
+        ));
+
+        let strict = manager_with_tokens(
+            dir.path(),
+            TokenConfig {
+                accept_ws_query_token: false,
+                ..TokenConfig::default()
+            },
+        );
+        assert!(matches!(
+            validate_ws_token(&strict, &query),
+            Err(AuthError::InvalidToken)
+        ));
+        assert!(strict.validate_token(&tokens.access_token).is_ok());
+    }
+
+    /// Sign `claims` as they are, without the issuer and audience `generate_token` adds
+    fn sign_raw(auth: &AuthManager, claims: &serde_json::Value) -> String {
+        encode(
+            &Header::default(),
+            claims,
+            &EncodingKey::from_secret(&auth.keyring.signing_key().secret),
+        )
+        .unwrap()
+    }
+
+    fn raw_claims(now: i64) -> serde_json::Value {
+        serde_json::json!({
+            "sub": "admin",
+            "role": "admin",
+            "iat": now,
+            "exp": now + 3600,
+            "type": "access",
+            "jti": "raw",
+        })
+    }
+
+    #[test]
+    fn test_tokens_carry_issuer_and_audience() {
+        let dir = tempdir().unwrap();
+        let auth = manager_with_admin(dir.path());
+        let tokens = expect_tokens(auth.login("admin", "correct horse").unwrap());
+
+        let claims = auth.validate_token(&tokens.access_token).unwrap();
+        assert_eq!(claims.iss.as_deref(), Some("claw-pen-orchestrator"));
+        assert_eq!(claims.aud.as_deref(), Some("claw-pen-api"));
+        assert_eq!(claims.nbf, Some(claims.iat));
+    }
+
+    #[test]
+    fn test_foreign_issuer_or_audience_is_rejected() {
+        let dir = tempdir().unwrap();
+        let auth = manager_with_admin(dir.path());
+        let strict = TokenConfig {
+            accept_legacy: false,
+            ..TokenConfig::default()
+        };
+
+        // Same secret and accounts, different issuer or audience
+        let other_issuer = manager_with_tokens(
+            dir.path(),
+            TokenConfig {
+                issuer: "other-orchestrator".to_string(),
+                ..strict.clone()
+            },
+        );
+        let other_audience = manager_with_tokens(
+            dir.path(),
+            TokenConfig {
+                audience: "other-api".to_string(),
+                ..strict.clone()
+            },
+        );
+        for other in [&other_issuer, &other_audience] {
+            let tokens = expect_tokens(other.login("admin", "correct horse").unwrap());
+            assert!(other.validate_token(&tokens.access_token).is_ok());
+            assert!(auth.validate_token(&tokens.access_token).is_err());
+        }
+
+        // The legacy fallback doesn't cover tokens with only one of the two
+        let now = Utc::now().timestamp();
+        let mut claims = raw_claims(now);
+        claims["iss"] = "claw-pen-orchestrator".into();
+        assert!(auth.validate_token(&sign_raw(&auth, &claims)).is_err());
+        let mut claims = raw_claims(now);
+        claims["aud"] = "claw-pen-api".into();
+        assert!(auth.validate_token(&sign_raw(&auth, &claims)).is_err());
+    }
+
+    #[test]
+    fn test_legacy_tokens_depend_on_the_flag() {
+        let dir = tempdir().unwrap();
+        let lenient = manager_with_admin(dir.path());
+        let strict = manager_with_tokens(
+            dir.path(),
+            TokenConfig {
+                accept_legacy: false,
+                ..TokenConfig::default()
+            },
+        );
+
+        let legacy = sign_raw(&lenient, &raw_claims(Utc::now().timestamp()));
+        assert_eq!(lenient.validate_token(&legacy).unwrap().sub, "admin");
+        assert!(strict.validate_token(&legacy).is_err());
+
+        // Expired legacy tokens stay expired
+        let mut claims = raw_claims(Utc::now().timestamp() - 7200);
+        claims["exp"] = (Utc::now().timestamp() - 3600).into();
+        assert!(lenient
+            .validate_token(&sign_raw(&lenient, &claims))
+            .is_err());
+
+        // New tokens work either way
+        let tokens = expect_tokens(strict.login("admin", "correct horse").unwrap());
+        assert!(strict.validate_token(&tokens.access_token).is_ok());
+        assert!(lenient.validate_token(&tokens.access_token).is_ok());
+    }
+
+    #[test]
+    fn test_error_codes_and_response_shapes() {
+        let jwt = |kind| AuthError::JwtError(jsonwebtoken::errors::Error::from(kind));
+        let cases = [
+            (
+                AuthError::InvalidCredentials,
+                401,
+                "INVALID_CREDENTIALS",
+                false,
+            ),
+            (AuthError::InvalidToken, 401, "TOKEN_INVALID", false),
+            (AuthError::TokenExpired, 401, "TOKEN_EXPIRED", false),
+            (AuthError::TokenSuperseded, 401, "TOKEN_SUPERSEDED", false),
+            (
+                jwt(ErrorKind::ExpiredSignature),
+                401,
+                "TOKEN_EXPIRED",
+                false,
+            ),
+            (
+                jwt(ErrorKind::InvalidSignature),
+                401,
+                "TOKEN_INVALID",
+                false,
+            ),
+            (
+                jwt(ErrorKind::ImmatureSignature),
+                401,
+                "TOKEN_INVALID",
+                false,
+            ),
+            (
+                jwt(ErrorKind::InvalidKeyFormat),
+                500,
+                "INTERNAL_ERROR",
+                false,
+            ),
+            (
+                AuthError::MissingAuthHeader,
+                401,
+                "MISSING_AUTH_HEADER",
+                false,
+            ),
+            (AuthError::MissingToken, 401, "MISSING_TOKEN", false),
+            (
+                AuthError::InvalidAuthHeaderFormat,
+                401,
+                "INVALID_AUTH_HEADER",
+                false,
+            ),
+            (
+                AuthError::RegistrationDisabled,
+                403,
+                "REGISTRATION_DISABLED",
+                false,
+            ),
+            (
+                AuthError::RegistrationNetworkDenied,
+                403,
+                "REGISTRATION_NETWORK_DENIED",
+                false,
+            ),
+            (AuthError::UserAlreadyExists, 409, "USER_EXISTS", false),
+            (AuthError::WrongPassword, 403, "WRONG_PASSWORD", false),
+            (
+                AuthError::InvalidBootstrapToken,
+                403,
+                "BOOTSTRAP_TOKEN_INVALID",
+                false,
+            ),
+            (
+                AuthError::WeakPassword(Vec::new()),
+                400,
+                "WEAK_PASSWORD",
+                false,
+            ),
+            (AuthError::Forbidden, 403, "FORBIDDEN", false),
+            (
+                AuthError::MissingScope("agents.write".into()),
+                403,
+                "INSUFFICIENT_SCOPE",
+                false,
+            ),
+            (AuthError::UserNotFound, 404, "USER_NOT_FOUND", false),
+            (AuthError::LastAdmin, 409, "LAST_ADMIN", false),
+            (AuthError::InvalidUsername, 400, "INVALID_USERNAME", false),
+            (AuthError::ApiKeyNotFound, 404, "API_KEY_NOT_FOUND", false),
+            (
+                AuthError::InvalidApiKeyRequest("bad scope".to_string()),
+                400,
+                "INVALID_API_KEY_REQUEST",
+                false,
+            ),
+            (
+                AuthError::TooManyAttempts { retry_after: 5 },
+                429,
+                "RATE_LIMITED",
+                true,
+            ),
+            (
+                AuthError::AccountLocked { retry_after: 60 },
+                429,
+                "ACCOUNT_LOCKED",
+                true,
+            ),
+            (
+                AuthError::TooManyRequests { retry_after: 30 },
+                429,
+                "TOO_MANY_REQUESTS",
+                true,
+            ),
+            (AuthError::InvalidTotpCode, 401, "INVALID_TOTP_CODE", false),
+            (
+                AuthError::InvalidDeviceSignature,
+                401,
+                "DEVICE_SIGNATURE_INVALID",
+                false,
+            ),
+            (
+                AuthError::StaleDeviceSignature,
+                401,
+                "DEVICE_SIGNATURE_STALE",
+                false,
+            ),
+            (
+                AuthError::InvalidDeviceChallenge,
+                401,
+                "DEVICE_CHALLENGE_INVALID",
+                false,
+            ),
+            (
+                AuthError::DeviceNotApproved,
+                403,
+                "DEVICE_NOT_APPROVED",
+                false,
+            ),
+            (AuthError::DeviceNotFound, 404, "DEVICE_NOT_FOUND", false),
+            (AuthError::SessionNotFound, 404, "SESSION_NOT_FOUND", false),
+            (
+                AuthError::InvalidDeviceRequest("bad key".to_string()),
+                400,
+                "INVALID_DEVICE_REQUEST",
+                false,
+            ),
+            (
+                AuthError::InvalidCsrfToken,
+                403,
+                "CSRF_TOKEN_INVALID",
+                false,
+            ),
+            (
+                AuthError::ServiceTokenNotAllowed,
+                403,
+                "SERVICE_TOKEN_NOT_ALLOWED",
+                false,
+            ),
+            (AuthError::PayloadTooLarge, 413, "PAYLOAD_TOO_LARGE", false),
+            (
+                AuthError::InvalidBody("expected value".to_string()),
+                400,
+                "INVALID_BODY",
+                false,
+            ),
+            (
+                AuthError::TotpAlreadyEnabled,
+                409,
+                "TOTP_ALREADY_ENABLED",
+                false,
+            ),
+            (AuthError::TotpNotSetUp, 409, "TOTP_NOT_SET_UP", false),
+            (
+                AuthError::EncryptionError("x".into()),
+                500,
+                "INTERNAL_ERROR",
+                false,
+            ),
+            (
+                AuthError::InvalidConfig("x".into()),
+                500,
+                "INTERNAL_ERROR",
+                false,
+            ),
+            (
+                AuthError::DatabaseError("x".into()),
+                500,
+                "INTERNAL_ERROR",
+                false,
+            ),
+            (
+                AuthError::HashError("x".into()),
+                500,
+                "INTERNAL_ERROR",
+                false,
+            ),
+            (
+                AuthError::IoError(std::io::Error::other("disk")),
+                500,
+                "INTERNAL_ERROR",
+                false,
+            ),
+            (
+                AuthError::JsonError(serde_json::from_str::<u8>("x").unwrap_err()),
+                500,
+                "INTERNAL_ERROR",
+                false,
+            ),
+            (
+                AuthError::Base64Error("x".into()),
+                500,
+                "INTERNAL_ERROR",
+                false,
+            ),
+        ];
+
+        for (err, status, code, retryable) in cases {
+            let (actual_status, _, message) = err.parts();
+            assert_eq!(actual_status.as_u16(), status, "{}", code);
+            assert_eq!(err.code(), code);
+            assert_eq!(err.retryable(), retryable, "{}", code);
+
+            let body = err.body(false);
+            assert_eq!(body["error"]["code"], code);
+            assert_eq!(body["error"]["message"], message);
+            assert_eq!(body["error"]["retryable"], retryable);
+            assert_eq!(body.as_object().unwrap().len(), 1, "{}", code);
+
+            // The old shape, behind AUTH_LEGACY_ERROR_FORMAT
+            assert_eq!(err.body(true)["error"], message, "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_expired_and_invalid_tokens_have_distinct_codes() {
+        let dir = tempdir().unwrap();
+        let auth = manager_with_admin(dir.path());
+        let now = Utc::now().timestamp();
+        let mut claims = raw_claims(now);
+        claims["iss"] = "claw-pen-orchestrator".into();
+        claims["aud"] = "claw-pen-api".into();
+        claims["nbf"] = (now - 7200).into();
+        claims["exp"] = (now - 3600).into();
+
+        let expired = auth.validate_token(&sign_raw(&auth, &claims)).unwrap_err();
+        assert_eq!(expired.code(), "TOKEN_EXPIRED");
+        assert_eq!(expired.parts().0, StatusCode::UNAUTHORIZED);
+        let garbage = auth.validate_token("garbage").unwrap_err();
+        assert_eq!(garbage.code(), "TOKEN_INVALID");
+        assert_eq!(garbage.parts().0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_introspection_only_vouches_for_live_access_tokens() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        add_user(&mut auth, "watcher", Role::Viewer, false);
+        let tokens = expect_tokens(auth.login("watcher", "viewer password").unwrap());
+        let claims = auth.validate_token(&tokens.access_token).unwrap();
+
+        let active = auth.introspect(&tokens.access_token);
+        assert!(active.active);
+        assert_eq!(active.sub.as_deref(), Some("watcher"));
+        assert_eq!(active.exp, Some(claims.exp));
+        assert_eq!(active.iat, Some(claims.iat));
+        assert_eq!(active.token_type.as_deref(), Some("access"));
+        let scope = active.scope.unwrap();
+        assert!(scope.split(' ').any(|s| s == scopes::AGENTS_READ));
+        assert!(!scope.split(' ').any(|s| s == scopes::AGENTS_WRITE));
+
+        let now = Utc::now().timestamp();
+        let mut expired = raw_claims(now);
+        expired["iss"] = "claw-pen-orchestrator".into();
+        expired["aud"] = "claw-pen-api".into();
+        expired["nbf"] = (now - 7200).into();
+        expired["exp"] = (now - 3600).into();
+        let expired = sign_raw(&auth, &expired);
+
+        // A refresh token is live, but not something to call an API with
+        let inactive = [
+            expired,
+            "not.a.jwt".to_string(),
+            tokens.refresh_token.clone(),
+        ];
+        for token in &inactive {
+            assert_eq!(auth.introspect(token), Introspection::inactive());
+        }
+
+        auth.logout(&claims).unwrap();
+        assert_eq!(
+            auth.introspect(&tokens.access_token),
+            Introspection::inactive()
+        );
+
+        let tokens = expect_tokens(auth.login("watcher", "viewer password").unwrap());
+        assert!(auth.introspect(&tokens.access_token).active);
+        auth.logout_all("watcher").unwrap();
+        assert_eq!(
+            auth.introspect(&tokens.access_token),
+            Introspection::inactive()
+        );
+    }
+
+    #[test]
+    fn test_service_tokens_belong_to_one_agent_until_revoked() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        let token = auth
+            .mint_service_token("agent-1", &[scopes::AGENT_SELF_READ], 3600)
+            .unwrap();
+
+        let claims = auth.validate_token(&token).unwrap();
+        assert_eq!(claims.token_type, SERVICE_TOKEN_TYPE);
+        assert_eq!(claims.sub, "agent:agent-1");
+        assert_eq!(claims.agent_id.as_deref(), Some("agent-1"));
+        assert!(claims.has_scope(scopes::AGENT_SELF_READ));
+        assert!(!claims.has_scope(scopes::AGENT_SELF_REPORT));
+        assert!(!claims.has_scope(scopes::AGENTS_READ));
+        assert_eq!(claims.exp - claims.iat, 3600);
+        // Sibling services are only told about people's tokens
+        assert!(!auth.introspect(&token).active);
+
+        // Only service scopes, some of them, for at most MAX_TTL_SECS
+        for (agent_id, requested, ttl) in [
+            ("agent-1", vec![scopes::AGENTS_READ], 3600),
+            ("agent-1", vec![], 3600),
+            ("", vec![scopes::AGENT_SELF_READ], 3600),
+            ("agent-1", vec![scopes::AGENT_SELF_READ], 0),
+            (
+                "agent-1",
+                vec![scopes::AGENT_SELF_READ],
+                service_tokens::MAX_TTL_SECS + 1,
+            ),
+        ] {
+            assert!(matches!(
+                auth.mint_service_token(agent_id, &requested, ttl),
+                Err(AuthError::InvalidConfig(_))
+            ));
+        }
+
+        // Revoking ends this agent's tokens, not another's
+        let other = auth
+            .mint_service_token("agent-2", &[scopes::AGENT_SELF_READ], 3600)
+            .unwrap();
+        auth.revoke_service_tokens("agent-1").unwrap();
+        assert!(matches!(
+            auth.validate_token(&token),
+            Err(AuthError::InvalidToken)
+        ));
+        auth.validate_token(&other).unwrap();
+        // A token minted afterwards, at the next launch, works
+        let relaunched = auth
+            .mint_service_token("agent-1", &[scopes::AGENT_SELF_READ], 3600)
+            .unwrap();
+        auth.validate_token(&relaunched).unwrap();
+    }
+
+    #[test]
+    fn test_nbf_and_exp_allow_configured_leeway() {
+        let dir = tempdir().unwrap();
+        let auth = manager_with_admin(dir.path());
+        let now = Utc::now().timestamp();
+        let with_times = |nbf: i64, exp: i64| {
+            let mut claims = raw_claims(now);
+            claims["iss"] = "claw-pen-orchestrator".into();
+            claims["aud"] = "claw-pen-api".into();
+            claims["nbf"] = nbf.into();
+            claims["exp"] = exp.into();
+            sign_raw(&auth, &claims)
+        };
+
+        // Default leeway is 30 seconds
+        assert!(auth
+            .validate_token(&with_times(now + 10, now + 3600))
+            .is_ok());
+        assert!(auth
+            .validate_token(&with_times(now + 120, now + 3600))
+            .is_err());
+        assert!(auth.validate_token(&with_times(now - 60, now - 10)).is_ok());
+        assert!(auth
+            .validate_token(&with_times(now - 600, now - 120))
+            .is_err());
+
+        let no_leeway = manager_with_tokens(
+            dir.path(),
+            TokenConfig {
+                leeway_secs: 0,
+                ..TokenConfig::default()
+            },
+        );
+        assert!(no_leeway
+            .validate_token(&with_times(now + 10, now + 3600))
+            .is_err());
+        assert!(no_leeway
+            .validate_token(&with_times(now - 60, now - 10))
+            .is_err());
+    }
+
+    fn lookup(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_password_policy_from_variables() {
+        let config = AuthConfig::from_lookup(lookup(&[])).unwrap();
+        assert_eq!(config.password.min_length, 12);
+        assert_eq!(config.password.min_entropy_bits, 50);
+
+        let config = AuthConfig::from_lookup(lookup(&[
+            ("PASSWORD_MIN_LENGTH", "16"),
+            ("PASSWORD_MIN_ENTROPY_BITS", "0"),
+        ]))
+        .unwrap();
+        assert_eq!(config.password.min_length, 16);
+        assert_eq!(config.password.min_entropy_bits, 0);
+    }
+
+    #[test]
+    fn test_weak_passwords_rejected_everywhere() {
+        let dir = tempdir().unwrap();
+        let mut auth = AuthManager::new(dir.path()).unwrap();
+        assert!(matches!(
+            auth.create_first_admin("clawpen2024!"),
+            Err(AuthError::WeakPassword(_))
+        ));
+        assert!(!auth.has_admin());
+        auth.create_first_admin("correct horse").unwrap();
+
+        assert!(matches!(
+            auth.set_password("admin", "password1234"),
+            Err(AuthError::WeakPassword(_))
+        ));
+        let req = CreateUserRequest {
+            username: "viewer".to_string(),
+            password: "aaaaaaaaaaaaaaaa".to_string(),
+            role: Role::Viewer,
+            disabled: false,
+        };
+        assert!(matches!(
+            auth.create_user(&req),
+            Err(AuthError::WeakPassword(_))
+        ));
+        assert!(auth.login("admin", "correct horse").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_weak_password_response_lists_violations() {
+        let err = match PasswordPolicy::default().check("admin") {
+            Err(violations) => AuthError::WeakPassword(violations),
+            Ok(()) => panic!("weak password accepted"),
+        };
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let rules: Vec<&str> = body["error"]["violations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["rule"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                "min_length",
+                "common_password",
+                "forbidden_word",
+                "min_entropy"
+            ]
+        );
+        assert!(body["error"]["violations"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("12 characters"));
+    }
+
+    #[test]
+    fn test_auth_config_from_variables() {
+        let config = AuthConfig::from_lookup(lookup(&[])).unwrap();
+        assert_eq!(config.access_ttl_secs, 24 * 3600);
+        assert_eq!(config.algorithm, KeyAlgorithm::Hs256);
+        let config = AuthConfig::from_lookup(lookup(&[("JWT_ALGORITHM", "EdDSA")])).unwrap();
+        assert_eq!(config.algorithm, KeyAlgorithm::EdDsa);
+        assert!(AuthConfig::from_lookup(lookup(&[("JWT_ALGORITHM", "none")])).is_err());
+        assert_eq!(config.argon2, Params::DEFAULT);
+
+        let config = AuthConfig::from_lookup(lookup(&[
+            ("JWT_ACCESS_TTL_SECS", "900"),
+            ("JWT_REFRESH_TTL_SECS", " 86400 "),
+            ("ARGON2_MEMORY_KIB", "65536"),
+            ("ARGON2_ITERATIONS", "3"),
+            ("ARGON2_PARALLELISM", "4"),
+        ]))
+        .unwrap();
+        assert_eq!(config.access_ttl_secs, 900);
+        assert_eq!(config.refresh_ttl_secs, 86400);
+        assert_eq!(config.argon2.m_cost(), 65536);
+        assert_eq!(config.argon2.t_cost(), 3);
+        assert_eq!(config.argon2.p_cost(), 4);
+
+        for bad in [
+            ("JWT_ACCESS_TTL_SECS", "15m"),
+            ("JWT_ACCESS_TTL_SECS", "0"),
+            ("JWT_ACCESS_TTL_SECS", "-60"),
+            ("JWT_REFRESH_TTL_SECS", "999999999"),
+            ("ARGON2_MEMORY_KIB", "64"),
+            ("ARGON2_ITERATIONS", "0"),
+            ("ARGON2_PARALLELISM", "many"),
+            ("PASSWORD_MIN_LENGTH", "6"),
+            ("PASSWORD_MIN_ENTROPY_BITS", "500"),
+        ] {
+            let err = AuthConfig::from_lookup(lookup(&[bad])).unwrap_err();
+            assert!(
+                matches!(&err, AuthError::InvalidConfig(m) if m.starts_with(bad.0)),
+                "{:?}",
+                bad
+            );
+        }
+        // A refresh token can't expire before its access token
+        assert!(AuthConfig::from_lookup(lookup(&[
+            ("JWT_ACCESS_TTL_SECS", "7200"),
+            ("JWT_REFRESH_TTL_SECS", "3600"),
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_configured_lifetimes_are_used() {
+        let dir = tempdir().unwrap();
+        let config = AuthConfig {
+            access_ttl_secs: 900,
+            refresh_ttl_secs: 3600,
+            ..AuthConfig::default()
+        };
+        let mut auth = AuthManager::with_config(dir.path(), config).unwrap();
+        auth.create_first_admin("correct horse").unwrap();
+
+        let tokens = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        assert_eq!(tokens.expires_in, 900);
+        let access = auth.validate_token(&tokens.access_token).unwrap();
+        assert_eq!(access.exp - access.iat, 900);
+        let refresh = auth.validate_token(&tokens.refresh_token).unwrap();
+        assert_eq!(refresh.exp - refresh.iat, 3600);
+    }
+
+    #[test]
+    fn test_changed_argon2_params_rehash_on_login() {
+        let dir = tempdir().unwrap();
+        let auth = manager_with_admin(dir.path());
+        let tokens = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        assert!(!auth.needs_rehash("admin"));
+
+        let stronger = AuthConfig {
+            argon2: Params::new(32 * 1024, 3, 1, None).unwrap(),
+            ..AuthConfig::default()
+        };
+        let mut auth = AuthManager::with_config(dir.path(), stronger).unwrap();
+        assert!(auth.needs_rehash("admin"));
+
+        // A wrong password doesn't get re-hashed
+        assert!(auth.rehash_password("admin", "wrong horse").is_err());
+        assert!(auth.needs_rehash("admin"));
+
+        auth.rehash_password("admin", "correct horse").unwrap();
+        assert!(!auth.needs_rehash("admin"));
+        let stored = auth.users.get("admin").unwrap().password_hash.clone();
+        assert!(stored.contains("m=32768,t=3,p=1"));
+
+        // Same password, and earlier tokens stay valid
+        expect_tokens(auth.login("admin", "correct horse").unwrap());
+        assert!(auth.validate_token(&tokens.refresh_token).is_ok());
+        assert!(auth.refresh(&tokens.refresh_token).is_ok());
+    }
+
+    #[test]
+    fn test_rotation_keeps_earlier_tokens_valid() {
+        let dir = tempdir().unwrap();
+        let mut auth = manager_with_admin(dir.path());
+        let before = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        let old_kid = decode_header(&before.access_token).unwrap().kid.unwrap();
+
+        let keys = auth.rotate_secret().unwrap();
+        assert_eq!(keys.len(), 2);
+        let after = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        let new_kid = decode_header(&after.access_token).unwrap().kid.unwrap();
+        assert_ne!(old_kid, new_kid);
+        assert_eq!(keys[1].kid, new_kid);
+        assert!(keys[0].retire_at.is_some() && keys[1].retire_at.is_none());
+
+        // Both generations validate, also after a restart
+        let reloaded = AuthManager::new(dir.path()).unwrap();
+        for tokens in [&before, &after] {
+            assert!(auth.validate_token(&tokens.access_token).is_ok());
+            assert!(reloaded.validate_token(&tokens.access_token).is_ok());
+            assert!(reloaded.refresh(&tokens.refresh_token).is_ok());
+        }
+
+        // A token naming one key but signed with another is rejected
+        let mut claims = raw_claims(Utc::now().timestamp());
+        claims["iss"] = "claw-pen-orchestrator".into();
+        claims["aud"] = "claw-pen-api".into();
+        claims["nbf"] = claims["iat"].clone();
+        let forged = encode(
+            &Header {
+                kid: Some(old_kid),
+                ..Header::default()
+            },
+            &claims,
+            &EncodingKey::from_secret(&auth.keyring.signing_key().secret),
+        )
+        .unwrap();
+        assert!(auth.validate_token(&forged).is_err());
+
+        // API keys and two-factor secrets don't depend on the signing key
+        let created = auth
+            .create_api_key(&CreateApiKeyRequest {
+                name: "ci".to_string(),
+                role: Role::Viewer,
+                scopes: vec![],
+                expires_at: None,
+            })
+            .unwrap();
+        auth.rotate_secret().unwrap();
+        assert!(AuthManager::new(dir.path())
+            .unwrap()
+            .validate_api_key(&created.secret)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_single_secret_install_migrates() {
+        let dir = tempdir().unwrap();
+        let auth = manager_with_admin(dir.path());
+        // A token from before keyrings: no kid, signed with jwt_secret
+        let legacy_secret = BASE64_STANDARD
+            .decode(auth.store.secret(JWT_SECRET).unwrap().unwrap())
+            .unwrap();
+        let mut claims = raw_claims(Utc::now().timestamp());
+        claims["iss"] = "claw-pen-orchestrator".into();
+        claims["aud"] = "claw-pen-api".into();
+        claims["nbf"] = claims["iat"].clone();
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(&legacy_secret),
+        )
+        .unwrap();
+
+        // An install that predates the keyring
+        let mut snapshot = auth.store.export().unwrap();
+        snapshot.secrets.remove(auth_store::JWT_KEYS);
+        let store: Arc<dyn AuthStore> = Arc::new(auth_store::MemoryAuthStore::default());
+        store.import(&snapshot).unwrap();
+        let auth = AuthManager::with_store(store.clone(), AuthConfig::default()).unwrap();
+        assert!(store.secret(auth_store::JWT_KEYS).unwrap().is_some());
+        assert_eq!(auth.validate_token(&token).unwrap().sub, "admin");
+    }
+
+    #[test]
+    fn test_master_key_seals_secrets_at_rest() {
+        let raw: Arc<dyn AuthStore> = Arc::new(auth_store::MemoryAuthStore::default());
+        let sealed = |byte: Option<u8>| -> Arc<dyn AuthStore> {
+            let key = byte.map(|b| MasterKey::from_bytes(&[b; 32]).unwrap());
+            Arc::new(SealedStore::new(raw.clone(), key))
+        };
+        let mut auth = AuthManager::with_store(sealed(Some(1)), AuthConfig::default()).unwrap();
+        auth.create_first_admin("correct horse").unwrap();
+        let tokens = expect_tokens(auth.login("admin", "correct horse").unwrap());
+
+        // A copy of the store alone is no use
+        let secrets = raw.export().unwrap().secrets;
+        assert!(secrets.contains_key(JWT_SECRET) && secrets.contains_key(auth_store::JWT_KEYS));
+        assert!(secrets.values().all(|value| master_key::is_sealed(value)));
+
+        let restarted = AuthManager::with_store(sealed(Some(1)), AuthConfig::default()).unwrap();
+        assert_eq!(
+            restarted.validate_token(&tokens.access_token).unwrap().sub,
+            "admin"
+        );
+        assert!(matches!(
+            AuthManager::with_store(sealed(Some(2)), AuthConfig::default()),
+            Err(AuthError::EncryptionError(_))
+        ));
+        assert!(matches!(
+            AuthManager::with_store(sealed(None), AuthConfig::default()),
+            Err(AuthError::InvalidConfig(_))
+        ));
+    }
+
+    fn eddsa_manager(dir: &Path) -> AuthManager {
+        let config = AuthConfig {
+            algorithm: KeyAlgorithm::EdDsa,
+            ..AuthConfig::default()
+        };
+        AuthManager::with_config(dir, config).unwrap()
+    }
+
+    #[test]
+    fn test_switching_to_eddsa_rotates_the_key() {
+        let dir = tempdir().unwrap();
+        let hs = manager_with_admin(dir.path());
+        let hs_tokens = expect_tokens(hs.login("admin", "correct horse").unwrap());
+        assert!(hs.jwks().keys.is_empty());
+
+        let ed = eddsa_manager(dir.path());
+        let ed_tokens = expect_tokens(ed.login("admin", "correct horse").unwrap());
+        let header = decode_header(&ed_tokens.access_token).unwrap();
+        assert_eq!(header.alg, jsonwebtoken::Algorithm::EdDSA);
+
+        // Tokens from before the switch keep working until they expire
+        assert!(ed.validate_token(&hs_tokens.access_token).is_ok());
+        assert!(ed.validate_token(&ed_tokens.access_token).is_ok());
+        assert!(ed.refresh(&hs_tokens.refresh_token).is_ok());
+
+        // Restarting with the same algorithm doesn't rotate again
+        let again = eddsa_manager(dir.path());
+        assert_eq!(again.keyring.list().len(), 2);
+        assert!(again.validate_token(&ed_tokens.access_token).is_ok());
+    }
+
+    #[test]
+    fn test_eddsa_tokens_verify_with_the_published_key() {
+        let dir = tempdir().unwrap();
+        let mut auth = eddsa_manager(dir.path());
+        auth.create_first_admin("correct horse").unwrap();
+        let tokens = expect_tokens(auth.login("admin", "correct horse").unwrap());
+
+        let jwks = auth.jwks();
+        assert_eq!(jwks.keys.len(), 1);
+        let jwk = &jwks.keys[0];
+        assert_eq!(
+            decode_header(&tokens.access_token).unwrap().kid.as_deref(),
+            Some(jwk.kid.as_str())
+        );
+
+        // What a gateway holding only the JWKS would do
+        let key = DecodingKey::from_ed_components(&jwk.x).unwrap();
+        let mut validation = Validation::new(jsonwebtoken::Algorithm::EdDSA);
+        validation.set_issuer(&["claw-pen-orchestrator"]);
+        validation.set_audience(&["claw-pen-api"]);
+        let claims = decode::<Claims>(&tokens.access_token, &key, &validation)
+            .unwrap()
+            .claims;
+        assert_eq!(claims.sub, "admin");
+    }
+
+    #[test]
+    fn test_hs256_token_against_a_public_key_is_rejected() {
+        let dir = tempdir().unwrap();
+        let mut auth = eddsa_manager(dir.path());
+        auth.create_first_admin("correct horse").unwrap();
+        let signing = auth.keyring.signing_key().clone();
+
+        // The public key is no secret; signing HS256 with it must not work
+        let mut claims = raw_claims(Utc::now().timestamp());
+        claims["iss"] = "claw-pen-orchestrator".into();
+        claims["aud"] = "claw-pen-api".into();
+        claims["nbf"] = claims["iat"].clone();
+        for kid in [Some(signing.kid.clone()), None] {
+            let forged = encode(
+                &Header {
+                    kid,
+                    ..Header::default()
+                },
+                &claims,
+                &EncodingKey::from_secret(&signing.public_key),
+            )
+            .unwrap();
+            assert!(auth.validate_token(&forged).is_err());
+        }
+    }
+
+    #[test]
+    fn test_legacy_tokens_default_to_admin() {
+        let claims: Claims = serde_json::from_value(serde_json::json!({
+            "sub": "admin",
+            "exp": 0,
+            "iat": 0,
+            "type": "access"
+        }))
+        .unwrap();
+        assert_eq!(claims.role, Role::Admin);
+    }
+
+    #[test]
+    fn test_login_username_defaults_to_admin() {
+        let req: LoginRequest =
+            serde_json::from_value(serde_json::json!({"password": "correct horse"})).unwrap();
+        assert_eq!(req.username, DEFAULT_USERNAME);
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_set_password_options_from_args() {
+        let options =
+            SetPasswordOptions::from_args(&args(&["orch", "--set-password", "--password-stdin"]))
+                .unwrap();
+        assert_eq!(options.source, PasswordSource::Stdin);
+        assert!(!options.force);
+
+        let options = SetPasswordOptions::from_args(&args(&[
+            "orch",
+            "--set-password",
+            "--force",
+            "--password-file",
+            "/run/secrets/admin",
+        ]))
+        .unwrap();
+        assert_eq!(
+            options.source,
+            PasswordSource::File(PathBuf::from("/run/secrets/admin"))
+        );
+        assert!(options.force);
+
+        for bad in [
+            &["orch", "--set-password", "--password-file"][..],
+            &["orch", "--set-password", "--password-file", "--force"],
+            &[
+                "orch",
+                "--set-password",
+                "--password-file",
+                "p",
+                "--password-stdin",
+            ],
+        ] {
+            assert!(
+                SetPasswordOptions::from_args(&args(bad)).is_err(),
+                "{:?}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_password_file_reads_first_line() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("admin_password");
+        fs::write(&path, "correct horse\r\nsecond line\n").unwrap();
+        assert_eq!(
+            read_password(&PasswordSource::File(path)).unwrap(),
+            "correct horse"
+        );
+        assert!(read_password(&PasswordSource::File(dir.path().join("missing"))).is_err());
+    }
+
+    #[test]
+    fn test_cli_password_needs_policy_and_force() {
+        let dir = tempdir().unwrap();
+        assert!(matches!(
+            set_admin_password(dir.path(), "short", false),
+            Err(AuthError::WeakPassword(_))
+        ));
+        assert!(!dir.path().join("auth.db").exists());
+
+        set_admin_password(dir.path(), "correct horse", false).unwrap();
+        assert!(matches!(
+            set_admin_password(dir.path(), "battery staple", false),
+            Err(AuthError::UserAlreadyExists)
+        ));
+        let auth = AuthManager::new(dir.path()).unwrap();
+        assert!(auth.login("admin", "correct horse").is_ok());
+
+        set_admin_password(dir.path(), "battery staple", true).unwrap();
+        let auth = AuthManager::new(dir.path()).unwrap();
+        assert!(auth.login("admin", "battery staple").is_ok());
+        assert!(auth.login("admin", "correct horse").is_err());
+    }
+
+    #[test]
+    fn test_auth_state_export_and_import() {
+        let dir = tempdir().unwrap();
+        let auth = manager_with_admin(dir.path());
+        let tokens = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        drop(auth);
+
+        let export = dir.path().join("export.json");
+        cli_export_auth_state(dir.path(), &export).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&export).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // A fresh data directory takes over the accounts and signing keys
+        let other = tempdir().unwrap();
+        cli_import_auth_state(other.path(), &export).unwrap();
+        let auth = AuthManager::new(other.path()).unwrap();
+        assert!(auth.login("admin", "correct horse").is_ok());
+        assert!(auth.validate_token(&tokens.access_token).is_ok());
+
+        fs::write(&export, "{}").unwrap();
+        assert!(matches!(
+            cli_import_auth_state(other.path(), &export),
+            Err(AuthError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_export_imports_its_backup() {
+        let dir = tempdir().unwrap();
+        drop(manager_with_admin(dir.path()));
+        let export = dir.path().join("export.json");
+        cli_export_auth_state(dir.path(), &export).unwrap();
+        cli_export_auth_state(dir.path(), &export).unwrap();
+        let contents = fs::read_to_string(&export).unwrap();
+        fs::write(&export, &contents[..contents.len() / 2]).unwrap();
+
+        let other = tempdir().unwrap();
+        cli_import_auth_state(other.path(), &export).unwrap();
+        let auth = AuthManager::new(other.path()).unwrap();
+        assert!(auth.login("admin", "correct horse").is_ok());
+
+        fs::write(auth_files::backup_path(&export), "{").unwrap();
+        assert!(cli_import_auth_state(other.path(), &export).is_err());
+        cli_check_auth_files(other.path()).unwrap();
+    }
+
+    #[test]
+    fn test_loose_files_migrate_into_the_store() {
+        let dir = tempdir().unwrap();
+        let auth = manager_with_admin(dir.path());
+        let tokens = expect_tokens(auth.login("admin", "correct horse").unwrap());
+        let snapshot = auth.store.export().unwrap();
+        drop(auth);
+
+        // Lay the state out as files, the way older versions kept it
+        let legacy = tempdir().unwrap();
+        let write = |name: &str, contents: String| fs::write(legacy.path().join(name), contents);
+        write("jwt_secret", snapshot.secrets[JWT_SECRET].clone()).unwrap();
+        write(
+            "jwt_keys.json",
+            snapshot.secrets[auth_store::JWT_KEYS].clone(),
+        )
+        .unwrap();
+        write(
+            "users.json",
+            serde_json::to_string(&snapshot.users).unwrap(),
+        )
+        .unwrap();
+
+        let auth = AuthManager::new(legacy.path()).unwrap();
+        assert!(auth.login("admin", "correct horse").is_ok());
+        assert!(auth.validate_token(&tokens.access_token).is_ok());
+        for name in ["jwt_secret", "jwt_keys.json", "users.json"] {
+            assert!(!legacy.path().join(name).exists());
+            assert!(legacy.path().join(format!("{}.migrated", name)).exists());
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Request body limits for the auth endpoints
+//!
+//! The auth endpoints are the ones anyone can reach, and their bodies are a
+//! few short strings. So their bodies are capped at [`MAX_BODY_BYTES`]:
+//! [`limit`] refuses a larger `Content-Length` before reading anything, and
+//! [`AuthJson`], the JSON extractor of every auth handler, stops reading a
+//! body without one at the cap. Both answer `413 PAYLOAD_TOO_LARGE`. JSON
+//! nested deeper than [`MAX_JSON_DEPTH`] is refused before serde sees it, and
+//! bodies that don't parse get `400 INVALID_BODY`, both with the usual error
+//! shape.
+//!
+//! Each refused body counts in `claw_pen_auth_rejected_bodies_total{reason}`
+//! and as a failed login from the client's IP, so a client sending junk is
+//! throttled like one guessing passwords.
+
+use axum::{
+    async_trait,
+    extract::{FromRequest, Request, State},
+    http::{header, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use serde::de::DeserializeOwned;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::auth::AuthError;
+use crate::client_ip::ClientIp;
+use crate::AppState;
+
+/// Largest body an auth endpoint reads
+pub const MAX_BODY_BYTES: usize = 4 * 1024;
+
+/// Deepest nesting of JSON arrays and objects an auth endpoint parses
+pub const MAX_JSON_DEPTH: usize = 8;
+
+/// Count a refused body against the client, then return `error`
+async fn reject(state: &AppState, parts: &Parts, error: AuthError) -> AuthError {
+    let ip = parts
+        .extensions
+        .get::<ClientIp>()
+        .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |ClientIp(ip)| *ip);
+    state.metrics.rejected_body(error.code());
+    state
+        .login_limiter
+        .lock()
+        .await
+        .record_failure(ip, Instant::now());
+    tracing::debug!("Refused auth request body from {}: {}", ip, error);
+    error
+}
+
+/// Middleware refusing bodies whose `Content-Length` is over the cap
+pub async fn limit(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let too_large = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .is_some_and(|length| length > MAX_BODY_BYTES as u64);
+    if too_large {
+        let (parts, _) = request.into_parts();
+        return Err(reject(&state, &parts, AuthError::PayloadTooLarge).await);
+    }
+    Ok(next.run(request).await)
+}
+
+/// JSON body of an auth request, read up to [`MAX_BODY_BYTES`] and at most
+/// [`MAX_JSON_DEPTH`] deep
+pub struct AuthJson<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned + Send> FromRequest<Arc<AppState>> for AuthJson<T> {
+    type Rejection = AuthError;
+
+    async fn from_request(request: Request, state: &Arc<AppState>) -> Result<Self, AuthError> {
+        let (parts, body) = request.into_parts();
+        let bytes = match axum::body::to_bytes(body, MAX_BODY_BYTES).await {
+            Ok(bytes) => bytes,
+            // Over the cap, or the client went away; either way, no body
+            Err(_) => return Err(reject(state, &parts, AuthError::PayloadTooLarge).await),
+        };
+        if json_depth(&bytes) > MAX_JSON_DEPTH {
+            let error = AuthError::InvalidBody("JSON is nested too deeply".to_string());
+            return Err(reject(state, &parts, error).await);
+        }
+        match serde_json::from_slice(&bytes) {
+            Ok(value) => Ok(Self(value)),
+            Err(e) => Err(reject(state, &parts, AuthError::InvalidBody(e.to_string())).await),
+        }
+    }
+}
+
+/// Deepest nesting of arrays and objects in `json`, ignoring brackets in
+/// strings; doesn't check the rest is valid
+fn json_depth(json: &[u8]) -> usize {
+    let (mut depth, mut deepest) = (0usize, 0usize);
+    let (mut in_string, mut escaped) = (false, false);
+    for &byte in json {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                deepest = deepest.max(depth);
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    deepest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_depth() {
+        assert_eq!(json_depth(b"\"flat\""), 0);
+        assert_eq!(json_depth(br#"{"username": "admin", "password": "x"}"#), 1);
+        assert_eq!(json_depth(br#"{"a": [1, {"b": []}]}"#), 4);
+        // Brackets in strings, escaped quotes included, don't count
+        assert_eq!(json_depth(br#"{"password": "[[[{\"[["}"#), 1);
+        let deep = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+        assert_eq!(json_depth(deep.as_bytes()), 10_000);
+    }
+}
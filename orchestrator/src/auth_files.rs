@@ -0,0 +1,650 @@
+//! Writing and checking auth files
+//!
+//! Auth state lives in `auth.db`, where SQLite makes every write atomic. The
+//! files still written directly - the bootstrap token and
+//! `--export-auth-state` exports - go through [`write_private_atomic`]: the
+//! new contents go to a temporary file in the same directory, are synced to
+//! disk and made readable only by their owner, and are then renamed over the
+//! target. A crash or power loss leaves the old file or the new one, never a
+//! truncated one. The previous contents are kept as `<name>.bak`, and
+//! [`read_with_backup`] falls back to them when the file itself is missing or
+//! doesn't parse.
+//!
+//! `claw-pen-orchestrator --check-auth-files` runs [`check`] over the data
+//! directory and reports on everything auth keeps there.
+//!
+//! Files restored from a backup or copied in by other tools often come back
+//! readable by everyone, so each start runs [`enforce_permissions`]: auth
+//! files readable or writable by group or others are tightened to owner-only,
+//! and a world-writable data directory loses that bit. With
+//! `STRICT_AUTH_PERMISSIONS=true` the orchestrator refuses to start instead.
+//! Either way the file and its mode are logged. Other platforms skip this.
+
+use argon2::PasswordHash;
+use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::auth::AuthError;
+use crate::auth_store::{self, JWT_SECRET, LEGACY_FILES};
+use crate::bootstrap::{BOOTSTRAP_TOKEN_FILE, BOOTSTRAP_TOKEN_PREFIX};
+use crate::master_key::{self, MasterKey};
+
+/// Appended to a file's name for the copy of its previous contents
+pub const BACKUP_SUFFIX: &str = ".bak";
+
+/// Where the previous contents of `path` are kept
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(BACKUP_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Replace `path` with `contents` through a synced, owner-only temporary
+/// file in the same directory
+fn replace(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let tmp_path = dir.join(format!(
+        ".{}.{:08x}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        rand::random::<u32>()
+    ));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options.open(&tmp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        // The umask can't widen it, but be sure
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|()| fs::rename(&tmp_path, path)) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    sync_dir(dir)
+}
+
+/// Make a rename in `dir` survive a power loss
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Replace a file with owner-only contents without ever leaving it
+/// half-written, keeping what it held before as `<name>.bak`
+pub fn write_private_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    match fs::read(path) {
+        Ok(previous) => replace(&backup_path(path), &previous)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    replace(path, contents.as_bytes())
+}
+
+/// Delete `path` and its backup; missing ones are fine
+pub fn remove_with_backup(path: &Path) -> io::Result<()> {
+    for path in [path.to_path_buf(), backup_path(path)] {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Which copy [`read_with_backup`] read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Primary,
+    /// The file was missing or didn't parse; the caller should say so loudly
+    Backup,
+}
+
+/// Parse `path`, or its backup if `path` is missing or doesn't parse
+pub fn read_with_backup<T, E: Display>(
+    path: &Path,
+    parse: impl Fn(&str) -> Result<T, E>,
+) -> Result<(T, Source), AuthError> {
+    let primary_error = match fs::read_to_string(path) {
+        Ok(contents) => match parse(&contents) {
+            Ok(value) => return Ok((value, Source::Primary)),
+            Err(e) => AuthError::InvalidConfig(format!("{:?} is corrupt: {}", path, e)),
+        },
+        Err(e) => AuthError::IoError(e),
+    };
+    let backup = backup_path(path);
+    let contents = match fs::read_to_string(&backup) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(primary_error),
+        Err(e) => return Err(e.into()),
+    };
+    match parse(&contents) {
+        Ok(value) => {
+            tracing::error!(
+                "{}; read the backup {:?} instead. Check the disk, then rewrite the file",
+                primary_error,
+                backup
+            );
+            Ok((value, Source::Backup))
+        }
+        Err(e) => Err(AuthError::InvalidConfig(format!(
+            "{}, and so is its backup {:?}: {}",
+            primary_error, backup, e
+        ))),
+    }
+}
+
+/// Whether `name`, in the data directory, is a file auth keeps secrets in
+fn is_auth_file(name: &str) -> bool {
+    if let Some(original) = name.strip_suffix(BACKUP_SUFFIX) {
+        return is_auth_file(original);
+    }
+    let legacy = name.strip_suffix(".migrated").unwrap_or(name);
+    // auth.db with its -wal, -shm and -journal files
+    name.starts_with("auth.db")
+        || name.starts_with("ws_tickets.db")
+        || name == BOOTSTRAP_TOKEN_FILE
+        || LEGACY_FILES.contains(&legacy)
+        || (name.starts_with('.') && name.ends_with(".tmp"))
+}
+
+/// A file or directory [`enforce_permissions`] tightened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PermissionFix {
+    pub path: PathBuf,
+    pub mode: u32,
+    pub new_mode: u32,
+}
+
+/// Make sure the auth files in `data_dir` are only readable by their owner
+/// and `data_dir` isn't world-writable, tightening what isn't, or with
+/// `strict` failing on the first one; returns what was tightened
+#[cfg(unix)]
+pub fn enforce_permissions(data_dir: &Path, strict: bool) -> Result<Vec<PermissionFix>, AuthError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let Ok(metadata) = fs::metadata(data_dir) else {
+        // Created with the database
+        return Ok(Vec::new());
+    };
+    let mut fixes = Vec::new();
+    let mut fix =
+        |path: PathBuf, mode: u32, new_mode: u32, problem: &str| -> Result<(), AuthError> {
+            if strict {
+                return Err(AuthError::InvalidConfig(format!(
+                    "{} has mode {:o} and is {}; fix it or unset STRICT_AUTH_PERMISSIONS",
+                    path.display(),
+                    mode,
+                    problem
+                )));
+            }
+            fs::set_permissions(&path, fs::Permissions::from_mode(new_mode))?;
+            tracing::warn!(
+                "{} had mode {:o} and was {}; changed it to {:o}",
+                path.display(),
+                mode,
+                problem,
+                new_mode
+            );
+            fixes.push(PermissionFix {
+                path,
+                mode,
+                new_mode,
+            });
+            Ok(())
+        };
+
+    let mode = metadata.permissions().mode() & 0o7777;
+    if mode & 0o002 != 0 {
+        fix(
+            data_dir.to_path_buf(),
+            mode,
+            mode & !0o002,
+            "world-writable",
+        )?;
+    }
+
+    let mut names: Vec<String> = fs::read_dir(data_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| is_auth_file(name))
+        .collect();
+    names.sort();
+    for name in names {
+        let path = data_dir.join(name);
+        let metadata = fs::metadata(&path)?;
+        let mode = metadata.permissions().mode() & 0o7777;
+        if metadata.is_file() && mode & 0o077 != 0 {
+            fix(path, mode, mode & 0o700, "accessible to group or others")?;
+        }
+    }
+    Ok(fixes)
+}
+
+#[cfg(not(unix))]
+pub fn enforce_permissions(
+    _data_dir: &Path,
+    _strict: bool,
+) -> Result<Vec<PermissionFix>, AuthError> {
+    Ok(Vec::new())
+}
+
+/// How a checked file fared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Ok,
+    /// Works, but wants attention
+    Warning,
+    /// Broken
+    Error,
+}
+
+/// One line of the `--check-auth-files` report
+#[derive(Debug, Clone)]
+pub struct FileCheck {
+    pub path: PathBuf,
+    pub status: Status,
+    pub detail: String,
+}
+
+/// Check everything auth keeps in `data_dir`: `auth.db`, the bootstrap
+/// token, legacy files still to be migrated, backups and temporary files
+/// left by interrupted writes. Changes nothing.
+pub fn check(data_dir: &Path) -> Vec<FileCheck> {
+    let mut checks = Vec::new();
+    let mut report = |path: PathBuf, status: Status, detail: String| {
+        checks.push(FileCheck {
+            path,
+            status,
+            detail,
+        })
+    };
+
+    let db = data_dir.join("auth.db");
+    if db.exists() {
+        let (status, detail) = check_database(&db);
+        report(db, status, detail);
+    } else {
+        report(
+            db,
+            Status::Warning,
+            "missing; the next start creates it, or imports the legacy files".to_string(),
+        );
+    }
+
+    let mut names: Vec<String> = match fs::read_dir(data_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect(),
+        Err(e) => {
+            report(
+                data_dir.to_path_buf(),
+                Status::Error,
+                format!("unreadable: {}", e),
+            );
+            return checks;
+        }
+    };
+    names.sort();
+    for name in names {
+        let path = data_dir.join(&name);
+        let (status, detail) = if name == BOOTSTRAP_TOKEN_FILE {
+            check_contents(&path, |contents| {
+                if contents.trim().starts_with(BOOTSTRAP_TOKEN_PREFIX) {
+                    Ok("bootstrap token".to_string())
+                } else {
+                    Err("not a bootstrap token; restart to make a new one".to_string())
+                }
+            })
+        } else if LEGACY_FILES.contains(&name.as_str()) {
+            let (status, detail) = check_contents(&path, |contents| check_legacy(&name, contents));
+            (
+                status.max(Status::Warning),
+                format!("{}; not migrated to auth.db yet", detail),
+            )
+        } else if let Some(original) = name.strip_suffix(BACKUP_SUFFIX) {
+            (Status::Ok, format!("backup of {}", original))
+        } else if name.starts_with('.') && name.ends_with(".tmp") {
+            (
+                Status::Warning,
+                "left by an interrupted write; safe to delete".to_string(),
+            )
+        } else {
+            continue;
+        };
+        let status = status.max(check_mode(&path));
+        report(path, status, detail);
+    }
+    checks
+}
+
+/// Check `auth.db` with SQLite's integrity check and by reading back every
+/// row, then the values that would stop logins
+fn check_database(path: &Path) -> (Status, String) {
+    let snapshot = match auth_store::check_database(path) {
+        Ok(snapshot) => snapshot,
+        Err(e) => return (Status::Error, e.to_string()),
+    };
+    let mut problems = Vec::new();
+    let mut warnings = Vec::new();
+    let key = match MasterKey::from_env(path.parent().unwrap_or(Path::new("."))) {
+        Ok(key) => key,
+        Err(e) => return (Status::Error, e.to_string()),
+    };
+    let secret = match (snapshot.secrets.get(JWT_SECRET), &key) {
+        (Some(secret), Some(key)) if master_key::is_sealed(secret) => {
+            key.open(JWT_SECRET, secret).map(Some).unwrap_or_else(|e| {
+                problems.push(e.to_string());
+                None
+            })
+        }
+        (Some(secret), None) if master_key::is_sealed(secret) => {
+            warnings.push("the secrets are encrypted; set the master key to check them");
+            None
+        }
+        (Some(secret), _) => Some(secret.clone()),
+        (None, _) => {
+            problems.push("no JWT secret".to_string());
+            None
+        }
+    };
+    if secret.is_some_and(|secret| BASE64_STANDARD.decode(secret.trim()).is_err()) {
+        problems.push("the JWT secret isn't base64".to_string());
+    }
+    if key.is_some()
+        && snapshot
+            .secrets
+            .values()
+            .any(|value| !master_key::is_sealed(value))
+    {
+        warnings.push("some secrets aren't encrypted yet; run --rewrap-auth-secrets");
+    }
+    for user in &snapshot.users {
+        if PasswordHash::new(&user.password_hash).is_err() {
+            problems.push(format!("{}'s password hash doesn't parse", user.username));
+        }
+    }
+    let summary = format!(
+        "{} accounts, {} API keys, {} devices, {} sessions",
+        snapshot.users.len(),
+        snapshot.api_keys.len(),
+        snapshot.devices.len(),
+        snapshot.sessions.len()
+    );
+    let status = if !problems.is_empty() {
+        Status::Error
+    } else if !warnings.is_empty() {
+        Status::Warning.max(check_mode(path))
+    } else {
+        Status::Ok.max(check_mode(path))
+    };
+    problems.extend(warnings.into_iter().map(str::to_string));
+    let detail = if problems.is_empty() {
+        summary
+    } else {
+        format!("{}; {}", summary, problems.join("; "))
+    };
+    (status, detail)
+}
+
+/// Check a legacy file's contents parse as that file's format
+fn check_legacy(name: &str, contents: &str) -> Result<String, String> {
+    match name {
+        "jwt_secret" => BASE64_STANDARD
+            .decode(contents.trim())
+            .map(|_| "JWT secret".to_string())
+            .map_err(|e| format!("not base64: {}", e)),
+        "admin_password" => PasswordHash::new(contents.trim())
+            .map(|_| "password hash".to_string())
+            .map_err(|e| format!("not a password hash: {}", e)),
+        "password_version" => contents
+            .trim()
+            .parse::<u64>()
+            .map(|_| "password version".to_string())
+            .map_err(|e| format!("not a number: {}", e)),
+        _ => serde_json::from_str::<serde_json::Value>(contents)
+            .map(|_| "JSON".to_string())
+            .map_err(|e| format!("not JSON: {}", e)),
+    }
+}
+
+/// Check a file with `parse`, falling back to its backup like
+/// [`read_with_backup`]
+fn check_contents(path: &Path, parse: impl Fn(&str) -> Result<String, String>) -> (Status, String) {
+    match read_with_backup(path, parse) {
+        Ok((detail, Source::Primary)) => (Status::Ok, detail),
+        Ok((detail, Source::Backup)) => (
+            Status::Error,
+            format!("corrupt, but its backup is good ({})", detail),
+        ),
+        Err(e) => (Status::Error, e.to_string()),
+    }
+}
+
+/// Warn about auth files others can read
+#[cfg(unix)]
+fn check_mode(path: &Path) -> Status {
+    use std::os::unix::fs::PermissionsExt;
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.permissions().mode() & 0o077 != 0 => Status::Warning,
+        _ => Status::Ok,
+    }
+}
+
+#[cfg(not(unix))]
+fn check_mode(_path: &Path) -> Status {
+    Status::Ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn parse_number(contents: &str) -> Result<u64, std::num::ParseIntError> {
+        contents.trim().parse()
+    }
+
+    #[test]
+    fn test_writes_keep_one_backup() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("export.json");
+        write_private_atomic(&path, "1").unwrap();
+        assert!(!backup_path(&path).exists());
+        write_private_atomic(&path, "2").unwrap();
+        write_private_atomic(&path, "3").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "3");
+        assert_eq!(fs::read_to_string(backup_path(&path)).unwrap(), "2");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            for path in [&path, &backup_path(&path)] {
+                let mode = fs::metadata(path).unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o600);
+            }
+        }
+        // No temporary files are left behind
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names.len(), 2);
+
+        remove_with_backup(&path).unwrap();
+        assert!(!path.exists() && !backup_path(&path).exists());
+        remove_with_backup(&path).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_file_falls_back_to_backup() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("version");
+        write_private_atomic(&path, "41").unwrap();
+        write_private_atomic(&path, "42").unwrap();
+        assert_eq!(
+            read_with_backup(&path, parse_number).unwrap(),
+            (42, Source::Primary)
+        );
+
+        // As a crash during a plain `fs::write` would leave it
+        fs::write(&path, "").unwrap();
+        assert_eq!(
+            read_with_backup(&path, parse_number).unwrap(),
+            (41, Source::Backup)
+        );
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            read_with_backup(&path, parse_number).unwrap(),
+            (41, Source::Backup)
+        );
+
+        fs::write(&path, "x").unwrap();
+        fs::write(backup_path(&path), "y").unwrap();
+        let err = read_with_backup(&path, parse_number).unwrap_err();
+        assert!(err.to_string().contains("its backup"));
+
+        let missing = dir.path().join("missing");
+        assert!(matches!(
+            read_with_backup(&missing, parse_number),
+            Err(AuthError::IoError(e)) if e.kind() == io::ErrorKind::NotFound
+        ));
+    }
+
+    #[test]
+    fn test_check_reports_each_artifact() {
+        let dir = tempdir().unwrap();
+        let status = |checks: &[FileCheck], name: &str| {
+            checks
+                .iter()
+                .find(|c| c.path == dir.path().join(name))
+                .map(|c| c.status)
+        };
+
+        let checks = check(dir.path());
+        assert_eq!(status(&checks, "auth.db"), Some(Status::Warning));
+
+        let mut auth = crate::auth::AuthManager::new(dir.path()).unwrap();
+        auth.create_first_admin("correct horse").unwrap();
+        drop(auth);
+        write_private_atomic(&dir.path().join(BOOTSTRAP_TOKEN_FILE), "cpb_abc\n").unwrap();
+        write_private_atomic(&dir.path().join(BOOTSTRAP_TOKEN_FILE), "cpb_def\n").unwrap();
+        fs::write(dir.path().join(".bootstrap_token.0badf00d.tmp"), "cpb_").unwrap();
+        let checks = check(dir.path());
+        assert_eq!(status(&checks, "auth.db"), Some(Status::Ok));
+        assert_eq!(status(&checks, BOOTSTRAP_TOKEN_FILE), Some(Status::Ok));
+        assert_eq!(status(&checks, "bootstrap_token.bak"), Some(Status::Ok));
+        assert_eq!(
+            status(&checks, ".bootstrap_token.0badf00d.tmp"),
+            Some(Status::Warning)
+        );
+
+        // A truncated token with a good backup is still an error to fix
+        fs::write(dir.path().join(BOOTSTRAP_TOKEN_FILE), "cp").unwrap();
+        fs::write(dir.path().join("admin_password"), "argon2id$v=19$m=").unwrap();
+        let checks = check(dir.path());
+        let token = checks
+            .iter()
+            .find(|c| c.path.ends_with(BOOTSTRAP_TOKEN_FILE))
+            .unwrap();
+        assert_eq!(token.status, Status::Error);
+        assert!(token.detail.contains("backup is good"));
+        assert_eq!(status(&checks, "admin_password"), Some(Status::Error));
+
+        fs::write(dir.path().join("auth.db"), "SQLite format 3\0truncated").unwrap();
+        let checks = check(dir.path());
+        assert_eq!(status(&checks, "auth.db"), Some(Status::Error));
+    }
+
+    #[cfg(unix)]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[cfg(unix)]
+    fn set_mode(path: &Path, mode: u32) {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_loose_permissions_are_tightened() {
+        let dir = tempdir().unwrap();
+        for (name, loose) in [
+            ("auth.db", 0o644),
+            ("auth.db-wal", 0o660),
+            (BOOTSTRAP_TOKEN_FILE, 0o640),
+            ("bootstrap_token.bak", 0o604),
+            ("users.json.migrated", 0o666),
+            ("notes.txt", 0o644),
+        ] {
+            fs::write(dir.path().join(name), "x").unwrap();
+            set_mode(&dir.path().join(name), loose);
+        }
+        set_mode(dir.path(), 0o777);
+
+        let fixes = enforce_permissions(dir.path(), false).unwrap();
+        assert_eq!(
+            fixes[0],
+            PermissionFix {
+                path: dir.path().to_path_buf(),
+                mode: 0o777,
+                new_mode: 0o775,
+            }
+        );
+        assert_eq!(fixes.len(), 6);
+        assert_eq!(mode(dir.path()), 0o775);
+        assert_eq!(mode(&dir.path().join("auth.db")), 0o600);
+        assert_eq!(mode(&dir.path().join("users.json.migrated")), 0o600);
+        // Not ours
+        assert_eq!(mode(&dir.path().join("notes.txt")), 0o644);
+
+        // Nothing left to do
+        assert!(enforce_permissions(dir.path(), false).unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_strict_permissions_refuse_instead() {
+        let dir = tempdir().unwrap();
+        let db = dir.path().join("auth.db");
+        fs::write(&db, "x").unwrap();
+        set_mode(&db, 0o644);
+
+        match enforce_permissions(dir.path(), true) {
+            Err(AuthError::InvalidConfig(message)) => {
+                assert!(message.contains(&db.display().to_string()), "{}", message);
+                assert!(message.contains("644"), "{}", message);
+            }
+            other => panic!("expected a refusal, got {:?}", other),
+        }
+        assert_eq!(mode(&db), 0o644);
+
+        set_mode(&db, 0o600);
+        set_mode(dir.path(), 0o707);
+        assert!(enforce_permissions(dir.path(), true).is_err());
+        set_mode(dir.path(), 0o755);
+        assert!(enforce_permissions(dir.path(), true).unwrap().is_empty());
+    }
+}
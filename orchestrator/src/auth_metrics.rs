@@ -0,0 +1,275 @@
+//! Prometheus metrics for the auth subsystem
+//!
+//! - `claw_pen_auth_login_attempts_total{outcome}` - `success`, `mfa_required`
+//!   or the error code, e.g. `INVALID_CREDENTIALS` or `RATE_LIMITED`
+//! - `claw_pen_auth_token_validations_total{result}` - `valid` or the error code
+//! - `claw_pen_auth_denylist_size` - revoked tokens that haven't expired yet
+//! - `claw_pen_auth_refresh_rotations_total` - refresh tokens exchanged for new pairs
+//! - `claw_pen_auth_middleware_seconds` - time the auth middleware takes to
+//!   authenticate a request
+//! - `claw_pen_auth_rejected_bodies_total{reason}` - auth request bodies
+//!   refused as `PAYLOAD_TOO_LARGE` or `INVALID_BODY`
+//!
+//! `GET /metrics` serves them in the Prometheus text format to clients in
+//! `METRICS_ALLOWED_NETWORKS` (comma-separated CIDRs, default loopback) and to
+//! anyone sending `Authorization: Bearer <METRICS_SCRAPE_TOKEN>`.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use sha2::{Digest, Sha256};
+
+use crate::auth::AuthError;
+use crate::client_ip::{loopback_networks, networks_from_env, ClientIp, IpNetwork};
+use crate::AppState;
+
+/// Authentication takes microseconds for a JWT and up to milliseconds for an
+/// API key, below the default buckets
+const LATENCY_BUCKETS: &[f64] = &[
+    0.000_05, 0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01, 0.025, 0.05, 0.1,
+];
+
+pub struct AuthMetrics {
+    registry: Registry,
+    login_attempts: IntCounterVec,
+    token_validations: IntCounterVec,
+    denylist_size: IntGauge,
+    refresh_rotations: IntCounter,
+    middleware_seconds: Histogram,
+    rejected_bodies: IntCounterVec,
+}
+
+impl AuthMetrics {
+    pub fn new() -> Self {
+        let login_attempts = IntCounterVec::new(
+            Opts::new(
+                "claw_pen_auth_login_attempts_total",
+                "Login attempts by outcome",
+            ),
+            &["outcome"],
+        )
+        .expect("valid metric");
+        let token_validations = IntCounterVec::new(
+            Opts::new(
+                "claw_pen_auth_token_validations_total",
+                "JWT validations by result",
+            ),
+            &["result"],
+        )
+        .expect("valid metric");
+        let denylist_size = IntGauge::new(
+            "claw_pen_auth_denylist_size",
+            "Revoked tokens that haven't expired yet",
+        )
+        .expect("valid metric");
+        let refresh_rotations = IntCounter::new(
+            "claw_pen_auth_refresh_rotations_total",
+            "Refresh tokens exchanged for new pairs",
+        )
+        .expect("valid metric");
+        let middleware_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "claw_pen_auth_middleware_seconds",
+                "Time the auth middleware takes to authenticate a request",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+        )
+        .expect("valid metric");
+        let rejected_bodies = IntCounterVec::new(
+            Opts::new(
+                "claw_pen_auth_rejected_bodies_total",
+                "Auth request bodies refused, by reason",
+            ),
+            &["reason"],
+        )
+        .expect("valid metric");
+
+        let registry = Registry::new();
+        for collector in [
+            Box::new(login_attempts.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(token_validations.clone()),
+            Box::new(denylist_size.clone()),
+            Box::new(refresh_rotations.clone()),
+            Box::new(middleware_seconds.clone()),
+            Box::new(rejected_bodies.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+
+        Self {
+            registry,
+            login_attempts,
+            token_validations,
+            denylist_size,
+            refresh_rotations,
+            middleware_seconds,
+            rejected_bodies,
+        }
+    }
+
+    pub fn login(&self, outcome: &str) {
+        self.login_attempts.with_label_values(&[outcome]).inc();
+    }
+
+    pub fn token_validation(&self, result: &str) {
+        self.token_validations.with_label_values(&[result]).inc();
+    }
+
+    pub fn refresh_rotation(&self) {
+        self.refresh_rotations.inc();
+    }
+
+    pub fn middleware_latency(&self, elapsed: Duration) {
+        self.middleware_seconds.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn rejected_body(&self, reason: &str) {
+        self.rejected_bodies.with_label_values(&[reason]).inc();
+    }
+
+    /// Every series in the Prometheus text format; the denylist size is only
+    /// read when scraped
+    pub fn render(&self, denylist_size: usize) -> String {
+        self.denylist_size.set(denylist_size as i64);
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("text encoding doesn't fail");
+        String::from_utf8(buffer).expect("text encoding is UTF-8")
+    }
+}
+
+impl Default for AuthMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Who may scrape `GET /metrics`
+#[derive(Debug, Clone)]
+pub struct MetricsAccess {
+    pub allowed_networks: Vec<IpNetwork>,
+    pub scrape_token: Option<String>,
+}
+
+impl Default for MetricsAccess {
+    fn default() -> Self {
+        Self {
+            allowed_networks: loopback_networks(),
+            scrape_token: None,
+        }
+    }
+}
+
+impl MetricsAccess {
+    pub fn from_env() -> Result<Self, AuthError> {
+        let mut access = Self::default();
+        if let Some(networks) = networks_from_env("METRICS_ALLOWED_NETWORKS")? {
+            access.allowed_networks = networks;
+        }
+        access.scrape_token = std::env::var("METRICS_SCRAPE_TOKEN")
+            .ok()
+            .filter(|t| !t.is_empty());
+        Ok(access)
+    }
+
+    /// Whether a request from `ip` with `headers` may scrape
+    pub fn allows(&self, ip: Option<IpAddr>, headers: &HeaderMap) -> bool {
+        if ip.is_some_and(|ip| self.allowed_networks.iter().any(|n| n.contains(ip))) {
+            return true;
+        }
+        let Some(expected) = &self.scrape_token else {
+            return false;
+        };
+        let presented = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        // Compare digests so the time taken says nothing about the token
+        presented.is_some_and(|token| {
+            Sha256::digest(token.as_bytes()) == Sha256::digest(expected.as_bytes())
+        })
+    }
+}
+
+/// GET /metrics - Auth metrics for Prometheus
+pub async fn scrape(
+    State(state): State<Arc<AppState>>,
+    client_ip: Option<ClientIp>,
+    headers: HeaderMap,
+) -> Result<Response, AuthError> {
+    let ip = client_ip.map(|ClientIp(ip)| ip);
+    if !state.metrics_access.allows(ip, &headers) {
+        return Err(AuthError::Forbidden);
+    }
+    let denylist_size = state.auth.read().await.revoked_count();
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            TextEncoder::new().format_type().to_string(),
+        )],
+        state.metrics.render(denylist_size),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_access_by_network_or_token() {
+        let mut access = MetricsAccess::default();
+        let none = HeaderMap::new();
+        assert!(access.allows(Some("127.0.0.1".parse().unwrap()), &none));
+        assert!(!access.allows(Some("192.0.2.1".parse().unwrap()), &none));
+        assert!(!access.allows(None, &none));
+
+        access.scrape_token = Some("scrape-secret".to_string());
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer scrape-secret"),
+        );
+        assert!(access.allows(None, &headers));
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer scrape-secreT"),
+        );
+        assert!(!access.allows(None, &headers));
+    }
+
+    #[test]
+    fn test_render_lists_every_series() {
+        let metrics = AuthMetrics::new();
+        metrics.login("INVALID_CREDENTIALS");
+        metrics.token_validation("valid");
+        metrics.refresh_rotation();
+        metrics.middleware_latency(Duration::from_micros(80));
+        metrics.rejected_body("PAYLOAD_TOO_LARGE");
+        let text = metrics.render(3);
+        assert!(
+            text.contains("claw_pen_auth_login_attempts_total{outcome=\"INVALID_CREDENTIALS\"} 1")
+        );
+        assert!(text.contains("claw_pen_auth_token_validations_total{result=\"valid\"} 1"));
+        assert!(text.contains("claw_pen_auth_denylist_size 3"));
+        assert!(text.contains("claw_pen_auth_refresh_rotations_total 1"));
+        assert!(text.contains("claw_pen_auth_middleware_seconds_count 1"));
+        assert!(
+            text.contains("claw_pen_auth_rejected_bodies_total{reason=\"PAYLOAD_TOO_LARGE\"} 1")
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Reloading auth state while running
+//!
+//! `--set-password` and other CLI modes write the auth store directly, so a
+//! running server keeps serving what it read at startup. Three things make it
+//! re-read the store:
+//! - `POST /auth/reload` (admin only)
+//! - `SIGHUP`, on unix
+//! - with `AUTH_RELOAD_WATCH_SECS` set, a change to `auth.db` that has settled
+//!   for that many seconds; for containers, where signals are awkward
+//!
+//! A reload that fails leaves the previous state in place.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::auth::{AuthError, ReloadSummary};
+use crate::AppState;
+
+/// Reload under the write lock, logging what changed; `source` says what
+/// asked for it
+pub async fn reload(state: &AppState, source: &str) -> Result<ReloadSummary, AuthError> {
+    let result = state.auth.write().await.reload();
+    match &result {
+        Ok(summary) if summary.is_empty() => {
+            tracing::debug!("Reloaded auth state ({}): no changes", source)
+        }
+        Ok(summary) => tracing::info!("Reloaded auth state ({}): {}", source, summary),
+        Err(e) => tracing::warn!("Kept the previous auth state ({}): {}", source, e),
+    }
+    result
+}
+
+/// Reload on every `SIGHUP`
+#[cfg(unix)]
+pub fn spawn_sighup_listener(state: Arc<AppState>) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            let _ = reload(&state, "SIGHUP").await;
+        }
+    });
+    Ok(())
+}
+
+/// How often to check the store for changes, from `AUTH_RELOAD_WATCH_SECS`;
+/// `None` when unset or 0
+pub fn watch_interval_from_env() -> Result<Option<Duration>, AuthError> {
+    let Some(raw) = std::env::var("AUTH_RELOAD_WATCH_SECS")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+    else {
+        return Ok(None);
+    };
+    let secs: u64 = raw.trim().parse().map_err(|_| {
+        AuthError::InvalidConfig(format!(
+            "AUTH_RELOAD_WATCH_SECS must be a whole number of seconds, got {:?}",
+            raw
+        ))
+    })?;
+    Ok((secs > 0).then(|| Duration::from_secs(secs)))
+}
+
+/// Modification time and size of the store's files, which change on every
+/// write
+fn fingerprint(data_dir: &Path) -> Vec<Option<(SystemTime, u64)>> {
+    ["auth.db", "auth.db-wal"]
+        .iter()
+        .map(|name| {
+            let meta = std::fs::metadata(data_dir.join(name)).ok()?;
+            Some((meta.modified().ok()?, meta.len()))
+        })
+        .collect()
+}
+
+/// Watches the store's files every `interval` and reloads once a change
+/// has stopped changing for a whole interval, so a burst of writes causes one
+/// reload
+pub struct StoreWatcher {
+    data_dir: PathBuf,
+    last: Vec<Option<(SystemTime, u64)>>,
+    pending: Option<Vec<Option<(SystemTime, u64)>>>,
+}
+
+impl StoreWatcher {
+    pub fn new(data_dir: PathBuf) -> Self {
+        let last = fingerprint(&data_dir);
+        Self {
+            data_dir,
+            last,
+            pending: None,
+        }
+    }
+
+    /// Look at the files again, returning whether a settled change is due
+    /// for a reload
+    pub fn poll(&mut self) -> bool {
+        let now = fingerprint(&self.data_dir);
+        if now == self.last {
+            self.pending = None;
+            return false;
+        }
+        if self.pending.as_ref() == Some(&now) {
+            self.last = now;
+            self.pending = None;
+            return true;
+        }
+        self.pending = Some(now);
+        false
+    }
+}
+
+pub fn spawn_watcher(state: Arc<AppState>, data_dir: PathBuf, interval: Duration) {
+    tokio::spawn(async move {
+        let mut watcher = StoreWatcher::new(data_dir);
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            if watcher.poll() {
+                let _ = reload(&state, "auth.db changed").await;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_watcher_waits_for_changes_to_settle() {
+        let dir = tempdir().unwrap();
+        let db = dir.path().join("auth.db");
+        std::fs::write(&db, "one").unwrap();
+        let mut watcher = StoreWatcher::new(dir.path().to_path_buf());
+        assert!(!watcher.poll());
+
+        std::fs::write(&db, "two!").unwrap();
+        assert!(!watcher.poll());
+        // Still changing
+        std::fs::write(&db, "three").unwrap();
+        assert!(!watcher.poll());
+        // Settled
+        assert!(watcher.poll());
+        assert!(!watcher.poll());
+
+        std::fs::remove_file(&db).unwrap();
+        assert!(!watcher.poll());
+        assert!(watcher.poll());
+    }
+}
@@ -0,0 +1,1044 @@
+//! Auth state storage
+//!
+//! Accounts, revoked token ids, token generations, API keys, approved
+//! devices, login sessions and signing secrets live behind [`AuthStore`]. The orchestrator keeps them in `auth.db`
+//! in the data directory, a SQLite database readable only by its owner; tests
+//! use an in-memory one. The auth types load what they need when
+//! `AuthManager` starts and write every change through the store. Steps that
+//! check and then write, like creating the first admin or removing an admin,
+//! run in one transaction, so racing requests or processes can't both pass the
+//! check.
+//!
+//! A data directory from before `auth.db` is migrated on first start: the
+//! loose files (`jwt_secret`, `jwt_keys.json`, `users.json` or
+//! `admin_password`, `revoked_tokens.json`, `token_generations.json`,
+//! `auth_api_keys.json`) are imported and each is renamed with a `.migrated`
+//! suffix.
+//!
+//! [`AuthSnapshot`] is everything in a store, for `--export-auth-state` and
+//! `--import-auth-state` backups.
+//!
+//! With a master key set, the secrets are sealed on their way into the
+//! store; see [`crate::master_key`].
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::api_keys::ApiKey;
+use crate::auth::AuthError;
+use crate::devices::Device;
+use crate::master_key::{MasterKey, SealedStore, MASTER_KEY_ENV};
+use crate::sessions::Session;
+use crate::users::{Role, User, DEFAULT_USERNAME};
+
+/// Name of the base64 root secret API key hashes and TOTP encryption derive from
+pub const JWT_SECRET: &str = "jwt_secret";
+
+/// Name of the JSON signing keyring
+pub const JWT_KEYS: &str = "jwt_keys";
+
+/// Name of the base64 key audit log entries hash source IPs with
+pub const AUDIT_IP_KEY: &str = "audit_ip_key";
+
+/// Everything an [`AuthStore`] holds
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuthSnapshot {
+    #[serde(default)]
+    pub users: Vec<User>,
+    /// jti -> expiry of the revoked token
+    #[serde(default)]
+    pub revoked_tokens: HashMap<String, i64>,
+    #[serde(default)]
+    pub token_generations: HashMap<String, u64>,
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    #[serde(default)]
+    pub sessions: Vec<Session>,
+    /// Secrets by name: [`JWT_SECRET`], [`JWT_KEYS`] and [`AUDIT_IP_KEY`]
+    #[serde(default)]
+    pub secrets: BTreeMap<String, String>,
+}
+
+pub trait AuthStore: Send + Sync {
+    fn users(&self) -> Result<Vec<User>, AuthError>;
+
+    /// Add an account; with `first_admin`, only while no enabled admin exists
+    fn insert_user(&self, user: &User, first_admin: bool) -> Result<(), AuthError>;
+
+    /// Replace a stored account
+    fn update_user(&self, user: &User) -> Result<(), AuthError>;
+
+    /// Remove an account unless it is the last enabled admin
+    fn remove_user(&self, username: &str) -> Result<(), AuthError>;
+
+    /// Revoked token ids that haven't expired by `now`
+    fn revoked_tokens(&self, now: i64) -> Result<HashMap<String, i64>, AuthError>;
+
+    /// Revoke a token until `exp`, dropping entries expired by `now`
+    fn revoke_token(&self, jti: &str, exp: i64, now: i64) -> Result<(), AuthError>;
+
+    fn token_generations(&self) -> Result<HashMap<String, u64>, AuthError>;
+
+    /// Bump a subject's token generation, returning the new one
+    fn bump_generation(&self, subject: &str) -> Result<u64, AuthError>;
+
+    fn api_keys(&self) -> Result<Vec<ApiKey>, AuthError>;
+
+    /// Add an API key or replace the one with its id
+    fn put_api_key(&self, key: &ApiKey) -> Result<(), AuthError>;
+
+    fn remove_api_key(&self, id: &str) -> Result<(), AuthError>;
+
+    fn devices(&self) -> Result<Vec<Device>, AuthError>;
+
+    /// Add a device or replace the one with its id
+    fn put_device(&self, device: &Device) -> Result<(), AuthError>;
+
+    /// Sessions that hadn't expired when one was last put
+    fn sessions(&self) -> Result<Vec<Session>, AuthError>;
+
+    fn session(&self, id: &str) -> Result<Option<Session>, AuthError>;
+
+    /// Add a session or replace the one with its id, dropping those expired
+    /// by `now`
+    fn put_session(&self, session: &Session, now: i64) -> Result<(), AuthError>;
+
+    fn secret(&self, name: &str) -> Result<Option<String>, AuthError>;
+
+    fn set_secret(&self, name: &str, value: &str) -> Result<(), AuthError>;
+
+    fn export(&self) -> Result<AuthSnapshot, AuthError>;
+
+    /// Replace everything with `snapshot`
+    fn import(&self, snapshot: &AuthSnapshot) -> Result<(), AuthError>;
+}
+
+/// Fail unless `user` may be added next to `users`
+fn check_insert(users: &[User], user: &User, first_admin: bool) -> Result<(), AuthError> {
+    let admin_exists = users.iter().any(|u| u.role == Role::Admin && !u.disabled);
+    if users.iter().any(|u| u.username == user.username) || (first_admin && admin_exists) {
+        return Err(AuthError::UserAlreadyExists);
+    }
+    Ok(())
+}
+
+/// Fail unless account `username` may be removed from `users`
+fn check_remove(users: &[User], username: &str) -> Result<(), AuthError> {
+    let user = users
+        .iter()
+        .find(|u| u.username == username)
+        .ok_or(AuthError::UserNotFound)?;
+    let other_admin = users
+        .iter()
+        .any(|u| u.username != username && u.role == Role::Admin && !u.disabled);
+    if user.role == Role::Admin && !user.disabled && !other_admin {
+        return Err(AuthError::LastAdmin);
+    }
+    Ok(())
+}
+
+/// An [`AuthStore`] that forgets everything when dropped, for tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryAuthStore {
+    state: Mutex<AuthSnapshot>,
+}
+
+#[cfg(test)]
+impl MemoryAuthStore {
+    fn state(&self) -> std::sync::MutexGuard<'_, AuthSnapshot> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+impl AuthStore for MemoryAuthStore {
+    fn users(&self) -> Result<Vec<User>, AuthError> {
+        Ok(self.state().users.clone())
+    }
+
+    fn insert_user(&self, user: &User, first_admin: bool) -> Result<(), AuthError> {
+        let mut state = self.state();
+        check_insert(&state.users, user, first_admin)?;
+        state.users.push(user.clone());
+        Ok(())
+    }
+
+    fn update_user(&self, user: &User) -> Result<(), AuthError> {
+        let mut state = self.state();
+        let stored = state
+            .users
+            .iter_mut()
+            .find(|u| u.username == user.username)
+            .ok_or(AuthError::UserNotFound)?;
+        *stored = user.clone();
+        Ok(())
+    }
+
+    fn remove_user(&self, username: &str) -> Result<(), AuthError> {
+        let mut state = self.state();
+        check_remove(&state.users, username)?;
+        state.users.retain(|u| u.username != username);
+        Ok(())
+    }
+
+    fn revoked_tokens(&self, now: i64) -> Result<HashMap<String, i64>, AuthError> {
+        let mut revoked = self.state().revoked_tokens.clone();
+        revoked.retain(|_, exp| *exp > now);
+        Ok(revoked)
+    }
+
+    fn revoke_token(&self, jti: &str, exp: i64, now: i64) -> Result<(), AuthError> {
+        let mut state = self.state();
+        state.revoked_tokens.retain(|_, exp| *exp > now);
+        state.revoked_tokens.insert(jti.to_string(), exp);
+        Ok(())
+    }
+
+    fn token_generations(&self) -> Result<HashMap<String, u64>, AuthError> {
+        Ok(self.state().token_generations.clone())
+    }
+
+    fn bump_generation(&self, subject: &str) -> Result<u64, AuthError> {
+        let mut state = self.state();
+        let generation = state
+            .token_generations
+            .entry(subject.to_string())
+            .or_insert(0);
+        *generation += 1;
+        Ok(*generation)
+    }
+
+    fn api_keys(&self) -> Result<Vec<ApiKey>, AuthError> {
+        Ok(self.state().api_keys.clone())
+    }
+
+    fn put_api_key(&self, key: &ApiKey) -> Result<(), AuthError> {
+        let mut state = self.state();
+        match state.api_keys.iter_mut().find(|k| k.id == key.id) {
+            Some(stored) => *stored = key.clone(),
+            None => state.api_keys.push(key.clone()),
+        }
+        Ok(())
+    }
+
+    fn remove_api_key(&self, id: &str) -> Result<(), AuthError> {
+        let mut state = self.state();
+        let before = state.api_keys.len();
+        state.api_keys.retain(|k| k.id != id);
+        if state.api_keys.len() == before {
+            return Err(AuthError::ApiKeyNotFound);
+        }
+        Ok(())
+    }
+
+    fn devices(&self) -> Result<Vec<Device>, AuthError> {
+        Ok(self.state().devices.clone())
+    }
+
+    fn put_device(&self, device: &Device) -> Result<(), AuthError> {
+        let mut state = self.state();
+        match state.devices.iter_mut().find(|d| d.id == device.id) {
+            Some(stored) => *stored = device.clone(),
+            None => state.devices.push(device.clone()),
+        }
+        Ok(())
+    }
+
+    fn sessions(&self) -> Result<Vec<Session>, AuthError> {
+        Ok(self.state().sessions.clone())
+    }
+
+    fn session(&self, id: &str) -> Result<Option<Session>, AuthError> {
+        Ok(self.state().sessions.iter().find(|s| s.id == id).cloned())
+    }
+
+    fn put_session(&self, session: &Session, now: i64) -> Result<(), AuthError> {
+        let mut state = self.state();
+        state.sessions.retain(|s| s.expires_at > now);
+        match state.sessions.iter_mut().find(|s| s.id == session.id) {
+            Some(stored) => *stored = session.clone(),
+            None => state.sessions.push(session.clone()),
+        }
+        Ok(())
+    }
+
+    fn secret(&self, name: &str) -> Result<Option<String>, AuthError> {
+        Ok(self.state().secrets.get(name).cloned())
+    }
+
+    fn set_secret(&self, name: &str, value: &str) -> Result<(), AuthError> {
+        self.state()
+            .secrets
+            .insert(name.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn export(&self) -> Result<AuthSnapshot, AuthError> {
+        Ok(self.state().clone())
+    }
+
+    fn import(&self, snapshot: &AuthSnapshot) -> Result<(), AuthError> {
+        *self.state() = snapshot.clone();
+        Ok(())
+    }
+}
+
+/// The [`AuthStore`] in `auth.db`
+pub struct SqliteAuthStore {
+    conn: Mutex<Connection>,
+}
+
+fn db_error(err: rusqlite::Error) -> AuthError {
+    AuthError::DatabaseError(err.to_string())
+}
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        username TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS revoked_tokens (
+        jti TEXT PRIMARY KEY,
+        exp INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS token_generations (
+        subject TEXT PRIMARY KEY,
+        generation INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS api_keys (
+        id TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS devices (
+        id TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS sessions (
+        id TEXT PRIMARY KEY,
+        expires_at INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS secrets (
+        name TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+";
+
+/// Rows of `sql`, each a JSON document
+fn json_rows<T: serde::de::DeserializeOwned>(
+    conn: &Connection,
+    sql: &str,
+) -> Result<Vec<T>, AuthError> {
+    let mut statement = conn.prepare(sql).map_err(db_error)?;
+    let rows = statement
+        .query_map([], |row| row.get::<_, String>(0))
+        .map_err(db_error)?;
+    let mut values = Vec::new();
+    for row in rows {
+        values.push(serde_json::from_str(&row.map_err(db_error)?)?);
+    }
+    Ok(values)
+}
+
+/// Rows of `sql` as a map of its first column to its second
+fn pairs<V: rusqlite::types::FromSql>(
+    conn: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<(String, V)>, AuthError> {
+    let mut statement = conn.prepare(sql).map_err(db_error)?;
+    let rows = statement
+        .query_map(params, |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(db_error)?;
+    rows.collect::<Result<_, _>>().map_err(db_error)
+}
+
+impl SqliteAuthStore {
+    /// Open or create the database at `path`, readable only by us
+    pub fn open(path: &Path) -> Result<Self, AuthError> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(false);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)?;
+
+        let conn = Connection::open(path).map_err(db_error)?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))
+            .map_err(db_error)?;
+        conn.execute_batch(SCHEMA).map_err(db_error)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl AuthStore for SqliteAuthStore {
+    fn users(&self) -> Result<Vec<User>, AuthError> {
+        json_rows(&self.conn(), "SELECT data FROM users ORDER BY username")
+    }
+
+    fn insert_user(&self, user: &User, first_admin: bool) -> Result<(), AuthError> {
+        let mut conn = self.conn();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(db_error)?;
+        let users: Vec<User> = json_rows(&tx, "SELECT data FROM users")?;
+        check_insert(&users, user, first_admin)?;
+        tx.execute(
+            "INSERT INTO users (username, data) VALUES (?1, ?2)",
+            params![user.username, serde_json::to_string(user)?],
+        )
+        .map_err(db_error)?;
+        tx.commit().map_err(db_error)
+    }
+
+    fn update_user(&self, user: &User) -> Result<(), AuthError> {
+        let updated = self
+            .conn()
+            .execute(
+                "UPDATE users SET data = ?2 WHERE username = ?1",
+                params![user.username, serde_json::to_string(user)?],
+            )
+            .map_err(db_error)?;
+        if updated == 0 {
+            return Err(AuthError::UserNotFound);
+        }
+        Ok(())
+    }
+
+    fn remove_user(&self, username: &str) -> Result<(), AuthError> {
+        let mut conn = self.conn();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(db_error)?;
+        let users: Vec<User> = json_rows(&tx, "SELECT data FROM users")?;
+        check_remove(&users, username)?;
+        tx.execute("DELETE FROM users WHERE username = ?1", params![username])
+            .map_err(db_error)?;
+        tx.commit().map_err(db_error)
+    }
+
+    fn revoked_tokens(&self, now: i64) -> Result<HashMap<String, i64>, AuthError> {
+        Ok(pairs(
+            &self.conn(),
+            "SELECT jti, exp FROM revoked_tokens WHERE exp > ?1",
+            params![now],
+        )?
+        .into_iter()
+        .collect())
+    }
+
+    fn revoke_token(&self, jti: &str, exp: i64, now: i64) -> Result<(), AuthError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(db_error)?;
+        tx.execute("DELETE FROM revoked_tokens WHERE exp <= ?1", params![now])
+            .map_err(db_error)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO revoked_tokens (jti, exp) VALUES (?1, ?2)",
+            params![jti, exp],
+        )
+        .map_err(db_error)?;
+        tx.commit().map_err(db_error)
+    }
+
+    fn token_generations(&self) -> Result<HashMap<String, u64>, AuthError> {
+        Ok(pairs::<i64>(
+            &self.conn(),
+            "SELECT subject, generation FROM token_generations",
+            [],
+        )?
+        .into_iter()
+        .map(|(subject, generation)| (subject, generation as u64))
+        .collect())
+    }
+
+    fn bump_generation(&self, subject: &str) -> Result<u64, AuthError> {
+        let generation: i64 = self
+            .conn()
+            .query_row(
+                "INSERT INTO token_generations (subject, generation) VALUES (?1, 1)
+                 ON CONFLICT (subject) DO UPDATE SET generation = generation + 1
+                 RETURNING generation",
+                params![subject],
+                |row| row.get(0),
+            )
+            .map_err(db_error)?;
+        Ok(generation as u64)
+    }
+
+    fn api_keys(&self) -> Result<Vec<ApiKey>, AuthError> {
+        json_rows(&self.conn(), "SELECT data FROM api_keys ORDER BY rowid")
+    }
+
+    fn put_api_key(&self, key: &ApiKey) -> Result<(), AuthError> {
+        self.conn()
+            .execute(
+                "INSERT INTO api_keys (id, data) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+                params![key.id, serde_json::to_string(key)?],
+            )
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn remove_api_key(&self, id: &str) -> Result<(), AuthError> {
+        let removed = self
+            .conn()
+            .execute("DELETE FROM api_keys WHERE id = ?1", params![id])
+            .map_err(db_error)?;
+        if removed == 0 {
+            return Err(AuthError::ApiKeyNotFound);
+        }
+        Ok(())
+    }
+
+    fn devices(&self) -> Result<Vec<Device>, AuthError> {
+        json_rows(&self.conn(), "SELECT data FROM devices ORDER BY rowid")
+    }
+
+    fn put_device(&self, device: &Device) -> Result<(), AuthError> {
+        self.conn()
+            .execute(
+                "INSERT INTO devices (id, data) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+                params![device.id, serde_json::to_string(device)?],
+            )
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn sessions(&self) -> Result<Vec<Session>, AuthError> {
+        json_rows(&self.conn(), "SELECT data FROM sessions ORDER BY rowid")
+    }
+
+    fn session(&self, id: &str) -> Result<Option<Session>, AuthError> {
+        let data: Option<String> = self
+            .conn()
+            .query_row(
+                "SELECT data FROM sessions WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_error)?;
+        Ok(data.map(|data| serde_json::from_str(&data)).transpose()?)
+    }
+
+    fn put_session(&self, session: &Session, now: i64) -> Result<(), AuthError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(db_error)?;
+        tx.execute("DELETE FROM sessions WHERE expires_at <= ?1", params![now])
+            .map_err(db_error)?;
+        tx.execute(
+            "INSERT INTO sessions (id, expires_at, data) VALUES (?1, ?2, ?3)
+             ON CONFLICT (id) DO UPDATE SET
+                 expires_at = excluded.expires_at, data = excluded.data",
+            params![
+                session.id,
+                session.expires_at,
+                serde_json::to_string(session)?
+            ],
+        )
+        .map_err(db_error)?;
+        tx.commit().map_err(db_error)
+    }
+
+    fn secret(&self, name: &str) -> Result<Option<String>, AuthError> {
+        self.conn()
+            .query_row(
+                "SELECT value FROM secrets WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_error)
+    }
+
+    fn set_secret(&self, name: &str, value: &str) -> Result<(), AuthError> {
+        self.conn()
+            .execute(
+                "INSERT OR REPLACE INTO secrets (name, value) VALUES (?1, ?2)",
+                params![name, value],
+            )
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn export(&self) -> Result<AuthSnapshot, AuthError> {
+        let mut conn = self.conn();
+        // One read transaction, so the snapshot is consistent
+        let tx = conn.transaction().map_err(db_error)?;
+        Ok(AuthSnapshot {
+            users: json_rows(&tx, "SELECT data FROM users ORDER BY username")?,
+            revoked_tokens: pairs(&tx, "SELECT jti, exp FROM revoked_tokens", [])?
+                .into_iter()
+                .collect(),
+            token_generations: pairs::<i64>(
+                &tx,
+                "SELECT subject, generation FROM token_generations",
+                [],
+            )?
+            .into_iter()
+            .map(|(subject, generation)| (subject, generation as u64))
+            .collect(),
+            api_keys: json_rows(&tx, "SELECT data FROM api_keys ORDER BY rowid")?,
+            devices: json_rows(&tx, "SELECT data FROM devices ORDER BY rowid")?,
+            sessions: json_rows(&tx, "SELECT data FROM sessions ORDER BY rowid")?,
+            secrets: pairs(&tx, "SELECT name, value FROM secrets", [])?
+                .into_iter()
+                .collect(),
+        })
+    }
+
+    fn import(&self, snapshot: &AuthSnapshot) -> Result<(), AuthError> {
+        let mut conn = self.conn();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(db_error)?;
+        tx.execute_batch(
+            "DELETE FROM users;
+             DELETE FROM revoked_tokens;
+             DELETE FROM token_generations;
+             DELETE FROM api_keys;
+             DELETE FROM devices;
+             DELETE FROM sessions;
+             DELETE FROM secrets;",
+        )
+        .map_err(db_error)?;
+        for user in &snapshot.users {
+            tx.execute(
+                "INSERT INTO users (username, data) VALUES (?1, ?2)",
+                params![user.username, serde_json::to_string(user)?],
+            )
+            .map_err(db_error)?;
+        }
+        for (jti, exp) in &snapshot.revoked_tokens {
+            tx.execute(
+                "INSERT INTO revoked_tokens (jti, exp) VALUES (?1, ?2)",
+                params![jti, exp],
+            )
+            .map_err(db_error)?;
+        }
+        for (subject, generation) in &snapshot.token_generations {
+            tx.execute(
+                "INSERT INTO token_generations (subject, generation) VALUES (?1, ?2)",
+                params![subject, *generation as i64],
+            )
+            .map_err(db_error)?;
+        }
+        for key in &snapshot.api_keys {
+            tx.execute(
+                "INSERT INTO api_keys (id, data) VALUES (?1, ?2)",
+                params![key.id, serde_json::to_string(key)?],
+            )
+            .map_err(db_error)?;
+        }
+        for device in &snapshot.devices {
+            tx.execute(
+                "INSERT INTO devices (id, data) VALUES (?1, ?2)",
+                params![device.id, serde_json::to_string(device)?],
+            )
+            .map_err(db_error)?;
+        }
+        for session in &snapshot.sessions {
+            tx.execute(
+                "INSERT INTO sessions (id, expires_at, data) VALUES (?1, ?2, ?3)",
+                params![
+                    session.id,
+                    session.expires_at,
+                    serde_json::to_string(session)?
+                ],
+            )
+            .map_err(db_error)?;
+        }
+        for (name, value) in &snapshot.secrets {
+            tx.execute(
+                "INSERT INTO secrets (name, value) VALUES (?1, ?2)",
+                params![name, value],
+            )
+            .map_err(db_error)?;
+        }
+        tx.commit().map_err(db_error)
+    }
+}
+
+/// Open `auth.db` in `data_dir`, sealing secrets with the master key if one
+/// is set, and migrating the loose files of older versions into it on first
+/// start
+pub fn open(data_dir: &Path) -> Result<Arc<dyn AuthStore>, AuthError> {
+    fs::create_dir_all(data_dir)?;
+    let key = MasterKey::from_env(data_dir)?;
+    let sealing = key.is_some();
+    let store = SealedStore::new(
+        Arc::new(SqliteAuthStore::open(&data_dir.join("auth.db"))?),
+        key,
+    );
+    if store.secret(JWT_SECRET)?.is_none() {
+        migrate_files(data_dir, &store)?;
+    }
+    if !sealing {
+        tracing::warn!(
+            "No master key set; the auth secrets in {:?} are stored unencrypted. Set {} to encrypt them.",
+            data_dir,
+            MASTER_KEY_ENV
+        );
+    } else if !store.plaintext_secrets()?.is_empty() {
+        tracing::warn!(
+            "Some auth secrets were stored before the master key was set; run --rewrap-auth-secrets to encrypt them"
+        );
+    }
+    Ok(Arc::new(store))
+}
+
+/// Check the database at `path` without changing it: SQLite's integrity
+/// check, then every row read back
+pub fn check_database(path: &Path) -> Result<AuthSnapshot, AuthError> {
+    let conn =
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(db_error)?;
+    let result: String = conn
+        .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+        .map_err(db_error)?;
+    if result != "ok" {
+        return Err(AuthError::DatabaseError(result));
+    }
+    SqliteAuthStore {
+        conn: Mutex::new(conn),
+    }
+    .export()
+}
+
+/// Files the auth state used to be kept in, in the data directory
+pub(crate) const LEGACY_FILES: &[&str] = &[
+    "jwt_secret",
+    "jwt_keys.json",
+    "users.json",
+    "admin_password",
+    "password_version",
+    "revoked_tokens.json",
+    "token_generations.json",
+    "auth_api_keys.json",
+];
+
+/// Import the legacy files in `data_dir` into `store` and mark them migrated
+fn migrate_files(data_dir: &Path, store: &dyn AuthStore) -> Result<(), AuthError> {
+    let read = |name: &str| -> Result<Option<String>, AuthError> {
+        let path = data_dir.join(name);
+        if path.exists() {
+            Ok(Some(fs::read_to_string(path)?))
+        } else {
+            Ok(None)
+        }
+    };
+    if LEGACY_FILES
+        .iter()
+        .all(|name| !data_dir.join(name).exists())
+    {
+        return Ok(());
+    }
+
+    let mut snapshot = AuthSnapshot::default();
+    if let Some(secret) = read("jwt_secret")? {
+        snapshot
+            .secrets
+            .insert(JWT_SECRET.to_string(), secret.trim().to_string());
+    }
+    if let Some(keys) = read("jwt_keys.json")? {
+        snapshot.secrets.insert(JWT_KEYS.to_string(), keys);
+    }
+    if let Some(users) = read("users.json")? {
+        snapshot.users = serde_json::from_str(&users)?;
+    } else if let Some(hash) = read("admin_password")? {
+        // From the single-admin days
+        let password_version = read("password_version")?
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(0);
+        snapshot.users.push(User {
+            username: DEFAULT_USERNAME.to_string(),
+            password_hash: hash.trim().to_string(),
+            role: Role::Admin,
+            disabled: false,
+            password_version,
+            password_changed_at: None,
+            totp: None,
+        });
+    }
+    if let Some(revoked) = read("revoked_tokens.json")? {
+        snapshot.revoked_tokens = serde_json::from_str(&revoked)?;
+    }
+    if let Some(generations) = read("token_generations.json")? {
+        snapshot.token_generations = serde_json::from_str(&generations)?;
+    }
+    if let Some(keys) = read("auth_api_keys.json")? {
+        snapshot.api_keys = serde_json::from_str(&keys)?;
+    }
+    store.import(&snapshot)?;
+
+    for name in LEGACY_FILES {
+        let path = data_dir.join(name);
+        if path.exists() {
+            fs::rename(&path, data_dir.join(format!("{}.migrated", name)))?;
+        }
+    }
+    tracing::info!(
+        "Migrated auth state in {:?} to auth.db; the old files now end in .migrated",
+        data_dir
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn user(username: &str, role: Role) -> User {
+        User {
+            username: username.to_string(),
+            password_hash: "hash".to_string(),
+            role,
+            disabled: false,
+            password_version: 0,
+            password_changed_at: None,
+            totp: None,
+        }
+    }
+
+    fn device(id: &str) -> Device {
+        Device {
+            id: id.to_string(),
+            public_key: "a2V5".to_string(),
+            username: "admin".to_string(),
+            name: None,
+            approved_by: "admin".to_string(),
+            approved_at: 1_000,
+            revoked_at: None,
+        }
+    }
+
+    fn session(id: &str, expires_at: i64) -> Session {
+        Session {
+            id: id.to_string(),
+            username: "admin".to_string(),
+            created_at: 1_000,
+            last_refresh: 1_000,
+            expires_at,
+            user_agent: Some("curl/8.0".to_string()),
+            ip_hash: None,
+            device_id: None,
+            generation: 0,
+            revoked_at: None,
+        }
+    }
+
+    fn stores(dir: &Path) -> Vec<Box<dyn AuthStore>> {
+        vec![
+            Box::new(MemoryAuthStore::default()),
+            Box::new(SqliteAuthStore::open(&dir.join("auth.db")).unwrap()),
+        ]
+    }
+
+    #[test]
+    fn test_first_admin_and_last_admin_checks() {
+        let dir = tempdir().unwrap();
+        for store in stores(dir.path()) {
+            store
+                .insert_user(&user("admin", Role::Admin), true)
+                .unwrap();
+            // A second first admin loses the race
+            assert!(matches!(
+                store.insert_user(&user("other", Role::Admin), true),
+                Err(AuthError::UserAlreadyExists)
+            ));
+            assert!(matches!(
+                store.insert_user(&user("admin", Role::Viewer), false),
+                Err(AuthError::UserAlreadyExists)
+            ));
+            assert!(matches!(
+                store.remove_user("admin"),
+                Err(AuthError::LastAdmin)
+            ));
+            assert!(matches!(
+                store.remove_user("nobody"),
+                Err(AuthError::UserNotFound)
+            ));
+
+            store
+                .insert_user(&user("second", Role::Admin), false)
+                .unwrap();
+            store.remove_user("admin").unwrap();
+            let users = store.users().unwrap();
+            assert_eq!(users.len(), 1);
+            assert_eq!(users[0].username, "second");
+        }
+    }
+
+    #[test]
+    fn test_records_round_trip() {
+        let dir = tempdir().unwrap();
+        for store in stores(dir.path()) {
+            let mut admin = user("admin", Role::Admin);
+            store.insert_user(&admin, false).unwrap();
+            admin.password_version = 4;
+            store.update_user(&admin).unwrap();
+            assert_eq!(store.users().unwrap()[0].password_version, 4);
+            assert!(matches!(
+                store.update_user(&user("nobody", Role::Viewer)),
+                Err(AuthError::UserNotFound)
+            ));
+
+            store.revoke_token("short", 1_100, 1_000).unwrap();
+            store.revoke_token("long", 5_000, 1_000).unwrap();
+            assert_eq!(store.revoked_tokens(1_050).unwrap().len(), 2);
+            store.revoke_token("other", 6_000, 2_000).unwrap();
+            let revoked = store.revoked_tokens(2_000).unwrap();
+            assert!(!revoked.contains_key("short"));
+            assert_eq!(revoked.get("long"), Some(&5_000));
+
+            assert_eq!(store.bump_generation("admin").unwrap(), 1);
+            assert_eq!(store.bump_generation("admin").unwrap(), 2);
+            assert_eq!(store.token_generations().unwrap().get("admin"), Some(&2));
+
+            assert!(store.secret(JWT_SECRET).unwrap().is_none());
+            store.set_secret(JWT_SECRET, "c2VjcmV0").unwrap();
+            store.set_secret(JWT_SECRET, "bmV3").unwrap();
+            assert_eq!(store.secret(JWT_SECRET).unwrap().as_deref(), Some("bmV3"));
+
+            assert!(matches!(
+                store.remove_api_key("missing"),
+                Err(AuthError::ApiKeyNotFound)
+            ));
+
+            let mut laptop = device("laptop");
+            store.put_device(&laptop).unwrap();
+            store.put_device(&device("desktop")).unwrap();
+            laptop.revoked_at = Some(2_000);
+            store.put_device(&laptop).unwrap();
+            assert_eq!(store.devices().unwrap(), vec![laptop, device("desktop")]);
+
+            let mut phone = session("phone", 5_000);
+            store.put_session(&session("short", 1_500), 1_000).unwrap();
+            store.put_session(&phone, 1_000).unwrap();
+            phone.revoked_at = Some(1_200);
+            store.put_session(&phone, 1_200).unwrap();
+            assert_eq!(store.session("phone").unwrap(), Some(phone.clone()));
+            // Putting a session drops the expired ones
+            store.put_session(&session("laptop", 6_000), 2_000).unwrap();
+            assert_eq!(store.session("short").unwrap(), None);
+            assert_eq!(
+                store.sessions().unwrap(),
+                vec![phone, session("laptop", 6_000)]
+            );
+        }
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let dir = tempdir().unwrap();
+        let source = SqliteAuthStore::open(&dir.path().join("source.db")).unwrap();
+        source
+            .insert_user(&user("admin", Role::Admin), false)
+            .unwrap();
+        source.revoke_token("jti", 5_000, 1_000).unwrap();
+        source.bump_generation("admin").unwrap();
+        source.set_secret(JWT_SECRET, "c2VjcmV0").unwrap();
+        source.put_device(&device("laptop")).unwrap();
+        source.put_session(&session("s1", 5_000), 1_000).unwrap();
+        let snapshot = source.export().unwrap();
+        assert_eq!(snapshot.devices.len(), 1);
+        assert_eq!(snapshot.sessions.len(), 1);
+
+        for target in stores(dir.path()) {
+            target
+                .insert_user(&user("stale", Role::Viewer), false)
+                .unwrap();
+            target.import(&snapshot).unwrap();
+            let copy = target.export().unwrap();
+            assert_eq!(
+                serde_json::to_value(&copy).unwrap(),
+                serde_json::to_value(&snapshot).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_database_is_private_and_shared() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("auth.db");
+        let one = SqliteAuthStore::open(&path).unwrap();
+        let two = SqliteAuthStore::open(&path).unwrap();
+        one.insert_user(&user("admin", Role::Admin), true).unwrap();
+        assert!(matches!(
+            two.insert_user(&user("racer", Role::Admin), true),
+            Err(AuthError::UserAlreadyExists)
+        ));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_migrates_loose_files_once() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("jwt_secret"), "c2VjcmV0\n").unwrap();
+        let users = serde_json::to_string(&[user("admin", Role::Admin)]).unwrap();
+        fs::write(dir.path().join("users.json"), users).unwrap();
+        fs::write(
+            dir.path().join("revoked_tokens.json"),
+            r#"{"jti": 9999999999}"#,
+        )
+        .unwrap();
+        fs::write(dir.path().join("token_generations.json"), r#"{"admin": 3}"#).unwrap();
+
+        let store = open(dir.path()).unwrap();
+        assert_eq!(
+            store.secret(JWT_SECRET).unwrap().as_deref(),
+            Some("c2VjcmV0")
+        );
+        assert_eq!(store.users().unwrap()[0].username, "admin");
+        assert!(store.revoked_tokens(0).unwrap().contains_key("jti"));
+        assert_eq!(store.token_generations().unwrap().get("admin"), Some(&3));
+        assert!(!dir.path().join("users.json").exists());
+        assert!(dir.path().join("users.json.migrated").exists());
+        assert!(dir.path().join("jwt_secret.migrated").exists());
+        drop(store);
+
+        // Opening again keeps what's in the database
+        let reopened = open(dir.path()).unwrap();
+        assert_eq!(reopened.users().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_migrates_legacy_admin_password() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("jwt_secret"), "c2VjcmV0").unwrap();
+        fs::write(dir.path().join("admin_password"), "$argon2id$legacy\n").unwrap();
+        fs::write(dir.path().join("password_version"), "3").unwrap();
+
+        let store = open(dir.path()).unwrap();
+        let users = store.users().unwrap();
+        assert_eq!(users[0].username, DEFAULT_USERNAME);
+        assert_eq!(users[0].password_hash, "$argon2id$legacy");
+        assert_eq!(users[0].role, Role::Admin);
+        assert_eq!(users[0].password_version, 3);
+        assert!(!dir.path().join("admin_password").exists());
+        assert!(dir.path().join("admin_password.migrated").exists());
+    }
+}
@@ -0,0 +1,111 @@
+//! First-run bootstrap token
+//!
+//! While no admin exists, anyone who can reach `/auth/register` could become
+//! one. So on a start without an admin, the orchestrator makes a one-time
+//! token, logs it and writes it to `bootstrap_token` in the data directory,
+//! readable only by its user. Registering the first admin needs the token.
+//! It is deleted once used, once an admin exists some other way, or after 24
+//! hours; a restart makes a new one. Only a SHA-256 of it is kept in memory.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+use crate::auth::AuthError;
+use crate::auth_files::{remove_with_backup, write_private_atomic};
+
+/// Prefix of every bootstrap token
+pub const BOOTSTRAP_TOKEN_PREFIX: &str = "cpb_";
+
+/// File in the data directory the token is written to
+pub const BOOTSTRAP_TOKEN_FILE: &str = "bootstrap_token";
+
+/// Seconds a token stays usable
+pub const BOOTSTRAP_TOKEN_TTL_SECS: i64 = 24 * 3600;
+
+/// Random bytes in a token, after the prefix
+const TOKEN_LENGTH: usize = 32;
+
+pub struct BootstrapToken {
+    hash: [u8; 32],
+    expires_at: i64,
+    path: PathBuf,
+}
+
+impl BootstrapToken {
+    /// Make a token and write it to `data_dir`, replacing any earlier one;
+    /// returns the token itself for logging
+    pub fn create(data_dir: &Path, now: i64) -> Result<(Self, String), AuthError> {
+        let mut bytes = [0u8; TOKEN_LENGTH];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let secret = format!(
+            "{}{}",
+            BOOTSTRAP_TOKEN_PREFIX,
+            URL_SAFE_NO_PAD.encode(bytes)
+        );
+        let path = data_dir.join(BOOTSTRAP_TOKEN_FILE);
+        write_private_atomic(&path, &format!("{}\n", secret))?;
+        let token = Self {
+            hash: Sha256::digest(secret.as_bytes()).into(),
+            expires_at: now + BOOTSTRAP_TOKEN_TTL_SECS,
+            path,
+        };
+        Ok((token, secret))
+    }
+
+    pub fn expires_at(&self) -> i64 {
+        self.expires_at
+    }
+
+    /// Whether `presented` is this token and it hasn't expired
+    pub fn matches(&self, presented: &str, now: i64) -> bool {
+        now < self.expires_at && Sha256::digest(presented.trim().as_bytes()).as_slice() == self.hash
+    }
+
+    /// Delete the token's file, and the backup of an earlier one
+    pub fn discard(self) {
+        if let Err(e) = remove_with_backup(&self.path) {
+            tracing::warn!("Failed to delete {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Delete a token file left by an earlier run, e.g. once the CLI has set
+/// an admin password
+pub fn remove_file(data_dir: &Path) -> std::io::Result<()> {
+    remove_with_backup(&data_dir.join(BOOTSTRAP_TOKEN_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_token_is_written_privately_and_expires() {
+        let dir = tempdir().unwrap();
+        let (token, secret) = BootstrapToken::create(dir.path(), 1_000).unwrap();
+        let path = dir.path().join(BOOTSTRAP_TOKEN_FILE);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().trim(), secret);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        assert!(token.matches(&secret, 1_000));
+        assert!(!token.matches("cpb_guess", 1_000));
+        assert!(!token.matches(&secret, 1_000 + BOOTSTRAP_TOKEN_TTL_SECS));
+
+        // A restart replaces the file, keeping the old token as a backup
+        let (token, _) = BootstrapToken::create(dir.path(), 2_000).unwrap();
+        let backup = crate::auth_files::backup_path(&path);
+        assert_eq!(std::fs::read_to_string(&backup).unwrap().trim(), secret);
+
+        token.discard();
+        assert!(!path.exists() && !backup.exists());
+        remove_file(dir.path()).unwrap();
+    }
+}
@@ -0,0 +1,564 @@
+//! Client addresses behind reverse proxies
+//!
+//! The orchestrator usually sits behind nginx, so the socket peer of most
+//! requests is the proxy. With `TRUSTED_PROXIES` (comma-separated CIDRs) set,
+//! a request whose peer is in that list has its client taken from
+//! `X-Forwarded-For`, or `Forwarded` without it: the rightmost address that
+//! isn't itself a trusted proxy. Any other peer is the client, and the
+//! headers are ignored, since anyone can send them.
+//!
+//! `resolve` runs before every route and stores the result as a [`ClientIp`],
+//! which the login limiter, the audit log, `/auth/register` and `/metrics`
+//! all use.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Request, State},
+    http::{request::Parts, HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::auth::AuthError;
+use crate::validation::{self, ValidationError};
+use crate::AppState;
+
+/// An address range like `10.0.0.0/8`; a bare address is a range of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub const fn new(addr: IpAddr, prefix: u8) -> Self {
+        Self { addr, prefix }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// The same network with the bits past the prefix cleared, as in
+    /// `10.0.0.0/8` for `10.1.2.3/8`
+    pub fn masked(&self) -> Self {
+        let (first, _) = self.range();
+        let addr = match self.addr {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(first as u32)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(first)),
+        };
+        Self::new(addr, self.prefix)
+    }
+
+    /// Whether every address of `other` is in this network
+    pub fn contains_network(&self, other: &IpNetwork) -> bool {
+        self.addr.is_ipv4() == other.addr.is_ipv4()
+            && self.prefix <= other.prefix
+            && self.contains(other.addr)
+    }
+
+    /// First and last address, as integers of the family's width
+    fn range(&self) -> (u128, u128) {
+        let (bits, addr) = match self.addr {
+            IpAddr::V4(addr) => (32, u32::from(addr) as u128),
+            IpAddr::V6(addr) => (128, u128::from(addr)),
+        };
+        let host_bits = bits - self.prefix as u32;
+        let host_mask = u128::MAX.checked_shr(128 - host_bits).unwrap_or(0);
+        (addr & !host_mask, addr | host_mask)
+    }
+}
+
+impl std::fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Networks compiled for matching many addresses against: the ranges they
+/// cover per family, merged and sorted, so a lookup is a binary search
+/// however many networks there are
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpMatcher {
+    v4: Vec<(u128, u128)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpMatcher {
+    pub fn new(networks: &[IpNetwork]) -> Self {
+        let merged = |v4: bool| {
+            let mut ranges: Vec<(u128, u128)> = networks
+                .iter()
+                .filter(|n| n.addr.is_ipv4() == v4)
+                .map(IpNetwork::range)
+                .collect();
+            ranges.sort_unstable();
+            let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+            for (first, last) in ranges {
+                match merged.last_mut() {
+                    // Overlapping or adjacent
+                    Some((_, end)) if first <= end.saturating_add(1) => *end = (*end).max(last),
+                    _ => merged.push((first, last)),
+                }
+            }
+            merged
+        };
+        Self {
+            v4: merged(true),
+            v6: merged(false),
+        }
+    }
+
+    /// Whether `ip` is in any of the networks; IPv4-mapped IPv6 addresses
+    /// count as IPv4, as in [`IpNetwork::contains`]
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (ranges, ip) = match ip.to_canonical() {
+            IpAddr::V4(ip) => (&self.v4, u32::from(ip) as u128),
+            IpAddr::V6(ip) => (&self.v6, u128::from(ip)),
+        };
+        // The last range starting at or before `ip`
+        let i = ranges.partition_point(|(first, _)| *first <= ip);
+        i > 0 && ip <= ranges[i - 1].1
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("{:?} is not an IP address", addr))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("{:?} is not a prefix length up to {}", prefix, max))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// `127.0.0.0/8` and `::1`, the default for endpoints only local clients reach
+pub fn loopback_networks() -> Vec<IpNetwork> {
+    vec![
+        IpNetwork::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8),
+        IpNetwork::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 128),
+    ]
+}
+
+/// Parse a comma-separated list of networks from the environment variable
+/// `var`; `None` when it is unset
+pub fn networks_from_env(var: &str) -> Result<Option<Vec<IpNetwork>>, AuthError> {
+    let Ok(networks) = std::env::var(var) else {
+        return Ok(None);
+    };
+    networks
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(|n| {
+            n.parse()
+                .map_err(|e| AuthError::InvalidConfig(format!("{}: {}", var, e)))
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Most networks `TRUSTED_PROXIES` can list
+pub const MAX_TRUSTED_PROXIES: usize = 64;
+
+/// Proxies whose forwarding headers are believed
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    matcher: IpMatcher,
+}
+
+impl TrustedProxies {
+    /// Trust the networks in `entries`, checked by
+    /// [`validation::validate_ip_allowlist`]; networks inside another are
+    /// logged and let through
+    pub fn new(entries: Vec<String>) -> Result<Self, Vec<ValidationError>> {
+        let networks = match validation::validate_ip_allowlist(entries.clone(), MAX_TRUSTED_PROXIES)
+        {
+            Ok(networks) => networks,
+            Err(errors) if errors.iter().all(|e| e.kind.is_warning()) => {
+                for e in &errors {
+                    tracing::warn!("TRUSTED_PROXIES: {}", e);
+                }
+                entries
+                    .iter()
+                    .filter_map(|entry| validation::validate_cidr(entry.trim(), false).ok())
+                    .collect()
+            }
+            Err(errors) => return Err(errors),
+        };
+        Ok(Self {
+            matcher: IpMatcher::new(&networks),
+        })
+    }
+
+    pub fn from_env() -> Result<Self, AuthError> {
+        let Ok(list) = std::env::var("TRUSTED_PROXIES") else {
+            return Ok(Self::default());
+        };
+        let entries = list
+            .split(',')
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+            .map(str::to_string)
+            .collect();
+        Self::new(entries).map_err(|errors| {
+            let messages: Vec<String> = errors
+                .iter()
+                .filter(|e| !e.kind.is_warning())
+                .map(|e| format!("{}: {}", e.field, e.message))
+                .collect();
+            AuthError::InvalidConfig(format!("TRUSTED_PROXIES: {}", messages.join("; ")))
+        })
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.matcher.contains(ip)
+    }
+
+    /// The client of a request from `peer` with `headers`
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusts(peer) {
+            return peer;
+        }
+        let hops = match forwarded_for(headers) {
+            Some(hops) => hops,
+            None => forwarded(headers),
+        };
+        // Walk back from the peer; each trusted proxy vouches for the hop
+        // before it. An entry that isn't an address ends the walk, as
+        // nothing before it can be checked
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            let Some(ip) = hop else {
+                break;
+            };
+            client = *ip;
+            if !self.trusts(client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// Addresses in every `X-Forwarded-For`, in order; `None` without one
+fn forwarded_for(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let mut values = headers.get_all("x-forwarded-for").iter().peekable();
+    values.peek()?;
+    Some(
+        values
+            .flat_map(|v| v.to_str().unwrap_or("").split(','))
+            .map(parse_node)
+            .collect(),
+    )
+}
+
+/// The `for=` addresses in every `Forwarded` (RFC 7239), in order
+fn forwarded(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("forwarded")
+        .iter()
+        .flat_map(|v| v.to_str().unwrap_or("").split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, value)| parse_node(value.trim().trim_matches('"')))
+        })
+        .collect()
+}
+
+/// An address as proxies write it: `192.0.2.1`, `192.0.2.1:4711`, `2001:db8::1`
+/// or `[2001:db8::1]:4711`; `None` for anything else, e.g. `unknown`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+/// The client of the current request, as resolved by [`resolve`]; missing
+/// when the peer address isn't known, e.g. in tests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<ClientIp>()
+            .copied()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+/// Middleware storing the request's [`ClientIp`]; goes outside every route
+pub async fn resolve(
+    State(state): State<Arc<AppState>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(ConnectInfo(peer)) = connect_info {
+        let ip = state
+            .trusted_proxies
+            .client_ip(peer.ip(), request.headers());
+        request.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn proxies(networks: &[&str]) -> TrustedProxies {
+        TrustedProxies::new(networks.iter().map(|n| n.to_string()).collect()).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_networks_match_by_prefix() {
+        let net: IpNetwork = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.200.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(!net.contains(ip("::1")));
+        // IPv4-mapped IPv6 addresses count as IPv4
+        assert!(net.contains(ip("::ffff:10.1.0.9")));
+
+        let any: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("192.0.2.1")));
+        let one: IpNetwork = "fd00::7".parse().unwrap();
+        assert!(one.contains(ip("fd00::7")));
+        assert!(!one.contains(ip("fd00::8")));
+
+        for bad in ["10.0.0.0/33", "fd00::/129", "example.com", "10.0.0.0/x"] {
+            assert!(bad.parse::<IpNetwork>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_networks_mask_and_nest() {
+        let net: IpNetwork = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(net.masked().to_string(), "10.0.0.0/8");
+        let net: IpNetwork = "2001:db8::1/32".parse().unwrap();
+        assert_eq!(net.masked().to_string(), "2001:db8::/32");
+        let all: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert_eq!(all.masked(), all);
+
+        let wide: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        let narrow: IpNetwork = "10.4.0.0/16".parse().unwrap();
+        assert!(wide.contains_network(&narrow));
+        assert!(wide.contains_network(&wide));
+        assert!(!narrow.contains_network(&wide));
+        assert!(!wide.contains_network(&"::/0".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_matcher_merges_ranges() {
+        let networks: Vec<IpNetwork> = ["10.0.0.0/9", "10.128.0.0/9", "10.1.0.0/16", "fd00::/8"]
+            .iter()
+            .map(|n| n.parse().unwrap())
+            .collect();
+        let matcher = IpMatcher::new(&networks);
+        // The two halves of 10.0.0.0/8 are one range, holding the /16
+        assert_eq!(matcher.v4.len(), 1);
+        assert!(matcher.contains(ip("10.0.0.0")));
+        assert!(matcher.contains(ip("10.255.255.255")));
+        assert!(!matcher.contains(ip("11.0.0.0")));
+        assert!(!matcher.contains(ip("9.255.255.255")));
+        assert!(matcher.contains(ip("::ffff:10.9.8.7")));
+        assert!(matcher.contains(ip("fdff::1")));
+        assert!(!matcher.contains(ip("fe00::")));
+
+        let everything = IpMatcher::new(&["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()]);
+        assert!(everything.contains(ip("255.255.255.255")));
+        assert!(everything.contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert_eq!(IpMatcher::new(&[]), IpMatcher::default());
+        assert!(!IpMatcher::new(&[]).contains(ip("127.0.0.1")));
+    }
+
+    /// Addresses near the networks' edges, where a matcher would go wrong
+    fn random_ip(rng: &mut impl rand::Rng, networks: &[IpNetwork]) -> IpAddr {
+        if networks.is_empty() || rng.gen_bool(0.2) {
+            return if rng.gen() {
+                IpAddr::V4(Ipv4Addr::from(rng.gen::<u32>()))
+            } else {
+                IpAddr::V6(Ipv6Addr::from(rng.gen::<u128>()))
+            };
+        }
+        let network = networks[rng.gen_range(0..networks.len())];
+        let (first, last) = network.range();
+        let edge = if rng.gen() { first } else { last };
+        let ip = match rng.gen_range(0..3) {
+            0 => edge.wrapping_sub(1),
+            1 => edge,
+            _ => edge.wrapping_add(1),
+        };
+        match network.addr {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(ip as u32)),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(ip)),
+        }
+    }
+
+    fn random_network(rng: &mut impl rand::Rng) -> IpNetwork {
+        if rng.gen() {
+            // Mostly inside 10.0.0.0/8, so networks overlap
+            let addr = 0x0a00_0000 | (rng.gen::<u32>() & 0x00ff_ffff);
+            IpNetwork::new(IpAddr::V4(Ipv4Addr::from(addr)), rng.gen_range(0..=32))
+        } else {
+            let addr = (0xfd00u128 << 112) | (rng.gen::<u128>() >> 16);
+            IpNetwork::new(IpAddr::V6(Ipv6Addr::from(addr)), rng.gen_range(0..=128))
+        }
+    }
+
+    #[test]
+    fn test_matcher_agrees_with_a_linear_scan() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(1124);
+        for _ in 0..500 {
+            let count = rng.gen_range(0..12);
+            let networks: Vec<IpNetwork> = (0..count).map(|_| random_network(&mut rng)).collect();
+            let matcher = IpMatcher::new(&networks);
+            for _ in 0..100 {
+                let ip = random_ip(&mut rng, &networks);
+                assert_eq!(
+                    matcher.contains(ip),
+                    networks.iter().any(|n| n.contains(ip)),
+                    "{} in {:?}",
+                    ip,
+                    networks
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_trusted_proxies_are_checked_as_an_allowlist() {
+        let list = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect();
+        // A network inside another is only a warning
+        let trusted =
+            TrustedProxies::new(list(&["10.0.0.0/8", "10.1.0.0/16", "10.0.0.0/8"])).unwrap();
+        assert!(trusted.trusts(ip("10.200.0.1")));
+        assert!(!trusted.trusts(ip("11.0.0.1")));
+
+        let errors = TrustedProxies::new(list(&["10.0.0.1/8", "proxy.local"])).unwrap_err();
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["allowlist[0]", "allowlist[1]"]);
+        let many = (0..=MAX_TRUSTED_PROXIES)
+            .map(|i| format!("10.0.{}.{}", i / 256, i % 256))
+            .collect();
+        let errors = TrustedProxies::new(many).unwrap_err();
+        assert_eq!(errors[0].limit, Some(MAX_TRUSTED_PROXIES));
+    }
+
+    #[test]
+    fn test_headers_from_untrusted_peers_are_ignored() {
+        let spoofed = headers(&[
+            ("x-forwarded-for", "198.51.100.7"),
+            ("forwarded", "for=198.51.100.8"),
+        ]);
+        let peer = ip("203.0.113.9");
+        assert_eq!(TrustedProxies::default().client_ip(peer, &spoofed), peer);
+        assert_eq!(proxies(&["127.0.0.1"]).client_ip(peer, &spoofed), peer);
+    }
+
+    #[test]
+    fn test_client_is_rightmost_untrusted_hop() {
+        let trusted = proxies(&["127.0.0.1", "10.0.0.0/8"]);
+        let localhost = ip("127.0.0.1");
+
+        // A client prepending its own entry can't hide behind it
+        let chained = headers(&[("x-forwarded-for", "198.51.100.7, 203.0.113.9, 10.0.0.2")]);
+        assert_eq!(trusted.client_ip(localhost, &chained), ip("203.0.113.9"));
+        // Repeated headers are one list
+        let repeated = headers(&[
+            ("x-forwarded-for", "198.51.100.7"),
+            ("x-forwarded-for", "203.0.113.9:5000, 10.0.0.2"),
+        ]);
+        assert_eq!(trusted.client_ip(localhost, &repeated), ip("203.0.113.9"));
+        // Only proxies: the furthest one
+        let internal = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(trusted.client_ip(localhost, &internal), ip("10.0.0.3"));
+        // Garbage stops the walk at the proxy that passed it on
+        let garbage = headers(&[("x-forwarded-for", "198.51.100.7, bogus, 10.0.0.2")]);
+        assert_eq!(trusted.client_ip(localhost, &garbage), ip("10.0.0.2"));
+        // No header: the proxy itself
+        assert_eq!(trusted.client_ip(localhost, &HeaderMap::new()), localhost);
+    }
+
+    #[test]
+    fn test_forwarded_header() {
+        let trusted = proxies(&["127.0.0.1", "10.0.0.0/8"]);
+        let localhost = ip("127.0.0.1");
+        let forwarded = headers(&[(
+            "forwarded",
+            "for=198.51.100.7, For=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2",
+        )]);
+        assert_eq!(trusted.client_ip(localhost, &forwarded), ip("2001:db8::1"));
+        let obfuscated = headers(&[("forwarded", "for=_hidden, for=10.0.0.2")]);
+        assert_eq!(trusted.client_ip(localhost, &obfuscated), ip("10.0.0.2"));
+        // X-Forwarded-For wins when both are sent
+        let both = headers(&[
+            ("forwarded", "for=198.51.100.7"),
+            ("x-forwarded-for", "203.0.113.9"),
+        ]);
+        assert_eq!(trusted.client_ip(localhost, &both), ip("203.0.113.9"));
+    }
+}
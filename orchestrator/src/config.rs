@@ -1,5 +1,7 @@
 use serde::Deserialize;
 
+use crate::validation::{self, UrlPolicy};
+
 #[derive(Debug, Deserialize, Clone, PartialEq, Default)]
 #[serde(rename_all = "kebab-case")]
 pub enum DeploymentMode {
@@ -51,6 +53,9 @@ pub struct Config {
     pub headscale_namespace: Option<String>,
     pub model_servers: ModelServers,
     pub andor_bridge: Option<AndorBridgeConfig>,
+    /// Directories volumes may be mounted from (`CLAW_PEN_MOUNT_BASES` wins)
+    #[serde(default)]
+    pub mount_bases: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -149,5 +154,87 @@ pub fn load() -> anyhow::Result<Config> {
         .add_source(config::Environment::default().separator("__"))
         .build()?;
 
-    Ok(config.try_deserialize()?)
+    let mut config: Config = config.try_deserialize()?;
+    config.check_urls()?;
+    Ok(config)
+}
+
+impl Config {
+    /// Check the URLs we send requests to and store them normalized;
+    /// the bridge and model servers are local services, so they may use
+    /// `http` on private addresses
+    fn check_urls(&mut self) -> anyhow::Result<()> {
+        let local = UrlPolicy {
+            allow_private_http: true,
+            ..Default::default()
+        };
+        if let Some(ref mut url) = self.headscale_url {
+            *url = checked_url("headscale_url", url, &UrlPolicy::default())?;
+        }
+        if let Some(ref mut bridge) = self.andor_bridge {
+            bridge.url = checked_url("andor_bridge.url", &bridge.url, &local)?;
+        }
+        for (name, server) in self.model_servers.iter_mut() {
+            let field = format!("model_servers.{}.endpoint", name);
+            server.endpoint = checked_url(&field, &server.endpoint, &local)?;
+        }
+        Ok(())
+    }
+
+    /// Fail if a configured host resolves to a link-local or metadata
+    /// address, which [`load`] can't tell without DNS
+    pub async fn check_url_resolution(&self) -> anyhow::Result<()> {
+        let mut urls = vec![("headscale_url".to_string(), self.headscale_url.as_deref())];
+        urls.push((
+            "andor_bridge.url".to_string(),
+            self.andor_bridge.as_ref().map(|b| b.url.as_str()),
+        ));
+        for (name, server) in self.model_servers.iter() {
+            urls.push((
+                format!("model_servers.{}.endpoint", name),
+                Some(server.endpoint.as_str()),
+            ));
+        }
+        for (field, url) in urls {
+            let Some(url) = url.and_then(|u| url::Url::parse(u).ok()) else {
+                continue;
+            };
+            validation::validate_url_resolution(&url)
+                .await
+                .map_err(|e| anyhow::anyhow!("{}: {}", field, e))?;
+        }
+        Ok(())
+    }
+}
+
+impl ModelServers {
+    fn iter(&self) -> impl Iterator<Item = (&'static str, &ModelServerConfig)> {
+        [
+            ("ollama", &self.ollama),
+            ("llama_cpp", &self.llama_cpp),
+            ("vllm", &self.vllm),
+            ("lm_studio", &self.lm_studio),
+        ]
+        .into_iter()
+        .filter_map(|(name, server)| server.as_ref().map(|s| (name, s)))
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = (&'static str, &mut ModelServerConfig)> {
+        [
+            ("ollama", &mut self.ollama),
+            ("llama_cpp", &mut self.llama_cpp),
+            ("vllm", &mut self.vllm),
+            ("lm_studio", &mut self.lm_studio),
+        ]
+        .into_iter()
+        .filter_map(|(name, server)| server.as_mut().map(|s| (name, s)))
+    }
+}
+
+/// `url` checked against `policy`, normalized without a trailing slash, as
+/// paths get appended to it
+fn checked_url(field: &str, url: &str, policy: &UrlPolicy) -> anyhow::Result<String> {
+    let url = validation::validate_http_url(url, policy)
+        .map_err(|e| anyhow::anyhow!("{}: {}", field, e))?;
+    Ok(url.as_str().trim_end_matches('/').to_string())
 }
@@ -428,9 +428,10 @@ impl DockerClient {
         }
     }
 
-    /// Build labels HashMap for a container
-    fn build_labels(name: &str, provider: &LlmProvider) -> HashMap<String, String> {
-        let mut labels = HashMap::new();
+    /// Build labels HashMap for a container, the agent's own and then ours
+    fn build_labels(name: &str, config: &AgentConfig) -> HashMap<String, String> {
+        let provider = &config.llm_provider;
+        let mut labels = config.labels.clone();
         labels.insert("claw-pen-agent".to_string(), "true".to_string());
         labels.insert("claw-pen-agent-name".to_string(), name.to_string());
         labels.insert(
@@ -440,6 +441,15 @@ impl DockerClient {
         labels
     }
 
+    /// Build storage driver options, with `disk_mb` as the size if set
+    fn build_storage_opts(config: &AgentConfig) -> Option<HashMap<String, String>> {
+        let mut opts = config.storage_opts.clone();
+        if let Some(disk_mb) = config.disk_mb {
+            opts.insert("size".to_string(), format!("{}m", disk_mb));
+        }
+        (!opts.is_empty()).then_some(opts)
+    }
+
     /// Ensure the Claw Pen network exists for container isolation
     async fn ensure_network(&self) -> Result<()> {
         // Check if network exists
@@ -547,8 +557,9 @@ impl ContainerRuntime for DockerClient {
     }
 
     async fn create_container(&self, name: &str, config: &AgentConfig) -> Result<String> {
-        // Validate container name to prevent command injection
-        validation::validate_container_name(name)
+        // Validate container name to prevent command injection; reserved
+        // names were already refused by the API
+        validation::validate_container_name(name, true)
             .map_err(|e| anyhow::anyhow!("Invalid container name: {}", e))?;
 
         // Validate resource limits
@@ -558,16 +569,27 @@ impl ContainerRuntime for DockerClient {
             .map_err(|e| anyhow::anyhow!("Invalid CPU config: {}", e))?;
 
         let image = Self::get_image_for_provider(&config.llm_provider);
+        let reference = validation::validate_image_reference(image)
+            .map_err(|e| anyhow::anyhow!("Invalid image: {}", e))?;
+        tracing::debug!("Creating container {} from {}", name, reference);
         let mut env = Self::build_env_vars(config);
 
         // Add Headscale environment variables if using Headscale backend
         let headscale_env = self.build_headscale_env_vars();
         env.extend(headscale_env);
 
-        let labels = Self::build_labels(name, &config.llm_provider);
+        let labels = Self::build_labels(name, config);
 
-        // Ensure the isolated network exists
-        self.ensure_network().await?;
+        // Docker's own networks are set as the mode; any other is joined
+        // after creation, claw-pen's isolated one unless the agent names one
+        let (network_mode, network) = match config.network.as_deref() {
+            Some(builtin @ ("host" | "none" | "bridge")) => (builtin, None),
+            Some(network) => ("bridge", Some(network)),
+            None => {
+                self.ensure_network().await?;
+                ("bridge", Some(CLAW_PEN_NETWORK))
+            }
+        };
 
         // Build port bindings for bridge mode
         // Agent containers expose port 8080 internally for communication
@@ -583,17 +605,57 @@ impl ContainerRuntime for DockerClient {
         let exposed_ports =
             HashMap::from([(format!("{}/tcp", AGENT_INTERNAL_PORT), HashMap::new())]);
 
+        // Devices are passed through as asked; GPUs go to the NVIDIA runtime,
+        // all of them unless a count or indices were asked for
+        let devices = config
+            .devices
+            .iter()
+            .map(|device| bollard::models::DeviceMapping {
+                path_on_host: Some(device.host_path.clone()),
+                path_in_container: Some(
+                    device
+                        .container_path
+                        .clone()
+                        .unwrap_or_else(|| device.host_path.clone()),
+                ),
+                cgroup_permissions: Some(device.permissions.clone()),
+            })
+            .collect::<Vec<_>>();
+        let device_requests = config.gpus.as_ref().map(|gpus| {
+            let capabilities = if gpus.capabilities.is_empty() {
+                vec!["gpu".to_string()]
+            } else {
+                gpus.capabilities.clone()
+            };
+            vec![bollard::models::DeviceRequest {
+                driver: Some("nvidia".to_string()),
+                count: match (gpus.count, gpus.device_ids.is_empty()) {
+                    (Some(count), _) => Some(count as i64),
+                    (None, true) => Some(-1),
+                    (None, false) => None,
+                },
+                device_ids: (!gpus.device_ids.is_empty())
+                    .then(|| gpus.device_ids.iter().map(u32::to_string).collect()),
+                capabilities: Some(vec![capabilities]),
+                options: None,
+            }]
+        });
+
         // Container configuration with bridge network (isolated from host)
         let container_config = Config {
             image: Some(image.to_string()),
+            hostname: config.hostname.clone(),
+            entrypoint: config.entrypoint.clone(),
+            cmd: config.cmd.clone(),
             env: Some(env),
             labels: Some(labels),
             exposed_ports: Some(exposed_ports),
             host_config: Some(bollard::models::HostConfig {
                 memory: Some((config.memory_mb * 1024 * 1024) as i64),
                 nano_cpus: Some((config.cpu_cores * 1_000_000_000.0) as i64),
-                // Use bridge mode for network isolation instead of host mode
-                network_mode: Some("bridge".to_string()),
+                // Bridge mode for network isolation, unless host mode was
+                // allowed and asked for
+                network_mode: Some(network_mode.to_string()),
                 port_bindings: Some(port_bindings),
                 // Security options
                 security_opt: Some(vec!["no-new-privileges:true".to_string()]),
@@ -604,6 +666,9 @@ impl ContainerRuntime for DockerClient {
                 // Drop all capabilities, add only what is needed
                 cap_drop: Some(vec!["ALL".to_string()]),
                 cap_add: Some(vec!["NET_BIND_SERVICE".to_string()]),
+                devices: (!devices.is_empty()).then_some(devices),
+                device_requests,
+                storage_opt: Self::build_storage_opts(config),
                 ..Default::default()
             }),
             ..Default::default()
@@ -620,24 +685,27 @@ impl ContainerRuntime for DockerClient {
             .await
             .map_err(|e| anyhow::anyhow!("Failed to create container: {}", e))?;
 
-        // Connect to the isolated Claw Pen network
-        let connect_opts = ConnectNetworkOptions {
-            container: &result.id,
-            endpoint_config: bollard::models::EndpointSettings {
-                // No special endpoint config needed
-                ..Default::default()
-            },
-        };
+        // Connect to the isolated Claw Pen network, or the agent's own
+        if let Some(network) = network {
+            let connect_opts = ConnectNetworkOptions {
+                container: &result.id,
+                endpoint_config: bollard::models::EndpointSettings {
+                    aliases: (!config.network_aliases.is_empty())
+                        .then(|| config.network_aliases.clone()),
+                    ..Default::default()
+                },
+            };
 
-        if let Err(e) = self
-            .docker
-            .connect_network(CLAW_PEN_NETWORK, connect_opts)
-            .await
-        {
-            tracing::warn!("Failed to connect container to isolated network: {}", e);
+            if let Err(e) = self.docker.connect_network(network, connect_opts).await {
+                tracing::warn!("Failed to connect container to network {}: {}", network, e);
+            }
         }
 
-        tracing::info!("Created container {} in isolated bridge network", result.id);
+        tracing::info!(
+            "Created container {} in {} network",
+            result.id,
+            network.unwrap_or(network_mode)
+        );
         Ok(result.id)
     }
 
@@ -895,11 +963,15 @@ impl ContainerRuntime for ExoClient {
     }
 
     async fn create_container(&self, name: &str, config: &AgentConfig) -> Result<String> {
-        // Validate container name
-        validation::validate_container_name(name)
+        // Validate container name; reserved names were already refused by
+        // the API
+        validation::validate_container_name(name, true)
             .map_err(|e| anyhow::anyhow!("Invalid container name: {}", e))?;
 
         let image = Self::get_image_for_provider(&config.llm_provider);
+        let reference = validation::validate_image_reference(image)
+            .map_err(|e| anyhow::anyhow!("Invalid image: {}", e))?;
+        tracing::debug!("Creating container {} from {}", name, reference);
         let mut args = vec![
             "run".to_string(),
             "--name".to_string(),
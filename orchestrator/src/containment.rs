@@ -106,8 +106,9 @@ impl ContainmentClient {
     }
 
     async fn create_container_internal(&self, name: &str, config: &AgentConfig) -> Result<String> {
-        // Build container spec
-        validation::validate_container_name(name)
+        // Build container spec; reserved names were already refused by the
+        // API
+        validation::validate_container_name(name, true)
             .map_err(|e| anyhow::anyhow!("Invalid container name: {}", e))?;
 
         // Validate resource limits
@@ -370,8 +371,9 @@ impl ContainmentClient {
         volumes
             .iter()
             .filter_map(|v| {
-                // Validate target path
-                if let Err(e) = validation::validate_container_target(&v.target) {
+                // Validate target path; denied targets were already refused
+                // by the API, which knows the configured allowlist
+                if let Err(e) = validation::normalize_container_target(&v.target) {
                     tracing::warn!("Invalid volume target path {}: {}", v.target, e);
                     return None;
                 }
@@ -395,12 +397,24 @@ impl ContainmentClient {
                     return None;
                 }
 
-                Some(serde_json::json!({
-                    "type": "bind",
+                let mut mount = serde_json::json!({
+                    "type": validation::mount_kind(&v.source, &v.options),
                     "source": v.source,
                     "target": v.target,
-                    "readonly": v.read_only,
-                }))
+                    "readonly": v.options.read_only,
+                });
+                let options = [
+                    ("propagation", serde_json::json!(v.options.propagation)),
+                    ("consistency", serde_json::json!(v.options.consistency)),
+                    ("tmpfs_size_mb", serde_json::json!(v.options.tmpfs_size_mb)),
+                    ("tmpfs_mode", serde_json::json!(v.options.tmpfs_mode)),
+                ];
+                for (key, value) in options {
+                    if !value.is_null() {
+                        mount[key] = value;
+                    }
+                }
+                Some(mount)
             })
             .collect()
     }
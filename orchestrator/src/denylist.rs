@@ -0,0 +1,71 @@
+//! Revoked token ids
+//!
+//! Logged-out tokens are remembered by `jti` until they would have expired
+//! anyway. The list is kept in the [`AuthStore`] so a restart doesn't make them
+//! valid again.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::auth::AuthError;
+use crate::auth_store::AuthStore;
+
+pub struct TokenDenylist {
+    store: Arc<dyn AuthStore>,
+    /// jti -> expiry timestamp of the revoked token
+    entries: HashMap<String, i64>,
+}
+
+impl TokenDenylist {
+    /// Load the unexpired revoked ids from `store`
+    pub fn load(store: Arc<dyn AuthStore>, now: i64) -> Result<Self, AuthError> {
+        let entries = store.revoked_tokens(now)?;
+        Ok(Self { store, entries })
+    }
+
+    pub fn contains(&self, jti: &str) -> bool {
+        !jti.is_empty() && self.entries.contains_key(jti)
+    }
+
+    /// How many revoked tokens haven't expired by `now`
+    pub fn active(&self, now: i64) -> usize {
+        self.entries.values().filter(|exp| **exp > now).count()
+    }
+
+    /// Revoke a token until `exp` and persist it
+    pub fn revoke(&mut self, jti: &str, exp: i64, now: i64) -> Result<(), AuthError> {
+        if jti.is_empty() {
+            return Ok(());
+        }
+        self.store.revoke_token(jti, exp, now)?;
+        self.entries.retain(|_, exp| *exp > now);
+        self.entries.insert(jti.to_string(), exp);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth_store::MemoryAuthStore;
+
+    #[test]
+    fn test_revoked_ids_persist_until_expiry() {
+        let store: Arc<dyn AuthStore> = Arc::new(MemoryAuthStore::default());
+
+        let mut denylist = TokenDenylist::load(store.clone(), 1_000).unwrap();
+        denylist.revoke("short", 1_100, 1_000).unwrap();
+        denylist.revoke("long", 5_000, 1_000).unwrap();
+        assert!(denylist.contains("short"));
+        assert!(!denylist.contains(""));
+
+        let reloaded = TokenDenylist::load(store.clone(), 1_050).unwrap();
+        assert!(reloaded.contains("short") && reloaded.contains("long"));
+
+        // Expired entries are pruned on load and on the next revocation
+        let mut later = TokenDenylist::load(store, 2_000).unwrap();
+        assert!(!later.contains("short"));
+        later.revoke("other", 6_000, 2_000).unwrap();
+        assert_eq!(later.entries.len(), 2);
+    }
+}
@@ -0,0 +1,327 @@
+//! Device logins
+//!
+//! The desktop app already holds an Ed25519 identity for the gateway; the same
+//! key can get orchestrator tokens without a password. An admin approves the
+//! device for an account with `POST /auth/devices`. To log in, the device asks
+//! `POST /auth/device/challenge` for a nonce and sends
+//! `POST /auth/device/login` with a signature over the same `v2|…` string the
+//! gateway's connect flow signs:
+//!
+//! `v2|<deviceId>|claw-pen-orchestrator|login|||<signedAt>||<nonce>`
+//!
+//! with no role, scopes or token. A nonce works once and for 60 seconds, and
+//! `signedAt` (Unix millis) must be within a minute of our clock. Tokens
+//! carry the device id, and stop working once the device is revoked.
+//!
+//! Device ids are the hex SHA-256 of the public key, as the gateway expects.
+//! Approved devices live in the [`AuthStore`]; revoking one keeps its record.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ed25519_dalek::{Signature, VerifyingKey};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::auth::AuthError;
+use crate::auth_store::AuthStore;
+
+/// Version of the signed string, shared with the gateway's connect flow
+const SIGNATURE_VERSION: &str = "v2";
+
+/// `clientId` in the signed string, so a gateway connect signature can't be
+/// replayed here
+pub const DEVICE_CLIENT_ID: &str = "claw-pen-orchestrator";
+
+/// `mode` in the signed string
+pub const DEVICE_LOGIN_MODE: &str = "login";
+
+/// Seconds a challenge nonce stays usable
+pub const DEVICE_CHALLENGE_TTL_SECS: i64 = 60;
+
+/// How far `signedAt` may be from our clock, either way
+pub const DEVICE_SIGNATURE_MAX_SKEW_MS: i64 = 60_000;
+
+/// Random bytes in a nonce
+const NONCE_LENGTH: usize = 32;
+
+/// Unanswered challenges kept at once; the oldest goes first
+const MAX_PENDING_CHALLENGES: usize = 10_000;
+
+/// A device approved to log in as an account
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Device {
+    /// Hex SHA-256 of the public key
+    pub id: String,
+    /// Base64 Ed25519 public key
+    pub public_key: String,
+    /// Account the device gets tokens for
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Admin who approved it
+    pub approved_by: String,
+    pub approved_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<i64>,
+}
+
+impl Device {
+    pub fn is_active(&self) -> bool {
+        self.revoked_at.is_none()
+    }
+}
+
+/// Hex SHA-256 of a public key
+pub fn device_id(public_key: &VerifyingKey) -> String {
+    Sha256::digest(public_key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Parse a base64 Ed25519 public key
+pub fn parse_public_key(public_key: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = BASE64.decode(public_key.trim()).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+/// The exact string a device signs to log in
+pub fn signable_string(device_id: &str, signed_at: i64, nonce: &str) -> String {
+    [
+        SIGNATURE_VERSION,
+        device_id,
+        DEVICE_CLIENT_ID,
+        DEVICE_LOGIN_MODE,
+        "",
+        "",
+        &signed_at.to_string(),
+        "",
+        nonce,
+    ]
+    .join("|")
+}
+
+/// Body of `POST /auth/device/login`; named like the gateway's `device`
+/// object, so the app can send what it signs for the gateway
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceLoginRequest {
+    #[serde(alias = "id")]
+    pub device_id: String,
+    pub public_key: String,
+    /// Unix millis
+    pub signed_at: i64,
+    /// Base64 signature of [`signable_string`]
+    pub signature: String,
+    /// From `POST /auth/device/challenge`
+    pub nonce: String,
+}
+
+impl DeviceLoginRequest {
+    /// Check the signature against the presented key and the key against the
+    /// device id; says nothing about whether the device is approved
+    pub fn verify(&self, now_ms: i64) -> Result<(), AuthError> {
+        if (now_ms - self.signed_at).abs() > DEVICE_SIGNATURE_MAX_SKEW_MS {
+            return Err(AuthError::StaleDeviceSignature);
+        }
+        let key = parse_public_key(&self.public_key).ok_or(AuthError::InvalidDeviceSignature)?;
+        if device_id(&key) != self.device_id {
+            return Err(AuthError::InvalidDeviceSignature);
+        }
+        let signature = BASE64
+            .decode(self.signature.trim())
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(AuthError::InvalidDeviceSignature)?;
+        let message = signable_string(&self.device_id, self.signed_at, &self.nonce);
+        key.verify_strict(message.as_bytes(), &signature)
+            .map_err(|_| AuthError::InvalidDeviceSignature)
+    }
+}
+
+/// Body of `POST /auth/devices`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApproveDeviceRequest {
+    pub device_id: String,
+    pub public_key: String,
+    /// Account the device logs in as; the approving admin's if unset
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// Response of `POST /auth/device/challenge`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceChallenge {
+    pub nonce: String,
+    pub expires_in: i64,
+}
+
+/// Approved and revoked devices, written through to the [`AuthStore`]
+pub struct DeviceStore {
+    store: Arc<dyn AuthStore>,
+    devices: Vec<Device>,
+}
+
+impl DeviceStore {
+    pub fn load(store: Arc<dyn AuthStore>) -> Result<Self, AuthError> {
+        let devices = store.devices()?;
+        Ok(Self { store, devices })
+    }
+
+    pub fn list(&self) -> Vec<Device> {
+        self.devices.clone()
+    }
+
+    /// The device with `id`, unless it was revoked
+    pub fn active(&self, id: &str) -> Option<&Device> {
+        self.devices.iter().find(|d| d.id == id && d.is_active())
+    }
+
+    /// Approve a device, or approve a revoked one again
+    pub fn approve(&mut self, device: Device) -> Result<(), AuthError> {
+        self.store.put_device(&device)?;
+        match self.devices.iter_mut().find(|d| d.id == device.id) {
+            Some(stored) => *stored = device,
+            None => self.devices.push(device),
+        }
+        Ok(())
+    }
+
+    pub fn revoke(&mut self, id: &str, now: i64) -> Result<(), AuthError> {
+        let mut device = self.active(id).cloned().ok_or(AuthError::DeviceNotFound)?;
+        device.revoked_at = Some(now);
+        self.approve(device)
+    }
+}
+
+/// Nonces handed out and not yet used
+#[derive(Default)]
+pub struct DeviceChallenges {
+    /// Nonce -> expiry
+    pending: HashMap<String, i64>,
+}
+
+impl DeviceChallenges {
+    pub fn issue(&mut self, now: i64) -> DeviceChallenge {
+        self.pending.retain(|_, expires_at| *expires_at > now);
+        if self.pending.len() >= MAX_PENDING_CHALLENGES {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, expires_at)| **expires_at)
+                .map(|(nonce, _)| nonce.clone());
+            if let Some(nonce) = oldest {
+                self.pending.remove(&nonce);
+            }
+        }
+        let mut bytes = [0u8; NONCE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let nonce = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+        self.pending
+            .insert(nonce.clone(), now + DEVICE_CHALLENGE_TTL_SECS);
+        DeviceChallenge {
+            nonce,
+            expires_in: DEVICE_CHALLENGE_TTL_SECS,
+        }
+    }
+
+    /// Use up a nonce; fails if it was never issued, already used or expired
+    pub fn redeem(&mut self, nonce: &str, now: i64) -> Result<(), AuthError> {
+        match self.pending.remove(nonce) {
+            Some(expires_at) if expires_at > now => Ok(()),
+            _ => Err(AuthError::InvalidDeviceChallenge),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn signed(key: &SigningKey, signed_at: i64, nonce: &str) -> DeviceLoginRequest {
+        let id = device_id(&key.verifying_key());
+        let message = signable_string(&id, signed_at, nonce);
+        DeviceLoginRequest {
+            device_id: id,
+            public_key: BASE64.encode(key.verifying_key().as_bytes()),
+            signed_at,
+            signature: BASE64.encode(key.sign(message.as_bytes()).to_bytes()),
+            nonce: nonce.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_signed_string_layout() {
+        assert_eq!(
+            signable_string("dev-1", 1700000000000, "n0nce"),
+            "v2|dev-1|claw-pen-orchestrator|login|||1700000000000||n0nce"
+        );
+    }
+
+    #[test]
+    fn test_signature_checks() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let now = 1_700_000_000_000;
+        signed(&key, now, "n0nce").verify(now).unwrap();
+        signed(&key, now - 30_000, "n0nce").verify(now).unwrap();
+
+        let stale = signed(&key, now - DEVICE_SIGNATURE_MAX_SKEW_MS - 1, "n0nce");
+        assert!(matches!(
+            stale.verify(now),
+            Err(AuthError::StaleDeviceSignature)
+        ));
+        let future = signed(&key, now + DEVICE_SIGNATURE_MAX_SKEW_MS + 1, "n0nce");
+        assert!(matches!(
+            future.verify(now),
+            Err(AuthError::StaleDeviceSignature)
+        ));
+
+        // Signed for another nonce
+        let mut other_nonce = signed(&key, now, "n0nce");
+        other_nonce.nonce = "other".to_string();
+        assert!(matches!(
+            other_nonce.verify(now),
+            Err(AuthError::InvalidDeviceSignature)
+        ));
+        // Someone else's key under this device id
+        let mut other_key = signed(&SigningKey::from_bytes(&[8; 32]), now, "n0nce");
+        other_key.device_id = device_id(&key.verifying_key());
+        assert!(matches!(
+            other_key.verify(now),
+            Err(AuthError::InvalidDeviceSignature)
+        ));
+        let mut garbage = signed(&key, now, "n0nce");
+        garbage.signature = "not base64!".to_string();
+        assert!(matches!(
+            garbage.verify(now),
+            Err(AuthError::InvalidDeviceSignature)
+        ));
+    }
+
+    #[test]
+    fn test_nonces_work_once_until_they_expire() {
+        let mut challenges = DeviceChallenges::default();
+        let first = challenges.issue(1_000);
+        assert_eq!(first.expires_in, DEVICE_CHALLENGE_TTL_SECS);
+        challenges.redeem(&first.nonce, 1_001).unwrap();
+        assert!(matches!(
+            challenges.redeem(&first.nonce, 1_001),
+            Err(AuthError::InvalidDeviceChallenge)
+        ));
+
+        let second = challenges.issue(1_000);
+        assert!(matches!(
+            challenges.redeem(&second.nonce, 1_000 + DEVICE_CHALLENGE_TTL_SECS),
+            Err(AuthError::InvalidDeviceChallenge)
+        ));
+        assert!(matches!(
+            challenges.redeem("never-issued", 1_000),
+            Err(AuthError::InvalidDeviceChallenge)
+        ));
+    }
+}
@@ -0,0 +1,326 @@
+//! What the host has to give agents
+//!
+//! A [`HostResources`] snapshot is taken at startup and refreshed every
+//! [`REFRESH_INTERVAL`]; agent creation and starts check memory and CPU
+//! against it (see `validation::validate_memory_against_host`). When the
+//! orchestrator runs in a container, the limits of its cgroup (v2, or v1)
+//! count instead of the machine's where they are lower. NVIDIA GPUs are
+//! counted from their device nodes when the driver is loaded.
+//!
+//! Free disk space is measured apart, on the filesystem behind the first
+//! mount base, when asked and at most every [`FREE_SPACE_TTL`].
+
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often the snapshot is taken again
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Where cgroup files are mounted
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Where device nodes live
+const DEV_ROOT: &str = "/dev";
+
+/// How long a free space measurement is used before it's taken again
+pub const FREE_SPACE_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct HostResources {
+    /// Memory of the machine; 0 if unknown
+    pub total_memory_mb: u64,
+    /// Logical CPUs of the machine
+    pub logical_cores: u32,
+    /// Memory limit of the orchestrator's cgroup, if it has one
+    pub cgroup_memory_mb: Option<u64>,
+    /// CPU quota of the orchestrator's cgroup in cores, if it has one
+    pub cgroup_cpu_cores: Option<f64>,
+    /// NVIDIA GPUs, if the driver is loaded
+    pub gpu_count: Option<u32>,
+}
+
+impl HostResources {
+    /// Take a snapshot of this host
+    pub fn gather() -> Self {
+        let mut system = sysinfo::System::new();
+        system.refresh_memory();
+        let mut host = Self {
+            total_memory_mb: system.total_memory() / (1024 * 1024),
+            logical_cores: num_cpus::get() as u32,
+            cgroup_memory_mb: None,
+            cgroup_cpu_cores: None,
+            gpu_count: count_gpus(Path::new(DEV_ROOT)),
+        };
+        host.read_cgroup(Path::new(CGROUP_ROOT));
+        host
+    }
+
+    /// Take the snapshot again
+    pub fn refresh(&mut self) {
+        *self = Self::gather();
+    }
+
+    /// Memory agents can share, the lower of the machine's and the cgroup's
+    pub fn memory_mb(&self) -> u64 {
+        match self.cgroup_memory_mb {
+            Some(limit) if self.total_memory_mb == 0 => limit,
+            Some(limit) => limit.min(self.total_memory_mb),
+            None => self.total_memory_mb,
+        }
+    }
+
+    /// Cores agents can share, the lower of the machine's and the cgroup's
+    pub fn cpu_cores(&self) -> f64 {
+        let cores = self.logical_cores as f64;
+        self.cgroup_cpu_cores
+            .map_or(cores, |quota| quota.min(cores))
+    }
+
+    /// Set the cgroup limits from the files under `root`, trying v2 first
+    fn read_cgroup(&mut self, root: &Path) {
+        let read = |file: &str| {
+            std::fs::read_to_string(root.join(file))
+                .ok()
+                .map(|s| s.trim().to_string())
+        };
+
+        // v2: `max` or bytes, and `max 100000` or `<quota> <period>`
+        if let Some(max) = read("memory.max") {
+            self.cgroup_memory_mb = max.parse::<u64>().ok().map(|b| b / (1024 * 1024));
+        } else if let Some(limit) = read("memory/memory.limit_in_bytes") {
+            // v1 writes a huge number for no limit, which `memory_mb` caps
+            self.cgroup_memory_mb = limit.parse::<u64>().ok().map(|b| b / (1024 * 1024));
+        }
+
+        if let Some(max) = read("cpu.max") {
+            let mut parts = max.split_whitespace();
+            self.cgroup_cpu_cores = quota_cores(parts.next(), parts.next());
+        } else {
+            self.cgroup_cpu_cores = quota_cores(
+                read("cpu/cpu.cfs_quota_us").as_deref(),
+                read("cpu/cpu.cfs_period_us").as_deref(),
+            );
+        }
+    }
+}
+
+/// Measures the free space of the filesystem a path is on
+pub trait FreeSpaceProbe: Send + Sync {
+    /// MB that unprivileged users can still write under `path`
+    fn free_mb(&self, path: &Path) -> std::io::Result<u64>;
+}
+
+/// [`FreeSpaceProbe`] asking the kernel with `statvfs`
+pub struct Statvfs;
+
+impl FreeSpaceProbe for Statvfs {
+    #[cfg(unix)]
+    fn free_mb(&self, path: &Path) -> std::io::Result<u64> {
+        let stat = rustix::fs::statvfs(path)?;
+        Ok(stat.f_bavail.saturating_mul(stat.f_frsize) / (1024 * 1024))
+    }
+
+    #[cfg(not(unix))]
+    fn free_mb(&self, _path: &Path) -> std::io::Result<u64> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Free space under a directory, measured when first asked for and again
+/// once the last measurement is older than [`FREE_SPACE_TTL`]
+pub struct FreeSpace {
+    path: Option<PathBuf>,
+    probe: Box<dyn FreeSpaceProbe>,
+    measured: Mutex<Option<(Instant, Option<u64>)>>,
+}
+
+impl FreeSpace {
+    /// Free space under `path` by [`Statvfs`]; none is known without a path
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self::with_probe(path, Box::new(Statvfs))
+    }
+
+    pub fn with_probe(path: Option<PathBuf>, probe: Box<dyn FreeSpaceProbe>) -> Self {
+        Self {
+            path,
+            probe,
+            measured: Mutex::new(None),
+        }
+    }
+
+    /// MB free, if it could be measured
+    pub fn free_mb(&self) -> Option<u64> {
+        let path = self.path.as_deref()?;
+        let mut measured = self.measured.lock().unwrap_or_else(|e| e.into_inner());
+        match *measured {
+            Some((at, free)) if at.elapsed() < FREE_SPACE_TTL => free,
+            _ => {
+                let free = match self.probe.free_mb(path) {
+                    Ok(free) => Some(free),
+                    Err(e) => {
+                        tracing::warn!("Can't measure free space under {}: {}", path.display(), e);
+                        None
+                    }
+                };
+                *measured = Some((Instant::now(), free));
+                free
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for FreeSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FreeSpace")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+/// `nvidia0`, `nvidia1`, ... under `dev`, if `nvidiactl` says the driver
+/// is loaded
+fn count_gpus(dev: &Path) -> Option<u32> {
+    if !dev.join("nvidiactl").exists() {
+        return None;
+    }
+    let entries = std::fs::read_dir(dev).ok()?;
+    let count = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            e.file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("nvidia"))
+                .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .count();
+    Some(count as u32)
+}
+
+/// Cores from a CFS quota and period, none for `max` or v1's `-1`
+fn quota_cores(quota: Option<&str>, period: Option<&str>) -> Option<f64> {
+    let quota = quota?.parse::<f64>().ok().filter(|q| *q > 0.0)?;
+    let period = period?.parse::<f64>().ok().filter(|p| *p > 0.0)?;
+    Some(quota / period)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host() -> HostResources {
+        HostResources {
+            total_memory_mb: 8192,
+            logical_cores: 4,
+            cgroup_memory_mb: None,
+            cgroup_cpu_cores: None,
+            gpu_count: None,
+        }
+    }
+
+    fn cgroup(files: &[(&str, &str)]) -> HostResources {
+        let dir = tempfile::tempdir().unwrap();
+        for (file, content) in files {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let mut host = host();
+        host.read_cgroup(dir.path());
+        host
+    }
+
+    #[test]
+    fn test_cgroup_limits_count_where_lower() {
+        let v2 = cgroup(&[
+            ("memory.max", "2147483648\n"),
+            ("cpu.max", "150000 100000\n"),
+        ]);
+        assert_eq!(v2.cgroup_memory_mb, Some(2048));
+        assert_eq!(v2.memory_mb(), 2048);
+        assert_eq!(v2.cpu_cores(), 1.5);
+
+        let unlimited = cgroup(&[("memory.max", "max\n"), ("cpu.max", "max 100000\n")]);
+        assert_eq!(unlimited.cgroup_memory_mb, None);
+        assert_eq!(unlimited.cgroup_cpu_cores, None);
+        assert_eq!(unlimited.memory_mb(), 8192);
+        assert_eq!(unlimited.cpu_cores(), 4.0);
+
+        let v1 = cgroup(&[
+            ("memory/memory.limit_in_bytes", "9223372036854771712\n"),
+            ("cpu/cpu.cfs_quota_us", "200000\n"),
+            ("cpu/cpu.cfs_period_us", "100000\n"),
+        ]);
+        assert_eq!(v1.memory_mb(), 8192);
+        assert_eq!(v1.cpu_cores(), 2.0);
+
+        let v1_unlimited = cgroup(&[
+            ("cpu/cpu.cfs_quota_us", "-1\n"),
+            ("cpu/cpu.cfs_period_us", "100000\n"),
+        ]);
+        assert_eq!(v1_unlimited.cgroup_cpu_cores, None);
+
+        // A quota above the machine's cores doesn't add any
+        let generous = cgroup(&[("cpu.max", "800000 100000\n")]);
+        assert_eq!(generous.cpu_cores(), 4.0);
+
+        assert_eq!(cgroup(&[]), host());
+    }
+
+    #[test]
+    fn test_gpus_counted_from_device_nodes() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["nvidia0", "nvidia1", "nvidia-uvm", "nvidia-modeset"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        assert_eq!(count_gpus(dir.path()), None);
+        std::fs::write(dir.path().join("nvidiactl"), "").unwrap();
+        assert_eq!(count_gpus(dir.path()), Some(2));
+    }
+
+    struct Counting(std::sync::Arc<std::sync::atomic::AtomicU32>);
+
+    impl FreeSpaceProbe for Counting {
+        fn free_mb(&self, _path: &Path) -> std::io::Result<u64> {
+            let calls = self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(1000 - calls as u64)
+        }
+    }
+
+    #[test]
+    fn test_free_space_is_measured_lazily() {
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let space = FreeSpace::with_probe(
+            Some(PathBuf::from("/volumes")),
+            Box::new(Counting(calls.clone())),
+        );
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+        assert_eq!(space.free_mb(), Some(1000));
+        assert_eq!(space.free_mb(), Some(1000));
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // A stale measurement is taken again
+        *space.measured.lock().unwrap() = Some((Instant::now() - FREE_SPACE_TTL, Some(1000)));
+        assert_eq!(space.free_mb(), Some(999));
+
+        let nowhere = FreeSpace::with_probe(None, Box::new(Counting(calls.clone())));
+        assert_eq!(nowhere.free_mb(), None);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_statvfs_measures_a_real_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Statvfs.free_mb(dir.path()).is_ok());
+        assert!(Statvfs.free_mb(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_gather_sees_this_host() {
+        let host = HostResources::gather();
+        assert!(host.logical_cores >= 1);
+        assert!(host.cpu_cores() > 0.0);
+    }
+}
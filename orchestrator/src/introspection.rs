@@ -0,0 +1,127 @@
+//! Token introspection
+//!
+//! Sibling services, like the agent gateway, get tokens from web clients and
+//! need to know whether they are good without holding the signing keys.
+//! `POST /auth/introspect` takes `{"token": "..."}` and answers in the shape
+//! of RFC 7662: `active`, and for an active token `sub`, `exp`, `iat`,
+//! `scope` and `token_type`. The same checks as the auth middleware apply, so
+//! logged-out tokens, ended sessions, `logout-all` and disabled accounts all
+//! count as inactive.
+//!
+//! Only access tokens are active. Anything else - expired, revoked, malformed,
+//! or a refresh token - gets `{"active": false}` with 200, never a reason.
+//!
+//! Callers need the `auth.introspect` scope, normally an API key limited to
+//! it. Each caller may introspect `INTROSPECT_RATE_LIMIT` tokens a minute
+//! (default 600); past that it gets 429 with `Retry-After`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::auth::AuthError;
+
+/// Length of a rate-limit window
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Introspections per caller per window when `INTROSPECT_RATE_LIMIT` is unset
+pub const DEFAULT_RATE_LIMIT: u32 = 600;
+
+/// Body of `POST /auth/introspect`
+#[derive(Debug, Deserialize)]
+pub struct IntrospectRequest {
+    pub token: String,
+}
+
+/// Response of `POST /auth/introspect`; only `active` for an inactive token
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Introspection {
+    pub active: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    /// Space-separated scopes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// `access`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+}
+
+impl Introspection {
+    pub fn inactive() -> Self {
+        Self::default()
+    }
+}
+
+/// Introspections per caller in fixed one-minute windows
+pub struct IntrospectionLimiter {
+    limit: u32,
+    /// caller -> (window start, introspections in it)
+    windows: HashMap<String, (Instant, u32)>,
+}
+
+impl IntrospectionLimiter {
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit: limit.max(1),
+            windows: HashMap::new(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(
+            std::env::var("INTROSPECT_RATE_LIMIT")
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(DEFAULT_RATE_LIMIT),
+        )
+    }
+
+    /// Count an introspection by `caller`, failing with 429 if it has had
+    /// its limit this window
+    pub fn check(&mut self, caller: &str, now: Instant) -> Result<(), AuthError> {
+        // Forget finished windows so idle callers don't pile up
+        self.windows
+            .retain(|_, (start, _)| now.saturating_duration_since(*start) < WINDOW);
+        let (start, count) = self.windows.entry(caller.to_string()).or_insert((now, 0));
+        if *count >= self.limit {
+            let left = WINDOW.saturating_sub(now.saturating_duration_since(*start));
+            return Err(AuthError::TooManyRequests {
+                retry_after: left.as_secs().max(1),
+            });
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inactive_is_only_active_false() {
+        let json = serde_json::to_value(Introspection::inactive()).unwrap();
+        assert_eq!(json, serde_json::json!({ "active": false }));
+    }
+
+    #[test]
+    fn test_limits_each_caller_per_window() {
+        let mut limiter = IntrospectionLimiter::new(2);
+        let start = Instant::now();
+        limiter.check("gateway", start).unwrap();
+        limiter.check("gateway", start).unwrap();
+        match limiter.check("gateway", start + Duration::from_secs(15)) {
+            Err(AuthError::TooManyRequests { retry_after }) => assert_eq!(retry_after, 45),
+            other => panic!("expected 429, got {:?}", other),
+        }
+        // Other callers have their own count
+        limiter.check("other", start).unwrap();
+        // The next window starts over
+        limiter.check("gateway", start + WINDOW).unwrap();
+    }
+}
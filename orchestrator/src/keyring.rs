@@ -0,0 +1,412 @@
+//! JWT signing keys
+//!
+//! Tokens are signed with the newest key and name it by `kid` in their header.
+//! Rotating adds a key and schedules the older ones for retirement once every
+//! token they signed has expired, so nobody is logged out. Keys are kept in
+//! the [`AuthStore`]; the first one is migrated from the original JWT secret,
+//! which stays in place as the root for API key hashes and TOTP encryption.
+//!
+//! Keys are HS256 secrets or, so other services can verify tokens without
+//! sharing a secret, Ed25519 keypairs whose public halves are published as a
+//! JWKS. Each key only verifies tokens of its own algorithm.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use rand::RngCore;
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::auth::AuthError;
+use crate::auth_store::{AuthStore, JWT_KEYS};
+
+/// Random bytes in a generated signing secret (256 bits)
+const SECRET_LENGTH: usize = 32;
+
+/// Signing algorithm of a key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyAlgorithm {
+    #[default]
+    #[serde(rename = "HS256")]
+    Hs256,
+    #[serde(rename = "EdDSA")]
+    EdDsa,
+}
+
+impl KeyAlgorithm {
+    pub fn jwt(self) -> Algorithm {
+        match self {
+            KeyAlgorithm::Hs256 => Algorithm::HS256,
+            KeyAlgorithm::EdDsa => Algorithm::EdDSA,
+        }
+    }
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hs256" => Ok(KeyAlgorithm::Hs256),
+            "eddsa" | "ed25519" => Ok(KeyAlgorithm::EdDsa),
+            _ => Err(AuthError::InvalidConfig(format!(
+                "JWT_ALGORITHM must be HS256 or EdDSA, got {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JwtKey {
+    pub kid: String,
+    /// Keys from before algorithms could be chosen are HS256
+    #[serde(default)]
+    pub algorithm: KeyAlgorithm,
+    /// HS256 secret, or PKCS#8 Ed25519 private key
+    #[serde(with = "base64_secret")]
+    pub secret: Vec<u8>,
+    /// Raw Ed25519 public key; empty for HS256
+    #[serde(default, with = "base64_secret", skip_serializing_if = "Vec::is_empty")]
+    pub public_key: Vec<u8>,
+    pub created_at: i64,
+    /// When tokens signed with the key stop being accepted; set on rotation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retire_at: Option<i64>,
+}
+
+impl JwtKey {
+    fn new(algorithm: KeyAlgorithm, secret: Vec<u8>, public_key: Vec<u8>, now: i64) -> Self {
+        Self {
+            kid: Uuid::new_v4().simple().to_string(),
+            algorithm,
+            secret,
+            public_key,
+            created_at: now,
+            retire_at: None,
+        }
+    }
+
+    /// A fresh random key
+    fn generate(algorithm: KeyAlgorithm, now: i64) -> Result<Self, AuthError> {
+        match algorithm {
+            KeyAlgorithm::Hs256 => {
+                let mut secret = vec![0u8; SECRET_LENGTH];
+                rand::thread_rng().fill_bytes(&mut secret);
+                Ok(Self::new(algorithm, secret, Vec::new(), now))
+            }
+            KeyAlgorithm::EdDsa => {
+                let failed = || AuthError::EncryptionError("failed to generate Ed25519 key".into());
+                let pkcs8 =
+                    Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|_| failed())?;
+                let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(|_| failed())?;
+                Ok(Self::new(
+                    algorithm,
+                    pkcs8.as_ref().to_vec(),
+                    pair.public_key().as_ref().to_vec(),
+                    now,
+                ))
+            }
+        }
+    }
+
+    fn is_retired(&self, now: i64) -> bool {
+        self.retire_at.is_some_and(|at| at <= now)
+    }
+
+    pub fn encoding_key(&self) -> EncodingKey {
+        match self.algorithm {
+            KeyAlgorithm::Hs256 => EncodingKey::from_secret(&self.secret),
+            KeyAlgorithm::EdDsa => EncodingKey::from_ed_der(&self.secret),
+        }
+    }
+
+    pub fn decoding_key(&self) -> Result<DecodingKey, AuthError> {
+        Ok(match self.algorithm {
+            KeyAlgorithm::Hs256 => DecodingKey::from_secret(&self.secret),
+            KeyAlgorithm::EdDsa => {
+                DecodingKey::from_ed_components(&URL_SAFE_NO_PAD.encode(&self.public_key))?
+            }
+        })
+    }
+}
+
+/// A public key in JWK form
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    /// Base64url public key
+    pub x: String,
+    pub kid: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+}
+
+/// Response of `GET /auth/jwks`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+/// A key as listed by `POST /auth/rotate-secret`, without the secret
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JwtKeyInfo {
+    pub kid: String,
+    pub algorithm: KeyAlgorithm,
+    pub created_at: i64,
+    pub retire_at: Option<i64>,
+}
+
+impl From<&JwtKey> for JwtKeyInfo {
+    fn from(key: &JwtKey) -> Self {
+        Self {
+            kid: key.kid.clone(),
+            algorithm: key.algorithm,
+            created_at: key.created_at,
+            retire_at: key.retire_at,
+        }
+    }
+}
+
+mod base64_secret {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(secret: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(secret))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD
+            .decode(encoded.trim())
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+pub struct JwtKeyring {
+    store: Arc<dyn AuthStore>,
+    /// Oldest first; never empty
+    keys: Vec<JwtKey>,
+}
+
+impl JwtKeyring {
+    /// Load the keyring in `store`, dropping retired keys. Without one, a
+    /// keyring is created holding `legacy_secret`, so tokens signed before
+    /// keyrings existed stay valid.
+    pub fn load(
+        store: Arc<dyn AuthStore>,
+        legacy_secret: &[u8],
+        now: i64,
+    ) -> Result<Self, AuthError> {
+        let keys: Vec<JwtKey> = match store.secret(JWT_KEYS)? {
+            Some(keys) => serde_json::from_str(&keys)?,
+            None => Vec::new(),
+        };
+        let mut keyring = Self { store, keys };
+        if keyring.keys.is_empty() {
+            keyring.keys.push(JwtKey::new(
+                KeyAlgorithm::Hs256,
+                legacy_secret.to_vec(),
+                Vec::new(),
+                now,
+            ));
+            keyring.save()?;
+            tracing::info!("Migrated JWT secret to a keyring");
+        } else if keyring.prune(now) {
+            keyring.save()?;
+        }
+        Ok(keyring)
+    }
+
+    /// The key new tokens are signed with
+    pub fn signing_key(&self) -> &JwtKey {
+        self.keys.last().expect("keyring is never empty")
+    }
+
+    /// Unretired keys that may have signed a token whose header names `kid`;
+    /// all of them for tokens without one
+    pub fn verification_keys<'a>(
+        &'a self,
+        kid: Option<&'a str>,
+        now: i64,
+    ) -> impl Iterator<Item = &'a JwtKey> + 'a {
+        self.keys
+            .iter()
+            .rev()
+            .filter(move |k| !k.is_retired(now) && (kid.is_none() || kid == Some(k.kid.as_str())))
+    }
+
+    /// Add a new signing key; keys not yet scheduled retire at `retire_at`
+    pub fn rotate(
+        &mut self,
+        algorithm: KeyAlgorithm,
+        retire_at: i64,
+        now: i64,
+    ) -> Result<&JwtKey, AuthError> {
+        let key = JwtKey::generate(algorithm, now)?;
+        self.prune(now);
+        for key in self.keys.iter_mut().filter(|k| k.retire_at.is_none()) {
+            key.retire_at = Some(retire_at);
+        }
+        self.keys.push(key);
+        self.save()?;
+        Ok(self.signing_key())
+    }
+
+    /// Public halves of the unretired Ed25519 keys
+    pub fn jwks(&self, now: i64) -> JwkSet {
+        let keys = self
+            .keys
+            .iter()
+            .filter(|k| k.algorithm == KeyAlgorithm::EdDsa && !k.is_retired(now))
+            .map(|k| Jwk {
+                kty: "OKP".to_string(),
+                crv: "Ed25519".to_string(),
+                x: URL_SAFE_NO_PAD.encode(&k.public_key),
+                kid: k.kid.clone(),
+                alg: "EdDSA".to_string(),
+                key_use: "sig".to_string(),
+            })
+            .collect();
+        JwkSet { keys }
+    }
+
+    pub fn list(&self) -> Vec<JwtKeyInfo> {
+        self.keys.iter().map(JwtKeyInfo::from).collect()
+    }
+
+    /// Fail unless every key can verify tokens
+    pub fn check(&self) -> Result<(), AuthError> {
+        for key in &self.keys {
+            key.decoding_key()?;
+        }
+        Ok(())
+    }
+
+    /// Drop retired keys, returning whether any were
+    fn prune(&mut self, now: i64) -> bool {
+        let before = self.keys.len();
+        self.keys.retain(|k| !k.is_retired(now));
+        self.keys.len() != before
+    }
+
+    fn save(&self) -> Result<(), AuthError> {
+        self.store
+            .set_secret(JWT_KEYS, &serde_json::to_string(&self.keys)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth_store::MemoryAuthStore;
+
+    fn kids<'a>(keys: impl Iterator<Item = &'a JwtKey>) -> Vec<String> {
+        keys.map(|k| k.kid.clone()).collect()
+    }
+
+    #[test]
+    fn test_migrates_the_legacy_secret() {
+        let store: Arc<dyn AuthStore> = Arc::new(MemoryAuthStore::default());
+
+        let keyring = JwtKeyring::load(store.clone(), b"legacy secret", 1_000).unwrap();
+        assert_eq!(keyring.signing_key().secret, b"legacy secret");
+        assert!(store.secret(JWT_KEYS).unwrap().is_some());
+
+        // Once migrated, the stored keyring is authoritative
+        let reloaded = JwtKeyring::load(store, b"ignored", 2_000).unwrap();
+        assert_eq!(reloaded.signing_key().secret, b"legacy secret");
+        assert_eq!(reloaded.signing_key().kid, keyring.signing_key().kid);
+    }
+
+    #[test]
+    fn test_rotation_keeps_old_keys_until_retirement() {
+        let store: Arc<dyn AuthStore> = Arc::new(MemoryAuthStore::default());
+        let mut keyring = JwtKeyring::load(store.clone(), b"legacy secret", 1_000).unwrap();
+        let old = keyring.signing_key().kid.clone();
+
+        let new = keyring
+            .rotate(KeyAlgorithm::Hs256, 5_000, 1_000)
+            .unwrap()
+            .kid
+            .clone();
+        assert_ne!(old, new);
+        assert_ne!(keyring.signing_key().secret, b"legacy secret");
+        assert_eq!(
+            kids(keyring.verification_keys(None, 4_999)),
+            [new.clone(), old.clone()]
+        );
+        assert_eq!(
+            kids(keyring.verification_keys(Some(&old), 4_999)),
+            std::slice::from_ref(&old)
+        );
+        assert_eq!(
+            kids(keyring.verification_keys(Some("nope"), 4_999)),
+            Vec::<String>::new()
+        );
+
+        // The old key stops verifying at its retirement, and is dropped on load
+        assert_eq!(
+            kids(keyring.verification_keys(None, 5_000)),
+            std::slice::from_ref(&new)
+        );
+        let reloaded = JwtKeyring::load(store.clone(), b"legacy secret", 5_000).unwrap();
+        let listed: Vec<String> = reloaded.list().into_iter().map(|k| k.kid).collect();
+        assert_eq!(listed, [new]);
+
+        // Keys already scheduled keep their date on the next rotation
+        let mut keyring = reloaded;
+        keyring.rotate(KeyAlgorithm::Hs256, 9_000, 6_000).unwrap();
+        keyring.rotate(KeyAlgorithm::Hs256, 12_000, 7_000).unwrap();
+        let retire: Vec<_> = keyring.list().iter().map(|k| k.retire_at).collect();
+        assert_eq!(retire, [Some(9_000), Some(12_000), None]);
+    }
+
+    #[test]
+    fn test_ed25519_keys_publish_only_the_public_half() {
+        let store: Arc<dyn AuthStore> = Arc::new(MemoryAuthStore::default());
+        let mut keyring = JwtKeyring::load(store.clone(), b"legacy secret", 1_000).unwrap();
+        assert!(keyring.jwks(1_000).keys.is_empty());
+
+        let key = keyring
+            .rotate(KeyAlgorithm::EdDsa, 5_000, 1_000)
+            .unwrap()
+            .clone();
+        assert_eq!(key.public_key.len(), 32);
+        let jwks = keyring.jwks(1_000);
+        assert_eq!(jwks.keys.len(), 1);
+        assert_eq!(jwks.keys[0].kid, key.kid);
+        assert_eq!(jwks.keys[0].x, URL_SAFE_NO_PAD.encode(&key.public_key));
+
+        // The keypair survives a reload
+        let reloaded = JwtKeyring::load(store, b"legacy secret", 2_000).unwrap();
+        assert_eq!(reloaded.signing_key().algorithm, KeyAlgorithm::EdDsa);
+        assert_eq!(reloaded.signing_key().secret, key.secret);
+        assert_eq!(reloaded.jwks(2_000).keys[0].x, jwks.keys[0].x);
+    }
+
+    #[test]
+    fn test_algorithm_names() {
+        assert_eq!(
+            "HS256".parse::<KeyAlgorithm>().unwrap(),
+            KeyAlgorithm::Hs256
+        );
+        assert_eq!(
+            "eddsa".parse::<KeyAlgorithm>().unwrap(),
+            KeyAlgorithm::EdDsa
+        );
+        assert!("RS256".parse::<KeyAlgorithm>().is_err());
+        assert_eq!(
+            serde_json::to_string(&KeyAlgorithm::EdDsa).unwrap(),
+            "\"EdDSA\""
+        );
+    }
+}
@@ -0,0 +1,349 @@
+//! Login throttling
+//!
+//! Failed logins are counted per client IP (see `client_ip`) and per account
+//! over a sliding window. Too many from one IP get 429 with `Retry-After`
+//! until the oldest failure leaves the window; an account that keeps failing,
+//! from any number of IPs, is locked for a while. Each attempt counts as a failure until it
+//! succeeds, so parallel guesses can't all slip past the check. Both maps are
+//! LRU-bounded.
+//!
+//! Thresholds come from the environment:
+//! - `LOGIN_MAX_FAILURES` - failures per IP in the window before 429 (default 5)
+//! - `LOGIN_LOCKOUT_THRESHOLD` - failures per account in the window before it is locked (default 20)
+//! - `LOGIN_FAILURE_WINDOW_SECS` - window length (default 300)
+//! - `LOGIN_LOCKOUT_SECS` - lockout length (default 900)
+//! - `LOGIN_MAX_TRACKED` - IPs and accounts remembered at once (default 10000)
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::auth::AuthError;
+
+#[derive(Debug, Clone)]
+pub struct LimiterConfig {
+    pub max_failures_per_ip: usize,
+    pub lockout_threshold: usize,
+    pub window: Duration,
+    pub lockout: Duration,
+    pub max_tracked: usize,
+}
+
+impl Default for LimiterConfig {
+    fn default() -> Self {
+        Self {
+            max_failures_per_ip: 5,
+            lockout_threshold: 20,
+            window: Duration::from_secs(300),
+            lockout: Duration::from_secs(900),
+            max_tracked: 10_000,
+        }
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+impl LimiterConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_failures_per_ip: env_or("LOGIN_MAX_FAILURES", default.max_failures_per_ip),
+            lockout_threshold: env_or("LOGIN_LOCKOUT_THRESHOLD", default.lockout_threshold),
+            window: Duration::from_secs(env_or(
+                "LOGIN_FAILURE_WINDOW_SECS",
+                default.window.as_secs(),
+            )),
+            lockout: Duration::from_secs(env_or("LOGIN_LOCKOUT_SECS", default.lockout.as_secs())),
+            max_tracked: env_or("LOGIN_MAX_TRACKED", default.max_tracked).max(1),
+        }
+    }
+}
+
+/// Map that forgets its least recently used entry when full
+struct Lru<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (u64, V)>,
+    /// tick of last use -> key
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V: Default> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// The entry for `key`, created if missing, marked most recently used
+    fn touch(&mut self, key: &K) -> &mut V {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some((last, _)) = self.entries.get(key) {
+            self.order.remove(last);
+        } else if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.order.insert(tick, key.clone());
+        let entry = self
+            .entries
+            .entry(key.clone())
+            .or_insert((tick, V::default()));
+        entry.0 = tick;
+        &mut entry.1
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some((last, _)) = self.entries.remove(key) {
+            self.order.remove(&last);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Failures {
+    times: VecDeque<Instant>,
+}
+
+impl Failures {
+    fn prune(&mut self, now: Instant, window: Duration) {
+        while self
+            .times
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= window)
+        {
+            self.times.pop_front();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Account {
+    failures: Failures,
+    locked_until: Option<Instant>,
+}
+
+/// Whole seconds until `until`, at least 1
+fn secs_until(until: Instant, now: Instant) -> u64 {
+    let remaining = until.saturating_duration_since(now);
+    (remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0)).max(1)
+}
+
+pub struct LoginLimiter {
+    config: LimiterConfig,
+    ips: Lru<IpAddr, Failures>,
+    accounts: Lru<String, Account>,
+}
+
+impl LoginLimiter {
+    pub fn new(config: LimiterConfig) -> Self {
+        Self {
+            ips: Lru::new(config.max_tracked),
+            accounts: Lru::new(config.max_tracked),
+            config,
+        }
+    }
+
+    /// Admit a login attempt, counting it as a failure until `record_success`
+    pub fn begin_attempt(
+        &mut self,
+        ip: IpAddr,
+        username: &str,
+        now: Instant,
+    ) -> Result<(), AuthError> {
+        let config = &self.config;
+
+        let account = self.accounts.touch(&username.to_string());
+        if let Some(until) = account.locked_until {
+            if until > now {
+                return Err(AuthError::AccountLocked {
+                    retry_after: secs_until(until, now),
+                });
+            }
+            account.locked_until = None;
+        }
+        account.failures.prune(now, config.window);
+        if account.failures.times.len() >= config.lockout_threshold {
+            let until = now + config.lockout;
+            account.locked_until = Some(until);
+            account.failures.times.clear();
+            tracing::warn!(
+                "Account '{}' locked for {}s after {} failed logins",
+                username,
+                config.lockout.as_secs(),
+                config.lockout_threshold
+            );
+            tracing::warn!(
+                target: "audit",
+                event = "account_locked",
+                subject = username,
+                source_ip = %ip,
+                lockout_secs = config.lockout.as_secs()
+            );
+            return Err(AuthError::AccountLocked {
+                retry_after: secs_until(until, now),
+            });
+        }
+
+        let failures = self.ips.touch(&ip);
+        failures.prune(now, config.window);
+        if failures.times.len() >= config.max_failures_per_ip {
+            let oldest = failures.times[0];
+            return Err(AuthError::TooManyAttempts {
+                retry_after: secs_until(oldest + config.window, now),
+            });
+        }
+
+        failures.times.push_back(now);
+        self.accounts
+            .touch(&username.to_string())
+            .failures
+            .times
+            .push_back(now);
+        Ok(())
+    }
+
+    /// Count a failure against `ip` alone, for requests refused before a
+    /// login was attempted, like ones with oversized or malformed bodies
+    pub fn record_failure(&mut self, ip: IpAddr, now: Instant) {
+        let failures = self.ips.touch(&ip);
+        failures.prune(now, self.config.window);
+        failures.times.push_back(now);
+    }
+
+    /// Clear the IP's and the account's failures after a successful login
+    pub fn record_success(&mut self, ip: IpAddr, username: &str) {
+        self.ips.remove(&ip);
+        self.accounts.remove(&username.to_string());
+    }
+
+    /// IPs and accounts currently tracked
+    #[cfg(test)]
+    fn tracked(&self) -> (usize, usize) {
+        (self.ips.entries.len(), self.accounts.entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> LimiterConfig {
+        LimiterConfig {
+            max_failures_per_ip: 3,
+            lockout_threshold: 5,
+            window: Duration::from_secs(60),
+            lockout: Duration::from_secs(600),
+            max_tracked: 4,
+        }
+    }
+
+    fn ip(n: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, n])
+    }
+
+    #[test]
+    fn test_refused_requests_count_against_the_ip() {
+        let mut limiter = LoginLimiter::new(config());
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.record_failure(ip(1), start);
+        }
+        assert!(matches!(
+            limiter.begin_attempt(ip(1), "admin", start),
+            Err(AuthError::TooManyAttempts { .. })
+        ));
+        // The account isn't charged for them
+        limiter.begin_attempt(ip(2), "admin", start).unwrap();
+    }
+
+    #[test]
+    fn test_burst_from_one_ip_gets_retry_after() {
+        let mut limiter = LoginLimiter::new(config());
+        let start = Instant::now();
+        for i in 0..3 {
+            let now = start + Duration::from_secs(i);
+            limiter.begin_attempt(ip(1), "admin", now).unwrap();
+        }
+
+        let blocked = limiter.begin_attempt(ip(1), "admin", start + Duration::from_secs(10));
+        assert!(matches!(
+            blocked,
+            Err(AuthError::TooManyAttempts { retry_after: 50 })
+        ));
+        // Other IPs aren't affected
+        limiter
+            .begin_attempt(ip(2), "admin", start + Duration::from_secs(10))
+            .unwrap();
+        // Once the oldest failure leaves the window, one more attempt is allowed
+        limiter
+            .begin_attempt(ip(1), "admin", start + Duration::from_secs(60))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_success_resets_counters() {
+        let mut limiter = LoginLimiter::new(config());
+        let now = Instant::now();
+        for _ in 0..2 {
+            limiter.begin_attempt(ip(1), "admin", now).unwrap();
+        }
+        limiter.record_success(ip(1), "admin");
+        for _ in 0..3 {
+            limiter.begin_attempt(ip(1), "admin", now).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_distributed_burst_locks_the_account() {
+        let mut limiter = LoginLimiter::new(config());
+        let start = Instant::now();
+        for n in 0..5 {
+            limiter.begin_attempt(ip(n), "admin", start).unwrap();
+        }
+
+        let locked = limiter.begin_attempt(ip(9), "admin", start + Duration::from_secs(1));
+        assert!(matches!(
+            locked,
+            Err(AuthError::AccountLocked { retry_after: 600 })
+        ));
+        // Even the right password from a fresh IP waits out the lockout
+        assert!(matches!(
+            limiter.begin_attempt(ip(10), "admin", start + Duration::from_secs(300)),
+            Err(AuthError::AccountLocked { retry_after: 301 })
+        ));
+        limiter.begin_attempt(ip(10), "other", start).unwrap();
+
+        limiter
+            .begin_attempt(ip(10), "admin", start + Duration::from_secs(601))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_tracking_is_bounded() {
+        let mut limiter = LoginLimiter::new(config());
+        let now = Instant::now();
+        for n in 0..50 {
+            let _ = limiter.begin_attempt(ip(n), &format!("user{}", n), now);
+        }
+        assert_eq!(limiter.tracked(), (4, 4));
+
+        // The least recently seen IP was forgotten, recent ones weren't
+        for _ in 0..2 {
+            limiter.begin_attempt(ip(49), "user49", now).unwrap();
+        }
+        assert!(limiter.begin_attempt(ip(49), "user49", now).is_err());
+        limiter.begin_attempt(ip(0), "user0", now).unwrap();
+    }
+}
@@ -1,28 +1,54 @@
 use std::collections::HashMap;
 mod andor;
 mod api;
+mod api_keys;
+mod audit;
 mod auth;
+mod auth_body;
+mod auth_files;
+mod auth_metrics;
+mod auth_reload;
+mod auth_store;
+mod bootstrap;
+mod client_ip;
 mod config;
 mod container;
 mod containment;
+mod denylist;
+mod devices;
+mod host_resources;
+mod introspection;
+mod keyring;
+mod login_limiter;
+mod master_key;
 mod network;
+mod password_policy;
+mod redaction;
+mod scopes;
 mod secret_manager;
+mod service_tokens;
+mod session_cookies;
+mod sessions;
 mod shared_memory;
 mod snapshots;
 mod storage;
 mod teams;
 mod templates;
+mod totp;
 mod types;
+mod users;
 mod validation;
+mod ws_tickets;
 
 use axum::http::{header, HeaderValue, Method};
 use axum::{
-    routing::{delete, get, post},
+    middleware,
+    routing::{delete, get, post, put},
     Router,
 };
 use container::ContainerRuntime;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 
 use crate::auth::AuthManager;
@@ -43,6 +69,30 @@ pub struct AppState {
     pub api_keys: RwLock<HashMap<String, String>>,
     pub data_dir: std::path::PathBuf,
     pub auth: RwLock<AuthManager>,
+    /// Failed login counters per IP and account
+    pub login_limiter: Mutex<login_limiter::LoginLimiter>,
+    /// One-time tickets for WebSocket upgrades
+    pub ws_tickets: Mutex<ws_tickets::WsTicketStore>,
+    /// Nonces handed out for device logins
+    pub device_challenges: Mutex<devices::DeviceChallenges>,
+    /// Introspections per caller at `/auth/introspect`
+    pub introspection_limiter: Mutex<introspection::IntrospectionLimiter>,
+    /// Authentication and authorization events
+    pub audit: audit::AuditLog,
+    /// Auth counters and latencies served at `/metrics`
+    pub metrics: Arc<auth_metrics::AuthMetrics>,
+    /// Who may scrape `/metrics`
+    pub metrics_access: auth_metrics::MetricsAccess,
+    /// Where agent volumes may be mounted from
+    pub validation: validation::ValidationConfig,
+    /// Memory and cores agents can share, refreshed now and then
+    pub host: RwLock<host_resources::HostResources>,
+    /// Free space under the volume base, measured when needed
+    pub disk: host_resources::FreeSpace,
+    /// Reverse proxies whose `X-Forwarded-For` names the client
+    pub trusted_proxies: client_ip::TrustedProxies,
+    /// Clients `/auth/register` answers
+    pub registration_networks: Vec<client_ip::IpNetwork>,
 }
 
 fn load_api_keys(data_dir: &std::path::Path) -> HashMap<String, String> {
@@ -57,32 +107,310 @@ fn load_api_keys(data_dir: &std::path::Path) -> HashMap<String, String> {
     HashMap::new()
 }
 
+/// `router` with every route behind `auth::require_scope` for `scope`
+fn scoped(router: Router<Arc<AppState>>, scope: &'static str) -> Router<Arc<AppState>> {
+    router.route_layer(middleware::from_fn_with_state(scope, auth::require_scope))
+}
+
+/// All routes: public ones, and the rest behind the auth middleware
+fn router(state: Arc<AppState>) -> Router {
+    // The protected API routes, each group behind the scope it needs, all
+    // behind the auth middleware
+    let agents_read = Router::new()
+        .route("/api/agents/:id/logs", get(api::get_logs))
+        .route("/api/agents/:id/metrics", get(api::get_metrics))
+        .route("/api/agents/:id/snapshots", get(api::list_snapshots))
+        .route("/api/agents/:id/export", get(api::export_agent))
+        .route("/api/agents/:id", get(api::get_agent))
+        .route("/api/agents", get(api::list_agents));
+    let agents_write = Router::new()
+        // Agent management - more specific routes MUST come before :id routes
+        .route("/api/agents/:id/start", post(api::start_agent))
+        .route("/api/agents/:id/stop", post(api::stop_agent))
+        .route("/api/agents/:id/health", post(api::run_health_check))
+        .route("/api/agents/:id/snapshots", post(api::create_snapshot))
+        .route(
+            "/api/agents/:id/snapshots/:snapshot_id/restore",
+            post(api::restore_snapshot),
+        )
+        .route(
+            "/api/agents/:id/snapshots/:snapshot_id",
+            delete(api::delete_snapshot),
+        )
+        // Generic :id routes come after all specific routes
+        .route(
+            "/api/agents/:id",
+            put(api::update_agent).delete(api::delete_agent),
+        )
+        .route("/api/agents", post(api::create_agent))
+        // Batch operations
+        .route("/api/agents/start-all", post(api::start_all))
+        .route("/api/agents/stop-all", post(api::stop_all))
+        // Import
+        .route("/api/agents/import", post(api::import_agent));
+    let secrets_read = Router::new().route("/api/agents/:id/secrets", get(api::list_secrets));
+    let secrets_write = Router::new()
+        .route("/api/agents/:id/secrets", post(api::set_secret))
+        .route("/api/agents/:id/secrets/:name", delete(api::delete_secret));
+    // API Keys
+    let keys_read = Router::new().route("/api/keys", get(api::list_api_keys));
+    let keys_write = Router::new()
+        .route("/api/keys", post(api::set_api_key))
+        .route("/api/keys/:provider", delete(api::delete_api_key));
+    // Projects
+    let projects_read = Router::new().route("/api/projects", get(api::list_projects));
+    let projects_write = Router::new().route("/api/projects", post(api::create_project));
+    // Teams
+    let teams_read = Router::new()
+        .route("/api/teams", get(api::list_teams))
+        .route("/api/teams/:id", get(api::get_team));
+    let teams_write = Router::new().route("/api/teams/:id/classify", post(api::classify_message));
+    let system_read = Router::new()
+        // Global metrics
+        .route("/api/metrics", get(api::get_all_metrics))
+        .route("/api/system/stats", get(api::get_system_stats))
+        // Templates
+        .route("/api/templates", get(api::list_templates))
+        // Runtime status
+        .route("/api/runtime/status", get(api::runtime_status))
+        // Rules forms can check names against before submitting
+        .route("/validation/rules", get(api::validation_rules));
+
+    let protected_routes = Router::new()
+        .merge(scoped(agents_read, scopes::AGENTS_READ))
+        .merge(scoped(agents_write, scopes::AGENTS_WRITE))
+        .merge(scoped(secrets_read, scopes::SECRETS_READ))
+        .merge(scoped(secrets_write, scopes::SECRETS_WRITE))
+        .merge(scoped(keys_read, scopes::KEYS_READ))
+        .merge(scoped(keys_write, scopes::KEYS_WRITE))
+        .merge(scoped(projects_read, scopes::PROJECTS_READ))
+        .merge(scoped(projects_write, scopes::PROJECTS_WRITE))
+        .merge(scoped(teams_read, scopes::TEAMS_READ))
+        .merge(scoped(teams_write, scopes::TEAMS_WRITE))
+        .merge(scoped(system_read, scopes::SYSTEM_READ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::auth_middleware,
+        ))
+        .with_state(state.clone());
+
+    // WebSocket routes take the token as `?token=`, since browsers can't set
+    // headers on WebSocket requests; it is checked before the upgrade. Chat
+    // messages also need the matching write scope, checked per message
+    let agents_ws = Router::new()
+        .route("/api/agents/:id/logs/stream", get(api::logs_websocket))
+        .route("/api/agents/:id/chat", get(api::chat_websocket));
+    let teams_ws = Router::new().route("/api/teams/:id/chat", get(api::team_chat_websocket));
+    let ws_routes = Router::new()
+        .merge(scoped(agents_ws, scopes::AGENTS_READ))
+        .merge(scoped(teams_ws, scopes::TEAMS_READ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::ws_auth_middleware,
+        ))
+        .with_state(state.clone());
+
+    // Public routes (no auth required)
+    let public_routes = Router::new()
+        .route("/health", get(api::health))
+        .route("/auth/login", post(auth::login))
+        .route("/auth/register", post(auth::register))
+        .route("/auth/status", get(auth::auth_status))
+        .route("/auth/jwks", get(auth::jwks))
+        // Guarded by network or scrape token rather than a login
+        .route("/metrics", get(auth_metrics::scrape))
+        .route("/auth/totp/verify", post(auth::totp_verify))
+        // The device signature is the credential
+        .route("/auth/device/challenge", post(auth::device_challenge))
+        .route("/auth/device/login", post(auth::device_login))
+        // The refresh token in the body is the credential
+        .route("/api/auth/refresh", post(auth::refresh))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_body::limit,
+        ))
+        .with_state(state.clone());
+
+    // Account management, behind the auth middleware
+    let account_routes = Router::new()
+        .route("/auth/change-password", post(auth::change_password))
+        .route("/auth/logout", post(auth::logout))
+        .route("/auth/logout-all", post(auth::logout_all))
+        .route("/auth/ws-ticket", post(auth::ws_ticket))
+        .route("/auth/sessions", get(auth::list_sessions))
+        .route("/auth/sessions/:id", delete(auth::revoke_session))
+        .route("/auth/totp/setup", post(auth::totp_setup))
+        .route("/auth/totp/confirm", post(auth::totp_confirm))
+        .route("/auth/users", get(auth::list_users).post(auth::create_user))
+        .route("/auth/users/:name", delete(auth::delete_user))
+        .route("/auth/users/:name/password", post(auth::set_user_password))
+        .route(
+            "/auth/api-keys",
+            get(auth::list_api_keys).post(auth::create_api_key),
+        )
+        .route("/auth/api-keys/:id", delete(auth::revoke_api_key))
+        .route(
+            "/auth/devices",
+            get(auth::list_devices).post(auth::approve_device),
+        )
+        .route("/auth/devices/:id", delete(auth::revoke_device))
+        .route("/auth/rotate-secret", post(auth::rotate_secret))
+        .route("/auth/reload", post(auth::reload))
+        .route("/auth/audit", get(auth::audit_log))
+        // For sibling services checking their clients' tokens
+        .merge(scoped(
+            Router::new().route("/auth/introspect", post(auth::introspect)),
+            scopes::AUTH_INTROSPECT,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::auth_middleware,
+        ))
+        // Outside the auth middleware, so oversized bodies never get that far
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_body::limit,
+        ))
+        .with_state(state.clone());
+
+    // The agent API, for agents' service tokens only
+    let agent_read = Router::new()
+        .route("/api/agent/self", get(api::agent_self))
+        .route("/api/agent/self/secrets", get(api::agent_self_secrets));
+    let agent_report = Router::new().route("/api/agent/self/health", post(api::agent_self_health));
+    let agent_routes = Router::new()
+        .merge(scoped(agent_read, scopes::AGENT_SELF_READ))
+        .merge(scoped(agent_report, scopes::AGENT_SELF_REPORT))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            service_tokens::agent_middleware,
+        ))
+        .with_state(state.clone());
+
+    // Configure CORS with explicit allowed origins (not permissive)
+    // Allowed origins: Claw Pen UI domains and localhost for development
+    let cors = CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(
+            |origin: &HeaderValue, _req_parts| {
+                // Check if origin is in allowed list
+                if let Ok(origin_str) = origin.to_str() {
+                    // Allow any localhost origin for development (with any port)
+                    if origin_str.starts_with("http://localhost:")
+                        || origin_str.starts_with("http://127.0.0.1:")
+                        || origin_str.starts_with("https://localhost")
+                        || origin_str == "tauri://localhost"
+                        || origin_str == "https://tauri.localhost"
+                    {
+                        return true;
+                    }
+                }
+                false
+            },
+        ))
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::OPTIONS,
+            Method::PATCH,
+        ])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::ACCEPT,
+            header::ORIGIN,
+            session_cookies::CSRF_HEADER,
+        ])
+        .allow_credentials(true);
+
+    Router::new()
+        .merge(public_routes)
+        .merge(account_routes)
+        .merge(protected_routes)
+        .merge(ws_routes)
+        .merge(agent_routes)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            client_ip::resolve,
+        ))
+        .layer(cors)
+        .with_state(state)
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Check for CLI password setting mode
     let args: Vec<String> = std::env::args().collect();
     if args.contains(&"--set-password".to_string()) {
         let data_dir = std::path::PathBuf::from("/data/claw-pen/data");
-        auth::cli_set_password(&data_dir)?;
+        auth::cli_set_password(&data_dir, &auth::SetPasswordOptions::from_args(&args)?)?;
+        return Ok(());
+    }
+    if args.contains(&"--check-auth-files".to_string()) {
+        let data_dir = std::path::PathBuf::from("/data/claw-pen/data");
+        auth::cli_check_auth_files(&data_dir)?;
+        return Ok(());
+    }
+    if args.contains(&"--rewrap-auth-secrets".to_string()) {
+        let data_dir = std::path::PathBuf::from("/data/claw-pen/data");
+        auth::cli_rewrap_auth_secrets(&data_dir)?;
+        return Ok(());
+    }
+    if args.contains(&"--rotate-jwt-secret".to_string()) {
+        let data_dir = std::path::PathBuf::from("/data/claw-pen/data");
+        auth::cli_rotate_jwt_secret(&data_dir)?;
+        return Ok(());
+    }
+    for flag in ["--export-auth-state", "--import-auth-state"] {
+        let Some(i) = args.iter().position(|a| a == flag) else {
+            continue;
+        };
+        let path = args
+            .get(i + 1)
+            .map(std::path::PathBuf::from)
+            .ok_or_else(|| anyhow::anyhow!("{} needs a file path", flag))?;
+        let data_dir = std::path::PathBuf::from("/data/claw-pen/data");
+        if flag == "--export-auth-state" {
+            auth::cli_export_auth_state(&data_dir, &path)?;
+        } else {
+            auth::cli_import_auth_state(&data_dir, &path)?;
+        }
         return Ok(());
     }
 
     tracing_subscriber::fmt()
         .with_env_filter(std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string()))
+        .with_writer(|| redaction::RedactingWriter(std::io::stdout()))
         .init();
 
     let config = config::load()?;
+    config.check_url_resolution().await?;
     let data_dir = std::path::PathBuf::from("/data/claw-pen/data");
     std::fs::create_dir_all(&data_dir).ok();
     tracing::info!("Loaded config: {:?}", config);
 
     // Initialize Auth Manager
-    let auth_manager = AuthManager::new(&data_dir)?;
+    let mut auth_manager = AuthManager::new(&data_dir)?;
     if !auth_manager.has_admin() {
-        tracing::warn!("⚠️  No admin password set. Use --set-password to set one, or enable ENABLE_REGISTRATION=true for first-time setup.");
+        tracing::warn!("⚠️  No admin password set. Use --set-password to set one, or register the first admin with the bootstrap token below.");
     } else {
         tracing::info!("Authentication initialized - admin user configured");
     }
+    auth_manager.start_bootstrap(&data_dir)?;
+    let bootstrap_expires_at = auth_manager.bootstrap_expires_at();
+    let ws_tickets = ws_tickets::WsTicketStore::from_env(&data_dir)?;
+    let audit_log = audit::AuditLog::start(
+        data_dir.join("audit"),
+        audit::AuditConfig::from_env(),
+        auth_manager.audit_ip_key()?,
+    )?;
+    let metrics = auth_manager.metrics();
+    let metrics_access = auth_metrics::MetricsAccess::from_env()?;
+    let trusted_proxies = client_ip::TrustedProxies::from_env()?;
+    let registration_networks = client_ip::networks_from_env("REGISTRATION_ALLOWED_NETWORKS")?
+        .unwrap_or_else(client_ip::loopback_networks);
+    let reload_watch = auth_reload::watch_interval_from_env()?;
 
     // Load templates
     let template_registry = templates::TemplateRegistry::load()?;
@@ -182,12 +510,25 @@ async fn main() -> anyhow::Result<()> {
 
     // Initialize secrets manager
     let secrets = SecretsManager::new()?;
+    secrets.refresh_redaction();
     tracing::info!("Secrets manager initialized");
 
+    let api_keys = load_api_keys(&data_dir);
+    api::redact_api_keys(&api_keys);
+
     // Initialize snapshots manager
     let snapshots = SnapshotManager::new()?;
     tracing::info!("Snapshots manager initialized");
 
+    let validation = validation::ValidationConfig::from_env(config.mount_bases.as_deref())?;
+    let host = host_resources::HostResources::gather();
+    tracing::info!(
+        "Agents may use {} MB of memory and {} cores",
+        host.memory_mb(),
+        host.cpu_cores()
+    );
+    let disk = host_resources::FreeSpace::new(validation.mount_bases.first().cloned());
+
     // Initialize teams registry
     let teams = teams::TeamRegistry::new("./teams");
     let teams_count = teams.load_all().await?;
@@ -203,123 +544,74 @@ async fn main() -> anyhow::Result<()> {
         secrets,
         snapshots,
         teams,
-        api_keys: RwLock::new(load_api_keys(&data_dir)),
+        api_keys: RwLock::new(api_keys),
         data_dir,
         auth: RwLock::new(auth_manager),
+        login_limiter: Mutex::new(login_limiter::LoginLimiter::new(
+            login_limiter::LimiterConfig::from_env(),
+        )),
+        ws_tickets: Mutex::new(ws_tickets),
+        device_challenges: Mutex::new(devices::DeviceChallenges::default()),
+        introspection_limiter: Mutex::new(introspection::IntrospectionLimiter::from_env()),
+        audit: audit_log,
+        metrics,
+        metrics_access,
+        validation,
+        host: RwLock::new(host),
+        disk,
+        trusted_proxies,
+        registration_networks,
     });
 
-    // Create the protected API routes with auth middleware
-    let protected_routes = Router::new()
-        // Agent management - more specific routes MUST come before :id routes
-        .route("/api/agents/:id/start", post(api::start_agent))
-        .route("/api/agents/:id/stop", post(api::stop_agent))
-        .route("/api/agents/:id/logs", get(api::get_logs))
-        .route("/api/agents/:id/logs/stream", get(api::logs_websocket))
-        .route("/api/agents/:id/chat", get(api::chat_websocket))
-        .route("/api/agents/:id/metrics", get(api::get_metrics))
-        .route("/api/agents/:id/health", post(api::run_health_check))
-        .route(
-            "/api/agents/:id/secrets",
-            get(api::list_secrets).post(api::set_secret),
-        )
-        .route("/api/agents/:id/secrets/:name", delete(api::delete_secret))
-        .route(
-            "/api/agents/:id/snapshots",
-            get(api::list_snapshots).post(api::create_snapshot),
-        )
-        .route(
-            "/api/agents/:id/snapshots/:snapshot_id/restore",
-            post(api::restore_snapshot),
-        )
-        .route(
-            "/api/agents/:id/snapshots/:snapshot_id",
-            delete(api::delete_snapshot),
-        )
-        .route("/api/agents/:id/export", get(api::export_agent))
-        // Generic :id routes come after all specific routes
-        .route(
-            "/api/agents/:id",
-            get(api::get_agent)
-                .put(api::update_agent)
-                .delete(api::delete_agent),
-        )
-        .route("/api/agents", get(api::list_agents).post(api::create_agent))
-        // Batch operations
-        .route("/api/agents/start-all", post(api::start_all))
-        .route("/api/agents/stop-all", post(api::stop_all))
-        // Global metrics
-        .route("/api/metrics", get(api::get_all_metrics))
-        .route("/api/system/stats", get(api::get_system_stats))
-        // Templates
-        .route("/api/templates", get(api::list_templates))
-        // API Keys
-        .route("/api/keys", get(api::list_api_keys).post(api::set_api_key))
-        .route("/api/keys/:provider", delete(api::delete_api_key))
-        // Projects
-        .route(
-            "/api/projects",
-            get(api::list_projects).post(api::create_project),
-        )
-        // Teams
-        .route("/api/teams", get(api::list_teams))
-        .route("/api/teams/:id", get(api::get_team))
-        .route("/api/teams/:id/chat", get(api::team_chat_websocket))
-        .route("/api/teams/:id/classify", post(api::classify_message))
-        // Import
-        .route("/api/agents/import", post(api::import_agent))
-        // Runtime status
-        .route("/api/runtime/status", get(api::runtime_status))
-        .route("/api/auth/refresh", post(auth::refresh))
-        .with_state(state.clone());
+    // Keep the host snapshot current, e.g. after a cgroup limit changes
+    let host_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(host_resources::REFRESH_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            host_state.host.write().await.refresh();
+        }
+    });
 
-    // Public routes (no auth required)
-    let public_routes = Router::new()
-        .route("/health", get(api::health))
-        .route("/auth/login", post(auth::login))
-        .route("/auth/register", post(auth::register))
-        .route("/auth/status", get(auth::auth_status))
-        .with_state(state.clone());
-    // Configure CORS with explicit allowed origins (not permissive)
-    // Allowed origins: Claw Pen UI domains and localhost for development
-    let cors = CorsLayer::new()
-        .allow_origin(AllowOrigin::predicate(
-            |origin: &HeaderValue, _req_parts| {
-                // Check if origin is in allowed list
-                if let Ok(origin_str) = origin.to_str() {
-                    // Allow any localhost origin for development (with any port)
-                    if origin_str.starts_with("http://localhost:")
-                        || origin_str.starts_with("http://127.0.0.1:")
-                        || origin_str.starts_with("https://localhost")
-                        || origin_str == "tauri://localhost"
-                        || origin_str == "https://tauri.localhost"
-                    {
-                        return true;
-                    }
-                }
-                false
-            },
-        ))
-        .allow_methods([
-            Method::GET,
-            Method::POST,
-            Method::PUT,
-            Method::DELETE,
-            Method::OPTIONS,
-            Method::PATCH,
-        ])
-        .allow_headers([
-            header::AUTHORIZATION,
-            header::CONTENT_TYPE,
-            header::ACCEPT,
-            header::ORIGIN,
-        ])
-        .allow_credentials(true);
+    // Delete an unused bootstrap token once it expires
+    if let Some(expires_at) = bootstrap_expires_at {
+        let bootstrap_state = state.clone();
+        tokio::spawn(async move {
+            let wait = expires_at - chrono::Utc::now().timestamp();
+            tokio::time::sleep(std::time::Duration::from_secs(wait.max(0) as u64)).await;
+            bootstrap_state
+                .auth
+                .write()
+                .await
+                .expire_bootstrap(chrono::Utc::now().timestamp());
+        });
+    }
 
-    let app = Router::new()
-        .merge(public_routes)
-        .merge(protected_routes)
-        .layer(cors)
-        .with_state(state);
+    // Pick up auth changes made on disk, e.g. by --set-password
+    #[cfg(unix)]
+    auth_reload::spawn_sighup_listener(state.clone())?;
+    if let Some(interval) = reload_watch {
+        tracing::info!("Watching the auth store for changes every {:?}", interval);
+        auth_reload::spawn_watcher(state.clone(), state.data_dir.clone(), interval);
+    }
+
+    // Drop expired WebSocket tickets
+    let prune_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+            ws_tickets::PRUNE_INTERVAL_SECS,
+        ));
+        loop {
+            interval.tick().await;
+            let now = chrono::Utc::now().timestamp();
+            if let Err(e) = prune_state.ws_tickets.lock().await.prune(now) {
+                tracing::warn!("Failed to prune WebSocket tickets: {}", e);
+            }
+        }
+    });
+
+    let app = router(state);
 
     let addr = format!("{}:{}", "0.0.0.0", 3000);
     tracing::info!("🦀 Claw Pen orchestrator listening on {}", addr);
@@ -328,7 +620,1571 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("   POST /auth/login to authenticate");
 
     let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use tempfile::{tempdir, TempDir};
+    use tower::ServiceExt;
+
+    /// Every route behind the auth middleware, with placeholder ids
+    const PROTECTED: &[(&str, &str)] = &[
+        ("POST", "/api/agents/a1/start"),
+        ("POST", "/api/agents/a1/stop"),
+        ("GET", "/api/agents/a1/logs"),
+        ("GET", "/api/agents/a1/metrics"),
+        ("POST", "/api/agents/a1/health"),
+        ("GET", "/api/agents/a1/secrets"),
+        ("POST", "/api/agents/a1/secrets"),
+        ("DELETE", "/api/agents/a1/secrets/s1"),
+        ("GET", "/api/agents/a1/snapshots"),
+        ("POST", "/api/agents/a1/snapshots"),
+        ("POST", "/api/agents/a1/snapshots/s1/restore"),
+        ("DELETE", "/api/agents/a1/snapshots/s1"),
+        ("GET", "/api/agents/a1/export"),
+        ("GET", "/api/agents/a1"),
+        ("PUT", "/api/agents/a1"),
+        ("DELETE", "/api/agents/a1"),
+        ("GET", "/api/agents"),
+        ("POST", "/api/agents"),
+        ("POST", "/api/agents/start-all"),
+        ("POST", "/api/agents/stop-all"),
+        ("GET", "/api/metrics"),
+        ("GET", "/api/system/stats"),
+        ("GET", "/api/templates"),
+        ("GET", "/api/keys"),
+        ("POST", "/api/keys"),
+        ("DELETE", "/api/keys/openai"),
+        ("GET", "/api/projects"),
+        ("POST", "/api/projects"),
+        ("GET", "/api/teams"),
+        ("GET", "/api/teams/t1"),
+        ("POST", "/api/teams/t1/classify"),
+        ("POST", "/api/agents/import"),
+        ("GET", "/api/runtime/status"),
+        ("GET", "/validation/rules"),
+        ("POST", "/auth/change-password"),
+        ("POST", "/auth/logout"),
+        ("POST", "/auth/logout-all"),
+        ("POST", "/auth/ws-ticket"),
+        ("GET", "/auth/sessions"),
+        ("DELETE", "/auth/sessions/s1"),
+        ("POST", "/auth/introspect"),
+        ("POST", "/auth/totp/setup"),
+        ("POST", "/auth/totp/confirm"),
+        ("GET", "/auth/users"),
+        ("POST", "/auth/users"),
+        ("DELETE", "/auth/users/bob"),
+        ("POST", "/auth/users/bob/password"),
+        ("GET", "/auth/api-keys"),
+        ("POST", "/auth/api-keys"),
+        ("DELETE", "/auth/api-keys/k1"),
+        ("GET", "/auth/devices"),
+        ("POST", "/auth/devices"),
+        ("DELETE", "/auth/devices/d1"),
+        ("POST", "/auth/rotate-secret"),
+        ("POST", "/auth/reload"),
+        ("GET", "/auth/audit"),
+        ("GET", "/api/agent/self"),
+        ("GET", "/api/agent/self/secrets"),
+        ("POST", "/api/agent/self/health"),
+    ];
+
+    /// Read-only routes that succeed against an empty state
+    const SAFE_GETS: &[&str] = &[
+        "/api/agents",
+        "/api/templates",
+        "/api/keys",
+        "/api/teams",
+        "/api/projects",
+        "/api/system/stats",
+        "/api/runtime/status",
+        "/api/metrics",
+        "/validation/rules",
+    ];
+
+    const WEBSOCKETS: &[&str] = &[
+        "/api/agents/a1/logs/stream",
+        "/api/agents/a1/chat",
+        "/api/teams/t1/chat",
+    ];
+
+    const SCRAPE_TOKEN: &str = "scrape-secret";
+
+    async fn test_state(dir: &TempDir) -> Arc<AppState> {
+        let config = config::Config {
+            deployment_mode: Default::default(),
+            network_backend: Default::default(),
+            runtime_socket: String::new(),
+            container_runtime: Default::default(),
+            exo_path: None,
+            tailscale_auth_key: None,
+            headscale_url: None,
+            headscale_auth_key: None,
+            headscale_namespace: None,
+            model_servers: config::ModelServers {
+                ollama: None,
+                llama_cpp: None,
+                vllm: None,
+                lm_studio: None,
+            },
+            andor_bridge: None,
+            mount_bases: None,
+        };
+        let runtime = container::RuntimeClient::new().await.unwrap();
+        let exo_runtime = runtime.clone_runtime_client();
+        let mut auth = AuthManager::new(dir.path()).unwrap();
+        auth.create_first_admin("correct horse").unwrap();
+        let audit_ip_key = auth.audit_ip_key().unwrap();
+        let metrics = auth.metrics();
+
+        Arc::new(AppState {
+            config,
+            containers: RwLock::new(Vec::new()),
+            runtime,
+            exo_runtime,
+            templates: templates::TemplateRegistry::default(),
+            andor: None,
+            secrets: SecretsManager::new().unwrap(),
+            snapshots: SnapshotManager::new().unwrap(),
+            teams: teams::TeamRegistry::new(&dir.path().join("teams").to_string_lossy()),
+            api_keys: RwLock::new(HashMap::new()),
+            data_dir: dir.path().to_path_buf(),
+            auth: RwLock::new(auth),
+            login_limiter: Mutex::new(login_limiter::LoginLimiter::new(Default::default())),
+            ws_tickets: Mutex::new(ws_tickets::WsTicketStore::memory()),
+            device_challenges: Mutex::new(devices::DeviceChallenges::default()),
+            introspection_limiter: Mutex::new(introspection::IntrospectionLimiter::new(
+                introspection::DEFAULT_RATE_LIMIT,
+            )),
+            audit: audit::AuditLog::start(
+                dir.path().join("audit"),
+                audit::AuditConfig::default(),
+                audit_ip_key,
+            )
+            .unwrap(),
+            metrics,
+            metrics_access: auth_metrics::MetricsAccess {
+                allowed_networks: Vec::new(),
+                scrape_token: Some(SCRAPE_TOKEN.to_string()),
+            },
+            validation: validation::ValidationConfig::default(),
+            host: RwLock::new(host_resources::HostResources {
+                total_memory_mb: 8192,
+                logical_cores: 4,
+                cgroup_memory_mb: None,
+                cgroup_cpu_cores: None,
+                gpu_count: Some(2),
+            }),
+            disk: host_resources::FreeSpace::with_probe(
+                Some(dir.path().to_path_buf()),
+                Box::new(FixedFreeSpace(10240)),
+            ),
+            trusted_proxies: client_ip::TrustedProxies::new(vec!["127.0.0.1".to_string()]).unwrap(),
+            registration_networks: client_ip::loopback_networks(),
+        })
+    }
+
+    /// A volume base with this many MB free
+    struct FixedFreeSpace(u64);
+
+    impl host_resources::FreeSpaceProbe for FixedFreeSpace {
+        fn free_mb(&self, _path: &std::path::Path) -> std::io::Result<u64> {
+            Ok(self.0)
+        }
+    }
+
+    async fn access_token(state: &AppState) -> String {
+        match state
+            .auth
+            .read()
+            .await
+            .login("admin", "correct horse")
+            .unwrap()
+        {
+            auth::LoginResponse::Tokens(tokens) => tokens.access_token,
+            auth::LoginResponse::MfaRequired(_) => panic!("unexpected MFA challenge"),
+        }
+    }
+
+    fn request(method: &str, uri: &str, token: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    async fn status(app: &Router, request: Request<Body>) -> StatusCode {
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_protected_routes_require_a_token() {
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let token = access_token(&state).await;
+        let app = router(state);
+
+        for (method, uri) in PROTECTED {
+            assert_eq!(
+                status(&app, request(method, uri, None)).await,
+                StatusCode::UNAUTHORIZED,
+                "{} {} without a token",
+                method,
+                uri
+            );
+            assert_eq!(
+                status(&app, request(method, uri, Some("not-a-jwt"))).await,
+                StatusCode::UNAUTHORIZED,
+                "{} {} with a bad token",
+                method,
+                uri
+            );
+            // Logging out would revoke the token the remaining checks use
+            if uri.starts_with("/auth/logout") {
+                continue;
+            }
+            assert_ne!(
+                status(&app, request(method, uri, Some(&token))).await,
+                StatusCode::UNAUTHORIZED,
+                "{} {} with a valid token",
+                method,
+                uri
+            );
+        }
+        for uri in SAFE_GETS {
+            assert_eq!(
+                status(&app, request("GET", uri, Some(&token))).await,
+                StatusCode::OK,
+                "GET {}",
+                uri
+            );
+        }
+
+        assert_eq!(
+            status(&app, request("POST", "/auth/logout", Some(&token))).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            status(&app, request("GET", "/api/agents", Some(&token))).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refresh_tokens_are_not_access_tokens() {
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let refresh = match state.auth.read().await.login("admin", "correct horse") {
+            Ok(auth::LoginResponse::Tokens(tokens)) => tokens.refresh_token,
+            _ => panic!("login failed"),
+        };
+        let app = router(state);
+
+        assert_eq!(
+            status(&app, request("GET", "/api/agents", Some(&refresh))).await,
+            StatusCode::UNAUTHORIZED
+        );
+        let uri = format!("/api/agents/a1/chat?token={}", refresh);
+        assert_eq!(
+            status(&app, request("GET", &uri, None)).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_websockets_check_the_token_before_upgrading() {
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let token = access_token(&state).await;
+        let app = router(state);
+
+        for uri in WEBSOCKETS {
+            assert_eq!(
+                status(&app, request("GET", uri, None)).await,
+                StatusCode::UNAUTHORIZED,
+                "{} without a token",
+                uri
+            );
+            let bad = format!("{}?token=not-a-jwt", uri);
+            assert_eq!(
+                status(&app, request("GET", &bad, None)).await,
+                StatusCode::UNAUTHORIZED,
+                "{} with a bad token",
+                uri
+            );
+            // Past the middleware, a plain GET fails the upgrade instead
+            let good = format!("{}?token={}", uri, token);
+            assert_ne!(
+                status(&app, request("GET", &good, None)).await,
+                StatusCode::UNAUTHORIZED,
+                "{} with a valid token",
+                uri
+            );
+        }
+    }
+
+    async fn ws_ticket(app: &Router, token: &str) -> String {
+        let response = app
+            .clone()
+            .oneshot(request("POST", "/auth/ws-ticket", Some(token)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["expires_in"], 30);
+        body["ticket"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_websocket_tickets_are_single_use() {
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let token = access_token(&state).await;
+        let app = router(state);
+
+        for uri in WEBSOCKETS {
+            let with_ticket = format!("{}?ticket={}", uri, ws_ticket(&app, &token).await);
+            assert_ne!(
+                status(&app, request("GET", &with_ticket, None)).await,
+                StatusCode::UNAUTHORIZED,
+                "{} with a fresh ticket",
+                uri
+            );
+            assert_eq!(
+                status(&app, request("GET", &with_ticket, None)).await,
+                StatusCode::UNAUTHORIZED,
+                "{} with a used ticket",
+                uri
+            );
+        }
+        assert_eq!(
+            status(
+                &app,
+                request("GET", "/api/teams/t1/chat?ticket=wst_nope", None)
+            )
+            .await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        // Logging out also voids tickets not yet used
+        let ticket = ws_ticket(&app, &token).await;
+        app.clone()
+            .oneshot(request("POST", "/auth/logout", Some(&token)))
+            .await
+            .unwrap();
+        let uri = format!("/api/teams/t1/chat?ticket={}", ticket);
+        assert_eq!(
+            status(&app, request("GET", &uri, None)).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_public_routes_need_no_token() {
+        let dir = tempdir().unwrap();
+        let app = router(test_state(&dir).await);
+
+        for uri in ["/health", "/auth/status", "/auth/jwks"] {
+            assert_eq!(
+                status(&app, request("GET", uri, None)).await,
+                StatusCode::OK,
+                "GET {}",
+                uri
+            );
+        }
+        // Reachable, but the empty body is rejected by the handler, not the middleware
+        assert_ne!(
+            status(&app, request("POST", "/api/auth/refresh", None)).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_claims_extractors_guard_handlers_without_middleware() {
+        use auth::{AdminClaims, Claims, OptionalClaims};
+
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let admin = access_token(&state).await;
+        let viewer = {
+            let mut auth = state.auth.write().await;
+            auth.create_user(&auth::CreateUserRequest {
+                username: "viewer".to_string(),
+                password: "viewer password".to_string(),
+                role: users::Role::Viewer,
+                disabled: false,
+            })
+            .unwrap();
+            match auth.login("viewer", "viewer password").unwrap() {
+                auth::LoginResponse::Tokens(tokens) => tokens.access_token,
+                auth::LoginResponse::MfaRequired(_) => panic!("unexpected MFA challenge"),
+            }
+        };
+        // No auth middleware on any of these routes
+        let app = Router::new()
+            .route("/whoami", get(|claims: Claims| async move { claims.sub }))
+            .route("/admin", get(|_admin: AdminClaims| async { "ok" }))
+            .route(
+                "/greet",
+                get(|OptionalClaims(claims): OptionalClaims| async move {
+                    claims.map_or("anonymous".to_string(), |c| c.sub)
+                }),
+            )
+            .with_state(state);
+
+        assert_eq!(
+            status(&app, request("GET", "/whoami", None)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, request("GET", "/whoami", Some("garbage"))).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, request("GET", "/whoami", Some(&viewer))).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&app, request("GET", "/admin", None)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, request("GET", "/admin", Some(&viewer))).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(&app, request("GET", "/admin", Some(&admin))).await,
+            StatusCode::OK
+        );
+
+        for (token, expected) in [(None, "anonymous"), (Some(admin.as_str()), "admin")] {
+            let response = app
+                .clone()
+                .oneshot(request("GET", "/greet", token))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body, expected);
+        }
+    }
+
+    /// Send `body` as JSON, returning the status and the JSON response, if any
+    #[tokio::test]
+    async fn test_agent_routes_take_runtime_ids() {
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let token = access_token(&state).await;
+        // Named by a runtime, not one of the formats validate_agent_id knows
+        state
+            .containers
+            .write()
+            .await
+            .push(crate::types::AgentContainer {
+                id: "MyAgent-1".to_string(),
+                name: "worker".to_string(),
+                status: crate::types::AgentStatus::Stopped,
+                config: Default::default(),
+                tailscale_ip: None,
+                resource_usage: None,
+                project: None,
+                tags: vec![],
+                restart_policy: Default::default(),
+                health_status: None,
+                runtime: None,
+            });
+        let app = router(state);
+        let put = |uri: &str| {
+            Request::builder()
+                .method("PUT")
+                .uri(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from("{}"))
+                .unwrap()
+        };
+
+        assert_eq!(
+            status(&app, request("GET", "/api/agents/MyAgent-1", Some(&token))).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&app, put("/api/agents/MyAgent-2")).await,
+            StatusCode::NOT_FOUND
+        );
+        for uri in ["/api/agents/my.agent", "/api/agents/my%20agent"] {
+            assert_eq!(
+                status(&app, request("GET", uri, Some(&token))).await,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "{}",
+                uri
+            );
+            assert_eq!(
+                status(&app, put(uri)).await,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "{}",
+                uri
+            );
+            assert_eq!(
+                status(&app, request("DELETE", uri, Some(&token))).await,
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "{}",
+                uri
+            );
+        }
+    }
+
+    async fn call_json(
+        app: &Router,
+        uri: &str,
+        token: Option<&str>,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let mut builder = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(audit::REQUEST_ID_HEADER, "req-42");
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let response = app
+            .clone()
+            .oneshot(builder.body(Body::from(body.to_string())).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_records_events_without_credentials() {
+        use audit::AuditEvent::{self, *};
+
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let viewer = {
+            let mut auth = state.auth.write().await;
+            auth.create_user(&auth::CreateUserRequest {
+                username: "viewer".to_string(),
+                password: "viewer password".to_string(),
+                role: users::Role::Viewer,
+                disabled: false,
+            })
+            .unwrap();
+            match auth.login("viewer", "viewer password").unwrap() {
+                auth::LoginResponse::Tokens(tokens) => tokens.access_token,
+                auth::LoginResponse::MfaRequired(_) => panic!("unexpected MFA challenge"),
+            }
+        };
+        let app = router(state.clone());
+        let login = |password: &str| serde_json::json!({"username": "admin", "password": password});
+        let mut secrets = vec![
+            "wrong password".to_string(),
+            "correct horse".to_string(),
+            "battery staple".to_string(),
+            viewer.clone(),
+        ];
+
+        let (code, _) = call_json(&app, "/auth/login", None, login("wrong password")).await;
+        assert_eq!(code, StatusCode::UNAUTHORIZED);
+        let (code, first) = call_json(&app, "/auth/login", None, login("correct horse")).await;
+        assert_eq!(code, StatusCode::OK);
+        let (code, refreshed) = call_json(
+            &app,
+            "/api/auth/refresh",
+            None,
+            serde_json::json!({"refresh_token": first["refresh_token"]}),
+        )
+        .await;
+        assert_eq!(code, StatusCode::OK);
+        let access = refreshed["access_token"].as_str().unwrap().to_string();
+        for tokens in [&first, &refreshed] {
+            secrets.push(tokens["access_token"].as_str().unwrap().to_string());
+            secrets.push(tokens["refresh_token"].as_str().unwrap().to_string());
+        }
+        assert_eq!(
+            status(&app, request("GET", "/auth/users", Some(&viewer))).await,
+            StatusCode::FORBIDDEN
+        );
+        let (code, _) = call_json(
+            &app,
+            "/auth/change-password",
+            Some(&access),
+            serde_json::json!({"current_password": "correct horse", "new_password": "battery staple"}),
+        )
+        .await;
+        assert_eq!(code, StatusCode::NO_CONTENT);
+        let (_, admin) = call_json(&app, "/auth/login", None, login("battery staple")).await;
+        let admin = admin["access_token"].as_str().unwrap().to_string();
+        secrets.push(admin.clone());
+        let (code, _) = call_json(&app, "/auth/logout", Some(&admin), serde_json::json!({})).await;
+        assert_eq!(code, StatusCode::NO_CONTENT);
+
+        let reader = {
+            let auth = state.auth.read().await;
+            match auth.login("admin", "battery staple").unwrap() {
+                auth::LoginResponse::Tokens(tokens) => tokens.access_token,
+                auth::LoginResponse::MfaRequired(_) => panic!("unexpected MFA challenge"),
+            }
+        };
+        assert_eq!(
+            status(&app, request("GET", "/auth/audit", Some(&viewer))).await,
+            StatusCode::FORBIDDEN
+        );
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/auth/audit?limit=1000", Some(&reader)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let page: audit::AuditPage = serde_json::from_slice(&body).unwrap();
+        let events: Vec<AuditEvent> = page.entries.iter().map(|e| e.event).collect();
+        assert_eq!(
+            events,
+            [
+                LoginFailure,
+                LoginSuccess,
+                TokenRefresh,
+                Forbidden,
+                PasswordChange,
+                LoginSuccess,
+                Logout,
+                Forbidden,
+            ]
+        );
+        assert_eq!(page.entries[0].subject.as_deref(), Some("admin"));
+        assert_eq!(page.entries[0].request_id, "req-42");
+        assert_eq!(page.entries[3].subject.as_deref(), Some("viewer"));
+        assert_eq!(page.entries[3].detail.as_deref(), Some("GET /auth/users"));
+
+        let mut written = String::new();
+        for file in std::fs::read_dir(dir.path().join("audit")).unwrap() {
+            written.push_str(&std::fs::read_to_string(file.unwrap().path()).unwrap());
+        }
+        assert_eq!(written.lines().count(), events.len());
+        for secret in &secrets {
+            assert!(
+                !written.contains(secret.as_str()),
+                "audit log holds {:?}",
+                secret
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_device_login_over_http() {
+        use ed25519_dalek::Signer;
+
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let admin = access_token(&state).await;
+        let app = router(state);
+        let key = ed25519_dalek::SigningKey::from_bytes(&[9; 32]);
+        let device_id = devices::device_id(&key.verifying_key());
+        let public_key = {
+            use base64::Engine;
+            base64::engine::general_purpose::STANDARD.encode(key.verifying_key().as_bytes())
+        };
+        let login = |nonce: &str, signed_at: i64| {
+            use base64::Engine;
+            let message = devices::signable_string(&device_id, signed_at, nonce);
+            serde_json::json!({
+                "deviceId": device_id,
+                "publicKey": public_key,
+                "signedAt": signed_at,
+                "signature": base64::engine::general_purpose::STANDARD
+                    .encode(key.sign(message.as_bytes()).to_bytes()),
+                "nonce": nonce,
+            })
+        };
+        let challenge = |app: Router| async move {
+            let (code, body) =
+                call_json(&app, "/auth/device/challenge", None, serde_json::json!({})).await;
+            assert_eq!(code, StatusCode::OK);
+            body["nonce"].as_str().unwrap().to_string()
+        };
+        let now_ms = || chrono::Utc::now().timestamp_millis();
+
+        // Not approved yet
+        let nonce = challenge(app.clone()).await;
+        let (code, body) =
+            call_json(&app, "/auth/device/login", None, login(&nonce, now_ms())).await;
+        assert_eq!(code, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["code"], "DEVICE_NOT_APPROVED");
+
+        let approve = serde_json::json!({"device_id": device_id, "public_key": public_key});
+        let (code, _) = call_json(&app, "/auth/devices", Some(&admin), approve).await;
+        assert_eq!(code, StatusCode::CREATED);
+
+        let nonce = challenge(app.clone()).await;
+        let signed = login(&nonce, now_ms());
+        let (code, body) = call_json(&app, "/auth/device/login", None, signed.clone()).await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(
+            status(
+                &app,
+                request("GET", "/api/agents", body["access_token"].as_str())
+            )
+            .await,
+            StatusCode::OK
+        );
+
+        // Replaying the same signed request fails on the used nonce
+        let (code, body) = call_json(&app, "/auth/device/login", None, signed).await;
+        assert_eq!(code, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["code"], "DEVICE_CHALLENGE_INVALID");
+
+        // A signature from two minutes ago is stale, even with a fresh nonce
+        let nonce = challenge(app.clone()).await;
+        let (code, body) = call_json(
+            &app,
+            "/auth/device/login",
+            None,
+            login(&nonce, now_ms() - 120_000),
+        )
+        .await;
+        assert_eq!(code, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["code"], "DEVICE_SIGNATURE_STALE");
+    }
+
+    #[tokio::test]
+    async fn test_sessions_over_http() {
+        let dir = tempdir().unwrap();
+        let app = router(test_state(&dir).await);
+        let login = serde_json::json!({"username": "admin", "password": "correct horse"});
+        let (_, laptop) = call_json(&app, "/auth/login", None, login.clone()).await;
+        let (_, desktop) = call_json(&app, "/auth/login", None, login).await;
+        let laptop = laptop["access_token"].as_str().unwrap();
+        let desktop = desktop["access_token"].as_str().unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/auth/sessions?limit=1", Some(desktop)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(!text.contains(laptop) && !text.contains(desktop));
+        let first: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(first["sessions"].as_array().unwrap().len(), 1);
+        let cursor = first["next_after"].as_str().unwrap();
+        let response = app
+            .clone()
+            .oneshot(request(
+                "GET",
+                &format!("/auth/sessions?after={}", cursor),
+                Some(desktop),
+            ))
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let second: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(second["next_after"], serde_json::Value::Null);
+        let sessions = [&first["sessions"][0], &second["sessions"][0]];
+        let other = sessions.iter().find(|s| s["current"] == false).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let uri = format!("/auth/sessions/{}", other);
+        assert_eq!(
+            status(&app, request("DELETE", &uri, Some(desktop))).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            status(&app, request("GET", "/api/agents", Some(laptop))).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&app, request("GET", "/api/agents", Some(desktop))).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&app, request("DELETE", &uri, Some(desktop))).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_secret_names_are_checked_before_touching_disk() {
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let token = access_token(&state).await;
+        let app = router(state);
+
+        for name in ["../../escape", ".", "a/b", ""] {
+            let (code, body) = call_json(
+                &app,
+                "/api/agents/a1/secrets",
+                Some(&token),
+                serde_json::json!({ "name": name, "value": "secret-value" }),
+            )
+            .await;
+            assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY, "{}", name);
+            assert_eq!(body["error"]["field"], "name", "{}", name);
+        }
+        assert_eq!(
+            status(
+                &app,
+                request("DELETE", "/api/agents/a1/secrets/..", Some(&token))
+            )
+            .await,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validation_rules_list_what_creation_refuses() {
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let token = access_token(&state).await;
+        let app = router(state);
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/validation/rules", Some(&token)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let rules: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            rules["container_name"]["reserved"],
+            serde_json::json!({
+                "exact": ["host", "none", "bridge", "default"],
+                "prefixes": ["claw-pen-", "openclaw-"],
+            })
+        );
+        assert_eq!(
+            rules["project_name"]["scripts"],
+            serde_json::json!(["Latin"])
+        );
+        assert_eq!(rules["max_tags"], validation::MAX_TAGS_COUNT);
+        assert_eq!(
+            rules["schema_version"],
+            validation::VALIDATION_RULES_VERSION
+        );
+        assert_eq!(
+            rules["env"]["max_value_length"],
+            validation::MAX_ENV_VALUE_LENGTH
+        );
+
+        for name in ["Bridge", "CLAW-PEN-orchestrator"] {
+            let (code, body) = call_json(
+                &app,
+                "/api/agents",
+                Some(&token),
+                serde_json::json!({ "name": name }),
+            )
+            .await;
+            assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY, "{}", name);
+            assert_eq!(body["error"]["errors"][0]["kind"], "reserved_name");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_introspection_over_http() {
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let admin = access_token(&state).await;
+        let gateway = state
+            .auth
+            .write()
+            .await
+            .create_api_key(&auth::CreateApiKeyRequest {
+                name: "gateway".to_string(),
+                role: users::Role::Admin,
+                scopes: vec![scopes::AUTH_INTROSPECT.to_string()],
+                expires_at: None,
+            })
+            .unwrap()
+            .secret;
+        let reader = state
+            .auth
+            .write()
+            .await
+            .create_api_key(&auth::CreateApiKeyRequest {
+                name: "reader".to_string(),
+                role: users::Role::Admin,
+                scopes: vec![scopes::AGENTS_READ.to_string()],
+                expires_at: None,
+            })
+            .unwrap()
+            .secret;
+        *state.introspection_limiter.lock().await = introspection::IntrospectionLimiter::new(3);
+        let app = router(state);
+
+        let (code, body) = call_json(
+            &app,
+            "/auth/introspect",
+            Some(&gateway),
+            serde_json::json!({ "token": admin }),
+        )
+        .await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["active"], true);
+        assert_eq!(body["sub"], "admin");
+        assert_eq!(body["token_type"], "access");
+        assert!(body["scope"].as_str().unwrap().contains("auth.admin"));
+
+        let (code, body) = call_json(
+            &app,
+            "/auth/introspect",
+            Some(&gateway),
+            serde_json::json!({ "token": "garbage" }),
+        )
+        .await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({ "active": false }));
+
+        // The gateway key can't do anything else, and keys without the
+        // scope can't introspect
+        assert_eq!(
+            status(&app, request("GET", "/api/agents", Some(&gateway))).await,
+            StatusCode::FORBIDDEN
+        );
+        let (code, body) = call_json(
+            &app,
+            "/auth/introspect",
+            Some(&reader),
+            serde_json::json!({ "token": admin }),
+        )
+        .await;
+        assert_eq!(code, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["code"], "INSUFFICIENT_SCOPE");
+
+        call_json(
+            &app,
+            "/auth/introspect",
+            Some(&gateway),
+            serde_json::json!({ "token": admin }),
+        )
+        .await;
+        let (code, body) = call_json(
+            &app,
+            "/auth/introspect",
+            Some(&gateway),
+            serde_json::json!({ "token": admin }),
+        )
+        .await;
+        assert_eq!(code, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["error"]["code"], "TOO_MANY_REQUESTS");
+    }
+
+    #[tokio::test]
+    async fn test_service_tokens_only_work_on_the_agent_api() {
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let admin = access_token(&state).await;
+        let agent_id = format!("service-token-test-{}", uuid::Uuid::new_v4());
+        state
+            .containers
+            .write()
+            .await
+            .push(crate::types::AgentContainer {
+                id: agent_id.clone(),
+                name: "worker".to_string(),
+                status: crate::types::AgentStatus::Running,
+                config: Default::default(),
+                tailscale_ip: None,
+                resource_usage: None,
+                project: None,
+                tags: vec![],
+                restart_policy: Default::default(),
+                health_status: None,
+                runtime: None,
+            });
+        let service_scopes: Vec<&str> = scopes::SERVICE_SCOPES
+            .iter()
+            .map(|(name, _)| *name)
+            .collect();
+        let token = state
+            .auth
+            .read()
+            .await
+            .mint_service_token(&agent_id, &service_scopes, 3600)
+            .unwrap();
+        let app = router(state.clone());
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/api/agent/self", Some(&token)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let agent: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(agent["id"], agent_id.as_str());
+        assert_eq!(
+            status(
+                &app,
+                request("GET", "/api/agent/self/secrets", Some(&token))
+            )
+            .await,
+            StatusCode::OK
+        );
+        let (code, body) = call_json(
+            &app,
+            "/api/agent/self/health",
+            Some(&token),
+            serde_json::json!({ "healthy": false, "message": "out of disk" }),
+        )
+        .await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["healthy"], false);
+        let health = state.containers.read().await[0].health_status.clone();
+        assert_eq!(health.unwrap().message.as_deref(), Some("out of disk"));
+
+        // Nowhere else, not even to log out
+        for (method, uri) in [("GET", "/api/agents"), ("POST", "/auth/logout")] {
+            let response = app
+                .clone()
+                .oneshot(request(method, uri, Some(&token)))
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::FORBIDDEN,
+                "{} {}",
+                method,
+                uri
+            );
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["error"]["code"], "SERVICE_TOKEN_NOT_ALLOWED");
+        }
+        // People can't use the agent API
+        assert_eq!(
+            status(&app, request("GET", "/api/agent/self", Some(&admin))).await,
+            StatusCode::FORBIDDEN
+        );
+
+        // Stopping or deleting the agent ends its lease
+        state
+            .auth
+            .write()
+            .await
+            .revoke_service_tokens(&agent_id)
+            .unwrap();
+        assert_eq!(
+            status(&app, request("GET", "/api/agent/self", Some(&token))).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cookie_session() {
+        let dir = tempdir().unwrap();
+        let app = router(test_state(&dir).await);
+        let send = |method: &str, uri: &str, cookies: &str, csrf: Option<&str>| {
+            let mut builder = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::COOKIE, cookies);
+            if let Some(csrf) = csrf {
+                builder = builder.header(session_cookies::CSRF_HEADER, csrf);
+            }
+            let body = if method == "GET" { "" } else { "{}" };
+            let request = builder.body(Body::from(body)).unwrap();
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap() }
+        };
+        // `name=value` of each Set-Cookie, as the browser would send them back
+        let set_cookies = |response: &axum::response::Response| -> Vec<String> {
+            response
+                .headers()
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .map(|v| v.to_str().unwrap().split(';').next().unwrap().to_string())
+                .collect()
+        };
+        let json = |response: axum::response::Response| async move {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/auth/login?cookie=true")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        serde_json::json!({"username": "admin", "password": "correct horse"})
+                            .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookies = set_cookies(&response);
+        assert_eq!(cookies.len(), 3);
+        let session = json(response).await;
+        assert!(session.get("access_token").is_none());
+        assert!(session.get("refresh_token").is_none());
+        let csrf = session["csrf_token"].as_str().unwrap().to_string();
+        assert!(cookies.contains(&format!("claw_pen_csrf={}", csrf)));
+        let jar = cookies.join("; ");
+
+        // Reads need only the cookie
+        let response = send("GET", "/api/agents", &jar, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Writes also need the CSRF token
+        let response = send("POST", "/auth/ws-ticket", &jar, None).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(json(response).await["error"]["code"], "CSRF_TOKEN_INVALID");
+        let response = send("POST", "/auth/ws-ticket", &jar, Some("forged")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = send("POST", "/auth/ws-ticket", &jar, Some(&csrf)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Refreshing by cookie needs the CSRF token and hands out new cookies
+        let response = send("POST", "/api/auth/refresh", &jar, None).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = send("POST", "/api/auth/refresh", &jar, Some(&csrf)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookies = set_cookies(&response);
+        assert_eq!(cookies.len(), 3);
+        let csrf = json(response).await["csrf_token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let jar = cookies.join("; ");
+
+        // Logging out clears the cookies and revokes the session
+        let response = send("POST", "/auth/logout", &jar, Some(&csrf)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            set_cookies(&response),
+            ["claw_pen_session=", "claw_pen_refresh=", "claw_pen_csrf="]
+        );
+        let response = send("GET", "/api/agents", &jar, None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_api_key_scopes_limit_routes() {
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let key = {
+            let mut auth = state.auth.write().await;
+            auth.create_api_key(&auth::CreateApiKeyRequest {
+                name: "dashboard".to_string(),
+                role: users::Role::Operator,
+                scopes: vec![scopes::AGENTS_READ.to_string()],
+                expires_at: None,
+            })
+            .unwrap()
+            .secret
+        };
+        let app = router(state);
+
+        assert_eq!(
+            status(&app, request("GET", "/api/agents", Some(&key))).await,
+            StatusCode::OK
+        );
+        for (uri, scope) in [
+            ("/api/agents/start-all", scopes::AGENTS_WRITE),
+            ("/api/keys", scopes::KEYS_WRITE),
+            ("/api/teams/t1/classify", scopes::TEAMS_WRITE),
+        ] {
+            let (code, body) = call_json(&app, uri, Some(&key), serde_json::json!({})).await;
+            assert_eq!(code, StatusCode::FORBIDDEN, "POST {}", uri);
+            assert_eq!(body["error"]["code"], "INSUFFICIENT_SCOPE");
+            assert_eq!(body["error"]["scope"], scope);
+        }
+        for uri in ["/api/projects", "/api/system/stats"] {
+            assert_eq!(
+                status(&app, request("GET", uri, Some(&key))).await,
+                StatusCode::FORBIDDEN,
+                "GET {}",
+                uri
+            );
+        }
+        assert_eq!(
+            status(&app, request("GET", "/auth/users", Some(&key))).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_bodies_are_capped() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let app = router(state.clone());
+        let post = |body: Body, length: Option<usize>| {
+            let mut builder = Request::builder()
+                .method("POST")
+                .uri("/auth/login")
+                .header(header::CONTENT_TYPE, "application/json");
+            if let Some(length) = length {
+                builder = builder.header(header::CONTENT_LENGTH, length);
+            }
+            let mut request = builder.body(body).unwrap();
+            let peer: std::net::SocketAddr = "203.0.113.20:40000".parse().unwrap();
+            request
+                .extensions_mut()
+                .insert(axum::extract::ConnectInfo(peer));
+            request
+        };
+        let call = |request: Request<Body>| {
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                (status, body["error"]["code"].as_str().unwrap().to_string())
+            }
+        };
+        const TEN_MB: usize = 10 * 1024 * 1024;
+
+        // Refused on the declared length, before reading any of it
+        let (code, error) = call(post(Body::from(vec![b'a'; TEN_MB]), Some(TEN_MB))).await;
+        assert_eq!(code, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error, "PAYLOAD_TOO_LARGE");
+
+        // Without a length, reading stops at the cap
+        static CHUNK: [u8; 1024] = [b' '; 1024];
+        let polled = Arc::new(AtomicUsize::new(0));
+        let counter = polled.clone();
+        let stream = futures_util::stream::iter((0..TEN_MB / CHUNK.len()).map(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, std::io::Error>(axum::body::Bytes::from_static(&CHUNK))
+        }));
+        let (code, error) = call(post(Body::from_stream(stream), None)).await;
+        assert_eq!(code, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error, "PAYLOAD_TOO_LARGE");
+        assert!(polled.load(Ordering::SeqCst) <= auth_body::MAX_BODY_BYTES / CHUNK.len() + 1);
+
+        let deep = format!("{}{}", "[".repeat(1000), "]".repeat(1000));
+        let (code, error) = call(post(Body::from(deep), None)).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(error, "INVALID_BODY");
+        let (code, error) = call(post(Body::from("{\"username\": "), None)).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(error, "INVALID_BODY");
+
+        // Junk counts like failed logins: one more and the IP is throttled
+        let (code, _) = call(post(Body::from("nope"), None)).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        let login = serde_json::json!({"username": "admin", "password": "correct horse"});
+        let (code, error) = call(post(Body::from(login.to_string()), None)).await;
+        assert_eq!(code, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error, "RATE_LIMITED");
+
+        let text = state.metrics.render(0);
+        assert!(
+            text.contains("claw_pen_auth_rejected_bodies_total{reason=\"PAYLOAD_TOO_LARGE\"} 2")
+        );
+        assert!(text.contains("claw_pen_auth_rejected_bodies_total{reason=\"INVALID_BODY\"} 3"));
+    }
+
+    #[tokio::test]
+    async fn test_login_limiter_keys_on_the_forwarded_client() {
+        let dir = tempdir().unwrap();
+        let app = router(test_state(&dir).await);
+        let login = |peer: &str, forwarded_for: &str| {
+            let mut request = Request::builder()
+                .method("POST")
+                .uri("/auth/login")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header("x-forwarded-for", forwarded_for)
+                .body(Body::from(
+                    serde_json::json!({"username": "admin", "password": "wrong password"})
+                        .to_string(),
+                ))
+                .unwrap();
+            let peer: std::net::SocketAddr = format!("{}:40000", peer).parse().unwrap();
+            request
+                .extensions_mut()
+                .insert(axum::extract::ConnectInfo(peer));
+            request
+        };
+
+        // Behind the trusted proxy, each forwarded client has its own budget
+        for _ in 0..5 {
+            assert_eq!(
+                status(&app, login("127.0.0.1", "198.51.100.7")).await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(
+            status(&app, login("127.0.0.1", "198.51.100.7")).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            status(&app, login("127.0.0.1", "198.51.100.8")).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        // A direct client can't escape its budget by sending the header
+        for i in 0..5 {
+            let request = login("203.0.113.9", &format!("192.0.2.{}", i));
+            assert_eq!(status(&app, request).await, StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(
+            status(&app, login("203.0.113.9", "192.0.2.99")).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn test_register_only_answers_allowed_networks() {
+        let dir = tempdir().unwrap();
+        let app = router(test_state(&dir).await);
+        let register = |peer: &str, forwarded_for: Option<&str>| {
+            let mut builder = Request::builder()
+                .method("POST")
+                .uri("/auth/register")
+                .header(header::CONTENT_TYPE, "application/json");
+            if let Some(forwarded_for) = forwarded_for {
+                builder = builder.header("x-forwarded-for", forwarded_for);
+            }
+            let mut request = builder
+                .body(Body::from(
+                    serde_json::json!({"password": "correct horse", "bootstrap_token": "cpb_guess"})
+                        .to_string(),
+                ))
+                .unwrap();
+            let peer = std::net::SocketAddr::new(peer.parse().unwrap(), 40000);
+            request
+                .extensions_mut()
+                .insert(axum::extract::ConnectInfo(peer));
+            request
+        };
+        let code = |response: axum::response::Response| async move {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            body["error"]["code"].as_str().unwrap().to_string()
+        };
+        let send = |request: Request<Body>| {
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap() }
+        };
+
+        // Local clients get as far as the registration checks
+        for (peer, forwarded_for) in [("127.0.0.1", None), ("::1", None)] {
+            let response = send(register(peer, forwarded_for)).await;
+            assert_eq!(code(response).await, "REGISTRATION_DISABLED");
+        }
+
+        // A remote client can't pass for a local one, whatever it forwards
+        for forwarded_for in [None, Some("127.0.0.1"), Some("127.0.0.1, 127.0.0.1")] {
+            let response = send(register("203.0.113.9", forwarded_for)).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            assert_eq!(code(response).await, "REGISTRATION_NETWORK_DENIED");
+        }
+
+        // Through the trusted proxy, the forwarded client counts
+        let response = send(register("127.0.0.1", Some("198.51.100.7"))).await;
+        assert_eq!(code(response).await, "REGISTRATION_NETWORK_DENIED");
+        let response = send(register("127.0.0.1", Some("127.0.0.1"))).await;
+        assert_eq!(code(response).await, "REGISTRATION_DISABLED");
+
+        // Without a resolved address, the answer is no
+        let body = serde_json::json!({"password": "correct horse"});
+        let (_, body) = call_json(&app, "/auth/register", None, body).await;
+        assert_eq!(body["error"]["code"], "REGISTRATION_NETWORK_DENIED");
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_failed_logins() {
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let app = router(state);
+        async fn scrape(app: &Router) -> String {
+            let response = app
+                .clone()
+                .oneshot(request("GET", "/metrics", Some(SCRAPE_TOKEN)))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+        let failures = |text: &str| {
+            text.lines()
+                .find(|l| {
+                    l.starts_with(
+                        "claw_pen_auth_login_attempts_total{outcome=\"INVALID_CREDENTIALS\"}",
+                    )
+                })
+                .map_or(0, |l| l.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+        };
+
+        assert_eq!(
+            status(&app, request("GET", "/metrics", None)).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(&app, request("GET", "/metrics", Some("wrong-token"))).await,
+            StatusCode::FORBIDDEN
+        );
+        let before = failures(&scrape(&app).await);
+        let login = serde_json::json!({"username": "admin", "password": "wrong password"});
+        let (code, _) = call_json(&app, "/auth/login", None, login).await;
+        assert_eq!(code, StatusCode::UNAUTHORIZED);
+        let text = scrape(&app).await;
+        assert_eq!(failures(&text), before + 1);
+        assert!(!text.contains("outcome=\"success\""));
+        assert!(text.contains("claw_pen_auth_denylist_size 0"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_specs_get_every_error_in_one_response() {
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let admin = access_token(&state).await;
+        let app = router(state);
+
+        let spec = serde_json::json!({
+            "name": "",
+            "config": {"env_vars": {"API_KEY": "a\0b"}, "memory_mb": 0},
+        });
+        let (code, body) = call_json(&app, "/api/agents", Some(&admin), spec).await;
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"]["code"], "VALIDATION_FAILED");
+        let fields: Vec<&str> = body["error"]["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["field"].as_str().unwrap())
+            .collect();
+        assert_eq!(fields, vec!["name", "env[API_KEY].value", "memory_mb"]);
+
+        let project = serde_json::json!({"name": ""});
+        let (code, body) = call_json(&app, "/api/projects", Some(&admin), project).await;
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"]["errors"][0]["kind"], "empty");
+    }
+
+    #[tokio::test]
+    async fn test_agents_must_fit_the_host() {
+        let dir = tempdir().unwrap();
+        let state = test_state(&dir).await;
+        let admin = access_token(&state).await;
+        let app = router(state);
+
+        // The test host has 8192 MB and 4 cores, 90% of which agents may use
+        let spec = serde_json::json!({
+            "name": "big-agent",
+            "config": {"memory_mb": 8000, "cpu_cores": 4.0},
+        });
+        let (code, body) = call_json(&app, "/api/agents", Some(&admin), spec).await;
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        let errors = body["error"]["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0]["field"], "memory_mb");
+        assert_eq!(errors[0]["kind"], "out_of_range");
+        assert_eq!(errors[0]["requested"], 8000.0);
+        assert_eq!(errors[0]["available"], 7372.0);
+        assert_eq!(errors[1]["field"], "cpu_cores");
+        assert_eq!(errors[1]["requested"], 4.0);
+
+        // ...and 2 GPUs
+        let spec = serde_json::json!({
+            "name": "gpu-agent",
+            "config": {"gpus": {"count": 3}},
+        });
+        let (code, body) = call_json(&app, "/api/agents", Some(&admin), spec).await;
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        let errors = body["error"]["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["field"], "gpus.count");
+        assert_eq!(errors[0]["kind"], "over_capacity");
+        assert_eq!(errors[0]["available"], 2.0);
+
+        // ...and 10 GB free under the volume base
+        let spec = serde_json::json!({
+            "name": "disk-agent",
+            "config": {"storage_opts": {"size": "20G"}},
+        });
+        let (code, body) = call_json(&app, "/api/agents", Some(&admin), spec).await;
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        let errors = body["error"]["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["field"], "storage_opts[size]");
+        assert_eq!(errors[0]["kind"], "over_capacity");
+        assert_eq!(errors[0]["requested"], 20480.0);
+        assert_eq!(errors[0]["available"], 10240.0);
+        assert_eq!(errors[0]["limit"], validation::DEFAULT_MAX_DISK_MB);
+    }
+
+    #[tokio::test]
+    async fn test_shell_syntax_warnings_refuse_only_in_strict_mode() {
+        // Too much memory, so creation stops at the host check
+        let spec = serde_json::json!({
+            "name": "shell-agent",
+            "config": {"entrypoint": ["sh -c 'run | tee log'"], "memory_mb": 8000},
+        });
+        for strict in [false, true] {
+            let dir = tempdir().unwrap();
+            let mut state = test_state(&dir).await;
+            Arc::get_mut(&mut state)
+                .unwrap()
+                .validation
+                .strict_exec_args = strict;
+            let admin = access_token(&state).await;
+            let app = router(state);
+            let (code, body) = call_json(&app, "/api/agents", Some(&admin), spec.clone()).await;
+            assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+            let error = &body["error"]["errors"][0];
+            if strict {
+                assert_eq!(error["field"], "entrypoint[0]");
+                assert_eq!(error["kind"], "shell_syntax");
+            } else {
+                assert_eq!(error["field"], "memory_mb");
+            }
+        }
+    }
+}
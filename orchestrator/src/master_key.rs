@@ -0,0 +1,434 @@
+//! Master key for auth secrets at rest
+//!
+//! The signing keys, the root secret that TOTP secrets are encrypted with
+//! and API keys are hashed with, and the audit IP key all sit in the
+//! `secrets` table of `auth.db`. Anyone with a copy of the data directory, a
+//! leaked backup say, could sign tokens and read second factors. So when a
+//! master key is set, [`SealedStore`] encrypts those secrets with
+//! AES-256-GCM before they reach the store and decrypts them on load.
+//!
+//! The key is 32 bytes, base64, from `CLAW_PEN_MASTER_KEY` or the file named
+//! by `CLAW_PEN_MASTER_KEY_FILE`, which must be outside the data directory.
+//! A sealed secret is `cpmk1:` and the base64 of a random nonce followed by
+//! the ciphertext; the secret's name is authenticated with it, so sealed
+//! values can't be swapped between names. Without a master key nothing is
+//! encrypted and a warning is logged at startup.
+//!
+//! Secrets stored before the key was set stay readable, and are sealed by
+//! `--rewrap-auth-secrets`. Exports keep secrets sealed.
+
+use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::api_keys::ApiKey;
+use crate::auth::AuthError;
+use crate::auth_store::{AuthSnapshot, AuthStore};
+use crate::devices::Device;
+use crate::sessions::Session;
+use crate::users::User;
+
+/// Environment variable holding the base64 master key
+pub const MASTER_KEY_ENV: &str = "CLAW_PEN_MASTER_KEY";
+
+/// Environment variable naming a file holding the base64 master key
+pub const MASTER_KEY_FILE_ENV: &str = "CLAW_PEN_MASTER_KEY_FILE";
+
+/// Prefix of a sealed secret: the envelope version
+const ENVELOPE_PREFIX: &str = "cpmk1:";
+
+/// Bytes in a master key
+const KEY_LENGTH: usize = 32;
+
+pub struct MasterKey {
+    key: LessSafeKey,
+}
+
+impl MasterKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AuthError> {
+        if bytes.len() != KEY_LENGTH {
+            return Err(AuthError::InvalidConfig(format!(
+                "the master key must be {} bytes, not {}",
+                KEY_LENGTH,
+                bytes.len()
+            )));
+        }
+        let key = UnboundKey::new(&AES_256_GCM, bytes)
+            .map_err(|_| AuthError::EncryptionError("unusable master key".into()))?;
+        Ok(Self {
+            key: LessSafeKey::new(key),
+        })
+    }
+
+    /// A master key from its base64
+    pub fn parse(text: &str) -> Result<Self, AuthError> {
+        let bytes = BASE64_STANDARD
+            .decode(text.trim())
+            .map_err(|_| AuthError::InvalidConfig("the master key must be base64".into()))?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// The master key from `CLAW_PEN_MASTER_KEY` or `CLAW_PEN_MASTER_KEY_FILE`,
+    /// if either is set; a key file must be outside `data_dir`
+    pub fn from_env(data_dir: &Path) -> Result<Option<Self>, AuthError> {
+        Self::load(
+            std::env::var(MASTER_KEY_ENV).ok(),
+            std::env::var(MASTER_KEY_FILE_ENV).ok(),
+            data_dir,
+        )
+    }
+
+    /// The master key from its base64 or the file holding it
+    fn load(
+        key: Option<String>,
+        key_file: Option<String>,
+        data_dir: &Path,
+    ) -> Result<Option<Self>, AuthError> {
+        if let Some(key) = key {
+            return Self::parse(&key).map(Some);
+        }
+        let Some(path) = key_file else {
+            return Ok(None);
+        };
+        let path = Path::new(&path).canonicalize()?;
+        if data_dir
+            .canonicalize()
+            .is_ok_and(|data_dir| path.starts_with(data_dir))
+        {
+            return Err(AuthError::InvalidConfig(format!(
+                "{} is in the data directory; keep the master key apart from what it protects",
+                path.display()
+            )));
+        }
+        Self::parse(&std::fs::read_to_string(&path)?).map(Some)
+    }
+
+    /// Encrypt secret `name`'s `value`
+    pub fn seal(&self, name: &str, value: &str) -> Result<String, AuthError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut data = value.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut data,
+            )
+            .map_err(|_| AuthError::EncryptionError(format!("failed to encrypt {}", name)))?;
+        Ok(format!(
+            "{}{}",
+            ENVELOPE_PREFIX,
+            BASE64_STANDARD.encode([nonce.as_slice(), &data].concat())
+        ))
+    }
+
+    /// Decrypt secret `name` from what [`MasterKey::seal`] made of it
+    pub fn open(&self, name: &str, sealed: &str) -> Result<String, AuthError> {
+        let unreadable = || {
+            AuthError::EncryptionError(format!(
+                "{} is corrupted or sealed with another master key",
+                name
+            ))
+        };
+        let envelope = sealed
+            .strip_prefix(ENVELOPE_PREFIX)
+            .ok_or_else(unreadable)?;
+        let mut data = BASE64_STANDARD.decode(envelope).map_err(|_| unreadable())?;
+        if data.len() <= NONCE_LEN {
+            return Err(unreadable());
+        }
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&data[..NONCE_LEN]);
+        let plaintext = self
+            .key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut data[NONCE_LEN..],
+            )
+            .map_err(|_| unreadable())?;
+        String::from_utf8(plaintext.to_vec()).map_err(|_| unreadable())
+    }
+}
+
+/// Whether a stored secret was sealed with a master key
+pub fn is_sealed(value: &str) -> bool {
+    value.starts_with(ENVELOPE_PREFIX)
+}
+
+/// An [`AuthStore`] whose secrets are sealed with the master key, if there
+/// is one
+pub struct SealedStore {
+    inner: Arc<dyn AuthStore>,
+    key: Option<MasterKey>,
+}
+
+impl SealedStore {
+    pub fn new(inner: Arc<dyn AuthStore>, key: Option<MasterKey>) -> Self {
+        Self { inner, key }
+    }
+
+    /// Names of the secrets still stored in plaintext
+    pub fn plaintext_secrets(&self) -> Result<Vec<String>, AuthError> {
+        let mut names: Vec<String> = self
+            .inner
+            .export()?
+            .secrets
+            .into_iter()
+            .filter(|(_, value)| !is_sealed(value))
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Seal every secret stored in plaintext, after checking the sealed ones
+    /// open with this key; returns the names of those sealed
+    pub fn rewrap(&self) -> Result<Vec<String>, AuthError> {
+        let key = self.key.as_ref().ok_or_else(missing_key)?;
+        let secrets = self.inner.export()?.secrets;
+        for (name, value) in secrets.iter().filter(|(_, value)| is_sealed(value)) {
+            key.open(name, value)?;
+        }
+        let mut sealed = Vec::new();
+        for (name, value) in secrets.iter().filter(|(_, value)| !is_sealed(value)) {
+            self.inner.set_secret(name, &key.seal(name, value)?)?;
+            sealed.push(name.clone());
+        }
+        sealed.sort();
+        Ok(sealed)
+    }
+}
+
+fn missing_key() -> AuthError {
+    AuthError::InvalidConfig(format!(
+        "the auth secrets are encrypted; set {} or {}",
+        MASTER_KEY_ENV, MASTER_KEY_FILE_ENV
+    ))
+}
+
+impl AuthStore for SealedStore {
+    fn users(&self) -> Result<Vec<User>, AuthError> {
+        self.inner.users()
+    }
+
+    fn insert_user(&self, user: &User, first_admin: bool) -> Result<(), AuthError> {
+        self.inner.insert_user(user, first_admin)
+    }
+
+    fn update_user(&self, user: &User) -> Result<(), AuthError> {
+        self.inner.update_user(user)
+    }
+
+    fn remove_user(&self, username: &str) -> Result<(), AuthError> {
+        self.inner.remove_user(username)
+    }
+
+    fn revoked_tokens(&self, now: i64) -> Result<HashMap<String, i64>, AuthError> {
+        self.inner.revoked_tokens(now)
+    }
+
+    fn revoke_token(&self, jti: &str, exp: i64, now: i64) -> Result<(), AuthError> {
+        self.inner.revoke_token(jti, exp, now)
+    }
+
+    fn token_generations(&self) -> Result<HashMap<String, u64>, AuthError> {
+        self.inner.token_generations()
+    }
+
+    fn bump_generation(&self, subject: &str) -> Result<u64, AuthError> {
+        self.inner.bump_generation(subject)
+    }
+
+    fn api_keys(&self) -> Result<Vec<ApiKey>, AuthError> {
+        self.inner.api_keys()
+    }
+
+    fn put_api_key(&self, key: &ApiKey) -> Result<(), AuthError> {
+        self.inner.put_api_key(key)
+    }
+
+    fn remove_api_key(&self, id: &str) -> Result<(), AuthError> {
+        self.inner.remove_api_key(id)
+    }
+
+    fn devices(&self) -> Result<Vec<Device>, AuthError> {
+        self.inner.devices()
+    }
+
+    fn put_device(&self, device: &Device) -> Result<(), AuthError> {
+        self.inner.put_device(device)
+    }
+
+    fn sessions(&self) -> Result<Vec<Session>, AuthError> {
+        self.inner.sessions()
+    }
+
+    fn session(&self, id: &str) -> Result<Option<Session>, AuthError> {
+        self.inner.session(id)
+    }
+
+    fn put_session(&self, session: &Session, now: i64) -> Result<(), AuthError> {
+        self.inner.put_session(session, now)
+    }
+
+    fn secret(&self, name: &str) -> Result<Option<String>, AuthError> {
+        match self.inner.secret(name)? {
+            Some(value) if is_sealed(&value) => {
+                let key = self.key.as_ref().ok_or_else(missing_key)?;
+                key.open(name, &value).map(Some)
+            }
+            // From before the master key, until rewrapped
+            value => Ok(value),
+        }
+    }
+
+    fn set_secret(&self, name: &str, value: &str) -> Result<(), AuthError> {
+        match &self.key {
+            Some(key) => self.inner.set_secret(name, &key.seal(name, value)?),
+            None => self.inner.set_secret(name, value),
+        }
+    }
+
+    /// Secrets as stored, sealed if they are
+    fn export(&self) -> Result<AuthSnapshot, AuthError> {
+        self.inner.export()
+    }
+
+    /// Seals the snapshot's plaintext secrets, if there is a master key
+    fn import(&self, snapshot: &AuthSnapshot) -> Result<(), AuthError> {
+        let Some(key) = &self.key else {
+            return self.inner.import(snapshot);
+        };
+        let mut snapshot = snapshot.clone();
+        for (name, value) in snapshot.secrets.iter_mut() {
+            if !is_sealed(value) {
+                *value = key.seal(name, value)?;
+            }
+        }
+        self.inner.import(&snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth_store::{MemoryAuthStore, JWT_SECRET};
+
+    fn key(byte: u8) -> MasterKey {
+        MasterKey::from_bytes(&[byte; KEY_LENGTH]).unwrap()
+    }
+
+    #[test]
+    fn test_seal_round_trip() {
+        let sealed = key(1).seal(JWT_SECRET, "root secret").unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.contains("root secret"));
+        assert_eq!(key(1).open(JWT_SECRET, &sealed).unwrap(), "root secret");
+        // Fresh nonce each time
+        assert_ne!(sealed, key(1).seal(JWT_SECRET, "root secret").unwrap());
+    }
+
+    #[test]
+    fn test_wrong_key_and_corruption_are_refused() {
+        let sealed = key(1).seal(JWT_SECRET, "root secret").unwrap();
+        let unreadable = |result: Result<String, AuthError>| {
+            assert!(
+                matches!(result, Err(AuthError::EncryptionError(_))),
+                "{:?}",
+                result
+            )
+        };
+        unreadable(key(2).open(JWT_SECRET, &sealed));
+        // Sealed under another name
+        unreadable(key(1).open("audit_ip_key", &sealed));
+
+        let mut data = BASE64_STANDARD
+            .decode(sealed.strip_prefix(ENVELOPE_PREFIX).unwrap())
+            .unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        let flipped = format!("{}{}", ENVELOPE_PREFIX, BASE64_STANDARD.encode(&data));
+        unreadable(key(1).open(JWT_SECRET, &flipped));
+        unreadable(key(1).open(JWT_SECRET, &sealed[..ENVELOPE_PREFIX.len() + 8]));
+        unreadable(key(1).open(JWT_SECRET, "cpmk1:not base64!"));
+    }
+
+    #[test]
+    fn test_key_must_be_32_base64_bytes() {
+        MasterKey::parse(&BASE64_STANDARD.encode([7u8; KEY_LENGTH])).unwrap();
+        for bad in ["not base64!", &BASE64_STANDARD.encode([7u8; 16])] {
+            assert!(matches!(
+                MasterKey::parse(bad),
+                Err(AuthError::InvalidConfig(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_sealed_store() {
+        let inner: Arc<dyn AuthStore> = Arc::new(MemoryAuthStore::default());
+        inner.set_secret("audit_ip_key", "from before").unwrap();
+        let store = SealedStore::new(inner.clone(), Some(key(1)));
+
+        store.set_secret(JWT_SECRET, "root secret").unwrap();
+        assert!(is_sealed(&inner.secret(JWT_SECRET).unwrap().unwrap()));
+        assert_eq!(store.secret(JWT_SECRET).unwrap().unwrap(), "root secret");
+        // Plaintext from before the key still reads, until rewrapped
+        assert_eq!(
+            store.secret("audit_ip_key").unwrap().unwrap(),
+            "from before"
+        );
+        assert_eq!(store.plaintext_secrets().unwrap(), vec!["audit_ip_key"]);
+        assert_eq!(store.rewrap().unwrap(), vec!["audit_ip_key"]);
+        assert!(store.plaintext_secrets().unwrap().is_empty());
+        assert_eq!(
+            store.secret("audit_ip_key").unwrap().unwrap(),
+            "from before"
+        );
+
+        // Without the key, or with another, sealed secrets don't load
+        let keyless = SealedStore::new(inner.clone(), None);
+        assert!(matches!(
+            keyless.secret(JWT_SECRET),
+            Err(AuthError::InvalidConfig(_))
+        ));
+        let wrong = SealedStore::new(inner.clone(), Some(key(2)));
+        assert!(matches!(
+            wrong.secret(JWT_SECRET),
+            Err(AuthError::EncryptionError(_))
+        ));
+        assert!(wrong.rewrap().is_err());
+
+        // Exports stay sealed; importing a plaintext snapshot seals it
+        let export = store.export().unwrap();
+        assert!(export.secrets.values().all(|v| is_sealed(v)));
+        let mut plain = AuthSnapshot::default();
+        plain
+            .secrets
+            .insert(JWT_SECRET.to_string(), "imported".to_string());
+        store.import(&plain).unwrap();
+        assert!(is_sealed(&inner.secret(JWT_SECRET).unwrap().unwrap()));
+        assert_eq!(store.secret(JWT_SECRET).unwrap().unwrap(), "imported");
+    }
+
+    #[test]
+    fn test_key_file_must_be_outside_the_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master.key");
+        std::fs::write(&path, BASE64_STANDARD.encode([7u8; KEY_LENGTH])).unwrap();
+        let path = Some(path.to_string_lossy().into_owned());
+        assert!(matches!(
+            MasterKey::load(None, path.clone(), dir.path()),
+            Err(AuthError::InvalidConfig(_))
+        ));
+        std::fs::create_dir(dir.path().join("data")).unwrap();
+        assert!(MasterKey::load(None, path, &dir.path().join("data"))
+            .unwrap()
+            .is_some());
+        assert!(MasterKey::load(None, None, dir.path()).unwrap().is_none());
+    }
+}
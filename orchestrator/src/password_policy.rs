@@ -0,0 +1,251 @@
+//! Password strength policy
+//!
+//! Every new password - first-run registration, password changes, accounts
+//! created by admins and the `--set-password` CLI - is checked against:
+//! - a minimum length, `PASSWORD_MIN_LENGTH` (default 12)
+//! - the 10,000 most common passwords, embedded gzip-compressed from
+//!   `common_passwords.txt.gz` and inflated into a set on first use
+//! - the product's own names, `clawpen` and `admin`, anywhere in the password
+//! - a rough entropy estimate, `PASSWORD_MIN_ENTROPY_BITS` (default 50)
+//!
+//! All failed rules are reported together so a client can show them at once.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+use serde::Serialize;
+
+/// Common passwords, gzipped, one per line, lowercase
+const COMMON_PASSWORDS_GZ: &[u8] = include_bytes!("common_passwords.txt.gz");
+
+/// Words a password may not contain, in any case
+const FORBIDDEN_WORDS: &[&str] = &["clawpen", "admin"];
+
+static COMMON: once_cell::sync::Lazy<HashSet<String>> = once_cell::sync::Lazy::new(|| {
+    let mut list = String::new();
+    GzDecoder::new(COMMON_PASSWORDS_GZ)
+        .read_to_string(&mut list)
+        .expect("embedded common password list is valid gzip");
+    list.lines().map(str::to_string).collect()
+});
+
+fn is_common(password: &str) -> bool {
+    COMMON.contains(&password.to_lowercase())
+}
+
+/// Estimated bits of entropy: the size of the character pool in use times
+/// the number of characters, not counting repeats and runs like `abc`/`321`
+pub fn entropy_bits(password: &str) -> f64 {
+    let (mut lower, mut upper, mut digit, mut symbol, mut other) =
+        (false, false, false, false, false);
+    let mut effective = 0usize;
+    let mut prev: Option<char> = None;
+    for c in password.chars() {
+        match c {
+            'a'..='z' => lower = true,
+            'A'..='Z' => upper = true,
+            '0'..='9' => digit = true,
+            c if c.is_ascii() => symbol = true,
+            _ => other = true,
+        }
+        let predictable = prev.is_some_and(|p| (c as i64 - p as i64).abs() <= 1);
+        if !predictable {
+            effective += 1;
+        }
+        prev = Some(c);
+    }
+
+    let pool = [
+        (lower, 26),
+        (upper, 26),
+        (digit, 10),
+        (symbol, 33),
+        (other, 100),
+    ]
+    .iter()
+    .filter(|(used, _)| *used)
+    .map(|(_, size)| *size)
+    .sum::<u32>();
+    if pool == 0 {
+        return 0.0;
+    }
+    effective as f64 * f64::from(pool).log2()
+}
+
+/// A rule a password failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum PasswordViolation {
+    TooShort { min_length: usize },
+    Common,
+    ContainsForbiddenWord { word: &'static str },
+    TooPredictable { bits: f64, min_bits: u32 },
+}
+
+impl PasswordViolation {
+    /// Stable name of the rule, for clients
+    pub fn rule(&self) -> &'static str {
+        match self {
+            PasswordViolation::TooShort { .. } => "min_length",
+            PasswordViolation::Common => "common_password",
+            PasswordViolation::ContainsForbiddenWord { .. } => "forbidden_word",
+            PasswordViolation::TooPredictable { .. } => "min_entropy",
+        }
+    }
+}
+
+impl fmt::Display for PasswordViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordViolation::TooShort { min_length } => {
+                write!(f, "Password must be at least {} characters", min_length)
+            }
+            PasswordViolation::Common => write!(f, "Password is too common"),
+            PasswordViolation::ContainsForbiddenWord { word } => {
+                write!(f, "Password must not contain \"{}\"", word)
+            }
+            PasswordViolation::TooPredictable { bits, min_bits } => write!(
+                f,
+                "Password is too predictable (about {:.0} bits, need {})",
+                bits, min_bits
+            ),
+        }
+    }
+}
+
+impl Serialize for PasswordViolation {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut s = serializer.serialize_struct("PasswordViolation", 2)?;
+        s.serialize_field("rule", self.rule())?;
+        s.serialize_field("message", &self.to_string())?;
+        s.end()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub min_entropy_bits: u32,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 12,
+            min_entropy_bits: 50,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Check a new password, returning every rule it fails
+    pub fn check(&self, password: &str) -> Result<(), Vec<PasswordViolation>> {
+        let mut violations = Vec::new();
+        if password.chars().count() < self.min_length {
+            violations.push(PasswordViolation::TooShort {
+                min_length: self.min_length,
+            });
+        }
+        if is_common(password) {
+            violations.push(PasswordViolation::Common);
+        }
+        let lower = password.to_lowercase();
+        for word in FORBIDDEN_WORDS {
+            if lower.contains(word) {
+                violations.push(PasswordViolation::ContainsForbiddenWord { word });
+            }
+        }
+        let bits = entropy_bits(password);
+        if bits < f64::from(self.min_entropy_bits) {
+            violations.push(PasswordViolation::TooPredictable {
+                bits,
+                min_bits: self.min_entropy_bits,
+            });
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(password: &str) -> Vec<&'static str> {
+        match PasswordPolicy::default().check(password) {
+            Ok(()) => Vec::new(),
+            Err(violations) => violations.iter().map(PasswordViolation::rule).collect(),
+        }
+    }
+
+    #[test]
+    fn test_common_list_inflates_to_lowercase_set() {
+        assert_eq!(COMMON.len(), 10_000);
+        assert!(COMMON.iter().all(|p| *p == p.to_lowercase()));
+        assert!(COMMON.contains("123456"));
+        assert!(COMMON.contains("password"));
+    }
+
+    #[test]
+    fn test_strong_passwords_pass() {
+        assert!(rules("correct horse").is_empty());
+        assert!(rules("battery staple").is_empty());
+        assert!(rules("Tr0ub4dor&3 xylophone").is_empty());
+    }
+
+    #[test]
+    fn test_short_password_fails_length() {
+        assert!(rules("short").contains(&"min_length"));
+        assert!(rules("aB3$eF7*hJ1").contains(&"min_length"));
+    }
+
+    #[test]
+    fn test_common_password_rejected_in_any_case() {
+        assert!(rules("qwertyuiop123").contains(&"common_password"));
+        assert!(rules("Password123456").contains(&"common_password"));
+        assert!(rules("correct horse").is_empty());
+    }
+
+    #[test]
+    fn test_forbidden_words_rejected() {
+        assert_eq!(rules("my ClawPen server key"), vec!["forbidden_word"]);
+        assert_eq!(rules("not the admin's password"), vec!["forbidden_word"]);
+    }
+
+    #[test]
+    fn test_repeats_and_runs_are_predictable() {
+        assert!(rules("aaaaaaaaaaaaaaaa").contains(&"min_entropy"));
+        assert!(rules("abcdefghijklmnop").contains(&"min_entropy"));
+        assert!(rules("987654321098").contains(&"min_entropy"));
+        assert!(entropy_bits("zq8vk3mw9xpt") > entropy_bits("abcdefghijkl"));
+    }
+
+    #[test]
+    fn test_all_failures_reported_together() {
+        assert_eq!(
+            rules("admin"),
+            vec![
+                "min_length",
+                "common_password",
+                "forbidden_word",
+                "min_entropy"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_policy_parameters_apply() {
+        let policy = PasswordPolicy {
+            min_length: 20,
+            min_entropy_bits: 0,
+        };
+        assert!(policy.check("correct horse").is_err());
+        assert!(policy.check("correct horse battery staple").is_ok());
+    }
+}
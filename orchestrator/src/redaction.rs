@@ -0,0 +1,260 @@
+//! Scrubbing known secret values out of messages
+//!
+//! Masking paths and IDs misses an error that embeds a secret itself, like
+//! an API key that failed to parse. The active redactors hold every stored
+//! agent secret, every provider API key and every process env var with a
+//! sensitive name, and replace each value, and its base64 and URL-encoded
+//! forms, with `[SECRET:<name>]`. Values shorter than [`MIN_SECRET_LENGTH`]
+//! are left out so ordinary words aren't mangled.
+//!
+//! [`crate::validation::sanitize_error_message`] runs them on every client
+//! error, and logs go through it via [`RedactingWriter`]. The secrets manager
+//! and the provider key handlers rebuild theirs with [`install`] whenever a
+//! value changes.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, RwLock};
+
+use aho_corasick::{AhoCorasick, MatchKind};
+use base64::Engine;
+use once_cell::sync::Lazy;
+
+/// Shortest value worth redacting, in characters
+pub const MIN_SECRET_LENGTH: usize = 6;
+
+/// Replaces a fixed set of secret values in one pass
+#[derive(Debug, Default)]
+pub struct Redactor {
+    matcher: Option<AhoCorasick>,
+    replacements: Vec<String>,
+}
+
+impl Redactor {
+    /// A redactor for `(name, value)` pairs
+    pub fn new<'a>(secrets: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut patterns: Vec<String> = Vec::new();
+        let mut replacements = Vec::new();
+        for (name, value) in secrets {
+            if value.chars().count() < MIN_SECRET_LENGTH {
+                continue;
+            }
+            for form in encodings(value) {
+                if !patterns.contains(&form) {
+                    patterns.push(form);
+                    replacements.push(format!("[SECRET:{}]", name));
+                }
+            }
+        }
+        if patterns.is_empty() {
+            return Self::default();
+        }
+        // Longest first, so a value containing another is replaced whole
+        let matcher = AhoCorasick::builder()
+            .match_kind(MatchKind::LeftmostLongest)
+            .build(&patterns)
+            .ok();
+        Self {
+            matcher,
+            replacements,
+        }
+    }
+
+    pub fn redact<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        match self.matcher {
+            Some(ref matcher) if matcher.is_match(text) => {
+                Cow::Owned(matcher.replace_all(text, &self.replacements))
+            }
+            _ => Cow::Borrowed(text),
+        }
+    }
+}
+
+/// A value as written, in base64 and URL-encoded
+fn encodings(value: &str) -> Vec<String> {
+    let engines = [
+        base64::engine::general_purpose::STANDARD,
+        base64::engine::general_purpose::URL_SAFE_NO_PAD,
+    ];
+    let mut forms = vec![value.to_string()];
+    forms.extend(engines.iter().map(|e| e.encode(value)));
+    forms.push(url_encode(value));
+    forms
+}
+
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Whether an env var named `name` probably holds a secret
+pub fn is_sensitive_name(name: &str) -> bool {
+    let name = name.to_uppercase();
+    ["KEY", "TOKEN", "SECRET", "PASSWORD", "PASSWD", "CREDENTIAL"]
+        .iter()
+        .any(|word| name.contains(word))
+}
+
+/// Process env vars with sensitive names, as `(name, value)` pairs
+pub fn sensitive_env() -> Vec<(String, String)> {
+    std::env::vars()
+        .filter(|(name, _)| is_sensitive_name(name))
+        .collect()
+}
+
+/// The active redactors, by who installed them
+static ACTIVE: Lazy<RwLock<HashMap<&'static str, Arc<Redactor>>>> = Lazy::new(Default::default);
+
+/// Make `redactor` the one [`redact`] uses for `source`'s values
+pub fn install(source: &'static str, redactor: Redactor) {
+    ACTIVE
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(source, Arc::new(redactor));
+}
+
+/// Replace known secret values in `text` with the active redactors
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let redactors: Vec<Arc<Redactor>> = ACTIVE
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .values()
+        .cloned()
+        .collect();
+    let mut text = Cow::Borrowed(text);
+    for redactor in redactors {
+        if let Cow::Owned(redacted) = redactor.redact(&text) {
+            text = Cow::Owned(redacted);
+        }
+    }
+    text
+}
+
+/// A log writer that redacts what passes through it
+///
+/// The fmt layer writes each event in one call, so a value isn't split
+/// across writes.
+pub struct RedactingWriter<W>(pub W);
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match std::str::from_utf8(buf) {
+            Ok(text) => self.0.write_all(redact(text).as_bytes())?,
+            Err(_) => self.0.write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+
+    const LOG_SECRET: &str = "log-writer-secret-7788";
+    const CHAIN_SECRET: &str = "chain-secret-9f8e7d6c";
+
+    /// The same for every test, as the active redactors are global
+    fn install_test_redactor() {
+        install(
+            "tests",
+            Redactor::new([
+                ("LOG_TEST_KEY", LOG_SECRET),
+                ("CHAIN_TEST_KEY", CHAIN_SECRET),
+            ]),
+        );
+    }
+
+    #[test]
+    fn test_values_and_their_encodings_are_replaced() {
+        let redactor = Redactor::new([
+            ("OPENAI_API_KEY", "sk-live-abc123/def+456"),
+            ("SHORT", "pin12"),
+        ]);
+        let value = "sk-live-abc123/def+456";
+        let base64 = base64::engine::general_purpose::STANDARD.encode(value);
+        let url_safe = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(value);
+        for text in [
+            format!("invalid key {}: bad format", value),
+            format!("header was Basic {}", base64),
+            format!("token={}", url_safe),
+            "GET /v1?key=sk-live-abc123%2Fdef%2B456".to_string(),
+        ] {
+            let redacted = redactor.redact(&text);
+            assert!(redacted.contains("[SECRET:OPENAI_API_KEY]"), "{}", redacted);
+            assert!(!redacted.contains("abc123"), "{}", redacted);
+        }
+
+        // Short values would mangle normal text
+        assert_eq!(redactor.redact("pin12 ok"), "pin12 ok");
+        assert!(matches!(redactor.redact("nothing here"), Cow::Borrowed(_)));
+        assert_eq!(Redactor::default().redact("x"), "x");
+    }
+
+    #[test]
+    fn test_the_longest_value_wins() {
+        let redactor = Redactor::new([("INNER", "secret-value"), ("OUTER", "secret-value-2")]);
+        assert_eq!(redactor.redact("got secret-value-2"), "got [SECRET:OUTER]");
+        assert_eq!(redactor.redact("got secret-value"), "got [SECRET:INNER]");
+    }
+
+    #[test]
+    fn test_sensitive_names() {
+        for name in [
+            "OPENAI_API_KEY",
+            "github_token",
+            "DB_PASSWORD",
+            "AWS_SECRET_ACCESS_KEY",
+        ] {
+            assert!(is_sensitive_name(name), "{}", name);
+        }
+        for name in ["PATH", "HOME", "RUST_LOG"] {
+            assert!(!is_sensitive_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_writer_redacts_log_lines() {
+        install_test_redactor();
+        let mut writer = RedactingWriter(Vec::new());
+        let line = format!("WARN failed with {}\n", LOG_SECRET).into_bytes();
+        assert_eq!(writer.write(&line).unwrap(), line.len());
+        assert_eq!(
+            String::from_utf8(writer.0).unwrap(),
+            "WARN failed with [SECRET:LOG_TEST_KEY]\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_secrets_in_error_chains_never_reach_the_response() {
+        let value = CHAIN_SECRET;
+        install_test_redactor();
+        let error = anyhow::anyhow!("could not parse '{}'", value)
+            .context("loading provider credentials")
+            .context("creating agent");
+        let response = (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            crate::validation::sanitize_error_message(&format!("{:#}", error)),
+        )
+            .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.starts_with("creating agent: loading provider credentials"));
+        assert!(body.contains("[SECRET:CHAIN_TEST_KEY]"));
+        assert!(!body.contains(value));
+    }
+}
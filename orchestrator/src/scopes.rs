@@ -0,0 +1,133 @@
+//! Permission scopes
+//!
+//! Each route group behind the auth middleware needs one scope, like
+//! `agents.read` or `agents.write`. Tokens carry the scopes of their account's
+//! role; an API key can be limited to a subset of its role's. Tokens from
+//! before scopes existed carry none and get their role's.
+
+use serde::{Deserialize, Serialize};
+
+use crate::users::Role;
+
+pub const AGENTS_READ: &str = "agents.read";
+pub const AGENTS_WRITE: &str = "agents.write";
+pub const SECRETS_READ: &str = "secrets.read";
+pub const SECRETS_WRITE: &str = "secrets.write";
+pub const KEYS_READ: &str = "keys.read";
+pub const KEYS_WRITE: &str = "keys.write";
+pub const PROJECTS_READ: &str = "projects.read";
+pub const PROJECTS_WRITE: &str = "projects.write";
+pub const TEAMS_READ: &str = "teams.read";
+pub const TEAMS_WRITE: &str = "teams.write";
+pub const SYSTEM_READ: &str = "system.read";
+pub const AUTH_ADMIN: &str = "auth.admin";
+pub const AUTH_INTROSPECT: &str = "auth.introspect";
+pub const AGENT_SELF_READ: &str = "agent.self.read";
+pub const AGENT_SELF_REPORT: &str = "agent.self.report";
+
+/// Every scope with what it allows
+pub const SCOPES: &[(&str, &str)] = &[
+    (
+        AGENTS_READ,
+        "List agents and read their logs, metrics and snapshots",
+    ),
+    (
+        AGENTS_WRITE,
+        "Create, change, start, stop, delete and chat with agents",
+    ),
+    (SECRETS_READ, "List agent secrets"),
+    (SECRETS_WRITE, "Set and delete agent secrets"),
+    (KEYS_READ, "List provider API keys"),
+    (KEYS_WRITE, "Set and delete provider API keys"),
+    (PROJECTS_READ, "List projects"),
+    (PROJECTS_WRITE, "Create projects"),
+    (TEAMS_READ, "List teams"),
+    (TEAMS_WRITE, "Route messages to teams"),
+    (
+        SYSTEM_READ,
+        "Read system stats, metrics, templates, runtime status and validation rules",
+    ),
+    (
+        AUTH_ADMIN,
+        "Manage accounts, API keys, signing keys and read the audit log",
+    ),
+    (
+        AUTH_INTROSPECT,
+        "Check other clients' tokens at /auth/introspect",
+    ),
+];
+
+/// Scopes only agents' service tokens carry, for the agent API; no role or
+/// API key gets them
+pub const SERVICE_SCOPES: &[(&str, &str)] = &[
+    (AGENT_SELF_READ, "Read the agent's own record and secrets"),
+    (AGENT_SELF_REPORT, "Report the agent's own health"),
+];
+
+/// A scope as listed by `GET /auth/status`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScopeInfo {
+    pub name: String,
+    pub description: String,
+}
+
+pub fn list() -> Vec<ScopeInfo> {
+    SCOPES
+        .iter()
+        .map(|(name, description)| ScopeInfo {
+            name: name.to_string(),
+            description: description.to_string(),
+        })
+        .collect()
+}
+
+pub fn is_known(scope: &str) -> bool {
+    SCOPES.iter().any(|(name, _)| *name == scope)
+}
+
+pub fn is_service_scope(scope: &str) -> bool {
+    SERVICE_SCOPES.iter().any(|(name, _)| *name == scope)
+}
+
+/// Scopes tokens of `role` get: viewers read, operators also write, admins
+/// also manage auth and introspect tokens
+pub fn for_role(role: Role) -> Vec<&'static str> {
+    SCOPES
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| match role {
+            Role::Viewer => name.ends_with(".read"),
+            Role::Operator => *name != AUTH_ADMIN && *name != AUTH_INTROSPECT,
+            Role::Admin => true,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles_widen() {
+        let viewer = for_role(Role::Viewer);
+        let operator = for_role(Role::Operator);
+        let admin = for_role(Role::Admin);
+        assert!(viewer.contains(&AGENTS_READ) && !viewer.contains(&AGENTS_WRITE));
+        assert!(operator.contains(&AGENTS_WRITE) && !operator.contains(&AUTH_ADMIN));
+        assert!(!operator.contains(&AUTH_INTROSPECT) && admin.contains(&AUTH_INTROSPECT));
+        assert_eq!(admin.len(), SCOPES.len());
+        assert!(viewer.iter().all(|s| operator.contains(s)));
+        assert!(operator.iter().all(|s| admin.contains(s)));
+    }
+
+    #[test]
+    fn test_vocabulary() {
+        assert!(is_known("agents.write"));
+        assert!(!is_known("agents.*"));
+        // API keys can't be given the agents' scopes
+        assert!(!is_known(AGENT_SELF_READ) && is_service_scope(AGENT_SELF_READ));
+        assert!(!is_service_scope(AGENTS_READ));
+        assert_eq!(list().len(), SCOPES.len());
+        assert_eq!(list()[0].name, AGENTS_READ);
+    }
+}
@@ -4,7 +4,9 @@ use anyhow::Result;
 use std::collections::HashMap;
 use std::path::PathBuf;
 
+use crate::redaction;
 use crate::types::SecretInfo;
+use crate::validation::{self, PathLimits, ValidationError};
 
 pub struct SecretsManager {
     base_path: PathBuf,
@@ -30,6 +32,16 @@ impl SecretsManager {
         self.base_path.join(agent_id)
     }
 
+    /// Where secret `name` of agent `agent_id` is kept, once both are
+    /// checked to make a file right under the agent's directory
+    fn secret_path(&self, agent_id: &str, name: &str) -> Result<PathBuf> {
+        validation::validate_agent_id_compat(agent_id)?;
+        validation::validate_secret_name(name)?;
+        let path = self.agent_path(agent_id).join(name);
+        PathLimits::default().check("name", "Secret path", &path.to_string_lossy())?;
+        Ok(path)
+    }
+
     pub async fn list_secrets(&self, agent_id: &str) -> Result<Vec<SecretInfo>> {
         let agent_dir = self.agent_path(agent_id);
         let mut secrets = Vec::new();
@@ -76,10 +88,8 @@ impl SecretsManager {
     }
 
     pub async fn set_secret(&self, agent_id: &str, name: &str, value: &str) -> Result<()> {
-        let agent_dir = self.agent_path(agent_id);
-        std::fs::create_dir_all(&agent_dir)?;
-
-        let secret_path = agent_dir.join(name);
+        let secret_path = self.secret_path(agent_id, name)?;
+        std::fs::create_dir_all(self.agent_path(agent_id))?;
 
         // Write with restricted permissions (0600)
         #[cfg(unix)]
@@ -100,15 +110,17 @@ impl SecretsManager {
             std::fs::write(&secret_path, value)?;
         }
 
+        self.refresh_redaction();
         tracing::info!("Set secret '{}' for agent {}", name, agent_id);
         Ok(())
     }
 
     pub async fn delete_secret(&self, agent_id: &str, name: &str) -> Result<()> {
-        let secret_path = self.agent_path(agent_id).join(name);
+        let secret_path = self.secret_path(agent_id, name)?;
 
         if secret_path.exists() {
             std::fs::remove_file(&secret_path)?;
+            self.refresh_redaction();
             tracing::info!("Deleted secret '{}' for agent {}", name, agent_id);
         }
 
@@ -116,7 +128,7 @@ impl SecretsManager {
     }
 
     pub async fn get_secret(&self, agent_id: &str, name: &str) -> Result<Option<String>> {
-        let secret_path = self.agent_path(agent_id).join(name);
+        let secret_path = self.secret_path(agent_id, name)?;
 
         if secret_path.exists() {
             let value = std::fs::read_to_string(&secret_path)?;
@@ -132,14 +144,53 @@ impl SecretsManager {
         let mut secrets = HashMap::new();
 
         for info in infos {
-            if let Some(value) = self.get_secret(agent_id, &info.name).await? {
-                secrets.insert(info.name, value);
+            match self.get_secret(agent_id, &info.name).await {
+                Ok(Some(value)) => {
+                    secrets.insert(info.name, value);
+                }
+                Ok(None) => {}
+                // Stored before names were checked
+                Err(e) if e.is::<ValidationError>() => {
+                    tracing::warn!(
+                        "Skipping secret '{}' of agent {}: {}",
+                        info.name,
+                        agent_id,
+                        e
+                    );
+                }
+                Err(e) => return Err(e),
             }
         }
 
         Ok(secrets)
     }
 
+    /// Rebuild the active redactor from every stored secret and the
+    /// sensitive env vars
+    pub fn refresh_redaction(&self) {
+        let mut secrets = redaction::sensitive_env();
+        let agent_dirs = std::fs::read_dir(&self.base_path)
+            .into_iter()
+            .flatten()
+            .flatten();
+        for agent_dir in agent_dirs {
+            let files = std::fs::read_dir(agent_dir.path())
+                .into_iter()
+                .flatten()
+                .flatten();
+            for file in files {
+                let name = file.file_name().to_string_lossy().to_string();
+                if let Ok(value) = std::fs::read_to_string(file.path()) {
+                    secrets.push((name, value));
+                }
+            }
+        }
+        redaction::install(
+            "secrets",
+            redaction::Redactor::new(secrets.iter().map(|(n, v)| (n.as_str(), v.as_str()))),
+        );
+    }
+
     /// Get mount path for secrets (used by container runtime)
     pub fn mount_path(&self) -> PathBuf {
         PathBuf::from("/run/secrets")
@@ -0,0 +1,99 @@
+//! Service tokens for agents
+//!
+//! Agents call back into the orchestrator to read their own record and
+//! secrets and to report their health. Rather than a person's token, each
+//! gets a service token: a JWT of type `service` with subject
+//! `agent:<id>`, the agent's id as `agent`, and only the scopes in
+//! [`SERVICE_SCOPES`]. [`AuthManager::mint_service_token`] signs one each
+//! time an agent starts; it goes into the agent's secrets as
+//! [`AGENT_TOKEN_SECRET`] and lasts `AGENT_TOKEN_TTL_SECS` (default a day).
+//! Stopping or deleting the agent ends its lease: the subject's token
+//! generation is bumped, so every token it was given stops working.
+//!
+//! Service tokens only work on the agent API under `/api/agent/self`, behind
+//! [`agent_middleware`], which takes nothing else and hands handlers the
+//! [`AgentIdentity`]. Everywhere else they get `403 SERVICE_TOKEN_NOT_ALLOWED`.
+//!
+//! [`SERVICE_SCOPES`]: crate::scopes::SERVICE_SCOPES
+//! [`AuthManager::mint_service_token`]: crate::auth::AuthManager::mint_service_token
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+
+use crate::auth::AuthError;
+use crate::AppState;
+
+/// `type` of a service token
+pub const SERVICE_TOKEN_TYPE: &str = "service";
+
+/// Name of the agent secret holding its service token
+pub const AGENT_TOKEN_SECRET: &str = "CLAW_PEN_AGENT_TOKEN";
+
+/// Service token lifetime when `AGENT_TOKEN_TTL_SECS` is unset
+pub const DEFAULT_TTL_SECS: i64 = 24 * 3600;
+
+/// Longest lifetime a service token may have
+pub const MAX_TTL_SECS: i64 = 30 * 24 * 3600;
+
+/// Subject of an agent's service tokens; usernames can't contain `:`
+pub fn subject(agent_id: &str) -> String {
+    format!("agent:{}", agent_id)
+}
+
+/// Lifetime of the service tokens minted at launch
+pub fn ttl_from_env() -> i64 {
+    std::env::var("AGENT_TOKEN_TTL_SECS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_TTL_SECS)
+        .clamp(60, MAX_TTL_SECS)
+}
+
+/// The agent calling the agent API, as a handler argument
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentIdentity {
+    pub agent_id: String,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AgentIdentity {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<AgentIdentity>()
+            .cloned()
+            .ok_or(AuthError::MissingToken)
+    }
+}
+
+/// Auth middleware of the agent API: takes only service tokens, and stores
+/// their claims and [`AgentIdentity`] for the scope checks and handlers
+pub async fn agent_middleware(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .ok_or(AuthError::MissingToken)?
+        .strip_prefix("Bearer ")
+        .ok_or(AuthError::InvalidAuthHeaderFormat)?;
+    let claims = state.auth.read().await.validate_token(token)?;
+    let agent_id = match (&claims.agent_id, claims.token_type.as_str()) {
+        (Some(agent_id), SERVICE_TOKEN_TYPE) => agent_id.clone(),
+        _ => return Err(AuthError::Forbidden),
+    };
+    request.extensions_mut().insert(AgentIdentity { agent_id });
+    request.extensions_mut().insert(claims);
+    Ok(next.run(request).await)
+}
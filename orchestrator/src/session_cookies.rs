@@ -0,0 +1,241 @@
+//! Cookie sessions for the web UI
+//!
+//! A token in `localStorage` can be read by any script that runs on the page.
+//! Browsers can log in with `POST /auth/login?cookie=true` instead, which puts
+//! the tokens in cookies scripts can't read:
+//! - `claw_pen_session` - the access token, sent with every request
+//! - `claw_pen_refresh` - the refresh token, only sent to `/api/auth/refresh`
+//! - `claw_pen_csrf` - a random CSRF token, readable by the page
+//!
+//! All are `Secure` and `SameSite=Lax`. The auth middleware accepts the
+//! session cookie when there is no `Authorization` header. Since the browser
+//! sends cookies whoever made the request, requests other than `GET`, `HEAD`
+//! and `OPTIONS` authenticated by cookie must also send the CSRF token in
+//! `X-CSRF-Token` (double submit): another site can make the browser send
+//! the cookie, but can't read it to copy it into the header.
+//!
+//! `Authorization: Bearer` works as before, and ignores the cookies.
+
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method};
+use axum::response::AppendHeaders;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::auth::{AuthError, TokenResponse};
+
+pub const SESSION_COOKIE: &str = "claw_pen_session";
+pub const REFRESH_COOKIE: &str = "claw_pen_refresh";
+pub const CSRF_COOKIE: &str = "claw_pen_csrf";
+
+/// Header carrying the CSRF token
+pub const CSRF_HEADER: HeaderName = HeaderName::from_static("x-csrf-token");
+
+/// The only path the refresh cookie is sent to
+pub const REFRESH_PATH: &str = "/api/auth/refresh";
+
+/// Random bytes in a CSRF token
+const CSRF_TOKEN_LENGTH: usize = 32;
+
+/// `?cookie=true` on the login endpoints
+#[derive(Debug, Default, Deserialize)]
+pub struct CookieOption {
+    #[serde(default)]
+    pub cookie: bool,
+}
+
+/// Response body of a cookie login; the tokens are only in the cookies
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CookieSession {
+    /// `Cookie`
+    pub token_type: String,
+    pub expires_in: i64,
+    /// Send back in `X-CSRF-Token`; also in the `claw_pen_csrf` cookie
+    pub csrf_token: String,
+}
+
+/// `Set-Cookie` headers for a response
+pub type SetCookies = AppendHeaders<[(HeaderName, HeaderValue); 3]>;
+
+/// Value of cookie `name` in the request's `Cookie` headers
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then_some(value)
+        })
+        .filter(|value| !value.is_empty())
+}
+
+/// Fail unless a request authenticated by cookie is safe or carries the
+/// CSRF cookie's value in `X-CSRF-Token`
+pub fn check_csrf(method: &Method, headers: &HeaderMap) -> Result<(), AuthError> {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(());
+    }
+    let presented = headers.get(CSRF_HEADER).and_then(|v| v.to_str().ok());
+    match (presented, cookie(headers, CSRF_COOKIE)) {
+        // Compare digests so the time taken says nothing about the token
+        (Some(presented), Some(expected))
+            if Sha256::digest(presented.as_bytes()) == Sha256::digest(expected.as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => Err(AuthError::InvalidCsrfToken),
+    }
+}
+
+fn set_cookie(name: &str, value: &str, path: &str, max_age: i64, http_only: bool) -> HeaderValue {
+    let mut cookie = format!(
+        "{}={}; Path={}; Max-Age={}; Secure; SameSite=Lax",
+        name, value, path, max_age
+    );
+    if http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    HeaderValue::from_str(&cookie).expect("tokens are valid header values")
+}
+
+/// Cookies holding `tokens` and a new CSRF token, and the response body
+pub fn start(tokens: &TokenResponse, refresh_ttl_secs: i64) -> (SetCookies, CookieSession) {
+    let mut bytes = [0u8; CSRF_TOKEN_LENGTH];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let csrf_token = URL_SAFE_NO_PAD.encode(bytes);
+    let cookies = AppendHeaders([
+        (
+            header::SET_COOKIE,
+            set_cookie(
+                SESSION_COOKIE,
+                &tokens.access_token,
+                "/",
+                tokens.expires_in,
+                true,
+            ),
+        ),
+        (
+            header::SET_COOKIE,
+            set_cookie(
+                REFRESH_COOKIE,
+                &tokens.refresh_token,
+                REFRESH_PATH,
+                refresh_ttl_secs,
+                true,
+            ),
+        ),
+        (
+            header::SET_COOKIE,
+            set_cookie(CSRF_COOKIE, &csrf_token, "/", refresh_ttl_secs, false),
+        ),
+    ]);
+    let session = CookieSession {
+        token_type: "Cookie".to_string(),
+        expires_in: tokens.expires_in,
+        csrf_token,
+    };
+    (cookies, session)
+}
+
+/// Cookies that delete all three
+pub fn clear() -> SetCookies {
+    AppendHeaders([
+        (
+            header::SET_COOKIE,
+            set_cookie(SESSION_COOKIE, "", "/", 0, true),
+        ),
+        (
+            header::SET_COOKIE,
+            set_cookie(REFRESH_COOKIE, "", REFRESH_PATH, 0, true),
+        ),
+        (
+            header::SET_COOKIE,
+            set_cookie(CSRF_COOKIE, "", "/", 0, false),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(HeaderName, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(name.clone(), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_reads_cookies_from_every_header() {
+        let headers = headers(&[
+            (header::COOKIE, "theme=dark; claw_pen_session=abc.def"),
+            (header::COOKIE, "claw_pen_csrf=xyz"),
+        ]);
+        assert_eq!(cookie(&headers, SESSION_COOKIE), Some("abc.def"));
+        assert_eq!(cookie(&headers, CSRF_COOKIE), Some("xyz"));
+        assert_eq!(cookie(&headers, REFRESH_COOKIE), None);
+        // Only whole names match
+        assert_eq!(cookie(&headers, "pen_session"), None);
+    }
+
+    #[test]
+    fn test_unsafe_methods_need_the_csrf_token() {
+        let cookie_only = headers(&[(header::COOKIE, "claw_pen_csrf=xyz")]);
+        let matching = headers(&[(header::COOKIE, "claw_pen_csrf=xyz"), (CSRF_HEADER, "xyz")]);
+        let wrong = headers(&[(header::COOKIE, "claw_pen_csrf=xyz"), (CSRF_HEADER, "xyZ")]);
+        // Without the cookie a header alone proves nothing
+        let header_only = headers(&[(CSRF_HEADER, "xyz")]);
+
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            check_csrf(&method, &cookie_only).unwrap();
+        }
+        for method in [Method::POST, Method::PUT, Method::PATCH, Method::DELETE] {
+            check_csrf(&method, &matching).unwrap();
+            for headers in [&cookie_only, &wrong, &header_only] {
+                assert!(matches!(
+                    check_csrf(&method, headers),
+                    Err(AuthError::InvalidCsrfToken)
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn test_cookie_attributes() {
+        let tokens = TokenResponse {
+            access_token: "access.jwt".to_string(),
+            refresh_token: "refresh.jwt".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+        };
+        let (AppendHeaders(cookies), session) = start(&tokens, 7200);
+        let values: Vec<&str> = cookies.iter().map(|(_, v)| v.to_str().unwrap()).collect();
+        assert_eq!(
+            values[0],
+            "claw_pen_session=access.jwt; Path=/; Max-Age=3600; Secure; SameSite=Lax; HttpOnly"
+        );
+        assert_eq!(
+            values[1],
+            "claw_pen_refresh=refresh.jwt; Path=/api/auth/refresh; Max-Age=7200; Secure; SameSite=Lax; HttpOnly"
+        );
+        assert_eq!(
+            values[2],
+            format!(
+                "claw_pen_csrf={}; Path=/; Max-Age=7200; Secure; SameSite=Lax",
+                session.csrf_token
+            )
+        );
+        assert_eq!(session.expires_in, 3600);
+
+        let AppendHeaders(cleared) = clear();
+        assert!(cleared
+            .iter()
+            .all(|(_, v)| v.to_str().unwrap().contains("=; Path=")
+                && v.to_str().unwrap().contains("Max-Age=0")));
+    }
+}
@@ -0,0 +1,223 @@
+//! Login sessions
+//!
+//! Every login starts a session: a family of refresh tokens, each exchanged
+//! for the next at `/api/auth/refresh`, and the access tokens issued with
+//! them. Tokens carry the session id as `sid`. The [`AuthStore`] keeps a
+//! record of each session - when it started and was last refreshed, the
+//! client's user agent and keyed IP hash, and the device of a device login -
+//! but never the tokens themselves.
+//!
+//! `GET /auth/sessions` lists the live ones and `DELETE /auth/sessions/:id`
+//! ends one. Ending a session puts its id in the token denylist until its
+//! newest refresh token would have expired, so every token of the family,
+//! refreshed or not, stops working at once. Logging out ends the session too.
+//!
+//! [`AuthStore`]: crate::auth_store::AuthStore
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts},
+};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use crate::client_ip::ClientIp;
+use crate::AppState;
+
+/// Sessions returned by `GET /auth/sessions` when no limit is given
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+
+pub const MAX_PAGE_SIZE: usize = 500;
+
+/// Characters of a user agent kept
+const MAX_USER_AGENT_LENGTH: usize = 256;
+
+/// A refresh-token family
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub username: String,
+    pub created_at: i64,
+    /// When the newest tokens were issued
+    pub last_refresh: i64,
+    /// When the newest refresh token expires
+    pub expires_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// Keyed hash of the client IP, as in the audit log
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_hash: Option<String>,
+    /// Device of a device login
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// Token generation of the account when the session started; the
+    /// session ends with `logout-all`
+    #[serde(default)]
+    pub generation: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<i64>,
+}
+
+/// Who is logging in or refreshing, as a handler argument
+#[derive(Debug, Clone, Default)]
+pub struct SessionClient {
+    pub user_agent: Option<String>,
+    pub ip_hash: Option<String>,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for SessionClient {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(|ua| ua.chars().take(MAX_USER_AGENT_LENGTH).collect());
+        let ip_hash = parts
+            .extensions
+            .get::<ClientIp>()
+            .filter(|ClientIp(ip)| !ip.is_unspecified())
+            .map(|ClientIp(ip)| state.audit.hash_ip(*ip));
+        Ok(Self {
+            user_agent,
+            ip_hash,
+        })
+    }
+}
+
+/// Query of `GET /auth/sessions`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SessionQuery {
+    /// Only this account's sessions; admins only, for anyone else's
+    pub username: Option<String>,
+    /// Only sessions after this id; pass the previous page's `next_after`
+    pub after: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// One entry of `GET /auth/sessions`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    #[serde(flatten)]
+    pub session: Session,
+    /// Whether the caller's token belongs to this session
+    pub current: bool,
+}
+
+/// Response of `GET /auth/sessions`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionPage {
+    /// Oldest first
+    pub sessions: Vec<SessionInfo>,
+    /// Cursor for the next page, if there are more sessions
+    pub next_after: Option<String>,
+}
+
+/// The page of `sessions` after `query.after`, oldest first, flagging the
+/// one with id `current`
+pub fn page(
+    mut sessions: Vec<Session>,
+    query: &SessionQuery,
+    current: Option<&str>,
+) -> SessionPage {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    sessions.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+    let start = match query.after.as_deref() {
+        Some(after) => match sessions.iter().position(|s| s.id == after) {
+            Some(index) => index + 1,
+            // The cursor ended since; nothing sorts after it
+            None => sessions.len(),
+        },
+        None => 0,
+    };
+    let rest = sessions.split_off(start);
+    let next_after = (rest.len() > limit).then(|| rest[limit - 1].id.clone());
+    SessionPage {
+        sessions: rest
+            .into_iter()
+            .take(limit)
+            .map(|session| SessionInfo {
+                current: current == Some(session.id.as_str()),
+                session,
+            })
+            .collect(),
+        next_after,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(id: &str, created_at: i64) -> Session {
+        Session {
+            id: id.to_string(),
+            username: "admin".to_string(),
+            created_at,
+            last_refresh: created_at,
+            expires_at: created_at + 3600,
+            user_agent: None,
+            ip_hash: None,
+            device_id: None,
+            generation: 0,
+            revoked_at: None,
+        }
+    }
+
+    #[test]
+    fn test_pages_oldest_first() {
+        let sessions = vec![session("c", 30), session("a", 10), session("b", 20)];
+        let query = |after: Option<&str>| SessionQuery {
+            username: None,
+            after: after.map(str::to_string),
+            limit: Some(2),
+        };
+        let ids = |page: &SessionPage| -> Vec<String> {
+            page.sessions.iter().map(|s| s.session.id.clone()).collect()
+        };
+
+        let first = page(sessions.clone(), &query(None), Some("b"));
+        assert_eq!(ids(&first), ["a", "b"]);
+        assert!(!first.sessions[0].current);
+        assert!(first.sessions[1].current);
+        assert_eq!(first.next_after.as_deref(), Some("b"));
+
+        let second = page(sessions.clone(), &query(Some("b")), None);
+        assert_eq!(ids(&second), ["c"]);
+        assert_eq!(second.next_after, None);
+
+        let gone = page(sessions, &query(Some("ended")), None);
+        assert!(gone.sessions.is_empty());
+    }
+
+    #[test]
+    fn test_listing_never_holds_tokens() {
+        let page = page(vec![session("a", 10)], &SessionQuery::default(), None);
+        let json = serde_json::to_value(&page).unwrap();
+        let entry = json["sessions"][0].as_object().unwrap();
+        let mut keys: Vec<&str> = entry.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                "created_at",
+                "current",
+                "expires_at",
+                "generation",
+                "id",
+                "last_refresh",
+                "username"
+            ]
+        );
+    }
+}
@@ -2,15 +2,54 @@ use anyhow::Result;
 use serde::Deserialize;
 use std::collections::HashMap;
 
+use crate::validation;
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Template {
     pub name: String,
     pub description: Option<String>,
+    /// Semver version, stored canonically once loaded
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Orchestrator versions the template works with, like `^0.1`
+    #[serde(default)]
+    pub compatibility: Option<String>,
     pub config: TemplateConfig,
     #[serde(default)]
     pub env: HashMap<String, String>,
 }
 
+impl Template {
+    /// The template's version, if it has one
+    pub fn semver(&self) -> Option<semver::Version> {
+        self.version.as_deref()?.parse().ok()
+    }
+
+    /// Check the version and compatibility range, storing them canonically
+    fn check(&mut self, key: &str) -> Result<()> {
+        if let Some(ref version) = self.version {
+            let version = validation::validate_semver(version)
+                .map_err(|e| anyhow::anyhow!("Template '{}': {}", key, e))?;
+            self.version = Some(version.to_string());
+        }
+        if let Some(ref compatibility) = self.compatibility {
+            let req = validation::validate_version_req(compatibility)
+                .map_err(|e| anyhow::anyhow!("Template '{}' compatibility: {}", key, e))?;
+            self.compatibility = Some(req.to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether the template works with orchestrator `version`; any do
+    /// without a compatibility range
+    fn works_with(&self, version: &semver::Version) -> bool {
+        self.compatibility
+            .as_deref()
+            .and_then(|c| c.parse::<semver::VersionReq>().ok())
+            .is_none_or(|req| req.matches(version))
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct TemplateConfig {
     #[serde(default)]
@@ -35,6 +74,7 @@ struct TemplatesFile {
     templates: HashMap<String, Template>,
 }
 
+#[derive(Debug)]
 pub struct TemplateRegistry {
     templates: HashMap<String, Template>,
 }
@@ -49,10 +89,7 @@ impl TemplateRegistry {
 
         for path in &paths {
             if let Ok(contents) = std::fs::read_to_string(path) {
-                let file: TemplatesFile = serde_yaml::from_str(&contents)?;
-                return Ok(Self {
-                    templates: file.templates,
-                });
+                return Self::parse(&contents);
             }
         }
 
@@ -62,6 +99,27 @@ impl TemplateRegistry {
         })
     }
 
+    /// Templates from a templates file, leaving out those that don't work
+    /// with this orchestrator
+    fn parse(contents: &str) -> Result<Self> {
+        let file: TemplatesFile = serde_yaml::from_str(contents)?;
+        let orchestrator: semver::Version = env!("CARGO_PKG_VERSION").parse()?;
+        let mut templates = HashMap::new();
+        for (key, mut template) in file.templates {
+            template.check(&key)?;
+            if template.works_with(&orchestrator) {
+                templates.insert(key, template);
+            } else {
+                tracing::warn!(
+                    "Skipping template '{}', which needs orchestrator {}",
+                    key,
+                    template.compatibility.as_deref().unwrap_or_default()
+                );
+            }
+        }
+        Ok(Self { templates })
+    }
+
     pub fn get(&self, name: &str) -> Option<&Template> {
         self.templates.get(name)
     }
@@ -78,3 +136,41 @@ impl Default for TemplateRegistry {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(version: &str, compatibility: &str) -> String {
+        format!(
+            "templates:\n  t:\n    name: T\n    version: \"{}\"\n    compatibility: \"{}\"\n    config: {{}}\n",
+            version, compatibility
+        )
+    }
+
+    #[test]
+    fn test_versions_are_checked_and_stored_canonically() {
+        let registry = TemplateRegistry::parse(&template("1.2.0-rc.1+b5", ">=0.1,   <2")).unwrap();
+        let t = registry.get("t").unwrap();
+        assert_eq!(t.version.as_deref(), Some("1.2.0-rc.1+b5"));
+        assert_eq!(t.compatibility.as_deref(), Some(">=0.1, <2"));
+        assert_eq!(t.semver().unwrap().pre.as_str(), "rc.1");
+
+        let e = TemplateRegistry::parse(&template("1.02.0", "*")).unwrap_err();
+        assert!(e.to_string().starts_with("Template 't': "), "{}", e);
+        assert!(TemplateRegistry::parse(&template("1.0.0", "^1.0.0+build")).is_err());
+    }
+
+    #[test]
+    fn test_templates_for_other_orchestrators_are_left_out() {
+        assert!(TemplateRegistry::parse(&template("1.0.0", ">=1000"))
+            .unwrap()
+            .get("t")
+            .is_none());
+        let current = format!("={}", env!("CARGO_PKG_VERSION"));
+        assert!(TemplateRegistry::parse(&template("1.0.0", &current))
+            .unwrap()
+            .get("t")
+            .is_some());
+    }
+}
@@ -0,0 +1,265 @@
+//! TOTP two-factor authentication
+//!
+//! RFC 6238 codes: HMAC-SHA1, 6 digits, 30 second steps. Codes from one step
+//! either side of the current one are accepted, and a step is never accepted
+//! twice. Secrets are encrypted with ChaCha20-Poly1305 under a key derived
+//! from the JWT secret before they are written to the auth store; recovery codes
+//! are stored as keyed hashes and work once each.
+
+use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hmac::{Hmac, Mac};
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::Sha256;
+
+use crate::auth::AuthError;
+
+/// Issuer shown in authenticator apps
+pub const ISSUER: &str = "Claw Pen";
+
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Steps either side of the current one that are still accepted
+const DRIFT_STEPS: i64 = 1;
+/// 160 bits, as RFC 4226 recommends
+const SECRET_LENGTH: usize = 20;
+const NONCE_LENGTH: usize = 12;
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+/// Unambiguous lowercase characters for recovery codes
+const RECOVERY_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// An account's second factor, as stored with its account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpState {
+    /// Encrypted secret: base64 of nonce followed by ciphertext
+    pub secret: String,
+    /// False between setup and the first confirmed code
+    pub enabled: bool,
+    /// Keyed hashes of unused recovery codes
+    pub recovery_codes: Vec<String>,
+    /// Step of the last accepted code
+    #[serde(default)]
+    pub last_step: Option<i64>,
+}
+
+/// Response of `POST /auth/totp/setup`; none of it is shown again
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpSetup {
+    /// Base32 secret, for manual entry
+    pub secret: String,
+    /// `otpauth://` URI, for QR codes
+    pub provisioning_uri: String,
+    pub recovery_codes: Vec<String>,
+}
+
+/// Encrypts secrets and hashes recovery codes
+pub struct TotpCipher {
+    key: Vec<u8>,
+}
+
+impl TotpCipher {
+    pub fn new(jwt_secret: &[u8]) -> Self {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(jwt_secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(b"claw-pen totp");
+        Self {
+            key: mac.finalize().into_bytes().to_vec(),
+        }
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+    }
+
+    pub fn encrypt(&self, secret: &[u8]) -> Result<String, AuthError> {
+        let mut nonce = [0u8; NONCE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher()
+            .encrypt(Nonce::from_slice(&nonce), secret)
+            .map_err(|_| AuthError::EncryptionError("failed to encrypt TOTP secret".into()))?;
+        Ok(BASE64_STANDARD.encode([nonce.as_slice(), &ciphertext].concat()))
+    }
+
+    pub fn decrypt(&self, stored: &str) -> Result<Vec<u8>, AuthError> {
+        let data = BASE64_STANDARD.decode(stored)?;
+        if data.len() <= NONCE_LENGTH {
+            return Err(AuthError::EncryptionError("truncated TOTP secret".into()));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+        self.cipher()
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| AuthError::EncryptionError("failed to decrypt TOTP secret".into()))
+    }
+
+    /// Keyed hash of a recovery code, ignoring case, spaces and dashes
+    pub fn hash_recovery_code(&self, code: &str) -> String {
+        let normalized: String = code
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(b"recovery:");
+        mac.update(normalized.as_bytes());
+        BASE64_STANDARD.encode(mac.finalize().into_bytes())
+    }
+}
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LENGTH];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// RFC 4648 base32 without padding, as authenticator apps expect
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+pub fn provisioning_uri(account: &str, secret: &[u8]) -> String {
+    let issuer = ISSUER.replace(' ', "%20");
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}",
+        base32_encode(secret)
+    )
+}
+
+/// The code for time step `step`
+pub fn code_at(secret: &[u8], step: i64) -> u32 {
+    let mut mac =
+        <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// Check `code` against the steps around `now`; returns the matching step
+/// unless it is not after `last_step`
+pub fn verify_code(secret: &[u8], code: &str, now: i64, last_step: Option<i64>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = now.div_euclid(STEP_SECS);
+    (current - DRIFT_STEPS..=current + DRIFT_STEPS)
+        .filter(|step| !matches!(last_step, Some(last) if *step <= last))
+        .find(|step| code_at(secret, *step) == code)
+}
+
+/// Fresh recovery codes, formatted `xxxxx-xxxxx`
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut code: String = (0..10)
+                .map(|_| RECOVERY_ALPHABET[rng.gen_range(0..RECOVERY_ALPHABET.len())] as char)
+                .collect();
+            code.insert(5, '-');
+            code
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        // RFC 6238 appendix B, SHA-1, last six digits
+        assert_eq!(code_at(RFC_SECRET, 59 / STEP_SECS), 287_082);
+        assert_eq!(code_at(RFC_SECRET, 1_111_111_109 / STEP_SECS), 81_804);
+        assert_eq!(code_at(RFC_SECRET, 1_234_567_890 / STEP_SECS), 5_924);
+    }
+
+    #[test]
+    fn test_verify_allows_one_step_of_drift_once() {
+        let now = 1_111_111_109;
+        let step = now / STEP_SECS;
+        let previous = format!("{:06}", code_at(RFC_SECRET, step - 1));
+        let next = format!("{:06}", code_at(RFC_SECRET, step + 1));
+        let stale = format!("{:06}", code_at(RFC_SECRET, step - 2));
+
+        assert_eq!(
+            verify_code(RFC_SECRET, &previous, now, None),
+            Some(step - 1)
+        );
+        assert_eq!(verify_code(RFC_SECRET, &next, now, None), Some(step + 1));
+        assert_eq!(verify_code(RFC_SECRET, &stale, now, None), None);
+        assert_eq!(verify_code(RFC_SECRET, "081804", now, None), Some(step));
+        // A step already used can't be used again
+        assert_eq!(verify_code(RFC_SECRET, "081804", now, Some(step)), None);
+        assert_eq!(verify_code(RFC_SECRET, "81804", now, None), None);
+        assert_eq!(verify_code(RFC_SECRET, "08180a", now, None), None);
+    }
+
+    #[test]
+    fn test_base32_and_uri() {
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(
+            base32_encode(RFC_SECRET),
+            "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+        );
+        let uri = provisioning_uri("alice", RFC_SECRET);
+        assert!(uri.starts_with("otpauth://totp/Claw%20Pen:alice?secret=GEZDGNBV"));
+        assert!(uri.contains("&digits=6&period=30"));
+    }
+
+    #[test]
+    fn test_secret_round_trips_encrypted() {
+        let cipher = TotpCipher::new(b"jwt secret");
+        let stored = cipher.encrypt(RFC_SECRET).unwrap();
+        assert!(!stored.contains("GEZDGNBV"));
+        assert_eq!(cipher.decrypt(&stored).unwrap(), RFC_SECRET);
+        assert!(TotpCipher::new(b"other secret").decrypt(&stored).is_err());
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(codes
+            .iter()
+            .all(|c| c.len() == 11 && c.as_bytes()[5] == b'-'));
+
+        let cipher = TotpCipher::new(b"jwt secret");
+        assert_eq!(
+            cipher.hash_recovery_code("abcde-fghjk"),
+            cipher.hash_recovery_code(" ABCDE FGHJK ")
+        );
+    }
+}
@@ -63,6 +63,36 @@ pub struct AgentConfig {
     /// Volumes to mount
     #[serde(default)]
     pub volumes: Vec<VolumeMount>,
+    /// Hostname inside the container; the runtime picks one if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Network to attach to instead of claw-pen's own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<String>,
+    /// Other names the agent answers to on its network
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub network_aliases: Vec<String>,
+    /// GPUs to pass through
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpus: Option<GpuRequest>,
+    /// Host devices to pass through
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<DeviceMapping>,
+    /// Writable layer size in MB; unlimited if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_mb: Option<u64>,
+    /// Storage driver options, of which only `size` is allowed
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub storage_opts: HashMap<String, String>,
+    /// Labels set on the container next to claw-pen's own
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+    /// Program and arguments run instead of the image's entrypoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<Vec<String>>,
+    /// Arguments instead of the image's command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<Vec<String>>,
     /// API key for the LLM provider (stored encrypted)
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub api_key: Option<String>,
@@ -180,9 +210,92 @@ pub struct VolumeMount {
     pub source: String,
     /// Path inside container
     pub target: String,
+    /// How it's mounted
+    #[serde(flatten)]
+    pub options: MountOptions,
+}
+
+/// How a volume is mounted, alongside its source and target
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MountOptions {
+    /// What the source is; a host path means a bind mount and anything
+    /// else a named volume if unset
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<MountKind>,
     /// Read-only mount
     #[serde(default)]
     pub read_only: bool,
+    /// Bind propagation, like `rprivate` or `rslave`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub propagation: Option<String>,
+    /// `default`, `consistent`, `cached` or `delegated`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consistency: Option<String>,
+    /// Size of a tmpfs mount
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tmpfs_size_mb: Option<u32>,
+    /// File mode of a tmpfs mount, like `0o1777`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tmpfs_mode: Option<u32>,
+}
+
+/// GPUs an agent asks for, either a number of any or specific ones
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GpuRequest {
+    /// How many GPUs, whichever are free
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u32>,
+    /// Indices of the GPUs wanted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub device_ids: Vec<u32>,
+    /// Driver capabilities, like `compute` and `utility`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+}
+
+/// A host device made available inside the container
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceMapping {
+    /// Device on the host, like `/dev/dri/renderD128`
+    pub host_path: String,
+    /// Path inside the container; the host path if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container_path: Option<String>,
+    /// cgroup permissions, any of `r`, `w` and `m`
+    #[serde(default = "default_device_permissions")]
+    pub permissions: String,
+}
+
+fn default_device_permissions() -> String {
+    "rwm".to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MountKind {
+    Bind,
+    Volume,
+    Tmpfs,
+}
+
+/// A container port published on the host
+#[allow(dead_code)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortMapping {
+    /// Port on the host; 0 lets the runtime pick one
+    pub host_port: u32,
+    /// Port inside the container
+    pub container_port: u32,
+    /// "tcp" or "udp"
+    #[serde(default = "default_port_protocol")]
+    pub protocol: String,
+    /// Host address to bind, all of them if unset
+    #[serde(default)]
+    pub host_ip: Option<String>,
+}
+
+fn default_port_protocol() -> String {
+    "tcp".to_string()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -198,6 +311,9 @@ pub struct CreateAgentRequest {
     pub name: String,
     #[serde(default)]
     pub template: Option<String>,
+    /// Versions of the template the agent may be created from, like `^1.2`
+    #[serde(default)]
+    pub template_version: Option<String>,
     #[serde(default)]
     pub config: Option<PartialAgentConfig>,
     /// Project to assign agent to
@@ -231,6 +347,16 @@ pub struct PartialAgentConfig {
     pub restart_policy: Option<RestartPolicy>,
     pub health_check: Option<HealthCheck>,
     pub volumes: Option<Vec<VolumeMount>>,
+    pub hostname: Option<String>,
+    pub network: Option<String>,
+    pub network_aliases: Option<Vec<String>>,
+    pub gpus: Option<GpuRequest>,
+    pub devices: Option<Vec<DeviceMapping>>,
+    pub disk_mb: Option<u64>,
+    pub storage_opts: Option<HashMap<String, String>>,
+    pub labels: Option<HashMap<String, String>>,
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
 }
 
 // === Project/Group Management ===
@@ -242,6 +368,8 @@ pub struct Project {
     pub description: Option<String>,
     #[serde(default)]
     pub agents: Vec<String>, // Agent IDs
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
     pub created_at: String,
 }
 
@@ -249,6 +377,8 @@ pub struct Project {
 pub struct CreateProjectRequest {
     pub name: String,
     pub description: Option<String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
 }
 
 // === Secrets Management ===
@@ -330,6 +460,36 @@ impl AgentConfig {
         if let Some(ref volumes) = partial.volumes {
             self.volumes = volumes.clone();
         }
+        if let Some(ref hostname) = partial.hostname {
+            self.hostname = Some(hostname.clone());
+        }
+        if let Some(ref network) = partial.network {
+            self.network = Some(network.clone());
+        }
+        if let Some(ref aliases) = partial.network_aliases {
+            self.network_aliases = aliases.clone();
+        }
+        if let Some(ref gpus) = partial.gpus {
+            self.gpus = Some(gpus.clone());
+        }
+        if let Some(ref devices) = partial.devices {
+            self.devices = devices.clone();
+        }
+        if let Some(disk_mb) = partial.disk_mb {
+            self.disk_mb = Some(disk_mb);
+        }
+        if let Some(ref storage_opts) = partial.storage_opts {
+            self.storage_opts = storage_opts.clone();
+        }
+        if let Some(ref labels) = partial.labels {
+            self.labels = labels.clone();
+        }
+        if let Some(ref entrypoint) = partial.entrypoint {
+            self.entrypoint = Some(entrypoint.clone());
+        }
+        if let Some(ref cmd) = partial.cmd {
+            self.cmd = Some(cmd.clone());
+        }
     }
 }
 
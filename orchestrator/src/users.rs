@@ -0,0 +1,243 @@
+//! User accounts
+//!
+//! Accounts are kept in the [`AuthStore`]: username, Argon2 hash, role and a
+//! disabled flag. A data directory from the single-admin days (an
+//! `admin_password` file) is migrated to an `admin` account along with the rest
+//! of the files.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::auth::AuthError;
+use crate::auth_store::AuthStore;
+use crate::totp::TotpState;
+
+/// Account used when a login doesn't name one, and the one migrated from
+/// `admin_password`
+pub const DEFAULT_USERNAME: &str = "admin";
+
+const MAX_USERNAME_LENGTH: usize = 32;
+
+/// What an account may do; later variants include everything earlier ones can
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Read-only access
+    Viewer,
+    /// Manage agents, but not accounts
+    Operator,
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    pub password_hash: String,
+    pub role: Role,
+    #[serde(default)]
+    pub disabled: bool,
+    /// Bumped on every password change; refresh tokens carry it
+    #[serde(default)]
+    pub password_version: u64,
+    /// Unix time of the last password change; tokens issued before it are
+    /// superseded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_changed_at: Option<i64>,
+    /// Second factor, once set up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp: Option<TotpState>,
+}
+
+/// An account as listed by `GET /auth/users`, without the hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub username: String,
+    pub role: Role,
+    pub disabled: bool,
+}
+
+impl From<&User> for UserInfo {
+    fn from(user: &User) -> Self {
+        Self {
+            username: user.username.clone(),
+            role: user.role,
+            disabled: user.disabled,
+        }
+    }
+}
+
+/// Usernames are 1-32 lowercase letters, digits, `.`, `_` or `-`
+pub fn validate_username(username: &str) -> Result<(), AuthError> {
+    let valid = !username.is_empty()
+        && username.len() <= MAX_USERNAME_LENGTH
+        && username.chars().all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '_' || c == '-'
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(AuthError::InvalidUsername)
+    }
+}
+
+/// The accounts in an [`AuthStore`], read once and written through
+pub struct UserStore {
+    store: Arc<dyn AuthStore>,
+    users: BTreeMap<String, User>,
+}
+
+impl UserStore {
+    pub fn load(store: Arc<dyn AuthStore>) -> Result<Self, AuthError> {
+        let users = store
+            .users()?
+            .into_iter()
+            .map(|u| (u.username.clone(), u))
+            .collect();
+        Ok(Self { store, users })
+    }
+
+    pub fn get(&self, username: &str) -> Option<&User> {
+        self.users.get(username)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &User> {
+        self.users.values()
+    }
+
+    pub fn list(&self) -> Vec<UserInfo> {
+        self.users.values().map(UserInfo::from).collect()
+    }
+
+    /// Whether an enabled admin account exists
+    pub fn has_admin(&self) -> bool {
+        self.users
+            .values()
+            .any(|u| u.role == Role::Admin && !u.disabled)
+    }
+
+    pub fn insert(&mut self, user: User) -> Result<(), AuthError> {
+        self.store.insert_user(&user, false)?;
+        self.users.insert(user.username.clone(), user);
+        Ok(())
+    }
+
+    /// Add the first admin account; fails if an enabled admin exists, even
+    /// one another process just created
+    pub fn insert_first_admin(&mut self, user: User) -> Result<(), AuthError> {
+        self.store.insert_user(&user, true)?;
+        self.users.insert(user.username.clone(), user);
+        Ok(())
+    }
+
+    /// Remove an account; the last enabled admin can't be removed
+    pub fn remove(&mut self, username: &str) -> Result<(), AuthError> {
+        self.store.remove_user(username)?;
+        self.users.remove(username);
+        Ok(())
+    }
+
+    /// Replace an account's password hash, bumping its password version and
+    /// superseding the tokens issued so far
+    pub fn set_password(&mut self, username: &str, password_hash: String) -> Result<(), AuthError> {
+        self.update(username, |user| {
+            user.password_hash = password_hash;
+            user.password_version += 1;
+            user.password_changed_at = Some(Utc::now().timestamp());
+        })
+    }
+
+    /// Change an account in place and persist it
+    pub fn update(
+        &mut self,
+        username: &str,
+        change: impl FnOnce(&mut User),
+    ) -> Result<(), AuthError> {
+        let mut user = self
+            .users
+            .get(username)
+            .ok_or(AuthError::UserNotFound)?
+            .clone();
+        change(&mut user);
+        self.store.update_user(&user)?;
+        self.users.insert(user.username.clone(), user);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth_store::MemoryAuthStore;
+
+    fn user(username: &str, role: Role) -> User {
+        User {
+            username: username.to_string(),
+            password_hash: "hash".to_string(),
+            role,
+            disabled: false,
+            password_version: 0,
+            password_changed_at: None,
+            totp: None,
+        }
+    }
+
+    #[test]
+    fn test_validate_username() {
+        assert!(validate_username("alice").is_ok());
+        assert!(validate_username("ops-bot_2.eu").is_ok());
+        assert!(validate_username("").is_err());
+        assert!(validate_username("Alice").is_err());
+        assert!(validate_username("a/b").is_err());
+        assert!(validate_username(&"a".repeat(MAX_USERNAME_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_last_admin_cannot_be_removed() {
+        let mut store = UserStore::load(Arc::new(MemoryAuthStore::default())).unwrap();
+        store.insert(user("admin", Role::Admin)).unwrap();
+        store.insert(user("viewer", Role::Viewer)).unwrap();
+        assert!(matches!(
+            store.insert(user("viewer", Role::Operator)),
+            Err(AuthError::UserAlreadyExists)
+        ));
+
+        assert!(matches!(store.remove("admin"), Err(AuthError::LastAdmin)));
+        assert!(matches!(
+            store.remove("nobody"),
+            Err(AuthError::UserNotFound)
+        ));
+        store.remove("viewer").unwrap();
+
+        store.insert(user("second", Role::Admin)).unwrap();
+        store.remove("admin").unwrap();
+        assert!(store.has_admin());
+    }
+
+    #[test]
+    fn test_changes_are_written_through() {
+        let backing: Arc<dyn AuthStore> = Arc::new(MemoryAuthStore::default());
+        let mut store = UserStore::load(backing.clone()).unwrap();
+        store
+            .insert_first_admin(user("admin", Role::Admin))
+            .unwrap();
+        assert!(matches!(
+            store.insert_first_admin(user("other", Role::Admin)),
+            Err(AuthError::UserAlreadyExists)
+        ));
+        store.set_password("admin", "new hash".to_string()).unwrap();
+
+        let reloaded = UserStore::load(backing).unwrap();
+        let admin = reloaded.get("admin").unwrap();
+        assert_eq!(admin.password_hash, "new hash");
+        assert_eq!(admin.password_version, 1);
+    }
+
+    #[test]
+    fn test_roles_are_ordered() {
+        assert!(Role::Admin > Role::Operator);
+        assert!(Role::Operator > Role::Viewer);
+        assert_eq!(serde_json::to_string(&Role::Viewer).unwrap(), "\"viewer\"");
+    }
+}
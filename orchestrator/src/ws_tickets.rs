@@ -0,0 +1,300 @@
+//! One-time WebSocket tickets
+//!
+//! Browsers can't set headers on a WebSocket upgrade, so the credential has
+//! to go in the URL, where proxy logs and browser history keep it. Instead of
+//! an access token, clients get a ticket from `POST /auth/ws-ticket` and
+//! connect with `?ticket=<ticket>`. A ticket stands for the claims of whoever
+//! asked for it, works for one upgrade, and expires after 30 seconds. Only a
+//! SHA-256 of each ticket is stored.
+//!
+//! Tickets are kept in memory and pruned every minute. With
+//! `WS_TICKET_STORE=sqlite` they go in `ws_tickets.db` in the data directory
+//! instead, so orchestrator processes sharing it can redeem each other's.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::auth::{AuthError, Claims};
+
+/// Prefix of every ticket, so it can't be mistaken for a JWT or API key
+pub const WS_TICKET_PREFIX: &str = "wst_";
+
+/// Seconds a ticket stays redeemable
+pub const WS_TICKET_TTL_SECS: i64 = 30;
+
+/// Random bytes in a ticket, after the prefix
+const TICKET_LENGTH: usize = 32;
+
+/// Seconds between prunes of expired tickets
+pub const PRUNE_INTERVAL_SECS: u64 = 60;
+
+/// Response of `POST /auth/ws-ticket`
+#[derive(Debug, Clone, Serialize)]
+pub struct WsTicket {
+    pub ticket: String,
+    pub expires_in: i64,
+}
+
+struct StoredTicket {
+    claims: Claims,
+    expires_at: i64,
+}
+
+enum Backend {
+    /// Ticket hash -> ticket
+    Memory(HashMap<String, StoredTicket>),
+    Sqlite(Connection),
+}
+
+pub struct WsTicketStore {
+    backend: Backend,
+}
+
+fn ticket_hash(ticket: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(ticket.as_bytes()))
+}
+
+fn db_error(err: rusqlite::Error) -> AuthError {
+    AuthError::DatabaseError(err.to_string())
+}
+
+impl WsTicketStore {
+    pub fn memory() -> Self {
+        Self {
+            backend: Backend::Memory(HashMap::new()),
+        }
+    }
+
+    /// Open or create the ticket database at `path`, readable only by us
+    pub fn sqlite(path: &Path) -> Result<Self, AuthError> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(false);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)?;
+
+        let conn = Connection::open(path).map_err(db_error)?;
+        conn.execute_batch(
+            "PRAGMA busy_timeout = 5000;
+             CREATE TABLE IF NOT EXISTS ws_tickets (
+                 hash TEXT PRIMARY KEY,
+                 claims TEXT NOT NULL,
+                 expires_at INTEGER NOT NULL
+             );",
+        )
+        .map_err(db_error)?;
+        Ok(Self {
+            backend: Backend::Sqlite(conn),
+        })
+    }
+
+    /// The store `WS_TICKET_STORE` names: `memory` (default) or `sqlite`
+    pub fn from_env(data_dir: &Path) -> Result<Self, AuthError> {
+        match std::env::var("WS_TICKET_STORE")
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "memory" => Ok(Self::memory()),
+            "sqlite" => Self::sqlite(&data_dir.join("ws_tickets.db")),
+            other => Err(AuthError::InvalidConfig(format!(
+                "WS_TICKET_STORE must be memory or sqlite, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    /// Issue a ticket standing for `claims`
+    pub fn issue(&mut self, claims: &Claims, now: i64) -> Result<WsTicket, AuthError> {
+        let mut bytes = [0u8; TICKET_LENGTH];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let ticket = format!("{}{}", WS_TICKET_PREFIX, URL_SAFE_NO_PAD.encode(bytes));
+        let expires_at = now + WS_TICKET_TTL_SECS;
+
+        match &mut self.backend {
+            Backend::Memory(tickets) => {
+                tickets.insert(
+                    ticket_hash(&ticket),
+                    StoredTicket {
+                        claims: claims.clone(),
+                        expires_at,
+                    },
+                );
+            }
+            Backend::Sqlite(conn) => {
+                conn.execute(
+                    "INSERT INTO ws_tickets (hash, claims, expires_at) VALUES (?1, ?2, ?3)",
+                    params![
+                        ticket_hash(&ticket),
+                        serde_json::to_string(claims)?,
+                        expires_at
+                    ],
+                )
+                .map_err(db_error)?;
+            }
+        }
+        Ok(WsTicket {
+            ticket,
+            expires_in: WS_TICKET_TTL_SECS,
+        })
+    }
+
+    /// Use up a ticket, returning the claims it stands for
+    ///
+    /// The ticket is removed in the same step it is looked up, so of two
+    /// connections racing with one ticket only one gets through.
+    pub fn redeem(&mut self, ticket: &str, now: i64) -> Result<Claims, AuthError> {
+        let hash = ticket_hash(ticket);
+        let claims = match &mut self.backend {
+            Backend::Memory(tickets) => tickets
+                .remove(&hash)
+                .filter(|t| t.expires_at > now)
+                .map(|t| t.claims),
+            Backend::Sqlite(conn) => {
+                let stored: Option<(String, i64)> = conn
+                    .query_row(
+                        "DELETE FROM ws_tickets WHERE hash = ?1 RETURNING claims, expires_at",
+                        params![hash],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()
+                    .map_err(db_error)?;
+                match stored {
+                    Some((claims, expires_at)) if expires_at > now => {
+                        Some(serde_json::from_str(&claims)?)
+                    }
+                    _ => None,
+                }
+            }
+        };
+        claims.ok_or(AuthError::InvalidToken)
+    }
+
+    /// Drop expired tickets
+    pub fn prune(&mut self, now: i64) -> Result<(), AuthError> {
+        match &mut self.backend {
+            Backend::Memory(tickets) => tickets.retain(|_, t| t.expires_at > now),
+            Backend::Sqlite(conn) => {
+                conn.execute(
+                    "DELETE FROM ws_tickets WHERE expires_at <= ?1",
+                    params![now],
+                )
+                .map_err(db_error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn claims(sub: &str) -> Claims {
+        serde_json::from_value(serde_json::json!({
+            "sub": sub,
+            "role": "viewer",
+            "iat": 1_000,
+            "exp": 90_000,
+            "type": "access",
+            "jti": "j1",
+        }))
+        .unwrap()
+    }
+
+    fn count(store: &WsTicketStore) -> usize {
+        match &store.backend {
+            Backend::Memory(tickets) => tickets.len(),
+            Backend::Sqlite(conn) => conn
+                .query_row("SELECT COUNT(*) FROM ws_tickets", [], |row| {
+                    row.get::<_, i64>(0)
+                })
+                .unwrap() as usize,
+        }
+    }
+
+    fn check_single_use_and_expiry(store: &mut WsTicketStore) {
+        let ticket = store.issue(&claims("alice"), 1_000).unwrap();
+        assert!(ticket.ticket.starts_with(WS_TICKET_PREFIX));
+        assert_eq!(ticket.expires_in, WS_TICKET_TTL_SECS);
+        assert_eq!(store.redeem(&ticket.ticket, 1_010).unwrap().sub, "alice");
+        // Used up
+        assert!(matches!(
+            store.redeem(&ticket.ticket, 1_011),
+            Err(AuthError::InvalidToken)
+        ));
+
+        let late = store.issue(&claims("bob"), 1_000).unwrap();
+        assert!(store
+            .redeem(&late.ticket, 1_000 + WS_TICKET_TTL_SECS)
+            .is_err());
+        assert!(store.redeem("wst_made-up", 1_000).is_err());
+    }
+
+    #[test]
+    fn test_memory_tickets_are_single_use_and_expire() {
+        check_single_use_and_expiry(&mut WsTicketStore::memory());
+    }
+
+    #[test]
+    fn test_sqlite_tickets_are_single_use_and_expire() {
+        let dir = tempdir().unwrap();
+        check_single_use_and_expiry(&mut WsTicketStore::sqlite(&dir.path().join("t.db")).unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_tickets_shared_between_processes() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("ws_tickets.db");
+        let mut issuer = WsTicketStore::sqlite(&path).unwrap();
+        let mut other = WsTicketStore::sqlite(&path).unwrap();
+
+        let ticket = issuer.issue(&claims("alice"), 1_000).unwrap();
+        assert_eq!(other.redeem(&ticket.ticket, 1_001).unwrap().sub, "alice");
+        assert!(issuer.redeem(&ticket.ticket, 1_002).is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_prune_drops_only_expired_tickets() {
+        let dir = tempdir().unwrap();
+        for mut store in [
+            WsTicketStore::memory(),
+            WsTicketStore::sqlite(&dir.path().join("t.db")).unwrap(),
+        ] {
+            store.issue(&claims("old"), 1_000).unwrap();
+            let fresh = store.issue(&claims("new"), 1_020).unwrap();
+            store.prune(1_000 + WS_TICKET_TTL_SECS).unwrap();
+            assert_eq!(count(&store), 1);
+            assert_eq!(store.redeem(&fresh.ticket, 1_040).unwrap().sub, "new");
+        }
+    }
+
+    #[test]
+    fn test_tickets_are_stored_hashed() {
+        let mut store = WsTicketStore::memory();
+        let ticket = store.issue(&claims("alice"), 1_000).unwrap();
+        let Backend::Memory(tickets) = &store.backend else {
+            unreachable!()
+        };
+        assert!(!tickets.contains_key(&ticket.ticket));
+        assert!(tickets.contains_key(&ticket_hash(&ticket.ticket)));
+    }
+}
@@ -0,0 +1,102 @@
+// Agent directory from the gateway's `agents.list` RPC
+// Cached briefly so UI re-renders don't hammer the gateway.
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// How long a fetched agent list is reused
+pub const AGENT_CACHE_TTL: Duration = Duration::from_secs(30);
+
+pub const MAX_AGENT_ID_LENGTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentInfo {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+pub struct AgentCache {
+    pub agents: Vec<AgentInfo>,
+    fetched_at: Instant,
+}
+
+impl AgentCache {
+    pub fn new(agents: Vec<AgentInfo>) -> Self {
+        Self {
+            agents,
+            fetched_at: Instant::now(),
+        }
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.fetched_at.elapsed() < AGENT_CACHE_TTL
+    }
+}
+
+/// Validate an agent id
+/// Same rules as `validate_agent_id` on the orchestrator
+pub fn validate_agent_id(id: &str) -> Result<(), String> {
+    if id.is_empty() {
+        return Err("Agent ID cannot be empty".to_string());
+    }
+
+    if id.len() > MAX_AGENT_ID_LENGTH {
+        return Err("Agent ID too long".to_string());
+    }
+
+    let valid = id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == ':' || c == '_');
+
+    if !valid {
+        return Err("Agent ID contains invalid characters".to_string());
+    }
+
+    Ok(())
+}
+
+/// Parse an `agents.list` payload, accepting either `{agents: [...]}` or a bare array
+pub fn parse_agent_list(payload: &serde_json::Value) -> Result<Vec<AgentInfo>, String> {
+    let list = payload.get("agents").unwrap_or(payload);
+    serde_json::from_value(list.clone())
+        .map_err(|e| format!("Unexpected agents.list payload: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_agent_id() {
+        assert!(validate_agent_id("abc123").is_ok());
+        assert!(validate_agent_id("550e8400-e29b-41d4-a716-446655440000").is_ok());
+        assert!(validate_agent_id("container:abc_1").is_ok());
+        assert!(validate_agent_id("").is_err());
+        assert!(validate_agent_id("agent/../x").is_err());
+        assert!(validate_agent_id(&"a".repeat(MAX_AGENT_ID_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_parse_agent_list() {
+        let payload = serde_json::json!({
+            "agents": [
+                {"id": "a1", "name": "Coder", "status": "running", "model": "llama3", "extra": 1},
+                {"id": "a2"}
+            ]
+        });
+        let agents = parse_agent_list(&payload).unwrap();
+        assert_eq!(agents.len(), 2);
+        assert_eq!(agents[0].model.as_deref(), Some("llama3"));
+        assert_eq!(agents[1].name, None);
+
+        let bare = serde_json::json!([{"id": "a3"}]);
+        assert_eq!(parse_agent_list(&bare).unwrap()[0].id, "a3");
+
+        assert!(parse_agent_list(&serde_json::json!({"agents": "nope"})).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+// Device-auth challenge handling
+// Challenges can expire before our connect reaches the gateway; instead of
+// dropping the socket we ask for a fresh one with `connect.refresh`. Nonces we
+// have signed are remembered so a gateway can't get the same one signed twice.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How many signed nonces to remember
+pub const NONCE_HISTORY: usize = 32;
+
+/// Fresh challenges we'll request on one connection before giving up on it
+pub const MAX_REFRESHES: u32 = 3;
+
+const NONCE_EXPIRED_CODES: &[&str] = &["NONCE_EXPIRED", "CHALLENGE_EXPIRED"];
+
+/// A challenge as received
+#[derive(Debug, Clone)]
+pub struct Challenge {
+    pub received_at: Instant,
+    /// How long the gateway accepts a response, if it said
+    pub ttl: Option<Duration>,
+}
+
+impl Challenge {
+    pub fn parse(frame: &serde_json::Value, received_at: Instant) -> Self {
+        let payload = &frame["payload"];
+        let ttl = payload["ttlMs"]
+            .as_u64()
+            .map(Duration::from_millis)
+            .or_else(|| payload["ttl"].as_u64().map(Duration::from_secs));
+        Self { received_at, ttl }
+    }
+
+    /// Whether a response sent at `now` would arrive after the gateway gave up
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.ttl
+            .is_some_and(|ttl| now.duration_since(self.received_at) >= ttl)
+    }
+}
+
+/// Whether a connect error says the challenge expired
+pub fn is_nonce_expired(frame: &serde_json::Value) -> bool {
+    let error = &frame["error"];
+    if let Some(code) = error["code"].as_str() {
+        if NONCE_EXPIRED_CODES.contains(&code.to_uppercase().as_str()) {
+            return true;
+        }
+    }
+    error["message"].as_str().is_some_and(|m| {
+        let m = m.to_lowercase();
+        (m.contains("nonce") || m.contains("challenge")) && m.contains("expired")
+    })
+}
+
+/// Request frame asking the gateway for a new challenge
+pub fn refresh_request(id: &str) -> String {
+    serde_json::json!({
+        "type": "req",
+        "id": id,
+        "method": "connect.refresh",
+        "params": {}
+    })
+    .to_string()
+}
+
+/// Recently signed nonces
+#[derive(Default)]
+pub struct NonceHistory {
+    seen: VecDeque<String>,
+}
+
+impl NonceHistory {
+    /// Record a nonce about to be signed; false if it was signed before
+    pub fn record(&mut self, nonce: &str) -> bool {
+        if self.seen.iter().any(|n| n == nonce) {
+            return false;
+        }
+        if self.seen.len() == NONCE_HISTORY {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(nonce.to_string());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge_expiry() {
+        let now = Instant::now();
+        let frame = serde_json::json!({
+            "event": "connect.challenge",
+            "payload": {"nonce": "n", "ttlMs": 500}
+        });
+        let challenge = Challenge::parse(&frame, now);
+        assert!(!challenge.is_expired(now + Duration::from_millis(100)));
+        assert!(challenge.is_expired(now + Duration::from_millis(500)));
+
+        let no_ttl = Challenge::parse(&serde_json::json!({"payload": {"nonce": "n"}}), now);
+        assert!(!no_ttl.is_expired(now + Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_is_nonce_expired() {
+        let coded = serde_json::json!({"ok": false, "error": {"code": "nonce_expired"}});
+        assert!(is_nonce_expired(&coded));
+        let worded = serde_json::json!({"ok": false, "error": {"message": "Challenge expired"}});
+        assert!(is_nonce_expired(&worded));
+        let other = serde_json::json!({"ok": false, "error": {"code": "UNAUTHORIZED"}});
+        assert!(!is_nonce_expired(&other));
+    }
+
+    #[test]
+    fn test_nonce_history_rejects_repeats() {
+        let mut history = NonceHistory::default();
+        assert!(history.record("a"));
+        assert!(history.record("b"));
+        assert!(!history.record("a"));
+
+        for i in 0..NONCE_HISTORY {
+            assert!(history.record(&format!("n{}", i)));
+        }
+        // Old enough to have been forgotten
+        assert!(history.record("a"));
+    }
+}
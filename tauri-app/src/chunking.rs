@@ -0,0 +1,96 @@
+// Splitting oversized chat messages
+// Chunks break on paragraph boundaries where possible, then lines, then
+// characters, and always concatenate back to the original text.
+
+use std::mem;
+
+/// Default largest chat message sent as a single `chat.send`
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+pub fn default_max_message_bytes() -> usize {
+    DEFAULT_MAX_MESSAGE_BYTES
+}
+
+/// Split `text` into chunks of at most `max_bytes` bytes, in order
+pub fn split_message(text: &str, max_bytes: usize) -> Vec<String> {
+    if text.len() <= max_bytes || max_bytes == 0 {
+        return vec![text.to_string()];
+    }
+    split_on(text, max_bytes, &["\n\n", "\n"])
+}
+
+fn split_on(text: &str, max_bytes: usize, separators: &[&str]) -> Vec<String> {
+    let Some((separator, finer)) = separators.split_first() else {
+        return split_chars(text, max_bytes);
+    };
+
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for piece in text.split_inclusive(separator) {
+        if !current.is_empty() && current.len() + piece.len() > max_bytes {
+            chunks.push(mem::take(&mut current));
+        }
+        if piece.len() > max_bytes {
+            chunks.extend(split_on(piece, max_bytes, finer));
+        } else {
+            current.push_str(piece);
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+fn split_chars(text: &str, max_bytes: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for c in text.chars() {
+        if current.len() + c.len_utf8() > max_bytes {
+            chunks.push(mem::take(&mut current));
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(text: &str, max_bytes: usize) -> Vec<String> {
+        let chunks = split_message(text, max_bytes);
+        assert_eq!(chunks.concat(), text);
+        assert!(chunks.iter().all(|c| c.len() <= max_bytes));
+        chunks
+    }
+
+    #[test]
+    fn test_short_message_untouched() {
+        assert_eq!(split_message("hello", 10), vec!["hello".to_string()]);
+    }
+
+    #[test]
+    fn test_splits_on_paragraphs() {
+        let chunks = check("aaaa\n\nbbbb\n\ncccc", 12);
+        assert_eq!(chunks, vec!["aaaa\n\nbbbb\n\n", "cccc"]);
+    }
+
+    #[test]
+    fn test_long_paragraph_falls_back_to_lines_then_chars() {
+        let chunks = check("short\n\nline one\nline two\n", 10);
+        assert_eq!(chunks, vec!["short\n\n", "line one\n", "line two\n"]);
+
+        let chunks = check(&"x".repeat(25), 10);
+        assert_eq!(chunks.len(), 3);
+    }
+
+    #[test]
+    fn test_never_splits_inside_a_character() {
+        let chunks = check(&"é".repeat(5), 3);
+        assert!(chunks.iter().all(|c| c == "é"));
+    }
+}
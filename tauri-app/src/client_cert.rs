@@ -0,0 +1,215 @@
+// TLS client certificates for wss gateways
+// Loaded from PEM files (certificate plus PKCS#8 key) or a PKCS#12 bundle.
+// Everything is read and checked up front so a bad path or passphrase fails
+// `connect_websocket` directly instead of surfacing as a handshake error
+// inside the retry loop.
+
+use native_tls::{Identity, TlsConnector};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio_tungstenite::Connector;
+
+/// Warn when the certificate expires within this many days
+pub const EXPIRY_WARNING_DAYS: i64 = 14;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientCertConfig {
+    /// PEM certificate (chain), used with `key_path`
+    #[serde(default)]
+    pub cert_path: Option<PathBuf>,
+    /// PEM PKCS#8 private key
+    #[serde(default)]
+    pub key_path: Option<PathBuf>,
+    /// PKCS#12 bundle, as an alternative to the PEM pair
+    #[serde(default)]
+    pub pkcs12_path: Option<PathBuf>,
+    #[serde(default)]
+    pub pkcs12_password: Option<String>,
+}
+
+impl ClientCertConfig {
+    pub fn is_configured(&self) -> bool {
+        self.cert_path.is_some() || self.key_path.is_some() || self.pkcs12_path.is_some()
+    }
+}
+
+/// Expiry of the loaded certificate, reported by `get_ws_stats`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CertExpiry {
+    pub subject: String,
+    /// Unix seconds
+    pub not_after: i64,
+    pub days_remaining: i64,
+    pub expiring: bool,
+}
+
+impl CertExpiry {
+    fn new(subject: String, not_after: i64, now: i64) -> Self {
+        let days_remaining = (not_after - now).div_euclid(24 * 60 * 60);
+        Self {
+            subject,
+            not_after,
+            days_remaining,
+            expiring: days_remaining < EXPIRY_WARNING_DAYS,
+        }
+    }
+}
+
+/// A certificate ready to present during the TLS handshake
+#[derive(Clone)]
+pub struct ClientCert {
+    pub connector: Connector,
+    pub expiry: CertExpiry,
+}
+
+fn read(path: &Path, what: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("Cannot read client {} {}: {}", what, path.display(), e))
+}
+
+/// Subject and notAfter of a DER certificate
+fn inspect_der(der: &[u8], now: i64) -> Result<CertExpiry, String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der)
+        .map_err(|e| format!("Client certificate is not valid X.509: {}", e))?;
+    Ok(CertExpiry::new(
+        cert.subject().to_string(),
+        cert.validity().not_after.timestamp(),
+        now,
+    ))
+}
+
+/// Load the configured certificate; `Ok(None)` when none is configured
+pub fn load(config: &ClientCertConfig, now: i64) -> Result<Option<ClientCert>, String> {
+    if !config.is_configured() {
+        return Ok(None);
+    }
+
+    let (identity, expiry) = match (&config.cert_path, &config.key_path, &config.pkcs12_path) {
+        (Some(cert_path), Some(key_path), None) => {
+            let cert_pem = read(cert_path, "certificate")?;
+            let key_pem = read(key_path, "key")?;
+            let (_, pem) = x509_parser::pem::parse_x509_pem(&cert_pem).map_err(|e| {
+                format!(
+                    "Client certificate {} is not PEM: {}",
+                    cert_path.display(),
+                    e
+                )
+            })?;
+            let expiry = inspect_der(&pem.contents, now)?;
+            let identity = Identity::from_pkcs8(&cert_pem, &key_pem).map_err(|e| {
+                format!(
+                    "Client key {} does not match {} or is not a PEM PKCS#8 key: {}",
+                    key_path.display(),
+                    cert_path.display(),
+                    e
+                )
+            })?;
+            (identity, expiry)
+        }
+        (None, None, Some(pkcs12_path)) => {
+            let der = read(pkcs12_path, "PKCS#12 bundle")?;
+            let password = config.pkcs12_password.as_deref().unwrap_or("");
+            let identity = Identity::from_pkcs12(&der, password).map_err(|e| {
+                format!(
+                    "Cannot open PKCS#12 bundle {} (wrong passphrase?): {}",
+                    pkcs12_path.display(),
+                    e
+                )
+            })?;
+            let certs = p12::PFX::parse(&der)
+                .and_then(|pfx| pfx.cert_x509_bags(password))
+                .map_err(|e| {
+                    format!(
+                        "Cannot read certificate from {}: {:?}",
+                        pkcs12_path.display(),
+                        e
+                    )
+                })?;
+            let leaf = certs.first().ok_or_else(|| {
+                format!(
+                    "PKCS#12 bundle {} has no certificate",
+                    pkcs12_path.display()
+                )
+            })?;
+            (identity, inspect_der(leaf, now)?)
+        }
+        (Some(_), None, None) => {
+            return Err("client_cert.cert_path is set but key_path is missing".to_string())
+        }
+        (None, Some(_), None) => {
+            return Err("client_cert.key_path is set but cert_path is missing".to_string())
+        }
+        _ => {
+            return Err(
+                "Set either cert_path and key_path, or pkcs12_path, in client_cert, not both"
+                    .to_string(),
+            )
+        }
+    };
+
+    if expiry.not_after <= now {
+        return Err(format!(
+            "Client certificate '{}' expired {} days ago",
+            expiry.subject, -expiry.days_remaining
+        ));
+    }
+
+    let connector = TlsConnector::builder()
+        .identity(identity)
+        .build()
+        .map_err(|e| format!("Failed to set up TLS with the client certificate: {}", e))?;
+
+    Ok(Some(ClientCert {
+        connector: Connector::NativeTls(connector),
+        expiry,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unconfigured_is_none() {
+        assert!(load(&ClientCertConfig::default(), 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_errors_name_the_problem() {
+        let missing = ClientCertConfig {
+            cert_path: Some(PathBuf::from("/nonexistent/client.pem")),
+            key_path: Some(PathBuf::from("/nonexistent/client.key")),
+            ..Default::default()
+        };
+        let err = load(&missing, 0).err().unwrap();
+        assert!(err.contains("/nonexistent/client.pem"), "{}", err);
+
+        let half = ClientCertConfig {
+            cert_path: Some(PathBuf::from("/tmp/client.pem")),
+            ..Default::default()
+        };
+        assert!(load(&half, 0)
+            .err()
+            .unwrap()
+            .contains("key_path is missing"));
+
+        let both = ClientCertConfig {
+            cert_path: Some(PathBuf::from("/tmp/client.pem")),
+            key_path: Some(PathBuf::from("/tmp/client.key")),
+            pkcs12_path: Some(PathBuf::from("/tmp/client.p12")),
+            ..Default::default()
+        };
+        assert!(load(&both, 0).err().unwrap().contains("not both"));
+    }
+
+    #[test]
+    fn test_expiry_warning() {
+        let day = 24 * 60 * 60;
+        let soon = CertExpiry::new("CN=op".to_string(), 10 * day, 0);
+        assert_eq!(soon.days_remaining, 10);
+        assert!(soon.expiring);
+
+        let later = CertExpiry::new("CN=op".to_string(), 90 * day, 0);
+        assert!(!later.expiring);
+    }
+}
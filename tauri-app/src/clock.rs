@@ -0,0 +1,65 @@
+// Clock-skew handling for device auth
+// Gateways reject a connect whose signedAt is too far from their own clock, so
+// we learn an offset from the server's time hints and apply it when signing.
+
+/// Largest correction we will apply; beyond this the local clock is simply wrong
+pub const MAX_CLOCK_CORRECTION_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// Skew below this is left alone (network latency, gateways allow about a minute)
+pub const SKEW_TOLERANCE_MS: i64 = 30 * 1000;
+
+/// Error codes a gateway uses when signedAt is outside its acceptance window
+const SKEW_ERROR_CODES: &[&str] = &["CLOCK_SKEW", "SIGNATURE_EXPIRED", "TIMESTAMP_OUT_OF_RANGE"];
+
+/// Milliseconds since the Unix epoch according to the local clock
+pub fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// Local time corrected by the learned offset, as used for `signedAt`
+pub fn corrected_millis(offset_ms: i64) -> u64 {
+    (now_millis() + offset_ms).max(0) as u64
+}
+
+/// Whether a failed connect response blames our timestamp
+pub fn is_skew_error(frame: &serde_json::Value) -> bool {
+    let error = &frame["error"];
+    if let Some(code) = error["code"].as_str() {
+        if SKEW_ERROR_CODES.contains(&code.to_uppercase().as_str()) {
+            return true;
+        }
+    }
+    error["message"]
+        .as_str()
+        .map(|m| {
+            let m = m.to_lowercase();
+            m.contains("skew") || m.contains("signedat") || m.contains("clock")
+        })
+        .unwrap_or(false)
+}
+
+/// Pull the server's clock (ms since epoch) out of an error or challenge frame
+pub fn extract_server_time(frame: &serde_json::Value) -> Option<i64> {
+    [
+        &frame["error"]["details"]["serverTime"],
+        &frame["error"]["data"]["serverTime"],
+        &frame["error"]["serverTime"],
+        &frame["payload"]["serverTime"],
+        &frame["serverTime"],
+    ]
+    .iter()
+    .find_map(|v| v.as_i64())
+}
+
+/// Offset to add to the local clock, or `Err(offset)` if it exceeds the cap
+pub fn compute_offset(server_ms: i64, local_ms: i64) -> Result<i64, i64> {
+    let offset = server_ms - local_ms;
+    if offset.abs() > MAX_CLOCK_CORRECTION_MS {
+        Err(offset)
+    } else {
+        Ok(offset)
+    }
+}
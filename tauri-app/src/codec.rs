@@ -0,0 +1,200 @@
+// Gateway frame encoding
+// Frames are JSON text by default. If the gateway confirms the `msgpack`
+// capability during connect, outgoing frames are sent as msgpack binary.
+// Incoming frames may use either encoding, and both decode to the same `Frame`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tungstenite::Message;
+
+/// Capability advertised in the connect request's `caps`
+pub const CAP_MSGPACK: &str = "msgpack";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MsgPack,
+}
+
+impl Encoding {
+    /// Encoding to use after a successful connect response
+    pub fn negotiated(connect_response: &Value) -> Self {
+        let payload = &connect_response["payload"];
+        let confirmed = ["caps", "features"].iter().any(|k| {
+            payload[*k]
+                .as_array()
+                .is_some_and(|caps| caps.iter().any(|c| c.as_str() == Some(CAP_MSGPACK)))
+        });
+        if confirmed {
+            Encoding::MsgPack
+        } else {
+            Encoding::Json
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MsgPack => "msgpack",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Frame {
+    Req {
+        /// Absent for notifications such as `chat.typing`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        method: String,
+        #[serde(default)]
+        params: Value,
+    },
+    Res {
+        id: String,
+        ok: bool,
+        #[serde(default, skip_serializing_if = "Value::is_null")]
+        payload: Value,
+        #[serde(default, skip_serializing_if = "Value::is_null")]
+        error: Value,
+    },
+    Event {
+        event: String,
+        #[serde(default)]
+        payload: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+}
+
+impl Frame {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, String> {
+        // Named fields so the gateway sees maps, not positional arrays
+        rmp_serde::to_vec_named(self).map_err(|e| format!("msgpack encode failed: {}", e))
+    }
+
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self, String> {
+        rmp_serde::from_slice(bytes).map_err(|e| format!("msgpack decode failed: {}", e))
+    }
+}
+
+/// Wrap an outgoing JSON frame for the wire
+///
+/// Anything that doesn't parse as a `Frame` goes out as text unchanged.
+pub fn encode(text: String, encoding: Encoding) -> Message {
+    if encoding == Encoding::MsgPack {
+        let bytes = serde_json::from_str::<Frame>(&text)
+            .map_err(|e| e.to_string())
+            .and_then(|frame| frame.to_msgpack());
+        match bytes {
+            Ok(bytes) => return Message::Binary(bytes),
+            Err(e) => eprintln!("[WS] Sending frame as text: {}", e),
+        }
+    }
+    Message::Text(text)
+}
+
+/// JSON text of an incoming data frame, whichever encoding it arrived in
+///
+/// Returns `None` for control frames and for binary frames that don't decode.
+pub fn incoming_text(msg: &Message) -> Option<String> {
+    match msg {
+        Message::Text(text) => Some(text.clone()),
+        Message::Binary(bytes) => match Frame::from_msgpack(bytes) {
+            Ok(frame) => Some(frame.to_json()),
+            Err(e) => {
+                eprintln!("[WS] Dropping binary frame: {}", e);
+                None
+            }
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples() -> Vec<Frame> {
+        vec![
+            Frame::Req {
+                id: Some("msg-1".to_string()),
+                method: "chat.send".to_string(),
+                params: serde_json::json!({"sessionKey": "main", "message": "hi"}),
+            },
+            Frame::Req {
+                id: None,
+                method: "chat.typing".to_string(),
+                params: serde_json::json!({"sessionKey": "main", "active": true}),
+            },
+            Frame::Res {
+                id: "rpc-2".to_string(),
+                ok: true,
+                payload: serde_json::json!({"agents": [{"id": "a1"}]}),
+                error: Value::Null,
+            },
+            Frame::Res {
+                id: "rpc-3".to_string(),
+                ok: false,
+                payload: Value::Null,
+                error: serde_json::json!({"code": "NOT_FOUND", "message": "nope"}),
+            },
+            Frame::Event {
+                event: "chat.message".to_string(),
+                payload: serde_json::json!({"role": "assistant", "message": "hello", "ts": 1}),
+                seq: Some(7),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_roundtrip_json() {
+        for frame in samples() {
+            let decoded: Frame = serde_json::from_str(&frame.to_json()).unwrap();
+            assert_eq!(decoded, frame);
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_msgpack() {
+        for frame in samples() {
+            let decoded = Frame::from_msgpack(&frame.to_msgpack().unwrap()).unwrap();
+            assert_eq!(decoded, frame);
+        }
+    }
+
+    #[test]
+    fn test_wire_encodings_agree() {
+        for frame in samples() {
+            let text = frame.to_json();
+            assert_eq!(
+                encode(text.clone(), Encoding::Json),
+                Message::Text(text.clone())
+            );
+
+            let binary = encode(text, Encoding::MsgPack);
+            assert!(binary.is_binary());
+            let back: Frame = serde_json::from_str(&incoming_text(&binary).unwrap()).unwrap();
+            assert_eq!(back, frame);
+        }
+        // Text still decodes after msgpack was negotiated
+        let text = Message::Text(r#"{"type":"event","event":"tick","payload":{}}"#.to_string());
+        assert!(incoming_text(&text).is_some());
+        assert!(incoming_text(&Message::Binary(vec![0xc1])).is_none());
+    }
+
+    #[test]
+    fn test_negotiated() {
+        let confirmed =
+            serde_json::json!({"type": "res", "ok": true, "payload": {"caps": ["msgpack"]}});
+        assert_eq!(Encoding::negotiated(&confirmed), Encoding::MsgPack);
+        let plain = serde_json::json!({"type": "res", "ok": true, "payload": {}});
+        assert_eq!(Encoding::negotiated(&plain), Encoding::Json);
+    }
+}
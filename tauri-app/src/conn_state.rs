@@ -0,0 +1,52 @@
+// Gateway connection state machine
+// One source of truth for the `ws-state-changed` event and the tray icon.
+
+use crate::connections::ConnEvents;
+use serde::Serialize;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnState {
+    Disconnected,
+    Connecting,
+    Authenticating,
+    Ready,
+    /// Closed after `idle_timeout_minutes` without user activity
+    Idle,
+    Error,
+}
+
+impl ConnState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnState::Disconnected => "disconnected",
+            ConnState::Connecting => "connecting",
+            ConnState::Authenticating => "authenticating",
+            ConnState::Ready => "ready",
+            ConnState::Idle => "idle",
+            ConnState::Error => "error",
+        }
+    }
+}
+
+/// Move to `next`, emitting `ws-state-changed` only on an actual transition
+pub fn transition(events: &ConnEvents, states: &watch::Sender<ConnState>, next: ConnState) {
+    let previous = states.send_replace(next);
+    if previous == next {
+        return;
+    }
+    eprintln!(
+        "[WS] {} state: {} -> {}",
+        events.id(),
+        previous.as_str(),
+        next.as_str()
+    );
+    events.emit(
+        "ws-state-changed",
+        serde_json::json!({
+            "state": next,
+            "previous": previous,
+        }),
+    );
+}
@@ -0,0 +1,206 @@
+// Gateway connections
+// Several gateways can be connected at once, e.g. staging next to production.
+// Each connection has its own socket task, queues, reconnect loop and device
+// profile. Its events are emitted as `<event>:<connection id>` so the UI can
+// route them to the right pane; the `default` connection also emits under the
+// plain names, which is all a single-gateway UI listens to.
+
+use crate::conn_state::ConnState;
+use crate::{agents, mock, outbox, ratelimit, receipts, rpc, transport, typing};
+use serde::Serialize;
+use std::sync::atomic::AtomicI64;
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+
+/// Connection used when a command doesn't name one
+pub const DEFAULT_CONNECTION: &str = "default";
+
+const MAX_ID_LEN: usize = 64;
+
+/// Connection ids end up in event names, so keep them to safe characters
+pub fn validate_connection_id(id: &str) -> Result<(), String> {
+    if id.is_empty() || id.len() > MAX_ID_LEN {
+        return Err(format!(
+            "Connection id must be 1-{} characters long",
+            MAX_ID_LEN
+        ));
+    }
+    if !id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "Invalid connection id '{}': use letters, digits, '-' and '_'",
+            id
+        ));
+    }
+    Ok(())
+}
+
+/// The connection a command applies to
+pub fn resolve_id(id: Option<&str>) -> Result<&str, String> {
+    let id = id.unwrap_or(DEFAULT_CONNECTION);
+    validate_connection_id(id)?;
+    Ok(id)
+}
+
+/// Name a connection's event is emitted under
+pub fn event_name(event: &str, connection_id: &str) -> String {
+    format!("{}:{}", event, connection_id)
+}
+
+/// Emits events on behalf of one connection
+#[derive(Clone)]
+pub struct ConnEvents {
+    app: AppHandle,
+    id: String,
+    last_error: Arc<Mutex<Option<String>>>,
+}
+
+impl ConnEvents {
+    pub fn app(&self) -> &AppHandle {
+        &self.app
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn emit<S: Serialize + Clone>(&self, event: &str, payload: S) {
+        let _ = self.app.emit(&event_name(event, &self.id), payload.clone());
+        if self.id == DEFAULT_CONNECTION {
+            let _ = self.app.emit(event, payload);
+        }
+    }
+
+    /// Emit `ws-error`, keeping the payload for diagnostics
+    pub fn error<S: Serialize + Clone>(&self, payload: S) {
+        if let Ok(text) = serde_json::to_string(&payload) {
+            *self.last_error.lock().unwrap() = Some(text);
+        }
+        self.emit("ws-error", payload);
+    }
+}
+
+/// What a connection was last asked to connect to, reused to wake from idle
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub url: String,
+    /// Device profile; the configured one when unset
+    pub profile: Option<String>,
+    /// Talk to an in-process mock gateway instead of dialing `url`
+    pub mock: Option<mock::MockOptions>,
+}
+
+impl Target {
+    /// A real gateway, using the configured profile
+    pub fn gateway(url: String) -> Self {
+        Self {
+            url,
+            profile: None,
+            mock: None,
+        }
+    }
+}
+
+pub struct Connection {
+    pub id: String,
+    pub events: ConnEvents,
+    pub ws_sender: tokio::sync::Mutex<Option<Sender<String>>>,
+    /// Learned difference between the gateway's clock and ours, in milliseconds
+    pub clock_offset_ms: AtomicI64,
+    /// Flipped to `true` to make the connection task close the socket and stop
+    pub ws_shutdown: watch::Sender<bool>,
+    pub ws_task: tokio::sync::Mutex<Option<tokio::task::JoinHandle<()>>>,
+    pub outbox_pressure: outbox::Backpressure,
+    pub rate_limiter: Mutex<ratelimit::RateLimiter>,
+    /// Commands waiting on a gateway response
+    pub pending_requests: rpc::PendingRequests,
+    /// Retry policy from the config, refreshed on each connect
+    pub rpc_policy: Mutex<rpc::RetryPolicy>,
+    pub agent_cache: tokio::sync::Mutex<Option<agents::AgentCache>>,
+    /// Connection state behind `ws-state-changed` (and the tray icon, for `default`)
+    pub conn_state: watch::Sender<ConnState>,
+    /// Our typing state per session, swept by the connection task
+    pub typing: Mutex<typing::TypingTracker>,
+    /// Keys of messages sent with `deliver`, to correlate receipts
+    pub sent_keys: Mutex<receipts::SentKeys>,
+    /// Our read position per session, flushed by the connection task
+    pub read_markers: Mutex<receipts::ReadMarkers>,
+    /// Handshake parameters of the live connection, for `get_ws_stats`
+    pub negotiated: Mutex<Option<transport::Negotiated>>,
+    /// Protocol version picked in the connect response
+    pub protocol: Mutex<Option<u32>>,
+    pub target: Mutex<Option<Target>>,
+    /// Serialises wake-ups so concurrent sends reconnect only once
+    pub wake_lock: tokio::sync::Mutex<()>,
+    /// Payload of the most recent `ws-error` event, for diagnostics
+    pub last_error: Arc<Mutex<Option<String>>>,
+}
+
+impl Connection {
+    pub fn new(app: &AppHandle, id: &str) -> Self {
+        let last_error = Arc::new(Mutex::new(None));
+        Self {
+            id: id.to_string(),
+            events: ConnEvents {
+                app: app.clone(),
+                id: id.to_string(),
+                last_error: last_error.clone(),
+            },
+            ws_sender: tokio::sync::Mutex::new(None),
+            clock_offset_ms: AtomicI64::new(0),
+            ws_shutdown: watch::channel(false).0,
+            ws_task: tokio::sync::Mutex::new(None),
+            outbox_pressure: outbox::Backpressure::default(),
+            rate_limiter: Mutex::new(ratelimit::RateLimiter::new(
+                ratelimit::RateLimitConfig::default(),
+            )),
+            pending_requests: rpc::PendingRequests::default(),
+            rpc_policy: Mutex::new(rpc::RetryPolicy::default()),
+            agent_cache: tokio::sync::Mutex::new(None),
+            conn_state: watch::channel(ConnState::Disconnected).0,
+            typing: Mutex::new(typing::TypingTracker::default()),
+            sent_keys: Mutex::new(receipts::SentKeys::default()),
+            read_markers: Mutex::new(receipts::ReadMarkers::default()),
+            negotiated: Mutex::new(None),
+            protocol: Mutex::new(None),
+            target: Mutex::new(None),
+            wake_lock: tokio::sync::Mutex::new(()),
+            last_error,
+        }
+    }
+
+    pub fn state(&self) -> ConnState {
+        *self.conn_state.borrow()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_connection_id() {
+        assert!(validate_connection_id("default").is_ok());
+        assert!(validate_connection_id("prod_eu-1").is_ok());
+        assert!(validate_connection_id("").is_err());
+        assert!(validate_connection_id("staging gateway").is_err());
+        assert!(validate_connection_id("a:b").is_err());
+        assert!(validate_connection_id(&"x".repeat(MAX_ID_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_resolve_id_defaults() {
+        assert_eq!(resolve_id(None).unwrap(), DEFAULT_CONNECTION);
+        assert_eq!(resolve_id(Some("staging")).unwrap(), "staging");
+        assert!(resolve_id(Some("../x")).is_err());
+    }
+
+    #[test]
+    fn test_event_name() {
+        assert_eq!(event_name("ws-message", "staging"), "ws-message:staging");
+    }
+}
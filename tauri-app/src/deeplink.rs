@@ -0,0 +1,163 @@
+// claw-pen:// deep links
+// e.g. claw-pen://connect?gateway=wss://gw.example.com/ws&session=incident-42
+// Links come from outside the app, so anything unexpected is rejected outright.
+
+use serde::Serialize;
+use tauri::Url;
+
+use crate::transport::GatewayTarget;
+
+pub const SCHEME: &str = "claw-pen";
+
+pub const MAX_SESSION_KEY_LENGTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeepLink {
+    pub action: String,
+    pub gateway: Option<String>,
+    pub session: Option<String>,
+    pub autoconnect: bool,
+}
+
+/// Payload of the `deep-link` event
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeepLinkEvent {
+    #[serde(flatten)]
+    pub link: DeepLink,
+    /// The app is connecting to the linked gateway on its own
+    pub autoconnecting: bool,
+    /// A connection is already active; the UI must ask before acting on the link
+    pub requires_confirmation: bool,
+}
+
+/// Validate a session key; same character set as agent ids
+pub fn validate_session_key(key: &str) -> Result<(), String> {
+    if key.is_empty() {
+        return Err("Session key cannot be empty".to_string());
+    }
+
+    if key.len() > MAX_SESSION_KEY_LENGTH {
+        return Err("Session key too long".to_string());
+    }
+
+    let valid = key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == ':' || c == '_');
+
+    if !valid {
+        return Err("Session key contains invalid characters".to_string());
+    }
+
+    Ok(())
+}
+
+/// Only plain ws/wss gateways may come from a link, never a local socket
+fn validate_gateway_url(url: &str) -> Result<(), String> {
+    if !(url.starts_with("ws://") || url.starts_with("wss://")) {
+        return Err("Gateway URL must use ws:// or wss://".to_string());
+    }
+    match GatewayTarget::parse(url)? {
+        GatewayTarget::Tcp { .. } => Ok(()),
+        GatewayTarget::Unix { .. } => Err("Gateway URL must use ws:// or wss://".to_string()),
+    }
+}
+
+/// Parse and validate a deep link
+pub fn parse(url: &Url) -> Result<DeepLink, String> {
+    if url.scheme() != SCHEME {
+        return Err(format!("Unsupported scheme: {}", url.scheme()));
+    }
+
+    let action = url.host_str().unwrap_or_default();
+    if action != "connect" {
+        return Err(format!("Unsupported deep link action: {}", action));
+    }
+
+    let mut link = DeepLink {
+        action: action.to_string(),
+        gateway: None,
+        session: None,
+        autoconnect: false,
+    };
+
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "gateway" if link.gateway.is_none() => {
+                validate_gateway_url(&value)?;
+                link.gateway = Some(value.into_owned());
+            }
+            "session" if link.session.is_none() => {
+                validate_session_key(&value)?;
+                link.session = Some(value.into_owned());
+            }
+            "autoconnect" => link.autoconnect = value == "true",
+            other => return Err(format!("Unexpected deep link parameter: {}", other)),
+        }
+    }
+
+    if link.gateway.is_none() && link.session.is_none() {
+        return Err("Deep link has neither a gateway nor a session".to_string());
+    }
+
+    Ok(link)
+}
+
+/// Whether two gateway URLs name the same endpoint
+pub fn same_gateway(a: &str, b: &str) -> bool {
+    a.trim_end_matches('/')
+        .eq_ignore_ascii_case(b.trim_end_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(s: &str) -> Result<DeepLink, String> {
+        parse(&Url::parse(s).unwrap())
+    }
+
+    #[test]
+    fn test_parse_connect_link() {
+        let link = parse_str(
+            "claw-pen://connect?gateway=wss://gw.example.com/ws&session=incident-42&autoconnect=true",
+        )
+        .unwrap();
+        assert_eq!(link.gateway.as_deref(), Some("wss://gw.example.com/ws"));
+        assert_eq!(link.session.as_deref(), Some("incident-42"));
+        assert!(link.autoconnect);
+
+        let link = parse_str("claw-pen://connect?session=main").unwrap();
+        assert_eq!(link.gateway, None);
+        assert!(!link.autoconnect);
+    }
+
+    #[test]
+    fn test_reject_malformed_links() {
+        assert!(parse_str("https://connect?session=main").is_err());
+        assert!(parse_str("claw-pen://delete?session=main").is_err());
+        assert!(parse_str("claw-pen://connect").is_err());
+        assert!(parse_str("claw-pen://connect?gateway=http://evil.example.com").is_err());
+        assert!(parse_str("claw-pen://connect?gateway=ws%2Bunix:///var/run/gw.sock").is_err());
+        assert!(parse_str("claw-pen://connect?session=../../etc").is_err());
+        assert!(parse_str("claw-pen://connect?session=main&exec=rm").is_err());
+        // A second gateway can't sneak in after a valid one
+        assert!(parse_str(
+            "claw-pen://connect?gateway=wss://a.example.com/ws&gateway=wss://b.example.com/ws"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_same_gateway() {
+        assert!(same_gateway(
+            "wss://GW.example.com/ws/",
+            "wss://gw.example.com/ws"
+        ));
+        assert!(!same_gateway(
+            "wss://gw.example.com/ws",
+            "wss://other.example.com/ws"
+        ));
+    }
+}
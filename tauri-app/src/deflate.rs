@@ -0,0 +1,650 @@
+// permessage-deflate (RFC 7692) for incoming gateway messages
+// tungstenite refuses frames with RSV1 set and has no inflater, so this sits
+// between the socket (after TLS) and tungstenite. It reads the server's
+// answer to our offer from the upgrade response and, if the extension was
+// accepted, rewrites every compressed message into plain frames before
+// tungstenite sees it. Our own messages go out uncompressed, which the RFC
+// allows, so there is no deflate side.
+
+use flate2::{Decompress, FlushDecompress, Status};
+use serde::Serialize;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// What we put in `Sec-WebSocket-Extensions`
+pub const OFFER: &str = "permessage-deflate";
+
+const EXTENSION: &str = "permessage-deflate";
+
+/// Trailer the sender strips from every compressed message
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Longest upgrade response we buffer while looking for its end
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+
+/// Error put inside the `io::Error` when the gateway breaks the extension or
+/// our limits, so it can be told apart from the connection dropping
+#[derive(Debug)]
+pub struct DeflateError(pub String);
+
+impl std::fmt::Display for DeflateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DeflateError {}
+
+fn protocol_error(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, DeflateError(message.into()))
+}
+
+/// Parameters the gateway accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DeflateParams {
+    /// The gateway resets its compressor after every message
+    pub server_no_context_takeover: bool,
+    /// Window the gateway compresses with; informational, as we always
+    /// inflate with the largest one
+    pub server_max_window_bits: u8,
+}
+
+/// Read the `Sec-WebSocket-Extensions` response header
+///
+/// `Ok(None)` when it doesn't enable permessage-deflate. Anything we didn't
+/// offer (another extension, or `client_max_window_bits`) is an error, since
+/// the RFC has the client fail the connection then.
+pub fn parse_response(header: &str) -> Result<Option<DeflateParams>, String> {
+    let mut accepted = None;
+    for extension in header.split(',').filter(|e| !e.trim().is_empty()) {
+        let mut parts = extension.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        if !name.eq_ignore_ascii_case(EXTENSION) {
+            return Err(format!(
+                "Gateway enabled extension '{}' that we didn't offer",
+                name
+            ));
+        }
+        if accepted.is_some() {
+            return Err("Gateway enabled permessage-deflate twice".to_string());
+        }
+
+        let mut params = DeflateParams {
+            server_no_context_takeover: false,
+            server_max_window_bits: 15,
+        };
+        for param in parts.filter(|p| !p.is_empty()) {
+            let (key, value) = match param.split_once('=') {
+                Some((key, value)) => (key.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            match (key.to_ascii_lowercase().as_str(), value) {
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                // Only limits our own compressor, and we don't compress
+                ("client_no_context_takeover", None) => {}
+                ("server_max_window_bits", Some(bits)) => {
+                    params.server_max_window_bits = bits
+                        .parse()
+                        .ok()
+                        .filter(|b| (8..=15).contains(b))
+                        .ok_or_else(|| format!("Invalid server_max_window_bits '{}'", bits))?;
+                }
+                _ => {
+                    return Err(format!(
+                        "Unexpected permessage-deflate parameter '{}'",
+                        param
+                    ))
+                }
+            }
+        }
+        accepted = Some(params);
+    }
+    Ok(accepted)
+}
+
+/// Status code and `Sec-WebSocket-Extensions` value of an HTTP response head
+fn read_response_head(head: &[u8]) -> (Option<u16>, Option<String>) {
+    let text = String::from_utf8_lossy(head);
+    let mut lines = text.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok());
+    let extensions: Vec<&str> = lines
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-extensions"))
+        .map(|(_, value)| value.trim())
+        .collect();
+    (
+        status,
+        (!extensions.is_empty()).then(|| extensions.join(", ")),
+    )
+}
+
+/// Header of one WebSocket frame
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    masked: bool,
+    payload_len: u64,
+    /// Bytes the header itself takes, mask included
+    len: usize,
+}
+
+impl FrameHeader {
+    /// Parse a header from the start of `data`; `None` until it is all there
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (&first, &second) = (data.first()?, data.get(1)?);
+        let masked = second & 0x80 != 0;
+        let (payload_len, mut len) = match second & 0x7f {
+            126 => (
+                u16::from_be_bytes(data.get(2..4)?.try_into().ok()?) as u64,
+                4,
+            ),
+            127 => (u64::from_be_bytes(data.get(2..10)?.try_into().ok()?), 10),
+            short => (short as u64, 2),
+        };
+        if masked {
+            len += 4;
+        }
+        if data.len() < len {
+            return None;
+        }
+        Some(Self {
+            fin: first & 0x80 != 0,
+            rsv1: first & 0x40 != 0,
+            opcode: first & 0x0f,
+            masked,
+            payload_len,
+            len,
+        })
+    }
+}
+
+/// Append frames carrying `payload` to `out`, none longer than `max_frame_size`
+fn write_frames(opcode: u8, payload: &[u8], max_frame_size: usize, out: &mut Vec<u8>) {
+    let mut chunks: Vec<&[u8]> = payload.chunks(max_frame_size.max(1)).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    let last = chunks.len() - 1;
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let op = if i == 0 { opcode } else { OP_CONTINUATION };
+        out.push(if i == last { 0x80 | op } else { op });
+        match chunk.len() {
+            n if n < 126 => out.push(n as u8),
+            n if n <= u16::MAX as usize => {
+                out.push(126);
+                out.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                out.push(127);
+                out.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        out.extend_from_slice(chunk);
+    }
+}
+
+enum Phase {
+    /// Still reading the upgrade response
+    Handshake,
+    /// The extension is on: frames are parsed and compressed ones rewritten
+    Frames,
+    /// The extension is off: bytes go through untouched
+    Passthrough,
+}
+
+/// Rewrites the byte stream from the gateway, without any I/O of its own
+struct Rewriter {
+    phase: Phase,
+    params: DeflateParams,
+    max_message_size: usize,
+    max_frame_size: usize,
+    decompress: Decompress,
+    /// Bytes received but not yet handled
+    input: Vec<u8>,
+    /// Bytes ready for tungstenite
+    output: Vec<u8>,
+    /// Payload bytes of a frame we pass through that haven't arrived yet
+    passthrough_left: u64,
+    /// Opcode and compressed payload of a fragmented compressed message
+    message: Option<(u8, Vec<u8>)>,
+}
+
+impl Rewriter {
+    fn new(max_message_size: usize, max_frame_size: usize) -> Self {
+        Self {
+            phase: Phase::Handshake,
+            params: DeflateParams {
+                server_no_context_takeover: false,
+                server_max_window_bits: 15,
+            },
+            max_message_size,
+            max_frame_size,
+            decompress: Decompress::new(false),
+            input: Vec::new(),
+            output: Vec::new(),
+            passthrough_left: 0,
+            message: None,
+        }
+    }
+
+    /// Take in bytes from the gateway, moving whatever is complete to `output`
+    fn feed(&mut self, data: &[u8]) -> io::Result<()> {
+        self.input.extend_from_slice(data);
+        loop {
+            match self.phase {
+                Phase::Handshake => {
+                    let Some(end) = self.input.windows(4).position(|w| w == b"\r\n\r\n") else {
+                        if self.input.len() > MAX_RESPONSE_HEAD {
+                            return Err(protocol_error("Upgrade response headers are too long"));
+                        }
+                        return Ok(());
+                    };
+                    let head: Vec<u8> = self.input.drain(..end + 4).collect();
+                    let (status, extensions) = read_response_head(&head);
+                    self.output.extend_from_slice(&head);
+                    // A bad answer fails the handshake in `transport` instead
+                    let accepted = extensions.and_then(|e| parse_response(&e).ok().flatten());
+                    self.phase = match accepted {
+                        Some(params) if status == Some(101) => {
+                            self.params = params;
+                            Phase::Frames
+                        }
+                        _ => Phase::Passthrough,
+                    };
+                }
+                Phase::Passthrough => {
+                    self.output.append(&mut self.input);
+                    return Ok(());
+                }
+                Phase::Frames => {
+                    if !self.next_frame()? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle the frame at the start of `input`; `false` when more bytes are needed
+    fn next_frame(&mut self) -> io::Result<bool> {
+        if self.passthrough_left > 0 {
+            let n = self.input.len().min(self.passthrough_left as usize);
+            self.output.extend(self.input.drain(..n));
+            self.passthrough_left -= n as u64;
+            return Ok(self.passthrough_left == 0 && !self.input.is_empty());
+        }
+        let Some(header) = FrameHeader::parse(&self.input) else {
+            return Ok(false);
+        };
+
+        let compressed = match header.opcode {
+            OP_TEXT | OP_BINARY if header.rsv1 => {
+                if self.message.is_some() {
+                    return Err(protocol_error(
+                        "New message started before the compressed one finished",
+                    ));
+                }
+                true
+            }
+            OP_CONTINUATION if self.message.is_some() => {
+                if header.rsv1 {
+                    return Err(protocol_error("RSV1 set on a continuation frame"));
+                }
+                true
+            }
+            // Control frames, uncompressed messages and anything unknown are
+            // left for tungstenite to judge
+            _ => false,
+        };
+        if !compressed {
+            self.output.extend(self.input.drain(..header.len));
+            self.passthrough_left = header.payload_len;
+            return Ok(true);
+        }
+
+        if header.masked {
+            return Err(protocol_error("Gateway sent a masked frame"));
+        }
+        if header.payload_len > self.max_frame_size as u64 {
+            return Err(protocol_error(format!(
+                "Compressed frame of {} bytes exceeds max_frame_size {}",
+                header.payload_len, self.max_frame_size
+            )));
+        }
+        let end = header.len + header.payload_len as usize;
+        if self.input.len() < end {
+            return Ok(false);
+        }
+
+        let (opcode, mut payload) = self
+            .message
+            .take()
+            .unwrap_or_else(|| (header.opcode, Vec::new()));
+        payload.extend_from_slice(&self.input[header.len..end]);
+        self.input.drain(..end);
+        if payload.len() > self.max_message_size {
+            return Err(protocol_error(format!(
+                "Compressed message exceeds max_message_size {}",
+                self.max_message_size
+            )));
+        }
+        if !header.fin {
+            self.message = Some((opcode, payload));
+            return Ok(true);
+        }
+
+        let inflated = self.inflate(payload)?;
+        write_frames(opcode, &inflated, self.max_frame_size, &mut self.output);
+        Ok(true)
+    }
+
+    /// Decompress one whole message
+    fn inflate(&mut self, mut payload: Vec<u8>) -> io::Result<Vec<u8>> {
+        payload.extend_from_slice(&TAIL);
+        let mut out = Vec::with_capacity((payload.len() * 4).min(self.max_message_size + 1));
+        let mut consumed = 0;
+        loop {
+            if out.capacity() - out.len() < 1024 {
+                out.reserve(32 * 1024);
+            }
+            let (in_before, out_before) = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self
+                .decompress
+                .decompress_vec(&payload[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|e| protocol_error(format!("Invalid compressed message: {}", e)))?;
+            consumed += (self.decompress.total_in() - in_before) as usize;
+            let produced = self.decompress.total_out() - out_before;
+
+            if out.len() > self.max_message_size {
+                return Err(protocol_error(format!(
+                    "Decompressed message exceeds max_message_size {}",
+                    self.max_message_size
+                )));
+            }
+            if status == Status::StreamEnd {
+                // A final block ends the stream; the next message starts afresh
+                self.decompress.reset(false);
+                break;
+            }
+            let drained = consumed == payload.len() && out.len() < out.capacity();
+            if drained || (produced == 0 && self.decompress.total_in() == in_before) {
+                break;
+            }
+        }
+        if self.params.server_no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+/// Stream wrapper that inflates permessage-deflate messages from the gateway
+pub struct Inflating<S> {
+    inner: S,
+    rewriter: Rewriter,
+    /// How far into `rewriter.output` the reader has got
+    out_pos: usize,
+    eof: bool,
+}
+
+impl<S> Inflating<S> {
+    pub fn new(inner: S, max_message_size: usize, max_frame_size: usize) -> Self {
+        Self {
+            inner,
+            rewriter: Rewriter::new(max_message_size, max_frame_size),
+            out_pos: 0,
+            eof: false,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Inflating<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let output = &mut this.rewriter.output;
+            if this.out_pos < output.len() {
+                let n = buf.remaining().min(output.len() - this.out_pos);
+                buf.put_slice(&output[this.out_pos..this.out_pos + n]);
+                this.out_pos += n;
+                if this.out_pos == output.len() {
+                    output.clear();
+                    this.out_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            if matches!(this.rewriter.phase, Phase::Passthrough) && this.rewriter.input.is_empty() {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+
+            let mut chunk = [0u8; 8192];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                this.eof = true;
+            } else {
+                this.rewriter.feed(read.filled())?;
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Inflating<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compress, Compression, FlushCompress};
+
+    const ACCEPTED: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n";
+
+    /// Compress one message the way a gateway does, without the trailer
+    fn deflate(compress: &mut Compress, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 64);
+        compress
+            .compress_vec(data, &mut out, FlushCompress::Sync)
+            .unwrap();
+        assert!(out.ends_with(&TAIL));
+        out.truncate(out.len() - TAIL.len());
+        out
+    }
+
+    fn frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mut out = vec![first];
+        match payload.len() {
+            n if n < 126 => out.push(n as u8),
+            n if n <= u16::MAX as usize => {
+                out.push(126);
+                out.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                out.push(127);
+                out.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        out.extend_from_slice(payload);
+        out
+    }
+
+    /// (fin, opcode, payload) of every frame in `data`
+    fn frames(mut data: &[u8]) -> Vec<(bool, u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let header = FrameHeader::parse(data).unwrap();
+            assert!(!header.rsv1 && !header.masked);
+            let end = header.len + header.payload_len as usize;
+            frames.push((header.fin, header.opcode, data[header.len..end].to_vec()));
+            data = &data[end..];
+        }
+        frames
+    }
+
+    fn rewrite(stream: &[u8], step: usize) -> io::Result<Vec<u8>> {
+        let mut rewriter = Rewriter::new(1024 * 1024, 1024 * 1024);
+        for chunk in stream.chunks(step) {
+            rewriter.feed(chunk)?;
+        }
+        Ok(rewriter.output)
+    }
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(
+            parse_response("permessage-deflate").unwrap(),
+            Some(DeflateParams {
+                server_no_context_takeover: false,
+                server_max_window_bits: 15
+            })
+        );
+        assert_eq!(
+            parse_response("permessage-deflate; server_no_context_takeover; server_max_window_bits=10; client_no_context_takeover")
+                .unwrap(),
+            Some(DeflateParams {
+                server_no_context_takeover: true,
+                server_max_window_bits: 10
+            })
+        );
+        assert_eq!(parse_response("").unwrap(), None);
+
+        for bad in [
+            "x-webkit-deflate-frame",
+            "permessage-deflate, permessage-deflate",
+            "permessage-deflate; server_max_window_bits=7",
+            "permessage-deflate; client_max_window_bits=10",
+            "permessage-deflate; server_no_context_takeover=1",
+        ] {
+            assert!(parse_response(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_inflates_compressed_messages() {
+        let mut compress = Compress::new(Compression::default(), false);
+        let first = br#"{"type":"event","event":"chat.message","payload":{"message":"hello"}}"#;
+        let second =
+            br#"{"type":"event","event":"chat.message","payload":{"message":"hello again"}}"#;
+
+        let mut stream = ACCEPTED.to_vec();
+        // The second message leans on the first's context
+        stream.extend(frame(0xc1, &deflate(&mut compress, first)));
+        stream.extend(frame(0xc1, &deflate(&mut compress, second)));
+        // Uncompressed messages are still allowed
+        stream.extend(frame(0x81, b"plain"));
+
+        // However the bytes are split up on the way in
+        for step in [1, 7, 4096] {
+            let out = rewrite(&stream, step).unwrap();
+            assert!(out.starts_with(ACCEPTED));
+            assert_eq!(
+                frames(&out[ACCEPTED.len()..]),
+                [
+                    (true, OP_TEXT, first.to_vec()),
+                    (true, OP_TEXT, second.to_vec()),
+                    (true, OP_TEXT, b"plain".to_vec()),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_fragments_and_control_frames() {
+        let mut compress = Compress::new(Compression::default(), false);
+        let message = "streamed chunk ".repeat(200);
+        let compressed = deflate(&mut compress, message.as_bytes());
+        let (head, tail) = compressed.split_at(compressed.len() / 2);
+
+        let mut stream = ACCEPTED.to_vec();
+        stream.extend(frame(0x41, head));
+        // A ping may arrive in the middle of a fragmented message
+        stream.extend(frame(0x89, b"ping"));
+        stream.extend(frame(0x80, tail));
+
+        let out = rewrite(&stream, 5).unwrap();
+        assert_eq!(
+            frames(&out[ACCEPTED.len()..]),
+            [
+                (true, 0x9, b"ping".to_vec()),
+                (true, OP_TEXT, message.into_bytes()),
+            ]
+        );
+
+        // RSV1 belongs on the first frame only
+        let mut stream = ACCEPTED.to_vec();
+        stream.extend(frame(0x41, head));
+        stream.extend(frame(0xc0, tail));
+        assert!(rewrite(&stream, 4096).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut compress = Compress::new(Compression::best(), false);
+        let bomb = deflate(&mut compress, &vec![b'a'; 4 * 1024 * 1024]);
+        let mut stream = ACCEPTED.to_vec();
+        stream.extend(frame(0xc2, &bomb));
+        let err = rewrite(&stream, 4096).unwrap_err();
+        assert!(err
+            .get_ref()
+            .is_some_and(|inner| inner.is::<DeflateError>()));
+
+        // Large messages come out in frames tungstenite accepts
+        let mut rewriter = Rewriter::new(1024 * 1024, 1000);
+        let mut compress = Compress::new(Compression::default(), false);
+        let data = vec![b'x'; 2500];
+        rewriter.feed(ACCEPTED).unwrap();
+        rewriter
+            .feed(&frame(0xc2, &deflate(&mut compress, &data)))
+            .unwrap();
+        let out = frames(&rewriter.output[ACCEPTED.len()..]);
+        assert_eq!(out.len(), 3);
+        assert_eq!((out[0].0, out[0].1), (false, OP_BINARY));
+        assert_eq!((out[2].0, out[2].1), (true, OP_CONTINUATION));
+        assert_eq!(out.iter().map(|f| f.2.len()).sum::<usize>(), 2500);
+    }
+
+    #[test]
+    fn test_passthrough_without_the_extension() {
+        let declined = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+        let mut stream = declined.to_vec();
+        // Without the extension RSV1 is tungstenite's problem, not ours
+        stream.extend(frame(0xc1, b"not compressed"));
+        stream.extend(frame(0x81, b"plain"));
+        assert_eq!(rewrite(&stream, 3).unwrap(), stream);
+
+        let refused =
+            b"HTTP/1.1 403 Forbidden\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\nnope";
+        assert_eq!(rewrite(refused, 4096).unwrap(), refused);
+    }
+}
@@ -0,0 +1,169 @@
+// Gateway auto-discovery
+// Probes the usual localhost ports for a gateway greeting and asks the
+// orchestrator for gateways it knows about. Probes read the connect.challenge
+// and hang up; discovery never authenticates.
+
+use futures_util::future::join_all;
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use crate::transport::{dial_gateway, FrameLimits};
+
+/// Ports a local gateway usually listens on
+pub const PROBE_PORTS: RangeInclusive<u16> = 18790..=18799;
+
+/// Upper bound on the whole discovery run
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Budget for a single probe, leaving headroom inside `DISCOVERY_TIMEOUT`
+const PROBE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GatewayCandidate {
+    pub url: String,
+    /// `localhost` or `orchestrator`
+    pub source: String,
+    pub protocol_version: Option<u64>,
+    /// Whether the greeting advertised device-key auth, if it said either way
+    pub device_auth: Option<bool>,
+}
+
+/// What a connect.challenge tells us about the gateway
+pub fn parse_challenge(frame: &serde_json::Value) -> Option<(Option<u64>, Option<bool>)> {
+    if frame["event"].as_str() != Some("connect.challenge") {
+        return None;
+    }
+    let payload = &frame["payload"];
+    let protocol = ["protocol", "maxProtocol", "protocolVersion"]
+        .iter()
+        .find_map(|k| payload[*k].as_u64());
+    let device_auth = payload["deviceAuth"].as_bool().or_else(|| {
+        payload["auth"]["methods"]
+            .as_array()
+            .map(|methods| methods.iter().any(|m| m.as_str() == Some("device")))
+    });
+    Some((protocol, device_auth))
+}
+
+/// Handshake with one URL and wait for its greeting
+async fn probe(url: String) -> Option<GatewayCandidate> {
+    let (ws, _) = dial_gateway(&url, None, "", &FrameLimits::default(), None, false)
+        .await
+        .ok()?;
+    let (_, mut read) = ws.split();
+
+    while let Some(Ok(msg)) = read.next().await {
+        if !msg.is_text() {
+            continue;
+        }
+        let frame: serde_json::Value = serde_json::from_str(&msg.to_string()).ok()?;
+        let (protocol_version, device_auth) = parse_challenge(&frame)?;
+        return Some(GatewayCandidate {
+            url,
+            source: "localhost".to_string(),
+            protocol_version,
+            device_auth,
+        });
+    }
+    None
+}
+
+/// Probe every port in `PROBE_PORTS` concurrently
+pub async fn probe_localhost() -> Vec<GatewayCandidate> {
+    let probes = PROBE_PORTS.map(|port| {
+        tokio::time::timeout(PROBE_TIMEOUT, probe(format!("ws://127.0.0.1:{}/ws", port)))
+    });
+    join_all(probes)
+        .await
+        .into_iter()
+        .filter_map(|result| result.ok().flatten())
+        .collect()
+}
+
+/// Ask the orchestrator which gateways it manages
+pub async fn query_orchestrator(base_url: &str, token: &str) -> Vec<GatewayCandidate> {
+    let url = format!("{}/api/gateways", base_url.trim_end_matches('/'));
+    let response = match reqwest::Client::new()
+        .get(&url)
+        .bearer_auth(token)
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await
+    {
+        Ok(r) if r.status().is_success() => r,
+        Ok(r) => {
+            eprintln!("[Discovery] {} returned {}", url, r.status());
+            return Vec::new();
+        }
+        Err(e) => {
+            eprintln!("[Discovery] {} failed: {}", url, e);
+            return Vec::new();
+        }
+    };
+
+    let body: serde_json::Value = response.json().await.unwrap_or_default();
+    parse_orchestrator_list(&body)
+}
+
+/// Accepts `[...]` or `{gateways: [...]}` with entries carrying a `url`
+pub fn parse_orchestrator_list(body: &serde_json::Value) -> Vec<GatewayCandidate> {
+    body.get("gateways")
+        .unwrap_or(body)
+        .as_array()
+        .map(|entries| {
+            entries
+                .iter()
+                .filter_map(|entry| {
+                    let url = entry["url"].as_str()?;
+                    (url.starts_with("ws://") || url.starts_with("wss://")).then(|| {
+                        GatewayCandidate {
+                            url: url.to_string(),
+                            source: "orchestrator".to_string(),
+                            protocol_version: entry["protocolVersion"].as_u64(),
+                            device_auth: entry["deviceAuth"].as_bool(),
+                        }
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_challenge() {
+        let frame = serde_json::json!({
+            "type": "event",
+            "event": "connect.challenge",
+            "payload": {"nonce": "n", "maxProtocol": 3, "auth": {"methods": ["token", "device"]}}
+        });
+        assert_eq!(parse_challenge(&frame), Some((Some(3), Some(true))));
+
+        let bare = serde_json::json!({"event": "connect.challenge", "payload": {"nonce": "n"}});
+        assert_eq!(parse_challenge(&bare), Some((None, None)));
+
+        let other = serde_json::json!({"event": "chat.message"});
+        assert_eq!(parse_challenge(&other), None);
+    }
+
+    #[test]
+    fn test_parse_orchestrator_list() {
+        let body = serde_json::json!({
+            "gateways": [
+                {"url": "ws://10.0.0.5:18790/ws", "protocolVersion": 3},
+                {"url": "file:///etc/passwd"},
+                {"name": "no url"}
+            ]
+        });
+        let candidates = parse_orchestrator_list(&body);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].url, "ws://10.0.0.5:18790/ws");
+        assert_eq!(candidates[0].protocol_version, Some(3));
+    }
+}
@@ -0,0 +1,215 @@
+// Typed gateway errors
+// Error frames are parsed once into a `GatewayError` so the UI can branch on a
+// kind instead of matching English messages. Codes we don't know keep the
+// original frame text.
+
+use serde::Serialize;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorKind {
+    AuthFailed,
+    ScopeDenied,
+    RateLimited,
+    NonceExpired,
+    Unknown,
+}
+
+impl ErrorKind {
+    pub fn from_code(code: &str) -> Self {
+        match code.to_uppercase().as_str() {
+            "AUTH_FAILED" | "AUTH_REQUIRED" | "UNAUTHORIZED" | "INVALID_SIGNATURE" => {
+                ErrorKind::AuthFailed
+            }
+            "SCOPE_DENIED" | "FORBIDDEN" => ErrorKind::ScopeDenied,
+            "RATE_LIMITED" | "TOO_MANY_REQUESTS" => ErrorKind::RateLimited,
+            "NONCE_EXPIRED" | "CHALLENGE_EXPIRED" => ErrorKind::NonceExpired,
+            _ => ErrorKind::Unknown,
+        }
+    }
+
+    /// Whether trying again later can succeed, when the gateway doesn't say
+    fn default_retryable(self) -> bool {
+        matches!(self, ErrorKind::RateLimited | ErrorKind::NonceExpired)
+    }
+}
+
+/// Payload of `ws-error` for errors reported by the gateway
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GatewayError {
+    pub kind: ErrorKind,
+    pub code: String,
+    pub message: String,
+    pub retryable: bool,
+    /// Id of the request the error answers, if any
+    pub request_id: Option<String>,
+    /// The frame as received, kept for codes we don't recognise
+    pub raw: Option<String>,
+}
+
+impl GatewayError {
+    /// Parse a frame carrying an `error`; `None` if it has none
+    pub fn from_frame(frame: &Value) -> Option<Self> {
+        if frame["error"].is_null() {
+            return None;
+        }
+        Some(Self::from_response(frame))
+    }
+
+    /// Parse an `ok: false` response, which may lack an `error` object
+    pub fn from_response(frame: &Value) -> Self {
+        let error = &frame["error"];
+        let code = error["code"].as_str().unwrap_or("UNKNOWN").to_string();
+        let kind = ErrorKind::from_code(&code);
+        let message = error["message"]
+            .as_str()
+            .or_else(|| error.as_str())
+            .unwrap_or("request failed")
+            .to_string();
+        let request_id = frame["id"]
+            .as_str()
+            .or_else(|| error["requestId"].as_str())
+            .or_else(|| frame["requestId"].as_str())
+            .map(str::to_string);
+        Self {
+            kind,
+            code,
+            message,
+            retryable: error["retryable"]
+                .as_bool()
+                .unwrap_or_else(|| kind.default_retryable()),
+            request_id,
+            raw: (kind == ErrorKind::Unknown).then(|| frame.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for GatewayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Gateway error {}: {}", self.code, self.message)
+    }
+}
+
+/// Error codes a gateway uses when it refuses the credentials in a connect request
+const AUTH_ERROR_CODES: &[&str] = &[
+    "AUTH_FAILED",
+    "AUTH_REQUIRED",
+    "UNAUTHORIZED",
+    "DEVICE_AUTH_DISABLED",
+    "DEVICE_AUTH_REJECTED",
+    "INVALID_SIGNATURE",
+];
+
+/// Messages older gateways send for the same refusals, without a code
+const AUTH_ERROR_MESSAGES: &[&str] = &[
+    "unauthorized",
+    "authentication failed",
+    "authentication required",
+    "invalid signature",
+    "device auth disabled",
+    "device auth rejected",
+];
+
+/// Check whether a failed connect response was an authentication rejection
+///
+/// The code decides when there is one. Frames without a code only count if
+/// the whole message is one of the known refusals, so an unrelated error that
+/// happens to mention a device or an author doesn't tear the connection down.
+pub fn is_auth_rejection(frame: &Value) -> bool {
+    let error = &frame["error"];
+    if let Some(code) = error["code"].as_str() {
+        return AUTH_ERROR_CODES.contains(&code.to_uppercase().as_str());
+    }
+    error["message"]
+        .as_str()
+        .or_else(|| error.as_str())
+        .map(|m| {
+            let m = m.trim().trim_end_matches('.').to_lowercase();
+            AUTH_ERROR_MESSAGES.contains(&m.as_str())
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_codes() {
+        let frame = serde_json::json!({
+            "type": "res",
+            "id": "rpc-1",
+            "ok": false,
+            "error": {"code": "RATE_LIMITED", "message": "slow down"}
+        });
+        let error = GatewayError::from_frame(&frame).unwrap();
+        assert_eq!(error.kind, ErrorKind::RateLimited);
+        assert_eq!(error.message, "slow down");
+        assert!(error.retryable);
+        assert_eq!(error.request_id.as_deref(), Some("rpc-1"));
+        assert_eq!(error.raw, None);
+
+        let denied = serde_json::json!({
+            "ok": false,
+            "error": {"code": "scope_denied", "message": "no", "retryable": true}
+        });
+        let error = GatewayError::from_frame(&denied).unwrap();
+        assert_eq!(error.kind, ErrorKind::ScopeDenied);
+        assert!(error.retryable);
+        assert_eq!(error.request_id, None);
+    }
+
+    #[test]
+    fn test_unknown_code_keeps_frame() {
+        let frame = serde_json::json!({
+            "type": "event",
+            "event": "error",
+            "error": "gateway melted",
+            "requestId": "msg-4"
+        });
+        let error = GatewayError::from_frame(&frame).unwrap();
+        assert_eq!(error.kind, ErrorKind::Unknown);
+        assert_eq!(error.code, "UNKNOWN");
+        assert_eq!(error.message, "gateway melted");
+        assert!(!error.retryable);
+        assert_eq!(error.request_id.as_deref(), Some("msg-4"));
+        assert!(error.raw.unwrap().contains("gateway melted"));
+    }
+
+    #[test]
+    fn test_serialized_shape() {
+        let frame = serde_json::json!({"error": {"code": "AUTH_FAILED", "message": "bad key"}});
+        let json = serde_json::to_value(GatewayError::from_frame(&frame).unwrap()).unwrap();
+        assert_eq!(json["kind"], "authFailed");
+        assert_eq!(json["code"], "AUTH_FAILED");
+        assert_eq!(json["retryable"], false);
+
+        assert!(GatewayError::from_frame(&serde_json::json!({"ok": true})).is_none());
+    }
+
+    #[test]
+    fn test_auth_rejection() {
+        for frame in [
+            serde_json::json!({"error": {"code": "INVALID_SIGNATURE", "message": "nope"}}),
+            serde_json::json!({"error": {"code": "device_auth_disabled"}}),
+            serde_json::json!({"error": {"message": "Authentication failed."}}),
+            serde_json::json!({"error": "unauthorized"}),
+        ] {
+            assert!(is_auth_rejection(&frame), "{}", frame);
+        }
+
+        for frame in [
+            // The code wins over a message that sounds like auth
+            serde_json::json!({"error": {"code": "RATE_LIMITED", "message": "unauthorized"}}),
+            serde_json::json!({"error": {"code": "DEVICE_BUSY", "message": "device busy"}}),
+            serde_json::json!({"error": {"message": "device busy"}}),
+            serde_json::json!({"error": {"message": "missing author field"}}),
+            serde_json::json!({"error": "signature of the upload didn't match"}),
+            serde_json::json!({"ok": false}),
+        ] {
+            assert!(!is_auth_rejection(&frame), "{}", frame);
+        }
+    }
+}
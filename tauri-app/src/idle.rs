@@ -0,0 +1,80 @@
+// Idle auto-disconnect
+// With `idle_timeout_minutes` set, the connection is closed after that long
+// without user activity (sends or window focus). Incoming gateway events don't
+// count. The next send or focus reconnects.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often the connection task checks for idleness
+pub const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a send waits for the reconnect after idling
+pub const WAKE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Idle timeout from the config; zero or unset disables it
+pub fn timeout_from_minutes(minutes: Option<u64>) -> Option<Duration> {
+    minutes
+        .filter(|m| *m > 0)
+        .map(|m| Duration::from_secs(m.saturating_mul(60)))
+}
+
+/// The `session.idle` notification sent before closing
+pub fn idle_notification() -> String {
+    serde_json::json!({
+        "type": "req",
+        "method": "session.idle",
+        "params": {}
+    })
+    .to_string()
+}
+
+/// Time of the last user action
+pub struct Activity {
+    last: Mutex<Instant>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Activity {
+    pub fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    pub fn is_idle(&self, now: Instant, timeout: Duration) -> bool {
+        now.saturating_duration_since(*self.last.lock().unwrap()) >= timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeout_from_minutes() {
+        assert_eq!(timeout_from_minutes(None), None);
+        assert_eq!(timeout_from_minutes(Some(0)), None);
+        assert_eq!(
+            timeout_from_minutes(Some(30)),
+            Some(Duration::from_secs(1800))
+        );
+    }
+
+    #[test]
+    fn test_is_idle() {
+        let activity = Activity::default();
+        let timeout = Duration::from_secs(60);
+        let now = Instant::now();
+        assert!(!activity.is_idle(now, timeout));
+        assert!(activity.is_idle(now + timeout, timeout));
+
+        activity.touch();
+        assert!(!activity.is_idle(Instant::now(), timeout));
+    }
+}
@@ -0,0 +1,214 @@
+// Device key file format and integrity checks
+// The file holds the Ed25519 private key, so it is written owner-only and
+// checked on every load instead of trusting whatever is on disk.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ed25519_dalek::SigningKey;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentityError {
+    /// A field is missing or can't be decoded
+    Malformed(String),
+    /// The stored checksum doesn't match the key material
+    ChecksumMismatch,
+    /// The stored public key isn't the one derived from the private key
+    PublicKeyMismatch,
+    /// The stored device id isn't SHA-256 of the derived public key
+    DeviceIdMismatch { stored: String, derived: String },
+}
+
+impl fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentityError::Malformed(m) => write!(f, "key file is malformed: {}", m),
+            IdentityError::ChecksumMismatch => {
+                write!(f, "key checksum does not match the stored key material")
+            }
+            IdentityError::PublicKeyMismatch => {
+                write!(f, "stored public key does not match the private key")
+            }
+            IdentityError::DeviceIdMismatch { stored, derived } => write!(
+                f,
+                "stored device id {} does not match the key (expected {})",
+                stored, derived
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IdentityError {}
+
+/// Device id for a key: hex SHA-256 of the public key
+pub fn derive_device_id(signing_key: &SigningKey) -> String {
+    hex::encode(Sha256::digest(signing_key.verifying_key().to_bytes()))
+}
+
+fn key_checksum(signing_key: &SigningKey) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(signing_key.to_bytes());
+    hasher.update(signing_key.verifying_key().to_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// JSON stored on disk for a key
+pub fn to_json(signing_key: &SigningKey) -> serde_json::Value {
+    serde_json::json!({
+        "privateKey": BASE64.encode(signing_key.to_bytes()),
+        "publicKey": BASE64.encode(signing_key.verifying_key().to_bytes()),
+        "deviceId": derive_device_id(signing_key),
+        "keyChecksum": key_checksum(signing_key),
+    })
+}
+
+/// A verified key file
+pub struct ParsedKey {
+    pub signing_key: SigningKey,
+    pub device_id: String,
+    /// Written before checksums existed; should be rewritten with one
+    pub needs_upgrade: bool,
+}
+
+/// Decode and verify a key file's JSON
+pub fn parse(keys: &serde_json::Value) -> Result<ParsedKey, IdentityError> {
+    let private_key_b64 = keys["privateKey"]
+        .as_str()
+        .ok_or_else(|| IdentityError::Malformed("missing privateKey".to_string()))?;
+    let private_key_bytes = BASE64
+        .decode(private_key_b64)
+        .map_err(|e| IdentityError::Malformed(format!("privateKey is not base64: {}", e)))?;
+    let bytes: [u8; 32] = private_key_bytes.try_into().map_err(|b: Vec<u8>| {
+        IdentityError::Malformed(format!(
+            "privateKey is {} bytes, expected 32 (file truncated?)",
+            b.len()
+        ))
+    })?;
+    let signing_key = SigningKey::from_bytes(&bytes);
+
+    let needs_upgrade = match keys["keyChecksum"].as_str() {
+        Some(checksum) if checksum != key_checksum(&signing_key) => {
+            return Err(IdentityError::ChecksumMismatch)
+        }
+        Some(_) => false,
+        None => true,
+    };
+
+    if let Some(public_key) = keys["publicKey"].as_str() {
+        if public_key != BASE64.encode(signing_key.verifying_key().to_bytes()) {
+            return Err(IdentityError::PublicKeyMismatch);
+        }
+    }
+
+    let derived = derive_device_id(&signing_key);
+    let stored = keys["deviceId"]
+        .as_str()
+        .ok_or_else(|| IdentityError::Malformed("missing deviceId".to_string()))?;
+    if stored != derived {
+        return Err(IdentityError::DeviceIdMismatch {
+            stored: stored.to_string(),
+            derived,
+        });
+    }
+
+    Ok(ParsedKey {
+        signing_key,
+        device_id: derived,
+        needs_upgrade,
+    })
+}
+
+/// Write a key file readable only by its owner
+pub fn write(path: &Path, signing_key: &SigningKey) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let data = serde_json::to_string_pretty(&to_json(signing_key))?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(data.as_bytes())?;
+    // `mode` only applies to newly created files
+    restrict_permissions(path)?;
+    Ok(())
+}
+
+/// Tighten an existing key file to owner-only; returns true if it was looser
+#[cfg(unix)]
+pub fn restrict_permissions(path: &Path) -> std::io::Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+    if mode & 0o077 == 0 {
+        return Ok(false);
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn restrict_permissions(_path: &Path) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[42u8; 32])
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let parsed = parse(&to_json(&key())).unwrap();
+        assert_eq!(parsed.device_id, derive_device_id(&key()));
+        assert!(!parsed.needs_upgrade);
+    }
+
+    #[test]
+    fn test_legacy_file_needs_upgrade() {
+        let mut json = to_json(&key());
+        json.as_object_mut().unwrap().remove("keyChecksum");
+        assert!(parse(&json).unwrap().needs_upgrade);
+    }
+
+    #[test]
+    fn test_tampered_files_rejected() {
+        let mut json = to_json(&key());
+        json["deviceId"] = serde_json::json!("deadbeef");
+        assert!(matches!(
+            parse(&json),
+            Err(IdentityError::DeviceIdMismatch { .. })
+        ));
+
+        let mut json = to_json(&key());
+        json["keyChecksum"] = serde_json::json!("00");
+        assert_eq!(parse(&json).err(), Some(IdentityError::ChecksumMismatch));
+
+        let mut json = to_json(&key());
+        json["privateKey"] = serde_json::json!(BASE64.encode([1u8; 16]));
+        assert!(matches!(parse(&json), Err(IdentityError::Malformed(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_is_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("claw-pen-key-{}.json", std::process::id()));
+        write(&path, &key()).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode, 0o600);
+    }
+}
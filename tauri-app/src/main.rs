@@ -3,24 +3,59 @@
 
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod agents;
+mod challenge;
+mod chunking;
+mod client_cert;
+mod clock;
+mod codec;
+mod conn_state;
+mod connections;
+mod deeplink;
+mod deflate;
+mod diagnostics;
+mod discovery;
+mod gateway_error;
+mod health;
+mod idle;
+mod keyfile;
+mod mock;
+mod notify;
+mod outbox;
+mod outbox_store;
+mod power;
+mod profiles;
+mod protocol;
+mod proxy;
+mod ratelimit;
+mod receipts;
+mod rpc;
+mod shutdown;
+mod signing;
+mod transport;
+mod tray;
+mod typing;
+
 use anyhow::Result;
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
-use ed25519_dalek::Signer;
+use conn_state::ConnState;
+use connections::{ConnEvents, Connection, DEFAULT_CONNECTION};
 use ed25519_dalek::SigningKey;
-use futures_util::{SinkExt, StreamExt};
-use http::request::Request;
+use futures_util::{Sink, SinkExt, StreamExt};
+use proxy::ProxyConfig;
 use rand::rngs::OsRng;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
 use std::sync::Arc;
-use tauri::{AppHandle, Emitter, State};
-use tokio::sync::mpsc::{channel, Sender};
-use tokio_tungstenite::connect_async_with_config;
-use tungstenite::handshake::client::generate_key;
+use tauri::{AppHandle, Emitter, Manager, RunEvent, State, WindowEvent};
+use tauri_plugin_clipboard_manager::ClipboardExt;
+use tauri_plugin_deep_link::DeepLinkExt;
+use tokio::sync::mpsc::channel;
+use transport::{dial_gateway, DialError};
 
 static REQUEST_ID_COUNTER: AtomicU64 = AtomicU64::new(1);
 
@@ -28,6 +63,73 @@ static REQUEST_ID_COUNTER: AtomicU64 = AtomicU64::new(1);
 pub struct AppConfig {
     pub orchestrator_url: String,
     pub agent_gateway_url: String,
+    /// Bearer token used when the gateway rejects device-key auth
+    #[serde(default)]
+    pub auth_token: Option<String>,
+    /// Orchestrator JWT, used to ask it which gateways it knows about
+    #[serde(default)]
+    pub orchestrator_token: Option<String>,
+    /// Proxy for the gateway connection: `http://host:port` or `socks5://host:port`
+    #[serde(default)]
+    pub proxy_url: Option<String>,
+    #[serde(default)]
+    pub proxy_username: Option<String>,
+    #[serde(default)]
+    pub proxy_password: Option<String>,
+    /// Hosts that bypass the proxy, in `NO_PROXY` format (falls back to the env var)
+    #[serde(default)]
+    pub no_proxy: Option<String>,
+    /// Device profile (identity) to connect with; `default` when unset
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Client-side pacing of outgoing frames
+    #[serde(default)]
+    pub rate_limit: ratelimit::RateLimitConfig,
+    /// Deadlines and retries for gateway RPCs
+    #[serde(default)]
+    pub rpc_retry: rpc::RetryPolicy,
+    /// Largest incoming WebSocket message and frame we accept
+    #[serde(default)]
+    pub frame_limits: transport::FrameLimits,
+    /// Offer permessage-deflate so the gateway can compress what it sends
+    #[serde(default = "default_true")]
+    pub ws_compression: bool,
+    /// Disconnect after this many minutes without user activity; unset or 0 never does
+    #[serde(default)]
+    pub idle_timeout_minutes: Option<u64>,
+    /// Largest chat message sent in one piece; longer ones need `split`
+    #[serde(default = "chunking::default_max_message_bytes")]
+    pub max_message_bytes: usize,
+    /// Client certificate presented to wss gateways
+    #[serde(default)]
+    pub client_cert: client_cert::ClientCertConfig,
+    /// Notify about agent chat messages while the window is in the background
+    #[serde(default = "default_true")]
+    pub notify_chat: bool,
+    #[serde(default = "default_true")]
+    pub notify_approvals: bool,
+    /// Local-time window during which no notifications are shown
+    #[serde(default)]
+    pub quiet_hours: Option<notify::QuietHours>,
+    /// Contexts other local tools may request signatures for via `sign_payload`
+    ///
+    /// Only read from the config file; `set_config` keeps whatever is on disk.
+    #[serde(default)]
+    pub signing_contexts: Vec<String>,
+}
+
+impl AppConfig {
+    /// Device profile to use, falling back to the default one
+    fn profile_name(&self) -> &str {
+        self.profile
+            .as_deref()
+            .filter(|p| !p.is_empty())
+            .unwrap_or(profiles::DEFAULT_PROFILE)
+    }
+}
+
+fn default_true() -> bool {
+    true
 }
 
 impl Default for AppConfig {
@@ -35,17 +137,122 @@ impl Default for AppConfig {
         Self {
             orchestrator_url: "http://localhost:3000".to_string(),
             agent_gateway_url: "ws://127.0.0.1:18790/ws".to_string(),
+            auth_token: None,
+            orchestrator_token: None,
+            proxy_url: None,
+            proxy_username: None,
+            proxy_password: None,
+            no_proxy: None,
+            profile: None,
+            rate_limit: ratelimit::RateLimitConfig::default(),
+            rpc_retry: rpc::RetryPolicy::default(),
+            frame_limits: transport::FrameLimits::default(),
+            ws_compression: true,
+            idle_timeout_minutes: None,
+            max_message_bytes: chunking::DEFAULT_MAX_MESSAGE_BYTES,
+            client_cert: client_cert::ClientCertConfig::default(),
+            notify_chat: true,
+            notify_approvals: true,
+            quiet_hours: None,
+            signing_contexts: Vec::new(),
         }
     }
 }
 
+fn get_config_path() -> PathBuf {
+    let home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
+    home.join(".openclaw").join("claw-pen.json")
+}
+
+/// Load the app config from disk, falling back to defaults
+fn load_config() -> AppConfig {
+    fs::read_to_string(get_config_path())
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+/// How the connect request proves the operator's identity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AuthMode {
+    Device,
+    Token,
+}
+
+impl AuthMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuthMode::Device => "device",
+            AuthMode::Token => "token",
+        }
+    }
+}
+
+enum ConnectAuth<'a> {
+    Device(&'a DeviceKeys),
+    Token(&'a str),
+}
+
 pub struct AppState {
-    pub ws_sender: Arc<tokio::sync::Mutex<Option<Sender<String>>>>,
+    /// Gateway connections by id; `default` is created during setup
+    pub connections: std::sync::Mutex<HashMap<String, Arc<Connection>>>,
+    /// Set once the app has started exiting, so the exit hook only runs once
+    pub exiting: AtomicBool,
+    /// Notified when the machine wakes from suspend
+    pub system_resumed: Arc<tokio::sync::Notify>,
+    /// True while the machine is going to or staying asleep
+    pub system_suspended: Arc<tokio::sync::watch::Sender<bool>>,
+    /// Notified to abandon a running `discover_gateways`
+    pub discovery_cancel: Arc<tokio::sync::Notify>,
+    /// Chat messages kept on disk until the gateway acks them; `None` if the
+    /// database couldn't be opened
+    pub outbox_store: Option<Arc<outbox_store::OutboxStore>>,
+    /// Last user action, for the idle timeout
+    pub activity: Arc<idle::Activity>,
+    /// Loaded client certificate; replaced by `set_config`, read on every dial
+    pub client_cert: Arc<std::sync::Mutex<Option<client_cert::ClientCert>>>,
 }
 
-fn get_device_keys_path() -> PathBuf {
-    let home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
-    home.join(".openclaw").join("claw-pen-device.json")
+impl AppState {
+    /// The connection with this id, created on first use
+    fn connection(&self, app: &AppHandle, id: &str) -> Arc<Connection> {
+        self.connections
+            .lock()
+            .unwrap()
+            .entry(id.to_string())
+            .or_insert_with(|| Arc::new(Connection::new(app, id)))
+            .clone()
+    }
+
+    /// An existing connection, `default` when no id is given
+    fn find_connection(&self, id: Option<&str>) -> Result<Arc<Connection>, String> {
+        let id = connections::resolve_id(id)?;
+        self.connections
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| format!("No connection '{}'", id))
+    }
+
+    fn all_connections(&self) -> Vec<Arc<Connection>> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// Snapshot of the gateway connection for diagnostics
+#[derive(Debug, Clone, Serialize)]
+pub struct WsStats {
+    pub queue_depth: usize,
+    pub queue_capacity: usize,
+    pub backpressure: bool,
+    pub rate_limit: ratelimit::RateLimitStats,
+    pub rpc: rpc::RpcStats,
+    /// Parameters of the current connection, if any
+    pub transport: Option<transport::Negotiated>,
+    /// Protocol version the gateway picked
+    pub protocol: Option<u32>,
+    pub client_cert: Option<client_cert::CertExpiry>,
 }
 
 struct DeviceKeys {
@@ -53,151 +260,527 @@ struct DeviceKeys {
     device_id: String,
 }
 
-fn load_or_create_device_keys() -> Result<DeviceKeys> {
-    let path = get_device_keys_path();
+impl DeviceKeys {
+    fn sign_connect(&self, params: &protocol::ConnectParams) -> protocol::SignedConnect {
+        eprintln!(
+            "[Device] Signing message: {}",
+            params.signable_string(&self.device_id)
+        );
+        protocol::SignedConnect::sign(&self.signing_key, &self.device_id, params)
+    }
+}
 
-    if path.exists() {
-        let data = fs::read_to_string(&path)?;
-        let keys: serde_json::Value = serde_json::from_str(&data)?;
-
-        let private_key_b64 = keys["privateKey"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Missing privateKey"))?;
-        let private_key_bytes = BASE64.decode(private_key_b64)?;
-        let bytes: [u8; 32] = private_key_bytes
-            .try_into()
-            .map_err(|_| anyhow::anyhow!("Invalid key length"))?;
-        let signing_key = SigningKey::from_bytes(&bytes);
-
-        let device_id = keys["deviceId"].as_str().unwrap_or("unknown").to_string();
-
-        return Ok(DeviceKeys {
-            signing_key,
-            device_id,
-        });
+/// A device identity as shown in the profile picker
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceProfile {
+    pub name: String,
+    pub device_id: String,
+}
+
+fn load_or_create_device_keys(profile: &str) -> Result<DeviceKeys> {
+    match load_device_keys(profile)? {
+        Some(keys) => Ok(keys),
+        None => create_device_keys(&profiles::profile_path(profile)),
     }
+}
 
-    let mut rng = OsRng;
-    let signing_key = SigningKey::generate(&mut rng);
-    let verifying_key = signing_key.verifying_key();
+/// The profile's identity, or `None` if it has none yet; never creates one,
+/// for callers that must not mint a key the gateway hasn't paired
+fn load_device_keys(profile: &str) -> Result<Option<DeviceKeys>> {
+    profiles::validate_profile_name(profile).map_err(|e| anyhow::anyhow!(e))?;
+    profiles::migrate_legacy_device()?;
+    let path = profiles::profile_path(profile);
+    if !path.exists() {
+        return Ok(None);
+    }
 
-    let mut hasher = Sha256::new();
-    hasher.update(verifying_key.to_bytes());
-    let device_id = hex::encode(hasher.finalize());
+    if keyfile::restrict_permissions(&path)? {
+        eprintln!(
+            "[Device] Tightened permissions on {} to owner-only",
+            path.display()
+        );
+    }
 
-    let keys_json = serde_json::json!({
-        "privateKey": BASE64.encode(signing_key.to_bytes()),
-        "publicKey": BASE64.encode(verifying_key.to_bytes()),
-        "deviceId": device_id
-    });
+    let data = fs::read_to_string(&path)?;
+    let keys: serde_json::Value = serde_json::from_str(&data)
+        .map_err(|e| keyfile::IdentityError::Malformed(e.to_string()))?;
+    let parsed = keyfile::parse(&keys)?;
 
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
+    if parsed.needs_upgrade {
+        keyfile::write(&path, &parsed.signing_key)?;
+        eprintln!("[Device] Added key checksum to {}", path.display());
     }
-    fs::write(&path, serde_json::to_string_pretty(&keys_json)?)?;
+
+    Ok(Some(DeviceKeys {
+        signing_key: parsed.signing_key,
+        device_id: parsed.device_id,
+    }))
+}
+
+/// Generate a fresh identity and write it to `path`
+fn create_device_keys(path: &std::path::Path) -> Result<DeviceKeys> {
+    let mut rng = OsRng;
+    let signing_key = SigningKey::generate(&mut rng);
+    keyfile::write(path, &signing_key)?;
 
     Ok(DeviceKeys {
+        device_id: keyfile::derive_device_id(&signing_key),
         signing_key,
-        device_id,
     })
 }
 
+/// What to tell the user when a key file fails its integrity check
+fn identity_recovery_message(path: &std::path::Path, err: &keyfile::IdentityError) -> String {
+    format!(
+        "Device identity in {} is corrupt or was modified ({}). Restore the file from a backup, or move it aside to generate a new identity; the gateway admin will then need to approve the new device.",
+        path.display(),
+        err
+    )
+}
+
+/// Result of `verify_device_identity`
+#[derive(Debug, Clone, Serialize)]
+pub struct IdentityReport {
+    pub profile: String,
+    pub path: String,
+    pub ok: bool,
+    pub device_id: Option<String>,
+    pub error: Option<String>,
+    /// The file was readable by others and has been tightened
+    pub permissions_fixed: bool,
+}
+
+/// Check the configured profile's key file without connecting
+#[tauri::command]
+async fn verify_device_identity() -> Result<IdentityReport, String> {
+    let config = load_config();
+    let profile = config.profile_name();
+    profiles::validate_profile_name(profile)?;
+    let path = profiles::profile_path(profile);
+
+    let mut report = IdentityReport {
+        profile: profile.to_string(),
+        path: path.display().to_string(),
+        ok: false,
+        device_id: None,
+        error: None,
+        permissions_fixed: false,
+    };
+
+    if !path.exists() {
+        report.error = Some("No key file yet; one is created on the next connect".to_string());
+        return Ok(report);
+    }
+
+    report.permissions_fixed = keyfile::restrict_permissions(&path).map_err(|e| e.to_string())?;
+    let data = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+    let parsed = serde_json::from_str::<serde_json::Value>(&data)
+        .map_err(|e| keyfile::IdentityError::Malformed(e.to_string()))
+        .and_then(|keys| keyfile::parse(&keys));
+
+    match parsed {
+        Ok(parsed) => {
+            report.ok = true;
+            report.device_id = Some(parsed.device_id);
+        }
+        Err(e) => report.error = Some(identity_recovery_message(&path, &e)),
+    }
+    Ok(report)
+}
+
 #[tauri::command]
 async fn get_config() -> Result<AppConfig, String> {
-    Ok(AppConfig::default())
+    Ok(load_config())
 }
 
-fn build_connect_request(req_id: &str, nonce: &str, device_keys: &DeviceKeys) -> String {
-    let signed_at = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .map(|d| d.as_millis() as u64)
-        .unwrap_or(0);
+/// Save the config, checking the client certificate first so a bad one is
+/// reported here rather than on the next connect
+#[tauri::command]
+async fn set_config(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    mut config: AppConfig,
+) -> Result<(), String> {
+    // The webview must not grant itself signing contexts; only a hand edit can
+    config.signing_contexts = load_config().signing_contexts;
+    reload_client_cert(&app, &state, &config.client_cert)?;
+
+    let path = get_config_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create config dir: {}", e))?;
+    }
+    let data = serde_json::to_string_pretty(&config).map_err(|e| e.to_string())?;
+    fs::write(&path, data).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Load the configured client certificate for the next dial
+fn reload_client_cert(
+    app: &AppHandle,
+    state: &AppState,
+    config: &client_cert::ClientCertConfig,
+) -> Result<(), String> {
+    let cert = client_cert::load(config, clock::now_millis() / 1000)?;
+    if let Some(ref cert) = cert {
+        eprintln!(
+            "[TLS] Client certificate '{}', {} days left",
+            cert.expiry.subject, cert.expiry.days_remaining
+        );
+        if cert.expiry.expiring {
+            let _ = app.emit("client-cert-expiring", &cert.expiry);
+        }
+    }
+    *state.client_cert.lock().unwrap() = cert;
+    Ok(())
+}
+
+#[tauri::command]
+async fn list_device_profiles() -> Result<Vec<DeviceProfile>, String> {
+    profiles::migrate_legacy_device().map_err(|e| e.to_string())?;
+    let names = profiles::list_profiles().map_err(|e| e.to_string())?;
+
+    Ok(names
+        .into_iter()
+        .filter_map(|name| {
+            let data = fs::read_to_string(profiles::profile_path(&name)).ok()?;
+            let keys: serde_json::Value = serde_json::from_str(&data).ok()?;
+            let device_id = keys["deviceId"].as_str().unwrap_or("unknown").to_string();
+            Some(DeviceProfile { name, device_id })
+        })
+        .collect())
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedPayload {
+    pub signature: String,
+    pub public_key: String,
+    pub device_id: String,
+}
+
+/// Sign a payload with the device key for another local tool
+///
+/// The signature covers `claw-pen:ext:<context>|<payload>`, and only contexts
+/// listed in `signing_contexts` are accepted.
+#[tauri::command]
+async fn sign_payload(payload_b64: String, context: String) -> Result<SignedPayload, String> {
+    let config = load_config();
+    signing::check_context(&context, &config.signing_contexts)?;
+
+    let payload = BASE64
+        .decode(payload_b64.trim())
+        .map_err(|e| format!("Payload is not valid base64: {}", e))?;
+    if payload.len() > signing::MAX_PAYLOAD_BYTES {
+        return Err(format!(
+            "Payload too large (max {} bytes)",
+            signing::MAX_PAYLOAD_BYTES
+        ));
+    }
 
-    let scopes = "operator.admin,operator.approvals,operator.pairing";
+    let profile = config.profile_name();
+    // A new key would sign as a device the gateway has never paired
+    let keys = load_device_keys(profile)
+        .map_err(|e| format!("Failed to load device keys: {}", e))?
+        .ok_or_else(|| {
+            format!(
+                "Device profile '{}' has no identity yet; connect to the gateway first",
+                profile
+            )
+        })?;
 
-    let message = format!(
-        "v2|{}|openclaw-control-ui|webchat|operator|{}|{}||{}",
-        device_keys.device_id, scopes, signed_at, nonce
+    let signature = signing::sign_external(&keys.signing_key, &context, &payload);
+    eprintln!(
+        "[Sign] context={} device={} payload_sha256={}",
+        context,
+        keys.device_id,
+        signing::payload_hash(&payload)
     );
 
-    eprintln!("[Device] Signing message: {}", &message);
+    Ok(SignedPayload {
+        signature: BASE64.encode(signature.to_bytes()),
+        public_key: BASE64.encode(keys.signing_key.verifying_key().to_bytes()),
+        device_id: keys.device_id,
+    })
+}
 
-    let signature = device_keys.signing_key.sign(message.as_bytes());
-    let signature_b64 = BASE64.encode(signature.to_bytes());
-    let public_key_b64 = BASE64.encode(device_keys.signing_key.verifying_key().to_bytes());
+#[tauri::command]
+async fn create_device_profile(name: String) -> Result<DeviceProfile, String> {
+    profiles::validate_profile_name(&name)?;
+    profiles::migrate_legacy_device().map_err(|e| e.to_string())?;
+
+    let path = profiles::profile_path(&name);
+    if path.exists() {
+        return Err(format!("Device profile '{}' already exists", name));
+    }
+
+    let keys =
+        create_device_keys(&path).map_err(|e| format!("Failed to create device profile: {}", e))?;
+    eprintln!("[Device] Created profile '{}': {}", name, keys.device_id);
+
+    Ok(DeviceProfile {
+        name,
+        device_id: keys.device_id,
+    })
+}
+
+fn build_connect_request(req_id: &str, nonce: &str, auth: &ConnectAuth, signed_at: u64) -> String {
+    let mut params = protocol::ConnectParams::new(nonce, signed_at);
+    params.caps = vec![codec::CAP_MSGPACK.to_string()];
+
+    let signed = match auth {
+        ConnectAuth::Device(device_keys) => Some(device_keys.sign_connect(&params)),
+        ConnectAuth::Token(token) => {
+            params.token = Some(token.to_string());
+            None
+        }
+    };
 
     serde_json::json!({
         "type": "req",
         "id": req_id,
         "method": "connect",
-        "params": {
-            "minProtocol": 3,
-            "maxProtocol": 3,
-            "client": {
-                "id": "openclaw-control-ui",
-                "version": "1.0.0",
-                "platform": "desktop",
-                "mode": "webchat"
-            },
-            "role": "operator",
-            "scopes": ["operator.admin", "operator.approvals", "operator.pairing"],
-            "device": {
-                "id": device_keys.device_id,
-                "publicKey": public_key_b64,
-                "signature": signature_b64,
-                "signedAt": signed_at,
-                "nonce": nonce
-            },
-            "caps": [],
-            "commands": []
-        }
+        "params": params.to_json(signed.as_ref())
     })
     .to_string()
 }
 
+/// Learn the clock offset from a gateway time hint
+///
+/// Returns `Ok(true)` when the stored offset changed, or an actionable error
+/// when the local clock is further off than we are willing to correct.
+fn learn_clock_offset(
+    events: &ConnEvents,
+    offset_store: &AtomicI64,
+    server_ms: i64,
+    source: &str,
+) -> Result<bool, String> {
+    let offset = clock::compute_offset(server_ms, clock::now_millis()).map_err(|offset| {
+        format!(
+            "Your system clock is off by about {} hours compared to the gateway. Fix the system time and reconnect.",
+            offset.abs() / 3_600_000
+        )
+    })?;
+
+    let current = offset_store.load(Ordering::SeqCst);
+    if (offset - current).abs() < clock::SKEW_TOLERANCE_MS {
+        return Ok(false);
+    }
+
+    offset_store.store(offset, Ordering::SeqCst);
+    eprintln!(
+        "[Clock] Gateway clock differs by {}ms (from {}), correcting signedAt",
+        offset, source
+    );
+    events.emit(
+        "clock-skew-detected",
+        serde_json::json!({
+            "offsetMs": offset,
+            "magnitudeSecs": offset.abs() / 1000,
+            "source": source,
+        }),
+    );
+    Ok(true)
+}
+
+/// Connect to a gateway, returning the connection's id
+///
+/// The URL is normalized first (see `transport::normalize_gateway_url`) and a
+/// malformed one is rejected here instead of failing inside the retry loop.
+/// `connection_id` names the connection (`default` when unset); connecting an
+/// id that is already connected replaces that connection only. `profile`
+/// picks the device identity for it, falling back to the configured one.
+///
+/// With `mock` set, `url` is ignored and an in-process mock gateway answers
+/// instead (see `mock`), replying after `mock_delay_ms` or from the
+/// `scripted_responses` file.
+#[allow(clippy::too_many_arguments)]
 #[tauri::command]
 async fn connect_websocket(
     app: AppHandle,
     state: State<'_, AppState>,
     url: String,
+    connection_id: Option<String>,
+    profile: Option<String>,
+    mock: Option<bool>,
+    mock_delay_ms: Option<u64>,
+    scripted_responses: Option<PathBuf>,
+) -> Result<String, String> {
+    let connection_id = connections::resolve_id(connection_id.as_deref())?.to_string();
+    let mock_options = if mock.unwrap_or(false) {
+        Some(mock::MockOptions {
+            reply_delay: mock_delay_ms
+                .map(std::time::Duration::from_millis)
+                .unwrap_or(mock::DEFAULT_REPLY_DELAY),
+            script: scripted_responses
+                .as_deref()
+                .map(mock::load_script)
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    } else {
+        None
+    };
+    let url = match mock_options {
+        Some(_) => mock::MOCK_URL.to_string(),
+        None => transport::normalize_gateway_url(&url)?,
+    };
+    let target = connections::Target {
+        url,
+        profile: profile.filter(|p| !p.is_empty()),
+        mock: mock_options,
+    };
+
+    open_connection(&app, &state, &connection_id, target).await?;
+    Ok(connection_id)
+}
+
+/// Connect the default connection to the configured gateway
+async fn connect_default(app: &AppHandle) -> Result<(), String> {
+    let url = transport::normalize_gateway_url(&load_config().agent_gateway_url)?;
+    open_connection(
+        app,
+        &app.state::<AppState>(),
+        DEFAULT_CONNECTION,
+        connections::Target::gateway(url),
+    )
+    .await
+}
+
+/// Start a connection's task for `target`, replacing any running one
+async fn open_connection(
+    app: &AppHandle,
+    state: &AppState,
+    connection_id: &str,
+    target: connections::Target,
 ) -> Result<(), String> {
-    let app_handle = app.clone();
+    let config = load_config();
+    let profile = target
+        .profile
+        .clone()
+        .unwrap_or_else(|| config.profile_name().to_string());
+    let url = target.url.clone();
+    let mock_options = target.mock.clone();
+    let conn = state.connection(app, connection_id);
+    let events = conn.events.clone();
 
-    let device_keys =
-        load_or_create_device_keys().map_err(|e| format!("Failed to load device keys: {}", e))?;
-    eprintln!("[Device] ID: {}", device_keys.device_id);
+    let device_keys = load_or_create_device_keys(&profile).map_err(|e| {
+        // Never fall back to a mismatched identity; tell the user how to recover
+        if let Some(err) = e.downcast_ref::<keyfile::IdentityError>() {
+            let path = profiles::profile_path(&profile);
+            let message = identity_recovery_message(&path, err);
+            eprintln!("[Device] {}", message);
+            events.emit(
+                "device-identity-corrupt",
+                serde_json::json!({
+                    "profile": profile,
+                    "path": path.display().to_string(),
+                    "reason": err.to_string(),
+                    "message": message,
+                }),
+            );
+            return message;
+        }
+        format!("Failed to load device keys: {}", e)
+    })?;
+    eprintln!(
+        "[Device] Profile '{}', ID: {}",
+        profile, device_keys.device_id
+    );
+
+    let auth_token = config.auth_token.filter(|t| !t.is_empty());
+    let proxy = config
+        .proxy_url
+        .as_deref()
+        .filter(|u| !u.is_empty())
+        .map(|u| ProxyConfig::parse(u, config.proxy_username, config.proxy_password))
+        .transpose()
+        .map_err(|e| e.to_string())?;
+    let no_proxy = config
+        .no_proxy
+        .or_else(|| std::env::var("NO_PROXY").ok())
+        .or_else(|| std::env::var("no_proxy").ok())
+        .unwrap_or_default();
+
+    reload_client_cert(app, state, &config.client_cert)?;
 
-    let (tx, mut rx) = channel::<String>(100);
-    *state.ws_sender.lock().await = Some(tx);
+    let (tx, mut rx) = channel::<String>(outbox::OUTBOX_CAPACITY);
+    *conn.ws_sender.lock().await = Some(tx);
 
-    eprintln!("[WS] Connecting to: {}", url);
+    eprintln!("[WS] Connecting {} to: {}", connection_id, url);
+    events.emit("ws-connecting", serde_json::json!({ "url": url }));
 
     let signing_key_bytes = device_keys.signing_key.to_bytes();
     let device_id = device_keys.device_id.clone();
+    let system_resumed = state.system_resumed.clone();
+    let mut suspended = state.system_suspended.subscribe();
+    let outbox_store = state.outbox_store.clone();
+    let frame_limits = config.frame_limits;
+    let ws_compression = config.ws_compression;
+    let activity = state.activity.clone();
+    let client_cert = state.client_cert.clone();
+    let idle_timeout = idle::timeout_from_minutes(config.idle_timeout_minutes);
+    activity.touch();
+    *conn.target.lock().unwrap() = Some(target);
+    let notify_prefs = notify::NotifyPrefs {
+        chat: config.notify_chat,
+        approvals: config.notify_approvals,
+        quiet_hours: config.quiet_hours.clone(),
+    };
+    *conn.rate_limiter.lock().unwrap() = ratelimit::RateLimiter::new(config.rate_limit.clone());
+    *conn.rpc_policy.lock().unwrap() = config.rpc_retry.clone();
+    conn.ws_shutdown.send_replace(false);
+    let mut shutdown_rx = conn.ws_shutdown.subscribe();
+    let task_conn = conn.clone();
+
+    let task = tokio::spawn(async move {
+        let conn = task_conn;
+        let mut auth_mode = AuthMode::Device;
+        // Only one immediate re-sign per skew correction, so a bad hint can't spin
+        let mut skew_retried = false;
+        // Nonces signed on any connection of this task, to catch replays
+        let mut nonce_history = challenge::NonceHistory::default();
 
-    tokio::spawn(async move {
         loop {
-            eprintln!("[WS] Attempting connection to {}", url);
-
-            let request = Request::builder()
-                .uri(&url)
-                .header("Host", "127.0.0.1:18790")
-                .header("Connection", "Upgrade")
-                .header("Upgrade", "websocket")
-                .header("Sec-WebSocket-Version", "13")
-                .header("Sec-WebSocket-Key", generate_key())
-                .header("Origin", "http://127.0.0.1:18790")
-                .body(())
-                .unwrap();
-
-            match connect_async_with_config(request, None, false).await {
-                Ok((ws_stream, _)) => {
-                    eprintln!("[WS] Connected successfully");
-                    let _ = app_handle.emit("ws-connected", true);
+            eprintln!("[WS] Attempting {} connection to {}", conn.id, url);
+            conn_state::transition(&events, &conn.conn_state, ConnState::Connecting);
+
+            // Set when the connect was rejected and the next attempt should
+            // switch auth mode immediately, or when retrying is pointless
+            let mut retry_now = false;
+            let mut fatal = false;
+            // Set when the app is exiting or this connection has been replaced
+            let mut stop = false;
+
+            let dialed = match mock_options {
+                Some(ref options) => mock::dial(options.clone(), &frame_limits).await,
+                None => {
+                    let tls = client_cert
+                        .lock()
+                        .unwrap()
+                        .as_ref()
+                        .map(|c| c.connector.clone());
+                    dial_gateway(
+                        &url,
+                        proxy.as_ref(),
+                        &no_proxy,
+                        &frame_limits,
+                        tls,
+                        ws_compression,
+                    )
+                    .await
+                }
+            };
+            match dialed {
+                Ok((ws_stream, params)) => {
+                    eprintln!("[WS] Connected successfully ({:?})", params);
+                    *conn.negotiated.lock().unwrap() = Some(params);
+                    events.emit("ws-connected", true);
+                    conn_state::transition(&events, &conn.conn_state, ConnState::Authenticating);
 
                     let (mut write, mut read) = ws_stream.split();
                     let mut authenticated = false;
                     let mut connect_sent = false;
+                    // Fresh challenges requested on this connection
+                    let mut refreshes = 0;
 
                     let signing_key = SigningKey::from_bytes(&signing_key_bytes);
                     let dk = DeviceKeys {
@@ -205,47 +788,274 @@ async fn connect_websocket(
                         device_id: device_id.clone(),
                     };
 
+                    // Set on authentication so unsent chat messages go out first
+                    let mut flush_outbox = false;
+                    // JSON until the connect response confirms msgpack
+                    let mut encoding = codec::Encoding::Json;
+                    // v3 frames until the connect response picks a version
+                    let mut protocol_version = protocol::MIN_PROTOCOL;
+                    let mut typing_sweep = tokio::time::interval(std::time::Duration::from_secs(1));
+                    let mut idle_check = tokio::time::interval(idle::IDLE_CHECK_INTERVAL);
+                    let mut idled = false;
+
                     loop {
+                        if std::mem::take(&mut flush_outbox) {
+                            if let Some(ref store) = outbox_store {
+                                if let Err(e) = resend_outbox(
+                                    &mut write,
+                                    store,
+                                    &conn.id,
+                                    &conn.rate_limiter,
+                                    &events,
+                                    encoding,
+                                    protocol_version,
+                                )
+                                .await
+                                {
+                                    eprintln!("[WS] Send error: {}", e);
+                                    break;
+                                }
+                            }
+                        }
+
                         tokio::select! {
+                            res = shutdown_rx.changed() => {
+                                if res.is_err() || *shutdown_rx.borrow() {
+                                    eprintln!("[WS] Shutting down, closing connection");
+                                    if let Err(e) = shutdown::close_gracefully(&mut write, &mut read, &mut rx, authenticated, &conn.rate_limiter, encoding, protocol_version).await {
+                                        eprintln!("[WS] Close error: {}", e);
+                                    }
+                                    stop = true;
+                                    break;
+                                }
+                            }
                             // Timeout for no-auth mode: if no challenge after 2s, assume auth disabled
-                            _ = tokio::time::sleep(std::time::Duration::from_secs(2)), if !connect_sent && !authenticated => {
+                            _ = tokio::time::sleep(std::time::Duration::from_secs(2)), if !connect_sent && !authenticated && refreshes == 0 => {
                                 eprintln!("[WS] No challenge received - assuming no-auth mode");
                                 authenticated = true;
                                 connect_sent = true;
-                                let _ = app_handle.emit("ws-authenticated", true);
+                                events.emit("ws-authenticated", true);
+                                conn_state::transition(&events, &conn.conn_state, ConnState::Ready);
+                                events.emit("ws-auth-mode", serde_json::json!({ "mode": "none" }));
+                                flush_outbox = true;
+                            }
+                            // Periodic sends wait out a suspend
+                            _ = idle_check.tick(), if authenticated && idle_timeout.is_some() && !*suspended.borrow() => {
+                                let timeout = idle_timeout.unwrap_or_default();
+                                if activity.is_idle(std::time::Instant::now(), timeout) && !is_window_focused(events.app()) {
+                                    eprintln!("[WS] No user activity for {:?}, going idle", timeout);
+                                    if let Err(e) = send_paced(&mut write, &conn.rate_limiter, &events, idle::idle_notification(), encoding, protocol_version).await {
+                                        eprintln!("[WS] Send error: {}", e);
+                                    }
+                                    if let Err(e) = shutdown::close_gracefully(&mut write, &mut read, &mut rx, true, &conn.rate_limiter, encoding, protocol_version).await {
+                                        eprintln!("[WS] Close error: {}", e);
+                                    }
+                                    idled = true;
+                                    stop = true;
+                                    break;
+                                }
+                            }
+                            _ = typing_sweep.tick(), if authenticated && !*suspended.borrow() => {
+                                if let Err(e) = expire_typing(&mut write, &conn.typing, &conn.rate_limiter, &events, encoding, protocol_version).await {
+                                    eprintln!("[WS] Send error: {}", e);
+                                    break;
+                                }
+                                if let Err(e) = flush_read_markers(&mut write, &conn.read_markers, &conn.rate_limiter, &events, encoding, protocol_version).await {
+                                    eprintln!("[WS] Send error: {}", e);
+                                    break;
+                                }
                             }
                             msg = read.next() => {
                                 match msg {
                                     Some(Ok(m)) => {
-                                        if m.is_text() {
-                                            let text = m.to_string();
+                                        if let Some(text) = codec::incoming_text(&m) {
+                                            if text.contains("\"id\":\"msg-") {
+                                                if let Some(ref store) = outbox_store {
+                                                    record_chat_ack(store, &text);
+                                                }
+                                            }
 
                                             if !connect_sent && text.contains("\"event\":\"connect.challenge\"") {
                                                 let nonce = extract_nonce(&text).unwrap_or("");
                                                 eprintln!("[WS] Got challenge, nonce: {}", nonce);
 
+                                                let challenge: serde_json::Value =
+                                                    serde_json::from_str(&text).unwrap_or_default();
+                                                let received = challenge::Challenge::parse(&challenge, std::time::Instant::now());
+                                                if let Some(server_ms) = clock::extract_server_time(&challenge) {
+                                                    if let Err(message) = learn_clock_offset(&events, &conn.clock_offset_ms, server_ms, "challenge") {
+                                                        eprintln!("[WS] {}", message);
+                                                        events.error(&message);
+                                                        fatal = true;
+                                                        break;
+                                                    }
+                                                }
+
+                                                let auth = match (auth_mode, auth_token.as_deref()) {
+                                                    (AuthMode::Token, Some(token)) => ConnectAuth::Token(token),
+                                                    _ => ConnectAuth::Device(&dk),
+                                                };
+
+                                                if matches!(auth, ConnectAuth::Device(_)) && !nonce_history.record(nonce) {
+                                                    let message = format!(
+                                                        "Gateway at {} sent a challenge nonce this device already signed ({}). Refusing to sign it again; the gateway may be misbehaving or impersonated.",
+                                                        url, nonce
+                                                    );
+                                                    eprintln!("[WS] SECURITY: {}", message);
+                                                    events.emit("ws-security-alert", &message);
+                                                    events.error(&message);
+                                                    fatal = true;
+                                                    break;
+                                                }
+
                                                 let id = REQUEST_ID_COUNTER.fetch_add(1, Ordering::SeqCst);
                                                 let response = build_connect_request(
                                                     &format!("cp-{}", id),
                                                     nonce,
-                                                    &dk
+                                                    &auth,
+                                                    clock::corrected_millis(conn.clock_offset_ms.load(Ordering::SeqCst)),
                                                 );
-                                                eprintln!("[WS] Sending connect");
+
+                                                if received.is_expired(std::time::Instant::now()) {
+                                                    eprintln!("[WS] Challenge expired before connect was sent");
+                                                    if let Err(e) = request_fresh_challenge(&mut write, &mut refreshes).await {
+                                                        eprintln!("[WS] {}", e);
+                                                        break;
+                                                    }
+                                                    continue;
+                                                }
+
+                                                eprintln!("[WS] Sending connect ({} auth)", auth_mode.as_str());
                                                 if let Err(e) = write.send(tungstenite::Message::Text(response)).await {
                                                     eprintln!("[WS] Send error: {}", e);
                                                     break;
                                                 }
                                                 connect_sent = true;
-                                            } else if text.contains("\"ok\":true") && text.contains("\"id\":\"cp-") {
-                                                eprintln!("[WS] Authenticated!");
-                                                authenticated = true;
-                                                let _ = app_handle.emit("ws-authenticated", true);
-                                            } else if text.contains("\"error\"") {
-                                                eprintln!("[WS] Error: {}", &text[..text.len().min(200)]);
-                                                let _ = app_handle.emit("ws-error", &text);
+                                            } else if !authenticated && text.contains("\"id\":\"cr-") {
+                                                let frame: serde_json::Value =
+                                                    serde_json::from_str(&text).unwrap_or_default();
+                                                if frame["ok"].as_bool() != Some(true) {
+                                                    eprintln!("[WS] Gateway refused connect.refresh: {}", diagnostics::excerpt(&text, 200));
+                                                    break;
+                                                }
+                                                // The new challenge follows as a connect.challenge event
+                                            } else if !authenticated && text.contains("\"id\":\"cp-") {
+                                                let frame: serde_json::Value =
+                                                    serde_json::from_str(&text).unwrap_or_default();
+
+                                                if frame["ok"].as_bool() == Some(true) {
+                                                    let version = protocol::negotiated(&frame);
+                                                    if !protocol::is_supported(version) {
+                                                        report_unsupported_protocol(&events, Some(protocol::ProtocolRange { min: version, max: version }));
+                                                        fatal = true;
+                                                        break;
+                                                    }
+                                                    protocol_version = version;
+                                                    *conn.protocol.lock().unwrap() = Some(version);
+                                                    encoding = codec::Encoding::negotiated(&frame);
+                                                    eprintln!("[WS] Authenticated! (protocol v{}, {} frames)", version, encoding.as_str());
+                                                    authenticated = true;
+                                                    skew_retried = false;
+                                                    flush_outbox = true;
+                                                    events.emit("ws-authenticated", true);
+                                                    conn_state::transition(&events, &conn.conn_state, ConnState::Ready);
+                                                    events.emit(
+                                                        "ws-auth-mode",
+                                                        serde_json::json!({
+                                                            "mode": auth_mode.as_str(),
+                                                            "encoding": encoding.as_str(),
+                                                            "protocol": version,
+                                                        }),
+                                                    );
+                                                } else if protocol::is_unsupported_error(&frame) {
+                                                    report_unsupported_protocol(&events, protocol::gateway_range(&frame));
+                                                    fatal = true;
+                                                    break;
+                                                } else if challenge::is_nonce_expired(&frame) {
+                                                    eprintln!("[WS] Gateway says the challenge expired");
+                                                    connect_sent = false;
+                                                    if let Err(e) = request_fresh_challenge(&mut write, &mut refreshes).await {
+                                                        eprintln!("[WS] {}", e);
+                                                        break;
+                                                    }
+                                                } else if clock::is_skew_error(&frame)
+                                                    && !skew_retried
+                                                    && clock::extract_server_time(&frame).is_some()
+                                                {
+                                                    let server_ms = clock::extract_server_time(&frame).unwrap_or_default();
+                                                    match learn_clock_offset(&events, &conn.clock_offset_ms, server_ms, "error") {
+                                                        Ok(_) => {
+                                                            eprintln!("[WS] Connect rejected for clock skew, re-signing");
+                                                            skew_retried = true;
+                                                            retry_now = true;
+                                                        }
+                                                        Err(message) => {
+                                                            eprintln!("[WS] {}", message);
+                                                            events.error(&message);
+                                                            fatal = true;
+                                                        }
+                                                    }
+                                                    break;
+                                                } else if gateway_error::is_auth_rejection(&frame) {
+                                                    if auth_mode == AuthMode::Device && auth_token.is_some() {
+                                                        eprintln!("[WS] Device auth rejected, retrying with token auth");
+                                                        auth_mode = AuthMode::Token;
+                                                        retry_now = true;
+                                                    } else {
+                                                        let message = if auth_mode == AuthMode::Token {
+                                                            "Gateway rejected both device-key and token authentication. Check that auth_token in ~/.openclaw/claw-pen.json is current, or ask the gateway admin to approve this device.".to_string()
+                                                        } else {
+                                                            format!(
+                                                                "Gateway rejected device-key authentication for device {}. Ask the gateway admin to approve this device, or set auth_token in ~/.openclaw/claw-pen.json.",
+                                                                dk.device_id
+                                                            )
+                                                        };
+                                                        eprintln!("[WS] {}", message);
+                                                        events.error(gateway_error::GatewayError {
+                                                            kind: gateway_error::ErrorKind::AuthFailed,
+                                                            message,
+                                                            retryable: false,
+                                                            ..gateway_error::GatewayError::from_response(&frame)
+                                                        });
+                                                        fatal = true;
+                                                    }
+                                                    break;
+                                                } else {
+                                                    eprintln!("[WS] Error: {}", diagnostics::excerpt(&text, 200));
+                                                    events.error(gateway_error::GatewayError::from_response(&frame));
+                                                }
+                                            } else if text.contains("\"type\":\"res\"")
+                                                && serde_json::from_str::<serde_json::Value>(&text)
+                                                    .map(|frame| conn.pending_requests.resolve(&frame))
+                                                    .unwrap_or(false)
+                                            {
+                                                // Answer to a command's RPC, already handed back
+                                            } else if text.contains("\"error\"")
+                                                && report_gateway_error(&events, &conn.pending_requests, &text)
+                                            {
+                                                // Reported as a typed ws-error, or handed to the RPC it answers
+                                            } else if authenticated && text.contains("\"event\":\"chat.typing\"") {
+                                                let remote = serde_json::from_str::<serde_json::Value>(&text)
+                                                    .ok()
+                                                    .and_then(|frame| typing::parse_remote(&frame, clock::now_millis()));
+                                                if let Some(remote) = remote {
+                                                    events.emit("remote-typing", remote);
+                                                }
+                                            } else if authenticated
+                                                && (text.contains("\"event\":\"chat.delivered\"")
+                                                    || text.contains("\"event\":\"chat.read\""))
+                                            {
+                                                let receipt = serde_json::from_str::<serde_json::Value>(&text)
+                                                    .ok()
+                                                    .and_then(|frame| receipts::parse(&frame));
+                                                if let Some((kind, mut receipt)) = receipt {
+                                                    receipt.correlated = conn.sent_keys.lock().unwrap().contains(&receipt.idempotency_key);
+                                                    events.emit(kind.event_name(), receipt);
+                                                }
                                             } else if authenticated {
-                                                eprintln!("[WS] Event: {}", &text[..text.len().min(100)]);
-                                                let _ = app_handle.emit("ws-message", &text);
+                                                eprintln!("[WS] Event: {}", diagnostics::excerpt(&text, 100));
+                                                events.emit("ws-message", &text);
+                                                notify_if_background(events.app(), &notify_prefs, &text);
                                             }
                                         } else if m.is_close() {
                                             eprintln!("[WS] Server closed");
@@ -254,41 +1064,340 @@ async fn connect_websocket(
                                     }
                                     Some(Err(e)) => {
                                         eprintln!("[WS] Read error: {}", e);
+                                        if transport::is_protocol_error(&e) {
+                                            events.emit("ws-protocol-error", e.to_string());
+                                        }
                                         break;
                                     }
                                     None => break,
                                 }
                             }
                             msg = rx.recv() => {
-                                if let Some(text) = msg {
-                                    if authenticated {
-                                        eprintln!("[WS] TX: {}", &text);
-                                        if let Err(e) = write.send(tungstenite::Message::Text(text)).await {
-                                            eprintln!("[WS] Send error: {}", e);
-                                            break;
+                                match msg {
+                                    Some(text) => {
+                                        if conn.outbox_pressure.update(rx.len()) == Some(false) {
+                                            emit_backpressure(&events, false, rx.len());
+                                        }
+                                        if authenticated {
+                                            if let Err(e) = send_paced(&mut write, &conn.rate_limiter, &events, text, encoding, protocol_version).await {
+                                                eprintln!("[WS] Send error: {}", e);
+                                                break;
+                                            }
                                         }
                                     }
+                                    None => {
+                                        // Our sender was dropped: a newer connection replaced this one
+                                        eprintln!("[WS] Connection superseded, closing");
+                                        if let Err(e) = shutdown::close_gracefully(&mut write, &mut read, &mut rx, false, &conn.rate_limiter, encoding, protocol_version).await {
+                                            eprintln!("[WS] Close error: {}", e);
+                                        }
+                                        stop = true;
+                                        break;
+                                    }
                                 }
                             }
                         }
                     }
 
-                    let _ = app_handle.emit("ws-connected", false);
+                    conn.pending_requests.fail_all();
+                    conn.typing.lock().unwrap().clear();
+                    *conn.negotiated.lock().unwrap() = None;
+                    *conn.protocol.lock().unwrap() = None;
+                    events.emit("ws-connected", false);
+                    // A stopped task leaves the state to whoever stopped it
+                    if idled {
+                        conn_state::transition(&events, &conn.conn_state, ConnState::Idle);
+                    } else if !stop {
+                        let next = if fatal {
+                            ConnState::Error
+                        } else {
+                            ConnState::Disconnected
+                        };
+                        conn_state::transition(&events, &conn.conn_state, next);
+                    }
+                }
+                Err(DialError::Proxy(e)) => {
+                    eprintln!("[WS] Proxy failed: {}", e);
+                    events.error(serde_json::json!({
+                        "source": "proxy",
+                        "code": e.code(),
+                        "message": e.to_string(),
+                    }));
+                    events.emit("ws-connected", false);
+                    conn_state::transition(&events, &conn.conn_state, ConnState::Error);
                 }
-                Err(e) => {
+                Err(DialError::Gateway(e)) => {
                     eprintln!("[WS] Connection failed: {}", e);
-                    let _ = app_handle.emit("ws-connected", false);
+                    events.emit("ws-connected", false);
+                    conn_state::transition(&events, &conn.conn_state, ConnState::Error);
                 }
             }
 
+            if stop {
+                return;
+            }
+
+            if fatal {
+                eprintln!("[WS] Authentication cannot succeed, giving up");
+                return;
+            }
+
+            if retry_now {
+                continue;
+            }
+
             eprintln!("[WS] Reconnecting in 3s...");
-            tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(tokio::time::Duration::from_secs(3)) => {}
+                _ = shutdown_rx.wait_for(|exiting| *exiting) => return,
+                // The network is likely back after a wake; don't sit out the delay
+                _ = system_resumed.notified() => {
+                    eprintln!("[WS] System resumed, reconnecting now");
+                }
+            }
+            // Dialing while the machine goes to sleep can only fail
+            if *suspended.borrow() {
+                eprintln!("[WS] System suspending, reconnecting after resume");
+                tokio::select! {
+                    _ = suspended.wait_for(|asleep| !*asleep) => {}
+                    _ = shutdown_rx.wait_for(|exiting| *exiting) => return,
+                }
+            }
+            if rx.is_closed() {
+                return;
+            }
         }
     });
 
+    // A previous task sees its sender dropped and closes its own socket
+    *conn.ws_task.lock().await = Some(task);
+
     Ok(())
 }
 
+/// Ask the gateway for a new challenge, up to `challenge::MAX_REFRESHES` times
+/// per connection
+async fn request_fresh_challenge<W>(write: &mut W, refreshes: &mut u32) -> Result<(), String>
+where
+    W: Sink<tungstenite::Message, Error = tungstenite::Error> + Unpin,
+{
+    if *refreshes >= challenge::MAX_REFRESHES {
+        return Err(format!(
+            "Challenge expired {} times, reconnecting",
+            challenge::MAX_REFRESHES
+        ));
+    }
+    *refreshes += 1;
+    let id = format!("cr-{}", REQUEST_ID_COUNTER.fetch_add(1, Ordering::SeqCst));
+    eprintln!("[WS] Requesting a fresh challenge ({})", id);
+    write
+        .send(tungstenite::Message::Text(challenge::refresh_request(&id)))
+        .await
+        .map_err(|e| format!("Send error: {}", e))
+}
+
+/// Report an error frame as a typed `ws-error`, or fail the RPC waiting on it;
+/// returns false if the frame carries no error
+fn report_gateway_error(events: &ConnEvents, pending: &rpc::PendingRequests, text: &str) -> bool {
+    let Some(error) = serde_json::from_str::<serde_json::Value>(text)
+        .ok()
+        .and_then(|frame| gateway_error::GatewayError::from_frame(&frame))
+    else {
+        return false;
+    };
+    eprintln!("[WS] Error {}: {}", error.code, error.message);
+    if !pending.fail(&error) {
+        events.error(error);
+    }
+    true
+}
+
+/// Tell the UI the gateway speaks no protocol version we do
+fn report_unsupported_protocol(events: &ConnEvents, gateway: Option<protocol::ProtocolRange>) {
+    let message = protocol::unsupported_message(gateway);
+    eprintln!("[WS] {}", message);
+    events.emit(
+        "ws-protocol-unsupported",
+        serde_json::json!({
+            "supported": protocol::ProtocolRange::SUPPORTED,
+            "gateway": gateway,
+            "message": message,
+        }),
+    );
+    events.error(&message);
+}
+
+/// Send a frame once the rate limiter allows it, telling the UI when it waits
+async fn send_paced<W>(
+    write: &mut W,
+    rate_limiter: &std::sync::Mutex<ratelimit::RateLimiter>,
+    events: &ConnEvents,
+    text: String,
+    encoding: codec::Encoding,
+    protocol_version: u32,
+) -> Result<(), tungstenite::Error>
+where
+    W: Sink<tungstenite::Message, Error = tungstenite::Error> + Unpin,
+{
+    ratelimit::send_paced(
+        write,
+        rate_limiter,
+        text,
+        encoding,
+        protocol_version,
+        |method, wait| {
+            events.emit(
+                "ws-rate-limited",
+                serde_json::json!({
+                    "method": method,
+                    "waitMs": wait.as_millis() as u64,
+                }),
+            );
+        },
+    )
+    .await
+}
+
+/// Resend unacked chat messages in order, keeping their idempotency keys
+async fn resend_outbox<W>(
+    write: &mut W,
+    store: &outbox_store::OutboxStore,
+    connection_id: &str,
+    rate_limiter: &std::sync::Mutex<ratelimit::RateLimiter>,
+    events: &ConnEvents,
+    encoding: codec::Encoding,
+    protocol_version: u32,
+) -> Result<(), tungstenite::Error>
+where
+    W: Sink<tungstenite::Message, Error = tungstenite::Error> + Unpin,
+{
+    let entries = match store.unsent(Some(connection_id)) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("[Outbox] Failed to read unsent messages: {}", e);
+            return Ok(());
+        }
+    };
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!("[Outbox] Resending {} unsent message(s)", entries.len());
+    for mut entry in entries {
+        // Request ids restart with the process, so each resend gets a fresh one
+        entry.request_id = format!("msg-{}", REQUEST_ID_COUNTER.fetch_add(1, Ordering::SeqCst));
+        if let Err(e) = store.set_request_id(entry.id, &entry.request_id) {
+            eprintln!("[Outbox] Failed to update entry {}: {}", entry.id, e);
+            continue;
+        }
+        send_paced(
+            write,
+            rate_limiter,
+            events,
+            entry.to_frame(),
+            encoding,
+            protocol_version,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Tell the gateway we stopped typing where the UI let typing lapse
+async fn expire_typing<W>(
+    write: &mut W,
+    tracker: &std::sync::Mutex<typing::TypingTracker>,
+    rate_limiter: &std::sync::Mutex<ratelimit::RateLimiter>,
+    events: &ConnEvents,
+    encoding: codec::Encoding,
+    protocol_version: u32,
+) -> Result<(), tungstenite::Error>
+where
+    W: Sink<tungstenite::Message, Error = tungstenite::Error> + Unpin,
+{
+    let expired = tracker.lock().unwrap().expire(std::time::Instant::now());
+    for session_key in expired {
+        eprintln!("[WS] Typing in {} expired", session_key);
+        send_paced(
+            write,
+            rate_limiter,
+            events,
+            typing::typing_frame(&session_key, false),
+            encoding,
+            protocol_version,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Send read markers the throttle held back once their window has passed
+async fn flush_read_markers<W>(
+    write: &mut W,
+    markers: &std::sync::Mutex<receipts::ReadMarkers>,
+    rate_limiter: &std::sync::Mutex<ratelimit::RateLimiter>,
+    events: &ConnEvents,
+    encoding: codec::Encoding,
+    protocol_version: u32,
+) -> Result<(), tungstenite::Error>
+where
+    W: Sink<tungstenite::Message, Error = tungstenite::Error> + Unpin,
+{
+    let due = markers.lock().unwrap().due(std::time::Instant::now());
+    for (session_key, message_id) in due {
+        send_paced(
+            write,
+            rate_limiter,
+            events,
+            receipts::read_frame(&session_key, &message_id),
+            encoding,
+            protocol_version,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Mark an outbox entry done once the gateway answers its `chat.send`
+///
+/// A rejection counts too: resending the same message won't change the answer,
+/// and the error still reaches the UI through the normal frame handling.
+fn record_chat_ack(store: &outbox_store::OutboxStore, text: &str) {
+    let Ok(frame) = serde_json::from_str::<serde_json::Value>(text) else {
+        return;
+    };
+    if frame["type"].as_str() != Some("res") {
+        return;
+    }
+    if let Some(request_id) = frame["id"].as_str() {
+        if let Err(e) = store.mark_sent(request_id) {
+            eprintln!("[Outbox] Failed to mark {} sent: {}", request_id, e);
+        }
+    }
+}
+
+/// Show an OS notification for chat/approval events while the main window is unfocused
+fn notify_if_background(app: &AppHandle, prefs: &notify::NotifyPrefs, text: &str) {
+    if !text.contains("\"chat.message\"") && !text.contains("\"approval.requested\"") {
+        return;
+    }
+    let Ok(frame) = serde_json::from_str::<serde_json::Value>(text) else {
+        return;
+    };
+    let Some(notice) = notify::classify(&frame) else {
+        return;
+    };
+    if !prefs.allows(notice.category, notify::local_time()) {
+        return;
+    }
+
+    if is_window_focused(app) {
+        return;
+    }
+
+    notify::show(app, notice);
+}
+
 fn extract_nonce(json: &str) -> Option<&str> {
     if let Some(start) = json.find("\"nonce\":\"") {
         let start = start + 9;
@@ -311,49 +1420,805 @@ fn uuid() -> String {
     )
 }
 
+fn emit_backpressure(events: &ConnEvents, engaged: bool, pending: usize) {
+    if engaged {
+        eprintln!("[WS] Outbox backing up: {} frames pending", pending);
+    } else {
+        eprintln!("[WS] Outbox drained");
+    }
+    events.emit(
+        "ws-backpressure",
+        serde_json::json!({
+            "engaged": engaged,
+            "pending": pending,
+            "capacity": outbox::OUTBOX_CAPACITY,
+        }),
+    );
+}
+
 #[tauri::command]
-async fn send_chat_message(state: State<'_, AppState>, text: String) -> Result<(), String> {
-    let sender = state.ws_sender.lock().await;
+async fn get_ws_stats(
+    state: State<'_, AppState>,
+    connection_id: Option<String>,
+) -> Result<WsStats, String> {
+    let conn = state.find_connection(connection_id.as_deref())?;
+    let queue_depth = conn
+        .ws_sender
+        .lock()
+        .await
+        .as_ref()
+        .map(outbox::pending)
+        .unwrap_or(0);
 
-    if let Some(tx) = sender.as_ref() {
-        let id = REQUEST_ID_COUNTER.fetch_add(1, Ordering::SeqCst);
-        let idempotency_key = uuid();
-        let msg = serde_json::json!({
-            "type": "req",
-            "id": format!("msg-{}", id),
-            "method": "chat.send",
-            "params": {
-                "sessionKey": "main",
-                "message": text,
-                "deliver": false,
-                "idempotencyKey": idempotency_key
+    Ok(WsStats {
+        queue_depth,
+        queue_capacity: outbox::OUTBOX_CAPACITY,
+        backpressure: conn.outbox_pressure.is_engaged(),
+        rate_limit: conn
+            .rate_limiter
+            .lock()
+            .unwrap()
+            .stats(std::time::Instant::now()),
+        rpc: conn.pending_requests.stats(),
+        transport: conn.negotiated.lock().unwrap().clone(),
+        protocol: *conn.protocol.lock().unwrap(),
+        client_cert: state
+            .client_cert
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|c| c.expiry.clone()),
+    })
+}
+
+/// Put a support-friendly summary of this device and connection on the clipboard
+///
+/// Only public identifiers are included; credentials and anything secret-looking
+/// are stripped (see `diagnostics`).
+#[tauri::command]
+async fn copy_diagnostics(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    connection_id: Option<String>,
+) -> Result<String, String> {
+    let conn = state.find_connection(connection_id.as_deref())?;
+    let config = load_config();
+    let target = conn.target.lock().unwrap().clone();
+    let profile = target
+        .as_ref()
+        .and_then(|t| t.profile.clone())
+        .unwrap_or_else(|| config.profile_name().to_string());
+
+    // Only reports; copying diagnostics must not create an identity
+    let (device_id, public_key) = match load_device_keys(&profile) {
+        Ok(Some(keys)) => (
+            keys.device_id,
+            Some(BASE64.encode(keys.signing_key.verifying_key().to_bytes())),
+        ),
+        Ok(None) => ("no device identity".to_string(), None),
+        Err(e) => (format!("unavailable ({})", e), None),
+    };
+    let gateway_url = target
+        .map(|t| t.url)
+        .unwrap_or_else(|| config.agent_gateway_url.clone());
+
+    let known_secrets: Vec<&str> = [
+        config.auth_token.as_deref(),
+        config.orchestrator_token.as_deref(),
+        config.proxy_password.as_deref(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    let last_error = conn
+        .last_error
+        .lock()
+        .unwrap()
+        .as_deref()
+        .map(|e| diagnostics::redact_text(e, &known_secrets));
+
+    let text = diagnostics::Diagnostics {
+        app_version: app.package_info().version.to_string(),
+        profile,
+        device_id,
+        public_key,
+        gateway_url: diagnostics::strip_credentials(&gateway_url),
+        connection_id: conn.id.clone(),
+        connection_state: conn.state().as_str().to_string(),
+        last_error,
+    }
+    .format();
+
+    app.clipboard()
+        .write_text(text.clone())
+        .map_err(|e| format!("Failed to copy to clipboard: {}", e))?;
+    Ok(text)
+}
+
+/// Fetch the gateway's agents, reusing a list younger than `AGENT_CACHE_TTL`
+async fn fetch_agents(conn: &Connection) -> Result<Vec<agents::AgentInfo>, String> {
+    let mut cache = conn.agent_cache.lock().await;
+    if let Some(cached) = cache.as_ref().filter(|c| c.is_fresh()) {
+        return Ok(cached.agents.clone());
+    }
+
+    let tx = conn
+        .ws_sender
+        .lock()
+        .await
+        .clone()
+        .ok_or_else(|| "WebSocket not connected".to_string())?;
+    let id = format!("rpc-{}", REQUEST_ID_COUNTER.fetch_add(1, Ordering::SeqCst));
+    let policy = conn.rpc_policy.lock().unwrap().clone();
+    let payload = rpc::call(
+        &tx,
+        &conn.pending_requests,
+        &policy,
+        &id,
+        "agents.list",
+        serde_json::json!({}),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let list = agents::parse_agent_list(&payload)?;
+    *cache = Some(agents::AgentCache::new(list.clone()));
+    Ok(list)
+}
+
+#[tauri::command]
+async fn list_agents(
+    state: State<'_, AppState>,
+    connection_id: Option<String>,
+) -> Result<Vec<agents::AgentInfo>, String> {
+    let conn = state.find_connection(connection_id.as_deref())?;
+    fetch_agents(&conn).await
+}
+
+/// Queue a chat message for the gateway
+///
+/// Fails immediately when the outbox is full unless `block_on_full` is set, in
+/// which case it waits up to `outbox::BLOCK_TIMEOUT` for room. With `agent_id`
+/// the message goes to that agent instead of whoever owns the main session.
+/// Messages are stored on disk first; if the connection is down they are sent
+/// after the next successful connect. A connection closed for idleness is
+/// reopened first.
+///
+/// Messages over `max_message_bytes` are rejected unless `split` is set, in
+/// which case they go out as several `chat.send` calls sharing a `threadKey`.
+///
+/// With `deliver` the gateway reports delivery and reads, emitted as
+/// `message-delivered` / `message-read` keyed by the idempotency key.
+#[tauri::command]
+async fn send_chat_message(
+    state: State<'_, AppState>,
+    connection_id: Option<String>,
+    text: String,
+    agent_id: Option<String>,
+    block_on_full: Option<bool>,
+    split: Option<bool>,
+    deliver: Option<bool>,
+) -> Result<(), String> {
+    let deliver = deliver.unwrap_or(false);
+    let conn = state.find_connection(connection_id.as_deref())?;
+    state.activity.touch();
+    if let Err(e) = wake_from_idle(&conn).await {
+        eprintln!("[WS] {}", e);
+    }
+
+    if let Some(ref agent_id) = agent_id {
+        agents::validate_agent_id(agent_id)?;
+        let known = fetch_agents(&conn).await?;
+        if !known.iter().any(|a| &a.id == agent_id) {
+            return Err(format!("Unknown agent: {}", agent_id));
+        }
+    }
+
+    let max_bytes = load_config().max_message_bytes;
+    if text.len() > max_bytes {
+        if !split.unwrap_or(false) {
+            return Err(format!(
+                "Message is {} bytes, over the {}-byte limit. Send it with split enabled to deliver it in parts.",
+                text.len(),
+                max_bytes
+            ));
+        }
+        return send_split_message(&conn, &text, agent_id, max_bytes, deliver).await;
+    }
+
+    let request_id = format!("msg-{}", REQUEST_ID_COUNTER.fetch_add(1, Ordering::SeqCst));
+    let idempotency_key = uuid();
+    if deliver {
+        conn.sent_keys.lock().unwrap().record(&idempotency_key);
+    }
+    let draft = outbox_store::OutboxEntry {
+        id: 0,
+        connection_id: conn.id.clone(),
+        request_id,
+        session_key: "main".to_string(),
+        message: text,
+        agent_id,
+        idempotency_key,
+        deliver,
+        created_at: clock::now_millis() / 1000,
+    };
+
+    // Record the message first so it survives a crash or a dropped connection
+    let persisted = state.outbox_store.as_ref().and_then(|store| {
+        store
+            .insert(draft.clone())
+            .map_err(|e| eprintln!("[Outbox] Failed to persist message: {}", e))
+            .ok()
+    });
+    let entry_id = persisted.as_ref().map(|e| e.id);
+    let entry = persisted.unwrap_or(draft);
+
+    // Clone the sender so a blocking send doesn't hold the lock
+    let sender = conn.ws_sender.lock().await.clone();
+
+    let Some(tx) = sender else {
+        if entry_id.is_some() {
+            eprintln!("[Outbox] Not connected, message will be sent after the next connect");
+            return Ok(());
+        }
+        return Err("WebSocket not connected".to_string());
+    };
+
+    let block_for = block_on_full
+        .unwrap_or(false)
+        .then_some(outbox::BLOCK_TIMEOUT);
+    let result = outbox::enqueue(&tx, entry.to_frame(), block_for).await;
+
+    let pending = outbox::pending(&tx);
+    if conn.outbox_pressure.update(pending) == Some(true) {
+        emit_backpressure(&conn.events, true, pending);
+    }
+
+    match (result, entry_id) {
+        (Ok(()), _) => Ok(()),
+        // The task is reconnecting; the stored copy goes out once it's back
+        (Err(outbox::OutboxError::Closed), Some(_)) => Ok(()),
+        (Err(e), id) => {
+            // The caller sees the failure, so don't resend it behind their back
+            if let (Some(id), Some(store)) = (id, state.outbox_store.as_ref()) {
+                let _ = store.discard(id);
             }
-        })
-        .to_string();
+            Err(e.to_string())
+        }
+    }
+}
 
-        tx.send(msg)
-            .await
-            .map_err(|e: tokio::sync::mpsc::error::SendError<String>| e.to_string())?;
+/// Send an oversized message as ordered parts, waiting for each ack before the next
+async fn send_split_message(
+    conn: &Connection,
+    text: &str,
+    agent_id: Option<String>,
+    max_bytes: usize,
+    deliver: bool,
+) -> Result<(), String> {
+    let tx = conn
+        .ws_sender
+        .lock()
+        .await
+        .clone()
+        .ok_or_else(|| "WebSocket not connected".to_string())?;
+
+    let chunks = chunking::split_message(text, max_bytes);
+    let total = chunks.len();
+    let thread_key = uuid();
+    let progress = |sent: usize| {
+        conn.events.emit(
+            "chat-split-progress",
+            serde_json::json!({
+                "threadKey": thread_key,
+                "sent": sent,
+                "total": total,
+            }),
+        );
+    };
+    eprintln!(
+        "[WS] Splitting {}-byte message into {} parts",
+        text.len(),
+        total
+    );
+    progress(0);
+
+    // chat.send isn't idempotent, so the policy never retries these
+    let policy = conn.rpc_policy.lock().unwrap().clone();
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let id = format!("msg-{}", REQUEST_ID_COUNTER.fetch_add(1, Ordering::SeqCst));
+        let idempotency_key = uuid();
+        if deliver {
+            conn.sent_keys.lock().unwrap().record(&idempotency_key);
+        }
+        let mut params = serde_json::json!({
+            "sessionKey": "main",
+            "message": chunk,
+            "deliver": deliver,
+            "idempotencyKey": idempotency_key,
+            "threadKey": thread_key,
+            "part": i + 1,
+            "parts": total,
+        });
+        if let Some(ref agent_id) = agent_id {
+            params["agentId"] = serde_json::json!(agent_id);
+        }
+        rpc::call(
+            &tx,
+            &conn.pending_requests,
+            &policy,
+            &id,
+            "chat.send",
+            params,
+        )
+        .await
+        .map_err(|e| format!("Part {} of {} failed: {}", i + 1, total, e))?;
+        progress(i + 1);
+    }
+    Ok(())
+}
+
+/// Chat messages the gateway hasn't acknowledged yet, oldest first; those of
+/// every connection unless `connection_id` is given
+#[tauri::command]
+async fn get_outbox(
+    state: State<'_, AppState>,
+    connection_id: Option<String>,
+) -> Result<Vec<outbox_store::OutboxEntry>, String> {
+    if let Some(ref id) = connection_id {
+        connections::validate_connection_id(id)?;
+    }
+    match state.outbox_store.as_ref() {
+        Some(store) => store
+            .unsent(connection_id.as_deref())
+            .map_err(|e| e.to_string()),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Drop an unsent chat message so it is never resent
+#[tauri::command]
+async fn discard_outbox_entry(state: State<'_, AppState>, id: i64) -> Result<(), String> {
+    let store = state
+        .outbox_store
+        .as_ref()
+        .ok_or_else(|| "Outbox persistence is unavailable".to_string())?;
+    if store.discard(id).map_err(|e| e.to_string())? {
         Ok(())
     } else {
-        Err("WebSocket not connected".to_string())
+        Err(format!("No unsent outbox entry {}", id))
+    }
+}
+
+/// Tell the gateway whether we're typing in a session
+///
+/// Throttled to one frame per `typing::TYPING_THROTTLE` per session. Typing
+/// lapses after `typing::TYPING_EXPIRY` without a refresh. Frames are dropped,
+/// never queued, while the connection isn't ready.
+#[tauri::command]
+async fn set_typing(
+    state: State<'_, AppState>,
+    connection_id: Option<String>,
+    session_key: String,
+    active: bool,
+) -> Result<(), String> {
+    deeplink::validate_session_key(&session_key)?;
+    let conn = state.find_connection(connection_id.as_deref())?;
+
+    if conn.state() != ConnState::Ready {
+        return Ok(());
+    }
+    let Some(tx) = conn.ws_sender.lock().await.clone() else {
+        return Ok(());
+    };
+
+    let send = conn
+        .typing
+        .lock()
+        .unwrap()
+        .update(&session_key, active, std::time::Instant::now());
+    if send {
+        // A full outbox means real messages are waiting; typing can be skipped
+        if let Err(e) = outbox::enqueue(&tx, typing::typing_frame(&session_key, active), None).await
+        {
+            eprintln!("[WS] Dropped typing frame: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Tell the gateway we've read a session up to a message
+///
+/// At most one marker per session goes out per `receipts::READ_THROTTLE`; the
+/// latest one within a window is sent when it ends. Markers are dropped while
+/// the connection isn't ready.
+#[tauri::command]
+async fn mark_read(
+    state: State<'_, AppState>,
+    connection_id: Option<String>,
+    session_key: String,
+    up_to_message_id: String,
+) -> Result<(), String> {
+    deeplink::validate_session_key(&session_key)?;
+    if up_to_message_id.is_empty() {
+        return Err("up_to_message_id must not be empty".to_string());
+    }
+    let conn = state.find_connection(connection_id.as_deref())?;
+
+    if conn.state() != ConnState::Ready {
+        return Ok(());
+    }
+    let Some(tx) = conn.ws_sender.lock().await.clone() else {
+        return Ok(());
+    };
+
+    let send = conn.read_markers.lock().unwrap().update(
+        &session_key,
+        &up_to_message_id,
+        std::time::Instant::now(),
+    );
+    if send {
+        if let Err(e) = outbox::enqueue(
+            &tx,
+            receipts::read_frame(&session_key, &up_to_message_id),
+            None,
+        )
+        .await
+        {
+            eprintln!("[WS] Dropped read marker: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn is_window_focused(app: &AppHandle) -> bool {
+    app.get_webview_window("main")
+        .and_then(|w| w.is_focused().ok())
+        .unwrap_or(false)
+}
+
+/// Reconnect if the connection was closed for idleness, waiting until it's ready
+async fn wake_from_idle(conn: &Connection) -> Result<(), String> {
+    if conn.state() != ConnState::Idle {
+        return Ok(());
+    }
+
+    {
+        let _guard = conn.wake_lock.lock().await;
+        // Someone else may have woken it while we waited for the lock
+        if conn.state() == ConnState::Idle {
+            let target = conn.target.lock().unwrap().clone();
+            let target = match target {
+                Some(target) => target,
+                None => connections::Target::gateway(transport::normalize_gateway_url(
+                    &load_config().agent_gateway_url,
+                )?),
+            };
+            eprintln!("[WS] Waking {} from idle", conn.id);
+            let app = conn.events.app();
+            open_connection(app, &app.state::<AppState>(), &conn.id, target).await?;
+        }
+    }
+
+    let mut states = conn.conn_state.subscribe();
+    match tokio::time::timeout(
+        idle::WAKE_TIMEOUT,
+        states.wait_for(|s| *s == ConnState::Ready),
+    )
+    .await
+    {
+        Ok(Ok(_)) => Ok(()),
+        _ => Err("Reconnecting after idle timed out".to_string()),
+    }
+}
+
+/// Ask the connection task to close the gateway socket and wait for it to finish
+async fn shutdown_websocket(conn: &Connection) {
+    conn.ws_shutdown.send_replace(true);
+    conn.ws_sender.lock().await.take();
+
+    let task = conn.ws_task.lock().await.take();
+    if let Some(task) = task {
+        // Close handshake plus a little slack for the flush
+        let deadline = shutdown::CLOSE_TIMEOUT + std::time::Duration::from_secs(1);
+        if tokio::time::timeout(deadline, task).await.is_err() {
+            eprintln!(
+                "[WS] {} connection task did not stop in time, exiting anyway",
+                conn.id
+            );
+        }
+    }
+}
+
+/// Close every connection at once
+async fn shutdown_all(state: &AppState) {
+    let conns = state.all_connections();
+    futures_util::future::join_all(conns.iter().map(|conn| shutdown_websocket(conn))).await;
+}
+
+/// Check that an orchestrator answers before it is saved in settings
+///
+/// Sends no credentials; each request is bounded by `health::CHECK_TIMEOUT`.
+/// Only a malformed URL is an error, every network outcome is in the result.
+#[tauri::command]
+async fn check_orchestrator(url: String) -> Result<health::OrchestratorHealth, String> {
+    health::check(&url).await
+}
+
+/// Look for gateways on localhost and via the orchestrator
+///
+/// Bounded by `discovery::DISCOVERY_TIMEOUT`; `cancel_discovery` abandons it.
+#[tauri::command]
+async fn discover_gateways(
+    state: State<'_, AppState>,
+) -> Result<Vec<discovery::GatewayCandidate>, String> {
+    let config = load_config();
+    let orchestrator = async {
+        match config
+            .orchestrator_token
+            .as_deref()
+            .filter(|t| !t.is_empty())
+        {
+            Some(token) => discovery::query_orchestrator(&config.orchestrator_url, token).await,
+            None => Vec::new(),
+        }
+    };
+    let search = async {
+        let (mut local, remote) = tokio::join!(discovery::probe_localhost(), orchestrator);
+        let remote: Vec<_> = remote
+            .into_iter()
+            .filter(|r| !local.iter().any(|l| l.url == r.url))
+            .collect();
+        local.extend(remote);
+        local
+    };
+
+    tokio::select! {
+        result = tokio::time::timeout(discovery::DISCOVERY_TIMEOUT, search) => {
+            let found = result.unwrap_or_default();
+            eprintln!("[Discovery] Found {} gateway(s)", found.len());
+            Ok(found)
+        }
+        _ = state.discovery_cancel.notified() => Err("Discovery cancelled".to_string()),
+    }
+}
+
+#[tauri::command]
+async fn cancel_discovery(state: State<'_, AppState>) -> Result<(), String> {
+    state.discovery_cancel.notify_waiters();
+    Ok(())
+}
+
+#[tauri::command]
+async fn disconnect_websocket(
+    state: State<'_, AppState>,
+    connection_id: Option<String>,
+) -> Result<(), String> {
+    let conn = state.find_connection(connection_id.as_deref())?;
+    shutdown_websocket(&conn).await;
+    conn_state::transition(&conn.events, &conn.conn_state, ConnState::Disconnected);
+    if conn.id != DEFAULT_CONNECTION {
+        state.connections.lock().unwrap().remove(&conn.id);
+    }
+    Ok(())
+}
+
+/// Disconnect every gateway
+#[tauri::command]
+async fn disconnect_all(state: State<'_, AppState>) -> Result<(), String> {
+    shutdown_all(&state).await;
+    for conn in state.all_connections() {
+        conn_state::transition(&conn.events, &conn.conn_state, ConnState::Disconnected);
+    }
+    state
+        .connections
+        .lock()
+        .unwrap()
+        .retain(|id, _| id == DEFAULT_CONNECTION);
+    Ok(())
+}
+
+fn handle_tray_action(app: &AppHandle, action: tray::TrayAction) {
+    let app = app.clone();
+    match action {
+        tray::TrayAction::Connect => {
+            tauri::async_runtime::spawn(async move {
+                if let Err(e) = connect_default(&app).await {
+                    eprintln!("[Tray] Connect failed: {}", e);
+                    let state = app.state::<AppState>();
+                    state.connection(&app, DEFAULT_CONNECTION).events.error(&e);
+                }
+            });
+        }
+        tray::TrayAction::Disconnect => {
+            tauri::async_runtime::spawn(async move {
+                let _ = disconnect_all(app.state()).await;
+            });
+        }
+        tray::TrayAction::OpenLogs => {
+            if let Some(window) = app.get_webview_window("main") {
+                let _ = window.show();
+                let _ = window.set_focus();
+            }
+            let _ = app.emit("open-logs", ());
+        }
+        // Goes through the exit hook, which closes the socket first
+        tray::TrayAction::Quit => app.exit(0),
+    }
+}
+
+/// Act on a `claw-pen://` link; invalid links are logged and otherwise ignored
+fn handle_deep_link(app: &AppHandle, url: &tauri::Url) {
+    let link = match deeplink::parse(url) {
+        Ok(link) => link,
+        Err(e) => {
+            eprintln!("[DeepLink] Rejected link: {}", e);
+            return;
+        }
+    };
+
+    let state = app.state::<AppState>();
+    let default = state.connection(app, DEFAULT_CONNECTION);
+    let active = matches!(
+        default.state(),
+        ConnState::Connecting | ConnState::Authenticating | ConnState::Ready
+    );
+    let config = load_config();
+    let matches_config = link
+        .gateway
+        .as_deref()
+        .map(|g| deeplink::same_gateway(g, &config.agent_gateway_url))
+        .unwrap_or(true);
+
+    // Never switch an active connection behind the user's back
+    let autoconnecting = link.autoconnect && matches_config && !active;
+    let event = deeplink::DeepLinkEvent {
+        link,
+        autoconnecting,
+        requires_confirmation: active,
+    };
+    eprintln!(
+        "[DeepLink] {} (autoconnect: {})",
+        event.link.action, autoconnecting
+    );
+    let _ = app.emit("deep-link", &event);
+
+    if autoconnecting {
+        let app = app.clone();
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = connect_default(&app).await {
+                eprintln!("[DeepLink] Connect failed: {}", e);
+                default.events.error(&e);
+            }
+        });
     }
 }
 
 fn main() {
+    if let Err(e) = profiles::migrate_legacy_device() {
+        eprintln!("[Device] Failed to migrate legacy device file: {}", e);
+    }
+
+    let outbox_path = dirs::home_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join(".openclaw")
+        .join("claw-pen-outbox.db");
+    let outbox_store = match outbox_store::OutboxStore::open(&outbox_path) {
+        Ok(store) => Some(Arc::new(store)),
+        Err(e) => {
+            eprintln!("[Outbox] Persistence disabled: {:#}", e);
+            None
+        }
+    };
+
     let state = AppState {
-        ws_sender: Arc::new(tokio::sync::Mutex::new(None)),
+        connections: std::sync::Mutex::new(HashMap::new()),
+        exiting: AtomicBool::new(false),
+        system_resumed: Arc::new(tokio::sync::Notify::new()),
+        system_suspended: Arc::new(tokio::sync::watch::channel(false).0),
+        discovery_cancel: Arc::new(tokio::sync::Notify::new()),
+        outbox_store,
+        activity: Arc::new(idle::Activity::default()),
+        client_cert: Arc::new(std::sync::Mutex::new(None)),
     };
 
-    tauri::Builder::default()
+    let app = tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_http::init())
+        .plugin(tauri_plugin_notification::init())
+        .plugin(tauri_plugin_deep_link::init())
+        .plugin(tauri_plugin_clipboard_manager::init())
         .manage(state)
+        .setup(|app| {
+            // The tray follows the default connection
+            let default = app
+                .state::<AppState>()
+                .connection(app.handle(), DEFAULT_CONNECTION);
+            tray::setup(
+                app.handle(),
+                default.conn_state.subscribe(),
+                handle_tray_action,
+            );
+
+            power::spawn_monitor(
+                app.handle().clone(),
+                app.state::<AppState>().system_resumed.clone(),
+                app.state::<AppState>().system_suspended.clone(),
+            );
+
+            // Linux and Windows only know the scheme once we register it at runtime
+            #[cfg(any(target_os = "linux", windows))]
+            {
+                if let Err(e) = app.deep_link().register_all() {
+                    eprintln!("[DeepLink] Failed to register URL scheme: {}", e);
+                }
+            }
+            if let Ok(Some(urls)) = app.deep_link().get_current() {
+                for url in urls {
+                    handle_deep_link(app.handle(), &url);
+                }
+            }
+            let handle = app.handle().clone();
+            app.deep_link().on_open_url(move |event| {
+                for url in event.urls() {
+                    handle_deep_link(&handle, &url);
+                }
+            });
+            Ok(())
+        })
+        .on_window_event(|window, event| {
+            if let WindowEvent::Focused(true) = event {
+                let state = window.state::<AppState>();
+                state.activity.touch();
+                for conn in state.all_connections() {
+                    if conn.state() == ConnState::Idle {
+                        tauri::async_runtime::spawn(async move {
+                            if let Err(e) = wake_from_idle(&conn).await {
+                                eprintln!("[WS] {}", e);
+                            }
+                        });
+                    }
+                }
+            }
+        })
         .invoke_handler(tauri::generate_handler![
             get_config,
+            set_config,
+            list_device_profiles,
+            create_device_profile,
+            sign_payload,
+            verify_device_identity,
             connect_websocket,
+            disconnect_websocket,
+            disconnect_all,
+            discover_gateways,
+            check_orchestrator,
+            cancel_discovery,
             send_chat_message,
+            set_typing,
+            mark_read,
+            get_ws_stats,
+            copy_diagnostics,
+            get_outbox,
+            discard_outbox_entry,
+            list_agents,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application");
+
+    app.run(|app_handle, event| {
+        if let RunEvent::ExitRequested { api, .. } = event {
+            let state = app_handle.state::<AppState>();
+            if state.exiting.swap(true, Ordering::SeqCst) {
+                // Second pass, after the socket is closed: let the app exit
+                return;
+            }
+            api.prevent_exit();
+            let app_handle = app_handle.clone();
+            tauri::async_runtime::spawn(async move {
+                shutdown_all(&app_handle.state::<AppState>()).await;
+                app_handle.exit(0);
+            });
+        }
+    });
 }
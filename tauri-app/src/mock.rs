@@ -0,0 +1,326 @@
+// In-process mock gateway for UI development
+// `connect_websocket` with `mock: true` dials this instead of a socket. It
+// speaks the gateway protocol over an in-memory stream, so the connection task
+// and every event it emits run exactly as they would against a real gateway.
+// Chat messages are answered with a streamed assistant reply: an echo, or the
+// next entry of a scripted-responses file for deterministic conversations.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+use tungstenite::Message;
+
+use crate::transport::{self, DialError, FrameLimits, GatewayStream, Negotiated};
+use crate::{codec, protocol};
+
+/// Shown as the gateway URL of a mock connection
+pub const MOCK_URL: &str = "ws://mock.invalid/ws";
+
+const MOCK_HOST: &str = "mock.invalid";
+
+/// Wait before a reply starts streaming, unless the caller picks one
+pub const DEFAULT_REPLY_DELAY: Duration = Duration::from_millis(500);
+
+/// Gap between streamed deltas
+const DELTA_INTERVAL: Duration = Duration::from_millis(40);
+
+const DUPLEX_BUFFER: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct MockOptions {
+    pub reply_delay: Duration,
+    /// Replies used in order before falling back to echoing
+    pub script: Vec<String>,
+}
+
+impl Default for MockOptions {
+    fn default() -> Self {
+        Self {
+            reply_delay: DEFAULT_REPLY_DELAY,
+            script: Vec::new(),
+        }
+    }
+}
+
+/// Read a scripted-responses file: a JSON array of reply strings
+pub fn load_script(path: &Path) -> Result<Vec<String>, String> {
+    let data = std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read scripted responses {}: {}", path.display(), e))?;
+    serde_json::from_str(&data).map_err(|e| {
+        format!(
+            "Scripted responses {} must be a JSON array of strings: {}",
+            path.display(),
+            e
+        )
+    })
+}
+
+/// Start a mock gateway and connect to it
+pub async fn dial(
+    options: MockOptions,
+    limits: &FrameLimits,
+) -> Result<(GatewayStream, Negotiated), DialError> {
+    let (client, server) = tokio::io::duplex(DUPLEX_BUFFER);
+    tokio::spawn(async move {
+        if let Err(e) = serve(server, options).await {
+            eprintln!("[Mock] Gateway stopped: {}", e);
+        }
+    });
+    transport::handshake(Box::new(client), MOCK_URL, MOCK_HOST, limits, None, false).await
+}
+
+fn event(name: &str, payload: Value) -> Message {
+    Message::Text(json!({ "type": "event", "event": name, "payload": payload }).to_string())
+}
+
+fn response(id: &str, payload: Value) -> Message {
+    Message::Text(json!({ "type": "res", "id": id, "ok": true, "payload": payload }).to_string())
+}
+
+fn challenge() -> Message {
+    event(
+        "connect.challenge",
+        json!({
+            "nonce": format!("{:032x}", rand::random::<u128>()),
+            "ts": crate::clock::now_millis(),
+        }),
+    )
+}
+
+/// Split a reply into the pieces streamed as deltas, keeping whitespace
+fn chunks(reply: &str) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+    for c in reply.chars() {
+        if c.is_whitespace() && !current.trim().is_empty() {
+            pieces.push(std::mem::take(&mut current));
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        pieces.push(current);
+    }
+    pieces
+}
+
+/// The frames answering one chat message: deltas, the final message, and the
+/// `chat.message` event agents send alongside it
+fn reply_frames(run_id: &str, session_key: &str, reply: &str) -> Vec<Message> {
+    let content =
+        |text: &str| json!({ "role": "assistant", "content": [{ "type": "text", "text": text }] });
+    let mut frames: Vec<Message> = chunks(reply)
+        .iter()
+        .map(|piece| {
+            event(
+                "chat",
+                json!({
+                    "runId": run_id,
+                    "sessionKey": session_key,
+                    "state": "delta",
+                    "message": content(piece),
+                }),
+            )
+        })
+        .collect();
+    frames.push(event(
+        "chat",
+        json!({
+            "runId": run_id,
+            "sessionKey": session_key,
+            "state": "final",
+            "message": content(reply),
+        }),
+    ));
+    frames.push(event(
+        "chat.message",
+        json!({
+            "id": run_id,
+            "sessionKey": session_key,
+            "role": "assistant",
+            "agentName": "Mock agent",
+            "message": reply,
+        }),
+    ));
+    frames
+}
+
+fn next_reply(script: &Mutex<VecDeque<String>>, message: &str) -> String {
+    script
+        .lock()
+        .unwrap()
+        .pop_front()
+        .unwrap_or_else(|| format!("Echo: {}", message))
+}
+
+async fn serve(io: DuplexStream, options: MockOptions) -> Result<(), tungstenite::Error> {
+    let ws = tokio_tungstenite::accept_async(io).await?;
+    let (mut sink, mut stream) = ws.split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+
+    let writer = tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let script = Mutex::new(VecDeque::from(options.script));
+    let _ = tx.send(challenge());
+
+    while let Some(message) = stream.next().await {
+        let message = message?;
+        if message.is_close() {
+            break;
+        }
+        let Some(text) = codec::incoming_text(&message) else {
+            continue;
+        };
+        let Ok(frame) = serde_json::from_str::<Value>(&text) else {
+            continue;
+        };
+        // Notifications such as chat.typing carry no id and get no answer
+        let Some(id) = frame["id"].as_str() else {
+            continue;
+        };
+        let params = &frame["params"];
+
+        match frame["method"].as_str().unwrap_or_default() {
+            "connect" => {
+                let _ = tx.send(response(
+                    id,
+                    json!({ "type": "hello-ok", "protocol": protocol::MIN_PROTOCOL, "mock": true }),
+                ));
+            }
+            "connect.refresh" => {
+                let _ = tx.send(response(id, json!({})));
+                let _ = tx.send(challenge());
+            }
+            "agents.list" => {
+                let _ = tx.send(response(
+                    id,
+                    json!({ "agents": [{ "id": "mock", "name": "Mock agent", "status": "running" }] }),
+                ));
+            }
+            "chat.send" => {
+                let run_id = format!("mock-run-{}", id);
+                let _ = tx.send(response(id, json!({ "runId": run_id })));
+
+                let session_key = params["sessionKey"]
+                    .as_str()
+                    .or_else(|| params["session"].as_str())
+                    .unwrap_or("main")
+                    .to_string();
+                let reply = next_reply(&script, params["message"].as_str().unwrap_or_default());
+                let tx = tx.clone();
+                let delay = options.reply_delay;
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    for frame in reply_frames(&run_id, &session_key, &reply) {
+                        if tx.send(frame).is_err() {
+                            return;
+                        }
+                        tokio::time::sleep(DELTA_INTERVAL).await;
+                    }
+                });
+            }
+            // Everything else is accepted without a payload
+            _ => {
+                let _ = tx.send(response(id, json!({})));
+            }
+        }
+    }
+
+    drop(tx);
+    let _ = writer.await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(message: &Message) -> Value {
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_chunks_rebuild_the_reply() {
+        let reply = "Hello there,  world";
+        let pieces = chunks(reply);
+        assert_eq!(pieces, vec!["Hello", " there,", "  world"]);
+        assert_eq!(pieces.concat(), reply);
+    }
+
+    #[test]
+    fn test_reply_frames() {
+        let frames = reply_frames("run-1", "main", "Hi you");
+        assert_eq!(frames.len(), 4);
+        assert_eq!(text(&frames[0])["payload"]["state"], "delta");
+        assert_eq!(
+            text(&frames[1])["payload"]["message"]["content"][0]["text"],
+            " you"
+        );
+        assert_eq!(text(&frames[2])["payload"]["state"], "final");
+        let message = text(&frames[3]);
+        assert_eq!(message["event"], "chat.message");
+        assert_eq!(message["payload"]["role"], "assistant");
+    }
+
+    #[test]
+    fn test_script_then_echo() {
+        let script = Mutex::new(VecDeque::from(vec!["First".to_string()]));
+        assert_eq!(next_reply(&script, "one"), "First");
+        assert_eq!(next_reply(&script, "two"), "Echo: two");
+    }
+
+    #[tokio::test]
+    async fn test_conversation_over_mock() {
+        let options = MockOptions {
+            reply_delay: Duration::ZERO,
+            script: vec!["Scripted".to_string()],
+        };
+        let (mut ws, _) = dial(options, &FrameLimits::default()).await.ok().unwrap();
+
+        let challenge = text(&ws.next().await.unwrap().unwrap());
+        assert_eq!(challenge["event"], "connect.challenge");
+
+        ws.send(Message::Text(
+            json!({"type": "req", "id": "cp-1", "method": "connect", "params": {}}).to_string(),
+        ))
+        .await
+        .unwrap();
+        let hello = text(&ws.next().await.unwrap().unwrap());
+        assert_eq!(hello["ok"], true);
+
+        ws.send(Message::Text(
+            json!({"type": "req", "id": "msg-1", "method": "chat.send", "params": {"sessionKey": "main", "message": "hi"}}).to_string(),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(text(&ws.next().await.unwrap().unwrap())["id"], "msg-1");
+
+        let mut streamed = String::new();
+        loop {
+            let frame = text(&ws.next().await.unwrap().unwrap());
+            if frame["payload"]["state"] == "final" {
+                assert_eq!(
+                    frame["payload"]["message"]["content"][0]["text"],
+                    "Scripted"
+                );
+                break;
+            }
+            streamed.push_str(
+                frame["payload"]["message"]["content"][0]["text"]
+                    .as_str()
+                    .unwrap(),
+            );
+        }
+        assert_eq!(streamed, "Scripted");
+    }
+}
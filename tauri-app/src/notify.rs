@@ -0,0 +1,362 @@
+// Desktop notifications for gateway events
+// Only fires while the main window is unfocused, so background approval
+// requests and agent replies aren't missed. The notification plugin reports no
+// clicks on desktop, so notifications go through the backends it wraps, which
+// do: a `default` action over D-Bus on Linux, `NSUserNotification` on macOS
+// and the toast's `Activated` event on Windows. A click focuses the main
+// window and emits `notification-clicked` with the originating event id.
+
+use chrono::{Local, NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Longest notification body we show
+pub const MAX_BODY_CHARS: usize = 140;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuietHours {
+    /// Local time `HH:MM` when notifications stop
+    pub start: String,
+    /// Local time `HH:MM` when they resume; may be earlier than `start` to span midnight
+    pub end: String,
+}
+
+impl QuietHours {
+    /// Whether `now` falls inside the window; a malformed window never silences anything
+    pub fn contains(&self, now: NaiveTime) -> bool {
+        let parse = |s: &str| NaiveTime::parse_from_str(s.trim(), "%H:%M").ok();
+        let (Some(start), Some(end)) = (parse(&self.start), parse(&self.end)) else {
+            return false;
+        };
+        let now = NaiveTime::from_hms_opt(now.hour(), now.minute(), 0).unwrap_or(now);
+
+        if start <= end {
+            now >= start && now < end
+        } else {
+            now >= start || now < end
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Chat,
+    Approval,
+}
+
+/// Notification settings taken from `AppConfig`
+#[derive(Debug, Clone)]
+pub struct NotifyPrefs {
+    pub chat: bool,
+    pub approvals: bool,
+    pub quiet_hours: Option<QuietHours>,
+}
+
+impl NotifyPrefs {
+    pub fn allows(&self, category: Category, now: NaiveTime) -> bool {
+        let enabled = match category {
+            Category::Chat => self.chat,
+            Category::Approval => self.approvals,
+        };
+        enabled && !self.quiet_hours.as_ref().is_some_and(|q| q.contains(now))
+    }
+}
+
+/// A notification worth showing, extracted from a gateway event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notice {
+    pub category: Category,
+    pub title: String,
+    pub body: String,
+    /// Id of the originating event, echoed in `notification-clicked`
+    pub event_id: Option<String>,
+}
+
+/// Turn an event frame into a notice, if it is one we notify about
+pub fn classify(frame: &serde_json::Value) -> Option<Notice> {
+    if frame["type"].as_str() != Some("event") {
+        return None;
+    }
+    let payload = &frame["payload"];
+    let event_id = payload["id"]
+        .as_str()
+        .or_else(|| frame["id"].as_str())
+        .map(str::to_string);
+    let text = |keys: &[&str]| {
+        keys.iter()
+            .find_map(|k| payload[*k].as_str())
+            .unwrap_or("")
+            .to_string()
+    };
+
+    match frame["event"].as_str()? {
+        "chat.message" => {
+            // Our own messages echo back too; only agents are interesting
+            if payload["role"].as_str() == Some("user") {
+                return None;
+            }
+            let from = text(&["agentName", "agentId", "from"]);
+            Some(Notice {
+                category: Category::Chat,
+                title: if from.is_empty() {
+                    "New message".to_string()
+                } else {
+                    format!("Message from {}", from)
+                },
+                body: sanitize_body(&text(&["message", "text", "content"])),
+                event_id,
+            })
+        }
+        "approval.requested" => Some(Notice {
+            category: Category::Approval,
+            title: "Approval requested".to_string(),
+            body: sanitize_body(&text(&["summary", "description", "command"])),
+            event_id,
+        }),
+        _ => None,
+    }
+}
+
+/// Strip markdown and control characters and cap the length for a notification body
+pub fn sanitize_body(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            // [label](url) keeps only the label
+            '[' => {
+                let label: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                if chars.peek() == Some(&'(') {
+                    chars.by_ref().take_while(|&c| c != ')').for_each(drop);
+                }
+                plain.push_str(&label);
+            }
+            '*' | '_' | '`' | '~' | '#' | '>' => {}
+            c if c.is_control() => plain.push(' '),
+            c => plain.push(c),
+        }
+    }
+
+    let collapsed = plain.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.chars().count() <= MAX_BODY_CHARS {
+        return collapsed;
+    }
+    let mut truncated: String = collapsed.chars().take(MAX_BODY_CHARS - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Current local wall-clock time
+pub fn local_time() -> NaiveTime {
+    Local::now().time()
+}
+
+/// Show `notice`, focusing the main window if it is clicked
+pub fn show(app: &AppHandle, notice: Notice) {
+    native::show(app, notice);
+}
+
+/// Bring the main window forward and tell the UI which event was clicked
+fn clicked(app: &AppHandle, event_id: Option<String>) {
+    eprintln!("[Notify] Notification clicked: {:?}", event_id);
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.unminimize();
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+    let _ = app.emit(
+        "notification-clicked",
+        serde_json::json!({ "eventId": event_id }),
+    );
+}
+
+#[cfg(target_os = "linux")]
+mod native {
+    use super::Notice;
+    use tauri::AppHandle;
+
+    /// Clicking the body invokes the action with this key
+    const DEFAULT_ACTION: &str = "default";
+
+    pub fn show(app: &AppHandle, notice: Notice) {
+        let app = app.clone();
+        // Waiting for the action blocks until the notification goes away
+        tauri::async_runtime::spawn_blocking(move || {
+            let shown = notify_rust::Notification::new()
+                .summary(&notice.title)
+                .body(&notice.body)
+                .auto_icon()
+                .action(DEFAULT_ACTION, "Open")
+                .show();
+            match shown {
+                Ok(handle) => handle.wait_for_action(|action| {
+                    if action == DEFAULT_ACTION {
+                        super::clicked(&app, notice.event_id);
+                    }
+                }),
+                Err(e) => eprintln!("[Notify] Failed to show notification: {}", e),
+            }
+        });
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod native {
+    use super::Notice;
+    use mac_notification_sys::NotificationResponse;
+    use tauri::AppHandle;
+
+    pub fn show(app: &AppHandle, notice: Notice) {
+        let app = app.clone();
+        // Fails once the application is set, which is fine
+        let _ = mac_notification_sys::set_application(if tauri::is_dev() {
+            "com.apple.Terminal"
+        } else {
+            &app.config().identifier
+        });
+        // Waiting for the click blocks until the notification goes away
+        tauri::async_runtime::spawn_blocking(move || {
+            let response = mac_notification_sys::Notification::new()
+                .title(&notice.title)
+                .message(&notice.body)
+                .wait_for_click(true)
+                .send();
+            match response {
+                Ok(NotificationResponse::Click) => super::clicked(&app, notice.event_id),
+                Ok(_) => {}
+                Err(e) => eprintln!("[Notify] Failed to show notification: {}", e),
+            }
+        });
+    }
+}
+
+#[cfg(windows)]
+mod native {
+    use super::Notice;
+    use tauri::AppHandle;
+    use tauri_winrt_notification::Toast;
+
+    pub fn show(app: &AppHandle, notice: Notice) {
+        let clicked_app = app.clone();
+        let event_id = notice.event_id;
+        let shown = Toast::new(&app_id(app))
+            .title(&notice.title)
+            .text1(&notice.body)
+            .on_activated(move |_action| {
+                super::clicked(&clicked_app, event_id.clone());
+                Ok(())
+            })
+            .show();
+        if let Err(e) = shown {
+            eprintln!("[Notify] Failed to show notification: {:?}", e);
+        }
+    }
+
+    /// Toasts only show under a registered AppUserModelID, which an app run
+    /// from the build directory doesn't have, so those borrow PowerShell's
+    fn app_id(app: &AppHandle) -> String {
+        let from_build = tauri::utils::platform::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(|dir| dir.to_path_buf()))
+            .is_some_and(|dir| dir.ends_with("target/debug") || dir.ends_with("target/release"));
+        if from_build {
+            Toast::POWERSHELL_APP_ID.to_string()
+        } else {
+            app.config().identifier.clone()
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod native {
+    use super::Notice;
+    use tauri::AppHandle;
+    use tauri_plugin_notification::NotificationExt;
+
+    /// No click callback here; the notification only informs
+    pub fn show(app: &AppHandle, notice: Notice) {
+        let shown = app
+            .notification()
+            .builder()
+            .title(&notice.title)
+            .body(&notice.body)
+            .show();
+        if let Err(e) = shown {
+            eprintln!("[Notify] Failed to show notification: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_sanitize_body() {
+        assert_eq!(
+            sanitize_body("**Done!** see [the PR](https://x/y)\n\n```rm -rf```"),
+            "Done! see the PR rm -rf"
+        );
+        let long = sanitize_body(&"word ".repeat(100));
+        assert_eq!(long.chars().count(), MAX_BODY_CHARS);
+        assert!(long.ends_with('…'));
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let overnight = QuietHours {
+            start: "22:00".to_string(),
+            end: "07:30".to_string(),
+        };
+        assert!(overnight.contains(at(23, 15)));
+        assert!(overnight.contains(at(3, 0)));
+        assert!(!overnight.contains(at(7, 30)));
+        assert!(!overnight.contains(at(12, 0)));
+
+        let lunch = QuietHours {
+            start: "12:00".to_string(),
+            end: "13:00".to_string(),
+        };
+        assert!(lunch.contains(at(12, 30)));
+        assert!(!lunch.contains(at(13, 0)));
+
+        let broken = QuietHours {
+            start: "noon".to_string(),
+            end: "13:00".to_string(),
+        };
+        assert!(!broken.contains(at(12, 30)));
+    }
+
+    #[test]
+    fn test_classify() {
+        let chat = serde_json::json!({
+            "type": "event",
+            "event": "chat.message",
+            "payload": {"id": "m1", "role": "assistant", "agentName": "Coder", "message": "_hi_"}
+        });
+        let notice = classify(&chat).unwrap();
+        assert_eq!(notice.category, Category::Chat);
+        assert_eq!(notice.title, "Message from Coder");
+        assert_eq!(notice.body, "hi");
+        assert_eq!(notice.event_id.as_deref(), Some("m1"));
+
+        let own = serde_json::json!({
+            "type": "event",
+            "event": "chat.message",
+            "payload": {"role": "user", "message": "hello"}
+        });
+        assert!(classify(&own).is_none());
+
+        let approval = serde_json::json!({
+            "type": "event",
+            "event": "approval.requested",
+            "payload": {"id": "a9", "command": "git push"}
+        });
+        assert_eq!(classify(&approval).unwrap().category, Category::Approval);
+    }
+}
@@ -0,0 +1,164 @@
+// Outgoing frame queue between the Tauri commands and the WebSocket task
+// The queue is bounded; when the gateway is slow we tell the UI instead of
+// blocking the command forever.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc::error::{SendTimeoutError, TrySendError};
+use tokio::sync::mpsc::Sender;
+
+/// Frames that may be waiting for the WebSocket task at once
+pub const OUTBOX_CAPACITY: usize = 100;
+
+/// Queue depth at which we tell the UI to slow down
+pub const HIGH_WATER_MARK: usize = 80;
+
+/// Queue depth at which the backpressure warning is cleared
+pub const LOW_WATER_MARK: usize = 20;
+
+/// How long a `block_on_full` send waits for room before giving up
+pub const BLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboxError {
+    /// The queue is at capacity
+    Full { pending: usize },
+    /// A blocking send waited `BLOCK_TIMEOUT` without the queue draining
+    Timeout { pending: usize },
+    /// The WebSocket task is gone
+    Closed,
+}
+
+impl fmt::Display for OutboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutboxError::Full { pending } => {
+                write!(f, "outbox full, {} messages pending", pending)
+            }
+            OutboxError::Timeout { pending } => write!(
+                f,
+                "outbox still full after {:?}, {} messages pending",
+                BLOCK_TIMEOUT, pending
+            ),
+            OutboxError::Closed => write!(f, "WebSocket not connected"),
+        }
+    }
+}
+
+impl std::error::Error for OutboxError {}
+
+/// Frames queued but not yet picked up by the WebSocket task
+pub fn pending(tx: &Sender<String>) -> usize {
+    tx.max_capacity() - tx.capacity()
+}
+
+/// Queue a frame, failing fast when the outbox is full
+///
+/// With `block_for` set, wait up to that long for room instead.
+pub async fn enqueue(
+    tx: &Sender<String>,
+    frame: String,
+    block_for: Option<Duration>,
+) -> Result<(), OutboxError> {
+    match tx.try_send(frame) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Closed(_)) => Err(OutboxError::Closed),
+        Err(TrySendError::Full(frame)) => match block_for {
+            None => Err(OutboxError::Full {
+                pending: pending(tx),
+            }),
+            Some(timeout) => match tx.send_timeout(frame, timeout).await {
+                Ok(()) => Ok(()),
+                Err(SendTimeoutError::Closed(_)) => Err(OutboxError::Closed),
+                Err(SendTimeoutError::Timeout(_)) => Err(OutboxError::Timeout {
+                    pending: pending(tx),
+                }),
+            },
+        },
+    }
+}
+
+/// Tracks whether the UI has been told the outbox is backing up
+#[derive(Debug, Default)]
+pub struct Backpressure {
+    engaged: AtomicBool,
+}
+
+impl Backpressure {
+    pub fn is_engaged(&self) -> bool {
+        self.engaged.load(Ordering::SeqCst)
+    }
+
+    /// Record the current depth; returns `Some(engaged)` when the state flips
+    pub fn update(&self, pending: usize) -> Option<bool> {
+        if pending >= HIGH_WATER_MARK {
+            (!self.engaged.swap(true, Ordering::SeqCst)).then_some(true)
+        } else if pending <= LOW_WATER_MARK {
+            self.engaged.swap(false, Ordering::SeqCst).then_some(false)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    #[tokio::test]
+    async fn test_enqueue_full_queue_fails_fast() {
+        // Receiver held but never polled: a paused WebSocket task
+        let (tx, _rx) = channel::<String>(3);
+        for i in 0..3 {
+            enqueue(&tx, format!("frame-{}", i), None).await.unwrap();
+        }
+
+        let err = enqueue(&tx, "overflow".to_string(), None)
+            .await
+            .unwrap_err();
+        assert_eq!(err, OutboxError::Full { pending: 3 });
+        assert_eq!(err.to_string(), "outbox full, 3 messages pending");
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_blocking_times_out_then_succeeds_after_drain() {
+        let (tx, mut rx) = channel::<String>(1);
+        enqueue(&tx, "first".to_string(), None).await.unwrap();
+
+        let err = enqueue(&tx, "second".to_string(), Some(Duration::from_millis(20)))
+            .await
+            .unwrap_err();
+        assert_eq!(err, OutboxError::Timeout { pending: 1 });
+
+        // Resume the task: once it drains, a blocking send goes through
+        assert_eq!(rx.recv().await.as_deref(), Some("first"));
+        enqueue(&tx, "second".to_string(), Some(Duration::from_millis(20)))
+            .await
+            .unwrap();
+        assert_eq!(pending(&tx), 1);
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_closed() {
+        let (tx, rx) = channel::<String>(1);
+        drop(rx);
+        assert_eq!(
+            enqueue(&tx, "frame".to_string(), None).await,
+            Err(OutboxError::Closed)
+        );
+    }
+
+    #[test]
+    fn test_backpressure_transitions() {
+        let pressure = Backpressure::default();
+        assert_eq!(pressure.update(HIGH_WATER_MARK - 1), None);
+        assert_eq!(pressure.update(HIGH_WATER_MARK), Some(true));
+        assert_eq!(pressure.update(OUTBOX_CAPACITY), None);
+        assert!(pressure.is_engaged());
+        assert_eq!(pressure.update(LOW_WATER_MARK + 1), None);
+        assert_eq!(pressure.update(LOW_WATER_MARK), Some(false));
+        assert_eq!(pressure.update(0), None);
+    }
+}
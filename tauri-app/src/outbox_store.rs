@@ -0,0 +1,315 @@
+// Persistent chat outbox
+// Every chat message is recorded before it is queued and only marked sent once
+// the gateway acks its request id, so a crash or restart never loses it.
+// Resends reuse the idempotency key so the gateway can de-duplicate, and only
+// go out on the connection the message was written for.
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Sent entries are kept this long for reference, then pruned
+const SENT_RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub connection_id: String,
+    pub request_id: String,
+    pub session_key: String,
+    pub message: String,
+    pub agent_id: Option<String>,
+    pub idempotency_key: String,
+    /// Ask the gateway for delivery and read receipts
+    pub deliver: bool,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+}
+
+impl OutboxEntry {
+    /// The `chat.send` frame for this entry
+    pub fn to_frame(&self) -> String {
+        let mut frame = serde_json::json!({
+            "type": "req",
+            "id": self.request_id,
+            "method": "chat.send",
+            "params": {
+                "sessionKey": self.session_key,
+                "message": self.message,
+                "deliver": self.deliver,
+                "idempotencyKey": self.idempotency_key
+            }
+        });
+        if let Some(ref agent_id) = self.agent_id {
+            frame["params"]["agentId"] = serde_json::json!(agent_id);
+        }
+        frame.to_string()
+    }
+}
+
+pub struct OutboxStore {
+    conn: Mutex<Connection>,
+}
+
+fn now_secs() -> i64 {
+    crate::clock::now_millis() / 1000
+}
+
+/// Add a column that older databases lack
+fn add_column(conn: &Connection, name: &str, definition: &str) -> Result<()> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('outbox') WHERE name = ?1",
+        params![name],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE outbox ADD COLUMN {} {}", name, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+impl OutboxStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create outbox directory: {:?}", parent))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open outbox database at {:?}", path))?;
+        Self::with_connection(conn)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS outbox (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                request_id TEXT NOT NULL,
+                session_key TEXT NOT NULL,
+                message TEXT NOT NULL,
+                agent_id TEXT,
+                idempotency_key TEXT NOT NULL UNIQUE,
+                created_at INTEGER NOT NULL,
+                sent_at INTEGER
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_outbox_request_id ON outbox(request_id);
+            CREATE INDEX IF NOT EXISTS idx_outbox_sent_at ON outbox(sent_at);
+            "#,
+        )?;
+        // Databases from before multiple connections only hold default-connection messages
+        add_column(&conn, "connection_id", "TEXT NOT NULL DEFAULT 'default'")?;
+        add_column(&conn, "deliver", "INTEGER NOT NULL DEFAULT 0")?;
+        conn.execute(
+            "DELETE FROM outbox WHERE sent_at IS NOT NULL AND sent_at < ?1",
+            params![now_secs() - SENT_RETENTION_SECS],
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Lock error: {}", e))
+    }
+
+    /// Record a message before it is handed to the WebSocket task; the stored
+    /// entry is returned with its id and creation time filled in
+    pub fn insert(&self, entry: OutboxEntry) -> Result<OutboxEntry> {
+        let conn = self.lock()?;
+        let created_at = now_secs();
+        conn.execute(
+            "INSERT INTO outbox (connection_id, request_id, session_key, message, agent_id, idempotency_key, deliver, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                entry.connection_id,
+                entry.request_id,
+                entry.session_key,
+                entry.message,
+                entry.agent_id,
+                entry.idempotency_key,
+                entry.deliver,
+                created_at
+            ],
+        )?;
+        Ok(OutboxEntry {
+            id: conn.last_insert_rowid(),
+            created_at,
+            ..entry
+        })
+    }
+
+    /// Unsent entries, oldest first; all connections' when `connection_id` is `None`
+    pub fn unsent(&self, connection_id: Option<&str>) -> Result<Vec<OutboxEntry>> {
+        let conn = self.lock()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, connection_id, request_id, session_key, message, agent_id, idempotency_key, deliver, created_at
+             FROM outbox WHERE sent_at IS NULL AND (?1 IS NULL OR connection_id = ?1) ORDER BY id ASC",
+        )?;
+        let entries = stmt
+            .query_map(params![connection_id], |row| {
+                Ok(OutboxEntry {
+                    id: row.get(0)?,
+                    connection_id: row.get(1)?,
+                    request_id: row.get(2)?,
+                    session_key: row.get(3)?,
+                    message: row.get(4)?,
+                    agent_id: row.get(5)?,
+                    idempotency_key: row.get(6)?,
+                    deliver: row.get(7)?,
+                    created_at: row.get(8)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(entries)
+    }
+
+    /// Point an entry at the request id used for its latest send attempt
+    pub fn set_request_id(&self, id: i64, request_id: &str) -> Result<()> {
+        self.lock()?.execute(
+            "UPDATE outbox SET request_id = ?1 WHERE id = ?2",
+            params![request_id, id],
+        )?;
+        Ok(())
+    }
+
+    /// Mark the entry for an acked request as sent; returns false if none matched
+    pub fn mark_sent(&self, request_id: &str) -> Result<bool> {
+        let updated = self.lock()?.execute(
+            "UPDATE outbox SET sent_at = ?1 WHERE request_id = ?2 AND sent_at IS NULL",
+            params![now_secs(), request_id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Drop an unsent entry; returns false if it doesn't exist or was already sent
+    pub fn discard(&self, id: i64) -> Result<bool> {
+        let conn = self.lock()?;
+        let exists: Option<i64> = conn
+            .query_row(
+                "SELECT id FROM outbox WHERE id = ?1 AND sent_at IS NULL",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if exists.is_none() {
+            return Ok(false);
+        }
+        conn.execute("DELETE FROM outbox WHERE id = ?1", params![id])?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draft(connection_id: &str, request_id: &str, message: &str, key: &str) -> OutboxEntry {
+        OutboxEntry {
+            id: 0,
+            connection_id: connection_id.to_string(),
+            request_id: request_id.to_string(),
+            session_key: "main".to_string(),
+            message: message.to_string(),
+            agent_id: None,
+            idempotency_key: key.to_string(),
+            deliver: false,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_unsent_until_acked() {
+        let store = OutboxStore::open_in_memory().unwrap();
+        store
+            .insert(draft("default", "msg-1", "first", "k1"))
+            .unwrap();
+        store
+            .insert(OutboxEntry {
+                agent_id: Some("agent-1".to_string()),
+                ..draft("default", "msg-2", "second", "k2")
+            })
+            .unwrap();
+
+        let unsent = store.unsent(None).unwrap();
+        assert_eq!(unsent.len(), 2);
+        assert_eq!(unsent[0].message, "first");
+        assert_eq!(unsent[1].agent_id.as_deref(), Some("agent-1"));
+
+        assert!(store.mark_sent("msg-1").unwrap());
+        assert!(!store.mark_sent("msg-1").unwrap());
+        assert_eq!(store.unsent(None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_resend_keeps_idempotency_key() {
+        let store = OutboxStore::open_in_memory().unwrap();
+        let entry = store
+            .insert(draft("default", "msg-1", "hello", "k1"))
+            .unwrap();
+        store.set_request_id(entry.id, "msg-99").unwrap();
+
+        let resent = &store.unsent(None).unwrap()[0];
+        let frame: serde_json::Value = serde_json::from_str(&resent.to_frame()).unwrap();
+        assert_eq!(frame["id"], "msg-99");
+        assert_eq!(frame["params"]["idempotencyKey"], "k1");
+        assert!(store.mark_sent("msg-99").unwrap());
+    }
+
+    #[test]
+    fn test_deliver_flag_round_trips() {
+        let store = OutboxStore::open_in_memory().unwrap();
+        store
+            .insert(OutboxEntry {
+                deliver: true,
+                ..draft("default", "msg-1", "tracked", "k1")
+            })
+            .unwrap();
+
+        let stored = &store.unsent(None).unwrap()[0];
+        assert!(stored.deliver);
+        let frame: serde_json::Value = serde_json::from_str(&stored.to_frame()).unwrap();
+        assert_eq!(frame["params"]["deliver"], true);
+    }
+
+    #[test]
+    fn test_discard() {
+        let store = OutboxStore::open_in_memory().unwrap();
+        let entry = store
+            .insert(draft("default", "msg-1", "stale", "k1"))
+            .unwrap();
+        assert!(store.discard(entry.id).unwrap());
+        assert!(!store.discard(entry.id).unwrap());
+        assert!(store.unsent(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unsent_per_connection() {
+        let store = OutboxStore::open_in_memory().unwrap();
+        store
+            .insert(draft("staging", "msg-1", "to staging", "k1"))
+            .unwrap();
+        store
+            .insert(draft("prod", "msg-2", "to prod", "k2"))
+            .unwrap();
+
+        let staging = store.unsent(Some("staging")).unwrap();
+        assert_eq!(staging.len(), 1);
+        assert_eq!(staging[0].message, "to staging");
+        assert_eq!(store.unsent(Some("other")).unwrap().len(), 0);
+        assert_eq!(store.unsent(None).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,501 @@
+// Suspend/resume detection
+// Native power notifications are the primary source: logind's
+// `PrepareForSleep` on Linux, `NSWorkspace` sleep/wake notifications on macOS
+// and `WM_POWERBROADCAST` on Windows. Where none is available, a ticker that
+// notices when far more time passed between two ticks than it slept for stands
+// in: on Linux and macOS the monotonic clock stops during suspend while the
+// wall clock keeps going, and on Windows both jump forward.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::{mpsc, watch, Notify};
+
+/// How often the detector wakes up
+pub const TICK: Duration = Duration::from_secs(2);
+
+/// Extra time beyond `TICK` that counts as having been suspended
+pub const JUMP_THRESHOLD: Duration = Duration::from_secs(5);
+
+pub struct JumpDetector {
+    last_mono: Instant,
+    last_wall: SystemTime,
+}
+
+impl JumpDetector {
+    pub fn new(mono: Instant, wall: SystemTime) -> Self {
+        Self {
+            last_mono: mono,
+            last_wall: wall,
+        }
+    }
+
+    /// Record a tick; returns roughly how long we were suspended if a jump was seen
+    pub fn observe(&mut self, mono: Instant, wall: SystemTime) -> Option<Duration> {
+        let mono_elapsed = mono.saturating_duration_since(self.last_mono);
+        // A wall clock stepped backwards is not a resume
+        let wall_elapsed = wall.duration_since(self.last_wall).unwrap_or_default();
+        self.last_mono = mono;
+        self.last_wall = wall;
+
+        let elapsed = mono_elapsed.max(wall_elapsed);
+        (elapsed > TICK + JUMP_THRESHOLD).then(|| elapsed - TICK)
+    }
+}
+
+/// What a native power source reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NativeEvent {
+    /// The source is listening; clock jumps are no longer needed
+    Available,
+    /// The source stopped or never started; fall back to clock jumps
+    Unavailable,
+    Suspending,
+    Resumed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Suspending,
+    /// Roughly how long we were suspended
+    Resumed(Duration),
+}
+
+/// Combines native notifications with the `JumpDetector` fallback
+pub struct SuspendTracker {
+    detector: JumpDetector,
+    native: bool,
+    suspended_at: Option<SystemTime>,
+}
+
+impl SuspendTracker {
+    pub fn new(mono: Instant, wall: SystemTime) -> Self {
+        Self {
+            detector: JumpDetector::new(mono, wall),
+            native: false,
+            suspended_at: None,
+        }
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended_at.is_some()
+    }
+
+    /// Feed a native notification
+    pub fn native(
+        &mut self,
+        event: NativeEvent,
+        mono: Instant,
+        wall: SystemTime,
+    ) -> Option<Transition> {
+        match event {
+            NativeEvent::Available => {
+                self.native = true;
+                None
+            }
+            NativeEvent::Unavailable => {
+                self.native = false;
+                // Nothing will report the resume now
+                self.suspended_at
+                    .take()
+                    .map(|at| Transition::Resumed(wall.duration_since(at).unwrap_or_default()))
+            }
+            NativeEvent::Suspending => {
+                if self.suspended_at.is_some() {
+                    return None;
+                }
+                self.suspended_at = Some(wall);
+                Some(Transition::Suspending)
+            }
+            NativeEvent::Resumed => {
+                // The clocks jumped too; don't report this resume twice
+                self.detector = JumpDetector::new(mono, wall);
+                let at = self.suspended_at.take()?;
+                Some(Transition::Resumed(
+                    wall.duration_since(at).unwrap_or_default(),
+                ))
+            }
+        }
+    }
+
+    /// Record a tick of the fallback ticker
+    pub fn tick(&mut self, mono: Instant, wall: SystemTime) -> Option<Transition> {
+        let jump = self.detector.observe(mono, wall);
+        if self.native {
+            return None;
+        }
+        jump.map(Transition::Resumed)
+    }
+}
+
+/// Watch for suspends and resumes
+///
+/// `suspended` is true from a native suspend notice until the resume; on
+/// resume anyone waiting on `resumed` is woken and `system-resumed` emitted.
+pub fn spawn_monitor(app: AppHandle, resumed: Arc<Notify>, suspended: Arc<watch::Sender<bool>>) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    native::start(tx);
+
+    tauri::async_runtime::spawn(async move {
+        let mut tracker = SuspendTracker::new(Instant::now(), SystemTime::now());
+        loop {
+            let transition = tokio::select! {
+                Some(event) = rx.recv() => {
+                    tracker.native(event, Instant::now(), SystemTime::now())
+                }
+                _ = tokio::time::sleep(TICK) => {
+                    tracker.tick(Instant::now(), SystemTime::now())
+                }
+            };
+            match transition {
+                Some(Transition::Suspending) => {
+                    eprintln!("[Power] Suspending");
+                    suspended.send_replace(true);
+                    let _ = app.emit("system-suspending", ());
+                }
+                Some(Transition::Resumed(gap)) => {
+                    eprintln!("[Power] Resumed after about {}s suspended", gap.as_secs());
+                    suspended.send_replace(tracker.is_suspended());
+                    resumed.notify_waiters();
+                    let _ = app.emit(
+                        "system-resumed",
+                        serde_json::json!({ "suspendedSecs": gap.as_secs() }),
+                    );
+                }
+                None => {}
+            }
+        }
+    });
+}
+
+#[cfg(target_os = "linux")]
+mod native {
+    use super::NativeEvent;
+    use futures_util::StreamExt;
+    use tokio::sync::mpsc::UnboundedSender;
+
+    #[zbus::proxy(
+        interface = "org.freedesktop.login1.Manager",
+        default_service = "org.freedesktop.login1",
+        default_path = "/org/freedesktop/login1"
+    )]
+    trait Login1Manager {
+        #[zbus(signal)]
+        fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
+    }
+
+    pub fn start(tx: UnboundedSender<NativeEvent>) {
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = listen(&tx).await {
+                eprintln!(
+                    "[Power] logind unavailable, watching the clock instead: {}",
+                    e
+                );
+            }
+            let _ = tx.send(NativeEvent::Unavailable);
+        });
+    }
+
+    async fn listen(tx: &UnboundedSender<NativeEvent>) -> zbus::Result<()> {
+        let connection = zbus::Connection::system().await?;
+        let manager = Login1ManagerProxy::new(&connection).await?;
+        let mut signals = manager.receive_prepare_for_sleep().await?;
+        let _ = tx.send(NativeEvent::Available);
+
+        // Sent with `true` before sleeping and `false` after waking (or if
+        // the sleep was aborted)
+        while let Some(signal) = signals.next().await {
+            let event = if signal.args()?.start {
+                NativeEvent::Suspending
+            } else {
+                NativeEvent::Resumed
+            };
+            let _ = tx.send(event);
+        }
+        Err(zbus::Error::Failure("signal stream ended".to_string()))
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod native {
+    use super::NativeEvent;
+    use block2::RcBlock;
+    use objc2_app_kit::{
+        NSWorkspace, NSWorkspaceDidWakeNotification, NSWorkspaceWillSleepNotification,
+    };
+    use objc2_foundation::NSNotification;
+    use std::ptr::NonNull;
+    use tokio::sync::mpsc::UnboundedSender;
+
+    pub fn start(tx: UnboundedSender<NativeEvent>) {
+        let center = NSWorkspace::sharedWorkspace().notificationCenter();
+        // SAFETY: the names are AppKit constants, and the blocks only touch a
+        // `Send` channel sender
+        let names = unsafe {
+            [
+                (NSWorkspaceWillSleepNotification, NativeEvent::Suspending),
+                (NSWorkspaceDidWakeNotification, NativeEvent::Resumed),
+            ]
+        };
+        for (name, event) in names {
+            let tx = tx.clone();
+            let block = RcBlock::new(move |_: NonNull<NSNotification>| {
+                let _ = tx.send(event);
+            });
+            let observer = unsafe {
+                center.addObserverForName_object_queue_usingBlock(Some(name), None, None, &block)
+            };
+            // Observing lasts as long as the app does
+            std::mem::forget(observer);
+        }
+        let _ = tx.send(NativeEvent::Available);
+    }
+}
+
+#[cfg(windows)]
+mod native {
+    use super::NativeEvent;
+    use std::sync::OnceLock;
+    use tokio::sync::mpsc::UnboundedSender;
+    use windows_sys::Win32::Foundation::{HWND, LPARAM, LRESULT, TRUE, WPARAM};
+    use windows_sys::Win32::System::LibraryLoader::GetModuleHandleW;
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        CreateWindowExW, DefWindowProcW, DispatchMessageW, GetMessageW, RegisterClassW,
+        TranslateMessage, MSG, PBT_APMRESUMEAUTOMATIC, PBT_APMSUSPEND, WM_POWERBROADCAST,
+        WNDCLASSW,
+    };
+
+    static EVENTS: OnceLock<UnboundedSender<NativeEvent>> = OnceLock::new();
+
+    pub fn start(tx: UnboundedSender<NativeEvent>) {
+        let spawned = std::thread::Builder::new()
+            .name("power-events".to_string())
+            .spawn(move || {
+                // SAFETY: plain Win32 window setup and message loop on this thread
+                if let Err(e) = unsafe { run(&tx) } {
+                    eprintln!(
+                        "[Power] No power broadcasts, watching the clock instead: {}",
+                        e
+                    );
+                }
+                let _ = tx.send(NativeEvent::Unavailable);
+            });
+        if let Err(e) = spawned {
+            eprintln!("[Power] Failed to start power event thread: {}", e);
+        }
+    }
+
+    unsafe fn run(tx: &UnboundedSender<NativeEvent>) -> Result<(), &'static str> {
+        if EVENTS.set(tx.clone()).is_err() {
+            return Err("already started");
+        }
+        let class_name: Vec<u16> = "ClawPenPowerEvents\0".encode_utf16().collect();
+        let instance = GetModuleHandleW(std::ptr::null());
+        let class = WNDCLASSW {
+            lpfnWndProc: Some(window_proc),
+            hInstance: instance,
+            lpszClassName: class_name.as_ptr(),
+            ..std::mem::zeroed()
+        };
+        if RegisterClassW(&class) == 0 {
+            return Err("RegisterClassW failed");
+        }
+        // A hidden top-level window; message-only windows get no broadcasts
+        let hwnd = CreateWindowExW(
+            0,
+            class_name.as_ptr(),
+            class_name.as_ptr(),
+            0,
+            0,
+            0,
+            0,
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            instance,
+            std::ptr::null(),
+        );
+        if hwnd.is_null() {
+            return Err("CreateWindowExW failed");
+        }
+        let _ = tx.send(NativeEvent::Available);
+
+        let mut msg: MSG = std::mem::zeroed();
+        while GetMessageW(&mut msg, std::ptr::null_mut(), 0, 0) > 0 {
+            TranslateMessage(&msg);
+            DispatchMessageW(&msg);
+        }
+        Err("message loop ended")
+    }
+
+    unsafe extern "system" fn window_proc(
+        hwnd: HWND,
+        msg: u32,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        if msg != WM_POWERBROADCAST {
+            return DefWindowProcW(hwnd, msg, wparam, lparam);
+        }
+        let event = match wparam as u32 {
+            PBT_APMSUSPEND => Some(NativeEvent::Suspending),
+            PBT_APMRESUMEAUTOMATIC => Some(NativeEvent::Resumed),
+            _ => None,
+        };
+        if let (Some(event), Some(tx)) = (event, EVENTS.get()) {
+            let _ = tx.send(event);
+        }
+        TRUE as LRESULT
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod native {
+    use super::NativeEvent;
+    use tokio::sync::mpsc::UnboundedSender;
+
+    pub fn start(tx: UnboundedSender<NativeEvent>) {
+        let _ = tx.send(NativeEvent::Unavailable);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regular_ticks_are_not_resumes() {
+        let mono = Instant::now();
+        let wall = SystemTime::now();
+        let mut detector = JumpDetector::new(mono, wall);
+
+        for i in 1..10 {
+            // A little scheduling jitter is fine
+            let jitter = Duration::from_millis(300);
+            assert_eq!(
+                detector.observe(mono + TICK * i + jitter, wall + TICK * i + jitter),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_wall_clock_jump_detected() {
+        // Linux/macOS: monotonic clock paused, wall clock kept going
+        let mono = Instant::now();
+        let wall = SystemTime::now();
+        let mut detector = JumpDetector::new(mono, wall);
+
+        let suspended = Duration::from_secs(3600);
+        let gap = detector.observe(mono + TICK, wall + TICK + suspended);
+        assert_eq!(gap, Some(suspended));
+
+        // Back to normal afterwards
+        assert_eq!(
+            detector.observe(mono + TICK * 2, wall + TICK * 2 + suspended),
+            None
+        );
+    }
+
+    #[test]
+    fn test_monotonic_jump_detected() {
+        // Windows: both clocks advanced across the suspend
+        let mono = Instant::now();
+        let wall = SystemTime::now();
+        let mut detector = JumpDetector::new(mono, wall);
+
+        let suspended = Duration::from_secs(600);
+        assert_eq!(
+            detector.observe(mono + TICK + suspended, wall + TICK + suspended),
+            Some(suspended)
+        );
+    }
+
+    #[test]
+    fn test_wall_clock_set_backwards_ignored() {
+        let mono = Instant::now();
+        let wall = SystemTime::now();
+        let mut detector = JumpDetector::new(mono, wall);
+
+        assert_eq!(
+            detector.observe(mono + TICK, wall - Duration::from_secs(3600)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_native_events_drive_suspend_and_resume() {
+        let mono = Instant::now();
+        let wall = SystemTime::now();
+        let mut tracker = SuspendTracker::new(mono, wall);
+
+        assert_eq!(tracker.native(NativeEvent::Available, mono, wall), None);
+        assert_eq!(
+            tracker.native(NativeEvent::Suspending, mono, wall),
+            Some(Transition::Suspending)
+        );
+        assert!(tracker.is_suspended());
+        // A repeated notice changes nothing
+        assert_eq!(tracker.native(NativeEvent::Suspending, mono, wall), None);
+
+        let suspended = Duration::from_secs(900);
+        assert_eq!(
+            tracker.native(NativeEvent::Resumed, mono + TICK, wall + suspended),
+            Some(Transition::Resumed(suspended))
+        );
+        assert!(!tracker.is_suspended());
+        // The clock jump the ticker sees afterwards is the same resume
+        assert_eq!(tracker.tick(mono + TICK * 2, wall + suspended + TICK), None);
+    }
+
+    #[test]
+    fn test_clock_jumps_ignored_with_native_source() {
+        let mono = Instant::now();
+        let wall = SystemTime::now();
+        let mut tracker = SuspendTracker::new(mono, wall);
+        tracker.native(NativeEvent::Available, mono, wall);
+
+        let jump = Duration::from_secs(600);
+        assert_eq!(tracker.tick(mono + TICK + jump, wall + TICK + jump), None);
+    }
+
+    #[test]
+    fn test_clock_jumps_used_without_native_source() {
+        let mono = Instant::now();
+        let wall = SystemTime::now();
+        let mut tracker = SuspendTracker::new(mono, wall);
+        tracker.native(NativeEvent::Unavailable, mono, wall);
+
+        let suspended = Duration::from_secs(600);
+        assert_eq!(
+            tracker.tick(mono + TICK, wall + TICK + suspended),
+            Some(Transition::Resumed(suspended))
+        );
+    }
+
+    #[test]
+    fn test_losing_native_source_while_suspended_resumes() {
+        let mono = Instant::now();
+        let wall = SystemTime::now();
+        let mut tracker = SuspendTracker::new(mono, wall);
+        tracker.native(NativeEvent::Available, mono, wall);
+        tracker.native(NativeEvent::Suspending, mono, wall);
+
+        let later = wall + Duration::from_secs(30);
+        assert_eq!(
+            tracker.native(NativeEvent::Unavailable, mono, later),
+            Some(Transition::Resumed(Duration::from_secs(30)))
+        );
+        assert!(!tracker.is_suspended());
+    }
+
+    #[test]
+    fn test_resume_without_suspend_ignored() {
+        let mono = Instant::now();
+        let wall = SystemTime::now();
+        let mut tracker = SuspendTracker::new(mono, wall);
+        tracker.native(NativeEvent::Available, mono, wall);
+
+        assert_eq!(tracker.native(NativeEvent::Resumed, mono, wall), None);
+    }
+}
@@ -0,0 +1,127 @@
+// Named device profiles
+// Each profile is its own Ed25519 identity under ~/.openclaw/devices/<profile>.json,
+// so gateways that must not share a device (prod vs dev) each get their own.
+
+use std::fs;
+use std::path::PathBuf;
+
+/// Profile used when the config doesn't name one, and the target of the legacy migration
+pub const DEFAULT_PROFILE: &str = "default";
+
+pub const MAX_PROFILE_NAME_LENGTH: usize = 64;
+
+fn openclaw_dir() -> PathBuf {
+    let home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
+    home.join(".openclaw")
+}
+
+pub fn devices_dir() -> PathBuf {
+    openclaw_dir().join("devices")
+}
+
+/// The single device file used before profiles existed
+fn legacy_device_path() -> PathBuf {
+    openclaw_dir().join("claw-pen-device.json")
+}
+
+/// Path of a profile's key file; `name` must already be validated
+pub fn profile_path(name: &str) -> PathBuf {
+    devices_dir().join(format!("{}.json", name))
+}
+
+/// Validate a profile name
+/// Same rules as container names on the orchestrator, which also keeps the
+/// name safe to use as a file name
+pub fn validate_profile_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("Profile name cannot be empty".to_string());
+    }
+
+    if name.len() > MAX_PROFILE_NAME_LENGTH {
+        return Err(format!(
+            "Profile name too long (max {} characters)",
+            MAX_PROFILE_NAME_LENGTH
+        ));
+    }
+
+    if name.starts_with('-') {
+        return Err("Profile name cannot start with a hyphen".to_string());
+    }
+
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    if !valid {
+        return Err(
+            "Profile name contains invalid characters. Only alphanumeric, underscore (_), and hyphen (-) are allowed"
+                .to_string(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Move the legacy `claw-pen-device.json` into the `default` profile
+///
+/// Does nothing if there is no legacy file or a default profile already exists,
+/// so it is safe to call on every start.
+pub fn migrate_legacy_device() -> std::io::Result<bool> {
+    let legacy = legacy_device_path();
+    let target = profile_path(DEFAULT_PROFILE);
+    if !legacy.exists() || target.exists() {
+        return Ok(false);
+    }
+
+    fs::create_dir_all(devices_dir())?;
+    if fs::rename(&legacy, &target).is_err() {
+        // rename can fail across filesystems; copy then remove instead
+        fs::copy(&legacy, &target)?;
+        fs::remove_file(&legacy)?;
+    }
+    eprintln!(
+        "[Device] Migrated {} to profile '{}'",
+        legacy.display(),
+        DEFAULT_PROFILE
+    );
+    Ok(true)
+}
+
+/// Names of all profiles on disk, sorted
+pub fn list_profiles() -> std::io::Result<Vec<String>> {
+    let entries = match fs::read_dir(devices_dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                return None;
+            }
+            let name = path.file_stem()?.to_str()?.to_string();
+            validate_profile_name(&name).ok().map(|_| name)
+        })
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_profile_name() {
+        assert!(validate_profile_name("default").is_ok());
+        assert!(validate_profile_name("prod-gateway_2").is_ok());
+        assert!(validate_profile_name("").is_err());
+        assert!(validate_profile_name("-dev").is_err());
+        assert!(validate_profile_name("../escape").is_err());
+        assert!(validate_profile_name("dev.json").is_err());
+        assert!(validate_profile_name(&"a".repeat(MAX_PROFILE_NAME_LENGTH + 1)).is_err());
+    }
+}
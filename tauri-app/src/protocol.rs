@@ -0,0 +1,369 @@
+// Gateway protocol: versions and the connect request
+// The connect request offers a range of versions and the gateway names the one
+// it picked in its response. v4 renames `sessionKey` to `session` and expects a
+// `traceId` on every request, so frames are built in v3 shape and adapted to
+// the negotiated version just before they are sent.
+//
+// Device auth signs a `v2|...` string over the connect parameters. Both that
+// string and the JSON come from one `ConnectParams`, so what is signed can't
+// drift from what is sent.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ed25519_dalek::{Signer, SigningKey};
+use rand::Rng;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Oldest protocol version we speak
+pub const MIN_PROTOCOL: u32 = 3;
+/// Newest protocol version we speak
+pub const MAX_PROTOCOL: u32 = 4;
+
+/// Version of the signed connect string
+const SIGNATURE_VERSION: &str = "v2";
+
+pub const CLIENT_ID: &str = "openclaw-control-ui";
+pub const CLIENT_VERSION: &str = "1.0.0";
+pub const CLIENT_PLATFORM: &str = "desktop";
+pub const CLIENT_MODE: &str = "webchat";
+pub const ROLE: &str = "operator";
+pub const DEFAULT_SCOPES: &[&str] = &["operator.admin", "operator.approvals", "operator.pairing"];
+
+const UNSUPPORTED_CODES: &[&str] = &[
+    "PROTOCOL_UNSUPPORTED",
+    "UNSUPPORTED_PROTOCOL",
+    "PROTOCOL_MISMATCH",
+];
+
+/// A span of protocol versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ProtocolRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl ProtocolRange {
+    pub const SUPPORTED: ProtocolRange = ProtocolRange {
+        min: MIN_PROTOCOL,
+        max: MAX_PROTOCOL,
+    };
+}
+
+impl std::fmt::Display for ProtocolRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.min == self.max {
+            write!(f, "v{}", self.min)
+        } else {
+            write!(f, "v{}-v{}", self.min, self.max)
+        }
+    }
+}
+
+pub fn is_supported(version: u32) -> bool {
+    (MIN_PROTOCOL..=MAX_PROTOCOL).contains(&version)
+}
+
+fn as_version(value: &Value) -> Option<u32> {
+    value.as_u64().map(|v| u32::try_from(v).unwrap_or(u32::MAX))
+}
+
+/// Version picked in a successful connect response; gateways from before
+/// negotiation don't say and speak v3
+pub fn negotiated(frame: &Value) -> u32 {
+    let payload = &frame["payload"];
+    as_version(&payload["protocol"])
+        .or_else(|| as_version(&payload["protocolVersion"]))
+        .unwrap_or(MIN_PROTOCOL)
+}
+
+/// Whether a failed connect response says there is no common version
+pub fn is_unsupported_error(frame: &Value) -> bool {
+    let error = &frame["error"];
+    if let Some(code) = error["code"].as_str() {
+        if UNSUPPORTED_CODES.contains(&code.to_uppercase().as_str()) {
+            return true;
+        }
+    }
+    error["message"].as_str().is_some_and(|m| {
+        let m = m.to_lowercase();
+        m.contains("protocol") && (m.contains("unsupported") || m.contains("mismatch"))
+    })
+}
+
+/// The versions a gateway says it speaks, if a connect error tells us
+pub fn gateway_range(frame: &Value) -> Option<ProtocolRange> {
+    let details = &frame["error"]["details"];
+    let min = as_version(&details["minProtocol"])?;
+    let max = as_version(&details["maxProtocol"]).unwrap_or(min);
+    Some(ProtocolRange { min, max })
+}
+
+/// What to tell the user when no version is common to both sides
+pub fn unsupported_message(gateway: Option<ProtocolRange>) -> String {
+    match gateway {
+        Some(range) if range.min > MAX_PROTOCOL => format!(
+            "Gateway speaks protocol {} but this app supports {}. Update Claw Pen to connect.",
+            range,
+            ProtocolRange::SUPPORTED
+        ),
+        Some(range) => format!(
+            "Gateway speaks protocol {} but this app supports {}. The gateway needs updating.",
+            range,
+            ProtocolRange::SUPPORTED
+        ),
+        None => format!(
+            "Gateway supports none of protocol {} that this app speaks.",
+            ProtocolRange::SUPPORTED
+        ),
+    }
+}
+
+fn trace_id() -> String {
+    format!("{:032x}", rand::thread_rng().gen::<u128>())
+}
+
+/// Rewrite an outgoing v3-shaped frame for the negotiated version
+pub fn adapt(text: String, version: u32) -> String {
+    if version < 4 {
+        return text;
+    }
+    let Ok(mut frame) = serde_json::from_str::<Value>(&text) else {
+        return text;
+    };
+    if frame["type"].as_str() != Some("req") {
+        return text;
+    }
+    if let Some(params) = frame["params"].as_object_mut() {
+        if let Some(session) = params.remove("sessionKey") {
+            params.insert("session".to_string(), session);
+        }
+    }
+    frame["traceId"] = Value::String(trace_id());
+    frame.to_string()
+}
+
+/// Everything a connect request says about us
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectParams {
+    pub client_id: String,
+    pub client_version: String,
+    pub platform: String,
+    pub mode: String,
+    pub role: String,
+    pub scopes: Vec<String>,
+    pub caps: Vec<String>,
+    pub min_protocol: u32,
+    pub max_protocol: u32,
+    /// Nonce from the gateway's challenge
+    pub nonce: String,
+    /// Unix millis, corrected for gateway clock skew
+    pub signed_at: u64,
+    /// Shared token, sent instead of a device signature
+    pub token: Option<String>,
+}
+
+impl ConnectParams {
+    /// Our usual client description, answering `nonce`
+    pub fn new(nonce: &str, signed_at: u64) -> Self {
+        Self {
+            client_id: CLIENT_ID.to_string(),
+            client_version: CLIENT_VERSION.to_string(),
+            platform: CLIENT_PLATFORM.to_string(),
+            mode: CLIENT_MODE.to_string(),
+            role: ROLE.to_string(),
+            scopes: DEFAULT_SCOPES.iter().map(|s| s.to_string()).collect(),
+            caps: Vec::new(),
+            min_protocol: MIN_PROTOCOL,
+            max_protocol: MAX_PROTOCOL,
+            nonce: nonce.to_string(),
+            signed_at,
+            token: None,
+        }
+    }
+
+    /// The exact string a device signs:
+    /// `v2|deviceId|clientId|mode|role|scopes|signedAt|token|nonce`
+    pub fn signable_string(&self, device_id: &str) -> String {
+        [
+            SIGNATURE_VERSION,
+            device_id,
+            &self.client_id,
+            &self.mode,
+            &self.role,
+            &self.scopes.join(","),
+            &self.signed_at.to_string(),
+            self.token.as_deref().unwrap_or(""),
+            &self.nonce,
+        ]
+        .join("|")
+    }
+
+    /// `params` of the connect request, with the device signature if signed
+    pub fn to_json(&self, signed: Option<&SignedConnect>) -> Value {
+        let mut params = serde_json::json!({
+            "minProtocol": self.min_protocol,
+            "maxProtocol": self.max_protocol,
+            "client": {
+                "id": self.client_id,
+                "version": self.client_version,
+                "platform": self.platform,
+                "mode": self.mode,
+            },
+            "role": self.role,
+            "scopes": self.scopes,
+            "caps": self.caps,
+            "commands": [],
+        });
+        if let Some(signed) = signed {
+            params["device"] = serde_json::to_value(signed).unwrap_or_default();
+        }
+        if let Some(ref token) = self.token {
+            params["auth"] = serde_json::json!({ "token": token });
+        }
+        params
+    }
+}
+
+/// A device's signature over a `ConnectParams`; serialized as the request's
+/// `device` object
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedConnect {
+    pub id: String,
+    /// Base64 Ed25519 public key
+    pub public_key: String,
+    /// Base64 signature of `ConnectParams::signable_string`
+    pub signature: String,
+    pub signed_at: u64,
+    pub nonce: String,
+}
+
+impl SignedConnect {
+    pub fn sign(key: &SigningKey, device_id: &str, params: &ConnectParams) -> Self {
+        let message = params.signable_string(device_id);
+        Self {
+            id: device_id.to_string(),
+            public_key: BASE64.encode(key.verifying_key().to_bytes()),
+            signature: BASE64.encode(key.sign(message.as_bytes()).to_bytes()),
+            signed_at: params.signed_at,
+            nonce: params.nonce.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiated_version() {
+        let v4 = serde_json::json!({"ok": true, "payload": {"protocol": 4}});
+        assert_eq!(negotiated(&v4), 4);
+        let legacy = serde_json::json!({"ok": true, "payload": {}});
+        assert_eq!(negotiated(&legacy), 3);
+        assert!(is_supported(negotiated(&v4)));
+        assert!(!is_supported(5));
+    }
+
+    #[test]
+    fn test_unsupported_error_names_both_ranges() {
+        let frame = serde_json::json!({
+            "ok": false,
+            "error": {
+                "code": "PROTOCOL_UNSUPPORTED",
+                "details": {"minProtocol": 5, "maxProtocol": 6}
+            }
+        });
+        assert!(is_unsupported_error(&frame));
+        let range = gateway_range(&frame);
+        assert_eq!(range, Some(ProtocolRange { min: 5, max: 6 }));
+
+        let message = unsupported_message(range);
+        assert!(message.contains("v5-v6"), "{}", message);
+        assert!(message.contains("v3-v4"), "{}", message);
+        assert!(message.contains("Update Claw Pen"));
+
+        let auth = serde_json::json!({"ok": false, "error": {"code": "AUTH_FAILED"}});
+        assert!(!is_unsupported_error(&auth));
+    }
+
+    fn golden_params() -> ConnectParams {
+        ConnectParams::new("n0nce", 1_700_000_000_000)
+    }
+
+    #[test]
+    fn test_signable_string_golden_v2() {
+        assert_eq!(
+            golden_params().signable_string("dev-1"),
+            "v2|dev-1|openclaw-control-ui|webchat|operator|operator.admin,operator.approvals,operator.pairing|1700000000000||n0nce"
+        );
+
+        let mut custom = golden_params();
+        custom.scopes = vec!["operator.read".to_string()];
+        custom.token = Some("tok".to_string());
+        assert_eq!(
+            custom.signable_string("dev-1"),
+            "v2|dev-1|openclaw-control-ui|webchat|operator|operator.read|1700000000000|tok|n0nce"
+        );
+    }
+
+    #[test]
+    fn test_to_json_matches_signed_fields() {
+        let mut params = golden_params();
+        params.scopes = vec!["operator.read".to_string(), "operator.write".to_string()];
+        let json = params.to_json(None);
+        assert_eq!(
+            json["scopes"],
+            serde_json::json!(["operator.read", "operator.write"])
+        );
+        assert_eq!(json["client"]["id"], "openclaw-control-ui");
+        assert_eq!(json["minProtocol"], MIN_PROTOCOL);
+        assert!(json.get("device").is_none());
+        assert!(json.get("auth").is_none());
+        assert!(params
+            .signable_string("dev-1")
+            .contains("|operator.read,operator.write|"));
+
+        params.token = Some("tok".to_string());
+        assert_eq!(params.to_json(None)["auth"]["token"], "tok");
+    }
+
+    #[test]
+    fn test_signed_connect_verifies() {
+        use ed25519_dalek::{Signature, Verifier};
+
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let params = golden_params();
+        let signed = SignedConnect::sign(&key, "dev-1", &params);
+        assert_eq!(signed.signed_at, params.signed_at);
+
+        let bytes: [u8; 64] = BASE64
+            .decode(&signed.signature)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let signature = Signature::from_bytes(&bytes);
+        assert!(key
+            .verifying_key()
+            .verify(params.signable_string("dev-1").as_bytes(), &signature)
+            .is_ok());
+
+        let device = &params.to_json(Some(&signed))["device"];
+        assert_eq!(device["id"], "dev-1");
+        assert_eq!(device["signedAt"], 1_700_000_000_000u64);
+        assert_eq!(device["nonce"], "n0nce");
+        assert_eq!(device["publicKey"], signed.public_key);
+    }
+
+    #[test]
+    fn test_adapt_for_v4() {
+        let frame = r#"{"type":"req","id":"msg-1","method":"chat.send","params":{"sessionKey":"main","message":"hi"}}"#;
+        assert_eq!(adapt(frame.to_string(), 3), frame);
+
+        let adapted: Value = serde_json::from_str(&adapt(frame.to_string(), 4)).unwrap();
+        assert_eq!(adapted["params"]["session"], "main");
+        assert!(adapted["params"].get("sessionKey").is_none());
+        assert_eq!(adapted["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(adapted["params"]["message"], "hi");
+    }
+}
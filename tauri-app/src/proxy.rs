@@ -0,0 +1,359 @@
+// Outbound proxy support for the gateway connection
+// HTTP CONNECT and SOCKS5 tunnels, both yielding a plain TcpStream
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use http::Uri;
+use std::fmt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Upper bound on the proxy's CONNECT response headers
+const MAX_CONNECT_RESPONSE: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    Http,
+    Socks5,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The proxy URL in the config could not be understood
+    InvalidConfig(String),
+    /// Could not reach the proxy itself
+    Unreachable(std::io::Error),
+    /// The proxy refused our credentials
+    AuthFailed(String),
+    /// The proxy accepted us but could not reach the gateway
+    ConnectFailed(String),
+    /// The proxy spoke something we couldn't parse
+    Protocol(String),
+}
+
+impl ProxyError {
+    /// Stable code so the UI can tell proxy failures apart from gateway failures
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProxyError::InvalidConfig(_) => "PROXY_INVALID_CONFIG",
+            ProxyError::Unreachable(_) => "PROXY_UNREACHABLE",
+            ProxyError::AuthFailed(_) => "PROXY_AUTH_FAILED",
+            ProxyError::ConnectFailed(_) => "PROXY_CONNECT_FAILED",
+            ProxyError::Protocol(_) => "PROXY_PROTOCOL_ERROR",
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::InvalidConfig(m) => write!(f, "Invalid proxy config: {}", m),
+            ProxyError::Unreachable(e) => write!(f, "Proxy unreachable: {}", e),
+            ProxyError::AuthFailed(m) => write!(f, "Proxy authentication failed: {}", m),
+            ProxyError::ConnectFailed(m) => write!(f, "Proxy could not reach gateway: {}", m),
+            ProxyError::Protocol(m) => write!(f, "Proxy protocol error: {}", m),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl From<std::io::Error> for ProxyError {
+    fn from(err: std::io::Error) -> Self {
+        ProxyError::Protocol(err.to_string())
+    }
+}
+
+impl ProxyConfig {
+    /// Parse `http://host:port`, `socks5://host:port` or `socks5h://host:port`
+    pub fn parse(
+        url: &str,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> Result<Self, ProxyError> {
+        let uri: Uri = url
+            .parse()
+            .map_err(|e| ProxyError::InvalidConfig(format!("{}: {}", url, e)))?;
+
+        let kind = match uri.scheme_str().map(|s| s.to_lowercase()).as_deref() {
+            Some("http") => ProxyKind::Http,
+            Some("socks5") | Some("socks5h") => ProxyKind::Socks5,
+            Some(other) => {
+                return Err(ProxyError::InvalidConfig(format!(
+                    "unsupported proxy scheme '{}'",
+                    other
+                )))
+            }
+            None => {
+                return Err(ProxyError::InvalidConfig(
+                    "missing proxy scheme".to_string(),
+                ))
+            }
+        };
+
+        let host = uri
+            .host()
+            .ok_or_else(|| ProxyError::InvalidConfig("missing proxy host".to_string()))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+
+        let port = uri.port_u16().unwrap_or(match kind {
+            ProxyKind::Http => 8080,
+            ProxyKind::Socks5 => 1080,
+        });
+
+        Ok(Self {
+            kind,
+            host,
+            port,
+            username: username.filter(|u| !u.is_empty()),
+            password,
+        })
+    }
+}
+
+/// Whether a gateway host should be dialed directly instead of via the proxy
+///
+/// Loopback hosts are always direct. `no_proxy` is a comma separated list in
+/// the usual `NO_PROXY` format: exact hosts, `.suffix` / `*.suffix` domains, or `*`.
+pub fn should_bypass(host: &str, no_proxy: &str) -> bool {
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_lowercase();
+
+    if host == "localhost" || host.ends_with(".localhost") {
+        return true;
+    }
+    if let Ok(ip) = host.parse::<std::net::IpAddr>() {
+        if ip.is_loopback() {
+            return true;
+        }
+    }
+
+    no_proxy
+        .split(',')
+        .map(|entry| entry.trim().to_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+            let suffix = entry.trim_start_matches('*').trim_start_matches('.');
+            host == suffix || host.ends_with(&format!(".{}", suffix))
+        })
+}
+
+/// Open a TCP tunnel to `target_host:target_port` through the proxy
+pub async fn connect_via_proxy(
+    proxy: &ProxyConfig,
+    target_host: &str,
+    target_port: u16,
+) -> Result<TcpStream, ProxyError> {
+    let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port))
+        .await
+        .map_err(ProxyError::Unreachable)?;
+
+    match proxy.kind {
+        ProxyKind::Http => http_connect(&mut stream, proxy, target_host, target_port).await?,
+        ProxyKind::Socks5 => socks5_connect(&mut stream, proxy, target_host, target_port).await?,
+    }
+
+    Ok(stream)
+}
+
+async fn http_connect(
+    stream: &mut TcpStream,
+    proxy: &ProxyConfig,
+    target_host: &str,
+    target_port: u16,
+) -> Result<(), ProxyError> {
+    let authority = if target_host.contains(':') {
+        format!("[{}]:{}", target_host, target_port)
+    } else {
+        format!("{}:{}", target_host, target_port)
+    };
+
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let Some(ref username) = proxy.username {
+        let credentials = format!("{}:{}", username, proxy.password.as_deref().unwrap_or(""));
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            BASE64.encode(credentials)
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte-by-byte so nothing past the header block is consumed;
+    // whatever follows belongs to the WebSocket handshake
+    let mut response = Vec::with_capacity(256);
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_CONNECT_RESPONSE {
+            return Err(ProxyError::Protocol(
+                "CONNECT response headers too large".to_string(),
+            ));
+        }
+        if stream.read(&mut byte).await? == 0 {
+            return Err(ProxyError::Protocol(
+                "proxy closed the connection during CONNECT".to_string(),
+            ));
+        }
+        response.push(byte[0]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or("");
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| ProxyError::Protocol(format!("bad status line '{}'", status_line)))?;
+
+    match status {
+        200..=299 => Ok(()),
+        407 => Err(ProxyError::AuthFailed(status_line.to_string())),
+        _ => Err(ProxyError::ConnectFailed(status_line.to_string())),
+    }
+}
+
+async fn socks5_connect(
+    stream: &mut TcpStream,
+    proxy: &ProxyConfig,
+    target_host: &str,
+    target_port: u16,
+) -> Result<(), ProxyError> {
+    // Greeting: offer username/password only when we have credentials
+    if proxy.username.is_some() {
+        stream.write_all(&[0x05, 0x02, 0x00, 0x02]).await?;
+    } else {
+        stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    }
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 {
+        return Err(ProxyError::Protocol(format!(
+            "unexpected SOCKS version {}",
+            reply[0]
+        )));
+    }
+
+    match reply[1] {
+        0x00 => {}
+        0x02 => {
+            let username = proxy
+                .username
+                .as_deref()
+                .ok_or_else(|| ProxyError::AuthFailed("proxy requires credentials".to_string()))?;
+            let password = proxy.password.as_deref().unwrap_or("");
+            if username.len() > 255 || password.len() > 255 {
+                return Err(ProxyError::InvalidConfig(
+                    "SOCKS5 username and password must be at most 255 bytes".to_string(),
+                ));
+            }
+
+            let mut auth = vec![0x01, username.len() as u8];
+            auth.extend_from_slice(username.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth).await?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                return Err(ProxyError::AuthFailed(
+                    "SOCKS5 proxy rejected username/password".to_string(),
+                ));
+            }
+        }
+        0xFF => {
+            return Err(ProxyError::AuthFailed(
+                "SOCKS5 proxy accepted none of the offered auth methods".to_string(),
+            ))
+        }
+        other => {
+            return Err(ProxyError::Protocol(format!(
+                "SOCKS5 proxy selected unsupported auth method {}",
+                other
+            )))
+        }
+    }
+
+    // CONNECT request; hostnames are resolved by the proxy
+    let mut request = vec![0x05, 0x01, 0x00];
+    match target_host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(std::net::IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if target_host.len() > 255 {
+                return Err(ProxyError::InvalidConfig(
+                    "gateway hostname too long for SOCKS5".to_string(),
+                ));
+            }
+            request.push(0x03);
+            request.push(target_host.len() as u8);
+            request.extend_from_slice(target_host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&target_port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[1] != 0x00 {
+        return Err(ProxyError::ConnectFailed(socks5_reply_message(header[1])));
+    }
+
+    // Drain the bound address so the stream is positioned at the tunnel payload
+    let addr_len = match header[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        other => {
+            return Err(ProxyError::Protocol(format!(
+                "unknown SOCKS5 address type {}",
+                other
+            )))
+        }
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(())
+}
+
+fn socks5_reply_message(code: u8) -> String {
+    match code {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown SOCKS5 error",
+    }
+    .to_string()
+}
@@ -0,0 +1,277 @@
+// Client-side rate limiting of outgoing gateway frames
+// Gateways ban operators that exceed their message rate, so frames are paced
+// with a token bucket before they leave the process.
+
+use crate::{codec, protocol};
+use futures_util::{Sink, SinkExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+
+/// Rate for one bucket; a `rate_per_sec` of zero means unlimited
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LimitConfig {
+    pub rate_per_sec: f64,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Limit applied to every method without an override; zero disables limiting entirely
+    #[serde(default = "default_rate")]
+    pub rate_per_sec: f64,
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+    /// Per-method limits, each with its own bucket
+    #[serde(default = "default_method_overrides")]
+    pub method_overrides: HashMap<String, LimitConfig>,
+}
+
+fn default_rate() -> f64 {
+    5.0
+}
+
+fn default_burst() -> u32 {
+    10
+}
+
+fn default_method_overrides() -> HashMap<String, LimitConfig> {
+    // Approvals unblock agents, so never hold them back
+    HashMap::from([(
+        "approvals.resolve".to_string(),
+        LimitConfig {
+            rate_per_sec: 0.0,
+            burst: 0,
+        },
+    )])
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            rate_per_sec: default_rate(),
+            burst: default_burst(),
+            method_overrides: default_method_overrides(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate_per_sec: f64,
+    capacity: f64,
+    /// Goes negative when frames are already scheduled to wait
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: LimitConfig, now: Instant) -> Self {
+        let capacity = f64::from(limit.burst.max(1));
+        Self {
+            rate_per_sec: limit.rate_per_sec,
+            capacity,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Take a token, returning how long the caller must wait before sending
+    fn reserve(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate_per_sec)
+        }
+    }
+}
+
+/// Bucket state as reported by `get_ws_stats`
+#[derive(Debug, Clone, Serialize)]
+pub struct BucketStats {
+    /// Method name, or `*` for the shared default bucket
+    pub key: String,
+    pub rate_per_sec: f64,
+    pub burst: u32,
+    pub tokens: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitStats {
+    pub enabled: bool,
+    pub buckets: Vec<BucketStats>,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    /// Created on the first frame that uses the default limit
+    default_bucket: Option<TokenBucket>,
+    method_buckets: HashMap<String, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            default_bucket: None,
+            method_buckets: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.rate_per_sec > 0.0
+    }
+
+    /// Reserve a send slot for a frame of `method`, returning the delay to apply
+    pub fn reserve(&mut self, method: Option<&str>, now: Instant) -> Duration {
+        if !self.is_enabled() {
+            return Duration::ZERO;
+        }
+
+        if let Some(limit) = method.and_then(|m| self.config.method_overrides.get(m)) {
+            if limit.rate_per_sec <= 0.0 {
+                return Duration::ZERO;
+            }
+            let limit = *limit;
+            return self
+                .method_buckets
+                .entry(method.unwrap_or_default().to_string())
+                .or_insert_with(|| TokenBucket::new(limit, now))
+                .reserve(now);
+        }
+
+        let limit = LimitConfig {
+            rate_per_sec: self.config.rate_per_sec,
+            burst: self.config.burst,
+        };
+        self.default_bucket
+            .get_or_insert_with(|| TokenBucket::new(limit, now))
+            .reserve(now)
+    }
+
+    pub fn stats(&self, now: Instant) -> RateLimitStats {
+        let snapshot = |key: &str, bucket: &TokenBucket| {
+            let mut bucket = bucket.clone();
+            bucket.refill(now);
+            BucketStats {
+                key: key.to_string(),
+                rate_per_sec: bucket.rate_per_sec,
+                burst: bucket.capacity as u32,
+                tokens: bucket.tokens,
+            }
+        };
+
+        let mut buckets: Vec<BucketStats> = self
+            .default_bucket
+            .iter()
+            .map(|b| snapshot("*", b))
+            .chain(self.method_buckets.iter().map(|(k, b)| snapshot(k, b)))
+            .collect();
+        buckets.sort_by(|a, b| a.key.cmp(&b.key));
+
+        RateLimitStats {
+            enabled: self.is_enabled(),
+            buckets,
+        }
+    }
+}
+
+/// Send a frame once the rate limiter allows it, adapted to the negotiated
+/// protocol version and in the negotiated encoding
+///
+/// `on_wait` hears about a frame being held back, with its method and delay.
+pub async fn send_paced<W>(
+    write: &mut W,
+    rate_limiter: &Mutex<RateLimiter>,
+    text: String,
+    encoding: codec::Encoding,
+    protocol_version: u32,
+    on_wait: impl FnOnce(Option<&str>, Duration),
+) -> Result<(), tungstenite::Error>
+where
+    W: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    let text = protocol::adapt(text, protocol_version);
+    let method = serde_json::from_str::<serde_json::Value>(&text)
+        .ok()
+        .and_then(|v| v["method"].as_str().map(str::to_string));
+    let wait = rate_limiter
+        .lock()
+        .unwrap()
+        .reserve(method.as_deref(), Instant::now());
+    if !wait.is_zero() {
+        eprintln!("[WS] Rate limited, delaying {:?} by {:?}", method, wait);
+        on_wait(method.as_deref(), wait);
+        tokio::time::sleep(wait).await;
+    }
+    eprintln!("[WS] TX: {}", &text);
+    write.send(codec::encode(text, encoding)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_paced() {
+        let mut limiter = RateLimiter::new(RateLimitConfig::default());
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            assert_eq!(limiter.reserve(Some("chat.send"), now), Duration::ZERO);
+        }
+        // Eleventh frame waits one token's worth at 5/sec
+        assert_eq!(
+            limiter.reserve(Some("chat.send"), now),
+            Duration::from_millis(200)
+        );
+        assert_eq!(
+            limiter.reserve(Some("chat.send"), now),
+            Duration::from_millis(400)
+        );
+
+        // Refills over time
+        let later = now + Duration::from_secs(2);
+        assert_eq!(limiter.reserve(Some("chat.send"), later), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_exempt_method_not_limited() {
+        let mut limiter = RateLimiter::new(RateLimitConfig::default());
+        let now = Instant::now();
+
+        for _ in 0..50 {
+            limiter.reserve(Some("chat.send"), now);
+            assert_eq!(
+                limiter.reserve(Some("approvals.resolve"), now),
+                Duration::ZERO
+            );
+        }
+    }
+
+    #[test]
+    fn test_zero_rate_disables() {
+        let mut limiter = RateLimiter::new(RateLimitConfig {
+            rate_per_sec: 0.0,
+            ..RateLimitConfig::default()
+        });
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            assert_eq!(limiter.reserve(Some("chat.send"), now), Duration::ZERO);
+        }
+        assert!(!limiter.stats(now).enabled);
+    }
+}
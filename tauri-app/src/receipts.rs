@@ -0,0 +1,232 @@
+// Delivery receipts and read markers
+// Messages sent with `deliver` get `chat.delivered` / `chat.read` events back,
+// keyed by the idempotency key we sent; they become `message-delivered` /
+// `message-read` events for the UI. Our own read position goes out as
+// `chat.markRead`, throttled per session with the latest marker held back and
+// sent once the window passes, so scrolling through history doesn't flood the
+// gateway.
+
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// At most one read marker per session in this window
+pub const READ_THROTTLE: Duration = Duration::from_secs(2);
+
+/// Idempotency keys remembered for correlating receipts
+const TRACKED_KEYS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+impl ReceiptKind {
+    /// Tauri event the receipt is emitted as
+    pub fn event_name(self) -> &'static str {
+        match self {
+            ReceiptKind::Delivered => "message-delivered",
+            ReceiptKind::Read => "message-read",
+        }
+    }
+}
+
+/// Payload of the `message-delivered` and `message-read` events
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Receipt {
+    pub idempotency_key: String,
+    pub session_key: Option<String>,
+    pub message_id: Option<String>,
+    /// Unix millis, when the gateway says
+    pub at: Option<i64>,
+    /// False when the key isn't one this run sent, e.g. from a previous run
+    pub correlated: bool,
+}
+
+/// Parse an incoming `chat.delivered` or `chat.read` event; `correlated` is
+/// left false for the caller to settle
+pub fn parse(frame: &serde_json::Value) -> Option<(ReceiptKind, Receipt)> {
+    if frame["type"].as_str() != Some("event") {
+        return None;
+    }
+    let kind = match frame["event"].as_str()? {
+        "chat.delivered" => ReceiptKind::Delivered,
+        "chat.read" => ReceiptKind::Read,
+        _ => return None,
+    };
+    let payload = &frame["payload"];
+    let idempotency_key = payload["idempotencyKey"].as_str()?.to_string();
+    // Protocol v4 calls it `session`
+    let session_key = payload["sessionKey"]
+        .as_str()
+        .or_else(|| payload["session"].as_str())
+        .map(str::to_string);
+    Some((
+        kind,
+        Receipt {
+            idempotency_key,
+            session_key,
+            message_id: payload["messageId"].as_str().map(str::to_string),
+            at: payload["at"].as_i64().or_else(|| payload["ts"].as_i64()),
+            correlated: false,
+        },
+    ))
+}
+
+/// Idempotency keys of messages sent with `deliver`, oldest dropped first
+#[derive(Default)]
+pub struct SentKeys {
+    order: VecDeque<String>,
+    keys: HashSet<String>,
+}
+
+impl SentKeys {
+    pub fn record(&mut self, key: &str) {
+        if !self.keys.insert(key.to_string()) {
+            return;
+        }
+        self.order.push_back(key.to_string());
+        if self.order.len() > TRACKED_KEYS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.keys.contains(key)
+    }
+}
+
+/// The `chat.markRead` notification frame; it carries no id and gets no response
+pub fn read_frame(session_key: &str, up_to_message_id: &str) -> String {
+    serde_json::json!({
+        "type": "req",
+        "method": "chat.markRead",
+        "params": {
+            "sessionKey": session_key,
+            "upToMessageId": up_to_message_id,
+        }
+    })
+    .to_string()
+}
+
+struct ReadState {
+    last_sent: Instant,
+    /// Marker held back by the throttle
+    pending: Option<String>,
+}
+
+/// Our read position per session
+#[derive(Default)]
+pub struct ReadMarkers {
+    sessions: HashMap<String, ReadState>,
+}
+
+impl ReadMarkers {
+    /// Record a `mark_read` call; returns true if the marker should go out now,
+    /// otherwise it replaces any marker already held back
+    pub fn update(&mut self, session_key: &str, up_to_message_id: &str, now: Instant) -> bool {
+        match self.sessions.get_mut(session_key) {
+            Some(state) if now.duration_since(state.last_sent) < READ_THROTTLE => {
+                state.pending = Some(up_to_message_id.to_string());
+                false
+            }
+            _ => {
+                self.sessions.insert(
+                    session_key.to_string(),
+                    ReadState {
+                        last_sent: now,
+                        pending: None,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Held-back markers whose throttle window has passed, as
+    /// `(session_key, up_to_message_id)`; they count as sent
+    pub fn due(&mut self, now: Instant) -> Vec<(String, String)> {
+        let mut due = Vec::new();
+        for (session_key, state) in self.sessions.iter_mut() {
+            if now.duration_since(state.last_sent) < READ_THROTTLE {
+                continue;
+            }
+            if let Some(message_id) = state.pending.take() {
+                state.last_sent = now;
+                due.push((session_key.clone(), message_id));
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_receipts() {
+        let delivered = serde_json::json!({
+            "type": "event",
+            "event": "chat.delivered",
+            "payload": {"idempotencyKey": "k1", "sessionKey": "main", "messageId": "m-7", "at": 1_000}
+        });
+        let (kind, receipt) = parse(&delivered).unwrap();
+        assert_eq!(kind, ReceiptKind::Delivered);
+        assert_eq!(receipt.idempotency_key, "k1");
+        assert_eq!(receipt.message_id.as_deref(), Some("m-7"));
+        assert_eq!(receipt.at, Some(1_000));
+        assert!(!receipt.correlated);
+
+        let read = serde_json::json!({
+            "type": "event",
+            "event": "chat.read",
+            "payload": {"idempotencyKey": "k1", "session": "main"}
+        });
+        let (kind, receipt) = parse(&read).unwrap();
+        assert_eq!(kind.event_name(), "message-read");
+        assert_eq!(receipt.session_key.as_deref(), Some("main"));
+
+        let keyless = serde_json::json!({"type": "event", "event": "chat.read", "payload": {}});
+        assert_eq!(parse(&keyless), None);
+        let other = serde_json::json!({"type": "event", "event": "chat.message"});
+        assert_eq!(parse(&other), None);
+    }
+
+    #[test]
+    fn test_sent_keys_are_bounded() {
+        let mut keys = SentKeys::default();
+        keys.record("first");
+        assert!(keys.contains("first"));
+        for i in 0..TRACKED_KEYS {
+            keys.record(&format!("k{}", i));
+        }
+        assert!(!keys.contains("first"));
+        assert!(keys.contains(&format!("k{}", TRACKED_KEYS - 1)));
+    }
+
+    #[test]
+    fn test_read_markers_coalesce() {
+        let mut markers = ReadMarkers::default();
+        let start = Instant::now();
+
+        assert!(markers.update("main", "m-1", start));
+        assert!(!markers.update("main", "m-2", start + Duration::from_millis(100)));
+        assert!(!markers.update("main", "m-3", start + Duration::from_millis(200)));
+        // Other sessions aren't held back
+        assert!(markers.update("other", "m-9", start + Duration::from_millis(200)));
+
+        assert!(markers.due(start + Duration::from_secs(1)).is_empty());
+        assert_eq!(
+            markers.due(start + READ_THROTTLE),
+            vec![("main".to_string(), "m-3".to_string())]
+        );
+        assert!(markers.due(start + READ_THROTTLE * 3).is_empty());
+        // The flushed marker restarted the window
+        assert!(!markers.update("main", "m-4", start + READ_THROTTLE));
+    }
+}
@@ -0,0 +1,513 @@
+// Request/response correlation for gateway RPCs
+// Commands that need an answer register the request id here; the WebSocket
+// task hands matching `res` frames back instead of emitting them to the UI.
+// Every call has a deadline; read-only methods may be retried on timeout.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{oneshot, Notify};
+
+use crate::gateway_error::GatewayError;
+use crate::outbox::{self, OutboxError};
+
+/// How long a command waits for the gateway to answer
+pub const RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Deadline for each attempt
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Extra attempts after a timeout, for idempotent methods only
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Delay before the first retry; doubles on each further one
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    /// Methods that are safe to send again
+    #[serde(default = "default_idempotent_methods")]
+    pub idempotent_methods: HashSet<String>,
+}
+
+fn default_timeout_ms() -> u64 {
+    RPC_TIMEOUT.as_millis() as u64
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+fn default_backoff_ms() -> u64 {
+    500
+}
+
+fn default_idempotent_methods() -> HashSet<String> {
+    ["agents.list", "sessions.list", "chat.history", "status"]
+        .into_iter()
+        .map(str::to_string)
+        .collect()
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout_ms: default_timeout_ms(),
+            max_retries: default_max_retries(),
+            backoff_ms: default_backoff_ms(),
+            idempotent_methods: default_idempotent_methods(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    /// Attempts allowed for `method`, including the first
+    pub fn attempts_for(&self, method: &str) -> u32 {
+        if self.idempotent_methods.contains(method) {
+            self.max_retries + 1
+        } else {
+            1
+        }
+    }
+
+    /// Delay before retry number `retry` (1-based)
+    pub fn backoff(&self, retry: u32) -> Duration {
+        Duration::from_millis(self.backoff_ms.saturating_mul(1 << (retry - 1).min(6)))
+    }
+}
+
+/// RPC counters reported by `get_ws_stats`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RpcStats {
+    pub in_flight: usize,
+    /// Attempts that hit their deadline
+    pub timeouts: u64,
+    pub retries: u64,
+    /// Calls that failed after using up every attempt
+    pub exhausted: u64,
+}
+
+#[derive(Debug)]
+pub enum RpcError {
+    Send(OutboxError),
+    Timeout(Duration),
+    /// The connection dropped before the response arrived
+    Disconnected,
+    /// The gateway answered with an error
+    Gateway(GatewayError),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Send(e) => write!(f, "{}", e),
+            RpcError::Timeout(after) => write!(f, "Gateway did not respond within {:?}", after),
+            RpcError::Disconnected => write!(f, "Connection lost before the gateway responded"),
+            RpcError::Gateway(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+type Reply = Result<serde_json::Value, RpcError>;
+
+#[derive(Default)]
+pub struct PendingRequests {
+    waiters: Mutex<HashMap<String, oneshot::Sender<Reply>>>,
+    /// Bumped by `fail_all`, so a call waiting out a backoff notices the drop
+    disconnects: AtomicU64,
+    disconnected: Notify,
+    timeouts: AtomicU64,
+    retries: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+impl PendingRequests {
+    fn register(&self, id: &str) -> oneshot::Receiver<Reply> {
+        let (tx, rx) = oneshot::channel();
+        self.waiters.lock().unwrap().insert(id.to_string(), tx);
+        rx
+    }
+
+    fn cancel(&self, id: &str) {
+        self.waiters.lock().unwrap().remove(id);
+    }
+
+    /// Deliver a `res` frame to its waiter; returns false if nobody asked for it
+    pub fn resolve(&self, frame: &serde_json::Value) -> bool {
+        if frame["type"].as_str() != Some("res") {
+            return false;
+        }
+        let Some(id) = frame["id"].as_str() else {
+            return false;
+        };
+        let Some(waiter) = self.waiters.lock().unwrap().remove(id) else {
+            return false;
+        };
+
+        let reply = if frame["ok"].as_bool() == Some(true) {
+            Ok(frame["payload"].clone())
+        } else {
+            Err(RpcError::Gateway(GatewayError::from_response(frame)))
+        };
+        let _ = waiter.send(reply);
+        true
+    }
+
+    /// Fail the waiter for an error's request id, whatever frame carried it;
+    /// returns false if nobody is waiting on it
+    pub fn fail(&self, error: &GatewayError) -> bool {
+        let Some(ref id) = error.request_id else {
+            return false;
+        };
+        let Some(waiter) = self.waiters.lock().unwrap().remove(id) else {
+            return false;
+        };
+        let _ = waiter.send(Err(RpcError::Gateway(error.clone())));
+        true
+    }
+
+    /// Fail every outstanding request, used when the connection drops
+    pub fn fail_all(&self) {
+        self.disconnects.fetch_add(1, Ordering::SeqCst);
+        self.disconnected.notify_waiters();
+        for (_, waiter) in self.waiters.lock().unwrap().drain() {
+            let _ = waiter.send(Err(RpcError::Disconnected));
+        }
+    }
+
+    pub fn stats(&self) -> RpcStats {
+        RpcStats {
+            in_flight: self.waiters.lock().unwrap().len(),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            exhausted: self.exhausted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// One attempt: send the frame and wait up to `timeout` for the answer
+async fn attempt(
+    tx: &Sender<String>,
+    pending: &PendingRequests,
+    id: &str,
+    frame: String,
+    timeout: Duration,
+) -> Result<serde_json::Value, RpcError> {
+    let reply = pending.register(id);
+    if let Err(e) = outbox::enqueue(tx, frame, None).await {
+        pending.cancel(id);
+        return Err(RpcError::Send(e));
+    }
+
+    match tokio::time::timeout(timeout, reply).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => Err(RpcError::Disconnected),
+        Err(_) => {
+            pending.cancel(id);
+            pending.timeouts.fetch_add(1, Ordering::Relaxed);
+            Err(RpcError::Timeout(timeout))
+        }
+    }
+}
+
+/// Send a request frame and wait for the matching response payload
+///
+/// Idempotent methods are retried on timeout per `policy`. Retries reuse the
+/// request id, so a late answer to an earlier attempt still satisfies the
+/// call. Gives up with `Disconnected` if the connection drops
+/// while waiting to retry.
+pub async fn call(
+    tx: &Sender<String>,
+    pending: &PendingRequests,
+    policy: &RetryPolicy,
+    id: &str,
+    method: &str,
+    params: serde_json::Value,
+) -> Result<serde_json::Value, RpcError> {
+    let frame = serde_json::json!({
+        "type": "req",
+        "id": id,
+        "method": method,
+        "params": params,
+    })
+    .to_string();
+
+    let attempts = policy.attempts_for(method);
+    let disconnects = pending.disconnects.load(Ordering::SeqCst);
+    let mut retry = 0;
+    loop {
+        match attempt(tx, pending, id, frame.clone(), policy.timeout()).await {
+            Err(RpcError::Timeout(after)) => {
+                retry += 1;
+                if retry >= attempts {
+                    if attempts > 1 {
+                        pending.exhausted.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return Err(RpcError::Timeout(after));
+                }
+            }
+            result => return result,
+        }
+
+        // Register interest before checking, so a drop in between isn't missed
+        let dropped = pending.disconnected.notified();
+        if pending.disconnects.load(Ordering::SeqCst) != disconnects {
+            return Err(RpcError::Disconnected);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(policy.backoff(retry)) => {}
+            _ = dropped => return Err(RpcError::Disconnected),
+        }
+        eprintln!(
+            "[RPC] Retrying {} ({}) after timeout, attempt {}",
+            method,
+            id,
+            retry + 1
+        );
+        pending.retries.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    #[tokio::test]
+    async fn test_call_resolves_matching_response() {
+        let (tx, mut rx) = channel::<String>(4);
+        let pending = std::sync::Arc::new(PendingRequests::default());
+
+        let responder = {
+            let pending = pending.clone();
+            tokio::spawn(async move {
+                let sent: serde_json::Value =
+                    serde_json::from_str(&rx.recv().await.unwrap()).unwrap();
+                assert_eq!(sent["method"], "agents.list");
+                // An unrelated response is not consumed
+                assert!(!pending
+                    .resolve(&serde_json::json!({"type": "res", "id": "other", "ok": true})));
+                assert!(pending.resolve(&serde_json::json!({
+                    "type": "res",
+                    "id": sent["id"],
+                    "ok": true,
+                    "payload": {"agents": []}
+                })));
+            })
+        };
+
+        let payload = call(
+            &tx,
+            &pending,
+            &RetryPolicy::default(),
+            "rpc-1",
+            "agents.list",
+            serde_json::json!({}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(payload, serde_json::json!({"agents": []}));
+        responder.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_call_reports_gateway_error_and_disconnect() {
+        let (tx, _rx) = channel::<String>(4);
+        let pending = std::sync::Arc::new(PendingRequests::default());
+
+        let waiter = {
+            let pending = pending.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                call(
+                    &tx,
+                    &pending,
+                    &RetryPolicy::default(),
+                    "rpc-2",
+                    "x",
+                    serde_json::json!({}),
+                )
+                .await
+            })
+        };
+        while pending.waiters.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        pending.resolve(&serde_json::json!({
+            "type": "res",
+            "id": "rpc-2",
+            "ok": false,
+            "error": {"code": "NOT_FOUND", "message": "no such method"}
+        }));
+        match waiter.await.unwrap() {
+            Err(RpcError::Gateway(e)) => assert_eq!(e.code, "NOT_FOUND"),
+            other => panic!("expected gateway error, got {:?}", other),
+        }
+
+        let waiter = {
+            let pending = pending.clone();
+            tokio::spawn(async move {
+                call(
+                    &tx,
+                    &pending,
+                    &RetryPolicy::default(),
+                    "rpc-3",
+                    "x",
+                    serde_json::json!({}),
+                )
+                .await
+            })
+        };
+        while pending.waiters.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        pending.fail_all();
+        assert!(matches!(waiter.await.unwrap(), Err(RpcError::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn test_error_event_fails_correlated_call() {
+        let (tx, _rx) = channel::<String>(4);
+        let pending = std::sync::Arc::new(PendingRequests::default());
+
+        let waiter = {
+            let pending = pending.clone();
+            tokio::spawn(async move {
+                call(
+                    &tx,
+                    &pending,
+                    &RetryPolicy::default(),
+                    "rpc-7",
+                    "x",
+                    serde_json::json!({}),
+                )
+                .await
+            })
+        };
+        while pending.waiters.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let error = GatewayError::from_frame(&serde_json::json!({
+            "type": "event",
+            "event": "error",
+            "requestId": "rpc-7",
+            "error": {"code": "SCOPE_DENIED", "message": "operator.admin required"}
+        }))
+        .unwrap();
+        assert!(pending.fail(&error));
+        assert!(!pending.fail(&error));
+        match waiter.await.unwrap() {
+            Err(RpcError::Gateway(e)) => {
+                assert_eq!(e.kind, crate::gateway_error::ErrorKind::ScopeDenied)
+            }
+            other => panic!("expected gateway error, got {:?}", other),
+        }
+    }
+
+    fn fast_policy() -> RetryPolicy {
+        RetryPolicy {
+            timeout_ms: 20,
+            max_retries: 2,
+            backoff_ms: 10,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_method_retried_with_same_id() {
+        let (tx, mut rx) = channel::<String>(4);
+        let pending = std::sync::Arc::new(PendingRequests::default());
+
+        let responder = {
+            let pending = pending.clone();
+            tokio::spawn(async move {
+                // Ignore the first attempt, answer the retry
+                let first: serde_json::Value =
+                    serde_json::from_str(&rx.recv().await.unwrap()).unwrap();
+                let second: serde_json::Value =
+                    serde_json::from_str(&rx.recv().await.unwrap()).unwrap();
+                assert_eq!(first["id"], second["id"]);
+                pending.resolve(&serde_json::json!({
+                    "type": "res", "id": second["id"], "ok": true, "payload": []
+                }));
+            })
+        };
+
+        let payload = call(
+            &tx,
+            &pending,
+            &fast_policy(),
+            "rpc-4",
+            "sessions.list",
+            serde_json::json!({}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(payload, serde_json::json!([]));
+        responder.await.unwrap();
+
+        let stats = pending.stats();
+        assert_eq!((stats.timeouts, stats.retries, stats.exhausted), (1, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_non_idempotent_method_fails_fast() {
+        let (tx, mut rx) = channel::<String>(4);
+        let pending = PendingRequests::default();
+
+        let result = call(
+            &tx,
+            &pending,
+            &fast_policy(),
+            "rpc-5",
+            "chat.send",
+            serde_json::json!({}),
+        )
+        .await;
+        assert!(matches!(result, Err(RpcError::Timeout(_))));
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+        assert_eq!(pending.stats().retries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_during_backoff_cancels_retry() {
+        let (tx, _rx) = channel::<String>(4);
+        let pending = std::sync::Arc::new(PendingRequests::default());
+        let policy = RetryPolicy {
+            backoff_ms: 60_000,
+            ..fast_policy()
+        };
+
+        let waiter = {
+            let pending = pending.clone();
+            tokio::spawn(async move {
+                call(
+                    &tx,
+                    &pending,
+                    &policy,
+                    "rpc-6",
+                    "agents.list",
+                    serde_json::json!({}),
+                )
+                .await
+            })
+        };
+        while pending.stats().timeouts == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        pending.fail_all();
+        assert!(matches!(waiter.await.unwrap(), Err(RpcError::Disconnected)));
+        assert_eq!(pending.stats().retries, 0);
+    }
+}
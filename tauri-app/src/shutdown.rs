@@ -0,0 +1,236 @@
+// Graceful close of the gateway WebSocket
+// Used when the app exits or the connection is replaced, so the gateway sees a
+// normal closure instead of a TCP reset and can drop the operator session.
+
+use crate::codec::Encoding;
+use crate::ratelimit::{self, RateLimiter};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use std::borrow::Cow;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::Message;
+
+/// How long we wait for the gateway to answer our Close frame
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Send any queued frames, then a normal-closure Close frame, and wait for the
+/// gateway's Close reply (bounded by `CLOSE_TIMEOUT`).
+///
+/// Queued frames are only sent when `flush` is set (i.e. we are authenticated);
+/// otherwise they are discarded like any other pre-auth send. They go out
+/// like every other frame: paced by the connection's rate limiter, adapted to
+/// its protocol version and in its encoding.
+pub async fn close_gracefully<W, R>(
+    write: &mut W,
+    read: &mut R,
+    pending: &mut Receiver<String>,
+    flush: bool,
+    rate_limiter: &Mutex<RateLimiter>,
+    encoding: Encoding,
+    protocol_version: u32,
+) -> Result<(), tungstenite::Error>
+where
+    W: Sink<Message, Error = tungstenite::Error> + Unpin,
+    R: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    let mut flushed = 0;
+    while let Ok(text) = pending.try_recv() {
+        if flush {
+            ratelimit::send_paced(
+                write,
+                rate_limiter,
+                text,
+                encoding,
+                protocol_version,
+                |_, _| {},
+            )
+            .await?;
+            flushed += 1;
+        }
+    }
+    if flushed > 0 {
+        eprintln!("[WS] Flushed {} queued frame(s) before close", flushed);
+    }
+
+    write
+        .send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Normal,
+            reason: Cow::Borrowed("client exiting"),
+        })))
+        .await?;
+
+    let acknowledged = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        while let Some(msg) = read.next().await {
+            match msg {
+                Ok(Message::Close(_)) => return true,
+                Ok(_) => continue,
+                Err(_) => return false,
+            }
+        }
+        false
+    })
+    .await
+    .unwrap_or(false);
+
+    if acknowledged {
+        eprintln!("[WS] Close handshake complete");
+    } else {
+        eprintln!(
+            "[WS] Gateway did not acknowledge close within {:?}",
+            CLOSE_TIMEOUT
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Frame;
+    use crate::protocol;
+    use crate::ratelimit::RateLimitConfig;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::channel;
+
+    fn limiter() -> Mutex<RateLimiter> {
+        Mutex::new(RateLimiter::new(RateLimitConfig::default()))
+    }
+
+    /// Mock gateway: record every frame until the client's Close arrives
+    async fn record_until_close(listener: TcpListener) -> Vec<Message> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let mut received = Vec::new();
+        while let Some(Ok(msg)) = ws.next().await {
+            let is_close = msg.is_close();
+            received.push(msg);
+            if is_close {
+                break;
+            }
+        }
+        // Completes the close handshake by flushing the automatic reply
+        let _ = ws.close(None).await;
+        received
+    }
+
+    #[tokio::test]
+    async fn test_close_gracefully_flushes_and_sends_close_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(record_until_close(listener));
+
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        let (mut write, mut read) = ws.split();
+        let (tx, mut rx) = channel::<String>(8);
+        tx.send("queued".to_string()).await.unwrap();
+
+        close_gracefully(
+            &mut write,
+            &mut read,
+            &mut rx,
+            true,
+            &limiter(),
+            Encoding::Json,
+            protocol::MIN_PROTOCOL,
+        )
+        .await
+        .unwrap();
+
+        let received = server.await.unwrap();
+        assert_eq!(received[0], Message::Text("queued".to_string()));
+        match received.last() {
+            Some(Message::Close(Some(frame))) => assert_eq!(frame.code, CloseCode::Normal),
+            other => panic!("expected a Close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_gracefully_drops_queue_when_unauthenticated() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let first = ws.next().await.unwrap().unwrap();
+            let _ = ws.close(None).await;
+            first
+        });
+
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        let (mut write, mut read) = ws.split();
+        let (tx, mut rx) = channel::<String>(8);
+        tx.send("not yet authenticated".to_string()).await.unwrap();
+
+        close_gracefully(
+            &mut write,
+            &mut read,
+            &mut rx,
+            false,
+            &limiter(),
+            Encoding::Json,
+            protocol::MIN_PROTOCOL,
+        )
+        .await
+        .unwrap();
+
+        assert!(server.await.unwrap().is_close());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_close_gracefully_flushes_in_the_negotiated_encoding() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(record_until_close(listener));
+
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+        let (mut write, mut read) = ws.split();
+        let (tx, mut rx) = channel::<String>(8);
+        let queued = serde_json::json!({
+            "type": "req",
+            "id": "msg-1",
+            "method": "chat.send",
+            "params": {"sessionKey": "main", "message": "bye"}
+        });
+        tx.send(queued.to_string()).await.unwrap();
+
+        close_gracefully(
+            &mut write,
+            &mut read,
+            &mut rx,
+            true,
+            &limiter(),
+            Encoding::MsgPack,
+            protocol::MAX_PROTOCOL,
+        )
+        .await
+        .unwrap();
+
+        let received = server.await.unwrap();
+        let Message::Binary(bytes) = &received[0] else {
+            panic!("expected a Binary frame, got {:?}", received[0]);
+        };
+        // Sent as v4, where `sessionKey` is `session`
+        match Frame::from_msgpack(bytes).unwrap() {
+            Frame::Req { method, params, .. } => {
+                assert_eq!(method, "chat.send");
+                assert_eq!(params["session"], "main");
+                assert!(params.get("sessionKey").is_none());
+            }
+            other => panic!("expected a request, got {:?}", other),
+        }
+        assert!(received.last().unwrap().is_close());
+    }
+}
@@ -0,0 +1,74 @@
+// Signing on behalf of other local tools
+// Payloads are domain-separated from gateway connect messages (which start with
+// `v2|`), so nothing signed here can be replayed as a device login.
+
+use ed25519_dalek::{Signature, Signer, SigningKey};
+use sha2::{Digest, Sha256};
+
+/// Prefix of every externally requested signature: `claw-pen:ext:<context>|`
+pub const EXT_DOMAIN_PREFIX: &str = "claw-pen:ext:";
+
+/// Largest payload we will sign
+pub const MAX_PAYLOAD_BYTES: usize = 64 * 1024;
+
+/// Check a context against the configured whitelist
+pub fn check_context(context: &str, allowed: &[String]) -> Result<(), String> {
+    if context.is_empty() || context.contains('|') {
+        return Err("Invalid signing context".to_string());
+    }
+    if !allowed.iter().any(|c| c == context) {
+        return Err(format!(
+            "Signing context '{}' is not allowed. Add it to signing_contexts in ~/.openclaw/claw-pen.json to permit it.",
+            context
+        ));
+    }
+    Ok(())
+}
+
+/// The exact bytes that get signed for `context`
+pub fn signing_input(context: &str, payload: &[u8]) -> Vec<u8> {
+    let mut input = format!("{}{}|", EXT_DOMAIN_PREFIX, context).into_bytes();
+    input.extend_from_slice(payload);
+    input
+}
+
+/// Sign `payload` under `context`
+pub fn sign_external(key: &SigningKey, context: &str, payload: &[u8]) -> Signature {
+    key.sign(&signing_input(context, payload))
+}
+
+/// Hex SHA-256 of a payload, for the audit log
+pub fn payload_hash(payload: &[u8]) -> String {
+    hex::encode(Sha256::digest(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Verifier;
+
+    #[test]
+    fn test_signature_is_domain_separated() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let payload = b"hello";
+        let signature = sign_external(&key, "ci-runner", payload);
+
+        let verifying = key.verifying_key();
+        assert!(verifying
+            .verify(b"claw-pen:ext:ci-runner|hello", &signature)
+            .is_ok());
+        assert!(verifying.verify(payload, &signature).is_err());
+        assert!(verifying
+            .verify(&signing_input("other", payload), &signature)
+            .is_err());
+    }
+
+    #[test]
+    fn test_check_context() {
+        let allowed = vec!["ci-runner".to_string()];
+        assert!(check_context("ci-runner", &allowed).is_ok());
+        assert!(check_context("connect", &allowed).is_err());
+        assert!(check_context("", &allowed).is_err());
+        assert!(check_context("ci|runner", &["ci|runner".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+// System tray icon reflecting the gateway connection
+// Degrades silently where the platform has no tray.
+
+use std::time::Duration;
+use tauri::image::Image;
+use tauri::menu::{Menu, MenuItem, PredefinedMenuItem};
+use tauri::tray::TrayIconBuilder;
+use tauri::AppHandle;
+use tokio::sync::watch;
+
+use crate::conn_state::ConnState;
+
+const TRAY_ID: &str = "main";
+
+/// Quiet period before the icon follows a state change, so reconnect loops don't flicker
+const ICON_DEBOUNCE: Duration = Duration::from_millis(400);
+
+const ICON_SIZE: u32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrayAction {
+    Connect,
+    Disconnect,
+    OpenLogs,
+    Quit,
+}
+
+impl TrayAction {
+    fn from_id(id: &str) -> Option<Self> {
+        match id {
+            "connect" => Some(TrayAction::Connect),
+            "disconnect" => Some(TrayAction::Disconnect),
+            "open-logs" => Some(TrayAction::OpenLogs),
+            "quit" => Some(TrayAction::Quit),
+            _ => None,
+        }
+    }
+}
+
+fn color_for(state: ConnState) -> [u8; 3] {
+    match state {
+        ConnState::Disconnected => [0x9e, 0x9e, 0x9e],
+        ConnState::Connecting | ConnState::Authenticating => [0xf5, 0xc2, 0x42],
+        ConnState::Ready => [0x4c, 0xaf, 0x50],
+        ConnState::Idle => [0x64, 0x95, 0xed],
+        ConnState::Error => [0xe5, 0x39, 0x35],
+    }
+}
+
+/// A filled circle in the state's colour
+fn icon_for(state: ConnState) -> Image<'static> {
+    let [r, g, b] = color_for(state);
+    let center = (ICON_SIZE as f32 - 1.0) / 2.0;
+    let radius = ICON_SIZE as f32 / 2.0 - 2.0;
+
+    let mut rgba = Vec::with_capacity((ICON_SIZE * ICON_SIZE * 4) as usize);
+    for y in 0..ICON_SIZE {
+        for x in 0..ICON_SIZE {
+            let dx = x as f32 - center;
+            let dy = y as f32 - center;
+            let inside = (dx * dx + dy * dy).sqrt() <= radius;
+            rgba.extend_from_slice(&[r, g, b, if inside { 0xff } else { 0x00 }]);
+        }
+    }
+    Image::new_owned(rgba, ICON_SIZE, ICON_SIZE)
+}
+
+fn tooltip_for(state: ConnState) -> String {
+    format!("Claw Pen: {}", state.as_str())
+}
+
+/// Create the tray icon and keep it in sync with the connection state
+pub fn setup<F>(app: &AppHandle, mut states: watch::Receiver<ConnState>, on_action: F)
+where
+    F: Fn(&AppHandle, TrayAction) + Send + Sync + 'static,
+{
+    let initial = *states.borrow_and_update();
+
+    let built = (|| -> tauri::Result<()> {
+        let menu = Menu::with_items(
+            app,
+            &[
+                &MenuItem::with_id(app, "connect", "Connect", true, None::<&str>)?,
+                &MenuItem::with_id(app, "disconnect", "Disconnect", true, None::<&str>)?,
+                &PredefinedMenuItem::separator(app)?,
+                &MenuItem::with_id(app, "open-logs", "Open Logs", true, None::<&str>)?,
+                &PredefinedMenuItem::separator(app)?,
+                &MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?,
+            ],
+        )?;
+
+        TrayIconBuilder::with_id(TRAY_ID)
+            .icon(icon_for(initial))
+            .tooltip(tooltip_for(initial))
+            .menu(&menu)
+            .on_menu_event(move |app, event| {
+                if let Some(action) = TrayAction::from_id(event.id().as_ref()) {
+                    on_action(app, action);
+                }
+            })
+            .build(app)?;
+        Ok(())
+    })();
+
+    if let Err(e) = built {
+        eprintln!("[Tray] Tray icon unavailable: {}", e);
+        return;
+    }
+
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let mut shown = initial;
+        while states.changed().await.is_ok() {
+            // Let bursts of transitions settle and show only where they ended up
+            tokio::time::sleep(ICON_DEBOUNCE).await;
+            let state = *states.borrow_and_update();
+            if state == shown {
+                continue;
+            }
+            if let Some(tray) = app.tray_by_id(TRAY_ID) {
+                let _ = tray.set_icon(Some(icon_for(state)));
+                let _ = tray.set_tooltip(Some(tooltip_for(state)));
+            }
+            shown = state;
+        }
+    });
+}
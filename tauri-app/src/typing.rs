@@ -0,0 +1,192 @@
+// Typing indicators
+// Outgoing `chat.typing` notifications are throttled per session and expire on
+// their own if the UI stops refreshing them; incoming ones become
+// `remote-typing` events for the UI.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// At most one typing frame per session in this window, unless the state flips
+pub const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+
+/// Typing state is dropped if not refreshed within this long
+pub const TYPING_EXPIRY: Duration = Duration::from_secs(10);
+
+/// The `chat.typing` notification frame; it carries no id and gets no response
+pub fn typing_frame(session_key: &str, active: bool) -> String {
+    serde_json::json!({
+        "type": "req",
+        "method": "chat.typing",
+        "params": {
+            "sessionKey": session_key,
+            "active": active,
+        }
+    })
+    .to_string()
+}
+
+struct LocalTyping {
+    active: bool,
+    last_sent: Option<Instant>,
+    /// Last time the UI said we're typing
+    refreshed_at: Instant,
+}
+
+/// Our own typing state per session
+#[derive(Default)]
+pub struct TypingTracker {
+    sessions: HashMap<String, LocalTyping>,
+}
+
+impl TypingTracker {
+    /// Record a `set_typing` call; returns true if a frame should go out now
+    pub fn update(&mut self, session_key: &str, active: bool, now: Instant) -> bool {
+        let entry = self
+            .sessions
+            .entry(session_key.to_string())
+            .or_insert(LocalTyping {
+                active: false,
+                last_sent: None,
+                refreshed_at: now,
+            });
+        if active {
+            entry.refreshed_at = now;
+        }
+
+        let changed = entry.active != active;
+        let due = match entry.last_sent {
+            Some(sent) => now.duration_since(sent) >= TYPING_THROTTLE,
+            None => true,
+        };
+        // Repeating "stopped" is pointless; repeating "typing" keeps it alive remotely
+        if changed || (active && due) {
+            entry.active = active;
+            entry.last_sent = Some(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Clear sessions whose typing state wasn't refreshed in time, returning
+    /// the ones the gateway still thinks are active
+    pub fn expire(&mut self, now: Instant) -> Vec<String> {
+        let expired: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|(_, t)| now.duration_since(t.refreshed_at) >= TYPING_EXPIRY)
+            .map(|(key, _)| key.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter(|key| self.sessions.remove(key).is_some_and(|t| t.active))
+            .collect()
+    }
+
+    /// Forget everything, e.g. after a disconnect
+    pub fn clear(&mut self) {
+        self.sessions.clear();
+    }
+}
+
+/// Payload of the `remote-typing` event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteTyping {
+    pub session_key: String,
+    pub participant: String,
+    pub active: bool,
+    /// Unix millis after which the UI should stop showing the indicator
+    pub expires_at: i64,
+}
+
+/// Parse an incoming `chat.typing` event
+pub fn parse_remote(frame: &serde_json::Value, now_ms: i64) -> Option<RemoteTyping> {
+    if frame["type"].as_str() != Some("event") || frame["event"].as_str() != Some("chat.typing") {
+        return None;
+    }
+    let payload = &frame["payload"];
+    // Protocol v4 calls it `session`
+    let session_key = payload["sessionKey"]
+        .as_str()
+        .or_else(|| payload["session"].as_str())?
+        .to_string();
+    let participant = ["participant", "agentId", "from", "deviceId"]
+        .iter()
+        .find_map(|k| payload[*k].as_str())
+        .unwrap_or("unknown")
+        .to_string();
+    let active = payload["active"].as_bool().unwrap_or(true);
+    // Never trust the gateway to keep an indicator up longer than our own expiry
+    let max_expiry = now_ms + TYPING_EXPIRY.as_millis() as i64;
+    let expires_at = payload["expiresAt"]
+        .as_i64()
+        .or_else(|| payload["ttlMs"].as_i64().map(|ttl| now_ms + ttl))
+        .map_or(max_expiry, |at| at.min(max_expiry));
+
+    Some(RemoteTyping {
+        session_key,
+        participant,
+        active,
+        expires_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_throttles_refreshes() {
+        let mut tracker = TypingTracker::default();
+        let start = Instant::now();
+
+        assert!(tracker.update("main", true, start));
+        assert!(!tracker.update("main", true, start + Duration::from_secs(1)));
+        assert!(tracker.update("main", true, start + TYPING_THROTTLE));
+        // Stopping always goes out, repeating it doesn't
+        assert!(tracker.update("main", false, start + Duration::from_secs(4)));
+        assert!(!tracker.update("main", false, start + Duration::from_secs(8)));
+        // Sessions are throttled independently
+        assert!(tracker.update("other", true, start + Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn test_expire_unrefreshed_sessions() {
+        let mut tracker = TypingTracker::default();
+        let start = Instant::now();
+        tracker.update("main", true, start);
+        tracker.update("idle", false, start);
+        tracker.update("busy", true, start + Duration::from_secs(5));
+
+        let expired = tracker.expire(start + TYPING_EXPIRY);
+        assert_eq!(expired, vec!["main".to_string()]);
+        assert!(tracker.expire(start + TYPING_EXPIRY).is_empty());
+        // A later refresh counts as a fresh start
+        assert!(tracker.update("main", true, start + TYPING_EXPIRY));
+    }
+
+    #[test]
+    fn test_parse_remote() {
+        let frame = serde_json::json!({
+            "type": "event",
+            "event": "chat.typing",
+            "payload": {"sessionKey": "main", "participant": "alice", "ttlMs": 4000}
+        });
+        let typing = parse_remote(&frame, 1_000).unwrap();
+        assert_eq!(typing.participant, "alice");
+        assert!(typing.active);
+        assert_eq!(typing.expires_at, 5_000);
+
+        let far = serde_json::json!({
+            "type": "event",
+            "event": "chat.typing",
+            "payload": {"sessionKey": "main", "agentId": "a1", "expiresAt": 999_999}
+        });
+        assert_eq!(parse_remote(&far, 1_000).unwrap().expires_at, 11_000);
+
+        let other = serde_json::json!({"type": "event", "event": "chat.message"});
+        assert_eq!(parse_remote(&other, 0), None);
+    }
+}